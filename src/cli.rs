@@ -0,0 +1,187 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::config::Config;
+
+const USAGE: &str = "Usage: metrixd [COMMAND] [OPTIONS]
+
+Commands:
+  check-config <PATH>           Validate a configuration file and exit
+  collect [--once]              Run all collectors once, print the metrics to stdout and exit
+  config-schema                 Print a JSON Schema of the configuration file and exit
+  list-collectors               List collectors, their state, platforms and metrics
+  print-default-config          Print a configuration file with every setting at its default and exit
+  push                          Run all collectors once, push to every configured exporter and exit
+  record --out <PATH>           Run the collectors and append every cycle to a file until stopped
+  replay <PATH> [--loop]        Serve a recording on /metrics with its original timing
+
+Options:
+  --config.file <PATH>          Configuration file to load
+  --web.listen-address <ADDR>   Address to serve /metrics on [default: 0.0.0.0:9100]
+  --web.enable-lifecycle        Enable the /-/reload and /-/quit endpoints
+  --web.enable-admin-api        Enable the /admin/collectors endpoints
+  --grpc.listen-address <ADDR>  Address to serve the gRPC query API on (disabled by default)
+  --demo                        Export simulated values instead of this host's metrics
+  --demo.seed <N>               Seed for --demo, for reproducible series [default: 0]
+  --dry-run                     Collect once, print every metric with its label keys and series count, and exit
+  -h, --help                    Print this help";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Command {
+    /// Run the daemon (the default when no command is given)
+    #[default]
+    Serve,
+    CheckConfig,
+    Collect,
+    ConfigSchema,
+    ListCollectors,
+    PrintDefaultConfig,
+    /// Collect once, push to the exporters and exit, for systemd timers
+    Push,
+    Record,
+    Replay,
+    /// `--dry-run`: collect once and print the metric inventory
+    DryRun,
+}
+
+/// Command line arguments. Flags take precedence over the config file and
+/// are re-applied on every reload.
+#[derive(Debug, Clone, Default)]
+pub struct Args {
+    pub command: Command,
+    pub config_file: Option<PathBuf>,
+    pub listen_address: Option<SocketAddr>,
+    pub grpc_listen_address: Option<SocketAddr>,
+    pub enable_lifecycle: bool,
+    pub enable_admin_api: bool,
+    /// File written by `record` or read by `replay`
+    pub recording: Option<PathBuf>,
+    /// Start a replay over once it reaches the end
+    pub replay_loop: bool,
+    /// Seed of `--demo`; `None` means real data
+    pub demo_seed: Option<u64>,
+}
+
+impl Args {
+    /// Parse the process arguments, printing usage and exiting on error
+    pub fn parse() -> Self {
+        match Self::parse_from(std::env::args().skip(1)) {
+            Ok(args) => args,
+            Err(e) => {
+                eprintln!("{}\n\n{}", e, USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    fn parse_from(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Args::default();
+
+        let mut args = args.into_iter().peekable();
+        if let Some(command) = args.next_if(|arg| !arg.starts_with('-')) {
+            parsed.command = match command.as_str() {
+                "check-config" => Command::CheckConfig,
+                "collect" => Command::Collect,
+                "config-schema" => Command::ConfigSchema,
+                "list-collectors" => Command::ListCollectors,
+                "print-default-config" => Command::PrintDefaultConfig,
+                "push" => Command::Push,
+                "record" => Command::Record,
+                "replay" => Command::Replay,
+                other => return Err(format!("unknown command: {}", other)),
+            };
+        }
+
+        while let Some(arg) = args.next() {
+            // Accept both `--flag value` and `--flag=value`
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("missing value for {}", flag))
+            };
+
+            match flag.as_str() {
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                "--config.file" => parsed.config_file = Some(PathBuf::from(value()?)),
+                "--web.listen-address" => parsed.listen_address = Some(parse_addr(&value()?)?),
+                "--web.enable-lifecycle" => parsed.enable_lifecycle = true,
+                "--web.enable-admin-api" => parsed.enable_admin_api = true,
+                "--demo" => parsed.demo_seed = parsed.demo_seed.or(Some(0)),
+                "--demo.seed" => {
+                    let seed = value()?;
+                    parsed.demo_seed = Some(
+                        seed.parse()
+                            .map_err(|_| format!("invalid seed '{}'", seed))?,
+                    )
+                }
+                "--dry-run" if parsed.command == Command::Serve => parsed.command = Command::DryRun,
+                // One-shot is the only collect mode; the flag documents intent in scripts
+                "--once" if parsed.command == Command::Collect => {}
+                "--out" if parsed.command == Command::Record => {
+                    parsed.recording = Some(PathBuf::from(value()?))
+                }
+                "--loop" if parsed.command == Command::Replay => parsed.replay_loop = true,
+                "--grpc.listen-address" => {
+                    parsed.grpc_listen_address = Some(parse_addr(&value()?)?)
+                }
+                // check-config takes the file as a positional argument
+                path if parsed.command == Command::CheckConfig
+                    && !path.starts_with('-')
+                    && parsed.config_file.is_none() =>
+                {
+                    parsed.config_file = Some(PathBuf::from(path))
+                }
+                path if parsed.command == Command::Replay
+                    && !path.starts_with('-')
+                    && parsed.recording.is_none() =>
+                {
+                    parsed.recording = Some(PathBuf::from(path))
+                }
+                _ => return Err(format!("unknown argument: {}", flag)),
+            }
+        }
+
+        if parsed.command == Command::CheckConfig && parsed.config_file.is_none() {
+            return Err("check-config requires a config file path".to_string());
+        }
+        if parsed.command == Command::Record && parsed.recording.is_none() {
+            return Err("record requires --out <PATH>".to_string());
+        }
+        if parsed.command == Command::Replay && parsed.recording.is_none() {
+            return Err("replay requires a recording path".to_string());
+        }
+
+        Ok(parsed)
+    }
+
+    /// Override config file values with the flags that were given
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(addr) = self.listen_address {
+            config.web.listen_address = addr;
+        }
+        if self.enable_lifecycle {
+            config.web.enable_lifecycle = true;
+        }
+        if self.enable_admin_api {
+            config.web.enable_admin_api = true;
+        }
+        if let Some(addr) = self.grpc_listen_address {
+            config.grpc.listen_address = Some(addr);
+        }
+        config.demo = self.demo_seed;
+    }
+}
+
+fn parse_addr(value: &str) -> Result<SocketAddr, String> {
+    value
+        .parse()
+        .map_err(|e| format!("invalid address '{}': {}", value, e))
+}
@@ -0,0 +1,370 @@
+//! `cloud.metadata_labels`: ask the instance metadata service of EC2, GCE
+//! or Azure, at startup, which instance this is, and add `instance_id`,
+//! `instance_type`, `region` and `zone` labels to every series. All three
+//! serve plain HTTP on the link-local 169.254.169.254, so a hand-written
+//! request with short timeouts is enough, and off-cloud hosts only pay
+//! for one failed connect. The CloudWatch, Google Cloud and Azure Monitor
+//! exporters get their credentials, and where they write to, here too.
+//! `cloud.identity_info` looks the instance up too, for
+//! `node_identity_info` only.
+
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use crate::fetch;
+use crate::sigv4::Credentials;
+
+const METADATA_ADDRESS: SocketAddr = SocketAddr::V4(std::net::SocketAddrV4::new(
+    std::net::Ipv4Addr::new(169, 254, 169, 254),
+    80,
+));
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The provider and instance we run on; none if no metadata service
+/// answers
+pub fn detect() -> Option<(&'static str, Instance)> {
+    detect_at(METADATA_ADDRESS)
+}
+
+fn detect_at(address: SocketAddr) -> Option<(&'static str, Instance)> {
+    let client = Client { address };
+    if TcpStream::connect_timeout(&address, TIMEOUT).is_err() {
+        println!("No cloud metadata service found");
+        return None;
+    }
+    for (provider, detect) in [
+        ("EC2", ec2 as fn(&Client) -> Option<Instance>),
+        ("GCE", gce),
+        ("Azure", azure),
+    ] {
+        if let Some(instance) = detect(&client) {
+            println!("Running on {} instance {}", provider, instance.id);
+            return Some((provider, instance));
+        }
+    }
+    println!("Cloud metadata service not recognised");
+    None
+}
+
+pub struct Instance {
+    pub id: String,
+    instance_type: String,
+    region: String,
+    zone: String,
+}
+
+impl Instance {
+    /// The `cloud.metadata_labels`, leaving out empty values
+    pub fn labels(&self) -> BTreeMap<String, String> {
+        [
+            ("instance_id", &self.id),
+            ("instance_type", &self.instance_type),
+            ("region", &self.region),
+            ("zone", &self.zone),
+        ]
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(name, value)| (name.to_string(), value.clone()))
+        .collect()
+    }
+}
+
+/// IMDSv2: a session token first, then the values
+fn ec2(client: &Client) -> Option<Instance> {
+    let token = client.request(
+        "PUT",
+        "/latest/api/token",
+        &[("X-aws-ec2-metadata-token-ttl-seconds", "60")],
+    )?;
+    let headers = [("X-aws-ec2-metadata-token", token.as_str())];
+    let get = |path: &str| client.request("GET", &format!("/latest/meta-data/{}", path), &headers);
+    Some(Instance {
+        id: get("instance-id")?,
+        instance_type: get("instance-type").unwrap_or_default(),
+        region: get("placement/region").unwrap_or_default(),
+        zone: get("placement/availability-zone").unwrap_or_default(),
+    })
+}
+
+/// Temporary credentials of the EC2 instance's role, for signing AWS API
+/// requests. They rotate, so callers fetch them again every few minutes.
+pub fn ec2_role_credentials() -> Result<Credentials, String> {
+    let client = Client {
+        address: METADATA_ADDRESS,
+    };
+    let token = client
+        .request(
+            "PUT",
+            "/latest/api/token",
+            &[("X-aws-ec2-metadata-token-ttl-seconds", "60")],
+        )
+        .ok_or("no EC2 instance metadata service")?;
+    let headers = [("X-aws-ec2-metadata-token", token.as_str())];
+    let path = "/latest/meta-data/iam/security-credentials/";
+    let role = client
+        .request("GET", path, &headers)
+        .ok_or("no EC2 instance role")?;
+    let role = role.lines().next().unwrap_or_default();
+    let document = client
+        .request("GET", &format!("{}{}", path, role), &headers)
+        .ok_or_else(|| format!("no credentials for instance role {}", role))?;
+    let field = |name: &str| {
+        json_field(&document, name)
+            .ok_or_else(|| format!("instance role credentials lack {}", name))
+    };
+    Ok(Credentials {
+        access_key_id: field("AccessKeyId")?,
+        secret_access_key: field("SecretAccessKey")?,
+        session_token: Some(field("Token")?),
+    })
+}
+
+/// An OAuth access token of the GCE instance's service account, and how
+/// long it stays valid
+pub fn gce_access_token() -> Result<(String, Duration), String> {
+    let client = Client {
+        address: METADATA_ADDRESS,
+    };
+    let document = gce_get(&client, "instance/service-accounts/default/token")
+        .ok_or("no GCE metadata server or service account")?;
+    let token = json_field(&document, "access_token").ok_or("no access_token in token")?;
+    let expires_in = json_field(&document, "expires_in")
+        .and_then(|secs| secs.parse().ok())
+        .ok_or("no expires_in in token")?;
+    Ok((token, Duration::from_secs(expires_in)))
+}
+
+/// The GCE instance or GKE node we run on, as a Cloud Monitoring monitored
+/// resource
+pub struct MonitoredResource {
+    pub kind: &'static str,
+    pub project_id: String,
+    pub labels: Vec<(&'static str, String)>,
+}
+
+pub fn gce_monitored_resource() -> Result<MonitoredResource, String> {
+    let client = Client {
+        address: METADATA_ADDRESS,
+    };
+    let get = |path: &str| gce_get(&client, path);
+    let project_id = get("project/project-id").ok_or("no GCE metadata server")?;
+    // GKE nodes carry their cluster in the instance attributes
+    if let Some(cluster) = get("instance/attributes/cluster-name") {
+        return Ok(MonitoredResource {
+            kind: "k8s_node",
+            labels: vec![
+                ("project_id", project_id.clone()),
+                (
+                    "location",
+                    get("instance/attributes/cluster-location").unwrap_or_default(),
+                ),
+                ("cluster_name", cluster),
+                ("node_name", get("instance/name").unwrap_or_default()),
+            ],
+            project_id,
+        });
+    }
+    let zone = get("instance/zone").ok_or("no zone in GCE metadata")?;
+    Ok(MonitoredResource {
+        kind: "gce_instance",
+        labels: vec![
+            ("project_id", project_id.clone()),
+            (
+                "instance_id",
+                get("instance/id").ok_or("no id in GCE metadata")?,
+            ),
+            (
+                "zone",
+                zone.rsplit('/').next().unwrap_or_default().to_string(),
+            ),
+        ],
+        project_id,
+    })
+}
+
+/// The first string or integer field called `name` in a JSON document,
+/// which is all the metadata services' small documents need
+pub fn json_field(json: &str, name: &str) -> Option<String> {
+    let start = json.find(&format!("\"{}\"", name))? + name.len() + 2;
+    let rest = json[start..].trim_start().strip_prefix(':')?.trim_start();
+    let Some(rest) = rest.strip_prefix('"') else {
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        return (end > 0).then(|| rest[..end].to_string());
+    };
+    let mut chars = rest.chars();
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                other => value.push(other),
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+fn gce_get(client: &Client, path: &str) -> Option<String> {
+    client.request(
+        "GET",
+        &format!("/computeMetadata/v1/{}", path),
+        &[("Metadata-Flavor", "Google")],
+    )
+}
+
+fn gce(client: &Client) -> Option<Instance> {
+    let get = |path: &str| gce_get(client, &format!("instance/{}", path));
+    // Both are paths like projects/123/zones/europe-west1-b
+    let last = |path: String| path.rsplit('/').next().unwrap_or_default().to_string();
+    let zone = get("zone").map(last).unwrap_or_default();
+    Some(Instance {
+        id: get("id")?,
+        instance_type: get("machine-type").map(last).unwrap_or_default(),
+        region: zone
+            .rsplit_once('-')
+            .map(|(region, _)| region.to_string())
+            .unwrap_or_default(),
+        zone,
+    })
+}
+
+/// A managed identity token of the Azure VM for Azure Monitor, and how
+/// long it stays valid
+pub fn azure_monitor_token() -> Result<(String, Duration), String> {
+    let client = Client {
+        address: METADATA_ADDRESS,
+    };
+    let document = client
+        .request(
+            "GET",
+            "/metadata/identity/oauth2/token?api-version=2018-02-01\
+             &resource=https%3A%2F%2Fmonitoring.azure.com%2F",
+            &[("Metadata", "true")],
+        )
+        .ok_or("no Azure metadata service or managed identity")?;
+    let token = json_field(&document, "access_token").ok_or("no access_token in token")?;
+    let expires_in = json_field(&document, "expires_in")
+        .and_then(|secs| secs.parse().ok())
+        .ok_or("no expires_in in token")?;
+    Ok((token, Duration::from_secs(expires_in)))
+}
+
+/// The Azure VM's region and resource id, which Azure Monitor files its
+/// custom metrics under
+pub fn azure_vm() -> Result<(String, String), String> {
+    let client = Client {
+        address: METADATA_ADDRESS,
+    };
+    let location = azure_get(&client, "location").ok_or("no Azure metadata service")?;
+    let resource_id = azure_get(&client, "resourceId").ok_or("no resourceId in Azure metadata")?;
+    Ok((location, resource_id))
+}
+
+fn azure_get(client: &Client, field: &str) -> Option<String> {
+    client.request(
+        "GET",
+        &format!(
+            "/metadata/instance/compute/{}?api-version=2021-02-01&format=text",
+            field
+        ),
+        &[("Metadata", "true")],
+    )
+}
+
+fn azure(client: &Client) -> Option<Instance> {
+    let get = |field: &str| azure_get(client, field);
+    Some(Instance {
+        id: get("vmId")?,
+        instance_type: get("vmSize").unwrap_or_default(),
+        region: get("location").unwrap_or_default(),
+        zone: get("zone").unwrap_or_default(),
+    })
+}
+
+struct Client {
+    address: SocketAddr,
+}
+
+impl Client {
+    /// The body of a 200 response, trimmed; None on anything else
+    fn request(&self, method: &str, path: &str, headers: &[(&str, &str)]) -> Option<String> {
+        let response =
+            fetch::request(&self.address.to_string(), method, path, headers, TIMEOUT).ok()?;
+        if response.status != 200 {
+            return None;
+        }
+        let body = String::from_utf8_lossy(&response.body);
+        let body = body.trim();
+        (!body.is_empty()).then(|| body.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// Answers `count` requests, by path, like a GCE metadata server
+    fn fake_gce(count: usize) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(count) {
+                let mut stream = stream.unwrap();
+                let mut request = [0u8; 4096];
+                let len = stream.read(&mut request).unwrap();
+                let request = String::from_utf8_lossy(&request[..len]);
+                let path = request.split_whitespace().nth(1).unwrap_or_default();
+                let body = match path {
+                    _ if !request.contains("Metadata-Flavor: Google") => None,
+                    "/computeMetadata/v1/instance/id" => Some("4520031799277581759"),
+                    "/computeMetadata/v1/instance/zone" => {
+                        Some("projects/123/zones/europe-west1-b")
+                    }
+                    "/computeMetadata/v1/instance/machine-type" => {
+                        Some("projects/123/machineTypes/e2-medium")
+                    }
+                    _ => None,
+                };
+                let response = match body {
+                    Some(body) => format!("HTTP/1.0 200 OK\r\n\r\n{}", body),
+                    None => "HTTP/1.0 404 Not Found\r\n\r\n".to_string(),
+                };
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn reads_fields_of_credential_documents() {
+        let document = "{\n  \"Code\" : \"Success\",\n  \"AccessKeyId\" : \"ASIAEXAMPLE\",\n  \"Token\" : \"a\\/b\\\"c\"\n}";
+        assert_eq!(
+            json_field(document, "AccessKeyId").as_deref(),
+            Some("ASIAEXAMPLE")
+        );
+        assert_eq!(json_field(document, "Token").as_deref(), Some("a/b\"c"));
+        assert_eq!(json_field(document, "Expiration"), None);
+        let token = "{\"access_token\":\"ya29.c\",\"expires_in\":3599,\"token_type\":\"Bearer\"}";
+        assert_eq!(json_field(token, "expires_in").as_deref(), Some("3599"));
+    }
+
+    #[test]
+    fn detects_gce_instances() {
+        // The connect check, the EC2 token and the three GCE values
+        let (provider, instance) = detect_at(fake_gce(5)).unwrap();
+        assert_eq!(provider, "GCE");
+        let labels = instance.labels();
+        assert_eq!(labels["instance_id"], "4520031799277581759");
+        assert_eq!(labels["instance_type"], "e2-medium");
+        assert_eq!(labels["region"], "europe-west1");
+        assert_eq!(labels["zone"], "europe-west1-b");
+    }
+}
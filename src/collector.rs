@@ -1,6 +1,91 @@
-use prometheus::Result;
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::proto::MetricType;
+use prometheus::{Registry, Result};
+
+/// Platforms sysinfo supports, used by collectors that rely on it only
+pub const ALL_PLATFORMS: &[&str] = &["linux", "macos", "windows", "freebsd"];
+
+/// A metric family as /metrics-docs describes it
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricDoc {
+    pub name: String,
+    /// `None` for families of custom collectors that only say their type
+    /// once they have samples
+    pub metric_type: Option<MetricType>,
+    pub help: String,
+    pub labels: Vec<String>,
+}
 
 pub trait Collector {
-    fn register_metrics(&self) -> Result<()>;
-    fn collect_metrics(&self);
+    /// Short identifier used in config, admin API and self-metrics labels
+    fn name(&self) -> &'static str;
+
+    /// Register metrics that aren't made with `metrics::builder::metric`,
+    /// which registers its own, with the registry the daemon serves. Use
+    /// `metrics::builder::register`, so a second instance doesn't fail.
+    fn register_metrics(&self, registry: &Registry) -> Result<()>;
+
+    /// Refresh the metrics. Repeated errors back the collector off, see
+    /// `collection.max_backoff`.
+    fn collect_metrics(&self) -> std::result::Result<(), String>;
+
+    /// The prometheus metrics this collector owns
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector>;
+
+    /// Drop the series the metrics hold, e.g. once the collector is shed to
+    /// save memory. Clears the vecs `metrics::builder::metric` built for
+    /// `registry`; collectors keeping series of their own clear them too.
+    fn reset_metrics(&self, registry: &Registry) {
+        crate::metrics::builder::reset(registry, &self.metrics());
+    }
+
+    /// Operating systems (as in `std::env::consts::OS`) the collector works on
+    fn platforms(&self) -> &'static [&'static str] {
+        ALL_PLATFORMS
+    }
+
+    /// Why this host lacks what the collector reads, such as a procfs file
+    /// or a program; checked once at startup, which leaves the collector
+    /// out instead of failing every cycle
+    fn unavailable(&self) -> Option<String> {
+        None
+    }
+
+    /// Fully-qualified names of the metric families this collector exports
+    fn metric_names(&self) -> Vec<String> {
+        self.metrics()
+            .iter()
+            .flat_map(|metric| metric.desc())
+            .map(|desc| desc.fq_name.clone())
+            .collect()
+    }
+
+    /// The metric families this collector exports, from their descriptors,
+    /// so the catalog can't drift from the code
+    fn metric_docs(&self) -> Vec<MetricDoc> {
+        self.metrics()
+            .iter()
+            .flat_map(|metric| {
+                let families = metric.collect();
+                metric
+                    .desc()
+                    .into_iter()
+                    .map(|desc| MetricDoc {
+                        name: desc.fq_name.clone(),
+                        metric_type: families
+                            .iter()
+                            .find(|family| family.name() == desc.fq_name)
+                            .map(|family| family.get_field_type()),
+                        help: desc.help.clone(),
+                        labels: desc
+                            .const_label_pairs
+                            .iter()
+                            .map(|pair| pair.name().to_string())
+                            .chain(desc.variable_labels.iter().cloned())
+                            .collect(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
 }
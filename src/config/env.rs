@@ -0,0 +1,204 @@
+//! `METRIXD_*` environment variables, so containers can be configured
+//! without mounting a file. They override the config file and are
+//! overridden by command line flags. Empty values are ignored.
+
+use std::net::SocketAddr;
+
+use super::{parse_duration, valid_label_name, CollectorConfig, Config, ConfigError};
+use crate::metrics::COLLECTOR_NAMES;
+
+const PREFIX: &str = "METRIXD_";
+
+/// Apply the `METRIXD_*` variables among `vars`, reporting unknown ones and
+/// invalid values like the config file does
+pub fn apply(
+    config: &mut Config,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<(), ConfigError> {
+    let mut vars: Vec<(String, String)> = vars
+        .into_iter()
+        .filter(|(name, value)| name.starts_with(PREFIX) && !value.is_empty())
+        .collect();
+    // METRIXD_PORT sorts after METRIXD_LISTEN_ADDRESS, so it replaces the port
+    // of whichever address is in effect
+    vars.sort();
+
+    let mut errors = Vec::new();
+    for (name, value) in vars {
+        if let Err(e) = apply_var(config, &name[PREFIX.len()..], &value) {
+            errors.push(format!("{}: {}", name, e));
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ConfigError { errors })
+    }
+}
+
+fn apply_var(config: &mut Config, name: &str, value: &str) -> Result<(), String> {
+    match name {
+        "LISTEN_ADDRESS" => {
+            config.web.listen_address = value
+                .parse()
+                .map_err(|e| format!("invalid address '{}': {}", value, e))?
+        }
+        "PORT" => {
+            let port = value
+                .parse()
+                .map_err(|_| format!("invalid port '{}'", value))?;
+            config.web.listen_address = SocketAddr::new(config.web.listen_address.ip(), port);
+        }
+        "INTERVAL" => config.collection.interval = parse_duration(value)?,
+        // Comma-separated name=value pairs, merged over `[labels]`
+        "LABELS" => {
+            for pair in list(value) {
+                let (label, label_value) = pair
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected name=value, found '{}'", pair))?;
+                if !valid_label_name(label) {
+                    return Err(format!("invalid label name '{}'", label));
+                }
+                config
+                    .labels
+                    .insert(label.to_string(), label_value.to_string());
+            }
+        }
+        // Only these collectors run
+        "COLLECTORS" => {
+            let enabled = collector_names(value)?;
+            for name in COLLECTOR_NAMES {
+                config.collectors.insert(
+                    name.to_string(),
+                    CollectorConfig {
+                        enabled: enabled.contains(name),
+                    },
+                );
+            }
+        }
+        "DISABLED_COLLECTORS" => {
+            for name in collector_names(value)? {
+                config
+                    .collectors
+                    .insert(name.to_string(), CollectorConfig { enabled: false });
+            }
+        }
+        _ => return Err("unknown variable".to_string()),
+    }
+    Ok(())
+}
+
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+fn collector_names(value: &str) -> Result<Vec<&str>, String> {
+    list(value)
+        .map(|name| {
+            if COLLECTOR_NAMES.contains(&name) {
+                Ok(name)
+            } else {
+                Err(format!(
+                    "unknown collector '{}' (available: {})",
+                    name,
+                    COLLECTOR_NAMES.join(", ")
+                ))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn overrides_file_values() {
+        let mut config = Config::parse("[web]\nlisten_address = \"127.0.0.1:9100\"\n").unwrap();
+        apply(
+            &mut config,
+            vars(&[
+                ("METRIXD_PORT", "9200"),
+                ("METRIXD_INTERVAL", "15s"),
+                ("METRIXD_LABELS", "env=prod, region=eu-west-1"),
+                ("METRIXD_DISABLED_COLLECTORS", "disk"),
+                ("HOME", "/root"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(config.web.listen_address.to_string(), "127.0.0.1:9200");
+        assert_eq!(config.collection.interval.as_secs(), 15);
+        assert_eq!(config.labels["env"], "prod");
+        assert_eq!(config.labels["region"], "eu-west-1");
+        assert!(!config.collector("disk").enabled);
+        assert!(config.collector("cpu").enabled);
+    }
+
+    #[test]
+    fn port_applies_to_listen_address_from_env() {
+        let mut config = Config::default();
+        apply(
+            &mut config,
+            vars(&[
+                ("METRIXD_PORT", "9300"),
+                ("METRIXD_LISTEN_ADDRESS", "[::1]:9100"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.web.listen_address.to_string(), "[::1]:9300");
+    }
+
+    #[test]
+    fn collectors_enables_only_the_listed_ones() {
+        let mut config = Config::default();
+        apply(&mut config, vars(&[("METRIXD_COLLECTORS", "cpu,memory")])).unwrap();
+        assert!(config.collector("cpu").enabled);
+        assert!(config.collector("memory").enabled);
+        assert!(!config.collector("network").enabled);
+    }
+
+    #[test]
+    fn ignores_empty_values() {
+        let mut config = Config::default();
+        apply(&mut config, vars(&[("METRIXD_PORT", "")])).unwrap();
+        assert_eq!(config.web.listen_address.port(), 9100);
+    }
+
+    #[test]
+    fn reports_every_invalid_variable() {
+        let mut config = Config::default();
+        let e = apply(
+            &mut config,
+            vars(&[
+                ("METRIXD_PORT", "http"),
+                ("METRIXD_LABELS", "__name__=x"),
+                ("METRIXD_COLLECTORS", "cpu,gpu"),
+                ("METRIXD_LISTEN_ADRESS", "0.0.0.0:9100"),
+            ]),
+        )
+        .unwrap_err();
+        assert_eq!(
+            e.errors,
+            vec![
+                format!(
+                    "METRIXD_COLLECTORS: unknown collector 'gpu' (available: {})",
+                    COLLECTOR_NAMES.join(", ")
+                ),
+                "METRIXD_LABELS: invalid label name '__name__'".to_string(),
+                "METRIXD_LISTEN_ADRESS: unknown variable".to_string(),
+                "METRIXD_PORT: invalid port 'http'".to_string(),
+            ]
+        );
+    }
+}
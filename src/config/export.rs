@@ -0,0 +1,642 @@
+//! `[export.<name>]` tables, one per push exporter. Each has the common
+//! `interval` and `include` keys plus its own settings.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use regex::Regex;
+
+use super::Section;
+
+/// Push exporters, each enabled by the presence of its table. Applied at
+/// startup only.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExportConfig {
+    pub datadog: Option<DatadogConfig>,
+    pub cloudwatch: Option<CloudWatchConfig>,
+    pub google_cloud: Option<GoogleCloudConfig>,
+    pub azure_monitor: Option<AzureMonitorConfig>,
+    pub mqtt: Option<MqttConfig>,
+    pub kafka: Option<KafkaConfig>,
+    pub nats: Option<NatsConfig>,
+    pub zabbix: Option<ZabbixConfig>,
+    pub collectd: Option<CollectdConfig>,
+    pub telegraf: Option<TelegrafConfig>,
+    pub victoriametrics: Option<VictoriaMetricsConfig>,
+    pub pushgateway: Option<PushgatewayConfig>,
+    pub remote_write: Option<RemoteWriteConfig>,
+    pub leader_election: Option<LeaderElectionConfig>,
+}
+
+/// Settings every exporter has
+#[derive(Debug, Clone, Default)]
+pub struct ExportCommon {
+    /// Push at most this often; every collection cycle when unset
+    pub interval: Option<Duration>,
+    /// Only samples whose metric name fully matches one of these are
+    /// pushed; all of them when empty
+    pub include: Vec<Regex>,
+}
+
+impl PartialEq for ExportCommon {
+    fn eq(&self, other: &Self) -> bool {
+        let patterns = |common: &ExportCommon| -> Vec<String> {
+            common
+                .include
+                .iter()
+                .map(|re| re.as_str().to_string())
+                .collect()
+        };
+        self.interval == other.interval && patterns(self) == patterns(other)
+    }
+}
+
+/// A lease on storage the agents of a cluster share, so that only its
+/// holder pushes the metrics every one of them reports alike
+#[derive(Debug, Clone)]
+pub struct LeaderElectionConfig {
+    /// Where the lease is kept; opened at startup
+    pub lock_file: PathBuf,
+    /// How long the lease lasts unless its holder renews it, which it does
+    /// every collection cycle
+    pub lease: Duration,
+    /// Only the leader pushes samples whose metric name fully matches one
+    /// of these
+    pub cluster_metrics: Vec<Regex>,
+}
+
+impl PartialEq for LeaderElectionConfig {
+    fn eq(&self, other: &Self) -> bool {
+        let patterns = |config: &LeaderElectionConfig| -> Vec<String> {
+            config
+                .cluster_metrics
+                .iter()
+                .map(|re| re.as_str().to_string())
+                .collect()
+        };
+        self.lock_file == other.lock_file
+            && self.lease == other.lease
+            && patterns(self) == patterns(other)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatadogConfig {
+    pub common: ExportCommon,
+    pub target: DatadogTarget,
+    /// Prepended to every metric name, e.g. `metrixd.`
+    pub prefix: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DatadogTarget {
+    /// A local agent's DogStatsD UDP port
+    Dogstatsd(String),
+    /// The series API, through a TLS-terminating proxy
+    Api { url: String, api_key: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CloudWatchConfig {
+    /// `include` is required, as CloudWatch bills every metric
+    pub common: ExportCommon,
+    /// Taken from AWS_REGION or AWS_DEFAULT_REGION when unset
+    pub region: Option<String>,
+    pub namespace: String,
+    /// The labels sent as dimensions, each optionally renamed with
+    /// `label=Dimension`; every label when empty
+    pub dimensions: Vec<(String, String)>,
+    /// `http://monitoring.<region>.amazonaws.com/` when unset, which only
+    /// works through a TLS-terminating proxy
+    pub url: Option<String>,
+    /// Static credentials; otherwise the environment's or the EC2
+    /// instance role's are used
+    pub credentials: Option<(String, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GoogleCloudConfig {
+    /// `interval` defaults to 60s, as a series takes at most one point
+    /// every 5s
+    pub common: ExportCommon,
+    /// The project written to; the instance's own when unset
+    pub project_id: Option<String>,
+    /// Prepended to metric names to make the metric type
+    pub metric_prefix: String,
+    /// `http://monitoring.googleapis.com/` when unset, which only works
+    /// through a TLS-terminating proxy
+    pub url: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AzureMonitorConfig {
+    /// `interval` defaults to 60s, Azure Monitor's resolution
+    pub common: ExportCommon,
+    pub namespace: String,
+    /// The VM's own when unset
+    pub region: Option<String>,
+    /// The VM's own when unset
+    pub resource_id: Option<String>,
+    /// `http://<region>.monitoring.azure.com/` when unset, which only
+    /// works through a TLS-terminating proxy
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MqttConfig {
+    pub common: ExportCommon,
+    /// `host:port` of the broker, plain MQTT 3.1.1
+    pub broker: String,
+    /// Where each sample goes; `{name}` and `{<label>}` are replaced by
+    /// the metric name and label values
+    pub topic: String,
+    /// Empty lets the broker pick one
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub retain: bool,
+}
+
+/// How the streaming exporters encode each sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamFormat {
+    /// The JSON object the MQTT exporter publishes
+    Json,
+    /// A `metrixd.v1.Sample` from proto/metrixd.proto
+    Protobuf,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KafkaConfig {
+    pub common: ExportCommon,
+    /// `host:port` of bootstrap brokers, tried in order
+    pub brokers: Vec<String>,
+    pub topic: String,
+    pub format: StreamFormat,
+    pub client_id: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NatsConfig {
+    pub common: ExportCommon,
+    /// `host:port` of the server
+    pub server: String,
+    /// Where each sample goes, filled in like the MQTT topic
+    pub subject: String,
+    pub format: StreamFormat,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZabbixConfig {
+    pub common: ExportCommon,
+    /// `host:port` of the Zabbix server or proxy's trapper
+    pub server: String,
+    /// The host the items belong to, as named in Zabbix; the instance ID
+    /// when unset
+    pub host: Option<String>,
+    /// Item key templates by metric name, filled in with `{<label>}`;
+    /// `name[label values...]` for the others
+    pub keys: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollectdConfig {
+    pub common: ExportCommon,
+    /// `host:port` of a collectd network listener
+    pub server: String,
+    /// The host values are reported for; the instance ID when unset
+    pub host: Option<String>,
+    pub plugin: String,
+    /// Sign packets (`SecurityLevel Sign`) as this user
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TelegrafConfig {
+    pub common: ExportCommon,
+    pub address: TelegrafAddress,
+}
+
+/// Where a socket_listener input listens, in its `service_address` syntax
+#[derive(Debug, Clone, PartialEq)]
+pub enum TelegrafAddress {
+    /// `tcp://host:port`
+    Tcp(String),
+    /// `unix:///path`
+    Unix(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VictoriaMetricsConfig {
+    pub common: ExportCommon,
+    /// Where `/api/v1/import/prometheus` is, e.g. a single-node
+    /// `http://victoriametrics:8428` or vminsert's `/insert/0/prometheus`
+    pub url: String,
+    /// Added to every sample by VictoriaMetrics, replacing labels of the
+    /// same name
+    pub extra_labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PushgatewayConfig {
+    pub common: ExportCommon,
+    pub url: String,
+    /// The `job` of the pushed group; `instance` is `instance.id`
+    pub job: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteWriteConfig {
+    pub common: ExportCommon,
+    /// The receiver's endpoint, e.g. Prometheus' `/api/v1/write`
+    pub url: String,
+}
+
+/// A `host:port`, or an error
+pub(super) fn check_address(key: &str, address: &str, errors: &mut Vec<String>) {
+    let port = address
+        .rsplit_once(':')
+        .map(|(_, port)| port.parse::<u16>());
+    if !matches!(port, Some(Ok(_))) {
+        errors.push(format!("{}: {}: expected host:port", key, address));
+    }
+}
+
+fn stream_format(section: &mut Section, errors: &mut Vec<String>) -> StreamFormat {
+    match section.string("format", errors).as_deref() {
+        None | Some("json") => StreamFormat::Json,
+        Some("protobuf") => StreamFormat::Protobuf,
+        Some(other) => {
+            errors.push(format!(
+                "{}: {}: expected json or protobuf",
+                section.key_path("format"),
+                other
+            ));
+            StreamFormat::Json
+        }
+    }
+}
+
+pub(super) fn parse(
+    mut export: Section,
+    secret_files: &mut Vec<std::path::PathBuf>,
+    errors: &mut Vec<String>,
+) -> ExportConfig {
+    let mut config = ExportConfig::default();
+    for name in export.keys() {
+        let mut section = export.section(&name, errors);
+        if name == "leader_election" {
+            // Not an exporter, so without interval and include
+            let lock_file = section.string("lock_file", errors).map(PathBuf::from);
+            let lease = section
+                .duration("lease", errors)
+                .unwrap_or(Duration::from_secs(60));
+            let cluster_metrics = section.regex_list("cluster_metrics", errors);
+            match lock_file {
+                Some(lock_file) if !lock_file.is_absolute() => errors.push(format!(
+                    "export.leader_election.lock_file: {} is not an absolute path",
+                    lock_file.display()
+                )),
+                Some(lock_file) => {
+                    config.leader_election = Some(LeaderElectionConfig {
+                        lock_file,
+                        lease,
+                        cluster_metrics,
+                    })
+                }
+                None => errors.push("export.leader_election: lock_file is required".to_string()),
+            }
+            section.finish(errors);
+            continue;
+        }
+        let common = ExportCommon {
+            interval: section.duration("interval", errors),
+            include: section.regex_list("include", errors),
+        };
+        match name.as_str() {
+            "datadog" => {
+                let url = section.url("api_url", errors);
+                let api_key = section.secret("api_key", secret_files, errors);
+                let address = section.string("dogstatsd_address", errors);
+                let target = match (url, api_key, address) {
+                    (Some(_), _, Some(_)) => {
+                        errors.push(
+                            "export.datadog: api_url cannot be combined with dogstatsd_address"
+                                .to_string(),
+                        );
+                        None
+                    }
+                    (Some(url), Some(api_key), None) => Some(DatadogTarget::Api { url, api_key }),
+                    (Some(_), None, None) => {
+                        errors.push("export.datadog: api_url needs an api_key".to_string());
+                        None
+                    }
+                    (None, Some(_), _) => {
+                        errors.push("export.datadog: api_key needs an api_url".to_string());
+                        None
+                    }
+                    (None, None, address) => Some(DatadogTarget::Dogstatsd(
+                        address.unwrap_or_else(|| "127.0.0.1:8125".to_string()),
+                    )),
+                };
+                let prefix = section.string("prefix", errors).unwrap_or_default();
+                if let Some(target) = target {
+                    config.datadog = Some(DatadogConfig {
+                        common,
+                        target,
+                        prefix,
+                    });
+                }
+            }
+            "cloudwatch" => {
+                let mut common = common;
+                if common.include.is_empty() {
+                    errors.push(
+                        "export.cloudwatch: include is required, as CloudWatch bills per metric"
+                            .to_string(),
+                    );
+                }
+                common.interval = common.interval.or(Some(Duration::from_secs(60)));
+                let region = section.string("region", errors);
+                let namespace = section
+                    .string("namespace", errors)
+                    .unwrap_or_else(|| "metrixd".to_string());
+                let dimensions = section
+                    .string_list("dimensions", errors)
+                    .into_iter()
+                    .map(|dimension| match dimension.split_once('=') {
+                        Some((label, name)) => (label.to_string(), name.to_string()),
+                        None => (dimension.clone(), dimension),
+                    })
+                    .collect();
+                let url = section.url("url", errors);
+                let access_key_id = section.string("access_key_id", errors);
+                let secret_access_key = section.secret("secret_access_key", secret_files, errors);
+                let credentials = match (access_key_id, secret_access_key) {
+                    (Some(id), Some(secret)) => Some((id, secret)),
+                    (None, None) => None,
+                    _ => {
+                        errors.push(
+                            "export.cloudwatch: access_key_id and secret_access_key go together"
+                                .to_string(),
+                        );
+                        None
+                    }
+                };
+                config.cloudwatch = Some(CloudWatchConfig {
+                    common,
+                    region,
+                    namespace,
+                    dimensions,
+                    url,
+                    credentials,
+                });
+            }
+            "google_cloud" => {
+                let mut common = common;
+                common.interval = common.interval.or(Some(Duration::from_secs(60)));
+                config.google_cloud = Some(GoogleCloudConfig {
+                    common,
+                    project_id: section.string("project_id", errors),
+                    metric_prefix: section
+                        .string("metric_prefix", errors)
+                        .unwrap_or_else(|| "custom.googleapis.com/metrixd/".to_string()),
+                    url: section
+                        .url("url", errors)
+                        .unwrap_or_else(|| "http://monitoring.googleapis.com/".to_string()),
+                });
+            }
+            "azure_monitor" => {
+                let mut common = common;
+                common.interval = common.interval.or(Some(Duration::from_secs(60)));
+                config.azure_monitor = Some(AzureMonitorConfig {
+                    common,
+                    namespace: section
+                        .string("namespace", errors)
+                        .unwrap_or_else(|| "metrixd".to_string()),
+                    region: section.string("region", errors),
+                    resource_id: section.string("resource_id", errors),
+                    url: section.url("url", errors),
+                });
+            }
+            "mqtt" => {
+                let broker = section
+                    .string("broker", errors)
+                    .unwrap_or_else(|| "127.0.0.1:1883".to_string());
+                check_address("export.mqtt.broker", &broker, errors);
+                let topic = section
+                    .string("topic", errors)
+                    .unwrap_or_else(|| "metrixd/{name}".to_string());
+                if topic.contains(['+', '#']) {
+                    errors.push(format!(
+                        "export.mqtt.topic: {}: wildcards cannot be published to",
+                        topic
+                    ));
+                }
+                let username = section.string("username", errors);
+                let password = section.secret("password", secret_files, errors);
+                if password.is_some() && username.is_none() {
+                    errors.push("export.mqtt: password needs a username".to_string());
+                }
+                config.mqtt = Some(MqttConfig {
+                    common,
+                    broker,
+                    topic,
+                    client_id: section.string("client_id", errors).unwrap_or_default(),
+                    username,
+                    password,
+                    retain: section.bool("retain", errors).unwrap_or(false),
+                });
+            }
+            "kafka" => {
+                let brokers = section.string_list("brokers", errors);
+                if brokers.is_empty() {
+                    errors.push("export.kafka: brokers is required".to_string());
+                }
+                for broker in &brokers {
+                    check_address("export.kafka.brokers", broker, errors);
+                }
+                let topic = section.string("topic", errors);
+                if topic.is_none() {
+                    errors.push("export.kafka: topic is required".to_string());
+                }
+                let format = stream_format(&mut section, errors);
+                config.kafka = Some(KafkaConfig {
+                    common,
+                    brokers,
+                    topic: topic.unwrap_or_default(),
+                    format,
+                    client_id: section
+                        .string("client_id", errors)
+                        .unwrap_or_else(|| "metrixd".to_string()),
+                });
+            }
+            "nats" => {
+                let server = section
+                    .string("server", errors)
+                    .unwrap_or_else(|| "127.0.0.1:4222".to_string());
+                check_address("export.nats.server", &server, errors);
+                let subject = section
+                    .string("subject", errors)
+                    .unwrap_or_else(|| "metrixd.{name}".to_string());
+                if subject.contains(['*', '>', ' ']) {
+                    errors.push(format!(
+                        "export.nats.subject: {}: wildcards cannot be published to",
+                        subject
+                    ));
+                }
+                let format = stream_format(&mut section, errors);
+                let username = section.string("username", errors);
+                let password = section.secret("password", secret_files, errors);
+                let token = section.secret("token", secret_files, errors);
+                if password.is_some() != username.is_some() {
+                    errors.push("export.nats: username and password go together".to_string());
+                }
+                config.nats = Some(NatsConfig {
+                    common,
+                    server,
+                    subject,
+                    format,
+                    username,
+                    password,
+                    token,
+                });
+            }
+            "zabbix" => {
+                let server = section
+                    .string("server", errors)
+                    .unwrap_or_else(|| "127.0.0.1:10051".to_string());
+                check_address("export.zabbix.server", &server, errors);
+                let host = section.string("host", errors);
+                let mut keys_section = section.section("keys", errors);
+                let mut keys = BTreeMap::new();
+                for name in keys_section.keys() {
+                    if let Some(key) = keys_section.string(&name, errors) {
+                        keys.insert(name, key);
+                    }
+                }
+                keys_section.finish(errors);
+                config.zabbix = Some(ZabbixConfig {
+                    common,
+                    server,
+                    host,
+                    keys,
+                });
+            }
+            "collectd" => {
+                let server = section
+                    .string("server", errors)
+                    .unwrap_or_else(|| "127.0.0.1:25826".to_string());
+                check_address("export.collectd.server", &server, errors);
+                let username = section.string("username", errors);
+                let password = section.secret("password", secret_files, errors);
+                if password.is_some() != username.is_some() {
+                    errors.push("export.collectd: username and password go together".to_string());
+                }
+                config.collectd = Some(CollectdConfig {
+                    common,
+                    server,
+                    host: section.string("host", errors),
+                    plugin: section
+                        .string("plugin", errors)
+                        .unwrap_or_else(|| "metrixd".to_string()),
+                    username,
+                    password,
+                });
+            }
+            "telegraf" => {
+                let address = section
+                    .string("address", errors)
+                    .unwrap_or_else(|| "tcp://127.0.0.1:8094".to_string());
+                let address = match address.split_once("://") {
+                    Some(("tcp", host_port)) => {
+                        check_address("export.telegraf.address", host_port, errors);
+                        Some(TelegrafAddress::Tcp(host_port.to_string()))
+                    }
+                    Some(("unix", path)) if path.starts_with('/') => {
+                        Some(TelegrafAddress::Unix(PathBuf::from(path)))
+                    }
+                    _ => {
+                        errors.push(format!(
+                            "export.telegraf.address: {}: expected tcp://host:port or unix:///path",
+                            address
+                        ));
+                        None
+                    }
+                };
+                if let Some(address) = address {
+                    config.telegraf = Some(TelegrafConfig { common, address });
+                }
+            }
+            "victoriametrics" => {
+                let url = section.url("url", errors);
+                if url.is_none() {
+                    errors.push("export.victoriametrics: url is required".to_string());
+                }
+                let mut labels_section = section.section("extra_labels", errors);
+                let mut extra_labels = BTreeMap::new();
+                for name in labels_section.keys() {
+                    if !super::valid_label_name(&name) {
+                        errors.push(format!(
+                            "export.victoriametrics.extra_labels.{}: invalid label name",
+                            name
+                        ));
+                    }
+                    if let Some(value) = labels_section.string(&name, errors) {
+                        extra_labels.insert(name, value);
+                    }
+                }
+                labels_section.finish(errors);
+                config.victoriametrics = Some(VictoriaMetricsConfig {
+                    common,
+                    url: url.unwrap_or_default(),
+                    extra_labels,
+                });
+            }
+            "pushgateway" => {
+                let url = section.url("url", errors);
+                if url.is_none() {
+                    errors.push("export.pushgateway: url is required".to_string());
+                }
+                let job = section
+                    .string("job", errors)
+                    .unwrap_or_else(|| "metrixd".to_string());
+                if job.is_empty() {
+                    errors.push("export.pushgateway.job: must not be empty".to_string());
+                }
+                config.pushgateway = Some(PushgatewayConfig {
+                    common,
+                    url: url.unwrap_or_default(),
+                    job,
+                });
+            }
+            "remote_write" => {
+                let url = section.url("url", errors);
+                if url.is_none() {
+                    errors.push("export.remote_write: url is required".to_string());
+                }
+                config.remote_write = Some(RemoteWriteConfig {
+                    common,
+                    url: url.unwrap_or_default(),
+                });
+            }
+            _ => {
+                errors.push(format!(
+                    "export.{}: unknown exporter (available: azure_monitor, cloudwatch, \
+                     collectd, datadog, google_cloud, kafka, mqtt, nats, pushgateway, \
+                     remote_write, telegraf, victoriametrics, zabbix; or leader_election)",
+                    name
+                ));
+                continue;
+            }
+        }
+        section.finish(errors);
+    }
+    export.finish(errors);
+    config
+}
@@ -0,0 +1,1613 @@
+mod env;
+mod export;
+mod parser;
+mod schema;
+mod services;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use regex::Regex;
+
+use crate::metrics::COLLECTOR_NAMES;
+use crate::script::Expression;
+
+pub use export::{
+    AzureMonitorConfig, CloudWatchConfig, CollectdConfig, DatadogConfig, DatadogTarget,
+    ExportCommon, ExportConfig, GoogleCloudConfig, KafkaConfig, LeaderElectionConfig, MqttConfig,
+    NatsConfig, PushgatewayConfig, RemoteWriteConfig, StreamFormat, TelegrafAddress,
+    TelegrafConfig, VictoriaMetricsConfig, ZabbixConfig,
+};
+pub use parser::{Table, Value};
+pub use schema::{default_toml, json_schema};
+pub use services::{
+    HaproxyConfig, HaproxyStats, MysqlConfig, NginxConfig, PostgresConfig, RedisConfig,
+    ServicesConfig,
+};
+
+/// Top-level configuration, loaded from the file passed via `--config.file`.
+/// Every field has a default so metrixd runs without a config file.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub web: WebConfig,
+    pub grpc: GrpcConfig,
+    pub mdns: MdnsConfig,
+    pub cloud: CloudConfig,
+    pub instance: InstanceConfig,
+    pub limits: LimitsConfig,
+    pub federation: FederationConfig,
+    /// Local exporters whose series are served on /metrics too, keyed by
+    /// name. Applied at startup only.
+    pub proxy: BTreeMap<String, ProxyTarget>,
+    pub probe: ProbeConfig,
+    pub export: ExportConfig,
+    pub services: ServicesConfig,
+    pub sysctl: SysctlConfig,
+    /// The package update collector runs only when `[packages]` is present
+    pub packages: Option<PackagesConfig>,
+    pub fsprobe: FsprobeConfig,
+    pub dirsize: DirsizeConfig,
+    pub freshness: FreshnessConfig,
+    pub logs: LogsConfig,
+    pub sockets: SocketsConfig,
+    pub thresholds: ThresholdsConfig,
+    pub memory: MemoryConfig,
+    pub processes: ProcessesConfig,
+    pub collection: CollectionConfig,
+    pub metrics: MetricsConfig,
+    /// Subsets of /metrics served on `/metrics/<name>`, keyed by name
+    pub views: BTreeMap<String, ViewConfig>,
+    pub security: SecurityConfig,
+    pub plugins: PluginsConfig,
+    pub debug: DebugConfig,
+    /// Bucket overrides keyed by histogram name. Applied at startup only.
+    pub histograms: BTreeMap<String, Vec<f64>>,
+    /// Added to every exposed series that doesn't have a label of that name
+    pub labels: BTreeMap<String, String>,
+    /// Per-collector settings keyed by collector name
+    pub collectors: BTreeMap<String, CollectorConfig>,
+    /// Seed for simulated values, set by `--demo`; real data when `None`
+    pub demo: Option<u64>,
+    /// Gauges computed by the scripts collector, keyed by metric name.
+    /// Applied at startup only.
+    pub scripts: BTreeMap<String, ScriptConfig>,
+    /// Files secrets were read from via `*_file` keys, which stay readable
+    /// under Landlock so reloads pick up rotated secrets
+    pub secret_files: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebConfig {
+    pub listen_address: SocketAddr,
+    /// Enables the /-/reload and /-/quit endpoints
+    pub enable_lifecycle: bool,
+    /// Enables the /admin/collectors endpoints
+    pub enable_admin_api: bool,
+    /// When set, admin endpoints require `Authorization: Bearer <token>`
+    pub admin_token: Option<String>,
+    /// When set, /metrics without a view, /probe, /status, /metrics-docs
+    /// and the gRPC service require `Authorization: Bearer <token>`.
+    /// Required once views have tokens.
+    pub metrics_token: Option<String>,
+    /// Print a line per request with the client address
+    pub access_log: bool,
+    /// Requests per second each client address may make; unlimited if unset
+    pub rate_limit: Option<u32>,
+    /// Requests a client may make at once before `rate_limit` applies;
+    /// defaults to one second's worth
+    pub rate_limit_burst: Option<u32>,
+    /// Accept HTTP/2 with prior knowledge (h2c) next to HTTP/1.1. This and
+    /// the settings below are applied at startup only.
+    pub http2: bool,
+    /// Keep HTTP/1.1 connections open between scrapes
+    pub keep_alive: bool,
+    /// Ping idle HTTP/2 connections this often, closing unanswered ones
+    pub http2_keep_alive_interval: Option<Duration>,
+    /// Streams one HTTP/2 connection may have open; unlimited if unset
+    pub http2_max_concurrent_streams: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrpcConfig {
+    pub listen_address: Option<SocketAddr>,
+}
+
+/// Advertising /metrics over multicast DNS. Applied at startup only.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MdnsConfig {
+    pub enabled: bool,
+    /// Service instance name; the instance ID when unset
+    pub instance: Option<String>,
+}
+
+/// Applied at startup only
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CloudConfig {
+    /// Label series with the instance's ID, type, region and zone from the
+    /// EC2, GCE or Azure metadata service
+    pub metadata_labels: bool,
+    /// Look the instance up for `node_identity_info` only
+    pub identity_info: bool,
+}
+
+/// What this agent calls itself (see `identity`)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InstanceConfig {
+    /// The host name, then the machine ID when unset
+    pub id: Option<String>,
+    /// Label every series with the identity under this name
+    pub label: Option<String>,
+}
+
+/// Bounds on the agent's own resource use
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LimitsConfig {
+    /// `limits.max_rss_mib` in bytes: collectors are shed near it
+    pub max_rss: Option<u64>,
+}
+
+/// Other metrixd agents whose series are re-exposed by this one. Applied at
+/// startup only.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FederationConfig {
+    /// `host:port` of each peer's web listener
+    pub peers: Vec<String>,
+    /// How long one scrape of a peer may take
+    pub timeout: Duration,
+}
+
+impl Default for FederationConfig {
+    fn default() -> Self {
+        FederationConfig {
+            peers: Vec::new(),
+            timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+/// A local exporter, such as an app that only listens on 127.0.0.1, whose
+/// series the proxy collector merges into /metrics
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyTarget {
+    /// Where its metrics are, in the text format
+    pub url: String,
+    /// Prepended to every metric name
+    pub prefix: String,
+    /// Added to every series; a label of the same name is kept as
+    /// `exported_<name>`
+    pub labels: BTreeMap<String, String>,
+    /// How long one scrape may take
+    pub timeout: Duration,
+}
+
+/// The /probe endpoint, which checks remote targets on behalf of the scraper
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeConfig {
+    pub enabled: bool,
+    /// Selected with the `module` parameter, keyed by name
+    pub modules: BTreeMap<String, ProbeModule>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Prober {
+    /// Connect to `host:port`
+    Tcp,
+    /// GET a plain HTTP URL and expect a 2xx status
+    Http,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeModule {
+    pub prober: Prober,
+    pub timeout: Duration,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        let module = |prober| ProbeModule {
+            prober,
+            timeout: Duration::from_secs(5),
+        };
+        ProbeConfig {
+            enabled: false,
+            modules: BTreeMap::from([
+                ("http_2xx".to_string(), module(Prober::Http)),
+                ("tcp_connect".to_string(), module(Prober::Tcp)),
+            ]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollectionConfig {
+    pub interval: Duration,
+    /// The first cycle is delayed by a random part of this
+    pub jitter: Duration,
+    /// Start cycles on wall-clock multiples of the interval
+    pub align: bool,
+    /// Longest a failing collector waits between attempts
+    pub max_backoff: Duration,
+    /// Stretch the interval while idle or on battery and shorten it under
+    /// load (see `schedule`)
+    pub adaptive: bool,
+    /// Adaptive bounds; half and four times the interval when unset
+    pub min_interval: Option<Duration>,
+    pub max_interval: Option<Duration>,
+}
+
+/// Identity to switch to after startup. Applied at startup only.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SecurityConfig {
+    /// User name or uid to run as once ports are bound
+    pub user: Option<String>,
+    /// Group name or gid; defaults to the user's primary group
+    pub group: Option<String>,
+    /// Confine the filesystem to read-only /proc, /sys and the config file
+    pub landlock: bool,
+    /// Further paths readable under Landlock, e.g. devices some collectors need
+    pub landlock_read_paths: Vec<String>,
+    /// Block syscalls the daemon never needs once it is running
+    pub seccomp: bool,
+}
+
+/// Collector plugins to load. Applied at startup only.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PluginsConfig {
+    /// Directory whose shared libraries and WASM modules are loaded as
+    /// collector plugins
+    pub directory: Option<PathBuf>,
+    /// Files and directories below which WASM plugins may read
+    pub wasm_read_paths: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DebugConfig {
+    /// Where SIGUSR1 writes the exposition; stdout when unset
+    pub dump_directory: Option<PathBuf>,
+}
+
+/// Kernel parameters the sysctl collector exports. Applied at startup only.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SysctlConfig {
+    /// Dotted names such as `vm.swappiness`
+    pub names: Vec<String>,
+}
+
+impl Default for SysctlConfig {
+    fn default() -> Self {
+        let names = [
+            "fs.file-max",
+            "kernel.pid_max",
+            "net.core.somaxconn",
+            "net.ipv4.ip_forward",
+            "net.ipv4.tcp_congestion_control",
+            "vm.overcommit_memory",
+            "vm.swappiness",
+        ];
+        SysctlConfig {
+            names: names.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+}
+
+/// How often apt, dnf or zypper is asked for updates. Applied at startup
+/// only.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackagesConfig {
+    pub interval: Duration,
+    /// After this the package manager is killed
+    pub timeout: Duration,
+}
+
+/// Directories the fsprobe collector writes a probe file to. Applied at
+/// startup only.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FsprobeConfig {
+    pub paths: Vec<PathBuf>,
+    /// A probe running longer than this counts as stalled
+    pub timeout: Duration,
+}
+
+impl Default for FsprobeConfig {
+    fn default() -> Self {
+        FsprobeConfig {
+            paths: Vec::new(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Directory trees the dirsize collector measures. Applied at startup only.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirsizeConfig {
+    pub paths: Vec<PathBuf>,
+    /// Directories nested deeper than this are not walked
+    pub max_depth: usize,
+    /// How often each tree is walked again
+    pub interval: Duration,
+}
+
+impl Default for DirsizeConfig {
+    fn default() -> Self {
+        DirsizeConfig {
+            paths: Vec::new(),
+            max_depth: 16,
+            interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Files whose age the freshness collector exports. Applied at startup
+/// only.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FreshnessConfig {
+    pub files: Vec<PathBuf>,
+}
+
+/// Log files the logs collector follows and the regexes whose matching
+/// lines it counts. Applied at startup only.
+#[derive(Debug, Clone, Default)]
+pub struct LogsConfig {
+    pub files: Vec<PathBuf>,
+    /// Keyed by the name exported as the `pattern` label; unanchored, so
+    /// a match anywhere in the line counts
+    pub patterns: BTreeMap<String, Regex>,
+}
+
+/// Remote networks the sockets collector counts established connections
+/// by. Applied at startup only.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SocketsConfig {
+    /// Named networks; a connection counts for the one with the longest
+    /// matching prefix, or as `external` when none matches
+    pub remote_networks: Vec<(String, Vec<Cidr>)>,
+}
+
+impl Default for SocketsConfig {
+    fn default() -> Self {
+        let networks: &[(&str, &[&str])] = &[
+            ("loopback", &["127.0.0.0/8", "::1/128"]),
+            (
+                "internal",
+                &[
+                    "10.0.0.0/8",
+                    "172.16.0.0/12",
+                    "192.168.0.0/16",
+                    "169.254.0.0/16",
+                    "fc00::/7",
+                    "fe80::/10",
+                ],
+            ),
+        ];
+        SocketsConfig {
+            remote_networks: networks
+                .iter()
+                .map(|(name, cidrs)| {
+                    let cidrs = cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect();
+                    (name.to_string(), cidrs)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// How much of the kernel's memory accounting the memory collector
+/// exports. Applied at startup only.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryConfig {
+    /// The largest slab caches exported by name; 0 for none
+    pub slab_top: usize,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        MemoryConfig { slab_top: 10 }
+    }
+}
+
+/// How many processes the processes collector exports. Applied at startup
+/// only.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessesConfig {
+    /// The processes with the most open file descriptors exported by pid
+    pub top: usize,
+}
+
+impl Default for ProcessesConfig {
+    fn default() -> Self {
+        ProcessesConfig { top: 10 }
+    }
+}
+
+/// Levels the cpu and system collectors count the cycles above of.
+/// Applied at startup only.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThresholdsConfig {
+    /// Multiples of the core count, compared with the 1-minute load average
+    pub load_per_core: Vec<f64>,
+    /// CPU usage percentages
+    pub cpu_percent: Vec<f64>,
+}
+
+impl Default for ThresholdsConfig {
+    fn default() -> Self {
+        ThresholdsConfig {
+            load_per_core: vec![1.0, 2.0],
+            cpu_percent: vec![90.0],
+        }
+    }
+}
+
+/// An IPv4 or IPv6 network such as `10.0.0.0/8`; a bare address is a
+/// network of one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// IPv4-mapped IPv6 addresses match IPv4 networks
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+            v4 => v4,
+        };
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        let (address, prefix) = value.split_once('/').unwrap_or((value, ""));
+        let network: IpAddr = address
+            .parse()
+            .map_err(|e| format!("invalid network '{}': {}", value, e))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = if prefix.is_empty() {
+            max
+        } else {
+            match prefix.parse() {
+                Ok(prefix) if prefix <= max => prefix,
+                _ => {
+                    return Err(format!(
+                        "invalid network '{}': prefix must be 0 to {}",
+                        value, max
+                    ))
+                }
+            }
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollectorConfig {
+    pub enabled: bool,
+}
+
+impl Default for CollectorConfig {
+    fn default() -> Self {
+        CollectorConfig { enabled: true }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptConfig {
+    pub help: String,
+    pub expression: Expression,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MetricsConfig {
+    /// Metric families whose name fully matches one of these are not exposed
+    pub exclude: Vec<Regex>,
+    /// Also keep native histograms for the latency histograms that support
+    /// them. Applied at startup only.
+    pub native_histograms: bool,
+}
+
+/// A subset of /metrics for one kind of scraper, or one tenant. Without
+/// `collectors` and `include` it starts from everything.
+#[derive(Debug, Clone, Default)]
+pub struct ViewConfig {
+    /// Keep the families of these collectors
+    pub collectors: Vec<String>,
+    /// Keep the families whose name fully matches one of these
+    pub include: Vec<Regex>,
+    /// Then drop the families whose name fully matches one of these
+    pub exclude: Vec<Regex>,
+    /// Set on every series; a label of the same name is kept as
+    /// `exported_<name>`
+    pub labels: BTreeMap<String, String>,
+    /// Bearer token scrapes of the view must carry
+    pub token: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            web: WebConfig {
+                listen_address: SocketAddr::from(([0, 0, 0, 0], 9100)),
+                enable_lifecycle: false,
+                enable_admin_api: false,
+                admin_token: None,
+                metrics_token: None,
+                access_log: false,
+                rate_limit: None,
+                rate_limit_burst: None,
+                http2: true,
+                keep_alive: true,
+                http2_keep_alive_interval: None,
+                http2_max_concurrent_streams: None,
+            },
+            grpc: GrpcConfig {
+                listen_address: None,
+            },
+            mdns: MdnsConfig::default(),
+            cloud: CloudConfig::default(),
+            instance: InstanceConfig::default(),
+            limits: LimitsConfig::default(),
+            federation: FederationConfig::default(),
+            proxy: BTreeMap::new(),
+            probe: ProbeConfig::default(),
+            export: ExportConfig::default(),
+            services: ServicesConfig::default(),
+            sysctl: SysctlConfig::default(),
+            packages: None,
+            fsprobe: FsprobeConfig::default(),
+            dirsize: DirsizeConfig::default(),
+            freshness: FreshnessConfig::default(),
+            logs: LogsConfig::default(),
+            sockets: SocketsConfig::default(),
+            thresholds: ThresholdsConfig::default(),
+            memory: MemoryConfig::default(),
+            processes: ProcessesConfig::default(),
+            collection: CollectionConfig {
+                interval: Duration::from_secs(5),
+                jitter: Duration::ZERO,
+                align: false,
+                max_backoff: Duration::from_secs(300),
+                adaptive: false,
+                min_interval: None,
+                max_interval: None,
+            },
+            metrics: MetricsConfig::default(),
+            views: BTreeMap::new(),
+            security: SecurityConfig::default(),
+            plugins: PluginsConfig::default(),
+            debug: DebugConfig::default(),
+            histograms: BTreeMap::new(),
+            labels: BTreeMap::new(),
+            collectors: BTreeMap::new(),
+            demo: None,
+            scripts: BTreeMap::new(),
+            secret_files: Vec::new(),
+        }
+    }
+}
+
+/// All problems found while loading a configuration file
+#[derive(Debug)]
+pub struct ConfigError {
+    pub errors: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.errors.join("\n"))
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn collector(&self, name: &str) -> CollectorConfig {
+        self.collectors.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Buckets for a histogram, honouring any `[histograms]` override
+    #[cfg_attr(
+        not(any(feature = "cpu", feature = "disk", feature = "network")),
+        allow(dead_code)
+    )]
+    pub fn histogram_buckets(&self, name: &str, default: Vec<f64>) -> Vec<f64> {
+        self.histograms.get(name).cloned().unwrap_or(default)
+    }
+
+    /// Override values with the `METRIXD_*` variables among `vars`
+    pub fn apply_env(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<(), ConfigError> {
+        env::apply(self, vars)
+    }
+
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|e| ConfigError {
+            errors: vec![format!("{}: {}", path.display(), e)],
+        })?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let table = parser::parse(text).map_err(|e| ConfigError {
+            errors: vec![e.to_string()],
+        })?;
+
+        let mut errors = Vec::new();
+        let mut root = Section::new(String::new(), table);
+        let mut config = Config::default();
+
+        let mut web = root.section("web", &mut errors);
+        if let Some(addr) = web.socket_addr("listen_address", &mut errors) {
+            config.web.listen_address = addr;
+        }
+        if let Some(enabled) = web.bool("enable_lifecycle", &mut errors) {
+            config.web.enable_lifecycle = enabled;
+        }
+        if let Some(enabled) = web.bool("enable_admin_api", &mut errors) {
+            config.web.enable_admin_api = enabled;
+        }
+        config.web.admin_token = web.secret("admin_token", &mut config.secret_files, &mut errors);
+        config.web.metrics_token =
+            web.secret("metrics_token", &mut config.secret_files, &mut errors);
+        if let Some(enabled) = web.bool("access_log", &mut errors) {
+            config.web.access_log = enabled;
+        }
+        for (key, limit) in [
+            ("rate_limit", &mut config.web.rate_limit),
+            ("rate_limit_burst", &mut config.web.rate_limit_burst),
+        ] {
+            if let Some(value) = web.integer(key, &mut errors) {
+                match u32::try_from(value) {
+                    Ok(value) if value > 0 => *limit = Some(value),
+                    _ => errors.push(format!("web.{}: must be positive", key)),
+                }
+            }
+        }
+        if let Some(enabled) = web.bool("http2", &mut errors) {
+            config.web.http2 = enabled;
+        }
+        if let Some(enabled) = web.bool("keep_alive", &mut errors) {
+            config.web.keep_alive = enabled;
+        }
+        config.web.http2_keep_alive_interval =
+            web.duration("http2_keep_alive_interval", &mut errors);
+        if let Some(streams) = web.integer("http2_max_concurrent_streams", &mut errors) {
+            match u32::try_from(streams) {
+                Ok(streams) if streams > 0 => {
+                    config.web.http2_max_concurrent_streams = Some(streams)
+                }
+                _ => errors.push("web.http2_max_concurrent_streams: must be positive".to_string()),
+            }
+        }
+        web.finish(&mut errors);
+
+        let mut grpc = root.section("grpc", &mut errors);
+        config.grpc.listen_address = grpc.socket_addr("listen_address", &mut errors);
+        grpc.finish(&mut errors);
+
+        let mut collection = root.section("collection", &mut errors);
+        if let Some(interval) = collection.duration("interval", &mut errors) {
+            config.collection.interval = interval;
+        }
+        if let Some(jitter) = collection.duration("jitter", &mut errors) {
+            config.collection.jitter = jitter;
+        }
+        if let Some(align) = collection.bool("align", &mut errors) {
+            config.collection.align = align;
+        }
+        if let Some(max_backoff) = collection.duration("max_backoff", &mut errors) {
+            config.collection.max_backoff = max_backoff;
+        }
+        if let Some(adaptive) = collection.bool("adaptive", &mut errors) {
+            config.collection.adaptive = adaptive;
+        }
+        config.collection.min_interval = collection.duration("min_interval", &mut errors);
+        config.collection.max_interval = collection.duration("max_interval", &mut errors);
+        if let (Some(min), Some(max)) = (
+            config.collection.min_interval,
+            config.collection.max_interval,
+        ) {
+            if min > max {
+                errors.push(
+                    "collection.min_interval: must not be above collection.max_interval"
+                        .to_string(),
+                );
+            }
+        }
+        collection.finish(&mut errors);
+
+        let mut metrics = root.section("metrics", &mut errors);
+        config.metrics.exclude = metrics.regex_list("exclude", &mut errors);
+        if let Some(enabled) = metrics.bool("native_histograms", &mut errors) {
+            config.metrics.native_histograms = enabled;
+        }
+        metrics.finish(&mut errors);
+
+        let mut security = root.section("security", &mut errors);
+        config.security.user = security.string("user", &mut errors);
+        config.security.group = security.string("group", &mut errors);
+        if let Some(enabled) = security.bool("landlock", &mut errors) {
+            config.security.landlock = enabled;
+        }
+        config.security.landlock_read_paths =
+            security.string_list("landlock_read_paths", &mut errors);
+        if let Some(enabled) = security.bool("seccomp", &mut errors) {
+            config.security.seccomp = enabled;
+        }
+        security.finish(&mut errors);
+
+        let mut mdns = root.section("mdns", &mut errors);
+        if let Some(enabled) = mdns.bool("enabled", &mut errors) {
+            config.mdns.enabled = enabled;
+        }
+        config.mdns.instance = mdns.string("instance", &mut errors);
+        if let Some(instance) = &config.mdns.instance {
+            // One DNS label
+            if instance.is_empty() || instance.len() > 63 {
+                errors.push("mdns.instance: must be 1 to 63 bytes long".to_string());
+            }
+        }
+        mdns.finish(&mut errors);
+
+        let mut cloud = root.section("cloud", &mut errors);
+        if let Some(enabled) = cloud.bool("metadata_labels", &mut errors) {
+            config.cloud.metadata_labels = enabled;
+        }
+        if let Some(enabled) = cloud.bool("identity_info", &mut errors) {
+            config.cloud.identity_info = enabled;
+        }
+        cloud.finish(&mut errors);
+
+        let mut instance = root.section("instance", &mut errors);
+        config.instance.id = instance.string("id", &mut errors);
+        if config.instance.id.as_deref() == Some("") {
+            errors.push("instance.id: must not be empty".to_string());
+        }
+        config.instance.label = instance.string("label", &mut errors);
+        if let Some(label) = &config.instance.label {
+            if !valid_label_name(label) {
+                errors.push(format!("instance.label: invalid label name '{}'", label));
+            }
+        }
+        instance.finish(&mut errors);
+
+        let mut limits = root.section("limits", &mut errors);
+        if let Some(mib) = limits.integer("max_rss_mib", &mut errors) {
+            match u64::try_from(mib) {
+                Ok(mib) if mib > 0 => config.limits.max_rss = Some(mib.saturating_mul(1024 * 1024)),
+                _ => errors.push("limits.max_rss_mib: must be positive".to_string()),
+            }
+        }
+        limits.finish(&mut errors);
+
+        let mut federation = root.section("federation", &mut errors);
+        config.federation.peers = federation.string_list("peers", &mut errors);
+        for peer in &config.federation.peers {
+            let port = peer.rsplit_once(':').map(|(_, port)| port.parse::<u16>());
+            if !matches!(port, Some(Ok(_))) {
+                errors.push(format!("federation.peers: {}: expected host:port", peer));
+            }
+        }
+        if let Some(timeout) = federation.duration("timeout", &mut errors) {
+            config.federation.timeout = timeout;
+        }
+        federation.finish(&mut errors);
+
+        let mut proxy = root.section("proxy", &mut errors);
+        let metric_name = Regex::new("^[a-zA-Z_:][a-zA-Z0-9_:]*$").unwrap();
+        for name in proxy.keys() {
+            let mut section = proxy.section(&name, &mut errors);
+            if !section.keys().iter().any(|key| key == "url") {
+                errors.push(format!("proxy.{}: missing url", name));
+            }
+            let url = section.url("url", &mut errors);
+            let prefix = section.string("prefix", &mut errors).unwrap_or_default();
+            if !prefix.is_empty() && !metric_name.is_match(&prefix) {
+                errors.push(format!("proxy.{}.prefix: invalid metric name prefix", name));
+            }
+            let mut labels = BTreeMap::new();
+            let mut label_section = section.section("labels", &mut errors);
+            for label in label_section.keys() {
+                if !valid_label_name(&label) {
+                    errors.push(format!(
+                        "proxy.{}.labels.{}: invalid label name",
+                        name, label
+                    ));
+                }
+                if let Some(value) = label_section.string(&label, &mut errors) {
+                    labels.insert(label, value);
+                }
+            }
+            label_section.finish(&mut errors);
+            let timeout = section
+                .duration("timeout", &mut errors)
+                .unwrap_or(Duration::from_secs(3));
+            section.finish(&mut errors);
+            if let Some(url) = url {
+                config.proxy.insert(
+                    name,
+                    ProxyTarget {
+                        url,
+                        prefix,
+                        labels,
+                        timeout,
+                    },
+                );
+            }
+        }
+        proxy.finish(&mut errors);
+
+        let mut probe = root.section("probe", &mut errors);
+        if let Some(enabled) = probe.bool("enabled", &mut errors) {
+            config.probe.enabled = enabled;
+        }
+        let mut modules = probe.section("modules", &mut errors);
+        for name in modules.keys() {
+            let mut section = modules.section(&name, &mut errors);
+            let prober = match section.string("prober", &mut errors).as_deref() {
+                Some("tcp") => Some(Prober::Tcp),
+                Some("http") => Some(Prober::Http),
+                Some(other) => {
+                    errors.push(format!(
+                        "probe.modules.{}.prober: unknown prober {} (available: http, tcp)",
+                        name, other
+                    ));
+                    None
+                }
+                None => {
+                    errors.push(format!("probe.modules.{}: missing prober", name));
+                    None
+                }
+            };
+            let timeout = section
+                .duration("timeout", &mut errors)
+                .unwrap_or(Duration::from_secs(5));
+            section.finish(&mut errors);
+            if let Some(prober) = prober {
+                config
+                    .probe
+                    .modules
+                    .insert(name, ProbeModule { prober, timeout });
+            }
+        }
+        modules.finish(&mut errors);
+        probe.finish(&mut errors);
+
+        let export = root.section("export", &mut errors);
+        config.export = export::parse(export, &mut config.secret_files, &mut errors);
+        if let Some(election) = &config.export.leader_election {
+            // The holder renews it once a cycle, so it must outlast one
+            // cycle with room to spare
+            let twice = config.collection.interval.checked_mul(2);
+            if twice.is_none_or(|twice| election.lease < twice) {
+                errors.push(
+                    "export.leader_election.lease: must be at least twice collection.interval"
+                        .to_string(),
+                );
+            }
+        }
+
+        let services = root.section("services", &mut errors);
+        config.services = services::parse(services, &mut config.secret_files, &mut errors);
+
+        let mut sysctl = root.section("sysctl", &mut errors);
+        let names = sysctl.string_list("names", &mut errors);
+        for name in &names {
+            // Each part becomes a path component below /proc/sys
+            let valid = !name.contains('/') && name.split('.').all(|part| !part.is_empty());
+            if !valid {
+                errors.push(format!("sysctl.names: invalid name {}", name));
+            }
+        }
+        if !names.is_empty() {
+            config.sysctl.names = names;
+        }
+        sysctl.finish(&mut errors);
+
+        let present = root.keys().iter().any(|key| key == "packages");
+        let mut packages = root.section("packages", &mut errors);
+        if present {
+            let interval = packages
+                .duration("interval", &mut errors)
+                .unwrap_or(Duration::from_secs(3600));
+            // Package managers take locks and spin up a CPU for seconds
+            if interval < Duration::from_secs(60) {
+                errors.push("packages.interval: must be at least 1m".to_string());
+            }
+            config.packages = Some(PackagesConfig {
+                interval,
+                timeout: packages
+                    .duration("timeout", &mut errors)
+                    .unwrap_or(Duration::from_secs(120)),
+            });
+        }
+        packages.finish(&mut errors);
+
+        let mut fsprobe = root.section("fsprobe", &mut errors);
+        for path in fsprobe.string_list("paths", &mut errors) {
+            if !path.starts_with('/') {
+                errors.push(format!("fsprobe.paths: {} is not an absolute path", path));
+            }
+            config.fsprobe.paths.push(PathBuf::from(path));
+        }
+        if let Some(timeout) = fsprobe.duration("timeout", &mut errors) {
+            config.fsprobe.timeout = timeout;
+        }
+        fsprobe.finish(&mut errors);
+
+        let mut dirsize = root.section("dirsize", &mut errors);
+        for path in dirsize.string_list("paths", &mut errors) {
+            if !path.starts_with('/') {
+                errors.push(format!("dirsize.paths: {} is not an absolute path", path));
+            }
+            config.dirsize.paths.push(PathBuf::from(path));
+        }
+        if let Some(depth) = dirsize.integer("max_depth", &mut errors) {
+            match usize::try_from(depth) {
+                Ok(depth) => config.dirsize.max_depth = depth,
+                Err(_) => errors.push("dirsize.max_depth: must not be negative".to_string()),
+            }
+        }
+        if let Some(interval) = dirsize.duration("interval", &mut errors) {
+            config.dirsize.interval = interval;
+        }
+        dirsize.finish(&mut errors);
+
+        let mut freshness = root.section("freshness", &mut errors);
+        for path in freshness.string_list("files", &mut errors) {
+            if !path.starts_with('/') {
+                errors.push(format!("freshness.files: {} is not an absolute path", path));
+            }
+            config.freshness.files.push(PathBuf::from(path));
+        }
+        freshness.finish(&mut errors);
+
+        let mut logs = root.section("logs", &mut errors);
+        for path in logs.string_list("files", &mut errors) {
+            if !path.starts_with('/') {
+                errors.push(format!("logs.files: {} is not an absolute path", path));
+            }
+            config.logs.files.push(PathBuf::from(path));
+        }
+        let mut patterns = logs.section("patterns", &mut errors);
+        for name in patterns.keys() {
+            let Some(pattern) = patterns.string(&name, &mut errors) else {
+                continue;
+            };
+            match Regex::new(&pattern) {
+                Ok(regex) => {
+                    config.logs.patterns.insert(name, regex);
+                }
+                Err(e) => {
+                    let e = e.to_string();
+                    let reason = e.lines().last().unwrap_or_default();
+                    errors.push(format!(
+                        "logs.patterns.{}: invalid regex '{}': {}",
+                        name,
+                        pattern,
+                        reason.trim_start_matches("error: ")
+                    ));
+                }
+            }
+        }
+        patterns.finish(&mut errors);
+        if !config.logs.files.is_empty() && config.logs.patterns.is_empty() {
+            errors.push("logs: files are set but no patterns".to_string());
+        }
+        logs.finish(&mut errors);
+
+        let mut sockets = root.section("sockets", &mut errors);
+        if sockets.keys().iter().any(|key| key == "remote_networks") {
+            let mut networks = sockets.section("remote_networks", &mut errors);
+            config.sockets.remote_networks.clear();
+            for name in networks.keys() {
+                let mut cidrs = Vec::new();
+                for cidr in networks.string_list(&name, &mut errors) {
+                    match cidr.parse() {
+                        Ok(cidr) => cidrs.push(cidr),
+                        Err(e) => errors.push(format!("sockets.remote_networks.{}: {}", name, e)),
+                    }
+                }
+                config.sockets.remote_networks.push((name, cidrs));
+            }
+            networks.finish(&mut errors);
+        }
+        sockets.finish(&mut errors);
+
+        let mut thresholds = root.section("thresholds", &mut errors);
+        if let Some(load_per_core) = thresholds.number_list("load_per_core", &mut errors) {
+            config.thresholds.load_per_core = load_per_core;
+        }
+        if let Some(cpu_percent) = thresholds.number_list("cpu_percent", &mut errors) {
+            config.thresholds.cpu_percent = cpu_percent;
+        }
+        thresholds.finish(&mut errors);
+
+        let mut memory = root.section("memory", &mut errors);
+        if let Some(top) = memory.integer("slab_top", &mut errors) {
+            match usize::try_from(top) {
+                Ok(top) => config.memory.slab_top = top,
+                Err(_) => errors.push("memory.slab_top: must not be negative".to_string()),
+            }
+        }
+        memory.finish(&mut errors);
+
+        let mut processes = root.section("processes", &mut errors);
+        if let Some(top) = processes.integer("top", &mut errors) {
+            match usize::try_from(top) {
+                Ok(top) => config.processes.top = top,
+                Err(_) => errors.push("processes.top: must not be negative".to_string()),
+            }
+        }
+        processes.finish(&mut errors);
+
+        let mut plugins = root.section("plugins", &mut errors);
+        config.plugins.directory = plugins.string("directory", &mut errors).map(PathBuf::from);
+        for path in plugins.string_list("wasm_read_paths", &mut errors) {
+            if !path.starts_with('/') {
+                errors.push(format!(
+                    "plugins.wasm_read_paths: {} is not an absolute path",
+                    path
+                ));
+            }
+            config.plugins.wasm_read_paths.push(PathBuf::from(path));
+        }
+        plugins.finish(&mut errors);
+
+        let mut debug = root.section("debug", &mut errors);
+        config.debug.dump_directory = debug
+            .string("dump_directory", &mut errors)
+            .map(PathBuf::from);
+        debug.finish(&mut errors);
+
+        let mut histograms = root.section("histograms", &mut errors);
+        for name in histograms.keys() {
+            if let Some(buckets) = histograms.buckets(&name, &mut errors) {
+                config.histograms.insert(name, buckets);
+            }
+        }
+        histograms.finish(&mut errors);
+
+        let mut labels = root.section("labels", &mut errors);
+        for name in labels.keys() {
+            if !valid_label_name(&name) {
+                errors.push(format!("labels.{}: invalid label name", name));
+            }
+            if let Some(value) = labels.string(&name, &mut errors) {
+                config.labels.insert(name, value);
+            }
+        }
+        labels.finish(&mut errors);
+
+        let mut views = root.section("views", &mut errors);
+        for name in views.keys() {
+            let mut section = views.section(&name, &mut errors);
+            // The last part of its path
+            let valid = name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+            if !valid {
+                errors.push(format!(
+                    "views.{}: names may only have letters, digits, _ and -",
+                    name
+                ));
+            }
+            let mut view = ViewConfig {
+                collectors: section.string_list("collectors", &mut errors),
+                include: section.regex_list("include", &mut errors),
+                exclude: section.regex_list("exclude", &mut errors),
+                token: section.secret("token", &mut config.secret_files, &mut errors),
+                ..ViewConfig::default()
+            };
+            let mut labels = section.section("labels", &mut errors);
+            for label in labels.keys() {
+                if !valid_label_name(&label) {
+                    errors.push(format!(
+                        "views.{}.labels.{}: invalid label name",
+                        name, label
+                    ));
+                }
+                if let Some(value) = labels.string(&label, &mut errors) {
+                    view.labels.insert(label, value);
+                }
+            }
+            labels.finish(&mut errors);
+            section.finish(&mut errors);
+            config.views.insert(name, view);
+        }
+        views.finish(&mut errors);
+        // Otherwise any tenant could read every other view on /metrics
+        let tenants = config.views.values().any(|view| view.token.is_some());
+        if tenants && config.web.metrics_token.is_none() {
+            errors.push(
+                "web.metrics_token: required when views have a token, so /metrics isn't open to every tenant"
+                    .to_string(),
+            );
+        }
+
+        let mut collectors = root.section("collectors", &mut errors);
+        for name in collectors.keys() {
+            let mut section = collectors.section(&name, &mut errors);
+            if !COLLECTOR_NAMES.contains(&name.as_str()) {
+                errors.push(format!(
+                    "collectors.{}: unknown collector (available: {})",
+                    name,
+                    COLLECTOR_NAMES.join(", ")
+                ));
+                continue;
+            }
+            let mut collector = CollectorConfig::default();
+            if let Some(enabled) = section.bool("enabled", &mut errors) {
+                collector.enabled = enabled;
+            }
+            section.finish(&mut errors);
+            config.collectors.insert(name, collector);
+        }
+        collectors.finish(&mut errors);
+
+        let mut scripts = root.section("scripts", &mut errors);
+        for name in scripts.keys() {
+            let mut section = scripts.section(&name, &mut errors);
+            if !metric_name.is_match(&name) {
+                errors.push(format!("scripts.{}: invalid metric name", name));
+            } else if name.starts_with("metrixd_") {
+                errors.push(format!(
+                    "scripts.{}: the metrixd_ prefix is reserved for metrixd's own metrics",
+                    name
+                ));
+            }
+            let help = section
+                .string("help", &mut errors)
+                .unwrap_or_else(|| format!("Computed by the {} script", name));
+            match section.string("expression", &mut errors) {
+                Some(text) => match Expression::parse(&text) {
+                    Ok(expression) => {
+                        config
+                            .scripts
+                            .insert(name.clone(), ScriptConfig { help, expression });
+                    }
+                    Err(e) => errors.push(format!("scripts.{}.expression: {}", name, e)),
+                },
+                None => errors.push(format!("scripts.{}: missing expression", name)),
+            }
+            section.finish(&mut errors);
+        }
+        scripts.finish(&mut errors);
+
+        root.finish(&mut errors);
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError { errors })
+        }
+    }
+}
+
+/// A table being converted into typed config. Keys are removed as they are
+/// read so that whatever is left over on `finish` can be reported as unknown.
+pub(crate) struct Section {
+    path: String,
+    table: Table,
+}
+
+impl Section {
+    fn new(path: String, table: Table) -> Self {
+        Section { path, table }
+    }
+
+    fn key_path(&self, key: &str) -> String {
+        if self.path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.path, key)
+        }
+    }
+
+    fn wrong_type(&self, key: &str, expected: &str, value: &Value, errors: &mut Vec<String>) {
+        errors.push(format!(
+            "{}: expected {}, found {}",
+            self.key_path(key),
+            expected,
+            value.type_name()
+        ));
+    }
+
+    pub fn section(&mut self, key: &str, errors: &mut Vec<String>) -> Section {
+        let path = self.key_path(key);
+        match self.table.remove(key) {
+            Some(Value::Table(table)) => Section::new(path, table),
+            Some(other) => {
+                self.wrong_type(key, "table", &other, errors);
+                Section::new(path, Table::new())
+            }
+            None => Section::new(path, Table::new()),
+        }
+    }
+
+    pub fn string(&mut self, key: &str, errors: &mut Vec<String>) -> Option<String> {
+        match self.table.remove(key)? {
+            Value::String(s) => Some(s),
+            other => {
+                self.wrong_type(key, "string", &other, errors);
+                None
+            }
+        }
+    }
+
+    /// A secret given inline as `key`, or read from the file named by
+    /// `key_file` so it stays out of the config file, argv and environment.
+    /// Trailing newlines are dropped; the file is remembered in `files`.
+    pub fn secret(
+        &mut self,
+        key: &str,
+        files: &mut Vec<PathBuf>,
+        errors: &mut Vec<String>,
+    ) -> Option<String> {
+        let file_key = format!("{}_file", key);
+        let inline = self.string(key, errors);
+        let Some(path) = self.string(&file_key, errors).map(PathBuf::from) else {
+            return inline;
+        };
+        if inline.is_some() {
+            errors.push(format!(
+                "{}: cannot be combined with {}",
+                self.key_path(&file_key),
+                key
+            ));
+            return None;
+        }
+
+        let secret = match std::fs::read_to_string(&path) {
+            Ok(text) => text.trim_end_matches(['\r', '\n']).to_string(),
+            Err(e) => {
+                errors.push(format!(
+                    "{}: {}: {}",
+                    self.key_path(&file_key),
+                    path.display(),
+                    e
+                ));
+                return None;
+            }
+        };
+        files.push(path.clone());
+        if secret.is_empty() {
+            errors.push(format!(
+                "{}: {} is empty",
+                self.key_path(&file_key),
+                path.display()
+            ));
+            return None;
+        }
+        Some(secret)
+    }
+
+    pub fn bool(&mut self, key: &str, errors: &mut Vec<String>) -> Option<bool> {
+        match self.table.remove(key)? {
+            Value::Boolean(b) => Some(b),
+            other => {
+                self.wrong_type(key, "boolean", &other, errors);
+                None
+            }
+        }
+    }
+
+    pub fn integer(&mut self, key: &str, errors: &mut Vec<String>) -> Option<i64> {
+        match self.table.remove(key)? {
+            Value::Integer(i) => Some(i),
+            other => {
+                self.wrong_type(key, "integer", &other, errors);
+                None
+            }
+        }
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.table.keys().cloned().collect()
+    }
+
+    pub fn string_list(&mut self, key: &str, errors: &mut Vec<String>) -> Vec<String> {
+        let items = match self.table.remove(key) {
+            None => return Vec::new(),
+            Some(Value::Array(items)) => items,
+            Some(other) => {
+                self.wrong_type(key, "array of strings", &other, errors);
+                return Vec::new();
+            }
+        };
+
+        let mut strings = Vec::new();
+        for item in items {
+            match item {
+                Value::String(s) => strings.push(s),
+                other => self.wrong_type(key, "array of strings", &other, errors),
+            }
+        }
+        strings
+    }
+
+    /// List of regexes, anchored at both ends like Prometheus relabeling
+    pub fn regex_list(&mut self, key: &str, errors: &mut Vec<String>) -> Vec<Regex> {
+        let mut regexes = Vec::new();
+        for pattern in self.string_list(key, errors) {
+            // Validate the pattern as written so error positions match the config
+            if let Err(e) = Regex::new(&pattern) {
+                let e = e.to_string();
+                let reason = e
+                    .lines()
+                    .last()
+                    .unwrap_or_default()
+                    .trim_start_matches("error: ");
+                errors.push(format!(
+                    "{}: invalid regex '{}': {}",
+                    self.key_path(key),
+                    pattern,
+                    reason
+                ));
+                continue;
+            }
+            regexes.push(Regex::new(&format!("^(?:{})$", pattern)).expect("validated above"));
+        }
+        regexes
+    }
+
+    /// List of finite numbers, integers or floats
+    pub fn number_list(&mut self, key: &str, errors: &mut Vec<String>) -> Option<Vec<f64>> {
+        let items = match self.table.remove(key)? {
+            Value::Array(items) => items,
+            other => {
+                self.wrong_type(key, "array of numbers", &other, errors);
+                return None;
+            }
+        };
+
+        let mut numbers = Vec::new();
+        for item in items {
+            match item {
+                Value::Integer(i) => numbers.push(i as f64),
+                Value::Float(f) if f.is_finite() => numbers.push(f),
+                other => {
+                    self.wrong_type(key, "array of finite numbers", &other, errors);
+                    return None;
+                }
+            }
+        }
+        Some(numbers)
+    }
+
+    /// Histogram bucket bounds: non-empty, finite and strictly increasing
+    pub fn buckets(&mut self, key: &str, errors: &mut Vec<String>) -> Option<Vec<f64>> {
+        let path = self.key_path(key);
+        let items = match self.table.remove(key)? {
+            Value::Array(items) => items,
+            other => {
+                self.wrong_type(key, "array of numbers", &other, errors);
+                return None;
+            }
+        };
+
+        let mut buckets = Vec::new();
+        for item in items {
+            match item {
+                Value::Integer(i) => buckets.push(i as f64),
+                Value::Float(f) => buckets.push(f),
+                other => {
+                    self.wrong_type(key, "array of numbers", &other, errors);
+                    return None;
+                }
+            }
+        }
+
+        if buckets.is_empty() {
+            errors.push(format!("{}: bucket list must not be empty", path));
+            return None;
+        }
+        if let Some(bad) = buckets.iter().find(|b| !b.is_finite()) {
+            errors.push(format!(
+                "{}: bucket bound {} is not finite (+Inf is added automatically)",
+                path, bad
+            ));
+            return None;
+        }
+        if let Some(pair) = buckets.windows(2).find(|w| w[0] >= w[1]) {
+            errors.push(format!(
+                "{}: buckets must be strictly increasing, but {} is followed by {}",
+                path, pair[0], pair[1]
+            ));
+            return None;
+        }
+        Some(buckets)
+    }
+
+    pub fn socket_addr(&mut self, key: &str, errors: &mut Vec<String>) -> Option<SocketAddr> {
+        let value = self.string(key, errors)?;
+        match value.parse() {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                errors.push(format!(
+                    "{}: invalid address '{}': {}",
+                    self.key_path(key),
+                    value,
+                    e
+                ));
+                None
+            }
+        }
+    }
+
+    pub fn duration(&mut self, key: &str, errors: &mut Vec<String>) -> Option<Duration> {
+        let value = self.string(key, errors)?;
+        match parse_duration(&value) {
+            Ok(duration) => Some(duration),
+            Err(e) => {
+                errors.push(format!("{}: {}", self.key_path(key), e));
+                None
+            }
+        }
+    }
+
+    /// A plain `http://` URL, as `fetch` understands it
+    pub fn url(&mut self, key: &str, errors: &mut Vec<String>) -> Option<String> {
+        let url = self.string(key, errors)?;
+        match crate::fetch::parse_url(&url) {
+            Ok(_) => Some(url),
+            Err(e) => {
+                errors.push(format!(
+                    "{}: {} (metrixd speaks plain HTTP; use a TLS-terminating proxy for https)",
+                    self.key_path(key),
+                    e
+                ));
+                None
+            }
+        }
+    }
+
+    /// Report every key that was not consumed
+    pub fn finish(self, errors: &mut Vec<String>) {
+        for key in self.table.keys() {
+            errors.push(format!("{}: unknown key", self.key_path(key)));
+        }
+    }
+}
+
+/// Label names as Prometheus accepts them, without the reserved `__` prefix
+pub fn valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("__")
+}
+
+/// Longest duration accepted anywhere in the config, 366 days. Callers scale
+/// intervals by small factors, which can't overflow from this bound.
+pub const MAX_DURATION: Duration = Duration::from_secs(366 * 24 * 3600);
+
+/// Parse a Prometheus-style duration such as `500ms`, `15s`, `5m` or `1h`
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", value))?;
+
+    let millis = match unit {
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 3600 * 1000,
+        _ => {
+            return Err(format!(
+                "invalid duration '{}': unit must be one of ms, s, m, h",
+                value
+            ))
+        }
+    };
+    let duration = number
+        .checked_mul(millis)
+        .map(Duration::from_millis)
+        .filter(|duration| *duration <= MAX_DURATION)
+        .ok_or_else(|| format!("invalid duration '{}': duration out of range", value))?;
+
+    if duration.is_zero() {
+        return Err(format!("invalid duration '{}': must be positive", value));
+    }
+    Ok(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_out_of_range_are_config_errors() {
+        assert_eq!(parse_duration("90m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(
+            parse_duration("9999999999999999h"),
+            Err("invalid duration '9999999999999999h': duration out of range".to_string())
+        );
+        let e = Config::parse("[collection]\ninterval = \"9999999999999999h\"\n").unwrap_err();
+        assert_eq!(
+            e.errors,
+            ["collection.interval: invalid duration '9999999999999999h': duration out of range"]
+        );
+        assert_eq!(parse_duration("8784h"), Ok(MAX_DURATION));
+        for value in ["8785h", "18446744073709551615s", "18446744073709551615ms"] {
+            assert_eq!(
+                parse_duration(value),
+                Err(format!(
+                    "invalid duration '{}': duration out of range",
+                    value
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn leader_election_needs_an_absolute_lock_file() {
+        let e = Config::parse("[export.leader_election]\nlock_file = \"lease\"\n").unwrap_err();
+        assert_eq!(
+            e.errors,
+            ["export.leader_election.lock_file: lease is not an absolute path"]
+        );
+        let e = Config::parse(
+            "[collection]\ninterval = \"8784h\"\n\
+             [export.leader_election]\nlock_file = \"/shared/lease\"\nlease = \"1h\"\n",
+        )
+        .unwrap_err();
+        assert_eq!(
+            e.errors,
+            ["export.leader_election.lease: must be at least twice collection.interval"]
+        );
+    }
+
+    #[test]
+    fn view_tokens_need_a_metrics_token() {
+        let e = Config::parse("[views.team-a]\ntoken = \"a-secret\"\n").unwrap_err();
+        assert_eq!(
+            e.errors,
+            ["web.metrics_token: required when views have a token, so /metrics isn't open to every tenant"]
+        );
+        let config = Config::parse(
+            "[web]\nmetrics_token = \"m-secret\"\n[views.team-a]\ntoken = \"a-secret\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.web.metrics_token.as_deref(), Some("m-secret"));
+    }
+
+    #[test]
+    fn scripts_cant_take_metrixd_metric_names() {
+        let e = Config::parse("[scripts.metrixd_degraded]\nexpression = \"1\"\n").unwrap_err();
+        assert_eq!(
+            e.errors,
+            ["scripts.metrixd_degraded: the metrixd_ prefix is reserved for metrixd's own metrics"]
+        );
+        let config = Config::parse("[scripts.metrixd]\nexpression = \"1\"\n").unwrap();
+        assert!(config.scripts.contains_key("metrixd"));
+    }
+}
@@ -0,0 +1,587 @@
+//! Minimal TOML parser covering the subset used by metrixd configuration:
+//! tables, arrays of tables, dotted keys, strings, integers, floats,
+//! booleans, arrays and inline tables. Like TOML it rejects a table defined
+//! twice, whether by a header, an inline table or a dotted key.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+pub type Table = BTreeMap<String, Value>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Float(_) => "float",
+            Value::Boolean(_) => "boolean",
+            Value::Array(_) => "array",
+            Value::Table(_) => "table",
+        }
+    }
+}
+
+/// Syntax error with the 1-based line it was found on
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+pub fn parse(input: &str) -> Result<Table, ParseError> {
+    Parser {
+        chars: input.chars().collect(),
+        pos: 0,
+        line: 1,
+    }
+    .parse_document()
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            line: self.line,
+            message: message.into(),
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.bump();
+                Ok(())
+            }
+            Some(c) => self.error(format!("expected '{}', found {}", expected, describe(c))),
+            None => self.error(format!("expected '{}', found end of file", expected)),
+        }
+    }
+
+    /// Skip spaces and tabs on the current line
+    fn skip_inline_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ') | Some('\t')) {
+            self.bump();
+        }
+    }
+
+    /// Skip whitespace, newlines and comments
+    fn skip_whitespace_and_comments(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ') | Some('\t') | Some('\r') | Some('\n') => {
+                    self.bump();
+                }
+                Some('#') => self.skip_comment(),
+                _ => return,
+            }
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '\n' {
+                return;
+            }
+            self.bump();
+        }
+    }
+
+    /// Consume the rest of a line after a statement, allowing a trailing comment
+    fn end_of_line(&mut self) -> Result<(), ParseError> {
+        self.skip_inline_whitespace();
+        if self.peek() == Some('#') {
+            self.skip_comment();
+        }
+        match self.peek() {
+            None | Some('\n') => Ok(()),
+            Some('\r') => {
+                self.bump();
+                self.expect('\n')
+            }
+            Some(c) => self.error(format!("unexpected {} after value", describe(c))),
+        }
+    }
+
+    fn parse_document(mut self) -> Result<Table, ParseError> {
+        let mut root = Table::new();
+        // Path of the table that key/value pairs are currently added to
+        let mut current: Vec<String> = Vec::new();
+        // Tables defined by a header, an inline table or a dotted key; tables
+        // only implied by a longer header can still be defined later
+        let mut defined: HashSet<Vec<String>> = HashSet::new();
+
+        loop {
+            self.skip_whitespace_and_comments();
+            match self.peek() {
+                None => return Ok(root),
+                Some('[') => {
+                    self.bump();
+                    let is_array = self.peek() == Some('[');
+                    if is_array {
+                        self.bump();
+                    }
+                    self.skip_inline_whitespace();
+                    let path = self.parse_key_path()?;
+                    self.skip_inline_whitespace();
+                    self.expect(']')?;
+                    if is_array {
+                        self.expect(']')?;
+                    }
+                    self.end_of_line()?;
+
+                    if is_array {
+                        self.push_array_table(&mut root, &path)?;
+                        // The new element starts with none of its subtables
+                        defined.retain(|table| !table.starts_with(&path));
+                    } else {
+                        if !defined.insert(path.clone()) {
+                            return self
+                                .error(format!("table '{}' is defined twice", path.join(".")));
+                        }
+                        self.open_table(&mut root, &path)?;
+                    }
+                    current = path;
+                }
+                Some(_) => {
+                    let path = self.parse_key_path()?;
+                    self.expect('=')?;
+                    self.skip_inline_whitespace();
+                    let value = self.parse_value()?;
+                    self.end_of_line()?;
+
+                    let mut table = current.clone();
+                    for key in &path[..path.len() - 1] {
+                        table.push(key.clone());
+                        defined.insert(table.clone());
+                    }
+                    if let Value::Table(_) = value {
+                        table.push(path[path.len() - 1].clone());
+                        defined.insert(table);
+                    }
+                    let table = self.resolve(&mut root, &current)?;
+                    self.insert(table, &path, value)?;
+                }
+            }
+        }
+    }
+
+    fn parse_key_path(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut path = vec![self.parse_key()?];
+        loop {
+            self.skip_inline_whitespace();
+            if self.peek() != Some('.') {
+                return Ok(path);
+            }
+            self.bump();
+            self.skip_inline_whitespace();
+            path.push(self.parse_key()?);
+        }
+    }
+
+    fn parse_key(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some('"') => self.parse_basic_string(),
+            Some('\'') => self.parse_literal_string(),
+            _ => {
+                let start = self.pos;
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                        self.bump();
+                    } else {
+                        break;
+                    }
+                }
+                if start == self.pos {
+                    return match self.peek() {
+                        Some(c) => self.error(format!("expected a key, found {}", describe(c))),
+                        None => self.error("expected a key, found end of file"),
+                    };
+                }
+                Ok(self.chars[start..self.pos].iter().collect())
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, ParseError> {
+        match self.peek() {
+            Some('"') => self.parse_basic_string().map(Value::String),
+            Some('\'') => self.parse_literal_string().map(Value::String),
+            Some('[') => self.parse_array(),
+            Some('{') => self.parse_inline_table(),
+            Some('t') | Some('f') => self.parse_bool(),
+            Some(c) if c == '-' || c == '+' || c.is_ascii_digit() || c == 'i' || c == 'n' => {
+                self.parse_number()
+            }
+            Some(c) => self.error(format!("expected a value, found {}", describe(c))),
+            None => self.error("expected a value, found end of file"),
+        }
+    }
+
+    fn parse_basic_string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            // Peek first so the error names the line the string is on
+            let c = match self.peek() {
+                None | Some('\n') => return self.error("unterminated string"),
+                Some(c) => c,
+            };
+            self.bump();
+            match c {
+                '"' => return Ok(s),
+                '\\' => match self.bump() {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('r') => s.push('\r'),
+                    Some(c) => return self.error(format!("invalid escape '\\{}'", c)),
+                    None => return self.error("unterminated string"),
+                },
+                c => s.push(c),
+            }
+        }
+    }
+
+    /// Single-quoted strings are taken verbatim, which is handy for regexes
+    fn parse_literal_string(&mut self) -> Result<String, ParseError> {
+        self.expect('\'')?;
+        let mut s = String::new();
+        loop {
+            let c = match self.peek() {
+                None | Some('\n') => return self.error("unterminated string"),
+                Some(c) => c,
+            };
+            self.bump();
+            if c == '\'' {
+                return Ok(s);
+            }
+            s.push(c);
+        }
+    }
+
+    fn parse_bool(&mut self) -> Result<Value, ParseError> {
+        for (word, value) in [("true", true), ("false", false)] {
+            let end = self.pos + word.len();
+            if end <= self.chars.len() && self.chars[self.pos..end].iter().copied().eq(word.chars())
+            {
+                self.pos = end;
+                return Ok(Value::Boolean(value));
+            }
+        }
+        self.error("expected a value")
+    }
+
+    fn parse_number(&mut self) -> Result<Value, ParseError> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '.' | '_') {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        let text: String = self.chars[start..self.pos]
+            .iter()
+            .filter(|c| **c != '_')
+            .collect();
+
+        match text.as_str() {
+            "inf" | "+inf" => return Ok(Value::Float(f64::INFINITY)),
+            "-inf" => return Ok(Value::Float(f64::NEG_INFINITY)),
+            "nan" | "+nan" | "-nan" => return Ok(Value::Float(f64::NAN)),
+            _ => {}
+        }
+
+        if let Ok(i) = text.parse::<i64>() {
+            return Ok(Value::Integer(i));
+        }
+        match text.parse::<f64>() {
+            Ok(f) if !text.contains(|c: char| c.is_ascii_alphabetic() && c != 'e' && c != 'E') => {
+                Ok(Value::Float(f))
+            }
+            _ => self.error(format!("invalid number '{}'", text)),
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value, ParseError> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        loop {
+            self.skip_whitespace_and_comments();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(items));
+            }
+            items.push(self.parse_value()?);
+            self.skip_whitespace_and_comments();
+            match self.peek() {
+                Some(',') => {
+                    self.bump();
+                }
+                Some(']') => {}
+                Some(c) => {
+                    return self.error(format!("expected ',' or ']', found {}", describe(c)))
+                }
+                None => return self.error("unterminated array"),
+            }
+        }
+    }
+
+    fn parse_inline_table(&mut self) -> Result<Value, ParseError> {
+        self.expect('{')?;
+        let mut table = Table::new();
+        self.skip_inline_whitespace();
+        if self.peek() == Some('}') {
+            self.bump();
+            return Ok(Value::Table(table));
+        }
+        loop {
+            self.skip_inline_whitespace();
+            let path = self.parse_key_path()?;
+            self.expect('=')?;
+            self.skip_inline_whitespace();
+            let value = self.parse_value()?;
+            self.insert(&mut table, &path, value)?;
+            self.skip_inline_whitespace();
+            match self.peek() {
+                Some(',') => {}
+                Some('}') => {
+                    self.bump();
+                    return Ok(Value::Table(table));
+                }
+                Some(c) => {
+                    return self.error(format!("expected ',' or '}}', found {}", describe(c)))
+                }
+                None => return self.error("unterminated inline table"),
+            }
+            self.bump();
+        }
+    }
+
+    /// Walk `path` from the root, descending into the last element of arrays
+    /// of tables, and return the table it names
+    fn resolve<'t>(
+        &self,
+        root: &'t mut Table,
+        path: &[String],
+    ) -> Result<&'t mut Table, ParseError> {
+        let mut table = root;
+        for key in path {
+            let entry = table
+                .entry(key.clone())
+                .or_insert_with(|| Value::Table(Table::new()));
+            table = match entry {
+                Value::Table(t) => t,
+                Value::Array(items) => match items.last_mut() {
+                    Some(Value::Table(t)) => t,
+                    _ => return self.error(format!("'{}' is not a table", key)),
+                },
+                _ => return self.error(format!("'{}' is not a table", key)),
+            };
+        }
+        Ok(table)
+    }
+
+    /// Set the dotted key `path` below `table`
+    fn insert(&self, table: &mut Table, path: &[String], value: Value) -> Result<(), ParseError> {
+        let (key, parent) = path.split_last().expect("key path is never empty");
+        let table = self.resolve(table, parent)?;
+        if table.contains_key(key) {
+            return self.error(format!("duplicate key '{}'", path.join(".")));
+        }
+        table.insert(key.clone(), value);
+        Ok(())
+    }
+
+    fn open_table(&self, root: &mut Table, path: &[String]) -> Result<(), ParseError> {
+        let (last, parent) = path.split_last().expect("key path is never empty");
+        let parent = self.resolve(root, parent)?;
+        match parent.get(last) {
+            Some(Value::Table(_)) | None => {
+                parent
+                    .entry(last.clone())
+                    .or_insert_with(|| Value::Table(Table::new()));
+                Ok(())
+            }
+            Some(_) => self.error(format!("'{}' is already defined", path.join("."))),
+        }
+    }
+
+    fn push_array_table(&self, root: &mut Table, path: &[String]) -> Result<(), ParseError> {
+        let (last, parent) = path.split_last().expect("key path is never empty");
+        let parent = self.resolve(root, parent)?;
+        match parent
+            .entry(last.clone())
+            .or_insert_with(|| Value::Array(Vec::new()))
+        {
+            Value::Array(items) => {
+                items.push(Value::Table(Table::new()));
+                Ok(())
+            }
+            _ => self.error(format!(
+                "'{}' is already defined as a table",
+                path.join(".")
+            )),
+        }
+    }
+}
+
+fn describe(c: char) -> String {
+    match c {
+        '\n' | '\r' => "end of line".to_string(),
+        c => format!("'{}'", c),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(input: &str) -> String {
+        parse(input).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_values() {
+        let table = parse(
+            "# comment\n\
+             basic = \"a\\\"b\\\\c\\n\\t\"  # trailing comment\n\
+             literal = 'C:\\d+'\n\
+             integer = -1_000\n\
+             float = 2.5e3\n\
+             infinite = -inf\n\
+             yes = true\n\
+             list = [1, \"two\",\n  [3], # nested\n]\n\
+             inline = { a = 1, b.c = 'x' }\n",
+        )
+        .unwrap();
+        assert_eq!(table["basic"], Value::String("a\"b\\c\n\t".to_string()));
+        assert_eq!(table["literal"], Value::String("C:\\d+".to_string()));
+        assert_eq!(table["integer"], Value::Integer(-1000));
+        assert_eq!(table["float"], Value::Float(2500.0));
+        assert_eq!(table["infinite"], Value::Float(f64::NEG_INFINITY));
+        assert_eq!(table["yes"], Value::Boolean(true));
+        assert_eq!(
+            table["list"],
+            Value::Array(vec![
+                Value::Integer(1),
+                Value::String("two".to_string()),
+                Value::Array(vec![Value::Integer(3)]),
+            ])
+        );
+        let inline = parse("a = 1\nb = { c = 'x' }\n").unwrap();
+        assert_eq!(table["inline"], Value::Table(inline));
+    }
+
+    #[test]
+    fn parses_tables_and_dotted_keys() {
+        let table = parse(
+            "top = 1\n\
+             [web]\n\
+             listen_address = \"0.0.0.0:9100\"\n\
+             tls.cert = 'a.pem'\n\
+             tls.key = 'a.key'\n\
+             [collectors.\"cpu\"]\n\
+             enabled = false\n\
+             [[scripts]]\n\
+             name = 'a'\n\
+             [scripts.labels]\n\
+             x = '1'\n\
+             [[scripts]]\n\
+             name = 'b'\n\
+             [scripts.labels]\n\
+             x = '2'\n",
+        )
+        .unwrap();
+        assert_eq!(
+            table,
+            parse(
+                "top = 1\n\
+                 web = { listen_address = \"0.0.0.0:9100\", tls = { cert = 'a.pem', key = 'a.key' } }\n\
+                 collectors = { cpu = { enabled = false } }\n\
+                 scripts = [{ name = 'a', labels = { x = '1' } }, { name = 'b', labels = { x = '2' } }]\n"
+            )
+            .unwrap()
+        );
+        // A table only implied by a longer header can be defined later
+        assert!(parse("[a.b]\nx = 1\n[a]\ny = 2\n").is_ok());
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        assert_eq!(error("a = 1\na = 2\n"), "line 2: duplicate key 'a'");
+        assert_eq!(error("a = { b = 1, b = 2 }\n"), "line 1: duplicate key 'b'");
+        assert_eq!(
+            error("[web]\nx = 1\n\n[web]\n"),
+            "line 4: table 'web' is defined twice"
+        );
+        assert_eq!(
+            error("a = { b = 1 }\n[a]\n"),
+            "line 2: table 'a' is defined twice"
+        );
+        assert_eq!(
+            error("[a]\nb.c = 1\n[a.b]\n"),
+            "line 3: table 'a.b' is defined twice"
+        );
+        assert_eq!(error("a.b = 1\na.b.c = 2\n"), "line 2: 'b' is not a table");
+        assert_eq!(error("[[a]]\n[a]\n"), "line 2: 'a' is already defined");
+        assert_eq!(
+            error("a = 1\n[[a]]\n"),
+            "line 2: 'a' is already defined as a table"
+        );
+    }
+
+    #[test]
+    fn reports_error_positions() {
+        assert_eq!(error("a = \"open\nb = 1\n"), "line 1: unterminated string");
+        assert_eq!(error("\n\na = \"\\q\"\n"), "line 3: invalid escape '\\q'");
+        assert_eq!(error("a = 1 2\n"), "line 1: unexpected '2' after value");
+        assert_eq!(
+            error("a =\n"),
+            "line 1: expected a value, found end of line"
+        );
+        assert_eq!(error("a = [1,\n2\n"), "line 3: unterminated array");
+        assert_eq!(
+            error("a = { b = 1\n"),
+            "line 1: expected ',' or '}', found end of line"
+        );
+        assert_eq!(error("a = 1.2.3\n"), "line 1: invalid number '1.2.3'");
+        assert_eq!(error("[web\n"), "line 1: expected ']', found end of line");
+        assert_eq!(error("= 1\n"), "line 1: expected a key, found '='");
+    }
+}
@@ -0,0 +1,560 @@
+//! Every table and key `Config::parse` accepts, with its type, default and
+//! a line of help, for `print-default-config` and `config-schema`. The
+//! tests keep it in step with the parser: the default file must parse to
+//! `Config::default()`, and the file with every example filled in must
+//! parse without unknown keys.
+
+use std::fmt::Write;
+
+use crate::export::json_string;
+
+/// One table per `[path]` line, followed by its keys, each line ending in
+/// `# <type>: <help>`. A key is `name = <default>`, or `name ~ <example>`
+/// if it is unset by default; values are TOML that is also JSON, unless
+/// unset. After the path, `?` marks a table that is only read, and
+/// switches something on, when present; `*` one whose last part is any
+/// name, here an example; `{}` one whose keys are any names, with an
+/// example entry.
+const SPEC: &str = r#"
+[web]  # The HTTP server
+listen_address = "0.0.0.0:9100"  # string: Address to serve /metrics on
+enable_lifecycle = false  # boolean: Enable /-/reload and /-/quit
+enable_admin_api = false  # boolean: Enable /admin/collectors
+admin_token ~ "changeme"  # secret: Bearer token the admin endpoints require
+metrics_token ~ "changeme"  # secret: Bearer token /metrics, /probe, /status, /metrics-docs and gRPC require
+access_log = false  # boolean: Print a line per request
+rate_limit ~ 5  # integer: Requests per second per client address
+rate_limit_burst ~ 10  # integer: Requests a client may make at once
+http2 = true  # boolean: Also accept HTTP/2 with prior knowledge (startup only)
+keep_alive = true  # boolean: Keep HTTP/1.1 connections open between scrapes
+http2_keep_alive_interval ~ "30s"  # duration: Ping idle HTTP/2 connections
+http2_max_concurrent_streams ~ 100  # integer: Per HTTP/2 connection
+
+[grpc]  # The gRPC query API
+listen_address ~ "0.0.0.0:9101"  # string: Address to serve it on
+
+[mdns]  # Advertising /metrics over multicast DNS (Unix, startup only)
+enabled = false  # boolean: Advertise _prometheus-http._tcp
+instance ~ "rack-3"  # string: Service instance name; instance.id by default
+
+[collection]  # The collection loop
+interval = "5s"  # duration: Time between collection cycles
+jitter ~ "5s"  # duration: Delay the first cycle by a random part of this
+align = false  # boolean: Run cycles at wall-clock multiples of the interval
+max_backoff = "5m"  # duration: Longest wait between retries of a failing collector
+adaptive = false  # boolean: Stretch the interval when idle or on battery
+min_interval ~ "2s"  # duration: Adaptive bound; half the interval by default
+max_interval ~ "1m"  # duration: Adaptive bound; four times the interval by default
+
+[metrics]  # What /metrics exposes
+exclude = []  # strings: Drop metric families fully matching one of these regexes
+native_histograms = false  # boolean: Also keep native histograms (startup only)
+
+[views.slim]*  # A subset of /metrics served on /metrics/<name>
+collectors ~ ["cpu", "memory", "disk"]  # strings: Keep the families of these collectors
+include = []  # strings: Keep metric families fully matching one of these regexes
+exclude = []  # strings: Then drop the families fully matching one of these
+token ~ "changeme"  # secret: Bearer token scrapes of the view must carry
+
+[views.slim.labels]{}  # Set on every series of the view; one it has already is kept as exported_<name>
+tenant ~ "team-a"  # string
+
+[security]  # Privileges and sandboxing (startup only)
+user ~ "metrixd"  # string: User to switch to after binding ports
+group ~ "metrixd"  # string: Group to switch to; the user's by default
+landlock = false  # boolean: Read-only /proc, /sys and the config file (Linux)
+landlock_read_paths = []  # strings: Further paths readable under Landlock
+seccomp = false  # boolean: Block exec, ptrace, new listeners and the like (Linux)
+
+[cloud]  # Cloud metadata (startup only)
+metadata_labels = false  # boolean: Label series with the instance ID, type, region and zone
+identity_info = false  # boolean: Only fill in node_identity_info
+
+[instance]  # What this agent calls itself
+id ~ "web-1"  # string: The host name, then /etc/machine-id, by default
+label ~ "host"  # string: Also add the ID to every series under this label
+
+[limits]  # Bounds on the agent's own resource use
+max_rss_mib ~ 256  # integer: Shed the largest collectors near this (Linux)
+
+[federation]  # Other agents to re-expose (startup only)
+peers = []  # strings: host:port of each peer
+timeout = "3s"  # duration: How long one scrape of a peer may take
+
+[proxy.app]*  # A local exporter whose series are merged into /metrics (startup only)
+url ~ "http://127.0.0.1:8080/metrics"  # url: Its metrics in the text format (required)
+prefix = ""  # string: Prepended to every metric name
+timeout = "3s"  # duration: How long one scrape may take
+
+[proxy.app.labels]{}  # Added to every series; one it has already is kept as exported_<name>
+job ~ "app"  # string
+
+[probe]  # The /probe endpoint
+enabled = false  # boolean: Serve /probe?target=...&module=...
+
+[probe.modules.ssh]*  # A probe module; http_2xx and tcp_connect are built in
+prober ~ "tcp"  # string: tcp or http
+timeout = "5s"  # duration: Give up after this
+
+[export.datadog]?  # Push to DogStatsD or the Datadog API
+interval ~ "60s"  # duration: Push at most this often; every cycle by default
+include ~ ["cpu_.*", "memory_.*"]  # strings: Only push metric names fully matching one of these
+dogstatsd_address = "127.0.0.1:8125"  # string: host:port of the agent's DogStatsD
+api_url ~ "http://localhost:8443/api/v2/series"  # url: The series API, instead of DogStatsD
+api_key ~ "changeme"  # secret: Key for api_url
+prefix = ""  # string: Prepended to every metric name
+
+[export.cloudwatch]?  # Push to CloudWatch
+interval ~ "60s"  # duration: Push at most this often; 60s by default
+include ~ ["cpu_.*", "memory_.*"]  # strings: Required; only push metric names fully matching these
+region ~ "eu-west-1"  # string: AWS_REGION or AWS_DEFAULT_REGION by default
+namespace = "metrixd"  # string: The metric namespace
+dimensions = []  # strings: Labels sent as dimensions, as label or label=Name; all by default
+url ~ "http://localhost:8444/"  # url: http://monitoring.<region>.amazonaws.com/ by default
+access_key_id ~ "AKIAEXAMPLE"  # string: Static credentials, with secret_access_key
+secret_access_key ~ "changeme"  # secret: Static credentials, with access_key_id
+
+[export.google_cloud]?  # Push to Google Cloud Monitoring
+interval ~ "60s"  # duration: Push at most this often; 60s by default
+include ~ ["cpu_.*", "memory_.*"]  # strings: Only push metric names fully matching one of these
+project_id ~ "my-project"  # string: The instance's project by default
+metric_prefix = "custom.googleapis.com/metrixd/"  # string: Prepended to metric names
+url = "http://monitoring.googleapis.com/"  # url: The API, through a TLS-terminating proxy
+
+[export.azure_monitor]?  # Push to Azure Monitor
+interval ~ "60s"  # duration: Push at most this often; 60s by default
+include ~ ["cpu_.*", "memory_.*"]  # strings: Only push metric names fully matching one of these
+namespace = "metrixd"  # string: The custom metric namespace
+region ~ "westeurope"  # string: The VM's own by default
+resource_id ~ "/subscriptions/example"  # string: The VM's own by default
+url ~ "http://localhost:8446/"  # url: http://<region>.monitoring.azure.com/ by default
+
+[export.mqtt]?  # Publish every sample to an MQTT broker
+interval ~ "60s"  # duration: Push at most this often; every cycle by default
+include ~ ["cpu_.*", "memory_.*"]  # strings: Only push metric names fully matching one of these
+broker = "127.0.0.1:1883"  # string: host:port of the broker
+topic = "metrixd/{name}"  # string: Filled in with {name} and {<label>}
+client_id = ""  # string: The broker picks one when empty
+username ~ "metrixd"  # string: Log in as this user
+password ~ "changeme"  # secret: With username
+retain = false  # boolean: Keep the last message of each topic
+
+[export.kafka]?  # Produce every sample to Kafka
+interval ~ "60s"  # duration: Push at most this often; every cycle by default
+include ~ ["cpu_.*", "memory_.*"]  # strings: Only push metric names fully matching one of these
+brokers ~ ["kafka-1:9092"]  # strings: host:port of bootstrap brokers (required)
+topic ~ "host-metrics"  # string: The topic (required)
+format = "json"  # string: json or protobuf
+client_id = "metrixd"  # string: The producer's client ID
+
+[export.nats]?  # Publish every sample to NATS
+interval ~ "60s"  # duration: Push at most this often; every cycle by default
+include ~ ["cpu_.*", "memory_.*"]  # strings: Only push metric names fully matching one of these
+server = "127.0.0.1:4222"  # string: host:port of the server
+subject = "metrixd.{name}"  # string: Filled in like the MQTT topic
+format = "json"  # string: json or protobuf
+username ~ "metrixd"  # string: With password
+password ~ "changeme"  # secret: With username
+token ~ "changeme"  # secret: Token authentication
+
+[export.zabbix]?  # Send values to Zabbix trapper items
+interval ~ "60s"  # duration: Push at most this often; every cycle by default
+include ~ ["cpu_.*", "memory_.*"]  # strings: Only push metric names fully matching one of these
+server = "127.0.0.1:10051"  # string: host:port of the server or proxy
+host ~ "web-1"  # string: The host in Zabbix; instance.id by default
+
+[export.zabbix.keys]{}  # Item key templates by metric name, filled in with {<label>}
+disk_usage_percent ~ "vfs.fs.size[/,pused]"  # string
+
+[export.collectd]?  # Send values in collectd's network protocol
+interval ~ "60s"  # duration: Push at most this often; every cycle by default
+include ~ ["cpu_.*", "memory_.*"]  # strings: Only push metric names fully matching one of these
+server = "127.0.0.1:25826"  # string: host:port of the listener
+host ~ "web-1"  # string: The host values are reported for; instance.id by default
+plugin = "metrixd"  # string: The plugin values are reported for
+username ~ "metrixd"  # string: Sign packets as this user
+password ~ "changeme"  # secret: With username
+
+[export.telegraf]?  # Write line protocol to a Telegraf socket_listener
+interval ~ "60s"  # duration: Push at most this often; every cycle by default
+include ~ ["cpu_.*", "memory_.*"]  # strings: Only push metric names fully matching one of these
+address = "tcp://127.0.0.1:8094"  # string: tcp://host:port or unix:///path
+
+[export.victoriametrics]?  # Import into VictoriaMetrics
+interval ~ "60s"  # duration: Push at most this often; every cycle by default
+include ~ ["cpu_.*", "memory_.*"]  # strings: Only push metric names fully matching one of these
+url ~ "http://victoriametrics:8428"  # url: Where /api/v1/import/prometheus is (required)
+
+[export.victoriametrics.extra_labels]{}  # Added to every sample by VictoriaMetrics
+datacenter ~ "eu-west"  # string
+
+[export.pushgateway]?  # Replace a group on a Pushgateway
+interval ~ "60s"  # duration: Push at most this often; every cycle by default
+include ~ ["cpu_.*", "memory_.*"]  # strings: Only push metric names fully matching one of these
+url ~ "http://pushgateway:9091"  # url: The Pushgateway (required)
+job = "metrixd"  # string: The group's job; its instance is instance.id
+
+[export.remote_write]?  # Prometheus remote write
+interval ~ "60s"  # duration: Push at most this often; every cycle by default
+include ~ ["cpu_.*", "memory_.*"]  # strings: Only push metric names fully matching one of these
+url ~ "http://prometheus:9090/api/v1/write"  # url: The receiver (required)
+
+[export.leader_election]?  # Only the agent holding a lease on shared storage pushes cluster metrics
+lock_file ~ "/shared/metrixd/lease"  # string: Absolute path of the lease, on storage every agent sees (required)
+lease = "60s"  # duration: Taken over once its holder stops renewing it; at least two cycles
+cluster_metrics = []  # strings: Metric names, fully matched, only the holder pushes
+
+[services.haproxy]?  # HAProxy's stats
+stats_socket = "/run/haproxy/admin.sock"  # string: The stats socket
+url ~ "http://127.0.0.1:8404/stats"  # url: The stats page, instead of the socket
+timeout = "3s"  # duration: How long to wait for the service
+
+[services.nginx]?  # nginx's stub_status
+urls = ["http://127.0.0.1/nginx_status"]  # strings: stub_status pages
+timeout = "3s"  # duration: How long to wait for the service
+
+[services.postgres]?  # PostgreSQL's statistics
+address = "/var/run/postgresql"  # string: host:port, or the socket or its directory
+user = "postgres"  # string: Connect as this role
+password ~ "changeme"  # secret: The role's password
+database = "postgres"  # string: Connect to this database
+timeout = "3s"  # duration: How long to wait for the service
+
+[services.mysql]?  # MySQL's status variables
+address = "/run/mysqld/mysqld.sock"  # string: host:port or the socket
+user = "root"  # string: Connect as this user
+password ~ "changeme"  # secret: The user's password
+timeout = "3s"  # duration: How long to wait for the service
+
+[services.redis]?  # Redis' INFO
+address = "127.0.0.1:6379"  # string: host:port or the socket
+user ~ "metrixd"  # string: ACL user, with password
+password ~ "changeme"  # secret: AUTH password
+timeout = "3s"  # duration: How long to wait for the service
+
+[sysctl]  # Kernel parameters to export (Linux, startup only)
+names = ["fs.file-max", "kernel.pid_max", "net.core.somaxconn", "net.ipv4.ip_forward", "net.ipv4.tcp_congestion_control", "vm.overcommit_memory", "vm.swappiness"]  # strings: Dotted names
+
+[packages]?  # Pending package updates (Linux, startup only)
+interval = "1h"  # duration: How often the package manager is asked
+timeout = "2m"  # duration: Kill the package manager after this
+
+[fsprobe]  # Filesystem write probes (startup only)
+paths = []  # strings: Directories to write, fsync and read a file in every cycle
+timeout = "10s"  # duration: A probe running longer counts as stalled
+
+[dirsize]  # Directory tree sizes (startup only)
+paths = []  # strings: Trees to measure
+max_depth = 16  # integer: Don't walk directories nested deeper than this
+interval = "1m"  # duration: Walk each tree at most this often
+
+[freshness]  # File ages (startup only)
+files = []  # strings: Files to export the age of
+
+[logs]  # Log lines matching patterns (startup only)
+files = []  # strings: Files to follow like tail -F
+
+[logs.patterns]{}  # Lines matching each regex are counted in log_matches_total
+error ~ '(?i)\berror\b'  # string
+
+[sockets.remote_networks]{}  # Networks to count connections by; replaces loopback and internal
+office ~ ["192.0.2.0/24", "2001:db8::/32"]  # strings
+
+[memory]  # The memory collector (startup only)
+slab_top = 10  # integer: Export the largest slab caches by name (Linux)
+
+[processes]  # The processes collector (startup only)
+top = 10  # integer: Export the processes with the most file descriptors
+
+[thresholds]  # Levels to count the cycles above of (startup only)
+load_per_core = [1, 2]  # numbers: Multiples of the core count
+cpu_percent = [90]  # numbers: CPU usage percentages
+
+[plugins]  # Collector plugins (startup only)
+directory ~ "/usr/lib/metrixd/plugins"  # string: Load the shared libraries (Unix) and WASM modules in here
+wasm_read_paths = []  # strings: Files and directories WASM plugins may read below
+
+[debug]  # Debugging aids
+dump_directory ~ "/var/tmp/metrixd"  # string: Where SIGUSR1 writes; stdout by default
+
+[histograms]{}  # Bucket overrides by histogram name (startup only)
+cpu_load_distribution ~ [0, 25, 50, 75, 90, 100]  # numbers
+
+[labels]{}  # Added to every series that doesn't have the label
+env ~ "prod"  # string
+
+[collectors.disk]*  # Settings of one collector
+enabled = true  # boolean: Collect and expose it
+
+[scripts.memory_used_ratio]*  # A gauge computed from other metrics (startup only)
+help ~ "Share of memory in use"  # string: The metric's help
+expression ~ 'value("memory_used_bytes") / value("memory_total_bytes")'  # string: Required
+"#;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Shape {
+    Fixed,
+    Optional,
+    Named,
+    Map,
+}
+
+struct Table {
+    path: &'static str,
+    help: &'static str,
+    shape: Shape,
+    /// For a `Map`, the example entry
+    keys: Vec<Key>,
+}
+
+struct Key {
+    name: &'static str,
+    kind: &'static str,
+    value: &'static str,
+    default: bool,
+    help: &'static str,
+}
+
+fn tables() -> Vec<Table> {
+    let mut tables: Vec<Table> = Vec::new();
+    for line in SPEC.lines().filter(|line| !line.is_empty()) {
+        let (spec, help) = line.split_once("  # ").expect("every line has help");
+        if let Some(header) = spec.strip_prefix('[') {
+            let (path, shape) = header.split_once(']').expect("a table path");
+            let shape = match shape {
+                "" => Shape::Fixed,
+                "?" => Shape::Optional,
+                "*" => Shape::Named,
+                "{}" => Shape::Map,
+                other => panic!("unknown table shape {}", other),
+            };
+            tables.push(Table {
+                path,
+                help,
+                shape,
+                keys: Vec::new(),
+            });
+            continue;
+        }
+        let (name, value, default) = match spec.split_once(" = ") {
+            Some((name, value)) => (name, value, true),
+            None => {
+                let (name, value) = spec.split_once(" ~ ").expect("a default or an example");
+                (name, value, false)
+            }
+        };
+        let (kind, help) = help.split_once(": ").unwrap_or((help, ""));
+        tables
+            .last_mut()
+            .expect("keys follow a table")
+            .keys
+            .push(Key {
+                name,
+                kind,
+                value,
+                default,
+                help,
+            });
+    }
+    tables
+}
+
+/// A config file with every setting at its default. Settings that are
+/// unset by default, and tables that are only read when present, are
+/// commented out with an example.
+pub fn default_toml() -> String {
+    render(false)
+}
+
+/// With `examples`, every table and key is written out
+fn render(examples: bool) -> String {
+    let mut toml = String::new();
+    if !examples {
+        toml.push_str(
+            "# metrixd configuration with every setting at its default. Settings\n\
+             # and tables that are commented out are unset by default.\n",
+        );
+    }
+    let comment = |commented: bool| if commented && !examples { "# " } else { "" };
+    for table in tables() {
+        let table_comment = comment(table.shape != Shape::Fixed);
+        let _ = write!(
+            toml,
+            "\n# {}\n{}[{}]\n",
+            table.help, table_comment, table.path
+        );
+        for key in &table.keys {
+            if !key.help.is_empty() {
+                let _ = writeln!(toml, "# {}", key.help);
+            }
+            let key_comment = match table.shape {
+                Shape::Fixed => comment(!key.default),
+                _ => table_comment,
+            };
+            let _ = writeln!(toml, "{}{} = {}", key_comment, key.name, key.value);
+        }
+    }
+    toml
+}
+
+/// A JSON Schema (draft 2020-12) of the config file, for editors and
+/// validators. Checks beyond types, such as of durations, regexes and
+/// addresses, are left to `check-config`.
+pub fn json_schema() -> String {
+    let mut root = Node::default();
+    let mut named = Vec::new();
+    for table in tables() {
+        let mut node = &mut root;
+        let mut parts: Vec<&'static str> = table.path.split('.').collect();
+        if table.shape == Shape::Named {
+            named.push(table.path);
+        }
+        // Any name, also in the paths of the tables below a named one
+        for path in &named {
+            if table.path == *path || table.path.starts_with(&format!("{}.", path)) {
+                parts[path.split('.').count() - 1] = "*";
+            }
+        }
+        for part in parts {
+            node = node.child(part);
+        }
+        node.table = Some(table);
+    }
+    format!(
+        "{{\"$schema\":\"https://json-schema.org/draft/2020-12/schema\",\
+         \"title\":\"metrixd configuration\",{}",
+        &root.schema()[1..]
+    )
+}
+
+#[derive(Default)]
+struct Node {
+    table: Option<Table>,
+    /// `*` for a named table
+    children: Vec<(&'static str, Node)>,
+}
+
+impl Node {
+    fn child(&mut self, name: &'static str) -> &mut Node {
+        let index = match self.children.iter().position(|(child, _)| *child == name) {
+            Some(index) => index,
+            None => {
+                self.children.push((name, Node::default()));
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[index].1
+    }
+
+    fn schema(&self) -> String {
+        let mut properties = Vec::new();
+        let mut additional = "false".to_string();
+        let mut description = String::new();
+        if let Some(table) = &self.table {
+            description = format!("\"description\":{},", json_string(table.help));
+            if table.shape == Shape::Map {
+                additional = kind_schema(table.keys[0].kind).to_string();
+            } else {
+                for key in &table.keys {
+                    properties.push(format!("{}:{}", json_string(key.name), key_schema(key)));
+                    if key.kind == "secret" {
+                        properties.push(format!(
+                            "{}:{{\"type\":\"string\",\"description\":{}}}",
+                            json_string(&format!("{}_file", key.name)),
+                            json_string(&format!("File holding {}", key.name))
+                        ));
+                    }
+                }
+            }
+        }
+        for (name, child) in &self.children {
+            match *name {
+                "*" => additional = child.schema(),
+                name => properties.push(format!("{}:{}", json_string(name), child.schema())),
+            }
+        }
+        format!(
+            "{{\"type\":\"object\",{}\"properties\":{{{}}},\"additionalProperties\":{}}}",
+            description,
+            properties.join(","),
+            additional
+        )
+    }
+}
+
+fn key_schema(key: &Key) -> String {
+    let schema = kind_schema(key.kind);
+    let default = if key.default {
+        format!(",\"default\":{}", key.value)
+    } else {
+        String::new()
+    };
+    format!(
+        "{},\"description\":{}{}}}",
+        &schema[..schema.len() - 1],
+        json_string(key.help),
+        default
+    )
+}
+
+fn kind_schema(kind: &str) -> &'static str {
+    match kind {
+        "boolean" => "{\"type\":\"boolean\"}",
+        "integer" => "{\"type\":\"integer\"}",
+        "string" | "secret" => "{\"type\":\"string\"}",
+        "duration" => "{\"type\":\"string\",\"pattern\":\"^[0-9]+(ms|s|m|h)$\"}",
+        "url" => "{\"type\":\"string\",\"pattern\":\"^http://\"}",
+        "strings" => "{\"type\":\"array\",\"items\":{\"type\":\"string\"}}",
+        "numbers" => "{\"type\":\"array\",\"items\":{\"type\":\"number\"}}",
+        other => panic!("unknown type {}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn default_file_matches_the_defaults() {
+        let config = Config::parse(&default_toml()).unwrap();
+        assert_eq!(format!("{:?}", config), format!("{:?}", Config::default()));
+    }
+
+    #[test]
+    fn every_key_is_known_to_the_parser() {
+        let errors = Config::parse(&render(true)).unwrap_err().errors;
+        // The examples of alternatives can't all be set at once
+        assert_eq!(
+            errors,
+            [
+                "export.datadog: api_url cannot be combined with dogstatsd_address",
+                "services.haproxy: stats_socket cannot be combined with url",
+            ]
+        );
+    }
+
+    #[test]
+    fn schema_describes_every_table() {
+        let schema = json_schema();
+        assert!(schema.starts_with(
+            "{\"$schema\":\"https://json-schema.org/draft/2020-12/schema\",\
+             \"title\":\"metrixd configuration\",\"type\":\"object\",\"properties\":{\"web\":"
+        ));
+        assert!(schema.contains(
+            "\"listen_address\":{\"type\":\"string\",\"description\":\
+             \"Address to serve /metrics on\",\"default\":\"0.0.0.0:9100\"}"
+        ));
+        assert!(schema.contains("\"admin_token_file\":{\"type\":\"string\""));
+        // Named tables take any name
+        assert!(schema.contains(
+            "\"collectors\":{\"type\":\"object\",\"properties\":{},\"additionalProperties\":\
+             {\"type\":\"object\",\"description\":\"Settings of one collector\""
+        ));
+        assert!(schema.contains(
+            "\"labels\":{\"type\":\"object\",\"description\":\"Added to every series that \
+             doesn't have the label\",\"properties\":{},\
+             \"additionalProperties\":{\"type\":\"string\"}}"
+        ));
+    }
+}
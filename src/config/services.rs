@@ -0,0 +1,181 @@
+//! `[services.<name>]` tables, one per collector of a local service such
+//! as HAProxy. A collector only reads its service when its table is
+//! present; each has a `timeout` key plus its own settings.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use super::export::check_address;
+use super::Section;
+
+/// Services whose collectors are enabled. Applied at startup only.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServicesConfig {
+    pub haproxy: Option<HaproxyConfig>,
+    pub nginx: Option<NginxConfig>,
+    pub postgres: Option<PostgresConfig>,
+    pub mysql: Option<MysqlConfig>,
+    pub redis: Option<RedisConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HaproxyConfig {
+    pub stats: HaproxyStats,
+    /// How long reading the statistics may take
+    pub timeout: Duration,
+}
+
+/// Where HAProxy's statistics are read from
+#[derive(Debug, Clone, PartialEq)]
+pub enum HaproxyStats {
+    /// A `stats socket`, asked for `show stat`
+    Socket(PathBuf),
+    /// The stats page, fetched as CSV
+    Url(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NginxConfig {
+    /// `stub_status` URLs, each one an `instance`
+    pub urls: Vec<String>,
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostgresConfig {
+    /// `host:port`, or a unix socket or the directory it's in
+    pub address: String,
+    pub user: String,
+    pub password: Option<String>,
+    pub database: String,
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MysqlConfig {
+    /// `host:port` or a unix socket
+    pub address: String,
+    pub user: String,
+    pub password: Option<String>,
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedisConfig {
+    /// `host:port` or a unix socket
+    pub address: String,
+    /// An ACL user; the password alone authenticates as `default`
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub timeout: Duration,
+}
+
+pub(super) fn parse(
+    mut services: Section,
+    secret_files: &mut Vec<PathBuf>,
+    errors: &mut Vec<String>,
+) -> ServicesConfig {
+    let mut config = ServicesConfig::default();
+    for name in services.keys() {
+        let mut section = services.section(&name, errors);
+        let timeout = section
+            .duration("timeout", errors)
+            .unwrap_or(Duration::from_secs(3));
+        match name.as_str() {
+            "haproxy" => {
+                let socket = section.string("stats_socket", errors);
+                let url = section.url("url", errors);
+                let stats = match (socket, url) {
+                    (Some(socket), None) => HaproxyStats::Socket(PathBuf::from(socket)),
+                    (None, Some(url)) => HaproxyStats::Url(url),
+                    (Some(_), Some(_)) => {
+                        errors.push(
+                            "services.haproxy: stats_socket cannot be combined with url"
+                                .to_string(),
+                        );
+                        continue;
+                    }
+                    (None, None) => HaproxyStats::Socket(PathBuf::from("/run/haproxy/admin.sock")),
+                };
+                config.haproxy = Some(HaproxyConfig { stats, timeout });
+            }
+            "nginx" => {
+                let mut urls = section.string_list("urls", errors);
+                for url in &urls {
+                    if let Err(e) = crate::fetch::parse_url(url) {
+                        errors.push(format!("services.nginx.urls: {}", e));
+                    }
+                }
+                if urls.is_empty() {
+                    urls.push("http://127.0.0.1/nginx_status".to_string());
+                }
+                config.nginx = Some(NginxConfig { urls, timeout });
+            }
+            "postgres" => {
+                let address = section
+                    .string("address", errors)
+                    .unwrap_or_else(|| "/var/run/postgresql".to_string());
+                if !address.starts_with('/') {
+                    check_address("services.postgres.address", &address, errors);
+                }
+                config.postgres = Some(PostgresConfig {
+                    address,
+                    user: section
+                        .string("user", errors)
+                        .unwrap_or_else(|| "postgres".to_string()),
+                    password: section.secret("password", secret_files, errors),
+                    database: section
+                        .string("database", errors)
+                        .unwrap_or_else(|| "postgres".to_string()),
+                    timeout,
+                });
+            }
+            "mysql" => {
+                let address = section
+                    .string("address", errors)
+                    .unwrap_or_else(|| "/run/mysqld/mysqld.sock".to_string());
+                if !address.starts_with('/') {
+                    check_address("services.mysql.address", &address, errors);
+                }
+                config.mysql = Some(MysqlConfig {
+                    address,
+                    user: section
+                        .string("user", errors)
+                        .unwrap_or_else(|| "root".to_string()),
+                    password: section.secret("password", secret_files, errors),
+                    timeout,
+                });
+            }
+            "redis" => {
+                let address = section
+                    .string("address", errors)
+                    .unwrap_or_else(|| "127.0.0.1:6379".to_string());
+                if !address.starts_with('/') {
+                    check_address("services.redis.address", &address, errors);
+                }
+                let user = section.string("user", errors);
+                let password = section.secret("password", secret_files, errors);
+                if user.is_some() && password.is_none() {
+                    errors.push("services.redis: user needs a password".to_string());
+                }
+                config.redis = Some(RedisConfig {
+                    address,
+                    user,
+                    password,
+                    timeout,
+                });
+            }
+            _ => {
+                errors.push(format!(
+                    "services.{}: unknown service \
+                     (available: haproxy, mysql, nginx, postgres, redis)",
+                    name
+                ));
+                continue;
+            }
+        }
+        section.finish(errors);
+    }
+    services.finish(errors);
+    config
+}
@@ -0,0 +1,613 @@
+//! The daemon itself: startup, sandboxing, the collection loop and the
+//! servers. `MetrixdBuilder` is also the entry point for applications that
+//! embed metrixd and want their own collectors served next to the built-in
+//! ones.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use prometheus::proto::MetricFamily;
+use prometheus::{Encoder, TextEncoder};
+use tokio::sync::watch;
+use tokio::task;
+
+use crate::cli::{Args, Command};
+use crate::collector::Collector;
+use crate::config::{self, Config};
+use crate::schedule::{Conditions, Schedule};
+use crate::state::AppState;
+use crate::{export, grpc, metrics, plugins, privileges, replay, sandbox, server};
+
+/// The bound mDNS socket and what to advertise on it
+#[cfg(unix)]
+type MdnsResponder = (std::net::UdpSocket, crate::mdns::Advertisement);
+#[cfg(not(unix))]
+type MdnsResponder = std::convert::Infallible;
+
+/// Configures and runs metrixd, e.g. from an application's `main`:
+///
+/// ```ignore
+/// metrixd::MetrixdBuilder::new(metrixd::cli::Args::parse())
+///     .with_collector(Box::new(OrdersCollector::new()))
+///     .run();
+/// ```
+pub struct MetrixdBuilder {
+    args: Args,
+    collectors: Vec<Box<dyn Collector + Send + Sync>>,
+}
+
+impl MetrixdBuilder {
+    pub fn new(args: Args) -> Self {
+        MetrixdBuilder {
+            args,
+            collectors: Vec::new(),
+        }
+    }
+
+    /// Add a collector to run and serve alongside the built-in ones. Its
+    /// metrics must be registered with the served registry, either when
+    /// built with `metrics::builder::metric` or in `register_metrics`,
+    /// which is passed that registry. Names must be unique.
+    pub fn with_collector(mut self, collector: Box<dyn Collector + Send + Sync>) -> Self {
+        self.collectors.push(collector);
+        self
+    }
+
+    /// Run the configured command. Serving returns once shutdown is
+    /// requested; the other commands and startup errors exit the process.
+    // Startup runs before the tokio runtime exists so the Landlock sandbox
+    // can be applied while the process is still single-threaded
+    pub fn run(self) {
+        let MetrixdBuilder {
+            args,
+            collectors: extra_collectors,
+        } = self;
+
+        match args.command {
+            Command::CheckConfig => check_config(&args),
+            // Neither needs a config file, nor should one break them
+            Command::PrintDefaultConfig => {
+                print!("{}", config::default_toml());
+                std::process::exit(0);
+            }
+            Command::ConfigSchema => {
+                println!("{}", config::json_schema());
+                std::process::exit(0);
+            }
+            _ => {}
+        }
+
+        let command = args.command.clone();
+        let config_file = args.config_file.clone();
+        let recording = args.recording.clone();
+        let replay_loop = args.replay_loop;
+        let state = match AppState::new(args) {
+            Ok(state) => Arc::new(state),
+            Err(e) => {
+                eprintln!("Failed to load configuration:\n{}", e);
+                std::process::exit(1);
+            }
+        };
+
+        let config = state.config();
+
+        // Before serving, so the first scrape already has the labels
+        let cloud = &config.cloud;
+        if (cloud.metadata_labels || cloud.identity_info) && command != Command::ListCollectors {
+            if let Some((provider, instance)) = crate::cloud::detect() {
+                if cloud.metadata_labels {
+                    state.set_cloud_labels(instance.labels());
+                }
+                state.set_cloud_identity(provider, &instance.id);
+            }
+        }
+
+        // Plugins are read before the filesystem is confined
+        let plugins = plugins::open(&config.plugins).unwrap_or_else(|e| {
+            eprintln!("Failed to load plugins: {}", e);
+            std::process::exit(1);
+        });
+
+        // Like plugins, read the whole recording before the sandbox is applied
+        let replay = match (&command, &recording) {
+            (Command::Replay, Some(path)) => Some(replay::load(path).unwrap_or_else(|e| {
+                eprintln!("Failed to load recording: {}", e);
+                std::process::exit(1);
+            })),
+            _ => None,
+        };
+
+        // Only the daemon is sandboxed; one-shot commands exit right away. Look
+        // the user up while /etc is still readable, then confine the filesystem
+        // before collectors start any threads.
+        let mut identity = None;
+        if command == Command::Serve || command == Command::Replay {
+            identity = privileges::Identity::resolve(&config.security).unwrap_or_else(|e| {
+                eprintln!("Failed to drop privileges: {}", e);
+                std::process::exit(1);
+            });
+            if let Err(e) = sandbox::restrict_filesystem(&config, config_file.as_deref()) {
+                eprintln!("Failed to apply Landlock rules: {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        // Create your collectors
+        // A replay serves recorded values only, so nothing is collected
+        let mut collectors = match command {
+            Command::Replay => Vec::new(),
+            _ => metrics::all_collectors(&config),
+        };
+        let plugin_collectors = plugins.iter().map(plugins::Plugin::create_collector);
+        for collector in extra_collectors.into_iter().chain(plugin_collectors) {
+            if collectors.iter().any(|c| c.name() == collector.name()) {
+                eprintln!("Duplicate collector name: {}", collector.name());
+                std::process::exit(1);
+            }
+            collectors.push(collector);
+        }
+
+        // Leave out what can't work on this host, saying why, rather than
+        // failing every cycle or exporting nothing useful
+        if command != Command::ListCollectors {
+            collectors.retain(|collector| {
+                let reason = metrics::unavailable_reason(collector.as_ref());
+                state.set_collector_available(collector.name(), reason.is_none());
+                if let Some(reason) = &reason {
+                    eprintln!("Collector {} is unavailable: {}", collector.name(), reason);
+                }
+                reason.is_none()
+            });
+        }
+
+        // Register all metrics. A metric registered already, e.g. one an
+        // embedding application built twice, is served as it is
+        for collector in &collectors {
+            match collector.register_metrics(state.registry()) {
+                Ok(()) | Err(prometheus::Error::AlreadyReg) => {}
+                Err(e) => {
+                    let e = match e {
+                        prometheus::Error::Msg(message) => message,
+                        e => e.to_string(),
+                    };
+                    eprintln!("Failed to register the {} metrics: {}", collector.name(), e);
+                    std::process::exit(1);
+                }
+            }
+            state.register_collector(collector.as_ref());
+        }
+
+        match command {
+            Command::Collect => collect_once(&collectors, &state),
+            Command::DryRun => dry_run(&collectors, &state),
+            Command::ListCollectors => list_collectors(&collectors, &state),
+            Command::Push => push_once(&collectors, &state),
+            Command::Record => {
+                let path = recording.as_deref().expect("record always has a path");
+                if let Err(e) = replay::record(&collectors, &state, path) {
+                    eprintln!("Failed to record: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            _ => {}
+        }
+
+        // Bind every port while still privileged, then drop to the configured
+        // user so the network-facing part of the daemon never runs as root
+        let web_listener = bind_or_exit(config.web.listen_address);
+        let grpc_listener = config.grpc.listen_address.map(bind_or_exit);
+        let mdns = bind_mdns_or_exit(&config, &state.instance_id());
+        let exports = export::Exports::new(&config, state.registry(), &state.instance_id())
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to set up exporters: {}", e);
+                std::process::exit(1);
+            });
+        if let Some(identity) = identity {
+            if let Err(e) = identity.switch() {
+                eprintln!("Failed to drop privileges: {}", e);
+                std::process::exit(1);
+            }
+        }
+        if let Err(e) = sandbox::restrict_syscalls(&config.security) {
+            eprintln!("Failed to apply seccomp filter: {}", e);
+            std::process::exit(1);
+        }
+
+        let runtime = tokio::runtime::Runtime::new().expect("failed to start the tokio runtime");
+        let replay = replay.map(|cycles| (cycles, replay_loop));
+        runtime.block_on(run(
+            state,
+            collectors,
+            replay,
+            web_listener,
+            grpc_listener,
+            mdns,
+            exports,
+        ));
+    }
+}
+
+/// Run the collection loop and the servers until shutdown is requested
+async fn run(
+    state: Arc<AppState>,
+    collectors: Vec<Box<dyn Collector + Send + Sync>>,
+    replay: Option<(Vec<replay::Cycle>, bool)>,
+    web_listener: TcpListener,
+    grpc_listener: Option<TcpListener>,
+    mdns: Option<MdnsResponder>,
+    exports: export::Exports,
+) {
+    if let Some((cycles, repeat)) = replay {
+        let player = replay::Player::default();
+        state
+            .registry()
+            .register(Box::new(player.clone()))
+            .expect("failed to register the replay");
+        println!("Replaying {} cycles", cycles.len());
+        let state = Arc::clone(&state);
+        task::spawn(async move { player.play(cycles, repeat, &state).await });
+    }
+
+    let runtime = metrics::RuntimeCollector::new(tokio::runtime::Handle::current())
+        .expect("invalid runtime metrics");
+    state
+        .registry()
+        .register(Box::new(runtime))
+        .expect("failed to register runtime metrics");
+
+    // Shared with the blocking collection threads
+    let collectors = Arc::new(collectors);
+
+    // Bumped after every collection cycle so streaming consumers can follow along
+    let (cycle_tx, cycle_rx) = watch::channel(0u64);
+
+    // Spawn a background task to update metrics periodically
+    {
+        let collectors = Arc::clone(&collectors);
+        let state = Arc::clone(&state);
+        task::spawn(async move {
+            let mut schedule = Schedule::new();
+            let delay = schedule.first_delay(&state.config().collection, SystemTime::now());
+            if !delay.is_zero() {
+                println!("First collection in {:?}", delay);
+                tokio::time::sleep(delay).await;
+            }
+            loop {
+                // Collectors make blocking syscalls (sysinfo refreshes, /proc
+                // reads), so run them on the blocking pool where a slow one
+                // can't stall the HTTP and gRPC servers
+                let cycle = {
+                    let collectors = Arc::clone(&collectors);
+                    let state = Arc::clone(&state);
+                    task::spawn_blocking(move || {
+                        for collector in collectors.iter() {
+                            if state.run_collector(collector.as_ref()) {
+                                println!("Collected metrics..");
+                            }
+                        }
+                    })
+                };
+                if let Err(e) = cycle.await {
+                    eprintln!("Collection cycle panicked: {}", e);
+                }
+                if let Some(name) = state.limit_memory() {
+                    // Disabling only stops refreshing its series; free them
+                    if let Some(collector) = collectors.iter().find(|c| c.name() == name) {
+                        collector.reset_metrics(state.registry());
+                    }
+                }
+                state.mark_changed();
+                cycle_tx.send_modify(|cycle| *cycle += 1);
+                // Re-read every cycle so a reload can change the interval
+                let config = state.config();
+                let busy = config
+                    .thresholds
+                    .load_per_core
+                    .iter()
+                    .copied()
+                    .reduce(f64::min)
+                    .unwrap_or(1.0);
+                let conditions = Conditions::read(Path::new("/"));
+                let interval = schedule.adapt(&config.collection, busy, conditions);
+                state.set_collection_interval(interval);
+                let delay = schedule.next_delay(&config.collection, SystemTime::now());
+                tokio::time::sleep(delay).await;
+            }
+        });
+    }
+
+    // Push each cycle's values to the configured exporters, off the
+    // collection loop so a slow backend can't delay it
+    if !exports.is_empty() {
+        let state = Arc::clone(&state);
+        let mut cycles = cycle_rx.clone();
+        task::spawn(async move {
+            let mut exports = exports;
+            while cycles.changed().await.is_ok() {
+                let state = Arc::clone(&state);
+                let pushed = task::spawn_blocking(move || {
+                    exports.run(&state);
+                    exports
+                });
+                exports = match pushed.await {
+                    Ok(exports) => exports,
+                    Err(e) => {
+                        eprintln!("Exporters panicked: {}", e);
+                        return;
+                    }
+                };
+            }
+        });
+    }
+
+    // Reload the configuration on SIGHUP, like Prometheus does
+    #[cfg(unix)]
+    {
+        let state = Arc::clone(&state);
+        task::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+                return;
+            };
+            while hangup.recv().await.is_some() {
+                if let Err(e) = state.reload() {
+                    eprintln!("Failed to reload configuration: {}", e);
+                }
+            }
+        });
+    }
+
+    // Dump the current exposition on SIGUSR1, for hosts where the port is
+    // unreachable
+    #[cfg(unix)]
+    {
+        let state = Arc::clone(&state);
+        task::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let Ok(mut usr1) = signal(SignalKind::user_defined1()) else {
+                return;
+            };
+            while usr1.recv().await.is_some() {
+                match crate::dump::dump(&state) {
+                    Ok(Some(path)) => println!("Dumped metrics to {}", path.display()),
+                    Ok(None) => {}
+                    Err(e) => eprintln!("Failed to dump metrics: {}", e),
+                }
+            }
+        });
+    }
+
+    // Optionally serve the gRPC query API alongside /metrics
+    if let Some(grpc_listener) = grpc_listener {
+        let state = Arc::clone(&state);
+        task::spawn(async move {
+            if let Err(e) = grpc::serve(grpc_listener, state, cycle_rx).await {
+                eprintln!("gRPC server error: {}", e);
+            }
+        });
+    }
+
+    // Advertise the endpoint on the LAN
+    #[cfg(unix)]
+    let mdns = mdns.map(|(socket, advertisement)| {
+        let state = Arc::clone(&state);
+        task::spawn(async move {
+            if let Err(e) = crate::mdns::advertise(socket, advertisement, state).await {
+                eprintln!("mDNS responder error: {}", e);
+            }
+        })
+    });
+
+    // Start HTTP server to expose metrics
+    server::serve(web_listener, state).await.unwrap();
+
+    // Give the responder a moment to send its goodbye
+    #[cfg(unix)]
+    if let Some(mdns) = mdns {
+        let _ = tokio::time::timeout(std::time::Duration::from_secs(1), mdns).await;
+    }
+    #[cfg(not(unix))]
+    let _ = mdns;
+}
+
+/// Bind the mDNS port if advertising is enabled; like the listeners, while
+/// still privileged and before seccomp
+fn bind_mdns_or_exit(config: &Config, instance: &str) -> Option<MdnsResponder> {
+    if !config.mdns.enabled {
+        return None;
+    }
+    #[cfg(unix)]
+    {
+        match crate::mdns::bind() {
+            Ok(socket) => Some((socket, crate::mdns::Advertisement::new(config, instance))),
+            Err(e) => {
+                eprintln!("Failed to bind the mDNS port: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        eprintln!("mDNS advertisement is only supported on Unix");
+        None
+    }
+}
+
+fn bind_or_exit(addr: SocketAddr) -> TcpListener {
+    match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to listen on {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Run every collector a single time
+fn collect_all(collectors: &[Box<dyn Collector + Send + Sync>], state: &AppState) {
+    // sysinfo needs two CPU refreshes some time apart to compute usage
+    #[cfg(feature = "cpu")]
+    std::thread::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
+
+    for collector in collectors {
+        state.run_collector(collector.as_ref());
+    }
+}
+
+/// Run every collector a single time, print the exposition to stdout and exit
+fn collect_once(collectors: &[Box<dyn Collector + Send + Sync>], state: &AppState) -> ! {
+    collect_all(collectors, state);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&server::gather_filtered(state), &mut buffer) {
+        eprintln!("Failed to encode metrics: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = std::io::stdout().write_all(&buffer) {
+        eprintln!("Failed to write metrics: {}", e);
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
+
+/// Run every collector a single time, push the values to every configured
+/// exporter and exit, unsuccessfully if a push failed, so a systemd timer
+/// can stand in for the daemon on devices where one is too much
+fn push_once(collectors: &[Box<dyn Collector + Send + Sync>], state: &AppState) -> ! {
+    let mut exports = export::Exports::new(&state.config(), state.registry(), &state.instance_id())
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to set up exporters: {}", e);
+            std::process::exit(1);
+        });
+    if exports.is_empty() {
+        eprintln!("No exporters configured; add an [export.<name>] table");
+        std::process::exit(1);
+    }
+    collect_all(collectors, state);
+    let failed = exports.run_all(state);
+    std::process::exit(if failed == 0 { 0 } else { 1 });
+}
+
+/// Run every collector a single time, then print each one's metrics with
+/// their label keys and the series they exported, and exit
+fn dry_run(collectors: &[Box<dyn Collector + Send + Sync>], state: &AppState) -> ! {
+    collect_all(collectors, state);
+
+    let mut families: BTreeMap<String, MetricFamily> = server::gather_filtered(state)
+        .into_iter()
+        .map(|family| (family.name().to_string(), family))
+        .collect();
+    let mut total = (0, 0);
+    for collector in collectors {
+        if !state.is_collector_enabled(collector.name()) {
+            println!("{} (disabled)", collector.name());
+            continue;
+        }
+        let metrics: Vec<_> = collector
+            .metrics()
+            .iter()
+            .flat_map(|metric| metric.desc())
+            .map(|desc| match families.remove(&desc.fq_name) {
+                Some(family) => inventory_entry(&family),
+                // Nothing exported, or dropped by `metrics.exclude`
+                None => (desc.fq_name.clone(), desc.variable_labels.clone(), 0),
+            })
+            .collect();
+        print_inventory(collector.name(), &metrics, &mut total);
+    }
+    // What is left are the self-metrics
+    let metrics: Vec<_> = families.values().map(inventory_entry).collect();
+    print_inventory("metrixd", &metrics, &mut total);
+
+    println!("{} series in {} metrics", total.0, total.1);
+    std::process::exit(0);
+}
+
+/// Name, label keys and series count of a gathered family
+fn inventory_entry(family: &MetricFamily) -> (String, Vec<String>, usize) {
+    let mut labels: Vec<String> = Vec::new();
+    for label in family.get_metric().iter().flat_map(|m| m.get_label()) {
+        if !labels.iter().any(|name| name == label.name()) {
+            labels.push(label.name().to_string());
+        }
+    }
+    (
+        family.name().to_string(),
+        labels,
+        server::series_count(family),
+    )
+}
+
+/// Print one collector's metrics, adding its series and metrics to `total`
+fn print_inventory(
+    name: &str,
+    metrics: &[(String, Vec<String>, usize)],
+    total: &mut (usize, usize),
+) {
+    let series: usize = metrics.iter().map(|(_, _, series)| series).sum();
+    println!("{} ({} series)", name, series);
+    for (metric, labels, series) in metrics {
+        if labels.is_empty() {
+            println!("  {}: {}", metric, series);
+        } else {
+            println!("  {}{{{}}}: {}", metric, labels.join(","), series);
+        }
+    }
+    total.0 += series;
+    total.1 += metrics.len();
+}
+
+/// Print every collector with its state, supported platforms and metrics
+fn list_collectors(collectors: &[Box<dyn Collector + Send + Sync>], state: &AppState) -> ! {
+    for collector in collectors {
+        let platforms = collector.platforms();
+        let status = if let Some(reason) = metrics::unavailable_reason(collector.as_ref()) {
+            format!("unavailable: {}", reason)
+        } else if state.is_collector_enabled(collector.name()) {
+            "enabled".to_string()
+        } else {
+            "disabled".to_string()
+        };
+
+        println!("{} ({})", collector.name(), status);
+        println!("  platforms: {}", platforms.join(", "));
+        println!("  metrics:");
+        for name in collector.metric_names() {
+            println!("    {}", name);
+        }
+    }
+
+    for name in metrics::COLLECTOR_NAMES {
+        if !collectors.iter().any(|c| c.name() == *name) {
+            println!("{} (not included in this build)", name);
+        }
+    }
+    std::process::exit(0);
+}
+
+/// Validate the config file, print every problem found and exit
+fn check_config(args: &Args) -> ! {
+    let path = args
+        .config_file
+        .as_deref()
+        .expect("check-config always has a path");
+
+    match Config::load(path) {
+        Ok(_) => {
+            println!("SUCCESS: {} is valid", path.display());
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("FAILED: {} is invalid:", path.display());
+            for error in &e.errors {
+                eprintln!("  - {}", error);
+            }
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! SIGUSR1 writes the current exposition, exactly as /metrics would serve
+//! it, to a timestamped file in `debug.dump_directory`, or to stdout
+//! without one. Useful on hosts where the port is firewalled.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use prometheus::{Encoder, TextEncoder};
+
+use crate::server::gather_filtered;
+use crate::state::AppState;
+use crate::utc::Utc;
+
+/// Write the exposition; returns the file written, if any
+pub fn dump(state: &AppState) -> Result<Option<PathBuf>, String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&gather_filtered(state), &mut buffer)
+        .map_err(|e| format!("failed to encode metrics: {}", e))?;
+
+    let Some(directory) = state.config().debug.dump_directory else {
+        let mut stdout = std::io::stdout().lock();
+        stdout
+            .write_all(&buffer)
+            .and_then(|()| stdout.flush())
+            .map_err(|e| format!("stdout: {}", e))?;
+        return Ok(None);
+    };
+
+    let path = directory.join(format!("metrixd-{}.prom", utc_timestamp(SystemTime::now())));
+    std::fs::write(&path, &buffer).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(Some(path))
+}
+
+/// Basic ISO 8601 with milliseconds, e.g. `20261014T093012.345Z`, which
+/// sorts by time and is safe in file names
+fn utc_timestamp(time: SystemTime) -> String {
+    let utc = Utc::from_system_time(time);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}.{:03}Z",
+        utc.year, utc.month, utc.day, utc.hour, utc.minute, utc.second, utc.millis
+    )
+}
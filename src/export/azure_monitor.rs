@@ -0,0 +1,243 @@
+//! `[export.azure_monitor]`: custom metrics for the Azure VM, with its
+//! managed identity. Azure Monitor takes one metric per request, with a
+//! series per dimension set, and keeps one-minute aggregates, so each
+//! value is sent as a single observation. Counters and the other
+//! cumulative samples are sent as their increase since the last push.
+
+use std::collections::BTreeMap;
+use std::time::{Instant, SystemTime};
+
+use super::{is_cumulative, json_string, Deltas, Exporter, TIMEOUT};
+use crate::cloud;
+use crate::config::AzureMonitorConfig;
+use crate::fetch;
+use crate::sample::Sample;
+use crate::utc::Utc;
+
+/// Custom metrics take at most this many dimensions
+const MAX_DIMENSIONS: usize = 10;
+
+pub struct AzureMonitor {
+    config: AzureMonitorConfig,
+    /// Resolved on the first push, from the VM's metadata where not
+    /// configured, and retried until that works
+    target: Option<Target>,
+    token: Option<(String, Instant)>,
+    deltas: Deltas,
+}
+
+struct Target {
+    address: String,
+    path: String,
+    /// The endpoint, whichever proxy `address` is
+    host: String,
+}
+
+/// A metric name and its dimension names
+type MetricKey<'a> = (&'a str, Vec<&'a str>);
+/// Dimension values and the value
+type Series<'a> = (Vec<&'a str>, f64);
+
+impl AzureMonitor {
+    pub fn new(config: &AzureMonitorConfig) -> Result<Self, String> {
+        if let Some(url) = &config.url {
+            fetch::parse_url(url)?;
+        }
+        Ok(AzureMonitor {
+            config: config.clone(),
+            target: None,
+            token: None,
+            deltas: Deltas::default(),
+        })
+    }
+
+    fn resolve_target(&self) -> Result<Target, String> {
+        let (region, resource_id) = match (&self.config.region, &self.config.resource_id) {
+            (Some(region), Some(resource_id)) => (region.clone(), resource_id.clone()),
+            (region, resource_id) => {
+                let (vm_region, vm_resource_id) = cloud::azure_vm()?;
+                (
+                    region.clone().unwrap_or(vm_region),
+                    resource_id.clone().unwrap_or(vm_resource_id),
+                )
+            }
+        };
+        let host = format!("{}.monitoring.azure.com", region);
+        let url = self
+            .config
+            .url
+            .clone()
+            .unwrap_or_else(|| format!("http://{}/", host));
+        let (address, base_path) = fetch::parse_url(&url)?;
+        println!("Writing Azure Monitor custom metrics for {}", resource_id);
+        Ok(Target {
+            address,
+            path: format!(
+                "{}/{}/metrics",
+                base_path.trim_end_matches('/'),
+                resource_id.trim_matches('/')
+            ),
+            host,
+        })
+    }
+
+    fn token(&mut self) -> Result<String, String> {
+        if let Some((token, expires)) = &self.token {
+            if Instant::now() + TIMEOUT < *expires {
+                return Ok(token.clone());
+            }
+        }
+        let (token, valid_for) = cloud::azure_monitor_token()?;
+        self.token = Some((token.clone(), Instant::now() + valid_for));
+        Ok(token)
+    }
+
+    /// One request body per metric and set of dimension names
+    fn bodies(&mut self, samples: &[Sample], now: SystemTime) -> Vec<String> {
+        let mut metrics: BTreeMap<MetricKey, Vec<Series>> = BTreeMap::new();
+        for sample in samples {
+            let value = if is_cumulative(sample) {
+                match self.deltas.delta(sample) {
+                    Some(delta) => delta,
+                    None => continue,
+                }
+            } else {
+                sample.value
+            };
+            if !value.is_finite() {
+                continue;
+            }
+            let labels = &sample.labels[..sample.labels.len().min(MAX_DIMENSIONS)];
+            let names = labels.iter().map(|(name, _)| name.as_str()).collect();
+            let values = labels.iter().map(|(_, value)| value.as_str()).collect();
+            metrics
+                .entry((sample.name.as_str(), names))
+                .or_default()
+                .push((values, value));
+        }
+
+        let time = Utc::from_system_time(now).rfc3339();
+        metrics
+            .into_iter()
+            .map(|(key, series)| self.body(&time, key, &series))
+            .collect()
+    }
+
+    fn body(&self, time: &str, (name, dim_names): MetricKey, series: &[Series]) -> String {
+        let strings = |values: &[&str]| -> String {
+            values
+                .iter()
+                .map(|value| json_string(value))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let series: Vec<String> = series
+            .iter()
+            .map(|(dim_values, value)| {
+                format!(
+                    "{{\"dimValues\":[{}],\"min\":{},\"max\":{},\"sum\":{},\"count\":1}}",
+                    strings(dim_values),
+                    value,
+                    value,
+                    value
+                )
+            })
+            .collect();
+        format!(
+            "{{\"time\":\"{}\",\"data\":{{\"baseData\":{{\"metric\":{},\"namespace\":{},\"dimNames\":[{}],\"series\":[{}]}}}}}}",
+            time,
+            json_string(name),
+            json_string(&self.config.namespace),
+            strings(&dim_names),
+            series.join(",")
+        )
+    }
+}
+
+impl Exporter for AzureMonitor {
+    fn name(&self) -> &'static str {
+        "azure_monitor"
+    }
+
+    fn export(&mut self, samples: &[Sample]) -> Result<(), String> {
+        if self.target.is_none() {
+            self.target = Some(self.resolve_target()?);
+        }
+        let authorization = format!("Bearer {}", self.token()?);
+        let bodies = self.bodies(samples, SystemTime::now());
+        let Some(target) = &self.target else {
+            return Ok(());
+        };
+        for body in bodies {
+            let response = fetch::send(
+                &target.address,
+                "POST",
+                &target.path,
+                &[
+                    ("Host", &target.host),
+                    ("Authorization", &authorization),
+                    ("Content-Type", "application/json"),
+                ],
+                body.as_bytes(),
+                TIMEOUT,
+            )?;
+            if !(200..300).contains(&response.status) {
+                let body = String::from_utf8_lossy(&response.body);
+                return Err(format!(
+                    "{}: HTTP status {}: {}",
+                    target.address,
+                    response.status,
+                    cloud::json_field(&body, "message").unwrap_or_else(|| body.trim().to_string())
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sample;
+    use super::*;
+    use prometheus::proto::MetricType;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn sends_one_request_per_metric() {
+        let mut azure_monitor = AzureMonitor::new(&AzureMonitorConfig {
+            common: Default::default(),
+            namespace: "metrixd".to_string(),
+            region: Some("westeurope".to_string()),
+            resource_id: Some("/subscriptions/s/resourceGroups/g".to_string()),
+            url: None,
+        })
+        .unwrap();
+        let samples = [
+            sample(
+                "cpu_usage_percent",
+                &[("core", "0")],
+                12.5,
+                MetricType::GAUGE,
+            ),
+            sample(
+                "cpu_usage_percent",
+                &[("core", "1")],
+                7.0,
+                MetricType::GAUGE,
+            ),
+            sample("requests_total", &[], 10.0, MetricType::COUNTER),
+        ];
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            azure_monitor.bodies(&samples, now),
+            ["{\"time\":\"2023-11-14T22:13:20.000Z\",\"data\":{\"baseData\":{\
+              \"metric\":\"cpu_usage_percent\",\"namespace\":\"metrixd\",\"dimNames\":[\"core\"],\
+              \"series\":[{\"dimValues\":[\"0\"],\"min\":12.5,\"max\":12.5,\"sum\":12.5,\"count\":1},\
+              {\"dimValues\":[\"1\"],\"min\":7,\"max\":7,\"sum\":7,\"count\":1}]}}}"]
+        );
+
+        let target = azure_monitor.resolve_target().unwrap();
+        assert_eq!(target.address, "westeurope.monitoring.azure.com:80");
+        assert_eq!(target.path, "/subscriptions/s/resourceGroups/g/metrics");
+    }
+}
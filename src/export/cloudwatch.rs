@@ -0,0 +1,323 @@
+//! `[export.cloudwatch]`: PutMetricData to CloudWatch, signed with SigV4.
+//! Every distinct name and dimension set is a billed custom metric, so
+//! only `include`d samples are sent, at most once a minute by default, and
+//! `dimensions` can narrow the labels down. Counters and the other
+//! cumulative samples are sent as their increase since the last push.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use super::{is_cumulative, percent_encode, Deltas, Exporter, TIMEOUT};
+use crate::cloud;
+use crate::config::CloudWatchConfig;
+use crate::fetch;
+use crate::sample::Sample;
+use crate::sigv4::{self, Credentials};
+
+/// PutMetricData's limits per request
+const METRICS_PER_REQUEST: usize = 1000;
+const MAX_DIMENSIONS: usize = 30;
+/// Instance role credentials rotate hourly; refetch well before that
+const ROLE_CREDENTIALS_TTL: Duration = Duration::from_secs(600);
+
+pub struct CloudWatch {
+    address: String,
+    path: String,
+    /// The endpoint signed for, whichever proxy `address` is
+    host: String,
+    region: String,
+    namespace: String,
+    dimensions: Vec<(String, String)>,
+    credentials: CredentialSource,
+    deltas: Deltas,
+}
+
+enum CredentialSource {
+    Static(Credentials),
+    InstanceRole(Option<(Instant, Credentials)>),
+}
+
+impl CredentialSource {
+    fn get(&mut self) -> Result<Credentials, String> {
+        match self {
+            CredentialSource::Static(credentials) => Ok(credentials.clone()),
+            CredentialSource::InstanceRole(cached) => {
+                if let Some((fetched, credentials)) = cached {
+                    if fetched.elapsed() < ROLE_CREDENTIALS_TTL {
+                        return Ok(credentials.clone());
+                    }
+                }
+                let credentials = cloud::ec2_role_credentials()?;
+                *cached = Some((Instant::now(), credentials.clone()));
+                Ok(credentials)
+            }
+        }
+    }
+}
+
+/// One value to send
+struct Datum {
+    name: String,
+    dimensions: Vec<(String, String)>,
+    value: f64,
+    unit: &'static str,
+}
+
+impl CloudWatch {
+    pub fn new(config: &CloudWatchConfig) -> Result<Self, String> {
+        let region = config
+            .region
+            .clone()
+            .or_else(|| std::env::var("AWS_REGION").ok())
+            .or_else(|| std::env::var("AWS_DEFAULT_REGION").ok())
+            .filter(|region| !region.is_empty())
+            .ok_or("export.cloudwatch: no region, nor AWS_REGION or AWS_DEFAULT_REGION")?;
+        let host = format!("monitoring.{}.amazonaws.com", region);
+        let url = config
+            .url
+            .clone()
+            .unwrap_or_else(|| format!("http://{}/", host));
+        let (address, path) = fetch::parse_url(&url)?;
+
+        let credentials = match &config.credentials {
+            Some((access_key_id, secret_access_key)) => CredentialSource::Static(Credentials {
+                access_key_id: access_key_id.clone(),
+                secret_access_key: secret_access_key.clone(),
+                session_token: None,
+            }),
+            None => match (
+                std::env::var("AWS_ACCESS_KEY_ID"),
+                std::env::var("AWS_SECRET_ACCESS_KEY"),
+            ) {
+                (Ok(access_key_id), Ok(secret_access_key)) => {
+                    CredentialSource::Static(Credentials {
+                        access_key_id,
+                        secret_access_key,
+                        session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+                    })
+                }
+                _ => CredentialSource::InstanceRole(None),
+            },
+        };
+
+        Ok(CloudWatch {
+            address,
+            path,
+            host,
+            region,
+            namespace: config.namespace.clone(),
+            dimensions: config.dimensions.clone(),
+            credentials,
+            deltas: Deltas::default(),
+        })
+    }
+
+    fn data(&mut self, samples: &[Sample]) -> Vec<Datum> {
+        let mut data = Vec::new();
+        for sample in samples {
+            let value = if is_cumulative(sample) {
+                match self.deltas.delta(sample) {
+                    Some(delta) => delta,
+                    None => continue,
+                }
+            } else {
+                sample.value
+            };
+            if !value.is_finite() {
+                continue;
+            }
+            // Dimension values may not be empty
+            let dimensions: Vec<(String, String)> = if self.dimensions.is_empty() {
+                sample
+                    .labels
+                    .iter()
+                    .filter(|(_, value)| !value.is_empty())
+                    .cloned()
+                    .collect()
+            } else {
+                self.dimensions
+                    .iter()
+                    .filter_map(|(label, dimension)| {
+                        let (_, value) = sample.labels.iter().find(|(name, _)| name == label)?;
+                        (!value.is_empty()).then(|| (dimension.clone(), value.clone()))
+                    })
+                    .collect()
+            };
+            data.push(Datum {
+                name: sample.name.clone(),
+                dimensions: dimensions.into_iter().take(MAX_DIMENSIONS).collect(),
+                value,
+                unit: unit(&sample.name),
+            });
+        }
+        data
+    }
+}
+
+impl Exporter for CloudWatch {
+    fn name(&self) -> &'static str {
+        "cloudwatch"
+    }
+
+    fn export(&mut self, samples: &[Sample]) -> Result<(), String> {
+        let data = self.data(samples);
+        if data.is_empty() {
+            return Ok(());
+        }
+        let credentials = self.credentials.get()?;
+        let content_type = "application/x-www-form-urlencoded; charset=utf-8";
+        for chunk in data.chunks(METRICS_PER_REQUEST) {
+            let body = put_metric_data_body(&self.namespace, chunk);
+            let headers = sigv4::sign(
+                &credentials,
+                &self.region,
+                "monitoring",
+                &self.host,
+                &self.path,
+                content_type,
+                body.as_bytes(),
+                SystemTime::now(),
+            );
+            let headers: Vec<(&str, &str)> = headers
+                .iter()
+                .map(|(name, value)| (*name, value.as_str()))
+                .collect();
+            let response = fetch::send(
+                &self.address,
+                "POST",
+                &self.path,
+                &headers,
+                body.as_bytes(),
+                TIMEOUT,
+            )?;
+            if response.status != 200 {
+                let body = String::from_utf8_lossy(&response.body);
+                return Err(format!(
+                    "{}: HTTP status {}: {}",
+                    self.address,
+                    response.status,
+                    error_message(&body).unwrap_or(body.trim())
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// CloudWatch's unit for a metric, from the Prometheus naming conventions
+fn unit(name: &str) -> &'static str {
+    let name = name.strip_suffix("_total").unwrap_or(name);
+    if name.ends_with("_seconds") {
+        "Seconds"
+    } else if name.ends_with("_bytes") {
+        "Bytes"
+    } else if name.ends_with("_percent") {
+        "Percent"
+    } else {
+        "None"
+    }
+}
+
+/// The form-encoded Query API request for `data`
+fn put_metric_data_body(namespace: &str, data: &[Datum]) -> String {
+    let mut params = vec![
+        ("Action".to_string(), "PutMetricData".to_string()),
+        ("Version".to_string(), "2010-08-01".to_string()),
+        ("Namespace".to_string(), namespace.to_string()),
+    ];
+    for (i, datum) in data.iter().enumerate() {
+        let member = format!("MetricData.member.{}", i + 1);
+        params.push((format!("{}.MetricName", member), datum.name.clone()));
+        params.push((format!("{}.Value", member), datum.value.to_string()));
+        params.push((format!("{}.Unit", member), datum.unit.to_string()));
+        for (j, (name, value)) in datum.dimensions.iter().enumerate() {
+            let dimension = format!("{}.Dimensions.member.{}", member, j + 1);
+            params.push((format!("{}.Name", dimension), name.clone()));
+            params.push((format!("{}.Value", dimension), value.clone()));
+        }
+    }
+    params
+        .iter()
+        .map(|(name, value)| format!("{}={}", percent_encode(name), percent_encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// The `<Message>` of an error response
+fn error_message(xml: &str) -> Option<&str> {
+    let start = xml.find("<Message>")? + "<Message>".len();
+    let end = xml[start..].find("</Message>")?;
+    Some(&xml[start..start + end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sample;
+    use super::*;
+    use prometheus::proto::MetricType;
+
+    fn cloudwatch(dimensions: &[(&str, &str)]) -> CloudWatch {
+        CloudWatch {
+            address: String::new(),
+            path: "/".to_string(),
+            host: String::new(),
+            region: "us-east-1".to_string(),
+            namespace: "metrixd".to_string(),
+            dimensions: dimensions
+                .iter()
+                .map(|(label, name)| (label.to_string(), name.to_string()))
+                .collect(),
+            credentials: CredentialSource::InstanceRole(None),
+            deltas: Deltas::default(),
+        }
+    }
+
+    #[test]
+    fn maps_labels_to_dimensions() {
+        let mut cloudwatch = cloudwatch(&[("mountpoint", "Path")]);
+        let data = cloudwatch.data(&[sample(
+            "disk_free_bytes",
+            &[("device", "sda1"), ("mountpoint", "/var")],
+            1024.0,
+            MetricType::GAUGE,
+        )]);
+        assert_eq!(
+            put_metric_data_body("metrixd", &data),
+            "Action=PutMetricData&Version=2010-08-01&Namespace=metrixd\
+             &MetricData.member.1.MetricName=disk_free_bytes\
+             &MetricData.member.1.Value=1024&MetricData.member.1.Unit=Bytes\
+             &MetricData.member.1.Dimensions.member.1.Name=Path\
+             &MetricData.member.1.Dimensions.member.1.Value=%2Fvar"
+        );
+    }
+
+    #[test]
+    fn sends_counter_increases_with_every_label_by_default() {
+        let mut cloudwatch = cloudwatch(&[]);
+        let samples = |value| {
+            [sample(
+                "cpu_seconds_total",
+                &[("core", "0"), ("mode", "")],
+                value,
+                MetricType::COUNTER,
+            )]
+        };
+        assert!(cloudwatch.data(&samples(10.0)).is_empty());
+        let data = cloudwatch.data(&samples(12.5));
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0].value, 2.5);
+        assert_eq!(data[0].unit, "Seconds");
+        assert_eq!(data[0].dimensions, [("core".to_string(), "0".to_string())]);
+    }
+
+    #[test]
+    fn reads_error_messages() {
+        let xml = "<ErrorResponse><Error><Type>Sender</Type><Code>InvalidClientTokenId</Code>\
+                   <Message>The security token included in the request is invalid.</Message>\
+                   </Error></ErrorResponse>";
+        assert_eq!(
+            error_message(xml),
+            Some("The security token included in the request is invalid.")
+        );
+        assert_eq!(error_message("Bad Gateway"), None);
+    }
+}
@@ -0,0 +1,239 @@
+//! `[export.collectd]`: values in collectd's binary network protocol, as
+//! its network plugin sends them, to a collectd (or anything listening
+//! like one) over UDP. Each sample becomes the value list
+//! `host/plugin-<label values>/gauge-<name>`, with counters and the other
+//! cumulative samples as `derive` values, which keep only whole numbers.
+
+use std::net::UdpSocket;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{is_cumulative, Exporter};
+use crate::config::CollectdConfig;
+use crate::sample::Sample;
+use crate::sigv4;
+
+/// What collectd sends at most, to stay within an Ethernet frame
+const MAX_PACKET: usize = 1452;
+/// collectd's names are at most this long, with the terminating NUL
+const MAX_NAME: usize = 64;
+
+const PART_HOST: u16 = 0x0000;
+const PART_PLUGIN: u16 = 0x0002;
+const PART_PLUGIN_INSTANCE: u16 = 0x0003;
+const PART_TYPE: u16 = 0x0004;
+const PART_TYPE_INSTANCE: u16 = 0x0005;
+const PART_VALUES: u16 = 0x0006;
+const PART_TIME_HR: u16 = 0x0008;
+const PART_INTERVAL_HR: u16 = 0x0009;
+const PART_SIGNATURE: u16 = 0x0200;
+
+const VALUE_GAUGE: u8 = 1;
+const VALUE_DERIVE: u8 = 2;
+
+pub struct Collectd {
+    config: CollectdConfig,
+    host: String,
+    interval: Duration,
+    socket: UdpSocket,
+}
+
+impl Collectd {
+    pub fn new(
+        config: &CollectdConfig,
+        interval: Duration,
+        instance: &str,
+    ) -> Result<Self, String> {
+        let socket = UdpSocket::bind(if config.server.starts_with('[') {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        })
+        .and_then(|socket| socket.connect(&config.server).map(|()| socket))
+        .map_err(|e| format!("collectd {}: {}", config.server, e))?;
+        Ok(Collectd {
+            config: config.clone(),
+            host: config.host.clone().unwrap_or_else(|| instance.to_string()),
+            interval,
+            socket,
+        })
+    }
+
+    fn packets(&self, samples: &[Sample], now: SystemTime) -> Vec<Vec<u8>> {
+        let mut header = Vec::new();
+        push_string(&mut header, PART_HOST, &self.host);
+        push_number(
+            &mut header,
+            PART_TIME_HR,
+            high_resolution(now.duration_since(UNIX_EPOCH).unwrap_or_default()),
+        );
+        push_number(
+            &mut header,
+            PART_INTERVAL_HR,
+            high_resolution(self.interval),
+        );
+        push_string(&mut header, PART_PLUGIN, &self.config.plugin);
+        let room = MAX_PACKET - header.len() - self.signature_len();
+
+        let mut packets = Vec::new();
+        let mut packet = Vec::new();
+        for sample in samples.iter().filter(|sample| sample.value.is_finite()) {
+            let instance: Vec<&str> = sample
+                .labels
+                .iter()
+                .map(|(_, value)| value.as_str())
+                .collect();
+            let cumulative = is_cumulative(sample);
+            let mut value_list = Vec::new();
+            push_string(&mut value_list, PART_PLUGIN_INSTANCE, &instance.join("-"));
+            push_string(
+                &mut value_list,
+                PART_TYPE,
+                if cumulative { "derive" } else { "gauge" },
+            );
+            push_string(&mut value_list, PART_TYPE_INSTANCE, &sample.name);
+            // One value: its count, its kind, then the value
+            value_list.extend_from_slice(&PART_VALUES.to_be_bytes());
+            value_list.extend_from_slice(&15u16.to_be_bytes());
+            value_list.extend_from_slice(&1u16.to_be_bytes());
+            if cumulative {
+                value_list.push(VALUE_DERIVE);
+                value_list.extend_from_slice(&(sample.value as i64).to_be_bytes());
+            } else {
+                value_list.push(VALUE_GAUGE);
+                // Gauges alone are in x86 byte order
+                value_list.extend_from_slice(&sample.value.to_le_bytes());
+            }
+            if !packet.is_empty() && packet.len() + value_list.len() > room {
+                packets.push(self.finish(&header, &packet));
+                packet.clear();
+            }
+            packet.extend_from_slice(&value_list);
+        }
+        if !packet.is_empty() {
+            packets.push(self.finish(&header, &packet));
+        }
+        packets
+    }
+
+    fn signature_len(&self) -> usize {
+        match &self.config.username {
+            Some(username) => 4 + 32 + username.len(),
+            None => 0,
+        }
+    }
+
+    /// `header` and `values`, signed when there's a username
+    fn finish(&self, header: &[u8], values: &[u8]) -> Vec<u8> {
+        let mut body = header.to_vec();
+        body.extend_from_slice(values);
+        let (Some(username), Some(password)) = (&self.config.username, &self.config.password)
+        else {
+            return body;
+        };
+        // HMAC-SHA-256 of the username and everything after the signature
+        let mut signed = username.as_bytes().to_vec();
+        signed.extend_from_slice(&body);
+        let mut packet = PART_SIGNATURE.to_be_bytes().to_vec();
+        packet.extend_from_slice(&(self.signature_len() as u16).to_be_bytes());
+        packet.extend_from_slice(&sigv4::hmac_sha256(password.as_bytes(), &signed));
+        packet.extend_from_slice(username.as_bytes());
+        packet.extend_from_slice(&body);
+        packet
+    }
+}
+
+impl Exporter for Collectd {
+    fn name(&self) -> &'static str {
+        "collectd"
+    }
+
+    fn export(&mut self, samples: &[Sample]) -> Result<(), String> {
+        for packet in self.packets(samples, SystemTime::now()) {
+            self.socket
+                .send(&packet)
+                .map_err(|e| format!("collectd {}: {}", self.config.server, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// A string part, cut to the length collectd takes
+fn push_string(buffer: &mut Vec<u8>, part: u16, text: &str) {
+    let mut end = text.len().min(MAX_NAME - 1);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    buffer.extend_from_slice(&part.to_be_bytes());
+    buffer.extend_from_slice(&(4 + end as u16 + 1).to_be_bytes());
+    buffer.extend_from_slice(&text.as_bytes()[..end]);
+    buffer.push(0);
+}
+
+fn push_number(buffer: &mut Vec<u8>, part: u16, number: u64) {
+    buffer.extend_from_slice(&part.to_be_bytes());
+    buffer.extend_from_slice(&12u16.to_be_bytes());
+    buffer.extend_from_slice(&number.to_be_bytes());
+}
+
+/// A duration in collectd's units of 2^-30 seconds
+fn high_resolution(duration: Duration) -> u64 {
+    (duration.as_secs() << 30) + ((duration.subsec_nanos() as u64) << 30) / 1_000_000_000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sample;
+    use super::*;
+    use prometheus::proto::MetricType;
+
+    #[test]
+    fn encodes_value_lists() {
+        let mut config = CollectdConfig {
+            common: Default::default(),
+            server: "127.0.0.1:25826".to_string(),
+            host: Some("web-1".to_string()),
+            plugin: "metrixd".to_string(),
+            username: None,
+            password: None,
+        };
+        let collectd = Collectd::new(&config, Duration::from_secs(10), "metrixd").unwrap();
+        let now = UNIX_EPOCH + Duration::from_millis(1_500);
+        let packets = collectd.packets(&[sample("load1", &[], 0.5, MetricType::GAUGE)], now);
+        let mut expected = vec![0, 0, 0, 10];
+        expected.extend_from_slice(b"web-1\0");
+        expected.extend_from_slice(&[0, 8, 0, 12, 0, 0, 0, 0, 0x60, 0, 0, 0]);
+        expected.extend_from_slice(&[0, 9, 0, 12, 0, 0, 0, 0x02, 0x80, 0, 0, 0]);
+        expected.extend_from_slice(b"\0\x02\0\x0cmetrixd\0");
+        expected.extend_from_slice(b"\0\x03\0\x05\0");
+        expected.extend_from_slice(b"\0\x04\0\x0agauge\0");
+        expected.extend_from_slice(b"\0\x05\0\x0aload1\0");
+        expected.extend_from_slice(&[0, 6, 0, 15, 0, 1, 1]);
+        expected.extend_from_slice(&0.5f64.to_le_bytes());
+        assert_eq!(packets, [expected]);
+
+        // Too many for one packet, each signed
+        config.username = Some("metrixd".to_string());
+        config.password = Some("s3cret".to_string());
+        let collectd = Collectd::new(&config, Duration::from_secs(10), "metrixd").unwrap();
+        let samples: Vec<Sample> = (0..100)
+            .map(|core| {
+                let core = core.to_string();
+                sample(
+                    "cpu_seconds_total",
+                    &[("core", &core)],
+                    1.0,
+                    MetricType::COUNTER,
+                )
+            })
+            .collect();
+        let packets = collectd.packets(&samples, now);
+        assert_eq!(packets.len(), 5);
+        for packet in &packets {
+            assert!(packet.len() <= MAX_PACKET);
+            assert_eq!(packet[..4], [0x02, 0, 0, 43]);
+            let mut signed = b"metrixd".to_vec();
+            signed.extend_from_slice(&packet[43..]);
+            assert_eq!(packet[4..36], sigv4::hmac_sha256(b"s3cret", &signed));
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! `[export.datadog]`: DogStatsD datagrams to the local agent, or the v2
+//! series API. Labels become `name:value` tags. Counters and the other
+//! cumulative samples are sent as counts of their increase since the last
+//! push, everything else as gauges.
+
+use std::net::UdpSocket;
+use std::time::SystemTime;
+
+use super::{is_cumulative, json_string, unix_time, Deltas, Exporter, TIMEOUT};
+use crate::config::{DatadogConfig, DatadogTarget};
+use crate::fetch;
+use crate::sample::Sample;
+
+/// Stays under a typical 1500 byte MTU once UDP and IP headers are added
+const MAX_DATAGRAM: usize = 1432;
+/// Series per API request, well below its 5MB payload limit
+const SERIES_PER_REQUEST: usize = 1000;
+
+pub struct Datadog {
+    target: Target,
+    prefix: String,
+    deltas: Deltas,
+}
+
+enum Target {
+    Dogstatsd(UdpSocket),
+    Api {
+        address: String,
+        path: String,
+        api_key: String,
+    },
+}
+
+/// One value to send, as a gauge or a count
+struct Point {
+    name: String,
+    tags: Vec<String>,
+    value: f64,
+    count: bool,
+}
+
+impl Datadog {
+    pub fn new(config: &DatadogConfig) -> Result<Self, String> {
+        let target = match &config.target {
+            DatadogTarget::Dogstatsd(address) => {
+                let socket = UdpSocket::bind(if address.starts_with('[') {
+                    "[::]:0"
+                } else {
+                    "0.0.0.0:0"
+                })
+                .and_then(|socket| socket.connect(address).map(|()| socket))
+                .map_err(|e| format!("dogstatsd {}: {}", address, e))?;
+                Target::Dogstatsd(socket)
+            }
+            DatadogTarget::Api { url, api_key } => {
+                let (address, path) = fetch::parse_url(url)?;
+                Target::Api {
+                    address,
+                    path,
+                    api_key: api_key.clone(),
+                }
+            }
+        };
+        Ok(Datadog {
+            target,
+            prefix: config.prefix.clone(),
+            deltas: Deltas::default(),
+        })
+    }
+
+    fn points(&mut self, samples: &[Sample]) -> Vec<Point> {
+        let mut points = Vec::new();
+        for sample in samples {
+            let count = is_cumulative(sample);
+            let value = if count {
+                match self.deltas.delta(sample) {
+                    Some(delta) => delta,
+                    None => continue,
+                }
+            } else {
+                sample.value
+            };
+            if !value.is_finite() {
+                continue;
+            }
+            points.push(Point {
+                name: format!("{}{}", self.prefix, sample.name),
+                tags: sample
+                    .labels
+                    .iter()
+                    .map(|(name, value)| format!("{}:{}", name, tag_value(value)))
+                    .collect(),
+                value,
+                count,
+            });
+        }
+        points
+    }
+}
+
+impl Exporter for Datadog {
+    fn name(&self) -> &'static str {
+        "datadog"
+    }
+
+    fn export(&mut self, samples: &[Sample]) -> Result<(), String> {
+        let points = self.points(samples);
+        match &self.target {
+            Target::Dogstatsd(socket) => {
+                for datagram in datagrams(&points) {
+                    socket
+                        .send(datagram.as_bytes())
+                        .map_err(|e| format!("dogstatsd: {}", e))?;
+                }
+                Ok(())
+            }
+            Target::Api {
+                address,
+                path,
+                api_key,
+            } => {
+                let timestamp = unix_time(SystemTime::now()) as u64;
+                for chunk in points.chunks(SERIES_PER_REQUEST) {
+                    let body = series_body(chunk, timestamp);
+                    let response = fetch::send(
+                        address,
+                        "POST",
+                        path,
+                        &[
+                            ("Content-Type", "application/json"),
+                            ("DD-API-KEY", api_key),
+                        ],
+                        body.as_bytes(),
+                        TIMEOUT,
+                    )?;
+                    if !(200..300).contains(&response.status) {
+                        return Err(format!(
+                            "{}: HTTP status {}: {}",
+                            address,
+                            response.status,
+                            String::from_utf8_lossy(&response.body).trim()
+                        ));
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Tags are split on commas and the statsd format on `|`
+fn tag_value(value: &str) -> String {
+    value.replace([',', '|', '#', '\n'], "_")
+}
+
+/// `name:value|g|#tag:value,...` lines, packed into as few datagrams as fit
+fn datagrams(points: &[Point]) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut datagram = String::new();
+    for point in points {
+        let mut line = format!(
+            "{}:{}|{}",
+            point.name,
+            point.value,
+            if point.count { "c" } else { "g" }
+        );
+        if !point.tags.is_empty() {
+            line.push_str("|#");
+            line.push_str(&point.tags.join(","));
+        }
+        if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+            datagrams.push(std::mem::take(&mut datagram));
+        }
+        if !datagram.is_empty() {
+            datagram.push('\n');
+        }
+        datagram.push_str(&line);
+    }
+    if !datagram.is_empty() {
+        datagrams.push(datagram);
+    }
+    datagrams
+}
+
+/// A `POST /api/v2/series` body; type 1 is a count, 3 a gauge
+fn series_body(points: &[Point], timestamp: u64) -> String {
+    let series: Vec<String> = points
+        .iter()
+        .map(|point| {
+            let tags: Vec<String> = point.tags.iter().map(|tag| json_string(tag)).collect();
+            format!(
+                "{{\"metric\":{},\"type\":{},\"points\":[{{\"timestamp\":{},\"value\":{}}}],\"tags\":[{}]}}",
+                json_string(&point.name),
+                if point.count { 1 } else { 3 },
+                timestamp,
+                point.value,
+                tags.join(",")
+            )
+        })
+        .collect();
+    format!("{{\"series\":[{}]}}", series.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sample;
+    use super::*;
+    use prometheus::proto::MetricType;
+
+    fn datadog() -> Datadog {
+        Datadog {
+            target: Target::Api {
+                address: String::new(),
+                path: String::new(),
+                api_key: String::new(),
+            },
+            prefix: "metrixd.".to_string(),
+            deltas: Deltas::default(),
+        }
+    }
+
+    #[test]
+    fn sends_gauges_and_counter_increases_as_dogstatsd_lines() {
+        let mut datadog = datadog();
+        let samples = |requests| {
+            vec![
+                sample(
+                    "cpu_usage_percent",
+                    &[("core", "0"), ("host", "a,b")],
+                    12.5,
+                    MetricType::GAUGE,
+                ),
+                sample("requests_total", &[], requests, MetricType::COUNTER),
+            ]
+        };
+        // Counters need a previous value first
+        let first = datadog.points(&samples(10.0));
+        assert_eq!(
+            datagrams(&first),
+            ["metrixd.cpu_usage_percent:12.5|g|#core:0,host:a_b"]
+        );
+        let second = datadog.points(&samples(14.0));
+        assert_eq!(
+            datagrams(&second),
+            ["metrixd.cpu_usage_percent:12.5|g|#core:0,host:a_b\nmetrixd.requests_total:4|c"]
+        );
+    }
+
+    #[test]
+    fn splits_datagrams_at_the_size_limit() {
+        let points: Vec<Point> = (0..200)
+            .map(|i| Point {
+                name: format!("gauge_{}", i),
+                tags: Vec::new(),
+                value: 1.0,
+                count: false,
+            })
+            .collect();
+        let datagrams = datagrams(&points);
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|d| d.len() <= MAX_DATAGRAM));
+        assert_eq!(
+            datagrams.iter().map(|d| d.lines().count()).sum::<usize>(),
+            200
+        );
+    }
+
+    #[test]
+    fn builds_series_api_bodies() {
+        let mut datadog = datadog();
+        let points = datadog.points(&[sample("load1", &[("env", "prod")], 0.5, MetricType::GAUGE)]);
+        assert_eq!(
+            series_body(&points, 1700000000),
+            "{\"series\":[{\"metric\":\"metrixd.load1\",\"type\":3,\"points\":[{\"timestamp\":1700000000,\"value\":0.5}],\"tags\":[\"env:prod\"]}]}"
+        );
+    }
+}
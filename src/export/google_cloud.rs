@@ -0,0 +1,254 @@
+//! `[export.google_cloud]`: timeSeries.create in Cloud Monitoring, with
+//! the service account token of the GCE instance or GKE node, whose
+//! monitored resource the series are written for. Counters and the other
+//! cumulative samples are written as CUMULATIVE points counted from when
+//! metrixd first saw them, everything else as GAUGE.
+
+use std::collections::HashMap;
+use std::time::{Instant, SystemTime};
+
+use super::{is_cumulative, json_string, series_key, Exporter, TIMEOUT};
+use crate::cloud::{self, MonitoredResource};
+use crate::config::GoogleCloudConfig;
+use crate::fetch;
+use crate::sample::Sample;
+use crate::utc::Utc;
+
+/// timeSeries.create's limit per request
+const SERIES_PER_REQUEST: usize = 200;
+
+pub struct GoogleCloud {
+    address: String,
+    /// Below which the API's `v3/...` paths go
+    base_path: String,
+    project_id: Option<String>,
+    metric_prefix: String,
+    /// Detected on the first push, and retried until that works
+    resource: Option<MonitoredResource>,
+    token: Option<(String, Instant)>,
+    starts: Starts,
+}
+
+/// Cumulative series, counted from when metrixd first saw them
+#[derive(Default)]
+struct Starts {
+    /// Start time, value at the start, and value and time of the last push
+    series: HashMap<String, (SystemTime, f64, f64, SystemTime)>,
+}
+
+impl Starts {
+    /// The start and value of `sample` pushed at `now`. `None` the first
+    /// time, which only sets the start. After a reset the series restarts
+    /// at the previous push, from zero.
+    fn point(&mut self, sample: &Sample, now: SystemTime) -> Option<(SystemTime, f64)> {
+        let key = series_key(sample);
+        let Some((start, base, last, pushed)) = self.series.get_mut(&key) else {
+            self.series
+                .insert(key, (now, sample.value, sample.value, now));
+            return None;
+        };
+        if sample.value < *last {
+            (*start, *base) = (*pushed, 0.0);
+        }
+        (*last, *pushed) = (sample.value, now);
+        Some((*start, sample.value - *base))
+    }
+}
+
+impl GoogleCloud {
+    pub fn new(config: &GoogleCloudConfig) -> Result<Self, String> {
+        let (address, mut base_path) = fetch::parse_url(&config.url)?;
+        if !base_path.ends_with('/') {
+            base_path.push('/');
+        }
+        Ok(GoogleCloud {
+            address,
+            base_path,
+            project_id: config.project_id.clone(),
+            metric_prefix: config.metric_prefix.clone(),
+            resource: None,
+            token: None,
+            starts: Starts::default(),
+        })
+    }
+
+    fn token(&mut self) -> Result<String, String> {
+        if let Some((token, expires)) = &self.token {
+            if Instant::now() + TIMEOUT < *expires {
+                return Ok(token.clone());
+            }
+        }
+        let (token, valid_for) = cloud::gce_access_token()?;
+        self.token = Some((token.clone(), Instant::now() + valid_for));
+        Ok(token)
+    }
+
+    /// `timeSeries` entries for `samples`, pushed at `now`
+    fn time_series(&mut self, resource: &str, samples: &[Sample], now: SystemTime) -> Vec<String> {
+        let end = Utc::from_system_time(now).rfc3339();
+        let mut series = Vec::new();
+        for sample in samples {
+            let (kind, value, interval) = if is_cumulative(sample) {
+                let Some((start, value)) = self.starts.point(sample, now) else {
+                    continue;
+                };
+                let start = Utc::from_system_time(start).rfc3339();
+                (
+                    "CUMULATIVE",
+                    value,
+                    format!("{{\"startTime\":\"{}\",\"endTime\":\"{}\"}}", start, end),
+                )
+            } else {
+                (
+                    "GAUGE",
+                    sample.value,
+                    format!("{{\"endTime\":\"{}\"}}", end),
+                )
+            };
+            if !value.is_finite() {
+                continue;
+            }
+            let labels: Vec<String> = sample
+                .labels
+                .iter()
+                .map(|(name, value)| {
+                    format!(
+                        "{}:{}",
+                        json_string(&name.to_ascii_lowercase()),
+                        json_string(value)
+                    )
+                })
+                .collect();
+            series.push(format!(
+                "{{\"metric\":{{\"type\":{},\"labels\":{{{}}}}},\"resource\":{},\"metricKind\":\"{}\",\"valueType\":\"DOUBLE\",\"points\":[{{\"interval\":{},\"value\":{{\"doubleValue\":{}}}}}]}}",
+                json_string(&format!("{}{}", self.metric_prefix, sample.name)),
+                labels.join(","),
+                resource,
+                kind,
+                interval,
+                value
+            ));
+        }
+        series
+    }
+}
+
+impl Exporter for GoogleCloud {
+    fn name(&self) -> &'static str {
+        "google_cloud"
+    }
+
+    fn export(&mut self, samples: &[Sample]) -> Result<(), String> {
+        let resource = match &self.resource {
+            Some(resource) => resource,
+            None => {
+                let resource = cloud::gce_monitored_resource()?;
+                println!(
+                    "Writing to Cloud Monitoring as {} in project {}",
+                    resource.kind, resource.project_id
+                );
+                self.resource.insert(resource)
+            }
+        };
+        let path = format!(
+            "{}v3/projects/{}/timeSeries",
+            self.base_path,
+            self.project_id.as_ref().unwrap_or(&resource.project_id)
+        );
+        let resource = resource_json(resource);
+
+        let token = self.token()?;
+        let authorization = format!("Bearer {}", token);
+        let series = self.time_series(&resource, samples, SystemTime::now());
+        for chunk in series.chunks(SERIES_PER_REQUEST) {
+            let body = format!("{{\"timeSeries\":[{}]}}", chunk.join(","));
+            let response = fetch::send(
+                &self.address,
+                "POST",
+                &path,
+                &[
+                    ("Host", "monitoring.googleapis.com"),
+                    ("Authorization", &authorization),
+                    ("Content-Type", "application/json"),
+                ],
+                body.as_bytes(),
+                TIMEOUT,
+            )?;
+            if response.status != 200 {
+                let body = String::from_utf8_lossy(&response.body);
+                return Err(format!(
+                    "{}: HTTP status {}: {}",
+                    self.address,
+                    response.status,
+                    cloud::json_field(&body, "message").unwrap_or_else(|| body.trim().to_string())
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn resource_json(resource: &MonitoredResource) -> String {
+    let labels: Vec<String> = resource
+        .labels
+        .iter()
+        .map(|(name, value)| format!("{}:{}", json_string(name), json_string(value)))
+        .collect();
+    format!(
+        "{{\"type\":{},\"labels\":{{{}}}}}",
+        json_string(resource.kind),
+        labels.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sample;
+    use super::*;
+    use prometheus::proto::MetricType;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn writes_gauges_and_cumulative_counters() {
+        let mut google_cloud = GoogleCloud::new(&GoogleCloudConfig {
+            common: Default::default(),
+            project_id: None,
+            metric_prefix: "custom.googleapis.com/metrixd/".to_string(),
+            url: "http://monitoring.googleapis.com/".to_string(),
+        })
+        .unwrap();
+        let resource = resource_json(&MonitoredResource {
+            kind: "gce_instance",
+            project_id: "p".to_string(),
+            labels: vec![("project_id", "p".to_string()), ("zone", "z".to_string())],
+        });
+        let samples = |value| {
+            [
+                sample("load1", &[("Core", "0")], 0.5, MetricType::GAUGE),
+                sample("requests_total", &[], value, MetricType::COUNTER),
+            ]
+        };
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+
+        // Counters start counting on the first push
+        let first = google_cloud.time_series(&resource, &samples(10.0), at(1_700_000_000));
+        assert_eq!(
+            first,
+            ["{\"metric\":{\"type\":\"custom.googleapis.com/metrixd/load1\",\"labels\":{\"core\":\"0\"}},\
+             \"resource\":{\"type\":\"gce_instance\",\"labels\":{\"project_id\":\"p\",\"zone\":\"z\"}},\
+             \"metricKind\":\"GAUGE\",\"valueType\":\"DOUBLE\",\
+             \"points\":[{\"interval\":{\"endTime\":\"2023-11-14T22:13:20.000Z\"},\"value\":{\"doubleValue\":0.5}}]}"]
+        );
+        let second = google_cloud.time_series(&resource, &samples(15.0), at(1_700_000_060));
+        assert!(second[1].ends_with(
+            "\"interval\":{\"startTime\":\"2023-11-14T22:13:20.000Z\",\"endTime\":\"2023-11-14T22:14:20.000Z\"},\
+             \"value\":{\"doubleValue\":5}}]}"
+        ));
+        // After a reset, from the previous push
+        let reset = google_cloud.time_series(&resource, &samples(2.0), at(1_700_000_120));
+        assert!(reset[1].ends_with(
+            "\"interval\":{\"startTime\":\"2023-11-14T22:14:20.000Z\",\"endTime\":\"2023-11-14T22:15:20.000Z\"},\
+             \"value\":{\"doubleValue\":2}}]}"
+        ));
+    }
+}
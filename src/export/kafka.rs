@@ -0,0 +1,444 @@
+//! `[export.kafka]`: every sample as a record in a Kafka topic. Speaks the
+//! few requests a producer needs, Metadata v4 and Produce v3 with a v2
+//! record batch, which every broker since 0.11 (and 4.x) understands. Each
+//! push goes to the next partition in turn, one uncompressed batch per
+//! request, acknowledged by the leader; no idempotence or transactions.
+
+use std::io::{Read, Write};
+use std::time::SystemTime;
+
+use super::{encode, unix_time, Exporter, TIMEOUT};
+use crate::config::KafkaConfig;
+use crate::fetch;
+use crate::sample::Sample;
+
+const METADATA: i16 = 3;
+const PRODUCE: i16 = 0;
+/// Below the default `max.message.bytes` of 1MB
+const MAX_BATCH: usize = 900_000;
+
+pub struct Kafka {
+    config: KafkaConfig,
+    /// Each partition and the address of its leader; looked up again after
+    /// an error, as leaders move
+    partitions: Vec<(i32, String)>,
+    next: usize,
+    correlation_id: i32,
+}
+
+impl Kafka {
+    pub fn new(config: &KafkaConfig) -> Self {
+        Kafka {
+            config: config.clone(),
+            partitions: Vec::new(),
+            next: 0,
+            correlation_id: 0,
+        }
+    }
+
+    /// Send one request to `address` and return the response after its
+    /// correlation id
+    fn request(
+        &mut self,
+        address: &str,
+        api_key: i16,
+        api_version: i16,
+        body: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let mut request = Vec::with_capacity(body.len() + 64);
+        put_i16(&mut request, api_key);
+        put_i16(&mut request, api_version);
+        put_i32(&mut request, self.correlation_id);
+        put_string(&mut request, &self.config.client_id);
+        request.extend_from_slice(body);
+
+        let error = |e: std::io::Error| format!("{}: {}", address, e);
+        let mut stream = fetch::connect(address, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT)).map_err(error)?;
+        stream.set_write_timeout(Some(TIMEOUT)).map_err(error)?;
+        stream
+            .write_all(&(request.len() as i32).to_be_bytes())
+            .and_then(|()| stream.write_all(&request))
+            .map_err(error)?;
+        let mut size = [0u8; 4];
+        stream.read_exact(&mut size).map_err(error)?;
+        let size = i32::from_be_bytes(size);
+        if !(4..=64 << 20).contains(&size) {
+            return Err(format!("{}: bad response size {}", address, size));
+        }
+        let mut response = vec![0u8; size as usize];
+        stream.read_exact(&mut response).map_err(error)?;
+        if response[..4] != self.correlation_id.to_be_bytes() {
+            return Err(format!("{}: response to another request", address));
+        }
+        Ok(response.split_off(4))
+    }
+
+    /// The topic's partitions, from the first bootstrap broker that answers
+    fn metadata(&mut self) -> Result<Vec<(i32, String)>, String> {
+        let mut body = Vec::new();
+        put_i32(&mut body, 1);
+        put_string(&mut body, &self.config.topic);
+        body.push(1); // allow_auto_topic_creation
+        let mut last_error = String::new();
+        for broker in self.config.brokers.clone() {
+            match self
+                .request(&broker, METADATA, 4, &body)
+                .and_then(|response| parse_metadata(&response, &self.config.topic))
+            {
+                Ok(partitions) => return Ok(partitions),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    fn produce(&mut self, partition: i32, leader: &str, batch: &[u8]) -> Result<(), String> {
+        let mut body = Vec::with_capacity(batch.len() + 64);
+        put_i16(&mut body, -1); // no transactional_id
+        put_i16(&mut body, 1); // acks: the leader's
+        put_i32(&mut body, TIMEOUT.as_millis() as i32);
+        put_i32(&mut body, 1);
+        put_string(&mut body, &self.config.topic);
+        put_i32(&mut body, 1);
+        put_i32(&mut body, partition);
+        put_i32(&mut body, batch.len() as i32);
+        body.extend_from_slice(batch);
+        let response = self.request(leader, PRODUCE, 3, &body)?;
+        parse_produce(&response).map_err(|e| format!("{}: {}", leader, e))
+    }
+}
+
+impl Exporter for Kafka {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    fn export(&mut self, samples: &[Sample]) -> Result<(), String> {
+        let now = SystemTime::now();
+        let messages: Vec<Vec<u8>> = samples
+            .iter()
+            .filter(|sample| sample.value.is_finite())
+            .map(|sample| encode(self.config.format, sample, unix_time(now)))
+            .collect();
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        if self.partitions.is_empty() {
+            self.partitions = self.metadata()?;
+        }
+        let (partition, leader) = self.partitions[self.next % self.partitions.len()].clone();
+        self.next = self.next.wrapping_add(1);
+        let timestamp_ms = (unix_time(now) * 1000.0) as i64;
+        let mut start = 0;
+        while start < messages.len() {
+            let mut end = start;
+            let mut size = 0;
+            while end < messages.len() && (end == start || size + messages[end].len() < MAX_BATCH) {
+                size += messages[end].len() + 16;
+                end += 1;
+            }
+            let batch = record_batch(&messages[start..end], timestamp_ms);
+            if let Err(e) = self.produce(partition, &leader, &batch) {
+                self.partitions.clear();
+                return Err(e);
+            }
+            start = end;
+        }
+        Ok(())
+    }
+}
+
+fn put_i16(buffer: &mut Vec<u8>, value: i16) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_i32(buffer: &mut Vec<u8>, value: i32) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_i64(buffer: &mut Vec<u8>, value: i64) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_string(buffer: &mut Vec<u8>, text: &str) {
+    put_i16(buffer, text.len() as i16);
+    buffer.extend_from_slice(text.as_bytes());
+}
+
+/// A zigzag varint, as records use
+fn put_varint(buffer: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+/// A v2 record batch of `messages` without keys or headers
+fn record_batch(messages: &[Vec<u8>], timestamp_ms: i64) -> Vec<u8> {
+    let mut records = Vec::new();
+    for (offset_delta, value) in messages.iter().enumerate() {
+        let mut record = vec![0]; // attributes
+        put_varint(&mut record, 0); // timestamp delta
+        put_varint(&mut record, offset_delta as i64);
+        put_varint(&mut record, -1); // no key
+        put_varint(&mut record, value.len() as i64);
+        record.extend_from_slice(value);
+        put_varint(&mut record, 0); // no headers
+        put_varint(&mut records, record.len() as i64);
+        records.extend_from_slice(&record);
+    }
+
+    // Everything after the CRC, which it covers
+    let mut checked = Vec::with_capacity(records.len() + 40);
+    put_i16(&mut checked, 0); // attributes: no compression
+    put_i32(&mut checked, messages.len() as i32 - 1); // last offset delta
+    put_i64(&mut checked, timestamp_ms); // first timestamp
+    put_i64(&mut checked, timestamp_ms); // max timestamp
+    put_i64(&mut checked, -1); // producer id
+    put_i16(&mut checked, -1); // producer epoch
+    put_i32(&mut checked, -1); // base sequence
+    put_i32(&mut checked, messages.len() as i32);
+    checked.extend_from_slice(&records);
+
+    let mut batch = Vec::with_capacity(checked.len() + 21);
+    put_i64(&mut batch, 0); // base offset
+    put_i32(&mut batch, (4 + 1 + 4 + checked.len()) as i32);
+    put_i32(&mut batch, -1); // partition leader epoch
+    batch.push(2); // magic
+    batch.extend_from_slice(&crc32c(&checked).to_be_bytes());
+    batch.extend_from_slice(&checked);
+    batch
+}
+
+/// CRC-32C (Castagnoli), which v2 record batches are checked with
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Reads a response front to back
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < len {
+            return Err("truncated response".to_string());
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn i16(&mut self) -> Result<i16, String> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// A string or null one
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.i16()?;
+        let bytes = self.take(len.max(0) as usize)?;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    /// The length of an array, 0 for null
+    fn len(&mut self) -> Result<usize, String> {
+        Ok(self.i32()?.max(0) as usize)
+    }
+}
+
+fn error_name(code: i16) -> String {
+    let name = match code {
+        3 => "UNKNOWN_TOPIC_OR_PARTITION",
+        5 => "LEADER_NOT_AVAILABLE",
+        6 => "NOT_LEADER_OR_FOLLOWER",
+        7 => "REQUEST_TIMED_OUT",
+        10 => "MESSAGE_TOO_LARGE",
+        29 => "TOPIC_AUTHORIZATION_FAILED",
+        _ => return format!("error code {}", code),
+    };
+    name.to_string()
+}
+
+/// Metadata v4: each partition of `topic` with a leader, and its address
+fn parse_metadata(response: &[u8], topic: &str) -> Result<Vec<(i32, String)>, String> {
+    let mut decoder = Decoder { data: response };
+    decoder.i32()?; // throttle time
+    let mut brokers = Vec::new();
+    for _ in 0..decoder.len()? {
+        let node_id = decoder.i32()?;
+        let host = decoder.string()?;
+        let port = decoder.i32()?;
+        decoder.string()?; // rack
+        brokers.push((node_id, format!("{}:{}", host, port)));
+    }
+    decoder.string()?; // cluster id
+    decoder.i32()?; // controller id
+    for _ in 0..decoder.len()? {
+        let error_code = decoder.i16()?;
+        let name = decoder.string()?;
+        decoder.take(1)?; // is_internal
+        let mut partitions = Vec::new();
+        for _ in 0..decoder.len()? {
+            decoder.i16()?; // partition error code
+            let partition = decoder.i32()?;
+            let leader = decoder.i32()?;
+            for _ in 0..2 {
+                // Replicas and in-sync replicas
+                let count = decoder.len()?;
+                decoder.take(count * 4)?;
+            }
+            if let Some((_, address)) = brokers.iter().find(|(id, _)| *id == leader) {
+                partitions.push((partition, address.clone()));
+            }
+        }
+        if name != topic {
+            continue;
+        }
+        if error_code != 0 {
+            return Err(format!("topic {}: {}", topic, error_name(error_code)));
+        }
+        if partitions.is_empty() {
+            return Err(format!("topic {}: no partition has a leader", topic));
+        }
+        partitions.sort();
+        return Ok(partitions);
+    }
+    Err(format!("topic {}: not in the metadata", topic))
+}
+
+/// Produce v3: the first partition error, if any
+fn parse_produce(response: &[u8]) -> Result<(), String> {
+    let mut decoder = Decoder { data: response };
+    for _ in 0..decoder.len()? {
+        decoder.string()?; // topic
+        for _ in 0..decoder.len()? {
+            decoder.i32()?; // partition
+            let error_code = decoder.i16()?;
+            decoder.take(16)?; // base offset, log append time
+            if error_code != 0 {
+                return Err(error_name(error_code));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sample;
+    use super::*;
+    use crate::config::StreamFormat;
+    use prometheus::proto::MetricType;
+    use std::net::TcpListener;
+
+    fn read_request(stream: &mut std::net::TcpStream) -> Vec<u8> {
+        let mut size = [0u8; 4];
+        stream.read_exact(&mut size).unwrap();
+        let mut request = vec![0u8; i32::from_be_bytes(size) as usize];
+        stream.read_exact(&mut request).unwrap();
+        request
+    }
+
+    fn respond(stream: &mut std::net::TcpStream, request: &[u8], body: &[u8]) {
+        let mut response = request[4..8].to_vec(); // correlation id
+        response.extend_from_slice(body);
+        stream
+            .write_all(&(response.len() as i32).to_be_bytes())
+            .unwrap();
+        stream.write_all(&response).unwrap();
+    }
+
+    #[test]
+    fn checks_batches_with_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn produces_to_the_partition_leader() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_request(&mut stream);
+            assert_eq!(request[..4], [0, 3, 0, 4], "Metadata v4");
+            let mut metadata = Vec::new();
+            put_i32(&mut metadata, 0);
+            put_i32(&mut metadata, 1);
+            put_i32(&mut metadata, 7);
+            put_string(&mut metadata, "127.0.0.1");
+            put_i32(&mut metadata, port as i32);
+            put_i16(&mut metadata, -1);
+            put_i16(&mut metadata, -1);
+            put_i32(&mut metadata, 7);
+            put_i32(&mut metadata, 1);
+            put_i16(&mut metadata, 0);
+            put_string(&mut metadata, "metrics");
+            metadata.push(0);
+            put_i32(&mut metadata, 1);
+            put_i16(&mut metadata, 0);
+            put_i32(&mut metadata, 0);
+            put_i32(&mut metadata, 7);
+            put_i32(&mut metadata, 0);
+            put_i32(&mut metadata, 0);
+            respond(&mut stream, &request, &metadata);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_request(&mut stream);
+            let mut produce = Vec::new();
+            put_i32(&mut produce, 1);
+            put_string(&mut produce, "metrics");
+            put_i32(&mut produce, 1);
+            put_i32(&mut produce, 0);
+            put_i16(&mut produce, 0);
+            put_i64(&mut produce, 0);
+            put_i64(&mut produce, -1);
+            put_i32(&mut produce, 0);
+            respond(&mut stream, &request, &produce);
+            request
+        });
+
+        let mut kafka = Kafka::new(&KafkaConfig {
+            common: Default::default(),
+            brokers: vec![format!("127.0.0.1:{}", port)],
+            topic: "metrics".to_string(),
+            format: StreamFormat::Json,
+            client_id: "metrixd".to_string(),
+        });
+        kafka
+            .export(&[sample("load1", &[], 0.5, MetricType::GAUGE)])
+            .unwrap();
+
+        let request = broker.join().unwrap();
+        assert_eq!(request[..4], [0, 0, 0, 3], "Produce v3");
+        // The batch closes the request; its CRC covers all after it
+        let crc_at = request
+            .windows(5)
+            .position(|window| window == [0xff, 0xff, 0xff, 0xff, 2])
+            .unwrap()
+            + 5;
+        let crc = u32::from_be_bytes(request[crc_at..crc_at + 4].try_into().unwrap());
+        assert_eq!(crc, crc32c(&request[crc_at + 4..]));
+        assert!(String::from_utf8_lossy(&request).contains("{\"name\":\"load1\""));
+    }
+}
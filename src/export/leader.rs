@@ -0,0 +1,170 @@
+//! `[export.leader_election]`: agents on the nodes of a cluster that share
+//! storage elect one of them through a lease kept in `lock_file`, and only
+//! that one pushes the `cluster_metrics`, which every node would otherwise
+//! push alike. The lease names its holder and when it expires. The holder
+//! renews it every cycle; any other agent takes it over once it has run
+//! out, e.g. because its holder died or lost the storage. Expiry times are
+//! compared across hosts, so their clocks need to be in sync.
+//!
+//! The file is locked only while the lease is read and written, so a lock
+//! left behind by a dead agent can't block the others for good. Records
+//! have a fixed length and are written in place, since Landlock may not
+//! allow truncating or replacing the file.
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use super::unix_time;
+use crate::config::LeaderElectionConfig;
+
+/// The length of a record, `<holder> <expiry in ms>` padded with spaces
+const RECORD_LEN: usize = 256;
+
+pub struct Lease {
+    file: File,
+    path: PathBuf,
+    holder: String,
+    duration: Duration,
+    /// When the lease this agent holds runs out; `None` while another
+    /// agent holds it
+    expires: Option<SystemTime>,
+}
+
+impl Lease {
+    /// Open, or create, the lease file for `holder`, the instance ID
+    pub fn open(config: &LeaderElectionConfig, holder: &str) -> Result<Self, String> {
+        // Spaces separate the holder from the expiry
+        let holder = holder.replace(char::is_whitespace, "_");
+        if holder.len() + 21 > RECORD_LEN {
+            return Err(format!(
+                "export.leader_election: instance ID {} is too long",
+                holder
+            ));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&config.lock_file)
+            .map_err(|e| format!("{}: {}", config.lock_file.display(), e))?;
+        Ok(Lease {
+            file,
+            path: config.lock_file.clone(),
+            holder,
+            duration: config.lease,
+            expires: None,
+        })
+    }
+
+    /// Renew the lease, or take it over if it has run out, and say whether
+    /// this agent holds it. When the file can't be read or written the
+    /// holder keeps the lease until it runs out, as the others would.
+    pub fn renew(&mut self, now: SystemTime) -> bool {
+        let held = self.expires.is_some();
+        let holds = match self.update(now) {
+            Ok(holds) => holds,
+            Err(e) => {
+                eprintln!(
+                    "Failed to renew the lease in {}: {}",
+                    self.path.display(),
+                    e
+                );
+                if self.expires.is_some_and(|expires| expires <= now) {
+                    self.expires = None;
+                }
+                self.expires.is_some()
+            }
+        };
+        if holds && !held {
+            println!("Holding the lease in {}", self.path.display());
+        } else if !holds && held {
+            println!("Lost the lease in {}", self.path.display());
+        }
+        holds
+    }
+
+    fn update(&mut self, now: SystemTime) -> Result<bool, String> {
+        match self.file.try_lock() {
+            Ok(()) => {}
+            // Another agent is renewing or taking it over right now
+            Err(TryLockError::WouldBlock) => {
+                if self.expires.is_some_and(|expires| expires <= now) {
+                    self.expires = None;
+                }
+                return Ok(self.expires.is_some());
+            }
+            Err(TryLockError::Error(e)) => return Err(e.to_string()),
+        }
+        let result = self.take(now).map_err(|e| e.to_string());
+        let _ = self.file.unlock();
+        result
+    }
+
+    /// With the file locked: write this agent's record unless another
+    /// agent's lease is still running
+    fn take(&mut self, now: SystemTime) -> std::io::Result<bool> {
+        let mut record = String::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        (&mut self.file)
+            .take(RECORD_LEN as u64)
+            .read_to_string(&mut record)?;
+        if let Some((holder, expires)) = parse_record(&record) {
+            if holder != self.holder && unix_time(now) < expires {
+                self.expires = None;
+                return Ok(false);
+            }
+        }
+        let expires = now + self.duration;
+        let mut record = format!("{} {}", self.holder, (unix_time(expires) * 1000.0) as u64);
+        record.extend(std::iter::repeat_n(' ', RECORD_LEN - 1 - record.len()));
+        record.push('\n');
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(record.as_bytes())?;
+        self.file.sync_data()?;
+        self.expires = Some(expires);
+        Ok(true)
+    }
+}
+
+/// The holder and expiry, in seconds, of a record; `None` for an empty or
+/// garbled file, which is free to take
+fn parse_record(record: &str) -> Option<(&str, f64)> {
+    let (holder, expires) = record.trim_end().rsplit_once(' ')?;
+    let expires: u64 = expires.parse().ok()?;
+    Some((holder, expires as f64 / 1000.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_agent_holds_the_lease_until_it_runs_out() {
+        let path = std::env::temp_dir().join(format!("metrixd-lease-{}", std::process::id()));
+        let config = LeaderElectionConfig {
+            lock_file: path.clone(),
+            lease: Duration::from_secs(30),
+            cluster_metrics: vec![],
+        };
+        let mut node_a = Lease::open(&config, "node-a").unwrap();
+        let mut node_b = Lease::open(&config, "node b").unwrap();
+        let now = SystemTime::now();
+        assert!(node_a.renew(now));
+        assert!(!node_b.renew(now));
+        let later = now + Duration::from_secs(20);
+        assert!(node_a.renew(later));
+        assert!(!node_b.renew(later));
+        // node-a stopped renewing
+        let expired = later + Duration::from_secs(31);
+        assert!(node_b.renew(expired));
+        assert!(!node_a.renew(expired));
+
+        let record = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(record.len(), RECORD_LEN);
+        assert_eq!(parse_record(&record).unwrap().0, "node_b");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,484 @@
+//! Push exporters, for backends that don't scrape. After each collection
+//! cycle the exposition (with `metrics.exclude`, `labels` and disabled
+//! collectors applied, like /metrics) is flattened into samples and handed
+//! to every configured `[export.<name>]` whose `interval` has passed. They
+//! run on the blocking pool, one after another, so a slow backend delays
+//! the other exporters but never collection or scrapes. With
+//! `[export.leader_election]` the `cluster_metrics` are left out of every
+//! push unless this agent holds the lease.
+
+mod azure_monitor;
+mod cloudwatch;
+mod collectd;
+mod datadog;
+mod google_cloud;
+mod kafka;
+mod leader;
+mod mqtt;
+mod nats;
+mod pushgateway;
+mod remote_write;
+mod telegraf;
+mod victoriametrics;
+mod zabbix;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use prometheus::proto::MetricType;
+use prometheus::{Gauge, GaugeVec, IntCounterVec, Registry};
+use regex::Regex;
+
+use crate::config::{Config, ExportCommon, StreamFormat};
+use crate::metrics::builder::metric;
+use crate::sample::{flatten, Sample};
+use crate::server::gather_filtered;
+use crate::state::AppState;
+
+/// How long one push may wait on the network
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub trait Exporter: Send {
+    /// The `[export.<name>]` table it is configured by
+    fn name(&self) -> &'static str;
+
+    /// Push this cycle's samples
+    fn export(&mut self, samples: &[Sample]) -> Result<(), String>;
+}
+
+struct Scheduled {
+    exporter: Box<dyn Exporter>,
+    common: ExportCommon,
+    last: Option<Instant>,
+}
+
+impl Scheduled {
+    fn is_due(&self) -> bool {
+        match (self.common.interval, self.last) {
+            (Some(interval), Some(last)) => last.elapsed() >= interval,
+            _ => true,
+        }
+    }
+}
+
+pub struct Exports {
+    scheduled: Vec<Scheduled>,
+    election: Option<Election>,
+    errors: IntCounterVec,
+    last_success: GaugeVec,
+}
+
+/// The lease and the metric names only its holder pushes
+struct Election {
+    lease: leader::Lease,
+    cluster_metrics: Vec<Regex>,
+    leader: Gauge,
+}
+
+impl Exports {
+    /// The configured exporters. Sockets are opened here, so this runs
+    /// before seccomp forbids binding.
+    pub fn new(config: &Config, registry: &Registry, instance: &str) -> Result<Self, String> {
+        let mut exporters: Vec<(Box<dyn Exporter>, &ExportCommon)> = Vec::new();
+        if let Some(datadog) = &config.export.datadog {
+            exporters.push((Box::new(datadog::Datadog::new(datadog)?), &datadog.common));
+        }
+        if let Some(cloudwatch) = &config.export.cloudwatch {
+            exporters.push((
+                Box::new(cloudwatch::CloudWatch::new(cloudwatch)?),
+                &cloudwatch.common,
+            ));
+        }
+        if let Some(google_cloud) = &config.export.google_cloud {
+            exporters.push((
+                Box::new(google_cloud::GoogleCloud::new(google_cloud)?),
+                &google_cloud.common,
+            ));
+        }
+        if let Some(azure_monitor) = &config.export.azure_monitor {
+            exporters.push((
+                Box::new(azure_monitor::AzureMonitor::new(azure_monitor)?),
+                &azure_monitor.common,
+            ));
+        }
+        if let Some(mqtt) = &config.export.mqtt {
+            exporters.push((Box::new(mqtt::Mqtt::new(mqtt)), &mqtt.common));
+        }
+        if let Some(kafka) = &config.export.kafka {
+            exporters.push((Box::new(kafka::Kafka::new(kafka)), &kafka.common));
+        }
+        if let Some(nats) = &config.export.nats {
+            exporters.push((Box::new(nats::Nats::new(nats)), &nats.common));
+        }
+        if let Some(zabbix) = &config.export.zabbix {
+            exporters.push((
+                Box::new(zabbix::Zabbix::new(zabbix, instance)),
+                &zabbix.common,
+            ));
+        }
+        if let Some(collectd) = &config.export.collectd {
+            let interval = collectd
+                .common
+                .interval
+                .unwrap_or(config.collection.interval);
+            exporters.push((
+                Box::new(collectd::Collectd::new(collectd, interval, instance)?),
+                &collectd.common,
+            ));
+        }
+        if let Some(telegraf) = &config.export.telegraf {
+            exporters.push((
+                Box::new(telegraf::Telegraf::new(telegraf)),
+                &telegraf.common,
+            ));
+        }
+        if let Some(victoriametrics) = &config.export.victoriametrics {
+            exporters.push((
+                Box::new(victoriametrics::VictoriaMetrics::new(victoriametrics)?),
+                &victoriametrics.common,
+            ));
+        }
+
+        if let Some(pushgateway) = &config.export.pushgateway {
+            exporters.push((
+                Box::new(pushgateway::Pushgateway::new(pushgateway, instance)?),
+                &pushgateway.common,
+            ));
+        }
+        if let Some(remote_write) = &config.export.remote_write {
+            exporters.push((
+                Box::new(remote_write::RemoteWrite::new(remote_write)?),
+                &remote_write.common,
+            ));
+        }
+
+        let errors = metric("export_errors_total", "Pushes that failed")
+            .namespace("metrixd")
+            .registry(registry)
+            .int_counter_vec(&["exporter"]);
+        let last_success = metric(
+            "export_last_success_timestamp_seconds",
+            "When the exporter last pushed successfully",
+        )
+        .namespace("metrixd")
+        .registry(registry)
+        .gauge_vec(&["exporter"]);
+        let scheduled = exporters
+            .into_iter()
+            .map(|(exporter, common)| {
+                errors.with_label_values(&[exporter.name()]);
+                println!("Exporting to {}", exporter.name());
+                Scheduled {
+                    exporter,
+                    common: common.clone(),
+                    last: None,
+                }
+            })
+            .collect();
+        let election = match &config.export.leader_election {
+            Some(election) => Some(Election {
+                lease: leader::Lease::open(election, instance)?,
+                cluster_metrics: election.cluster_metrics.clone(),
+                leader: metric(
+                    "export_leader",
+                    "Whether this agent holds the lease and pushes the cluster metrics",
+                )
+                .namespace("metrixd")
+                .registry(registry)
+                .gauge(),
+            }),
+            None => None,
+        };
+        Ok(Exports {
+            scheduled,
+            election,
+            errors,
+            last_success,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scheduled.is_empty()
+    }
+
+    /// Push the current values to every exporter that is due
+    pub fn run(&mut self, state: &AppState) {
+        self.push(state, false);
+    }
+
+    /// Push the current values to every exporter, due or not, and say how
+    /// many pushes failed: the `push` command's one round
+    pub fn run_all(&mut self, state: &AppState) -> usize {
+        self.push(state, true)
+    }
+
+    fn push(&mut self, state: &AppState, all: bool) -> usize {
+        // Renewed every cycle, whether or not an exporter is due
+        let cluster_metrics = match &mut self.election {
+            Some(election) => {
+                let holds = election.lease.renew(SystemTime::now());
+                election.leader.set(if holds { 1.0 } else { 0.0 });
+                (!holds).then_some(&election.cluster_metrics)
+            }
+            None => None,
+        };
+        if !all && !self.scheduled.iter().any(Scheduled::is_due) {
+            return 0;
+        }
+        let mut samples = flatten(&gather_filtered(state));
+        if let Some(cluster_metrics) = cluster_metrics {
+            samples.retain(|sample| !cluster_metrics.iter().any(|re| re.is_match(&sample.name)));
+        }
+        let mut failed = 0;
+        for scheduled in self
+            .scheduled
+            .iter_mut()
+            .filter(|scheduled| all || scheduled.is_due())
+        {
+            scheduled.last = Some(Instant::now());
+            let include = &scheduled.common.include;
+            let selected: Cow<[Sample]> = if include.is_empty() {
+                Cow::Borrowed(&samples)
+            } else {
+                samples
+                    .iter()
+                    .filter(|sample| include.iter().any(|re| re.is_match(&sample.name)))
+                    .cloned()
+                    .collect()
+            };
+            let name = scheduled.exporter.name();
+            match scheduled.exporter.export(&selected) {
+                Ok(()) => self
+                    .last_success
+                    .with_label_values(&[name])
+                    .set(unix_time(SystemTime::now())),
+                Err(e) => {
+                    self.errors.with_label_values(&[name]).inc();
+                    eprintln!("Export to {} failed: {}", name, e);
+                    failed += 1;
+                }
+            }
+        }
+        failed
+    }
+}
+
+fn unix_time(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Whether a sample only ever grows: counters and the buckets, counts and
+/// sums of histograms and summaries
+fn is_cumulative(sample: &Sample) -> bool {
+    match sample.kind {
+        MetricType::COUNTER | MetricType::HISTOGRAM => true,
+        MetricType::SUMMARY => !sample.labels.iter().any(|(name, _)| name == "quantile"),
+        _ => false,
+    }
+}
+
+/// A sample's name and labels in one string, to recognise it next cycle
+fn series_key(sample: &Sample) -> String {
+    let mut key = sample.name.clone();
+    for (name, value) in &sample.labels {
+        key.push_str(&format!("\u{0}{}={}", name, value));
+    }
+    key
+}
+
+/// Turns cumulative samples into their increase since the last push, for
+/// backends that add up what they receive
+#[derive(Default)]
+struct Deltas {
+    previous: HashMap<String, f64>,
+}
+
+impl Deltas {
+    /// `None` the first time a series is seen. After a reset (e.g. a
+    /// restart of whatever is counted) the whole new value is the increase.
+    fn delta(&mut self, sample: &Sample) -> Option<f64> {
+        let previous = self.previous.insert(series_key(sample), sample.value)?;
+        Some(if sample.value >= previous {
+            sample.value - previous
+        } else {
+            sample.value
+        })
+    }
+}
+
+/// `text` as a JSON string literal
+pub(crate) fn json_string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// RFC 3986 percent-encoding, for query strings and forms
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// `name{label="value",...} value [timestamp]` lines of the text format,
+/// timestamps in milliseconds
+fn text(samples: &[Sample], timestamp: Option<i64>) -> String {
+    let mut text = String::new();
+    for sample in samples.iter().filter(|sample| sample.value.is_finite()) {
+        text.push_str(&sample.name);
+        if !sample.labels.is_empty() {
+            let labels: Vec<String> = sample
+                .labels
+                .iter()
+                .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+                .collect();
+            let _ = write!(text, "{{{}}}", labels.join(","));
+        }
+        let _ = write!(text, " {}", sample.value);
+        if let Some(timestamp) = timestamp {
+            let _ = write!(text, " {}", timestamp);
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// A label value with the text format's escapes
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// `template` with `{name}` and `{<label>}` filled in, for topics and
+/// subjects. Values can't add `reserved` separators or wildcards; missing
+/// labels become `_`.
+fn fill_template(template: &str, sample: &Sample, reserved: &[char]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        filled.push_str(&rest[..start]);
+        let key = &rest[start + 1..start + end];
+        let value = if key == "name" {
+            Some(sample.name.as_str())
+        } else {
+            sample
+                .labels
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.as_str())
+        };
+        match value.filter(|value| !value.is_empty()) {
+            Some(value) => filled.push_str(&value.replace(reserved, "_")),
+            None => filled.push('_'),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// `{"name":..,"labels":{..},"value":..,"timestamp":..}`, the message
+/// format of the streaming exporters
+fn sample_json(sample: &Sample, timestamp: f64) -> String {
+    let labels: Vec<String> = sample
+        .labels
+        .iter()
+        .map(|(name, value)| format!("{}:{}", json_string(name), json_string(value)))
+        .collect();
+    format!(
+        "{{\"name\":{},\"labels\":{{{}}},\"value\":{},\"timestamp\":{}}}",
+        json_string(&sample.name),
+        labels.join(","),
+        sample.value,
+        timestamp
+    )
+}
+
+/// A sample as a streaming exporter's message
+fn encode(format: StreamFormat, sample: &Sample, timestamp: f64) -> Vec<u8> {
+    match format {
+        StreamFormat::Json => sample_json(sample, timestamp).into_bytes(),
+        // Writing to a Vec doesn't fail
+        StreamFormat::Protobuf => crate::grpc::encode_sample(sample).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+fn sample(name: &str, labels: &[(&str, &str)], value: f64, kind: MetricType) -> Sample {
+    Sample {
+        name: name.to_string(),
+        labels: labels
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        value,
+        kind,
+        help: String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deltas_start_on_the_second_push_and_survive_resets() {
+        let mut deltas = Deltas::default();
+        let requests = |value| sample("requests_total", &[], value, MetricType::COUNTER);
+        assert_eq!(deltas.delta(&requests(10.0)), None);
+        assert_eq!(deltas.delta(&requests(15.0)), Some(5.0));
+        assert_eq!(deltas.delta(&requests(3.0)), Some(3.0));
+    }
+
+    #[test]
+    fn fills_in_topics_and_messages() {
+        let sample = sample(
+            "disk_free_bytes",
+            &[("mountpoint", "/var"), ("device", "sda1")],
+            1024.0,
+            MetricType::GAUGE,
+        );
+        assert_eq!(
+            fill_template("site/{host}/{name}/{mountpoint}", &sample, &['/']),
+            "site/_/disk_free_bytes/_var"
+        );
+        assert_eq!(
+            sample_json(&sample, 1700000000.5),
+            "{\"name\":\"disk_free_bytes\",\"labels\":{\"mountpoint\":\"/var\",\"device\":\"sda1\"},\
+             \"value\":1024,\"timestamp\":1700000000.5}"
+        );
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("a\"b\\c\nd\u{1}"), "\"a\\\"b\\\\c\\nd\\u0001\"");
+    }
+}
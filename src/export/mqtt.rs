@@ -0,0 +1,159 @@
+//! `[export.mqtt]`: every sample as a JSON message, published with QoS 0
+//! to a topic made from the metric name and labels. Speaks just enough
+//! MQTT 3.1.1 to connect and publish; the connection is kept open across
+//! pushes and made again after an error.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::SystemTime;
+
+use super::{fill_template, sample_json, unix_time, Exporter, TIMEOUT};
+use crate::config::MqttConfig;
+use crate::fetch;
+use crate::sample::Sample;
+
+pub struct Mqtt {
+    config: MqttConfig,
+    stream: Option<TcpStream>,
+}
+
+impl Mqtt {
+    pub fn new(config: &MqttConfig) -> Self {
+        Mqtt {
+            config: config.clone(),
+            stream: None,
+        }
+    }
+
+    fn connect(&self) -> Result<TcpStream, String> {
+        let mut stream = fetch::connect(&self.config.broker, TIMEOUT)?;
+        stream
+            .set_read_timeout(Some(TIMEOUT))
+            .and_then(|()| stream.set_write_timeout(Some(TIMEOUT)))
+            .and_then(|()| stream.write_all(&connect_packet(&self.config)))
+            .map_err(|e| format!("{}: {}", self.config.broker, e))?;
+        let mut connack = [0u8; 4];
+        stream
+            .read_exact(&mut connack)
+            .map_err(|e| format!("{}: no CONNACK: {}", self.config.broker, e))?;
+        match connack {
+            [0x20, 2, _, 0] => Ok(stream),
+            [0x20, 2, _, 4] => Err(format!("{}: bad username or password", self.config.broker)),
+            [0x20, 2, _, 5] => Err(format!("{}: not authorized", self.config.broker)),
+            [0x20, 2, _, code] => Err(format!(
+                "{}: connection refused with code {}",
+                self.config.broker, code
+            )),
+            _ => Err(format!("{}: not an MQTT broker", self.config.broker)),
+        }
+    }
+}
+
+impl Exporter for Mqtt {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn export(&mut self, samples: &[Sample]) -> Result<(), String> {
+        let timestamp = unix_time(SystemTime::now());
+        let mut packets = Vec::new();
+        for sample in samples.iter().filter(|sample| sample.value.is_finite()) {
+            packets.extend(publish_packet(
+                &fill_template(&self.config.topic, sample, &['/', '+', '#']),
+                sample_json(sample, timestamp).as_bytes(),
+                self.config.retain,
+            ));
+        }
+        if packets.is_empty() {
+            return Ok(());
+        }
+
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => self.stream.insert(self.connect()?),
+        };
+        if let Err(e) = stream.write_all(&packets) {
+            self.stream = None;
+            return Err(format!("{}: {}", self.config.broker, e));
+        }
+        Ok(())
+    }
+}
+
+/// A packet with the fixed header `kind`
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    // Remaining length: 7 bits per byte, least significant first
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        packet.push(if length > 0 { byte | 0x80 } else { byte });
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn push_string(buffer: &mut Vec<u8>, text: &[u8]) {
+    buffer.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(text);
+}
+
+/// CONNECT with a clean session and no keep-alive, as metrixd only writes
+fn connect_packet(config: &MqttConfig) -> Vec<u8> {
+    let mut flags = 0x02;
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    if config.password.is_some() {
+        flags |= 0x40;
+    }
+    let mut body = Vec::new();
+    push_string(&mut body, b"MQTT");
+    body.extend_from_slice(&[4, flags, 0, 0]);
+    push_string(&mut body, config.client_id.as_bytes());
+    for field in [&config.username, &config.password].into_iter().flatten() {
+        push_string(&mut body, field.as_bytes());
+    }
+    packet(0x10, &body)
+}
+
+/// PUBLISH with QoS 0
+fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::with_capacity(2 + topic.len() + payload.len());
+    push_string(&mut body, topic.as_bytes());
+    body.extend_from_slice(payload);
+    packet(if retain { 0x31 } else { 0x30 }, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_packets() {
+        let config = MqttConfig {
+            common: Default::default(),
+            broker: String::new(),
+            topic: String::new(),
+            client_id: "a".to_string(),
+            username: Some("u".to_string()),
+            password: Some("p".to_string()),
+            retain: false,
+        };
+        assert_eq!(
+            connect_packet(&config),
+            [
+                0x10, 19, 0, 4, b'M', b'Q', b'T', b'T', 4, 0xc2, 0, 0, 0, 1, b'a', 0, 1, b'u', 0,
+                1, b'p'
+            ]
+        );
+        // Remaining lengths above 127 take a second byte
+        let publish = publish_packet("t", &[b'x'; 200], true);
+        assert_eq!(publish[..5], [0x31, 0xcb, 0x01, 0, 1]);
+        assert_eq!(publish.len(), 3 + 203);
+    }
+}
@@ -0,0 +1,201 @@
+//! `[export.nats]`: every sample as a message on a subject made from the
+//! metric name and labels, over the text protocol of core NATS (at most
+//! once, no JetStream acks). The connection is kept open across pushes,
+//! answering the server's PINGs before each one, and made again after an
+//! error.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::SystemTime;
+
+use super::{encode, fill_template, json_string, unix_time, Exporter, TIMEOUT};
+use crate::config::NatsConfig;
+use crate::fetch;
+use crate::sample::Sample;
+
+pub struct Nats {
+    config: NatsConfig,
+    stream: Option<TcpStream>,
+}
+
+impl Nats {
+    pub fn new(config: &NatsConfig) -> Self {
+        Nats {
+            config: config.clone(),
+            stream: None,
+        }
+    }
+
+    /// Connect, and PING so that a rejected CONNECT shows up as an error
+    fn connect(&self) -> Result<TcpStream, String> {
+        let server = &self.config.server;
+        let mut stream = fetch::connect(server, TIMEOUT)?;
+        let error = |e: std::io::Error| format!("{}: {}", server, e);
+        stream.set_read_timeout(Some(TIMEOUT)).map_err(error)?;
+        stream.set_write_timeout(Some(TIMEOUT)).map_err(error)?;
+        if !read_line(&mut stream).map_err(error)?.starts_with("INFO ") {
+            return Err(format!("{}: not a NATS server", server));
+        }
+        stream
+            .write_all(format!("CONNECT {}\r\nPING\r\n", connect_options(&self.config)).as_bytes())
+            .map_err(error)?;
+        match read_line(&mut stream).map_err(error)?.trim_end() {
+            "PONG" => Ok(stream),
+            reply => Err(format!("{}: {}", server, reply)),
+        }
+    }
+}
+
+impl Exporter for Nats {
+    fn name(&self) -> &'static str {
+        "nats"
+    }
+
+    fn export(&mut self, samples: &[Sample]) -> Result<(), String> {
+        let timestamp = unix_time(SystemTime::now());
+        let mut buffer = Vec::new();
+        for sample in samples.iter().filter(|sample| sample.value.is_finite()) {
+            let subject = fill_template(&self.config.subject, sample, &['.', '*', '>', ' ']);
+            let payload = encode(self.config.format, sample, timestamp);
+            buffer.extend_from_slice(format!("PUB {} {}\r\n", subject, payload.len()).as_bytes());
+            buffer.extend_from_slice(&payload);
+            buffer.extend_from_slice(b"\r\n");
+        }
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => self.stream.insert(self.connect()?),
+        };
+        let result = pongs(stream).and_then(|pongs| {
+            stream.write_all(&pongs)?;
+            stream.write_all(&buffer)
+        });
+        if let Err(e) = result {
+            self.stream = None;
+            return Err(format!("{}: {}", self.config.server, e));
+        }
+        Ok(())
+    }
+}
+
+/// One line, a byte at a time so that nothing after it is consumed
+fn read_line(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    while line.last() != Some(&b'\n') {
+        stream.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// A PONG for every PING the server sent since the last push, so it
+/// doesn't drop the connection as stale
+fn pongs(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    stream.set_nonblocking(true)?;
+    let mut received = Vec::new();
+    let mut buffer = [0u8; 4096];
+    let result = loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => break Err(ErrorKind::UnexpectedEof.into()),
+            Ok(len) => received.extend_from_slice(&buffer[..len]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    stream.set_nonblocking(false)?;
+    result?;
+    let pings = received
+        .split(|&byte| byte == b'\n')
+        .filter(|line| line.starts_with(b"PING"))
+        .count();
+    Ok(b"PONG\r\n".repeat(pings))
+}
+
+fn connect_options(config: &NatsConfig) -> String {
+    let mut options = format!(
+        "{{\"verbose\":false,\"pedantic\":false,\"lang\":\"rust\",\"name\":\"metrixd\",\"version\":{}",
+        json_string(env!("CARGO_PKG_VERSION"))
+    );
+    for (key, value) in [
+        ("user", &config.username),
+        ("pass", &config.password),
+        ("auth_token", &config.token),
+    ] {
+        if let Some(value) = value {
+            options.push_str(&format!(",\"{}\":{}", key, json_string(value)));
+        }
+    }
+    options.push('}');
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sample;
+    use super::*;
+    use crate::config::StreamFormat;
+    use prometheus::proto::MetricType;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    #[test]
+    fn publishes_to_a_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let received = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream
+                .write_all(b"INFO {\"max_payload\":1048576}\r\n")
+                .unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut lines = Vec::new();
+            let mut read_lines = |count| {
+                for _ in 0..count {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    lines.push(line);
+                }
+            };
+            read_lines(2);
+            stream.write_all(b"PONG\r\n").unwrap();
+            // The first push, then a PING that the second one answers
+            read_lines(2);
+            stream.write_all(b"PING\r\n").unwrap();
+            read_lines(3);
+            lines
+        });
+
+        let mut nats = Nats::new(&NatsConfig {
+            common: Default::default(),
+            server,
+            subject: "metrixd.{name}.{mountpoint}".to_string(),
+            format: StreamFormat::Json,
+            username: None,
+            password: None,
+            token: Some("s3cret".to_string()),
+        });
+        let samples = [sample(
+            "disk_free_bytes",
+            &[("mountpoint", "/var/lib")],
+            1024.0,
+            MetricType::GAUGE,
+        )];
+        nats.export(&samples).unwrap();
+        // Let the PING arrive, so the next push answers it
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        nats.export(&samples).unwrap();
+
+        let lines = received.join().unwrap();
+        assert!(lines[0].starts_with("CONNECT {\"verbose\":false,"));
+        assert!(lines[0].contains(",\"auth_token\":\"s3cret\"}"));
+        assert_eq!(lines[1], "PING\r\n");
+        assert!(lines[2].starts_with("PUB metrixd.disk_free_bytes./var/lib "));
+        assert!(lines[3].starts_with("{\"name\":\"disk_free_bytes\""));
+        assert_eq!(lines[4], "PONG\r\n");
+        assert!(lines[5].starts_with("PUB metrixd.disk_free_bytes./var/lib "));
+    }
+}
@@ -0,0 +1,74 @@
+//! `[export.pushgateway]`: samples in the text format, without types or
+//! timestamps (the Pushgateway rejects timestamps), PUT to the group
+//! `job/<job>/instance/<instance.id>`, replacing what the last push left
+//! there. Series that went away since are dropped with it.
+
+use super::{percent_encode, text, Exporter, TIMEOUT};
+use crate::config::PushgatewayConfig;
+use crate::fetch;
+use crate::sample::Sample;
+
+pub struct Pushgateway {
+    address: String,
+    path: String,
+}
+
+impl Pushgateway {
+    pub fn new(config: &PushgatewayConfig, instance: &str) -> Result<Self, String> {
+        let (address, base_path) = fetch::parse_url(&config.url)?;
+        let path = format!(
+            "{}/metrics/job/{}/instance/{}",
+            base_path.trim_end_matches('/'),
+            percent_encode(&config.job),
+            percent_encode(instance)
+        );
+        Ok(Pushgateway { address, path })
+    }
+}
+
+impl Exporter for Pushgateway {
+    fn name(&self) -> &'static str {
+        "pushgateway"
+    }
+
+    fn export(&mut self, samples: &[Sample]) -> Result<(), String> {
+        let body = text(samples, None);
+        let response = fetch::send(
+            &self.address,
+            "PUT",
+            &self.path,
+            &[("Content-Type", "text/plain; version=0.0.4")],
+            body.as_bytes(),
+            TIMEOUT,
+        )?;
+        if !(200..300).contains(&response.status) {
+            return Err(format!(
+                "{}: HTTP status {}: {}",
+                self.address,
+                response.status,
+                String::from_utf8_lossy(&response.body).trim()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pushes_to_the_instance_group() {
+        let pushgateway = Pushgateway::new(
+            &PushgatewayConfig {
+                common: Default::default(),
+                url: "http://pushgateway:9091/".to_string(),
+                job: "node".to_string(),
+            },
+            "edge 7",
+        )
+        .unwrap();
+        assert_eq!(pushgateway.address, "pushgateway:9091");
+        assert_eq!(pushgateway.path, "/metrics/job/node/instance/edge%207");
+    }
+}
@@ -0,0 +1,140 @@
+//! `[export.telegraf]`: samples in InfluxDB line protocol, written to a
+//! Telegraf socket_listener input over TCP or a unix socket. Each sample
+//! is a line with the metric name as the measurement, its labels as tags
+//! and a single `value` field. The connection is kept open across pushes
+//! and made again after an error.
+
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{Exporter, TIMEOUT};
+use crate::config::{TelegrafAddress, TelegrafConfig};
+use crate::fetch;
+use crate::sample::Sample;
+
+pub struct Telegraf {
+    config: TelegrafConfig,
+    stream: Option<Box<dyn Write + Send>>,
+}
+
+impl Telegraf {
+    pub fn new(config: &TelegrafConfig) -> Self {
+        Telegraf {
+            config: config.clone(),
+            stream: None,
+        }
+    }
+
+    fn address(&self) -> String {
+        match &self.config.address {
+            TelegrafAddress::Tcp(address) => address.clone(),
+            TelegrafAddress::Unix(path) => path.display().to_string(),
+        }
+    }
+
+    fn connect(&self) -> Result<Box<dyn Write + Send>, String> {
+        let error = |e: std::io::Error| format!("{}: {}", self.address(), e);
+        match &self.config.address {
+            TelegrafAddress::Tcp(address) => {
+                let stream = fetch::connect(address, TIMEOUT)?;
+                stream.set_write_timeout(Some(TIMEOUT)).map_err(error)?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(unix)]
+            TelegrafAddress::Unix(path) => {
+                let stream = std::os::unix::net::UnixStream::connect(path).map_err(error)?;
+                stream.set_write_timeout(Some(TIMEOUT)).map_err(error)?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(not(unix))]
+            TelegrafAddress::Unix(_) => Err(format!(
+                "{}: unix sockets aren't supported on this platform",
+                self.address()
+            )),
+        }
+    }
+}
+
+impl Exporter for Telegraf {
+    fn name(&self) -> &'static str {
+        "telegraf"
+    }
+
+    fn export(&mut self, samples: &[Sample]) -> Result<(), String> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let lines: String = samples
+            .iter()
+            .filter(|sample| sample.value.is_finite())
+            .map(|sample| line(sample, timestamp))
+            .collect();
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => self.stream.insert(self.connect()?),
+        };
+        if let Err(e) = stream.write_all(lines.as_bytes()) {
+            self.stream = None;
+            return Err(format!("{}: {}", self.address(), e));
+        }
+        Ok(())
+    }
+}
+
+/// `name,label=value,... value=<value> <nanoseconds>`, leaving out empty
+/// labels, which line protocol has no way to write
+fn line(sample: &Sample, timestamp: u128) -> String {
+    let mut line = escape(&sample.name, &[',', ' ']);
+    for (name, value) in &sample.labels {
+        if !value.is_empty() {
+            line.push(',');
+            line.push_str(&escape(name, &[',', '=', ' ']));
+            line.push('=');
+            line.push_str(&escape(value, &[',', '=', ' ']));
+        }
+    }
+    line.push_str(&format!(" value={:?} {}\n", sample.value, timestamp));
+    line
+}
+
+/// `text` with backslashes before `special`; newlines can't be escaped,
+/// so they become spaces
+fn escape(text: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        let c = if c == '\n' { ' ' } else { c };
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sample;
+    use super::*;
+    use prometheus::proto::MetricType;
+
+    #[test]
+    fn writes_line_protocol() {
+        assert_eq!(
+            line(
+                &sample(
+                    "disk_free_bytes",
+                    &[("mountpoint", "/mnt/my disk"), ("label", "a=b,c"), ("empty", "")],
+                    1024.0,
+                    MetricType::GAUGE
+                ),
+                1_700_000_000_000_000_000
+            ),
+            "disk_free_bytes,mountpoint=/mnt/my\\ disk,label=a\\=b\\,c value=1024.0 1700000000000000000\n"
+        );
+    }
+}
@@ -0,0 +1,107 @@
+//! `[export.victoriametrics]`: samples in the Prometheus text format,
+//! posted to VictoriaMetrics' `/api/v1/import/prometheus`. `extra_labels`
+//! go in the query string as `extra_label` arguments, which VictoriaMetrics
+//! adds to every sample.
+
+use std::time::SystemTime;
+
+use super::{percent_encode, text, unix_time, Exporter, TIMEOUT};
+use crate::config::VictoriaMetricsConfig;
+use crate::fetch;
+use crate::sample::Sample;
+
+pub struct VictoriaMetrics {
+    address: String,
+    path: String,
+}
+
+impl VictoriaMetrics {
+    pub fn new(config: &VictoriaMetricsConfig) -> Result<Self, String> {
+        let (address, base_path) = fetch::parse_url(&config.url)?;
+        let mut path = format!(
+            "{}/api/v1/import/prometheus",
+            base_path.trim_end_matches('/')
+        );
+        for (i, (name, value)) in config.extra_labels.iter().enumerate() {
+            path.push(if i == 0 { '?' } else { '&' });
+            path.push_str("extra_label=");
+            path.push_str(&percent_encode(&format!("{}={}", name, value)));
+        }
+        Ok(VictoriaMetrics { address, path })
+    }
+}
+
+impl Exporter for VictoriaMetrics {
+    fn name(&self) -> &'static str {
+        "victoriametrics"
+    }
+
+    fn export(&mut self, samples: &[Sample]) -> Result<(), String> {
+        let body = text(
+            samples,
+            Some((unix_time(SystemTime::now()) * 1000.0) as i64),
+        );
+        if body.is_empty() {
+            return Ok(());
+        }
+        let response = fetch::send(
+            &self.address,
+            "POST",
+            &self.path,
+            &[("Content-Type", "text/plain")],
+            body.as_bytes(),
+            TIMEOUT,
+        )?;
+        if !(200..300).contains(&response.status) {
+            return Err(format!(
+                "{}: HTTP status {}: {}",
+                self.address,
+                response.status,
+                String::from_utf8_lossy(&response.body).trim()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sample;
+    use super::*;
+    use prometheus::proto::MetricType;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn writes_the_text_format() {
+        let victoriametrics = VictoriaMetrics::new(&VictoriaMetricsConfig {
+            common: Default::default(),
+            url: "http://vminsert:8480/insert/0/prometheus/".to_string(),
+            extra_labels: BTreeMap::from([
+                ("dc".to_string(), "eu 1".to_string()),
+                ("job".to_string(), "node".to_string()),
+            ]),
+        })
+        .unwrap();
+        assert_eq!(victoriametrics.address, "vminsert:8480");
+        assert_eq!(
+            victoriametrics.path,
+            "/insert/0/prometheus/api/v1/import/prometheus\
+             ?extra_label=dc%3Deu%201&extra_label=job%3Dnode"
+        );
+
+        let samples = [
+            sample("load1", &[], 0.5, MetricType::GAUGE),
+            sample(
+                "disk_free_bytes",
+                &[("mountpoint", "/mnt/\"x\"")],
+                1024.0,
+                MetricType::GAUGE,
+            ),
+        ];
+        assert_eq!(
+            text(&samples, Some(1_700_000_000_000)),
+            "load1 0.5 1700000000000\n\
+             disk_free_bytes{mountpoint=\"/mnt/\\\"x\\\"\"} 1024 1700000000000\n"
+        );
+    }
+}
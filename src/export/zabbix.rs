@@ -0,0 +1,171 @@
+//! `[export.zabbix]`: values for trapper items, sent like zabbix_sender
+//! does. Each sample becomes the item whose key `keys` maps its metric
+//! name to, or `name[label values...]`. Values are sent as they are, so
+//! counters want a "change per second" preprocessing step in Zabbix.
+
+use std::io::{Read, Write};
+use std::time::SystemTime;
+
+use super::{fill_template, json_string, unix_time, Exporter, TIMEOUT};
+use crate::cloud;
+use crate::config::ZabbixConfig;
+use crate::fetch;
+use crate::sample::Sample;
+
+/// What zabbix_sender sends per connection
+const VALUES_PER_REQUEST: usize = 250;
+
+pub struct Zabbix {
+    config: ZabbixConfig,
+    host: String,
+}
+
+impl Zabbix {
+    /// Items belong to `instance` unless `host` names another Zabbix host
+    pub fn new(config: &ZabbixConfig, instance: &str) -> Self {
+        Zabbix {
+            config: config.clone(),
+            host: config.host.clone().unwrap_or_else(|| instance.to_string()),
+        }
+    }
+
+    fn item_key(&self, sample: &Sample) -> String {
+        match self.config.keys.get(&sample.name) {
+            Some(template) => fill_template(template, sample, &[',', '[', ']', '"']),
+            None if sample.labels.is_empty() => sample.name.clone(),
+            None => {
+                let parameters: Vec<String> = sample
+                    .labels
+                    .iter()
+                    .map(|(_, value)| key_parameter(value))
+                    .collect();
+                format!("{}[{}]", sample.name, parameters.join(","))
+            }
+        }
+    }
+
+    fn send(&self, body: &str) -> Result<(), String> {
+        let server = &self.config.server;
+        let error = |e: std::io::Error| format!("{}: {}", server, e);
+        let mut stream = fetch::connect(server, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT)).map_err(error)?;
+        stream.set_write_timeout(Some(TIMEOUT)).map_err(error)?;
+        stream.write_all(&frame(body.as_bytes())).map_err(error)?;
+
+        let mut header = [0u8; 13];
+        stream.read_exact(&mut header).map_err(error)?;
+        if &header[..4] != b"ZBXD" {
+            return Err(format!("{}: not a Zabbix server", server));
+        }
+        let len = u64::from_le_bytes(header[5..].try_into().unwrap());
+        let mut response = Vec::new();
+        stream
+            .take(len.min(1 << 20))
+            .read_to_end(&mut response)
+            .map_err(error)?;
+        let response = String::from_utf8_lossy(&response);
+        let info = cloud::json_field(&response, "info").unwrap_or_default();
+        if cloud::json_field(&response, "response").as_deref() != Some("success") {
+            return Err(format!("{}: {}", server, response.trim()));
+        }
+        // e.g. "processed: 2; failed: 1; total: 3; seconds spent: 0.000055"
+        if !info.contains("failed: 0;") {
+            return Err(format!(
+                "{}: {} (values of unknown items, or items that aren't trappers, fail)",
+                server, info
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Exporter for Zabbix {
+    fn name(&self) -> &'static str {
+        "zabbix"
+    }
+
+    fn export(&mut self, samples: &[Sample]) -> Result<(), String> {
+        let clock = unix_time(SystemTime::now()) as u64;
+        let values: Vec<String> = samples
+            .iter()
+            .filter(|sample| sample.value.is_finite())
+            .map(|sample| {
+                format!(
+                    "{{\"host\":{},\"key\":{},\"value\":\"{}\",\"clock\":{}}}",
+                    json_string(&self.host),
+                    json_string(&self.item_key(sample)),
+                    sample.value,
+                    clock
+                )
+            })
+            .collect();
+        for chunk in values.chunks(VALUES_PER_REQUEST) {
+            self.send(&format!(
+                "{{\"request\":\"sender data\",\"data\":[{}]}}",
+                chunk.join(",")
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+/// An item key parameter, quoted when it would otherwise end the
+/// parameter or the key
+fn key_parameter(value: &str) -> String {
+    if value.contains([',', ']', '"']) || value.starts_with(' ') {
+        format!("\"{}\"", value.replace('"', "\\\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// The Zabbix protocol header, then `data`
+fn frame(data: &[u8]) -> Vec<u8> {
+    let mut frame = b"ZBXD\x01".to_vec();
+    frame.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sample;
+    use super::*;
+    use prometheus::proto::MetricType;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn maps_metric_names_to_item_keys() {
+        let zabbix = Zabbix::new(
+            &ZabbixConfig {
+                common: Default::default(),
+                server: String::new(),
+                host: None,
+                keys: BTreeMap::from([(
+                    "disk_usage_percent".to_string(),
+                    "vfs.fs.size[{mountpoint},pused]".to_string(),
+                )]),
+            },
+            "web-1",
+        );
+        assert_eq!(zabbix.host, "web-1");
+        let key = |name, labels| zabbix.item_key(&sample(name, labels, 1.0, MetricType::GAUGE));
+        assert_eq!(
+            key("disk_usage_percent", &[("mountpoint", "/")]),
+            "vfs.fs.size[/,pused]"
+        );
+        assert_eq!(key("load1", &[]), "load1");
+        assert_eq!(
+            key("cpu_usage_percent", &[("core", "0"), ("mode", "a,b")]),
+            "cpu_usage_percent[0,\"a,b\"]"
+        );
+    }
+
+    #[test]
+    fn frames_data() {
+        assert_eq!(
+            frame(b"{}"),
+            [b'Z', b'B', b'X', b'D', 1, 2, 0, 0, 0, 0, 0, 0, 0, b'{', b'}']
+        );
+    }
+}
@@ -0,0 +1,678 @@
+//! The text exposition format, read back: what a textfile, a command or
+//! a proxied exporter hands over becomes typed metric families, ready to
+//! return from a collector's `collect()`. Input is checked the way a
+//! Prometheus scrape would be, i.e. valid names, at most one HELP and TYPE
+//! per family, no series twice and cumulative histogram buckets, and
+//! rejected as a whole with the line at fault. `conflicts` finds what
+//! would clash with the families already served.
+
+// Only the proxy collector reads exposition text so far
+#![cfg_attr(not(feature = "proxy"), allow(dead_code))]
+
+use std::collections::{HashMap, HashSet};
+
+use prometheus::proto::{
+    Bucket, Counter, Gauge, Histogram, LabelPair, Metric, MetricFamily, MetricType, Quantile,
+    Summary, Untyped,
+};
+
+/// The samples histograms and summaries have besides their own name
+const SUFFIXES: [(&str, MetricType); 5] = [
+    ("_bucket", MetricType::HISTOGRAM),
+    ("_sum", MetricType::HISTOGRAM),
+    ("_count", MetricType::HISTOGRAM),
+    ("_sum", MetricType::SUMMARY),
+    ("_count", MetricType::SUMMARY),
+];
+
+/// A family as it's being read
+struct Family {
+    name: String,
+    help: Option<String>,
+    kind: Option<MetricType>,
+    series: Vec<Series>,
+}
+
+/// One series of a family, i.e. one `Metric`: for histograms and summaries
+/// the samples sharing its labels apart from `le` or `quantile`
+#[derive(Default)]
+struct Series {
+    labels: Vec<(String, String)>,
+    value: Option<f64>,
+    /// `(le, count)` or `(quantile, value)`
+    points: Vec<(f64, f64)>,
+    sum: Option<f64>,
+    count: Option<f64>,
+    timestamp: Option<i64>,
+}
+
+/// Parse `text` into metric families, in the order they first appear
+pub fn parse(text: &str) -> Result<Vec<MetricFamily>, String> {
+    let mut families: Vec<Family> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for (number, line) in text.lines().enumerate() {
+        let at = |e: String| format!("line {}: {}", number + 1, e);
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(comment) = line.strip_prefix('#') {
+            read_comment(comment, &mut families, &mut index).map_err(at)?;
+        } else {
+            read_sample(line, &mut families, &mut index).map_err(at)?;
+        }
+    }
+    families.into_iter().map(finish).collect()
+}
+
+/// The families in `families` that would clash with `served`, by name
+/// and why: a sample name another family also writes, like a gauge
+/// `x_count` next to a histogram `x`, or the same family with a different
+/// type. The same family merges, unless one of its series is served
+/// already.
+pub fn conflicts<'a>(
+    families: &'a [MetricFamily],
+    served: &[MetricFamily],
+) -> Vec<(&'a str, String)> {
+    let taken: HashMap<String, &MetricFamily> = served
+        .iter()
+        .flat_map(|family| {
+            sample_names(family)
+                .into_iter()
+                .map(move |name| (name, family))
+        })
+        .collect();
+    families
+        .iter()
+        .filter_map(|family| {
+            let name = family.name();
+            let clash = sample_names(family).into_iter().find_map(|sample| {
+                let other = taken.get(&sample)?;
+                (other.name() != name || other.type_() != family.type_()).then_some(other)
+            });
+            if let Some(other) = clash {
+                let reason = format!(
+                    "clashes with the {} {}",
+                    type_name(other.type_()),
+                    other.name()
+                );
+                return Some((name, reason));
+            }
+            let series: HashSet<_> = served
+                .iter()
+                .filter(|other| other.name() == name)
+                .flat_map(|other| other.get_metric())
+                .map(label_set)
+                .collect();
+            let duplicate = family
+                .get_metric()
+                .iter()
+                .map(label_set)
+                .find(|labels| series.contains(labels))?;
+            Some((name, format!("{{{}}} is served already", duplicate)))
+        })
+        .collect()
+}
+
+/// A series' labels as in the text format, sorted by name
+fn label_set(metric: &Metric) -> String {
+    let mut labels: Vec<String> = metric
+        .get_label()
+        .iter()
+        .map(|label| format!("{}={:?}", label.name(), label.value()))
+        .collect();
+    labels.sort();
+    labels.join(",")
+}
+
+/// The names the family's samples have in the text format
+fn sample_names(family: &MetricFamily) -> Vec<String> {
+    let name = family.name();
+    match family.type_() {
+        MetricType::HISTOGRAM => ["_bucket", "_sum", "_count"]
+            .iter()
+            .map(|suffix| format!("{}{}", name, suffix))
+            .collect(),
+        MetricType::SUMMARY => vec![
+            name.to_string(),
+            format!("{}_sum", name),
+            format!("{}_count", name),
+        ],
+        _ => vec![name.to_string()],
+    }
+}
+
+/// `# HELP name text` or `# TYPE name type`; other comments are skipped
+fn read_comment(
+    comment: &str,
+    families: &mut Vec<Family>,
+    index: &mut HashMap<String, usize>,
+) -> Result<(), String> {
+    let mut words = comment.trim_start().splitn(3, [' ', '\t']);
+    let keyword = words.next().unwrap_or("");
+    if keyword != "HELP" && keyword != "TYPE" {
+        return Ok(());
+    }
+    let name = words.next().unwrap_or("");
+    check_metric_name(name)?;
+    let rest = words.next().unwrap_or("").trim();
+    let family = family(name, families, index);
+    if keyword == "HELP" {
+        if family.help.is_some() {
+            return Err(format!("second HELP line for {}", name));
+        }
+        family.help = Some(unescape(rest, false)?);
+        return Ok(());
+    }
+    if family.kind.is_some() {
+        return Err(format!("second TYPE line for {}", name));
+    }
+    if !family.series.is_empty() {
+        return Err(format!("TYPE line for {} after its samples", name));
+    }
+    family.kind = Some(match rest {
+        "counter" => MetricType::COUNTER,
+        "gauge" => MetricType::GAUGE,
+        "histogram" => MetricType::HISTOGRAM,
+        "summary" => MetricType::SUMMARY,
+        "untyped" => MetricType::UNTYPED,
+        _ => return Err(format!("unknown type {:?} for {}", rest, name)),
+    });
+    Ok(())
+}
+
+/// `name{label="value",...} value [timestamp]`
+fn read_sample(
+    line: &str,
+    families: &mut Vec<Family>,
+    index: &mut HashMap<String, usize>,
+) -> Result<(), String> {
+    let end = line
+        .find(|c: char| c == '{' || c.is_whitespace())
+        .unwrap_or(line.len());
+    let (name, mut rest) = line.split_at(end);
+    check_metric_name(name)?;
+    let mut labels = Vec::new();
+    if let Some(inner) = rest.strip_prefix('{') {
+        rest = read_labels(inner, &mut labels)?;
+    }
+    let mut fields = rest.split_whitespace();
+    let value = fields
+        .next()
+        .ok_or_else(|| format!("no value for {}", name))?;
+    let value: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid value {:?} for {}", value, name))?;
+    let timestamp = match fields.next() {
+        Some(timestamp) => Some(
+            timestamp
+                .parse::<i64>()
+                .map_err(|_| format!("invalid timestamp {:?} for {}", timestamp, name))?,
+        ),
+        None => None,
+    };
+    if fields.next().is_some() {
+        return Err(format!("unexpected text after the sample of {}", name));
+    }
+
+    // A histogram's or summary's sample belongs to the family it was
+    // typed under; anything else undeclared is untyped
+    let (family, suffix) = SUFFIXES
+        .iter()
+        .find_map(|(suffix, kind)| {
+            let base = name.strip_suffix(suffix)?;
+            let family = index.get(base)?;
+            (families[*family].kind == Some(*kind)).then_some((base, *suffix))
+        })
+        .unwrap_or((name, ""));
+    let family = self::family(family, families, index);
+    let kind = family.kind.unwrap_or(MetricType::UNTYPED);
+
+    let point_label = match (kind, suffix) {
+        (MetricType::HISTOGRAM, "_bucket") => Some("le"),
+        (MetricType::SUMMARY, "") => Some("quantile"),
+        _ => None,
+    };
+    let mut point = None;
+    if let Some(point_label) = point_label {
+        let position = labels
+            .iter()
+            .position(|(label, _)| label == point_label)
+            .ok_or_else(|| format!("{} without a {} label", name, point_label))?;
+        let (_, bound) = labels.remove(position);
+        let bound: f64 = bound
+            .parse()
+            .map_err(|_| format!("invalid {} {:?} for {}", point_label, bound, name))?;
+        point = Some(bound);
+    }
+    labels.sort();
+
+    let series = match family.series.iter().position(|s| s.labels == labels) {
+        Some(position) => &mut family.series[position],
+        None => {
+            family.series.push(Series {
+                labels,
+                ..Series::default()
+            });
+            family.series.last_mut().unwrap()
+        }
+    };
+    let duplicate = || format!("duplicate sample for {}", line);
+    match (point, suffix) {
+        (Some(bound), _) => {
+            if series.points.iter().any(|(b, _)| *b == bound) {
+                return Err(duplicate());
+            }
+            series.points.push((bound, value));
+        }
+        (None, "_sum") => set_once(&mut series.sum, value).ok_or_else(duplicate)?,
+        (None, "_count") => set_once(&mut series.count, value).ok_or_else(duplicate)?,
+        (None, _) => set_once(&mut series.value, value).ok_or_else(duplicate)?,
+    }
+    if timestamp.is_some() {
+        series.timestamp = timestamp;
+    }
+    Ok(())
+}
+
+fn set_once(slot: &mut Option<f64>, value: f64) -> Option<()> {
+    if slot.is_some() {
+        return None;
+    }
+    *slot = Some(value);
+    Some(())
+}
+
+/// Fill `labels` from the text after `{`, returning what follows `}`
+fn read_labels<'a>(
+    mut text: &'a str,
+    labels: &mut Vec<(String, String)>,
+) -> Result<&'a str, String> {
+    loop {
+        text = text.trim_start();
+        if let Some(rest) = text.strip_prefix('}') {
+            return Ok(rest);
+        }
+        let (name, rest) = text
+            .split_once('=')
+            .ok_or_else(|| "unterminated label set".to_string())?;
+        let name = name.trim_end();
+        check_label_name(name)?;
+        if labels.iter().any(|(label, _)| label == name) {
+            return Err(format!("label {} given twice", name));
+        }
+        let rest = rest
+            .trim_start()
+            .strip_prefix('"')
+            .ok_or_else(|| format!("label {} has no quoted value", name))?;
+        let (value, rest) =
+            quoted(rest).ok_or_else(|| format!("unterminated value of {}", name))?;
+        labels.push((name.to_string(), unescape(value, true)?));
+        text = rest.trim_start();
+        if let Some(rest) = text.strip_prefix(',') {
+            text = rest;
+        } else if !text.starts_with('}') {
+            return Err(format!("expected , or }} after label {}", name));
+        }
+    }
+}
+
+/// Split at the closing quote, skipping escaped ones
+fn quoted(text: &str) -> Option<(&str, &str)> {
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' if !escaped => return Some((&text[..i], &text[i + 1..])),
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+/// Undo `\\` and `\n`, plus `\"` in label values
+fn unescape(text: &str, label_value: bool) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('n') => unescaped.push('\n'),
+            Some('"') if label_value => unescaped.push('"'),
+            // Help text may have a lone backslash
+            other if !label_value => {
+                unescaped.push('\\');
+                unescaped.extend(other);
+            }
+            _ => return Err(format!("invalid escape in {:?}", text)),
+        }
+    }
+    Ok(unescaped)
+}
+
+fn family<'a>(
+    name: &str,
+    families: &'a mut Vec<Family>,
+    index: &mut HashMap<String, usize>,
+) -> &'a mut Family {
+    let position = *index.entry(name.to_string()).or_insert_with(|| {
+        families.push(Family {
+            name: name.to_string(),
+            help: None,
+            kind: None,
+            series: Vec::new(),
+        });
+        families.len() - 1
+    });
+    &mut families[position]
+}
+
+fn check_metric_name(name: &str) -> Result<(), String> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':');
+    match valid {
+        true => Ok(()),
+        false => Err(format!("invalid metric name {:?}", name)),
+    }
+}
+
+fn check_label_name(name: &str) -> Result<(), String> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    match valid {
+        true => Ok(()),
+        false => Err(format!("invalid label name {:?}", name)),
+    }
+}
+
+/// Turn a family that has been read into its protobuf form, checking what
+/// only the whole family shows
+fn finish(family: Family) -> Result<MetricFamily, String> {
+    let kind = family.kind.unwrap_or(MetricType::UNTYPED);
+    let mut metrics = Vec::with_capacity(family.series.len());
+    for mut series in family.series {
+        let mut metric = Metric::from_label(
+            series
+                .labels
+                .iter()
+                .map(|(name, value)| {
+                    let mut label = LabelPair::default();
+                    label.set_name(name.clone());
+                    label.set_value(value.clone());
+                    label
+                })
+                .collect(),
+        );
+        if let Some(timestamp) = series.timestamp {
+            metric.set_timestamp_ms(timestamp);
+        }
+        series.points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let value = series.value.unwrap_or_default();
+        match kind {
+            MetricType::COUNTER => {
+                if value < 0.0 {
+                    return Err(format!("counter {} is negative", family.name));
+                }
+                let mut counter = Counter::default();
+                counter.set_value(value);
+                metric.set_counter(counter);
+            }
+            MetricType::GAUGE => {
+                let mut gauge = Gauge::default();
+                gauge.set_value(value);
+                metric.set_gauge(gauge);
+            }
+            MetricType::HISTOGRAM => {
+                let counts: Vec<f64> = series.points.iter().map(|(_, count)| *count).collect();
+                if counts.windows(2).any(|pair| pair[1] < pair[0]) {
+                    return Err(format!("buckets of {} aren't cumulative", family.name));
+                }
+                // The +Inf bucket is implied by the count, as the encoder
+                // writes it from there
+                if series
+                    .points
+                    .last()
+                    .is_some_and(|(le, _)| *le == f64::INFINITY)
+                {
+                    let (_, inf) = series.points.pop().unwrap();
+                    if series.count.is_some_and(|count| count != inf) {
+                        return Err(format!(
+                            "+Inf bucket of {} doesn't match its count",
+                            family.name
+                        ));
+                    }
+                    series.count = Some(inf);
+                }
+                let mut histogram = Histogram::default();
+                histogram.set_sample_sum(series.sum.unwrap_or_default());
+                histogram.set_sample_count(series.count.unwrap_or_default() as u64);
+                histogram.set_bucket(
+                    series
+                        .points
+                        .iter()
+                        .map(|(le, count)| {
+                            let mut bucket = Bucket::default();
+                            bucket.set_upper_bound(*le);
+                            bucket.set_cumulative_count(*count as u64);
+                            bucket
+                        })
+                        .collect(),
+                );
+                metric.set_histogram(histogram);
+            }
+            MetricType::SUMMARY => {
+                let mut summary = Summary::default();
+                summary.set_sample_sum(series.sum.unwrap_or_default());
+                summary.set_sample_count(series.count.unwrap_or_default() as u64);
+                summary.set_quantile(
+                    series
+                        .points
+                        .iter()
+                        .map(|(q, value)| {
+                            let mut quantile = Quantile::default();
+                            quantile.set_quantile(*q);
+                            quantile.set_value(*value);
+                            quantile
+                        })
+                        .collect(),
+                );
+                metric.set_summary(summary);
+            }
+            MetricType::UNTYPED => {
+                let mut untyped = Untyped::default();
+                untyped.set_value(value);
+                metric.untyped = Some(untyped).into();
+            }
+        }
+        metrics.push(metric);
+    }
+
+    let mut proto = MetricFamily::default();
+    proto.set_name(family.name);
+    proto.set_help(family.help.unwrap_or_default());
+    proto.set_field_type(kind);
+    proto.set_metric(metrics);
+    Ok(proto)
+}
+
+fn type_name(kind: MetricType) -> &'static str {
+    match kind {
+        MetricType::COUNTER => "counter",
+        MetricType::GAUGE => "gauge",
+        MetricType::HISTOGRAM => "histogram",
+        MetricType::SUMMARY => "summary",
+        MetricType::UNTYPED => "untyped",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample::flatten;
+
+    #[test]
+    fn parses_typed_families() {
+        let families = parse(
+            r#"
+# HELP jobs_total Jobs run, by "result"\n and a \ backslash
+# TYPE jobs_total counter
+jobs_total{result="ok"} 12
+jobs_total{result="failed",  queue="a \"b\"\\c\n",} 3 1700000000000
+# TYPE latency_seconds histogram
+latency_seconds_bucket{le="0.5"} 4
+latency_seconds_bucket{le="+Inf"} 6
+latency_seconds_sum 2.5
+latency_seconds_count 6
+# TYPE rtt summary
+rtt{quantile="0.9"} 0.2
+rtt_sum 1
+rtt_count 5
+temperature -3.5e1
+"#,
+        )
+        .unwrap();
+        let names: Vec<_> = families.iter().map(|f| (f.name(), f.type_())).collect();
+        assert_eq!(
+            names,
+            [
+                ("jobs_total", MetricType::COUNTER),
+                ("latency_seconds", MetricType::HISTOGRAM),
+                ("rtt", MetricType::SUMMARY),
+                ("temperature", MetricType::UNTYPED),
+            ]
+        );
+        assert_eq!(
+            families[0].help(),
+            "Jobs run, by \"result\"\n and a \\ backslash"
+        );
+        let failed = &families[0].get_metric()[1];
+        assert_eq!(failed.timestamp_ms(), 1700000000000);
+        assert_eq!(failed.get_label()[0].value(), "a \"b\"\\c\n");
+        assert_eq!(failed.get_label()[1].name(), "result");
+
+        let samples = flatten(&families[1..3]);
+        let samples: Vec<_> = samples
+            .iter()
+            .map(|s| (s.name.as_str(), s.labels.clone(), s.value))
+            .collect();
+        let le = |bound: &str| vec![("le".to_string(), bound.to_string())];
+        assert_eq!(
+            samples,
+            [
+                ("latency_seconds_bucket", le("0.5"), 4.0),
+                ("latency_seconds_bucket", le("+Inf"), 6.0),
+                ("latency_seconds_sum", vec![], 2.5),
+                ("latency_seconds_count", vec![], 6.0),
+                (
+                    "rtt",
+                    vec![("quantile".to_string(), "0.9".to_string())],
+                    0.2
+                ),
+                ("rtt_sum", vec![], 1.0),
+                ("rtt_count", vec![], 5.0),
+            ]
+        );
+        assert_eq!(families[3].get_metric()[0].untyped.value(), -35.0);
+    }
+
+    #[test]
+    fn reads_back_what_the_encoder_writes() {
+        use crate::metrics::builder::metric;
+        use prometheus::{Encoder, Registry, TextEncoder};
+
+        let registry = Registry::new();
+        metric("parsed_jobs_total", "Jobs \\ run\nso far")
+            .registry(&registry)
+            .counter_vec(&["queue"])
+            .with_label_values(&["a \"b\"\n"])
+            .inc_by(3.0);
+        let histogram = metric("parsed_seconds", "Durations")
+            .registry(&registry)
+            .buckets(vec![0.1, 1.0])
+            .histogram();
+        histogram.observe(0.5);
+        histogram.observe(5.0);
+        metric("parsed_rtt", "Round trips")
+            .registry(&registry)
+            .summary()
+            .observe(0.2);
+
+        let gathered = registry.gather();
+        let mut text = Vec::new();
+        TextEncoder::new().encode(&gathered, &mut text).unwrap();
+        let parsed = parse(&String::from_utf8(text).unwrap()).unwrap();
+        assert_eq!(flatten(&parsed), flatten(&gathered));
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        for (text, error) in [
+            ("1up 1", "line 1: invalid metric name \"1up\""),
+            ("up{0=\"a\"} 1", "invalid label name \"0\""),
+            ("up{a=\"1\",a=\"2\"} 1", "label a given twice"),
+            ("up{a=\"1\" 1", "expected , or } after label a"),
+            ("up{a=\"1} 1", "unterminated value of a"),
+            ("up", "no value for up"),
+            ("up one", "invalid value \"one\" for up"),
+            ("up 1 2 3", "unexpected text"),
+            ("up 1\nup 2", "line 2: duplicate sample for up 2"),
+            (
+                "# TYPE up gauge\n# TYPE up gauge",
+                "second TYPE line for up",
+            ),
+            ("# TYPE up info", "unknown type \"info\""),
+            (
+                "up 1\n# TYPE up gauge",
+                "TYPE line for up after its samples",
+            ),
+            ("# TYPE c counter\nc -1", "counter c is negative"),
+            (
+                "# TYPE h histogram\nh_bucket 1",
+                "h_bucket without a le label",
+            ),
+            (
+                "# TYPE h histogram\nh_bucket{le=\"1\"} 5\nh_bucket{le=\"2\"} 3",
+                "buckets of h aren't cumulative",
+            ),
+            (
+                "# TYPE h histogram\nh_bucket{le=\"+Inf\"} 5\nh_count 4",
+                "+Inf bucket of h doesn't match its count",
+            ),
+        ] {
+            let e = parse(text).unwrap_err();
+            assert!(e.contains(error), "{:?}: {}", text, e);
+        }
+    }
+
+    #[test]
+    fn finds_conflicts_with_served_families() {
+        let served = parse("# TYPE latency histogram\nlatency_count 1\nup{job=\"a\"} 1").unwrap();
+        let families = parse(
+            "latency_count 2\nup{job=\"b\"} 1\n# TYPE up2 gauge\nup2 1\n\
+             # TYPE latency summary\nlatency_sum 1",
+        )
+        .unwrap();
+        assert_eq!(
+            conflicts(&families, &served),
+            [
+                (
+                    "latency_count",
+                    "clashes with the histogram latency".to_string()
+                ),
+                ("latency", "clashes with the histogram latency".to_string()),
+            ]
+        );
+        // The same family merges, but not the same series
+        let families = parse("up{job=\"a\"} 2").unwrap();
+        assert_eq!(
+            conflicts(&families, &served),
+            [("up", "{job=\"a\"} is served already".to_string())]
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! A small blocking HTTP/1.0 client for the few places metrixd makes
+//! requests itself (instance metadata, federation peers, proxy targets,
+//! probes). They run before the tokio runtime exists or on the blocking
+//! pool, and only need plain HTTP, so a request is written by hand over a
+//! `TcpStream`.
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Responses larger than this are cut off and rejected
+const MAX_RESPONSE_BYTES: u64 = 64 * 1024 * 1024;
+
+pub struct Response {
+    pub status: u16,
+    #[cfg_attr(not(feature = "federation"), allow(dead_code))]
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    #[cfg_attr(not(feature = "federation"), allow(dead_code))]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Send a request without a body to `address` (`host:port`). `timeout`
+/// applies to connecting and to each read and write.
+pub fn request(
+    address: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    timeout: Duration,
+) -> Result<Response, String> {
+    send(address, method, path, headers, &[], timeout)
+}
+
+/// Like `request`, with a body. A `Host` header in `headers` replaces the
+/// one derived from `address`, for requests through a proxy.
+pub fn send(
+    address: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    timeout: Duration,
+) -> Result<Response, String> {
+    let mut stream = connect(address, timeout)?;
+    let io_error = |e: std::io::Error| format!("{}: {}", address, e);
+    stream.set_read_timeout(Some(timeout)).map_err(io_error)?;
+    stream.set_write_timeout(Some(timeout)).map_err(io_error)?;
+
+    let mut request = format!("{} {} HTTP/1.0\r\n", method, path);
+    if !headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("Host"))
+    {
+        let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+        request.push_str(&format!("Host: {}\r\n", host));
+    }
+    request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    let mut request = request.into_bytes();
+    request.extend_from_slice(body);
+    stream.write_all(&request).map_err(io_error)?;
+
+    // HTTP/1.0, so the server closes the connection after the body
+    let mut response = Vec::new();
+    stream
+        .take(MAX_RESPONSE_BYTES + 1)
+        .read_to_end(&mut response)
+        .map_err(io_error)?;
+    if response.len() as u64 > MAX_RESPONSE_BYTES {
+        return Err(format!("{}: response larger than 64MiB", address));
+    }
+    parse_response(response).ok_or_else(|| format!("{}: invalid HTTP response", address))
+}
+
+/// Connect to the first address `address` (`host:port`) resolves to that
+/// accepts within `timeout`
+pub fn connect(address: &str, timeout: Duration) -> Result<TcpStream, String> {
+    let addresses = address
+        .to_socket_addrs()
+        .map_err(|e| format!("{}: {}", address, e))?;
+    let mut last_error = format!("{}: no addresses", address);
+    for socket_addr in addresses {
+        match TcpStream::connect_timeout(&socket_addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = format!("{}: {}", address, e),
+        }
+    }
+    Err(last_error)
+}
+
+/// `host[:port][/path]` or `http://host[:port][/path]` as an address and a
+/// path; port 80 unless given. There is no TLS, so `https://` is rejected.
+pub fn parse_url(url: &str) -> Result<(String, String), String> {
+    let rest = match url.split_once("://") {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => rest,
+        Some((scheme, _)) => return Err(format!("{}: unsupported scheme {}", url, scheme)),
+        None => url,
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(format!("{}: missing host", url));
+    }
+    // Not fooled by the colons of a bracketed IPv6 address
+    let has_port = authority
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+    let address = if has_port {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    Ok((address, path.to_string()))
+}
+
+fn parse_response(mut response: Vec<u8>) -> Option<Response> {
+    let head_end = response.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let body = response.split_off(head_end + 4);
+    let head = String::from_utf8_lossy(&response[..head_end]);
+    let mut lines = head.split("\r\n");
+    let status = lines.next()?.split_whitespace().nth(1)?.parse().ok()?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Some(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_status_headers_and_body() {
+        let response = parse_response(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 3\r\n\r\nab\n"
+                .to_vec(),
+        )
+        .unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("content-type"), Some("text/plain"));
+        assert_eq!(response.body, b"ab\n");
+
+        assert!(parse_response(b"garbage".to_vec()).is_none());
+        assert!(parse_response(b"HTTP/1.1 abc\r\n\r\n".to_vec()).is_none());
+    }
+
+    #[test]
+    fn urls_default_to_port_80_and_the_root_path() {
+        let url = |url: &str| parse_url(url).unwrap();
+        assert_eq!(
+            url("example.com"),
+            ("example.com:80".to_string(), "/".to_string())
+        );
+        assert_eq!(
+            url("http://example.com:8080/healthz?full=1"),
+            (
+                "example.com:8080".to_string(),
+                "/healthz?full=1".to_string()
+            )
+        );
+        assert_eq!(
+            url("[::1]/ready"),
+            ("[::1]:80".to_string(), "/ready".to_string())
+        );
+        assert!(parse_url("https://example.com").is_err());
+        assert!(parse_url("http:///path").is_err());
+    }
+}
@@ -0,0 +1,535 @@
+use std::convert::Infallible;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server};
+use protobuf::rt::WireType;
+use protobuf::{CodedInputStream, CodedOutputStream};
+use tokio::sync::watch;
+
+use crate::sample::{flatten, Sample};
+use crate::server::{gather_filtered, metrics_authorized};
+use crate::state::AppState;
+
+// gRPC status codes used by this server
+const STATUS_OK: u32 = 0;
+const STATUS_INVALID_ARGUMENT: u32 = 3;
+const STATUS_RESOURCE_EXHAUSTED: u32 = 8;
+const STATUS_INTERNAL: u32 = 13;
+const STATUS_UNIMPLEMENTED: u32 = 12;
+const STATUS_UNAUTHENTICATED: u32 = 16;
+
+const GET_METRICS: &str = "/metrixd.v1.Metrics/GetMetrics";
+const WATCH_METRICS: &str = "/metrixd.v1.Metrics/WatchMetrics";
+
+/// Requests only carry name prefixes, so anything larger is refused before
+/// it is buffered
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+
+/// Serve the `metrixd.v1.Metrics` service (see proto/metrixd.proto) over
+/// HTTP/2 on an already bound listener. `cycles` is bumped by the collection
+/// loop after every cycle and drives the WatchMetrics stream.
+pub async fn serve(
+    listener: TcpListener,
+    state: Arc<AppState>,
+    cycles: watch::Receiver<u64>,
+) -> hyper::Result<()> {
+    let shutdown = state.shutdown_signal();
+    let make_svc = make_service_fn(move |_conn| {
+        let state = Arc::clone(&state);
+        let cycles = cycles.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle(req, Arc::clone(&state), cycles.clone())
+            }))
+        }
+    });
+
+    if let Ok(addr) = listener.local_addr() {
+        println!("Serving gRPC on {}", addr);
+    }
+
+    Server::from_tcp(listener)?
+        .http2_only(true)
+        .serve(make_svc)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+async fn handle(
+    req: Request<Body>,
+    state: Arc<AppState>,
+    mut cycles: watch::Receiver<u64>,
+) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::POST {
+        return Ok(status_response(
+            STATUS_UNIMPLEMENTED,
+            "only POST is supported",
+        ));
+    }
+
+    let path = req.uri().path().to_string();
+    if path != GET_METRICS && path != WATCH_METRICS {
+        return Ok(status_response(
+            STATUS_UNIMPLEMENTED,
+            &format!("unknown method {}", path),
+        ));
+    }
+
+    // Serves every series, like /metrics
+    if !metrics_authorized(&req, &state) {
+        return Ok(status_response(
+            STATUS_UNAUTHENTICATED,
+            "missing or wrong metrics token",
+        ));
+    }
+
+    let body = match read_request(req.into_body()).await {
+        Ok(body) => body,
+        Err((status, message)) => return Ok(status_response(status, &message)),
+    };
+    let prefixes = match decode_request(&body) {
+        Ok(prefixes) => prefixes,
+        Err(e) => return Ok(status_response(STATUS_INVALID_ARGUMENT, &e)),
+    };
+
+    let (mut sender, response_body) = Body::channel();
+
+    if path == GET_METRICS {
+        let cycle = *cycles.borrow();
+        tokio::spawn(async move {
+            let status = match snapshot(&state, cycle, &prefixes) {
+                Ok(frame) => match sender.send_data(frame).await {
+                    Ok(()) => STATUS_OK,
+                    Err(_) => return,
+                },
+                Err(_) => STATUS_INTERNAL,
+            };
+            let _ = sender.send_trailers(status_trailers(status, "")).await;
+        });
+    } else {
+        tokio::spawn(async move {
+            loop {
+                let cycle = *cycles.borrow_and_update();
+                let frame = match snapshot(&state, cycle, &prefixes) {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        let trailers = status_trailers(STATUS_INTERNAL, &e.to_string());
+                        let _ = sender.send_trailers(trailers).await;
+                        return;
+                    }
+                };
+                // Stop streaming once the client has gone away
+                if sender.send_data(frame).await.is_err() {
+                    return;
+                }
+                if cycles.changed().await.is_err() {
+                    let _ = sender.send_trailers(status_trailers(STATUS_OK, "")).await;
+                    return;
+                }
+            }
+        });
+    }
+
+    Ok(Response::builder()
+        .header("content-type", "application/grpc")
+        .body(response_body)
+        .unwrap())
+}
+
+/// Read the request body, up to MAX_REQUEST_BYTES
+async fn read_request(mut body: Body) -> Result<Vec<u8>, (u32, String)> {
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| (STATUS_INTERNAL, e.to_string()))?;
+        if buffer.len() + chunk.len() > MAX_REQUEST_BYTES {
+            return Err((
+                STATUS_RESOURCE_EXHAUSTED,
+                format!("request larger than {} bytes", MAX_REQUEST_BYTES),
+            ));
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+    Ok(buffer)
+}
+
+/// Trailers-only response, used for errors raised before any message is sent
+fn status_response(status: u32, message: &str) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    let headers = response.headers_mut();
+    headers.insert("content-type", HeaderValue::from_static("application/grpc"));
+    headers.extend(status_trailers(status, message));
+    response
+}
+
+fn status_trailers(status: u32, message: &str) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from(status));
+    if !message.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(message) {
+            trailers.insert("grpc-message", value);
+        }
+    }
+    trailers
+}
+
+/// Parse a length-prefixed GetMetricsRequest/WatchMetricsRequest message and
+/// return its name_prefixes. Both requests share the same layout.
+fn decode_request(body: &[u8]) -> Result<Vec<String>, String> {
+    if body.is_empty() {
+        return Ok(Vec::new());
+    }
+    if body.len() < 5 {
+        return Err("truncated gRPC frame".to_string());
+    }
+    if body[0] != 0 {
+        return Err("compressed requests are not supported".to_string());
+    }
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    let message = body
+        .get(5..5 + len)
+        .ok_or_else(|| "truncated gRPC frame".to_string())?;
+
+    let mut prefixes = Vec::new();
+    let mut input = CodedInputStream::from_bytes(message);
+    while let Some(tag) = input.read_raw_tag_or_eof().map_err(|e| e.to_string())? {
+        let wire_type =
+            WireType::new(tag & 7).ok_or_else(|| format!("invalid wire type in tag {}", tag))?;
+        if tag >> 3 == 1 && wire_type == WireType::LengthDelimited {
+            prefixes.push(input.read_string().map_err(|e| e.to_string())?);
+        } else {
+            input.skip_field(wire_type).map_err(|e| e.to_string())?;
+        }
+    }
+
+    Ok(prefixes)
+}
+
+/// Gather the registry and encode it as a length-prefixed MetricsResponse frame
+fn snapshot(state: &AppState, cycle: u64, prefixes: &[String]) -> protobuf::Result<Bytes> {
+    let mut samples = flatten(&gather_filtered(state));
+    if !prefixes.is_empty() {
+        samples.retain(|s| prefixes.iter().any(|p| s.name.starts_with(p.as_str())));
+    }
+
+    let message = encode_response(cycle, &samples)?;
+    let mut frame = Vec::with_capacity(message.len() + 5);
+    frame.push(0);
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&message);
+    Ok(Bytes::from(frame))
+}
+
+fn encode_response(cycle: u64, samples: &[Sample]) -> protobuf::Result<Vec<u8>> {
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0);
+
+    let mut buffer = Vec::new();
+    {
+        let mut out = CodedOutputStream::vec(&mut buffer);
+        out.write_int64(1, timestamp_ms)?;
+        out.write_uint64(2, cycle)?;
+        for sample in samples {
+            out.write_bytes(3, &encode_sample(sample)?)?;
+        }
+        out.flush()?;
+    }
+    Ok(buffer)
+}
+
+/// A `metrixd.v1.Sample`, also what the streaming exporters send
+pub fn encode_sample(sample: &Sample) -> protobuf::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    {
+        let mut out = CodedOutputStream::vec(&mut buffer);
+        out.write_string(1, &sample.name)?;
+        for (name, value) in &sample.labels {
+            let mut label = Vec::new();
+            {
+                let mut label_out = CodedOutputStream::vec(&mut label);
+                label_out.write_string(1, name)?;
+                label_out.write_string(2, value)?;
+                label_out.flush()?;
+            }
+            out.write_bytes(2, &label)?;
+        }
+        out.write_double(3, sample.value)?;
+        out.write_enum(4, sample.kind as i32)?;
+        out.write_string(5, &sample.help)?;
+        out.flush()?;
+    }
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::proto::MetricType;
+    use protobuf::descriptor::field_descriptor_proto::{Label, Type};
+    use protobuf::descriptor::{
+        DescriptorProto, EnumDescriptorProto, EnumValueDescriptorProto, FieldDescriptorProto,
+        FileDescriptorProto,
+    };
+    use protobuf::reflect::{FileDescriptor, MessageDescriptor, ReflectValueBox};
+    use protobuf::MessageDyn;
+
+    /// Descriptors of the messages and enums in proto/metrixd.proto, read
+    /// from its `message`/`enum` blocks so the tests follow the schema
+    fn schema() -> FileDescriptor {
+        let source = include_str!("../proto/metrixd.proto");
+        let mut file = FileDescriptorProto::new();
+        file.set_name("metrixd.proto".to_string());
+        file.set_package("metrixd.v1".to_string());
+        file.set_syntax("proto3".to_string());
+
+        let mut lines = source
+            .lines()
+            .map(|line| line.split("//").next().unwrap().trim());
+        while let Some(line) = lines.next() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["message", name, "{"] => {
+                    let mut message = DescriptorProto::new();
+                    message.set_name(name.to_string());
+                    for line in lines.by_ref().take_while(|line| *line != "}") {
+                        let line = line.trim_end_matches(';');
+                        let (words, number) = match line.split_once(" = ") {
+                            Some((words, number)) => (words, number.parse().unwrap()),
+                            None => continue,
+                        };
+                        let words: Vec<&str> = words.split_whitespace().collect();
+                        let (repeated, kind, name) = match words.as_slice() {
+                            ["repeated", kind, name] => (true, *kind, *name),
+                            [kind, name] => (false, *kind, *name),
+                            _ => panic!("unexpected field '{}'", line),
+                        };
+                        let mut field = FieldDescriptorProto::new();
+                        field.set_name(name.to_string());
+                        field.set_number(number);
+                        field.set_label(if repeated {
+                            Label::LABEL_REPEATED
+                        } else {
+                            Label::LABEL_OPTIONAL
+                        });
+                        match kind {
+                            "string" => field.set_type(Type::TYPE_STRING),
+                            "double" => field.set_type(Type::TYPE_DOUBLE),
+                            "int64" => field.set_type(Type::TYPE_INT64),
+                            "uint64" => field.set_type(Type::TYPE_UINT64),
+                            "MetricType" => {
+                                field.set_type(Type::TYPE_ENUM);
+                                field.set_type_name(".metrixd.v1.MetricType".to_string());
+                            }
+                            message => {
+                                field.set_type(Type::TYPE_MESSAGE);
+                                field.set_type_name(format!(".metrixd.v1.{}", message));
+                            }
+                        }
+                        message.field.push(field);
+                    }
+                    file.message_type.push(message);
+                }
+                ["enum", name, "{"] => {
+                    let mut enumeration = EnumDescriptorProto::new();
+                    enumeration.set_name(name.to_string());
+                    for line in lines.by_ref().take_while(|line| *line != "}") {
+                        let (name, number) = line.trim_end_matches(';').split_once(" = ").unwrap();
+                        let mut value = EnumValueDescriptorProto::new();
+                        value.set_name(name.to_string());
+                        value.set_number(number.parse().unwrap());
+                        enumeration.value.push(value);
+                    }
+                    file.enum_type.push(enumeration);
+                }
+                _ => {}
+            }
+        }
+        FileDescriptor::new_dynamic(file, &[]).unwrap()
+    }
+
+    fn message(name: &str) -> MessageDescriptor {
+        schema().message_by_package_relative_name(name).unwrap()
+    }
+
+    fn string(message: &dyn MessageDyn, field: &str) -> String {
+        let descriptor = message.descriptor_dyn().field_by_name(field).unwrap();
+        let value = descriptor.get_singular_field_or_default(message);
+        value.to_str().unwrap().to_string()
+    }
+
+    fn frame(message: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0];
+        frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        frame.extend_from_slice(message);
+        frame
+    }
+
+    #[test]
+    fn decodes_requests_encoded_from_the_schema() {
+        for name in ["GetMetricsRequest", "WatchMetricsRequest"] {
+            let descriptor = message(name);
+            let mut request = descriptor.new_instance();
+            let prefixes = descriptor.field_by_name("name_prefixes").unwrap();
+            let mut repeated = prefixes.mut_repeated(&mut *request);
+            repeated.push(ReflectValueBox::String("node_cpu".to_string()));
+            repeated.push(ReflectValueBox::String("node_memory_".to_string()));
+            let body = frame(&request.write_to_bytes_dyn().unwrap());
+            assert_eq!(
+                decode_request(&body),
+                Ok(vec!["node_cpu".to_string(), "node_memory_".to_string()])
+            );
+        }
+        assert_eq!(decode_request(&[]), Ok(Vec::new()));
+        assert_eq!(decode_request(&frame(&[])), Ok(Vec::new()));
+        assert_eq!(
+            decode_request(&[0, 0, 0, 0, 9, 10]),
+            Err("truncated gRPC frame".to_string())
+        );
+        assert_eq!(
+            decode_request(&[1, 0, 0, 0, 0]),
+            Err("compressed requests are not supported".to_string())
+        );
+    }
+
+    #[test]
+    fn encodes_responses_the_schema_decodes() {
+        let samples = [
+            Sample {
+                name: "node_load1".to_string(),
+                labels: Vec::new(),
+                value: 0.25,
+                kind: MetricType::GAUGE,
+                help: "1m load average".to_string(),
+            },
+            Sample {
+                name: "http_requests_total".to_string(),
+                labels: vec![
+                    ("code".to_string(), "200".to_string()),
+                    ("method".to_string(), "GET".to_string()),
+                ],
+                value: 7.0,
+                kind: MetricType::COUNTER,
+                help: String::new(),
+            },
+        ];
+        let encoded = encode_response(42, &samples).unwrap();
+
+        let descriptor = message("MetricsResponse");
+        let response = descriptor.parse_from_bytes(&encoded).unwrap();
+        let field = |name: &str| descriptor.field_by_name(name).unwrap();
+        assert_eq!(
+            field("cycle")
+                .get_singular_field_or_default(&*response)
+                .to_u64(),
+            Some(42)
+        );
+        let timestamp_ms = field("timestamp_ms")
+            .get_singular_field_or_default(&*response)
+            .to_i64()
+            .unwrap();
+        assert!(timestamp_ms > 1_600_000_000_000);
+
+        let decoded = field("samples").get_repeated(&*response);
+        assert_eq!(decoded.len(), samples.len());
+        for (i, expected) in samples.iter().enumerate() {
+            let value = decoded.get(i);
+            let sample = value.to_message().unwrap();
+            let sample_descriptor = sample.descriptor_dyn();
+            let field = |name: &str| sample_descriptor.field_by_name(name).unwrap();
+            assert_eq!(string(&*sample, "name"), expected.name);
+            assert_eq!(string(&*sample, "help"), expected.help);
+            assert_eq!(
+                field("value")
+                    .get_singular_field_or_default(&*sample)
+                    .to_f64(),
+                Some(expected.value)
+            );
+            assert_eq!(
+                field("type")
+                    .get_singular_field_or_default(&*sample)
+                    .to_enum_value(),
+                Some(expected.kind as i32)
+            );
+            let labels = field("labels").get_repeated(&*sample);
+            let labels: Vec<(String, String)> = (0..labels.len())
+                .map(|i| {
+                    let label = labels.get(i).to_message().unwrap();
+                    (string(&*label, "name"), string(&*label, "value"))
+                })
+                .collect();
+            assert_eq!(labels, expected.labels);
+        }
+    }
+
+    #[test]
+    fn metric_types_match_the_schema_enum() {
+        let schema = schema();
+        let enumeration = schema.enum_by_package_relative_name("MetricType").unwrap();
+        for kind in [
+            MetricType::COUNTER,
+            MetricType::GAUGE,
+            MetricType::SUMMARY,
+            MetricType::UNTYPED,
+            MetricType::HISTOGRAM,
+        ] {
+            let value = enumeration.value_by_number(kind as i32).unwrap();
+            assert_eq!(value.name(), format!("{:?}", kind));
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_requests() {
+        let body = Body::from(vec![0; MAX_REQUEST_BYTES]);
+        assert_eq!(read_request(body).await.unwrap().len(), MAX_REQUEST_BYTES);
+
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            // Never finishes, so only the limit can end the read
+            loop {
+                let chunk = Bytes::from(vec![0; 16 * 1024]);
+                if sender.send_data(chunk).await.is_err() {
+                    return;
+                }
+            }
+        });
+        assert_eq!(
+            read_request(body).await,
+            Err((
+                STATUS_RESOURCE_EXHAUSTED,
+                "request larger than 65536 bytes".to_string()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn requires_the_metrics_token() {
+        let config_file =
+            std::env::temp_dir().join(format!("metrixd-grpc-token-{}.toml", std::process::id()));
+        std::fs::write(&config_file, "[web]\nmetrics_token = \"m-secret\"\n").unwrap();
+        let args = crate::cli::Args {
+            config_file: Some(config_file.clone()),
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::with_registry(args, prometheus::Registry::new()).unwrap());
+        std::fs::remove_file(&config_file).unwrap();
+        let (_cycles, receiver) = watch::channel(0);
+
+        let call = |token: &str| {
+            let request = Request::post(GET_METRICS)
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap();
+            handle(request, Arc::clone(&state), receiver.clone())
+        };
+        let response = call("a-secret").await.unwrap();
+        assert_eq!(response.headers()["grpc-status"], "16");
+        let response = call("m-secret").await.unwrap();
+        assert!(!response.headers().contains_key("grpc-status"));
+    }
+}
@@ -0,0 +1,56 @@
+//! The name this agent goes by towards other systems: `instance.id`, or
+//! else the host name, or else the machine ID. It names the host in push
+//! exporters that need one (collectd, Zabbix, the Pushgateway), the mDNS
+//! service instance, and, with `instance.label`, every series.
+
+use std::path::Path;
+
+/// The identity when `instance.id` isn't set, resolved once at startup
+/// while /etc is still readable
+pub fn host_identity(root: &Path) -> String {
+    hostname()
+        .or_else(|| machine_id(root))
+        .unwrap_or_else(|| "metrixd".to_string())
+}
+
+#[cfg(unix)]
+pub fn hostname() -> Option<String> {
+    let mut buffer = [0u8; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) } != 0 {
+        return None;
+    }
+    let len = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+    let name = String::from_utf8_lossy(&buffer[..len]);
+    (!name.is_empty()).then(|| name.into_owned())
+}
+
+#[cfg(not(unix))]
+pub fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME")
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+/// systemd's machine ID, or D-Bus's on systems without systemd
+pub fn machine_id(root: &Path) -> Option<String> {
+    ["etc/machine-id", "var/lib/dbus/machine-id"]
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(root.join(path)).ok())
+        .map(|id| id.trim().to_string())
+        .find(|id| !id.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_machine_id() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/procfs/linux-5.15");
+        assert_eq!(
+            machine_id(&root).as_deref(),
+            Some("4c4c4544004d3510804bc4c04f4e4432")
+        );
+        assert_eq!(machine_id(Path::new("/nonexistent")), None);
+    }
+}
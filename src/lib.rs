@@ -0,0 +1,39 @@
+//! metrixd as a library, for applications that want to serve their own
+//! collectors from the same endpoint as the built-in host metrics. See
+//! `MetrixdBuilder`.
+
+pub mod cli;
+mod cloud;
+pub mod collector;
+pub mod config;
+mod daemon;
+#[cfg(unix)]
+mod dump;
+mod export;
+mod exposition;
+mod fetch;
+mod grpc;
+mod identity;
+#[cfg(unix)]
+mod mdns;
+mod memory_limit;
+pub mod metrics;
+pub mod plugins;
+mod privileges;
+mod probe;
+mod rate_limit;
+mod replay;
+mod sample;
+mod sandbox;
+mod schedule;
+mod script;
+mod server;
+mod sigv4;
+mod state;
+pub mod testing;
+mod utc;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+pub use collector::Collector;
+pub use daemon::MetrixdBuilder;
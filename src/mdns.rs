@@ -0,0 +1,491 @@
+//! Advertises /metrics over multicast DNS as a `_prometheus-http._tcp`
+//! service, so agents on a LAN can be found with `avahi-browse` or
+//! Prometheus' service discovery tools without a list of hosts.
+//!
+//! A minimal responder (RFC 6762/6763): it announces the service twice at
+//! startup, answers queries for the service type, instance and host name
+//! with PTR, SRV, TXT and A records, and says goodbye on shutdown.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::state::AppState;
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+
+const SERVICE: [&str; 3] = ["_prometheus-http", "_tcp", "local"];
+const SERVICES_META: [&str; 4] = ["_services", "_dns-sd", "_udp", "local"];
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on records only we own, so caches replace rather than add to them
+const CACHE_FLUSH: u16 = 0x8000;
+
+// TTLs recommended by RFC 6762 section 10
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+/// Legacy unicast queries must not get longer TTLs than this
+const LEGACY_TTL: u32 = 10;
+
+/// Bind the mDNS port, shared with any other responder on the host, and
+/// join the multicast group. Done at startup, before seccomp forbids bind.
+pub fn bind() -> io::Result<UdpSocket> {
+    let socket = unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        UdpSocket::from_raw_fd(fd)
+    };
+    let fd = socket.as_raw_fd();
+    let one: libc::c_int = 1;
+    for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &one as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut address: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly"
+    ))]
+    {
+        address.sin_len = std::mem::size_of::<libc::sockaddr_in>() as u8;
+    }
+    address.sin_family = libc::AF_INET as libc::sa_family_t;
+    address.sin_port = PORT.to_be();
+    address.sin_addr.s_addr = u32::from(Ipv4Addr::UNSPECIFIED).to_be();
+    let result = unsafe {
+        libc::bind(
+            fd,
+            &address as *const libc::sockaddr_in as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    // Receivers drop mDNS packets with any other TTL
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// What is advertised: one service instance on this host
+pub struct Advertisement {
+    instance: String,
+    host: String,
+    port: u16,
+    addresses: Vec<Ipv4Addr>,
+}
+
+impl Advertisement {
+    /// From the config, the host name and the address /metrics listens on;
+    /// the service instance is named after the agent's `instance` unless
+    /// `mdns.instance` is set. Like `bind`, this runs at startup since it
+    /// needs a socket.
+    pub fn new(config: &Config, instance: &str) -> Self {
+        let host = hostname().unwrap_or_else(|| "metrixd".to_string());
+        let listen = config.web.listen_address;
+        let addresses = match listen.ip() {
+            std::net::IpAddr::V4(ip) if !ip.is_unspecified() => vec![ip],
+            _ => default_route_address().into_iter().collect(),
+        };
+        Advertisement {
+            instance: config
+                .mdns
+                .instance
+                .clone()
+                .unwrap_or_else(|| instance.to_string()),
+            host,
+            port: listen.port(),
+            addresses,
+        }
+    }
+
+    fn instance_name(&self) -> Vec<&str> {
+        let mut name = vec![self.instance.as_str()];
+        name.extend(SERVICE);
+        name
+    }
+
+    fn host_name(&self) -> Vec<&str> {
+        vec![self.host.as_str(), "local"]
+    }
+
+    /// Every record we own; TTL 0 says goodbye
+    fn records(&self, ttl: Option<u32>) -> Vec<Record> {
+        let ttl = |default| ttl.unwrap_or(default);
+        let mut srv = vec![0, 0, 0, 0];
+        srv.extend(self.port.to_be_bytes());
+        encode_name(&mut srv, &self.host_name());
+        let mut records = vec![
+            Record::new(&SERVICE, TYPE_PTR, false, ttl(SERVICE_TTL), {
+                let mut rdata = Vec::new();
+                encode_name(&mut rdata, &self.instance_name());
+                rdata
+            }),
+            Record::new(&self.instance_name(), TYPE_SRV, true, ttl(HOST_TTL), srv),
+            Record::new(&self.instance_name(), TYPE_TXT, true, ttl(SERVICE_TTL), {
+                let txt = b"path=/metrics";
+                let mut rdata = vec![txt.len() as u8];
+                rdata.extend(txt);
+                rdata
+            }),
+            Record::new(&SERVICES_META, TYPE_PTR, false, ttl(SERVICE_TTL), {
+                let mut rdata = Vec::new();
+                encode_name(&mut rdata, &SERVICE);
+                rdata
+            }),
+        ];
+        for address in &self.addresses {
+            records.push(Record::new(
+                &self.host_name(),
+                TYPE_A,
+                true,
+                ttl(HOST_TTL),
+                address.octets().to_vec(),
+            ));
+        }
+        records
+    }
+
+    /// Does any question ask for one of our records?
+    fn answers(&self, questions: &[Question]) -> bool {
+        questions.iter().any(|question| {
+            let asks = |name: &[&str], types: &[u16]| {
+                name_eq(&question.name, name)
+                    && (question.qtype == TYPE_ANY || types.contains(&question.qtype))
+            };
+            asks(&SERVICE, &[TYPE_PTR])
+                || asks(&SERVICES_META, &[TYPE_PTR])
+                || asks(&self.instance_name(), &[TYPE_SRV, TYPE_TXT])
+                || asks(&self.host_name(), &[TYPE_A])
+        })
+    }
+
+    /// The response to a received packet, if it's a query for us. Legacy
+    /// resolvers (not sending from port 5353) get a unicast reply that
+    /// echoes their query ID and questions; everyone else a multicast one.
+    fn reply(&self, packet: &[u8], from: SocketAddr) -> Option<(Vec<u8>, SocketAddr)> {
+        let (id, questions) = parse_query(packet)?;
+        if !self.answers(&questions) {
+            return None;
+        }
+        if from.port() == PORT {
+            let response = message(0, &[], &self.records(None));
+            Some((response, SocketAddr::from((GROUP, PORT))))
+        } else {
+            let mut records = self.records(Some(LEGACY_TTL));
+            for record in &mut records {
+                record.cache_flush = false;
+            }
+            Some((message(id, &questions, &records), from))
+        }
+    }
+}
+
+/// Announce, then answer queries until shutdown is requested
+pub async fn advertise(
+    socket: UdpSocket,
+    advertisement: Advertisement,
+    state: Arc<AppState>,
+) -> io::Result<()> {
+    let socket = tokio::net::UdpSocket::from_std(socket)?;
+    let group = SocketAddr::from((GROUP, PORT));
+    let announcement = message(0, &[], &advertisement.records(None));
+    let send = |packet: Vec<u8>, to: SocketAddr| {
+        let socket = &socket;
+        async move {
+            if let Err(e) = socket.send_to(&packet, to).await {
+                eprintln!("Failed to send mDNS response to {}: {}", to, e);
+            }
+        }
+    };
+
+    println!(
+        "Advertising {}._prometheus-http._tcp.local on port {} via mDNS",
+        advertisement.instance, advertisement.port
+    );
+    send(announcement.clone(), group).await;
+    // Announced twice, a second apart (RFC 6762 section 8.3)
+    let mut announce_again = pin!(tokio::time::sleep(Duration::from_secs(1)));
+    let mut announced = false;
+    let mut shutdown = pin!(state.shutdown_signal());
+    let mut buffer = vec![0u8; 9000];
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => {
+                send(message(0, &[], &advertisement.records(Some(0))), group).await;
+                return Ok(());
+            }
+            _ = &mut announce_again, if !announced => {
+                announced = true;
+                send(announcement.clone(), group).await;
+            }
+            received = socket.recv_from(&mut buffer) => {
+                let (len, from) = received?;
+                if let Some((response, to)) = advertisement.reply(&buffer[..len], from) {
+                    send(response, to).await;
+                }
+            }
+        }
+    }
+}
+
+fn hostname() -> Option<String> {
+    let name = crate::identity::hostname()?;
+    // The first label; ".local" is appended to it
+    let label = name.split('.').next().unwrap_or_default();
+    (!label.is_empty()).then(|| label.to_string())
+}
+
+/// The address outgoing multicast leaves from, for hosts listening on
+/// 0.0.0.0. Connecting a UDP socket sends nothing.
+fn default_route_address() -> Option<Ipv4Addr> {
+    let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    probe.connect((GROUP, PORT)).ok()?;
+    match probe.local_addr().ok()?.ip() {
+        std::net::IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+        _ => None,
+    }
+}
+
+struct Record {
+    name: Vec<u8>,
+    rtype: u16,
+    cache_flush: bool,
+    ttl: u32,
+    rdata: Vec<u8>,
+}
+
+impl Record {
+    fn new(name: &[&str], rtype: u16, cache_flush: bool, ttl: u32, rdata: Vec<u8>) -> Self {
+        let mut encoded = Vec::new();
+        encode_name(&mut encoded, name);
+        Record {
+            name: encoded,
+            rtype,
+            cache_flush,
+            ttl,
+            rdata,
+        }
+    }
+}
+
+struct Question {
+    /// Labels as received
+    name: Vec<String>,
+    qtype: u16,
+}
+
+fn name_eq(received: &[String], name: &[&str]) -> bool {
+    received.len() == name.len()
+        && received
+            .iter()
+            .zip(name)
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+fn encode_name(out: &mut Vec<u8>, labels: &[&str]) {
+    for label in labels {
+        out.push(label.len() as u8);
+        out.extend(label.as_bytes());
+    }
+    out.push(0);
+}
+
+/// A response with the given ID, echoed questions and answers
+fn message(id: u16, questions: &[Question], answers: &[Record]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(512);
+    out.extend(id.to_be_bytes());
+    // Response, authoritative answer
+    out.extend(0x8400u16.to_be_bytes());
+    out.extend((questions.len() as u16).to_be_bytes());
+    out.extend((answers.len() as u16).to_be_bytes());
+    out.extend([0, 0, 0, 0]);
+    for question in questions {
+        let labels: Vec<&str> = question.name.iter().map(String::as_str).collect();
+        encode_name(&mut out, &labels);
+        out.extend(question.qtype.to_be_bytes());
+        out.extend(CLASS_IN.to_be_bytes());
+    }
+    for record in answers {
+        out.extend(&record.name);
+        out.extend(record.rtype.to_be_bytes());
+        let class = if record.cache_flush {
+            CLASS_IN | CACHE_FLUSH
+        } else {
+            CLASS_IN
+        };
+        out.extend(class.to_be_bytes());
+        out.extend(record.ttl.to_be_bytes());
+        out.extend((record.rdata.len() as u16).to_be_bytes());
+        out.extend(&record.rdata);
+    }
+    out
+}
+
+/// The ID and questions of a query; None for responses and garbage
+fn parse_query(packet: &[u8]) -> Option<(u16, Vec<Question>)> {
+    let word = |at: usize| -> Option<u16> {
+        Some(u16::from_be_bytes(packet.get(at..at + 2)?.try_into().ok()?))
+    };
+    let id = word(0)?;
+    if word(2)? & 0x8000 != 0 {
+        return None;
+    }
+    let count = word(4)?;
+    let mut at = 12;
+    let mut questions = Vec::with_capacity(count.into());
+    for _ in 0..count {
+        let (name, next) = parse_name(packet, at)?;
+        // The top bit of the class asks for a unicast reply; multicast is
+        // always allowed instead
+        questions.push(Question {
+            name,
+            qtype: word(next)?,
+        });
+        word(next + 2)?;
+        at = next + 4;
+    }
+    Some((id, questions))
+}
+
+/// A possibly compressed name at `at`, and where the data after it starts
+fn parse_name(packet: &[u8], mut at: usize) -> Option<(Vec<String>, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds pointer loops
+    for _ in 0..128 {
+        let len = *packet.get(at)? as usize;
+        match len {
+            0 => return Some((labels, end.unwrap_or(at + 1))),
+            len if len & 0xc0 == 0xc0 => {
+                let pointer = (len & 0x3f) << 8 | *packet.get(at + 1)? as usize;
+                end.get_or_insert(at + 2);
+                at = pointer;
+            }
+            len if len < 64 => {
+                let label = packet.get(at + 1..at + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                at += 1 + len;
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn advertisement() -> Advertisement {
+        Advertisement {
+            instance: "rack-3".to_string(),
+            host: "node7".to_string(),
+            port: 9100,
+            addresses: vec![Ipv4Addr::new(192, 168, 1, 7)],
+        }
+    }
+
+    fn query(id: u16, name: &[&str], qtype: u16) -> Vec<u8> {
+        let mut packet = Vec::new();
+        packet.extend(id.to_be_bytes());
+        packet.extend([0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        encode_name(&mut packet, name);
+        packet.extend(qtype.to_be_bytes());
+        packet.extend(CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn answers_service_queries_by_multicast() {
+        let peer = SocketAddr::from(([192, 168, 1, 20], PORT));
+        let (response, to) = advertisement()
+            .reply(&query(0, &SERVICE, TYPE_PTR), peer)
+            .unwrap();
+        assert_eq!(to, SocketAddr::from((GROUP, PORT)));
+        // No questions, PTR, SRV, TXT, meta PTR and A
+        assert_eq!(&response[4..8], &[0, 0, 0, 5]);
+        let text = String::from_utf8_lossy(&response);
+        assert!(text.contains("rack-3"));
+        assert!(text.contains("path=/metrics"));
+        assert!(response.windows(4).any(|w| w == [192, 168, 1, 7]));
+    }
+
+    #[test]
+    fn answers_legacy_queries_by_unicast() {
+        let peer = SocketAddr::from(([192, 168, 1, 20], 40000));
+        let name = ["rack-3", "_PROMETHEUS-HTTP", "_tcp", "local"];
+        let (response, to) = advertisement()
+            .reply(&query(0x1234, &name, TYPE_SRV), peer)
+            .unwrap();
+        assert_eq!(to, peer);
+        assert_eq!(&response[0..2], &[0x12, 0x34]);
+        assert_eq!(&response[4..6], &[0, 1]);
+    }
+
+    #[test]
+    fn ignores_other_names_and_responses() {
+        let peer = SocketAddr::from(([192, 168, 1, 20], PORT));
+        let advertisement = advertisement();
+        let other = query(0, &["_http", "_tcp", "local"], TYPE_PTR);
+        assert!(advertisement.reply(&other, peer).is_none());
+        assert!(advertisement
+            .reply(&query(0, &["node7", "local"], TYPE_TXT), peer)
+            .is_none());
+        let mut response = query(0, &SERVICE, TYPE_PTR);
+        response[2] = 0x84;
+        assert!(advertisement.reply(&response, peer).is_none());
+        assert!(advertisement.reply(&[0, 1, 0], peer).is_none());
+    }
+
+    #[test]
+    fn follows_name_compression() {
+        // Second question is "_prometheus-http" plus a pointer to "_tcp.local"
+        let mut packet = query(0, &["_http", "_tcp", "local"], TYPE_PTR);
+        packet[5] = 2;
+        packet.push(16);
+        packet.extend(b"_prometheus-http");
+        packet.extend([0xc0, 18]);
+        packet.extend(TYPE_PTR.to_be_bytes());
+        packet.extend(CLASS_IN.to_be_bytes());
+
+        let (_, questions) = parse_query(&packet).unwrap();
+        assert!(name_eq(&questions[1].name, &SERVICE));
+        // A pointer to itself doesn't loop forever
+        assert!(parse_name(&[0xc0, 0], 0).is_none());
+    }
+}
@@ -0,0 +1,80 @@
+//! `limits.max_rss_mib`: a ceiling for the agent's own resident memory.
+//! Once it is within a tenth of the ceiling, the collector that exported
+//! the most series is shed, i.e. disabled, rather than waiting for the OOM
+//! killer. Freed memory rarely goes back to the kernel, so another one is
+//! only shed if memory keeps growing, by `REGROWTH` of the ceiling past
+//! where it was at the last shed.
+
+use std::sync::Mutex;
+
+/// Shedding starts at this share of the ceiling
+const SHED_AT: f64 = 0.9;
+
+/// Growth after a shed, as a share of the ceiling, that sheds the next
+/// collector
+const REGROWTH: f64 = 0.02;
+
+#[derive(Default)]
+pub struct MemoryLimit {
+    /// Resident memory when the last collector was shed
+    last_shed: Mutex<Option<u64>>,
+}
+
+impl MemoryLimit {
+    /// The collector to shed, if any: the candidate with the most series
+    /// once `resident` is close to `ceiling`, both in bytes
+    pub fn check(
+        &self,
+        resident: u64,
+        ceiling: u64,
+        candidates: &[(&'static str, i64)],
+    ) -> Option<&'static str> {
+        let (resident_f, ceiling) = (resident as f64, ceiling as f64);
+        if resident_f < ceiling * SHED_AT {
+            return None;
+        }
+        let mut last_shed = self.last_shed.lock().unwrap();
+        if last_shed.is_some_and(|last| resident_f < last as f64 + ceiling * REGROWTH) {
+            return None;
+        }
+        let (name, _) = candidates.iter().max_by_key(|(_, series)| *series)?;
+        *last_shed = Some(resident);
+        Some(name)
+    }
+}
+
+/// The agent's resident set size, from `/proc/self/statm`
+#[cfg(target_os = "linux")]
+pub fn resident_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * u64::try_from(page_size).ok()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn resident_bytes() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sheds_the_largest_collector_as_memory_grows() {
+        let limit = MemoryLimit::default();
+        let candidates = [("cpu", 20), ("processes", 400), ("memory", 60)];
+        let mib = 1024 * 1024;
+        assert_eq!(limit.check(80 * mib, 100 * mib, &candidates), None);
+        assert_eq!(
+            limit.check(95 * mib, 100 * mib, &candidates),
+            Some("processes")
+        );
+        // Not again until memory grows by another 2MiB
+        let rest = [("cpu", 20), ("memory", 60)];
+        assert_eq!(limit.check(96 * mib, 100 * mib, &rest), None);
+        assert_eq!(limit.check(97 * mib, 100 * mib, &rest), Some("memory"));
+        assert_eq!(limit.check(99 * mib, 100 * mib, &[]), None);
+    }
+}
@@ -0,0 +1,515 @@
+//! One place to create and register collector metrics, instead of a
+//! `register_*!` call plus `.unwrap()` per metric:
+//!
+//! ```ignore
+//! let usage = metric("cpu_usage_percent", "Current CPU usage percentage").gauge();
+//! let times = metric("cpu_time_seconds_total", "CPU time per mode")
+//!     .namespace("node")
+//!     .const_label("source", "proc")
+//!     .counter_vec(&["mode"]);
+//! ```
+//!
+//! Everything is registered with the default registry unless another one
+//! is given with `.registry()`, or injected for a whole collector with
+//! `with_registry`. Building a metric that is already registered with the
+//! same registry, e.g. for a second instance of a collector, returns the
+//! one built first, so both update the same series. Other registration
+//! failures are programming errors such as invalid names, or a type or
+//! help conflict, so the builder panics with the metric's name rather than
+//! returning a Result. Only `try_gauge`, for names that come from the
+//! config, returns them. `reset` drops the series of the vecs built for a
+//! registry, e.g. once their collector is shed.
+
+// Not every option is needed by the built-in collectors on every platform
+#![allow(dead_code)]
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    Counter, CounterVec, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+};
+
+use super::native_histogram::NativeHistogram;
+use super::summary::{Summary, SummaryVec, DEFAULT_QUANTILES};
+
+thread_local! {
+    /// Where metrics without `.registry()` go, while `with_registry` runs
+    static INJECTED: RefCell<Option<Registry>> = const { RefCell::new(None) };
+}
+
+/// Every metric built so far, per registry and by descriptor id, to hand
+/// out again when it would be registered twice
+static BUILT: Mutex<Vec<Built>> = Mutex::new(Vec::new());
+
+struct Built {
+    marker: Marker,
+    metrics: HashMap<u64, Box<dyn Any + Send>>,
+    /// Clear the series of the vecs among `metrics`
+    resets: HashMap<u64, Box<dyn Fn() + Send>>,
+}
+
+/// Registered with every registry metrics are built for, to tell them
+/// apart: prometheus gives registries no identity. Exports nothing.
+#[derive(Clone)]
+struct Marker(Desc);
+
+impl Collector for Marker {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.0]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        Vec::new()
+    }
+}
+
+/// The metrics built for `registry` so far
+fn built_for<'a>(built: &'a mut Vec<Built>, registry: &Registry) -> &'a mut Built {
+    // Only the registry a marker was registered with refuses it again
+    let index =
+        built.iter().position(
+            |entry| match registry.register(Box::new(entry.marker.clone())) {
+                Err(prometheus::Error::AlreadyReg) => true,
+                Ok(()) => {
+                    let _ = registry.unregister(Box::new(entry.marker.clone()));
+                    false
+                }
+                Err(_) => false,
+            },
+        );
+    let index = index.unwrap_or_else(|| {
+        let desc = Desc::new(
+            format!("metrixd_builder_registry_{}", built.len()),
+            "Marks a registry metrics were built for".to_string(),
+            Vec::new(),
+            HashMap::new(),
+        )
+        .expect("invalid registry marker");
+        let marker = Marker(desc);
+        registry
+            .register(Box::new(marker.clone()))
+            .expect("failed to register the registry marker");
+        built.push(Built {
+            marker,
+            metrics: HashMap::new(),
+            resets: HashMap::new(),
+        });
+        built.len() - 1
+    });
+    &mut built[index]
+}
+
+/// Run `build` with `registry` in place of the default one for the metrics
+/// it builds without `.registry()`, e.g. to construct a collector whose
+/// metrics are served from an embedding application's own registry
+pub fn with_registry<R>(registry: &Registry, build: impl FnOnce() -> R) -> R {
+    /// Puts the previous registry back, also if `build` panics
+    struct Restore(Option<Registry>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            INJECTED.with(|injected| *injected.borrow_mut() = self.0.take());
+        }
+    }
+
+    let _restore = Restore(INJECTED.with(|injected| injected.replace(Some(registry.clone()))));
+    build()
+}
+
+/// Register a collector that isn't built with `metric`, such as one whose
+/// families are only known at runtime. One already registered with the
+/// same descriptors, e.g. by a previous instance, is replaced: its state
+/// can't be shared the way built metrics are, so the newest instance is
+/// served.
+pub fn register<C: Collector + Clone + 'static>(
+    registry: &Registry,
+    collector: &C,
+) -> prometheus::Result<()> {
+    match registry.register(Box::new(collector.clone())) {
+        Err(prometheus::Error::AlreadyReg) => {
+            registry.unregister(Box::new(collector.clone()))?;
+            registry.register(Box::new(collector.clone()))
+        }
+        result => result,
+    }
+}
+
+/// Drop every series of the vecs among `metrics` that were built for
+/// `registry`, so their label values no longer take memory. Other metrics
+/// are left as they are.
+pub fn reset(registry: &Registry, metrics: &[&dyn Collector]) {
+    let mut built = BUILT.lock().unwrap_or_else(PoisonError::into_inner);
+    let built = built_for(&mut built, registry);
+    for desc in metrics.iter().flat_map(|metric| metric.desc()) {
+        if let Some(reset) = built.resets.get(&desc.id) {
+            reset();
+        }
+    }
+}
+
+/// Start building a metric with the given name and help text
+pub fn metric(name: &str, help: &str) -> MetricBuilder {
+    MetricBuilder {
+        name: name.to_string(),
+        help: help.to_string(),
+        namespace: String::new(),
+        subsystem: String::new(),
+        const_labels: HashMap::new(),
+        buckets: None,
+        quantiles: None,
+        registry: None,
+    }
+}
+
+pub struct MetricBuilder {
+    name: String,
+    help: String,
+    namespace: String,
+    subsystem: String,
+    const_labels: HashMap<String, String>,
+    buckets: Option<Vec<f64>>,
+    quantiles: Option<Vec<f64>>,
+    registry: Option<Registry>,
+}
+
+impl MetricBuilder {
+    /// Prefix the name with `<namespace>_`
+    pub fn namespace(mut self, namespace: &str) -> Self {
+        self.namespace = namespace.to_string();
+        self
+    }
+
+    /// Prefix the name with `<subsystem>_`, after the namespace
+    pub fn subsystem(mut self, subsystem: &str) -> Self {
+        self.subsystem = subsystem.to_string();
+        self
+    }
+
+    /// A label with the same value on every series of this metric
+    pub fn const_label(mut self, name: &str, value: &str) -> Self {
+        self.const_labels
+            .insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Histogram bucket upper bounds; the prometheus defaults otherwise
+    pub fn buckets(mut self, buckets: Vec<f64>) -> Self {
+        self.buckets = Some(buckets);
+        self
+    }
+
+    /// Summary quantiles; `DEFAULT_QUANTILES` otherwise
+    pub fn quantiles(mut self, quantiles: &[f64]) -> Self {
+        self.quantiles = Some(quantiles.to_vec());
+        self
+    }
+
+    /// Register with this registry instead of the default one
+    pub fn registry(mut self, registry: &Registry) -> Self {
+        self.registry = Some(registry.clone());
+        self
+    }
+
+    fn opts(&self) -> Opts {
+        Opts::new(self.name.clone(), self.help.clone())
+            .namespace(self.namespace.clone())
+            .subsystem(self.subsystem.clone())
+            .const_labels(self.const_labels.clone())
+    }
+
+    fn histogram_opts(&self) -> HistogramOpts {
+        let opts = HistogramOpts::from(self.opts());
+        match &self.buckets {
+            Some(buckets) => opts.buckets(buckets.clone()),
+            None => opts,
+        }
+    }
+
+    fn register<T: Collector + Clone + 'static>(&self, metric: prometheus::Result<T>) -> T {
+        self.register_resettable(metric, None)
+    }
+
+    /// Register a vec, which `reset` clears with `reset`
+    fn register_vec<T: Collector + Clone + 'static>(
+        &self,
+        metric: prometheus::Result<T>,
+        reset: fn(&T),
+    ) -> T {
+        self.register_resettable(metric, Some(reset))
+    }
+
+    fn register_resettable<T: Collector + Clone + 'static>(
+        &self,
+        metric: prometheus::Result<T>,
+        reset: Option<fn(&T)>,
+    ) -> T {
+        let metric = metric.unwrap_or_else(|e| panic!("invalid metric {}: {}", self.name, e));
+        self.try_register_resettable(Ok(metric), reset)
+            .unwrap_or_else(|e| panic!("failed to register {}: {}", self.name, e))
+    }
+
+    /// Register `metric`, or hand back the one built before with the same
+    /// name, type and help. A metric of another type or help with that
+    /// name is someone else's, so that's an error.
+    fn try_register_resettable<T: Collector + Clone + 'static>(
+        &self,
+        metric: prometheus::Result<T>,
+        reset: Option<fn(&T)>,
+    ) -> prometheus::Result<T> {
+        let metric = metric?;
+        let registry = self
+            .registry
+            .clone()
+            .or_else(|| INJECTED.with(|injected| injected.borrow().clone()))
+            .unwrap_or_else(|| prometheus::default_registry().clone());
+        let desc = metric.desc()[0].clone();
+        // Panics below leave the cache consistent, so a poisoned lock is fine
+        let mut built = BUILT.lock().unwrap_or_else(PoisonError::into_inner);
+        let built = built_for(&mut built, &registry);
+        match registry.register(Box::new(metric.clone())) {
+            Ok(()) => {
+                built.metrics.insert(desc.id, Box::new(metric.clone()));
+                if let Some(reset) = reset {
+                    let vec = metric.clone();
+                    built.resets.insert(desc.id, Box::new(move || reset(&vec)));
+                }
+                Ok(metric)
+            }
+            Err(prometheus::Error::AlreadyReg) => match built
+                .metrics
+                .get(&desc.id)
+                .and_then(|existing| existing.downcast_ref::<T>())
+            {
+                Some(existing) if existing.desc()[0].help == desc.help => Ok(existing.clone()),
+                Some(_) => Err(prometheus::Error::Msg(
+                    "registered with another help text".to_string(),
+                )),
+                None => Err(prometheus::Error::Msg(
+                    "registered as another type".to_string(),
+                )),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn counter(self) -> Counter {
+        self.register(Counter::with_opts(self.opts()))
+    }
+
+    pub fn counter_vec(self, labels: &[&str]) -> CounterVec {
+        self.register_vec(CounterVec::new(self.opts(), labels), CounterVec::reset)
+    }
+
+    pub fn int_counter(self) -> IntCounter {
+        self.register(IntCounter::with_opts(self.opts()))
+    }
+
+    pub fn int_counter_vec(self, labels: &[&str]) -> IntCounterVec {
+        self.register_vec(
+            IntCounterVec::new(self.opts(), labels),
+            IntCounterVec::reset,
+        )
+    }
+
+    pub fn gauge(self) -> Gauge {
+        self.register(Gauge::with_opts(self.opts()))
+    }
+
+    /// Like `gauge`, but a name that is taken is an error instead of a
+    /// panic, for metrics named by the user
+    pub fn try_gauge(self) -> prometheus::Result<Gauge> {
+        self.try_register_resettable(Gauge::with_opts(self.opts()), None)
+    }
+
+    pub fn gauge_vec(self, labels: &[&str]) -> GaugeVec {
+        self.register_vec(GaugeVec::new(self.opts(), labels), GaugeVec::reset)
+    }
+
+    pub fn int_gauge(self) -> IntGauge {
+        self.register(IntGauge::with_opts(self.opts()))
+    }
+
+    pub fn int_gauge_vec(self, labels: &[&str]) -> IntGaugeVec {
+        self.register_vec(IntGaugeVec::new(self.opts(), labels), IntGaugeVec::reset)
+    }
+
+    pub fn histogram(self) -> Histogram {
+        self.register(Histogram::with_opts(self.histogram_opts()))
+    }
+
+    pub fn histogram_vec(self, labels: &[&str]) -> HistogramVec {
+        self.register_vec(
+            HistogramVec::new(self.histogram_opts(), labels),
+            HistogramVec::reset,
+        )
+    }
+
+    /// A histogram that also keeps a native histogram if `native` is set
+    pub fn native_histogram(self, native: bool) -> NativeHistogram {
+        self.register(NativeHistogram::new(self.histogram_opts(), native))
+    }
+
+    pub fn summary(self) -> Summary {
+        let quantiles = self.summary_quantiles();
+        self.register(Summary::new(self.opts(), quantiles))
+    }
+
+    pub fn summary_vec(self, labels: &[&str]) -> SummaryVec {
+        let quantiles = self.summary_quantiles();
+        self.register_vec(
+            SummaryVec::new(self.opts(), labels, quantiles),
+            SummaryVec::reset,
+        )
+    }
+
+    fn summary_quantiles(&self) -> Vec<f64> {
+        self.quantiles
+            .clone()
+            .unwrap_or_else(|| DEFAULT_QUANTILES.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn building_a_registered_metric_again_reuses_it() {
+        let registry = Registry::new();
+        let first = metric("builder_reused", "Reused")
+            .registry(&registry)
+            .gauge();
+        let second = metric("builder_reused", "Reused")
+            .registry(&registry)
+            .gauge();
+        second.set(4.0);
+        assert_eq!(first.get(), 4.0);
+        assert_eq!(registry.gather().len(), 1);
+    }
+
+    #[test]
+    fn try_gauge_wont_take_another_metrics_name() {
+        let registry = Registry::new();
+        let owner = metric("builder_taken", "Owned").registry(&registry).gauge();
+        owner.set(1.0);
+        let e = metric("builder_taken", "Taken")
+            .registry(&registry)
+            .try_gauge()
+            .unwrap_err();
+        assert!(e.to_string().ends_with("registered with another help text"));
+        metric("builder_taken_counter", "Owned")
+            .registry(&registry)
+            .counter();
+        let e = metric("builder_taken_counter", "Owned")
+            .registry(&registry)
+            .try_gauge()
+            .unwrap_err();
+        assert!(e.to_string().ends_with("registered as another type"));
+        let same = metric("builder_taken", "Owned")
+            .registry(&registry)
+            .try_gauge()
+            .unwrap();
+        assert_eq!(same.get(), 1.0);
+    }
+
+    #[test]
+    fn injects_a_registry() {
+        let registry = Registry::new();
+        let gauge = with_registry(&registry, || metric("builder_injected", "Injected").gauge());
+        gauge.set(1.0);
+        assert_eq!(registry.gather()[0].name(), "builder_injected");
+        assert!(prometheus::gather()
+            .iter()
+            .all(|family| family.name() != "builder_injected"));
+    }
+
+    #[test]
+    fn reuses_metrics_per_registry() {
+        let (a, b) = (Registry::new(), Registry::new());
+        let build = |registry: &Registry| {
+            metric("builder_per_registry", "Per registry")
+                .registry(registry)
+                .gauge()
+        };
+        let first_a = build(&a);
+        let first_b = build(&b);
+        let second_a = build(&a);
+        second_a.set(1.0);
+        first_b.set(2.0);
+        assert_eq!(first_a.get(), 1.0);
+        assert_eq!(build(&b).get(), 2.0);
+
+        let value = |registry: &Registry| registry.gather()[0].get_metric()[0].get_gauge().value();
+        assert_eq!((value(&a), value(&b)), (1.0, 2.0));
+        // The markers telling them apart export nothing
+        assert_eq!((a.gather().len(), b.gather().len()), (1, 1));
+    }
+
+    #[test]
+    fn resets_the_vecs_built_for_a_registry() {
+        let (a, b) = (Registry::new(), Registry::new());
+        let build = |registry: &Registry| {
+            let vec = metric("builder_reset", "Reset")
+                .registry(registry)
+                .gauge_vec(&["device"]);
+            vec.with_label_values(&["sda"]).set(1.0);
+            vec
+        };
+        let (vec_a, vec_b) = (build(&a), build(&b));
+        let total = metric("builder_reset_total", "Not a vec")
+            .registry(&a)
+            .counter();
+        total.inc();
+
+        reset(&a, &[&vec_a, &total]);
+        // A vec without series isn't gathered at all
+        let names: Vec<String> = a.gather().iter().map(|f| f.name().to_string()).collect();
+        assert_eq!(names, ["builder_reset_total"]);
+        assert_eq!(total.get(), 1.0);
+        assert_eq!(vec_b.with_label_values(&["sda"]).get(), 1.0);
+        assert_eq!(b.gather()[0].get_metric().len(), 1);
+    }
+
+    #[test]
+    fn restores_the_registry_after_a_panic() {
+        let registry = Registry::new();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            with_registry(&registry, || panic!("collector failed to build"))
+        }));
+        assert!(result.is_err());
+        INJECTED.with(|injected| assert!(injected.borrow().is_none()));
+    }
+
+    #[test]
+    fn registering_again_replaces_the_collector() {
+        let registry = Registry::new();
+        let first = IntGauge::new("builder_replaced", "Replaced").unwrap();
+        let second = IntGauge::new("builder_replaced", "Replaced").unwrap();
+        register(&registry, &first).unwrap();
+        register(&registry, &second).unwrap();
+        first.set(1);
+        second.set(2);
+        assert_eq!(
+            registry.gather()[0].get_metric()[0].get_gauge().value(),
+            2.0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "failed to register builder_conflict")]
+    fn panics_on_a_type_conflict() {
+        let registry = Registry::new();
+        metric("builder_conflict", "Conflict")
+            .registry(&registry)
+            .gauge();
+        metric("builder_conflict", "Conflict")
+            .registry(&registry)
+            .counter();
+    }
+}
@@ -1,8 +1,12 @@
+use super::builder::metric;
+use super::demo::Simulation;
+use super::platform::{self, CpuTimes, Virtualization};
+use super::snapshot::{Refresh, SystemSnapshot};
 use crate::collector::Collector;
-use prometheus::{register_counter, register_gauge, register_histogram, Counter, Gauge, Histogram};
-use rand::random;
-use std::sync::Mutex;
-use sysinfo::System;
+use crate::config::Config;
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::{Counter, CounterVec, Gauge, GaugeVec, Histogram};
+use std::sync::{Arc, Mutex};
 
 pub struct CpuCollector {
     // Gauge for current CPU usage
@@ -14,49 +18,84 @@ pub struct CpuCollector {
     cpu_time_user_seconds_total: Counter,
     cpu_time_system_seconds_total: Counter,
     cpu_time_idle_seconds_total: Counter,
+    cpu_time_steal_seconds_total: Counter,
+
+    // Hypervisor and container runtime, detected once at startup
+    virtualization_info: GaugeVec,
+    virtualization: Option<Virtualization>,
 
     // Histogram for CPU load distribution
     cpu_load_histogram: Histogram,
 
-    system: Mutex<System>,
+    // Cycles with usage above each of thresholds.cpu_percent
+    cpu_usage_breaches_total: CounterVec,
+    cpu_thresholds: Vec<f64>,
+
+    snapshot: Arc<SystemSnapshot>,
+    // Previous native reading, so the counters advance by the difference
+    last_cpu_times: Mutex<CpuTimes>,
+    simulation: Option<Simulation>,
 }
 
 impl CpuCollector {
-    pub fn new() -> Self {
+    pub fn new(config: &Config, snapshot: Arc<SystemSnapshot>) -> Self {
         // Gauge metrics for current CPU state
-        let cpu_usage =
-            register_gauge!("cpu_usage_percent", "Current CPU usage percentage").unwrap();
+        let cpu_usage = metric("cpu_usage_percent", "Current CPU usage percentage").gauge();
 
-        let cpu_cores = register_gauge!("cpu_cores_total", "Total number of CPU cores").unwrap();
+        let cpu_cores = metric("cpu_cores_total", "Total number of CPU cores").gauge();
 
-        let cpu_frequency_mhz =
-            register_gauge!("cpu_frequency_mhz", "Current CPU frequency in MHz").unwrap();
+        let cpu_frequency_mhz = metric("cpu_frequency_mhz", "Current CPU frequency in MHz").gauge();
 
         // Counter metrics for CPU time (cumulative)
-        let cpu_time_user_seconds_total = register_counter!(
+        let cpu_time_user_seconds_total = metric(
             "cpu_time_user_seconds_total",
-            "Total CPU time spent in user mode"
+            "Total CPU time spent in user mode",
         )
-        .unwrap();
+        .counter();
 
-        let cpu_time_system_seconds_total = register_counter!(
+        let cpu_time_system_seconds_total = metric(
             "cpu_time_system_seconds_total",
-            "Total CPU time spent in system mode"
+            "Total CPU time spent in system mode",
         )
-        .unwrap();
+        .counter();
 
         let cpu_time_idle_seconds_total =
-            register_counter!("cpu_time_idle_seconds_total", "Total CPU time spent idle").unwrap();
+            metric("cpu_time_idle_seconds_total", "Total CPU time spent idle").counter();
+
+        // Time the hypervisor gave to other guests; high values mean noisy
+        // neighbors rather than load of our own
+        let cpu_time_steal_seconds_total = metric(
+            "cpu_time_steal_seconds_total",
+            "Total CPU time stolen by the hypervisor for other guests",
+        )
+        .counter();
+
+        let virtualization_info = metric(
+            "virtualization_info",
+            "Hypervisor and container runtime the host runs under, \"none\" for neither",
+        )
+        .namespace("node")
+        .gauge_vec(&["hypervisor", "container"]);
 
         // Histogram for CPU load distribution
-        let cpu_load_histogram = register_histogram!(
+        let cpu_load_histogram = metric(
             "cpu_load_distribution",
             "Distribution of CPU load measurements",
-            vec![0.0, 10.0, 25.0, 50.0, 75.0, 90.0, 95.0, 99.0, 100.0]
         )
-        .unwrap();
+        .buckets(config.histogram_buckets(
+            "cpu_load_distribution",
+            vec![0.0, 10.0, 25.0, 50.0, 75.0, 90.0, 95.0, 99.0, 100.0],
+        ))
+        .histogram();
 
-        let system = Mutex::new(System::new_all());
+        let cpu_usage_breaches_total = metric(
+            "cpu_usage_breaches_total",
+            "Collection cycles in which CPU usage was above threshold percent",
+        )
+        .counter_vec(&["threshold"]);
+        for threshold in &config.thresholds.cpu_percent {
+            cpu_usage_breaches_total.with_label_values(&[&threshold.to_string()]);
+        }
 
         CpuCollector {
             cpu_usage,
@@ -65,47 +104,125 @@ impl CpuCollector {
             cpu_time_user_seconds_total,
             cpu_time_system_seconds_total,
             cpu_time_idle_seconds_total,
+            cpu_time_steal_seconds_total,
+            virtualization_info,
+            virtualization: platform::virtualization(),
             cpu_load_histogram,
-            system,
+            cpu_usage_breaches_total,
+            cpu_thresholds: config.thresholds.cpu_percent.clone(),
+            snapshot,
+            last_cpu_times: Mutex::new(CpuTimes::default()),
+            simulation: config.demo.map(|seed| Simulation::new(seed, "cpu")),
+        }
+    }
+}
+
+impl CpuCollector {
+    fn simulate(&self, simulation: &Simulation) {
+        let cpu_usage = simulation.between(5.0, 95.0);
+        self.cpu_usage.set(cpu_usage);
+        self.cpu_cores.set(8.0);
+        self.cpu_frequency_mhz
+            .set(simulation.between(2000.0, 3500.0).round());
+
+        self.cpu_time_user_seconds_total
+            .inc_by(simulation.between(0.0, 10.0));
+        self.cpu_time_system_seconds_total
+            .inc_by(simulation.between(0.0, 5.0));
+        self.cpu_time_idle_seconds_total
+            .inc_by(simulation.between(0.0, 100.0));
+
+        self.cpu_load_histogram.observe(cpu_usage);
+        self.count_breaches(cpu_usage);
+    }
+
+    fn count_breaches(&self, cpu_usage: f64) {
+        for threshold in &self.cpu_thresholds {
+            if cpu_usage > *threshold {
+                self.cpu_usage_breaches_total
+                    .with_label_values(&[&threshold.to_string()])
+                    .inc();
+            }
         }
     }
 }
 
 impl Collector for CpuCollector {
-    fn register_metrics(&self) -> prometheus::Result<()> {
+    fn name(&self) -> &'static str {
+        "cpu"
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
         Ok(())
     }
 
-    fn collect_metrics(&self) {
-        let mut system = self.system.lock().unwrap();
-        system.refresh_cpu();
+    fn platforms(&self) -> &'static [&'static str] {
+        platform::PLATFORMS
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![
+            &self.cpu_usage,
+            &self.cpu_cores,
+            &self.cpu_frequency_mhz,
+            &self.cpu_time_user_seconds_total,
+            &self.cpu_time_system_seconds_total,
+            &self.cpu_time_idle_seconds_total,
+            &self.cpu_time_steal_seconds_total,
+            &self.virtualization_info,
+            &self.cpu_load_histogram,
+            &self.cpu_usage_breaches_total,
+        ]
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        if let Some(simulation) = &self.simulation {
+            self.simulate(simulation);
+            return Ok(());
+        }
 
-        // Get global CPU usage (average across all cores)
-        let cpu_usage = system.global_cpu_info().cpu_usage();
+        let (cpu_usage, cores, cpu_frequency) = self.snapshot.with(Refresh::Cpu, |system| {
+            (
+                // Global CPU usage (average across all cores)
+                system.global_cpu_info().cpu_usage(),
+                system.cpus().len(),
+                // First CPU's frequency as representative
+                system
+                    .cpus()
+                    .first()
+                    .map(|cpu| cpu.frequency())
+                    .unwrap_or(0),
+            )
+        });
 
         // Update gauge metrics
         self.cpu_usage.set(cpu_usage as f64);
-        self.cpu_cores.set(system.cpus().len() as f64);
-
-        // Get CPU frequency (use first CPU's frequency as representative)
-        let cpu_frequency = system
-            .cpus()
-            .first()
-            .map(|cpu| cpu.frequency())
-            .unwrap_or(0) as f64;
-        self.cpu_frequency_mhz.set(cpu_frequency);
-
-        // Simulate CPU time counters (in real implementation, read from /proc/stat)
-        let simulated_user_time = random::<f64>() * 10.0;
-        let simulated_system_time = random::<f64>() * 5.0;
-        let simulated_idle_time = random::<f64>() * 100.0;
-
-        self.cpu_time_user_seconds_total.inc_by(simulated_user_time);
-        self.cpu_time_system_seconds_total
-            .inc_by(simulated_system_time);
-        self.cpu_time_idle_seconds_total.inc_by(simulated_idle_time);
+        self.cpu_cores.set(cores as f64);
+        self.cpu_frequency_mhz.set(cpu_frequency as f64);
+
+        // CPU time counters come from the kernel where there is a native
+        // backend (/proc/stat, kern.cp_time); sysinfo doesn't report them
+        if let Some(times) = platform::cpu_times() {
+            let mut last = self.last_cpu_times.lock().unwrap();
+            self.cpu_time_user_seconds_total
+                .inc_by((times.user - last.user).max(0.0));
+            self.cpu_time_system_seconds_total
+                .inc_by((times.system - last.system).max(0.0));
+            self.cpu_time_idle_seconds_total
+                .inc_by((times.idle - last.idle).max(0.0));
+            self.cpu_time_steal_seconds_total
+                .inc_by((times.steal - last.steal).max(0.0));
+            *last = times;
+        }
+        if let Some(virtualization) = &self.virtualization {
+            self.virtualization_info
+                .with_label_values(&[&virtualization.hypervisor, &virtualization.container])
+                .set(1.0);
+        }
 
         // Record CPU usage in histogram for distribution analysis
         self.cpu_load_histogram.observe(cpu_usage as f64);
+        self.count_breaches(cpu_usage as f64);
+        Ok(())
     }
 }
@@ -0,0 +1,50 @@
+//! Simulated values for `--demo`, for docs and dashboard development.
+//! Every collector draws from its own generator, seeded from `--demo.seed`
+//! and the collector's name, so a seed always produces the same series no
+//! matter which collectors are enabled.
+
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// A small splitmix64 generator. Unlike `rand`'s generators its output is
+/// fixed, so recorded demo data stays reproducible across releases.
+pub struct Simulation {
+    state: Mutex<u64>,
+    #[cfg_attr(not(feature = "system"), allow(dead_code))]
+    started: Instant,
+}
+
+impl Simulation {
+    pub fn new(seed: u64, collector: &str) -> Self {
+        // FNV-1a, so each collector gets a distinct stream
+        let stream = collector.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+        Simulation {
+            state: Mutex::new(seed ^ stream),
+            started: Instant::now(),
+        }
+    }
+
+    /// Uniformly distributed in [0, 1)
+    pub fn next(&self) -> f64 {
+        let mut state = self.state.lock().unwrap();
+        *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniformly distributed in [low, high)
+    pub fn between(&self, low: f64, high: f64) -> f64 {
+        low + self.next() * (high - low)
+    }
+
+    /// Seconds since the simulation started, for values that grow with time
+    #[cfg_attr(not(feature = "system"), allow(dead_code))]
+    pub fn elapsed(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
+}
@@ -0,0 +1,279 @@
+//! Size, file count and file ages of the trees in `dirsize.paths`, to
+//! watch log growth or spool backlogs. Each tree is walked in a thread of
+//! its own at most every `dirsize.interval`, so a large one never holds up
+//! a cycle; the metrics show the last finished walk. Walks stay on the
+//! root's file system, don't follow symlinks and stop at
+//! `dirsize.max_depth`.
+
+use super::builder::metric;
+use super::demo::Simulation;
+use crate::collector::Collector;
+use crate::config::Config;
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::GaugeVec;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/// What one walk found
+#[derive(Debug, Default, PartialEq)]
+struct Tree {
+    /// Apparent size of the regular files
+    bytes: f64,
+    files: f64,
+    /// Modification times, as Unix timestamps
+    oldest: Option<f64>,
+    newest: Option<f64>,
+    /// Directories below `max_depth` were left out
+    depth_limited: bool,
+}
+
+#[derive(Default)]
+struct State {
+    running: bool,
+    last_started: Option<Instant>,
+    /// The latest walk's outcome, until a cycle picks it up
+    finished: Option<Result<Tree, String>>,
+}
+
+pub struct DirsizeCollector {
+    size_bytes: GaugeVec,
+    files: GaugeVec,
+    oldest_file: GaugeVec,
+    newest_file: GaugeVec,
+    depth_limited: GaugeVec,
+    trees: Vec<(PathBuf, Arc<Mutex<State>>)>,
+    max_depth: usize,
+    interval: Duration,
+    simulation: Option<Simulation>,
+}
+
+impl DirsizeCollector {
+    pub fn new(config: &Config) -> Self {
+        let gauge = |name, help| metric(name, help).gauge_vec(&["path"]);
+        DirsizeCollector {
+            size_bytes: gauge(
+                "directory_size_bytes",
+                "Apparent size of the regular files in the tree",
+            ),
+            files: gauge("directory_files", "Regular files in the tree"),
+            oldest_file: gauge(
+                "directory_oldest_file_timestamp_seconds",
+                "Modification time of the tree's oldest file",
+            ),
+            newest_file: gauge(
+                "directory_newest_file_timestamp_seconds",
+                "Modification time of the tree's newest file",
+            ),
+            depth_limited: gauge(
+                "directory_depth_limited",
+                "Whether directories nested deeper than dirsize.max_depth were left out",
+            ),
+            trees: config
+                .dirsize
+                .paths
+                .iter()
+                .map(|path| (path.clone(), Arc::default()))
+                .collect(),
+            max_depth: config.dirsize.max_depth,
+            interval: config.dirsize.interval,
+            simulation: config.demo.map(|seed| Simulation::new(seed, "dirsize")),
+        }
+    }
+
+    fn set(&self, path: &Path, tree: &Tree) {
+        let path = path.to_string_lossy();
+        let labels = [path.as_ref()];
+        self.size_bytes.with_label_values(&labels).set(tree.bytes);
+        self.files.with_label_values(&labels).set(tree.files);
+        // No files, no ages
+        match (tree.oldest, tree.newest) {
+            (Some(oldest), Some(newest)) => {
+                self.oldest_file.with_label_values(&labels).set(oldest);
+                self.newest_file.with_label_values(&labels).set(newest);
+            }
+            _ => {
+                let _ = self.oldest_file.remove_label_values(&labels);
+                let _ = self.newest_file.remove_label_values(&labels);
+            }
+        }
+        self.depth_limited
+            .with_label_values(&labels)
+            .set(tree.depth_limited as u8 as f64);
+    }
+
+    fn simulate(&self, simulation: &Simulation) {
+        let now = std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        for (path, _) in &self.trees {
+            // Growing by about 1KiB a second like a busy log directory
+            let tree = Tree {
+                bytes: (512.0 * 1024.0 * 1024.0 + simulation.elapsed() * 1024.0).round(),
+                files: simulation.between(40.0, 60.0).round(),
+                oldest: Some(now - 30.0 * 86400.0),
+                newest: Some(now - simulation.between(0.0, 60.0)),
+                depth_limited: false,
+            };
+            self.set(path, &tree);
+        }
+    }
+}
+
+impl Collector for DirsizeCollector {
+    fn name(&self) -> &'static str {
+        "dirsize"
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
+        Ok(())
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![
+            &self.size_bytes,
+            &self.files,
+            &self.oldest_file,
+            &self.newest_file,
+            &self.depth_limited,
+        ]
+    }
+
+    /// Starts the walks that are due and fails with the first walk that
+    /// failed since the last cycle
+    fn collect_metrics(&self) -> Result<(), String> {
+        if let Some(simulation) = &self.simulation {
+            self.simulate(simulation);
+            return Ok(());
+        }
+
+        let mut first_error = None;
+        for (path, state) in &self.trees {
+            let mut locked = state.lock().unwrap();
+            match locked.finished.take() {
+                Some(Ok(tree)) => self.set(path, &tree),
+                Some(Err(e)) => {
+                    first_error.get_or_insert(e);
+                }
+                None => {}
+            }
+            let due = locked
+                .last_started
+                .is_none_or(|started| started.elapsed() >= self.interval);
+            if locked.running || !due {
+                continue;
+            }
+            locked.running = true;
+            locked.last_started = Some(Instant::now());
+            let (path, state, max_depth) = (path.clone(), Arc::clone(state), self.max_depth);
+            let spawned = std::thread::Builder::new()
+                .name("dirsize".to_string())
+                .spawn(move || {
+                    let result =
+                        walk(&path, max_depth).map_err(|e| format!("{}: {}", path.display(), e));
+                    let mut state = state.lock().unwrap();
+                    state.running = false;
+                    state.finished = Some(result);
+                });
+            if let Err(e) = spawned {
+                locked.running = false;
+                first_error.get_or_insert(format!("cannot start a walk: {}", e));
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+}
+
+/// Add up the regular files below `root`. Only an unreadable root is an
+/// error; unreadable entries further down are skipped.
+fn walk(root: &Path, max_depth: usize) -> std::io::Result<Tree> {
+    let root_metadata = std::fs::metadata(root)?;
+    let mut tree = Tree::default();
+    // Paths rather than open handles, so wide trees don't pile up descriptors
+    let mut pending = vec![(root.to_path_buf(), 0)];
+    while let Some((dir, depth)) = pending.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if dir == root => return Err(e),
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                if !same_filesystem(&root_metadata, &metadata) {
+                    continue;
+                }
+                if depth >= max_depth {
+                    tree.depth_limited = true;
+                } else {
+                    pending.push((entry.path(), depth + 1));
+                }
+            } else if metadata.is_file() {
+                tree.bytes += metadata.len() as f64;
+                tree.files += 1.0;
+                let Some(modified) = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                else {
+                    continue;
+                };
+                let modified = modified.as_secs_f64();
+                tree.oldest = Some(tree.oldest.map_or(modified, |oldest| oldest.min(modified)));
+                tree.newest = Some(tree.newest.map_or(modified, |newest| newest.max(modified)));
+            }
+        }
+    }
+    Ok(tree)
+}
+
+#[cfg(unix)]
+fn same_filesystem(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev()
+}
+
+#[cfg(not(unix))]
+fn same_filesystem(_: &std::fs::Metadata, _: &std::fs::Metadata) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    #[test]
+    fn walks_a_tree() {
+        let root = std::env::temp_dir().join(format!("metrixd-dirsize-{}", std::process::id()));
+        let nested = root.join("a/b");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::write(root.join("top.log"), [0; 100]).unwrap();
+        std::fs::write(root.join("a/middle.log"), [0; 20]).unwrap();
+        std::fs::write(nested.join("deep.log"), [0; 3]).unwrap();
+        let old = SystemTime::now() - Duration::from_secs(86400);
+        std::fs::File::options()
+            .write(true)
+            .open(root.join("top.log"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        let tree = walk(&root, 16).unwrap();
+        assert_eq!((tree.bytes, tree.files), (123.0, 3.0));
+        assert!(!tree.depth_limited);
+        let old = old.duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+        assert_eq!(tree.oldest, Some(old));
+        assert!(tree.newest.unwrap() > old);
+
+        let shallow = walk(&root, 1).unwrap();
+        assert_eq!((shallow.bytes, shallow.files), (120.0, 2.0));
+        assert!(shallow.depth_limited);
+
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(walk(&root, 16).is_err());
+    }
+}
@@ -1,8 +1,13 @@
+use super::builder::metric;
+use super::demo::Simulation;
+use super::native_histogram::NativeHistogram;
+use super::platform::{self, DiskStats};
 use crate::collector::Collector;
-use prometheus::{register_counter, register_gauge, register_histogram, Counter, Gauge, Histogram};
-use rand::random;
+use crate::config::Config;
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::{Counter, Gauge};
 use std::sync::Mutex;
-use sysinfo::{Disks, System};
+use sysinfo::Disks;
 
 pub struct DiskCollector {
     // Gauge metrics for current disk space
@@ -19,87 +24,93 @@ pub struct DiskCollector {
     disk_read_bytes_total: Counter,
     disk_write_bytes_total: Counter,
 
-    // Histogram for disk operation latency simulation
-    disk_operation_duration_seconds: Histogram,
+    // Histogram for disk operation latency; there is no real source for
+    // it yet, so only demo mode observes it
+    disk_operation_duration_seconds: NativeHistogram,
 
-    #[allow(dead_code)]
-    system: Mutex<System>,
     disks: Mutex<Disks>,
+    // Previous native I/O totals; the counters count from the first reading
+    last_disk_stats: Mutex<Option<DiskStats>>,
+    simulation: Option<Simulation>,
 }
 
 impl DiskCollector {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
         // Gauge metrics for current disk space
-        let disk_usage_percent = register_gauge!(
+        let disk_usage_percent = metric(
             "disk_usage_percent",
-            "Disk usage percentage for root filesystem"
+            "Disk usage percentage for root filesystem",
         )
-        .unwrap();
+        .gauge();
 
-        let disk_total_bytes = register_gauge!(
+        let disk_total_bytes = metric(
             "disk_total_bytes",
-            "Total disk space in bytes for root filesystem"
+            "Total disk space in bytes for root filesystem",
         )
-        .unwrap();
+        .gauge();
 
-        let disk_used_bytes = register_gauge!(
+        let disk_used_bytes = metric(
             "disk_used_bytes",
-            "Used disk space in bytes for root filesystem"
+            "Used disk space in bytes for root filesystem",
         )
-        .unwrap();
+        .gauge();
 
-        let disk_available_bytes = register_gauge!(
+        let disk_available_bytes = metric(
             "disk_available_bytes",
-            "Available disk space in bytes for root filesystem"
+            "Available disk space in bytes for root filesystem",
         )
-        .unwrap();
+        .gauge();
 
-        let disk_inodes_total = register_gauge!(
+        let disk_inodes_total = metric(
             "disk_inodes_total",
-            "Total number of inodes on root filesystem"
+            "Total number of inodes on root filesystem",
         )
-        .unwrap();
+        .gauge();
 
-        let disk_inodes_used = register_gauge!(
+        let disk_inodes_used = metric(
             "disk_inodes_used",
-            "Number of used inodes on root filesystem"
+            "Number of used inodes on root filesystem",
         )
-        .unwrap();
+        .gauge();
 
         // Counter metrics for disk I/O operations
-        let disk_reads_total = register_counter!(
+        let disk_reads_total = metric(
             "disk_reads_total",
-            "Total number of disk read operations since start"
+            "Total number of disk read operations since start",
         )
-        .unwrap();
+        .counter();
 
-        let disk_writes_total = register_counter!(
+        let disk_writes_total = metric(
             "disk_writes_total",
-            "Total number of disk write operations since start"
+            "Total number of disk write operations since start",
         )
-        .unwrap();
+        .counter();
 
-        let disk_read_bytes_total = register_counter!(
+        let disk_read_bytes_total = metric(
             "disk_read_bytes_total",
-            "Total bytes read from disk since start"
+            "Total bytes read from disk since start",
         )
-        .unwrap();
+        .counter();
 
-        let disk_write_bytes_total = register_counter!(
+        let disk_write_bytes_total = metric(
             "disk_write_bytes_total",
-            "Total bytes written to disk since start"
+            "Total bytes written to disk since start",
         )
-        .unwrap();
+        .counter();
 
-        // Histogram for disk operation duration
-        let disk_operation_duration_seconds = register_histogram!(
+        // Only has values in demo mode; the blockio collector has real ones
+        let disk_operation_duration_seconds = metric(
             "disk_operation_duration_seconds",
             "Disk operation duration distribution in seconds",
-            vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]
         )
-        .unwrap();
+        .buckets(config.histogram_buckets(
+            "disk_operation_duration_seconds",
+            vec![
+                0.0001, 0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+            ],
+        ))
+        .native_histogram(config.metrics.native_histograms);
 
-        let system = Mutex::new(System::new_all());
         let disks = Mutex::new(Disks::new_with_refreshed_list());
 
         DiskCollector {
@@ -114,18 +125,76 @@ impl DiskCollector {
             disk_read_bytes_total,
             disk_write_bytes_total,
             disk_operation_duration_seconds,
-            system,
             disks,
+            last_disk_stats: Mutex::new(None),
+            simulation: config.demo.map(|seed| Simulation::new(seed, "disk")),
         }
     }
 }
 
+impl DiskCollector {
+    fn simulate(&self, simulation: &Simulation) {
+        let total_space = 100_000_000_000.0; // 100GB
+        let usage_percent = simulation.between(40.0, 60.0);
+        let used_space = (total_space * usage_percent / 100.0).round();
+        self.disk_usage_percent.set(usage_percent);
+        self.disk_total_bytes.set(total_space);
+        self.disk_used_bytes.set(used_space);
+        self.disk_available_bytes.set(total_space - used_space);
+
+        let total_inodes = (total_space / 4096.0).floor();
+        self.disk_inodes_total.set(total_inodes);
+        self.disk_inodes_used
+            .set((total_inodes * usage_percent / 100.0).round());
+
+        let reads = simulation.between(0.0, 100.0).round();
+        let writes = simulation.between(0.0, 50.0).round();
+        self.disk_reads_total.inc_by(reads);
+        self.disk_writes_total.inc_by(writes);
+        // 4KB per operation
+        self.disk_read_bytes_total.inc_by(reads * 4096.0);
+        self.disk_write_bytes_total.inc_by(writes * 4096.0);
+
+        self.disk_operation_duration_seconds
+            .observe(simulation.between(0.0, 0.1));
+    }
+}
+
 impl Collector for DiskCollector {
-    fn register_metrics(&self) -> prometheus::Result<()> {
+    fn name(&self) -> &'static str {
+        "disk"
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
         Ok(())
     }
 
-    fn collect_metrics(&self) {
+    fn platforms(&self) -> &'static [&'static str] {
+        platform::PLATFORMS
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![
+            &self.disk_usage_percent,
+            &self.disk_total_bytes,
+            &self.disk_used_bytes,
+            &self.disk_available_bytes,
+            &self.disk_inodes_total,
+            &self.disk_inodes_used,
+            &self.disk_reads_total,
+            &self.disk_writes_total,
+            &self.disk_read_bytes_total,
+            &self.disk_write_bytes_total,
+            &self.disk_operation_duration_seconds,
+        ]
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        if let Some(simulation) = &self.simulation {
+            self.simulate(simulation);
+            return Ok(());
+        }
+
         let mut disks = self.disks.lock().unwrap();
         disks.refresh();
 
@@ -150,38 +219,31 @@ impl Collector for DiskCollector {
             self.disk_used_bytes.set(used_space as f64);
             self.disk_available_bytes.set(available_space as f64);
 
-            // Simulate inode data (real implementation would need filesystem-specific calls)
-            let simulated_total_inodes = total_space / 4096; // Rough estimate
-            let simulated_used_inodes =
-                (simulated_total_inodes as f64 * (usage_percent / 100.0)) as u64;
-
-            self.disk_inodes_total.set(simulated_total_inodes as f64);
-            self.disk_inodes_used.set(simulated_used_inodes as f64);
-        } else {
-            // Fallback values if no disk found
-            self.disk_usage_percent.set(45.0);
-            self.disk_total_bytes.set(100_000_000_000.0); // 100GB
-            self.disk_used_bytes.set(45_000_000_000.0); // 45GB
-            self.disk_available_bytes.set(55_000_000_000.0); // 55GB
-            self.disk_inodes_total.set(25_600_000.0);
-            self.disk_inodes_used.set(11_520_000.0);
+            // sysinfo has no inode counts; filesystems without a fixed
+            // inode table (e.g. btrfs) report zero
+            if let Some((total_inodes, free_inodes)) = platform::inode_stats(disk.mount_point()) {
+                self.disk_inodes_total.set(total_inodes as f64);
+                self.disk_inodes_used
+                    .set(total_inodes.saturating_sub(free_inodes) as f64);
+            }
         }
 
-        // Simulate disk I/O counters (increment by random amounts for demo)
-        // In real implementation, you'd read from /proc/diskstats or similar
-        let simulated_reads = (random::<f64>() * 100.0) as f64;
-        let simulated_writes = (random::<f64>() * 50.0) as f64;
-        let simulated_read_bytes = simulated_reads * 4096.0; // Assume 4KB per read
-        let simulated_write_bytes = simulated_writes * 4096.0; // Assume 4KB per write
-
-        self.disk_reads_total.inc_by(simulated_reads);
-        self.disk_writes_total.inc_by(simulated_writes);
-        self.disk_read_bytes_total.inc_by(simulated_read_bytes);
-        self.disk_write_bytes_total.inc_by(simulated_write_bytes);
-
-        // Simulate disk operation latency for histogram
-        let simulated_latency = random::<f64>() * 0.1; // 0-100ms
-        self.disk_operation_duration_seconds
-            .observe(simulated_latency);
+        // Disk I/O counters come from the kernel where there is a native
+        // backend (/proc/diskstats, devstat, hw.diskstats); sysinfo doesn't
+        // report them
+        if let Some(stats) = platform::disk_stats() {
+            let mut last_stats = self.last_disk_stats.lock().unwrap();
+            let last = last_stats.unwrap_or(stats);
+            self.disk_reads_total
+                .inc_by(platform::delta(last.reads, stats.reads));
+            self.disk_writes_total
+                .inc_by(platform::delta(last.writes, stats.writes));
+            self.disk_read_bytes_total
+                .inc_by(platform::delta(last.read_bytes, stats.read_bytes));
+            self.disk_write_bytes_total
+                .inc_by(platform::delta(last.write_bytes, stats.write_bytes));
+            *last_stats = Some(stats);
+        }
+        Ok(())
     }
 }
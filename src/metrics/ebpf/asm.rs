@@ -0,0 +1,403 @@
+//! A small assembler for the eBPF instruction set, enough for the
+//! collectors' tracepoint programs: 64-bit ALU, loads and stores, atomic
+//! adds, map references, helper calls and jumps to named labels.
+
+use std::collections::HashMap;
+
+use super::bpf::Map;
+
+pub const R0: u8 = 0;
+pub const R1: u8 = 1;
+pub const R2: u8 = 2;
+pub const R3: u8 = 3;
+pub const R4: u8 = 4;
+pub const R6: u8 = 6;
+pub const R7: u8 = 7;
+pub const R8: u8 = 8;
+pub const R9: u8 = 9;
+/// The read-only frame pointer; the stack is the 512 bytes below it
+pub const R10: u8 = 10;
+
+// Access sizes
+pub const W: u8 = 0x00;
+pub const H: u8 = 0x08;
+pub const B: u8 = 0x10;
+pub const DW: u8 = 0x18;
+
+// ALU operations
+pub const ADD: u8 = 0x00;
+pub const SUB: u8 = 0x10;
+pub const DIV: u8 = 0x30;
+pub const OR: u8 = 0x40;
+pub const LSH: u8 = 0x60;
+pub const RSH: u8 = 0x70;
+const MOV: u8 = 0xb0;
+
+// Jump conditions, unsigned unless noted
+pub const JEQ: u8 = 0x10;
+pub const JNE: u8 = 0x50;
+pub const JLT: u8 = 0xa0;
+pub const JLE: u8 = 0xb0;
+/// Signed greater than
+pub const JSGT: u8 = 0x60;
+/// Signed less than
+pub const JSLT: u8 = 0xc0;
+const JA: u8 = 0x00;
+const CALL: u8 = 0x80;
+const EXIT: u8 = 0x90;
+
+// Instruction classes, sources and modes
+const LD: u8 = 0x00;
+const LDX: u8 = 0x01;
+const ST: u8 = 0x02;
+const STX: u8 = 0x03;
+const JMP: u8 = 0x05;
+const ALU64: u8 = 0x07;
+const K: u8 = 0x00;
+const X: u8 = 0x08;
+const IMM: u8 = 0x00;
+const MEM: u8 = 0x60;
+const ATOMIC: u8 = 0xc0;
+
+/// `src` of a 64-bit immediate load that holds a map's file descriptor
+const PSEUDO_MAP_FD: u8 = 1;
+
+// Helper functions, from include/uapi/linux/bpf.h
+pub const MAP_LOOKUP_ELEM: i32 = 1;
+pub const MAP_UPDATE_ELEM: i32 = 2;
+pub const MAP_DELETE_ELEM: i32 = 3;
+pub const KTIME_GET_NS: i32 = 5;
+pub const GET_CURRENT_COMM: i32 = 16;
+pub const GET_CURRENT_CGROUP_ID: i32 = 80;
+
+/// `flags` of map_update_elem
+pub const ANY: i32 = 0;
+pub const NOEXIST: i32 = 1;
+
+/// One instruction as the kernel reads it
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Insn {
+    pub code: u8,
+    /// Destination register in the low nibble, source in the high one
+    pub regs: u8,
+    pub off: i16,
+    pub imm: i32,
+}
+
+impl Insn {
+    fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+        Insn {
+            code,
+            regs: (src << 4) | dst,
+            off,
+            imm,
+        }
+    }
+}
+
+/// A program being assembled. Jumps name their target label, resolved by
+/// `finish`; a label may be placed before or after the jumps to it.
+#[derive(Default)]
+pub struct Asm {
+    insns: Vec<Insn>,
+    labels: HashMap<String, usize>,
+    /// Jumps by instruction index, with the label they go to
+    jumps: Vec<(usize, String)>,
+    /// For labels `fresh_label` makes up
+    next_label: usize,
+}
+
+impl Asm {
+    pub fn new() -> Self {
+        Asm::default()
+    }
+
+    /// `dst = src`
+    pub fn mov(&mut self, dst: u8, src: u8) -> &mut Self {
+        self.push(Insn::new(ALU64 | MOV | X, dst, src, 0, 0))
+    }
+
+    /// `dst = imm`, sign-extended
+    pub fn mov_imm(&mut self, dst: u8, imm: i32) -> &mut Self {
+        self.push(Insn::new(ALU64 | MOV | K, dst, 0, 0, imm))
+    }
+
+    /// `dst = dst <op> src`
+    pub fn alu(&mut self, op: u8, dst: u8, src: u8) -> &mut Self {
+        self.push(Insn::new(ALU64 | op | X, dst, src, 0, 0))
+    }
+
+    /// `dst = dst <op> imm`
+    pub fn alu_imm(&mut self, op: u8, dst: u8, imm: i32) -> &mut Self {
+        self.push(Insn::new(ALU64 | op | K, dst, 0, 0, imm))
+    }
+
+    /// `dst = *(size *)(src + off)`, zero-extended
+    pub fn load(&mut self, size: u8, dst: u8, src: u8, off: i16) -> &mut Self {
+        self.push(Insn::new(LDX | MEM | size, dst, src, off, 0))
+    }
+
+    /// `*(size *)(dst + off) = src`
+    pub fn store(&mut self, size: u8, dst: u8, off: i16, src: u8) -> &mut Self {
+        self.push(Insn::new(STX | MEM | size, dst, src, off, 0))
+    }
+
+    /// `*(size *)(dst + off) = imm`
+    pub fn store_imm(&mut self, size: u8, dst: u8, off: i16, imm: i32) -> &mut Self {
+        self.push(Insn::new(ST | MEM | size, dst, 0, off, imm))
+    }
+
+    /// `*(u64 *)(dst + off) += src`, atomically, as other CPUs update the
+    /// same map values
+    pub fn atomic_add(&mut self, dst: u8, off: i16, src: u8) -> &mut Self {
+        self.push(Insn::new(STX | ATOMIC | DW, dst, src, off, ADD as i32))
+    }
+
+    /// `dst = map`, for the helpers taking a map
+    pub fn load_map(&mut self, dst: u8, map: &Map) -> &mut Self {
+        self.push(Insn::new(LD | IMM | DW, dst, PSEUDO_MAP_FD, 0, map.fd()));
+        // The upper half of the 64-bit immediate
+        self.push(Insn::new(0, 0, 0, 0, 0))
+    }
+
+    /// Call a helper; arguments go in r1 to r5, the result comes in r0,
+    /// and r1 to r5 are clobbered
+    pub fn call(&mut self, helper: i32) -> &mut Self {
+        self.push(Insn::new(JMP | CALL, 0, 0, 0, helper))
+    }
+
+    /// `if dst <op> imm goto label`
+    pub fn jump_imm(&mut self, op: u8, dst: u8, imm: i32, label: &str) -> &mut Self {
+        self.jump(Insn::new(JMP | op | K, dst, 0, 0, imm), label)
+    }
+
+    pub fn goto(&mut self, label: &str) -> &mut Self {
+        self.jump(Insn::new(JMP | JA, 0, 0, 0, 0), label)
+    }
+
+    /// Mark where jumps to `label` go
+    pub fn label(&mut self, label: &str) -> &mut Self {
+        let previous = self.labels.insert(label.to_string(), self.insns.len());
+        assert!(previous.is_none(), "label {} placed twice", label);
+        self
+    }
+
+    /// A label no other part of the program uses
+    pub fn fresh_label(&mut self) -> String {
+        self.next_label += 1;
+        format!(".{}", self.next_label)
+    }
+
+    pub fn exit(&mut self) -> &mut Self {
+        self.push(Insn::new(JMP | EXIT, 0, 0, 0, 0))
+    }
+
+    /// `dst = floor(log2(src))`, and 0 for 0; clobbers `src`
+    pub fn log2(&mut self, dst: u8, src: u8, tmp: u8) -> &mut Self {
+        self.mov_imm(dst, 0);
+        // Immediates are 32-bit, so the upper half is checked by shifting
+        let low = self.fresh_label();
+        self.mov(tmp, src)
+            .alu_imm(RSH, tmp, 32)
+            .jump_imm(JEQ, tmp, 0, &low)
+            .mov(src, tmp)
+            .alu_imm(OR, dst, 32)
+            .label(&low);
+        for bits in [16, 8, 4, 2, 1] {
+            let done = self.fresh_label();
+            self.jump_imm(JLT, src, 1 << bits, &done)
+                .alu_imm(RSH, src, bits)
+                .alu_imm(OR, dst, bits)
+                .label(&done);
+        }
+        self
+    }
+
+    /// `r0 = map[r10 + key]`, a pointer to the value or 0. Clobbers r1 to
+    /// r5, like the other map helpers.
+    pub fn lookup(&mut self, map: &Map, key: i16) -> &mut Self {
+        self.load_map(R1, map)
+            .mov(R2, R10)
+            .alu_imm(ADD, R2, key as i32)
+            .call(MAP_LOOKUP_ELEM)
+    }
+
+    /// `map[r10 + key] = r10 + value`, with map_update_elem `flags`
+    pub fn update(&mut self, map: &Map, key: i16, value: i16, flags: i32) -> &mut Self {
+        self.load_map(R1, map)
+            .mov(R2, R10)
+            .alu_imm(ADD, R2, key as i32)
+            .mov(R3, R10)
+            .alu_imm(ADD, R3, value as i32)
+            .mov_imm(R4, flags)
+            .call(MAP_UPDATE_ELEM)
+    }
+
+    pub fn delete(&mut self, map: &Map, key: i16) -> &mut Self {
+        self.load_map(R1, map)
+            .mov(R2, R10)
+            .alu_imm(ADD, R2, key as i32)
+            .call(MAP_DELETE_ELEM)
+    }
+
+    /// Point r0 at the value of `key` (on the stack at `r10 + key`) in a
+    /// hash map, inserting zeroes first if the key is new; the zeroes are
+    /// written to the stack at `r10 + scratch`. Jumps to `missing` if the
+    /// map is full.
+    pub fn lookup_or_init(
+        &mut self,
+        map: &Map,
+        key: i16,
+        scratch: i16,
+        missing: &str,
+    ) -> &mut Self {
+        let found = self.fresh_label();
+        self.lookup(map, key).jump_imm(JNE, R0, 0, &found);
+        for offset in (0..map.value_size() as i16).step_by(8) {
+            self.store_imm(DW, R10, scratch + offset, 0);
+        }
+        // Someone else may have inserted it meanwhile, so look again
+        // whatever the update returned
+        self.update(map, key, scratch, NOEXIST)
+            .lookup(map, key)
+            .jump_imm(JEQ, R0, 0, missing)
+            .label(&found)
+    }
+
+    /// Count `value` in `slots` power-of-two slots, u64s at `ptr + off` in
+    /// a map value: slot `k` for values from `2^k` up to `2^(k+1)`, and the
+    /// last one for everything larger. Clobbers `value`, `ptr`, r1 and r2.
+    pub fn count_log2(&mut self, ptr: u8, off: i16, value: u8, slots: usize) -> &mut Self {
+        let last = slots as i32 - 1;
+        let clamped = self.fresh_label();
+        self.log2(R1, value, R2)
+            .jump_imm(JLE, R1, last, &clamped)
+            .mov_imm(R1, last)
+            .label(&clamped)
+            .alu_imm(LSH, R1, 3)
+            .alu(ADD, ptr, R1)
+            .mov_imm(R1, 1)
+            .atomic_add(ptr, off, R1)
+    }
+
+    /// The instructions, with every jump pointing at its label. Panics on
+    /// a label that was never placed, which is a bug in the program.
+    pub fn finish(mut self) -> Vec<Insn> {
+        for (index, label) in std::mem::take(&mut self.jumps) {
+            let target = *self
+                .labels
+                .get(&label)
+                .unwrap_or_else(|| panic!("jump to unknown label {}", label));
+            let offset = target as isize - index as isize - 1;
+            self.insns[index].off = i16::try_from(offset).expect("jump too far");
+        }
+        self.insns
+    }
+
+    fn jump(&mut self, insn: Insn, label: &str) -> &mut Self {
+        self.jumps.push((self.insns.len(), label.to_string()));
+        self.push(insn)
+    }
+
+    fn push(&mut self, insn: Insn) -> &mut Self {
+        self.insns.push(insn);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_instructions_like_the_kernel_headers() {
+        let mut asm = Asm::new();
+        asm.mov(R6, R1)
+            .load(W, R2, R6, 8)
+            .store(DW, R10, -8, R2)
+            .atomic_add(R0, 16, R1)
+            .call(MAP_DELETE_ELEM)
+            .exit();
+        assert_eq!(
+            asm.finish(),
+            [
+                Insn::new(0xbf, R6, R1, 0, 0),
+                Insn::new(0x61, R2, R6, 8, 0),
+                Insn::new(0x7b, R10, R2, -8, 0),
+                Insn::new(0xdb, R0, R1, 16, 0),
+                Insn::new(0x85, 0, 0, 0, 3),
+                Insn::new(0x95, 0, 0, 0, 0),
+            ]
+        );
+        assert_eq!(Insn::new(0x61, R2, R6, 8, 0).regs, 0x62);
+    }
+
+    #[test]
+    fn resolves_jumps_both_ways() {
+        let mut asm = Asm::new();
+        asm.label("top")
+            .jump_imm(JEQ, R1, 0, "out")
+            .mov_imm(R0, 1)
+            .goto("top")
+            .label("out")
+            .exit();
+        let insns = asm.finish();
+        assert_eq!(insns[0].off, 2);
+        assert_eq!(insns[2].off, -3);
+    }
+
+    /// Run the instructions `log2` emits on `value`
+    fn interpret_log2(value: u64) -> u64 {
+        let mut asm = Asm::new();
+        asm.log2(R0, R1, R2).exit();
+        let insns = asm.finish();
+        let mut regs = [0u64; 11];
+        regs[1] = value;
+        let mut pc = 0;
+        loop {
+            let insn = insns[pc];
+            let (dst, src) = ((insn.regs & 0xf) as usize, (insn.regs >> 4) as usize);
+            let operand = match insn.code & X {
+                X => regs[src],
+                _ => insn.imm as i64 as u64,
+            };
+            pc += 1;
+            match insn.code {
+                code if code & 0x07 == ALU64 => match code & 0xf0 {
+                    MOV => regs[dst] = operand,
+                    OR => regs[dst] |= operand,
+                    RSH => regs[dst] >>= operand,
+                    op => panic!("unexpected ALU op {:#x}", op),
+                },
+                code if code == JMP | EXIT => return regs[0],
+                code => {
+                    let taken = match code & 0xf0 {
+                        JEQ => regs[dst] == operand,
+                        JLT => regs[dst] < operand,
+                        op => panic!("unexpected jump {:#x}", op),
+                    };
+                    if taken {
+                        pc = (pc as isize + insn.off as isize) as usize;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn log2_rounds_down() {
+        for (value, expected) in [
+            (0, 0),
+            (1, 0),
+            (2, 1),
+            (3, 1),
+            (1000, 9),
+            (1 << 31, 31),
+            (u32::MAX as u64 + 1, 32),
+            (u64::MAX, 63),
+        ] {
+            assert_eq!(interpret_log2(value), expected, "log2({})", value);
+        }
+    }
+}
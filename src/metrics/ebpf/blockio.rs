@@ -0,0 +1,283 @@
+//! Block device I/O latency, from when a request is issued to the device
+//! driver until it completes, like biolatency.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::proto::MetricType;
+
+use super::asm::*;
+use super::bpf::{self, Attachment, Format, Map};
+use super::{log2_histogram, words, Families};
+use crate::collector::Collector;
+use crate::config::Config;
+
+/// Latency slots: powers of two of microseconds, up to about 67s
+const SLOTS: usize = 26;
+
+// Latency by device and operation: the sum in nanoseconds, then the slots
+const SUM: i16 = 0;
+const SLOT: i16 = 8;
+const LATENCY_SIZE: usize = 8 + SLOTS * 8;
+
+/// Operations, in the latency key after the device
+const OPERATIONS: &[&str] = &["read", "write", "other"];
+
+// Stack slots of the programs, below r10: the request's device and
+// sector, its issue time, and the latency key
+const REQUEST_KEY: i16 = -16;
+const ISSUED: i16 = -24;
+const LATENCY_KEY: i16 = -32;
+const SCRATCH: i16 = LATENCY_KEY - LATENCY_SIZE as i16;
+
+pub struct BlockioCollector {
+    programs: Result<Programs, String>,
+    families: Families,
+    devices: DeviceNames,
+}
+
+struct Programs {
+    latency: Map,
+    _attachments: Vec<Attachment>,
+}
+
+impl BlockioCollector {
+    pub fn new(config: &Config) -> Self {
+        BlockioCollector {
+            programs: super::load(config, "blockio", Programs::load),
+            families: Families::new(&[(
+                "disk_io_latency_seconds",
+                "Time block device requests took from being issued to the driver until they completed",
+                &["device", "operation"],
+            )]),
+            devices: DeviceNames::new(Path::new("/sys/dev/block")),
+        }
+    }
+}
+
+impl Programs {
+    fn load() -> std::io::Result<Programs> {
+        // Requests in flight; ones whose completion is missed get evicted
+        let issued = Map::lru_hash(16, 8, 65536)?;
+        let latency = Map::lru_hash(8, LATENCY_SIZE, 1024)?;
+
+        let issue = issue_program(&Format::read("block", "block_rq_issue")?, &issued)?;
+        let complete = complete_program(
+            &Format::read("block", "block_rq_complete")?,
+            &issued,
+            &latency,
+        )?;
+        let attachments = vec![
+            bpf::attach("blockio_issue", &issue, "block", "block_rq_issue")?,
+            bpf::attach("blockio_done", &complete, "block", "block_rq_complete")?,
+        ];
+        Ok(Programs {
+            latency,
+            _attachments: attachments,
+        })
+    }
+}
+
+/// Store the request's device and sector at REQUEST_KEY, and its device
+/// in r7
+fn request_key(asm: &mut Asm, format: &Format) -> std::io::Result<()> {
+    let dev = format.offset("dev", 4)?;
+    let sector = format.offset("sector", 8)?;
+    asm.load(W, R7, R1, dev)
+        .store(W, R10, REQUEST_KEY, R7)
+        .store_imm(W, R10, REQUEST_KEY + 4, 0)
+        .load(DW, R2, R1, sector)
+        .store(DW, R10, REQUEST_KEY + 8, R2);
+    Ok(())
+}
+
+fn issue_program(format: &Format, issued: &Map) -> std::io::Result<Vec<Insn>> {
+    let mut asm = Asm::new();
+    request_key(&mut asm, format)?;
+    asm.call(KTIME_GET_NS)
+        .store(DW, R10, ISSUED, R0)
+        .update(issued, REQUEST_KEY, ISSUED, ANY)
+        .mov_imm(R0, 0)
+        .exit();
+    Ok(asm.finish())
+}
+
+fn complete_program(format: &Format, issued: &Map, latency: &Map) -> std::io::Result<Vec<Insn>> {
+    // Flags like "WS" for a synchronous write, after an F for a preflush
+    let rwbs = format.offset("rwbs", 10)?;
+
+    let mut asm = Asm::new();
+    asm.mov(R6, R1);
+    request_key(&mut asm, format)?;
+    asm.lookup(issued, REQUEST_KEY)
+        .jump_imm(JEQ, R0, 0, "out")
+        .load(DW, R8, R0, 0)
+        .delete(issued, REQUEST_KEY)
+        .call(KTIME_GET_NS)
+        .alu(SUB, R0, R8)
+        .mov(R8, R0);
+
+    // The operation, as an index into OPERATIONS
+    asm.load(B, R1, R6, rwbs)
+        .jump_imm(JNE, R1, b'F' as i32, "operation")
+        .load(B, R1, R6, rwbs + 1)
+        .label("operation")
+        .mov_imm(R9, 2)
+        .jump_imm(JNE, R1, b'R' as i32, "not read")
+        .mov_imm(R9, 0)
+        .label("not read")
+        .jump_imm(JNE, R1, b'W' as i32, "key")
+        .mov_imm(R9, 1)
+        .label("key")
+        .store(W, R10, LATENCY_KEY, R7)
+        .store(W, R10, LATENCY_KEY + 4, R9);
+
+    asm.lookup_or_init(latency, LATENCY_KEY, SCRATCH, "out")
+        .mov(R9, R0)
+        .atomic_add(R9, SUM, R8)
+        .alu_imm(DIV, R8, 1000)
+        .count_log2(R9, SLOT, R8, SLOTS)
+        .label("out")
+        .mov_imm(R0, 0)
+        .exit();
+    Ok(asm.finish())
+}
+
+/// Names of block devices by their kernel dev_t, as in /dev
+struct DeviceNames {
+    sys: PathBuf,
+    names: Mutex<HashMap<u32, String>>,
+}
+
+impl DeviceNames {
+    fn new(sys: &Path) -> Self {
+        DeviceNames {
+            sys: sys.to_path_buf(),
+            names: Mutex::default(),
+        }
+    }
+
+    /// The DEVNAME of `/sys/dev/block/MAJOR:MINOR`, or `MAJOR:MINOR` for a
+    /// device that is gone
+    fn name(&self, dev: u32) -> String {
+        let mut names = self.names.lock().unwrap();
+        if let Some(name) = names.get(&dev) {
+            return name.clone();
+        }
+        // The kernel's dev_t has 12 bits of major and 20 of minor
+        let number = format!("{}:{}", dev >> 20, dev & 0xfffff);
+        let uevent = std::fs::read_to_string(self.sys.join(&number).join("uevent"));
+        let Some(name) = uevent.ok().and_then(|uevent| {
+            uevent
+                .lines()
+                .find_map(|line| line.strip_prefix("DEVNAME="))
+                .map(str::to_string)
+        }) else {
+            return number;
+        };
+        names.insert(dev, name.clone());
+        name
+    }
+}
+
+impl Collector for BlockioCollector {
+    fn name(&self) -> &'static str {
+        "blockio"
+    }
+
+    fn platforms(&self) -> &'static [&'static str] {
+        &["linux"]
+    }
+
+    fn unavailable(&self) -> Option<String> {
+        self.programs.as_ref().err().cloned()
+    }
+
+    fn register_metrics(&self, registry: &prometheus::Registry) -> prometheus::Result<()> {
+        super::super::builder::register(registry, &self.families)
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.families]
+    }
+
+    fn reset_metrics(&self, _registry: &prometheus::Registry) {
+        self.families.reset();
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        let programs = self.programs.as_ref()?;
+        let mut latencies = Vec::new();
+        for (key, value) in programs
+            .latency
+            .entries()
+            .map_err(|e| format!("reading block I/O latencies: {}", e))?
+        {
+            let dev = u32::from_ne_bytes(key[..4].try_into().unwrap());
+            let operation = u32::from_ne_bytes(key[4..].try_into().unwrap());
+            let Some(operation) = OPERATIONS.get(operation as usize) else {
+                continue;
+            };
+            let values = words(&value);
+            latencies.push(log2_histogram(
+                &[
+                    ("device", &self.devices.name(dev)),
+                    ("operation", operation),
+                ],
+                &values[SLOT as usize / 8..],
+                values[SUM as usize / 8] as f64 / 1e9,
+                1e-6,
+            ));
+        }
+        latencies.sort_by_key(|metric| {
+            let labels = metric.get_label();
+            (labels[0].value().to_string(), labels[1].value().to_string())
+        });
+        self.families.set(&[MetricType::HISTOGRAM], vec![latencies]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_devices_from_sysfs() {
+        let sys = std::env::temp_dir().join(format!("metrixd-dev-block-{}", std::process::id()));
+        std::fs::create_dir_all(sys.join("259:0")).unwrap();
+        std::fs::write(
+            sys.join("259:0/uevent"),
+            "MAJOR=259\nMINOR=0\nDEVNAME=nvme0n1\nDEVTYPE=disk\n",
+        )
+        .unwrap();
+        let devices = DeviceNames::new(&sys);
+        assert_eq!(devices.name(259 << 20), "nvme0n1");
+        assert_eq!(devices.name((8 << 20) | 16), "8:16");
+        std::fs::remove_dir_all(&sys).unwrap();
+        // Cached, as device numbers stay put while the device exists
+        assert_eq!(devices.name(259 << 20), "nvme0n1");
+    }
+
+    /// Loading needs root and a kernel with the tracepoints, so this only
+    /// checks the verifier accepts the programs where it can
+    #[test]
+    fn loads_and_reads_latencies() {
+        let config = Config::parse("").unwrap();
+        let collector = BlockioCollector::new(&config);
+        if let Some(reason) = collector.unavailable() {
+            eprintln!("skipping, the blockio collector can't load: {}", reason);
+            return;
+        }
+        collector.collect_metrics().unwrap();
+        for family in collector.families.collect() {
+            for metric in family.get_metric() {
+                let histogram = metric.get_histogram();
+                let buckets = histogram.get_bucket();
+                assert!(buckets.last().unwrap().cumulative_count() <= histogram.get_sample_count());
+            }
+        }
+    }
+}
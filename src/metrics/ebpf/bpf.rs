@@ -0,0 +1,419 @@
+//! The bpf() and perf_event_open() calls the collectors need: hash maps,
+//! loading programs and attaching them to tracepoints. Field offsets come
+//! from the tracepoints' format files in tracefs, so the same programs load
+//! on every kernel without BTF or libbpf.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use super::asm::Insn;
+
+// Commands, from include/uapi/linux/bpf.h
+const MAP_CREATE: libc::c_long = 0;
+const MAP_LOOKUP_ELEM: libc::c_long = 1;
+const MAP_GET_NEXT_KEY: libc::c_long = 4;
+const PROG_LOAD: libc::c_long = 5;
+
+const MAP_TYPE_LRU_HASH: u32 = 9;
+const PROG_TYPE_TRACEPOINT: u32 = 5;
+
+// include/uapi/linux/perf_event.h
+const PERF_TYPE_TRACEPOINT: u32 = 2;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 8;
+const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+const PERF_EVENT_IOC_SET_BPF: libc::c_ulong = 0x4004_2408;
+
+/// Where tracefs is mounted, on current kernels and on older ones
+const TRACEFS: &[&str] = &["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+
+#[repr(C)]
+#[derive(Default)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    /// The value, or the next key for MAP_GET_NEXT_KEY
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+}
+
+/// `struct perf_event_attr` up to PERF_ATTR_SIZE_VER5
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    kind: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+    branch_sample_type: u64,
+    sample_regs_user: u64,
+    sample_stack_user: u32,
+    clockid: i32,
+    sample_regs_intr: u64,
+    aux_watermark: u32,
+    sample_max_stack: u16,
+    _reserved: u16,
+}
+
+fn bpf<T>(command: libc::c_long, attr: &mut T) -> io::Result<libc::c_long> {
+    let rc = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            command,
+            attr as *mut T,
+            size_of::<T>() as libc::c_uint,
+        )
+    };
+    match rc {
+        -1 => Err(io::Error::last_os_error()),
+        rc => Ok(rc),
+    }
+}
+
+/// A hash map shared between programs and the collector, freed once the
+/// collector and the programs referring to it are gone
+pub struct Map {
+    fd: OwnedFd,
+    key_size: usize,
+    value_size: usize,
+    max_entries: u32,
+}
+
+impl Map {
+    /// A hash map that evicts the least recently used entries when full,
+    /// for keys whose removal a program may miss
+    pub fn lru_hash(key_size: usize, value_size: usize, max_entries: u32) -> io::Result<Map> {
+        Self::create(MAP_TYPE_LRU_HASH, key_size, value_size, max_entries)
+    }
+
+    fn create(
+        map_type: u32,
+        key_size: usize,
+        value_size: usize,
+        max_entries: u32,
+    ) -> io::Result<Map> {
+        let mut attr = MapCreateAttr {
+            map_type,
+            key_size: key_size as u32,
+            value_size: value_size as u32,
+            max_entries,
+            ..MapCreateAttr::default()
+        };
+        let fd = bpf(MAP_CREATE, &mut attr)
+            .map_err(|e| io::Error::new(e.kind(), format!("creating a BPF map: {}", e)))?;
+        Ok(Map {
+            fd: unsafe { OwnedFd::from_raw_fd(fd as RawFd) },
+            key_size,
+            value_size,
+            max_entries,
+        })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+
+    pub fn value_size(&self) -> usize {
+        self.value_size
+    }
+
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        assert_eq!(key.len(), self.key_size, "wrong key size");
+        let mut value = vec![0; self.value_size];
+        let mut attr = ElemAttr {
+            map_fd: self.fd() as u32,
+            key: key.as_ptr() as u64,
+            value: value.as_mut_ptr() as u64,
+            ..ElemAttr::default()
+        };
+        match bpf(MAP_LOOKUP_ELEM, &mut attr) {
+            Ok(_) => Ok(Some(value)),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Every key and its value. Programs keep updating the map meanwhile,
+    /// so an entry added or removed during the walk may be missed.
+    pub fn entries(&self) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        let mut key: Option<Vec<u8>> = None;
+        // Bounded, in case keys keep being removed under the walk
+        for _ in 0..self.max_entries {
+            let mut next = vec![0; self.key_size];
+            let mut attr = ElemAttr {
+                map_fd: self.fd() as u32,
+                // No key asks for the first one
+                key: key.as_ref().map_or(0, |key| key.as_ptr() as u64),
+                value: next.as_mut_ptr() as u64,
+                ..ElemAttr::default()
+            };
+            match bpf(MAP_GET_NEXT_KEY, &mut attr) {
+                Ok(_) => {}
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => break,
+                Err(e) => return Err(e),
+            }
+            if let Some(value) = self.get(&next)? {
+                entries.push((next.clone(), value));
+            }
+            key = Some(next);
+        }
+        Ok(entries)
+    }
+}
+
+/// A program attached to a tracepoint; dropping it detaches the program
+pub struct Attachment {
+    _event: OwnedFd,
+    _program: OwnedFd,
+}
+
+/// Load a tracepoint program and attach it to `category/event`. Load
+/// errors carry the end of the verifier's log.
+pub fn attach(name: &str, insns: &[Insn], category: &str, event: &str) -> io::Result<Attachment> {
+    let program = load(name, insns)?;
+    let id = tracepoint_id(category, event)?;
+
+    let mut attr = PerfEventAttr {
+        kind: PERF_TYPE_TRACEPOINT,
+        size: size_of::<PerfEventAttr>() as u32,
+        config: id,
+        sample_period: 1,
+        wakeup_events: 1,
+        ..PerfEventAttr::default()
+    };
+    // One event for all CPUs: the program runs wherever the tracepoint fires
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            &mut attr as *mut PerfEventAttr,
+            -1,
+            0,
+            -1,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(context(
+            io::Error::last_os_error(),
+            &format!("opening tracepoint {}/{}", category, event),
+        ));
+    }
+    let event_fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+    for (request, arg) in [
+        (PERF_EVENT_IOC_SET_BPF, program.as_raw_fd()),
+        (PERF_EVENT_IOC_ENABLE, 0),
+    ] {
+        if unsafe { libc::ioctl(event_fd.as_raw_fd(), request as _, arg) } != 0 {
+            return Err(context(
+                io::Error::last_os_error(),
+                &format!("attaching {} to {}/{}", name, category, event),
+            ));
+        }
+    }
+    Ok(Attachment {
+        _event: event_fd,
+        _program: program,
+    })
+}
+
+fn load(name: &str, insns: &[Insn]) -> io::Result<OwnedFd> {
+    let license = CString::new("GPL").unwrap();
+    let mut log = vec![0u8; 64 * 1024];
+    let mut prog_name = [0; 16];
+    for (slot, byte) in prog_name.iter_mut().zip(name.bytes().take(15)) {
+        *slot = byte;
+    }
+    let mut attr = ProgLoadAttr {
+        prog_type: PROG_TYPE_TRACEPOINT,
+        insn_cnt: insns.len() as u32,
+        insns: insns.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        log_level: 1,
+        log_size: log.len() as u32,
+        log_buf: log.as_mut_ptr() as u64,
+        prog_name,
+        ..ProgLoadAttr::default()
+    };
+    match bpf(PROG_LOAD, &mut attr) {
+        Ok(fd) => Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) }),
+        Err(e) => {
+            let log = String::from_utf8_lossy(&log);
+            let log = log.trim_end_matches('\0').trim_end();
+            // The last lines say what the verifier rejected
+            let tail: Vec<&str> = log.lines().rev().take(3).collect();
+            let tail: Vec<&str> = tail.into_iter().rev().collect();
+            Err(context(
+                e,
+                &format!("loading BPF program {}: {}", name, tail.join(" / ")),
+            ))
+        }
+    }
+}
+
+fn context(e: io::Error, what: &str) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", what, e))
+}
+
+fn tracefs() -> io::Result<PathBuf> {
+    TRACEFS
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.join("events").is_dir())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "tracefs isn't mounted"))
+}
+
+fn event_dir(category: &str, event: &str) -> io::Result<PathBuf> {
+    Ok(tracefs()?.join("events").join(category).join(event))
+}
+
+fn tracepoint_id(category: &str, event: &str) -> io::Result<u64> {
+    let path = event_dir(category, event)?.join("id");
+    let id = std::fs::read_to_string(&path)
+        .map_err(|e| context(e, &format!("tracepoint {}/{}", category, event)))?;
+    id.trim()
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid tracepoint id"))
+}
+
+/// Offsets of a tracepoint's fields in the record its programs get
+pub struct Format {
+    event: String,
+    fields: HashMap<String, (i16, usize)>,
+}
+
+impl Format {
+    pub fn read(category: &str, event: &str) -> io::Result<Format> {
+        let path = event_dir(category, event)?.join("format");
+        Self::parse(&format!("{}/{}", category, event), &path)
+    }
+
+    fn parse(event: &str, path: &Path) -> io::Result<Format> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| context(e, &format!("tracepoint {}", event)))?;
+        Ok(Self::from_text(event, &text))
+    }
+
+    /// `field:<declaration>; offset:<n>; size:<n>; signed:<n>;` lines
+    fn from_text(event: &str, text: &str) -> Format {
+        let mut fields = HashMap::new();
+        for line in text.lines() {
+            let mut parts = line.trim().split(';').map(str::trim);
+            let Some(declaration) = parts.next().and_then(|p| p.strip_prefix("field:")) else {
+                continue;
+            };
+            let number = |part: Option<&str>, key: &str| {
+                part.and_then(|p| p.strip_prefix(key))
+                    .and_then(|n| n.parse::<usize>().ok())
+            };
+            let (Some(offset), Some(size)) = (
+                number(parts.next(), "offset:"),
+                number(parts.next(), "size:"),
+            ) else {
+                continue;
+            };
+            let name = declaration
+                .rsplit(' ')
+                .next()
+                .unwrap_or_default()
+                .split('[')
+                .next()
+                .unwrap_or_default();
+            fields.insert(name.to_string(), (offset as i16, size));
+        }
+        Format {
+            event: event.to_string(),
+            fields,
+        }
+    }
+
+    /// The offset of `field`, checking it has the size a program loads
+    pub fn offset(&self, field: &str, size: usize) -> io::Result<i16> {
+        match self.fields.get(field) {
+            Some((offset, actual)) if *actual == size => Ok(*offset),
+            Some((_, actual)) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}: field {} has {} bytes, expected {}",
+                    self.event, field, actual, size
+                ),
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{}: no field {}", self.event, field),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_field_offsets_from_a_format_file() {
+        let text = "name: block_rq_issue\nID: 2004\nformat:\n\
+            \tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;\n\
+            \tfield:dev_t dev;\toffset:8;\tsize:4;\tsigned:0;\n\
+            \tfield:sector_t sector;\toffset:16;\tsize:8;\tsigned:0;\n\
+            \tfield:char rwbs[10];\toffset:34;\tsize:10;\tsigned:0;\n\
+            \n\
+            print fmt: \"%d,%d\", REC->dev\n";
+        let format = Format::from_text("block/block_rq_issue", text);
+        assert_eq!(format.offset("dev", 4).unwrap(), 8);
+        assert_eq!(format.offset("sector", 8).unwrap(), 16);
+        assert_eq!(format.offset("rwbs", 10).unwrap(), 34);
+        assert_eq!(
+            format.offset("sector", 4).unwrap_err().to_string(),
+            "block/block_rq_issue: field sector has 8 bytes, expected 4"
+        );
+        assert_eq!(
+            format.offset("bytes", 4).unwrap_err().to_string(),
+            "block/block_rq_issue: no field bytes"
+        );
+    }
+
+    #[test]
+    fn attr_layouts_match_the_kernel() {
+        // PERF_ATTR_SIZE_VER5
+        assert_eq!(size_of::<PerfEventAttr>(), 112);
+        assert_eq!(size_of::<ElemAttr>(), 32);
+        assert_eq!(size_of::<ProgLoadAttr>(), 64);
+    }
+}
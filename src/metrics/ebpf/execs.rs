@@ -0,0 +1,154 @@
+//! Programs started, by command name, from every successful execve().
+
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::proto::MetricType;
+
+use super::asm::*;
+use super::bpf::{self, Attachment, Map};
+use super::{counter, words, Families};
+use crate::collector::Collector;
+use crate::config::Config;
+
+/// Command names counted; the least recently run ones are dropped beyond
+/// that, so a host running random names can't grow the series forever
+const COMMANDS: u32 = 1024;
+
+/// The kernel's TASK_COMM_LEN
+const COMM_LEN: i16 = 16;
+
+// Stack slots of the program, below r10
+const COMM_KEY: i16 = -COMM_LEN;
+const SCRATCH: i16 = COMM_KEY - 8;
+
+pub struct ExecsCollector {
+    programs: Result<Programs, String>,
+    families: Families,
+}
+
+struct Programs {
+    execs: Map,
+    _attachments: Vec<Attachment>,
+}
+
+impl ExecsCollector {
+    pub fn new(config: &Config) -> Self {
+        ExecsCollector {
+            programs: super::load(config, "execs", Programs::load),
+            families: Families::new(&[(
+                "process_execs_total",
+                "Programs started with execve(), by the command name they run as",
+                &["command"],
+            )]),
+        }
+    }
+}
+
+impl Programs {
+    fn load() -> std::io::Result<Programs> {
+        let execs = Map::lru_hash(COMM_LEN as usize, 8, COMMANDS)?;
+
+        // The tracepoint fires in the new program, after its name is set
+        let mut asm = Asm::new();
+        asm.mov(R1, R10)
+            .alu_imm(ADD, R1, COMM_KEY as i32)
+            .mov_imm(R2, COMM_LEN as i32)
+            .call(GET_CURRENT_COMM)
+            .lookup_or_init(&execs, COMM_KEY, SCRATCH, "out")
+            .mov_imm(R1, 1)
+            .atomic_add(R0, 0, R1)
+            .label("out")
+            .mov_imm(R0, 0)
+            .exit();
+        let attachments = vec![bpf::attach(
+            "execs",
+            &asm.finish(),
+            "sched",
+            "sched_process_exec",
+        )?];
+        Ok(Programs {
+            execs,
+            _attachments: attachments,
+        })
+    }
+}
+
+/// A command name as the kernel pads it, up to the first NUL
+fn command(comm: &[u8]) -> String {
+    let end = comm.iter().position(|b| *b == 0).unwrap_or(comm.len());
+    String::from_utf8_lossy(&comm[..end]).into_owned()
+}
+
+impl Collector for ExecsCollector {
+    fn name(&self) -> &'static str {
+        "execs"
+    }
+
+    fn platforms(&self) -> &'static [&'static str] {
+        &["linux"]
+    }
+
+    fn unavailable(&self) -> Option<String> {
+        self.programs.as_ref().err().cloned()
+    }
+
+    fn register_metrics(&self, registry: &prometheus::Registry) -> prometheus::Result<()> {
+        super::super::builder::register(registry, &self.families)
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.families]
+    }
+
+    fn reset_metrics(&self, _registry: &prometheus::Registry) {
+        self.families.reset();
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        let programs = self.programs.as_ref()?;
+        let mut execs: Vec<(String, u64)> = programs
+            .execs
+            .entries()
+            .map_err(|e| format!("reading exec counts: {}", e))?
+            .into_iter()
+            .map(|(key, value)| (command(&key), words(&value)[0]))
+            .collect();
+        execs.sort();
+        let execs = execs
+            .iter()
+            .map(|(command, count)| counter(&[("command", command)], *count))
+            .collect();
+        self.families.set(&[MetricType::COUNTER], vec![execs]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trims_command_names_at_the_nul() {
+        assert_eq!(command(b"nginx\0\0\0\0\0\0\0\0\0\0\0"), "nginx");
+        assert_eq!(command(b"kworker/u16:2-ev"), "kworker/u16:2-ev");
+    }
+
+    /// Loading needs root and a kernel with the tracepoint, so this only
+    /// checks the verifier accepts the program where it can
+    #[test]
+    fn counts_programs_started() {
+        let config = Config::parse("").unwrap();
+        let collector = ExecsCollector::new(&config);
+        if let Some(reason) = collector.unavailable() {
+            eprintln!("skipping, the execs collector can't load: {}", reason);
+            return;
+        }
+        std::process::Command::new("true").status().unwrap();
+        collector.collect_metrics().unwrap();
+        let families = collector.families.collect();
+        assert!(families[0]
+            .get_metric()
+            .iter()
+            .any(|metric| metric.get_label()[0].value() == "true"
+                && metric.get_counter().value() >= 1.0));
+    }
+}
@@ -0,0 +1,282 @@
+//! Collectors built on eBPF programs attached to kernel tracepoints, for
+//! what /proc and /sys don't show. The programs are assembled at startup
+//! and loaded before privileges are dropped, so the daemon needs root (or
+//! CAP_BPF and CAP_PERFMON) to start them; kernels from 5.8 on are
+//! supported. The programs count into hash maps that each collection cycle
+//! reads into metric families.
+
+mod asm;
+mod blockio;
+mod bpf;
+mod execs;
+mod syscalls;
+mod tcp;
+
+pub(crate) use blockio::BlockioCollector;
+pub(crate) use execs::ExecsCollector;
+pub(crate) use syscalls::SyscallsCollector;
+pub(crate) use tcp::TcpCollector;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use prometheus::core::{Collector as PrometheusCollector, Desc};
+use prometheus::proto::{Bucket, Counter, Histogram, LabelPair, Metric, MetricFamily, MetricType};
+
+/// The families read from a collector's maps in the last cycle, exposed
+/// until the next one
+#[derive(Clone)]
+pub(crate) struct Families {
+    descs: Arc<Vec<Desc>>,
+    last: Arc<Mutex<Vec<MetricFamily>>>,
+}
+
+impl Families {
+    /// `(name, help, labels)` of every family the collector exports
+    pub fn new(families: &[(&str, &str, &[&str])]) -> Self {
+        let descs = families
+            .iter()
+            .map(|(name, help, labels)| {
+                Desc::new(
+                    name.to_string(),
+                    help.to_string(),
+                    labels.iter().map(|label| label.to_string()).collect(),
+                    HashMap::new(),
+                )
+                .expect("invalid eBPF metric")
+            })
+            .collect();
+        Families {
+            descs: Arc::new(descs),
+            last: Arc::default(),
+        }
+    }
+
+    /// Replace the families, given as the metrics of each descriptor in
+    /// the order `new` got them
+    pub fn set(&self, kinds: &[MetricType], metrics: Vec<Vec<Metric>>) {
+        let families = self
+            .descs
+            .iter()
+            .zip(kinds)
+            .zip(metrics)
+            .filter(|(_, metrics)| !metrics.is_empty())
+            .map(|((desc, kind), metrics)| {
+                let mut family = MetricFamily::default();
+                family.set_name(desc.fq_name.clone());
+                family.set_help(desc.help.clone());
+                family.set_field_type(*kind);
+                family.set_metric(metrics);
+                family
+            })
+            .collect();
+        *self.last.lock().unwrap() = families;
+    }
+
+    pub fn reset(&self) {
+        self.last.lock().unwrap().clear();
+    }
+}
+
+impl PrometheusCollector for Families {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.last.lock().unwrap().clone()
+    }
+}
+
+fn metric(labels: &[(&str, &str)]) -> Metric {
+    Metric::from_label(
+        labels
+            .iter()
+            .map(|(name, value)| {
+                let mut pair = LabelPair::default();
+                pair.set_name(name.to_string());
+                pair.set_value(value.to_string());
+                pair
+            })
+            .collect(),
+    )
+}
+
+pub(crate) fn counter(labels: &[(&str, &str)], value: u64) -> Metric {
+    let mut metric = metric(labels);
+    let mut counter = Counter::default();
+    counter.set_value(value as f64);
+    metric.set_counter(counter);
+    metric
+}
+
+/// A histogram from power-of-two slots as the programs count them: slot
+/// `k` holds values from `2^k` up to `2^(k+1)` units of `unit` seconds,
+/// slot 0 also zero, and the last slot everything larger
+pub(crate) fn log2_histogram(
+    labels: &[(&str, &str)],
+    slots: &[u64],
+    sum: f64,
+    unit: f64,
+) -> Metric {
+    let mut metric = metric(labels);
+    let mut histogram = Histogram::default();
+    let mut cumulative = 0;
+    let mut buckets = Vec::with_capacity(slots.len());
+    // The last slot is open-ended, so only the count (+Inf) holds it
+    for (k, count) in slots[..slots.len() - 1].iter().enumerate() {
+        cumulative += count;
+        let mut bucket = Bucket::default();
+        bucket.set_upper_bound((1u64 << (k + 1)) as f64 * unit);
+        bucket.set_cumulative_count(cumulative);
+        buckets.push(bucket);
+    }
+    histogram.set_bucket(buckets);
+    histogram.set_sample_count(slots.iter().sum());
+    histogram.set_sample_sum(sum);
+    metric.set_histogram(histogram);
+    metric
+}
+
+/// `u64`s of a map key or value, in the kernel's byte order
+pub(crate) fn words(bytes: &[u8]) -> Vec<u64> {
+    bytes
+        .chunks_exact(8)
+        .map(|chunk| u64::from_ne_bytes(chunk.try_into().unwrap()))
+        .collect()
+}
+
+/// Paths of cgroup v2 groups, relative to the hierarchy's root, by the ID
+/// `bpf_get_current_cgroup_id` returns: the inode number of their directory
+pub(crate) struct CgroupNames {
+    root: Option<PathBuf>,
+    names: Mutex<HashMap<u64, String>>,
+}
+
+impl CgroupNames {
+    pub fn new() -> Self {
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+        CgroupNames {
+            root: cgroup2_mount(&mountinfo),
+            names: Mutex::default(),
+        }
+    }
+
+    /// Names for `ids`, walking the hierarchy again if one is new. IDs that
+    /// are gone by then are named by their number; 0 stands for sockets
+    /// opened before the programs were loaded.
+    pub fn resolve(&self, ids: impl IntoIterator<Item = u64>) -> HashMap<u64, String> {
+        let mut names = self.names.lock().unwrap();
+        let ids: Vec<u64> = ids.into_iter().collect();
+        if ids.iter().any(|id| *id != 0 && !names.contains_key(id)) {
+            if let Some(root) = &self.root {
+                names.clear();
+                walk(root, root, &mut names);
+            }
+        }
+        ids.into_iter()
+            .map(|id| {
+                let name = match id {
+                    0 => "unknown".to_string(),
+                    id => names.get(&id).cloned().unwrap_or_else(|| id.to_string()),
+                };
+                (id, name)
+            })
+            .collect()
+    }
+}
+
+/// Where the cgroup v2 hierarchy is mounted, from /proc/self/mountinfo
+fn cgroup2_mount(mountinfo: &str) -> Option<PathBuf> {
+    mountinfo.lines().find_map(|line| {
+        let (mount, filesystem) = line.split_once(" - ")?;
+        let mount_point = mount.split(' ').nth(4)?;
+        (filesystem.split(' ').next() == Some("cgroup2")).then(|| PathBuf::from(mount_point))
+    })
+}
+
+fn walk(root: &Path, dir: &Path, names: &mut HashMap<u64, String>) {
+    use std::os::unix::fs::MetadataExt;
+
+    let Ok(metadata) = std::fs::metadata(dir) else {
+        return;
+    };
+    let relative = dir.strip_prefix(root).unwrap_or(dir);
+    names.insert(metadata.ino(), format!("/{}", relative.display()));
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+            walk(root, &entry.path(), names);
+        }
+    }
+}
+
+/// Load a collector's programs at startup, unless it is disabled in the
+/// config: they can't be loaded once privileges are dropped, and loaded
+/// programs run on every event they trace
+pub(crate) fn load<T>(
+    config: &crate::config::Config,
+    name: &str,
+    load: impl FnOnce() -> std::io::Result<T>,
+) -> Result<T, String> {
+    if !config.collector(name).enabled {
+        return Err("disabled in the config at startup".to_string());
+    }
+    load().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_cgroup2_mount() {
+        let mountinfo = "\
+            25 30 0:22 / /sys/fs/cgroup rw,nosuid - tmpfs tmpfs rw,mode=755\n\
+            26 25 0:23 / /sys/fs/cgroup/unified rw,nosuid - cgroup2 cgroup2 rw\n\
+            27 25 0:24 / /sys/fs/cgroup/cpu rw,nosuid - cgroup cgroup rw,cpu\n";
+        assert_eq!(
+            cgroup2_mount(mountinfo),
+            Some(PathBuf::from("/sys/fs/cgroup/unified"))
+        );
+        assert_eq!(cgroup2_mount(""), None);
+    }
+
+    #[test]
+    fn names_cgroups_by_their_directory() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = std::env::temp_dir().join(format!("metrixd-cgroups-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("system.slice/nginx.service")).unwrap();
+        let id = |path: &str| std::fs::metadata(root.join(path)).unwrap().ino();
+        let names = CgroupNames {
+            root: Some(root.clone()),
+            names: Mutex::default(),
+        };
+        let nginx = id("system.slice/nginx.service");
+        let resolved = names.resolve([0, nginx, id(""), u64::MAX]);
+        assert_eq!(resolved[&0], "unknown");
+        assert_eq!(resolved[&nginx], "/system.slice/nginx.service");
+        assert_eq!(resolved[&id("")], "/");
+        assert_eq!(resolved[&u64::MAX], u64::MAX.to_string());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn converts_log2_slots_to_cumulative_buckets() {
+        let metric = log2_histogram(&[("device", "sda")], &[1, 0, 2, 3], 12.0, 1e-6);
+        let histogram = metric.get_histogram();
+        let buckets: Vec<(f64, u64)> = histogram
+            .get_bucket()
+            .iter()
+            .map(|b| (b.upper_bound(), b.cumulative_count()))
+            .collect();
+        assert_eq!(buckets, [(2e-6, 1), (4e-6, 1), (8e-6, 3)]);
+        assert_eq!(histogram.get_sample_count(), 6);
+        assert_eq!(histogram.get_sample_sum(), 12.0);
+        assert_eq!(metric.get_label()[0].value(), "sda");
+    }
+}
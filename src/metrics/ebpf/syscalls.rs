@@ -0,0 +1,380 @@
+//! System calls that failed, by call and errno, from the exit of every
+//! system call on the host.
+
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::proto::MetricType;
+
+use super::asm::*;
+use super::bpf::{self, Attachment, Format, Map};
+use super::{counter, words, Families};
+use crate::collector::Collector;
+use crate::config::Config;
+
+/// Pairs of call and errno counted, the least recent dropped beyond that
+const ERRORS: u32 = 4096;
+
+/// The largest errno; a syscall returning something more negative
+/// succeeded, with an address or offset
+const MAX_ERRNO: i32 = 4095;
+
+// Stack slots of the program, below r10: the call and errno
+const ERROR_KEY: i16 = -8;
+const SCRATCH: i16 = ERROR_KEY - 8;
+
+pub struct SyscallsCollector {
+    programs: Result<Programs, String>,
+    families: Families,
+}
+
+struct Programs {
+    errors: Map,
+    _attachments: Vec<Attachment>,
+}
+
+impl SyscallsCollector {
+    pub fn new(config: &Config) -> Self {
+        SyscallsCollector {
+            programs: super::load(config, "syscalls", Programs::load),
+            families: Families::new(&[(
+                "syscall_errors_total",
+                "System calls that returned an error, by call and errno",
+                &["syscall", "errno"],
+            )]),
+        }
+    }
+}
+
+impl Programs {
+    fn load() -> std::io::Result<Programs> {
+        let errors = Map::lru_hash(8, 8, ERRORS)?;
+
+        let format = Format::read("raw_syscalls", "sys_exit")?;
+        let id = format.offset("id", 8)?;
+        let ret = format.offset("ret", 8)?;
+        let mut asm = Asm::new();
+        asm.load(DW, R2, R1, ret)
+            .jump_imm(JSGT, R2, -1, "out")
+            .jump_imm(JSLT, R2, -MAX_ERRNO, "out")
+            .mov_imm(R3, 0)
+            .alu(SUB, R3, R2)
+            .store(W, R10, ERROR_KEY + 4, R3)
+            .load(DW, R2, R1, id)
+            .store(W, R10, ERROR_KEY, R2)
+            .lookup_or_init(&errors, ERROR_KEY, SCRATCH, "out")
+            .mov_imm(R1, 1)
+            .atomic_add(R0, 0, R1)
+            .label("out")
+            .mov_imm(R0, 0)
+            .exit();
+        let attachments = vec![bpf::attach(
+            "syscall_errors",
+            &asm.finish(),
+            "raw_syscalls",
+            "sys_exit",
+        )?];
+        Ok(Programs {
+            errors,
+            _attachments: attachments,
+        })
+    }
+}
+
+impl Collector for SyscallsCollector {
+    fn name(&self) -> &'static str {
+        "syscalls"
+    }
+
+    fn platforms(&self) -> &'static [&'static str] {
+        &["linux"]
+    }
+
+    fn unavailable(&self) -> Option<String> {
+        self.programs.as_ref().err().cloned()
+    }
+
+    fn register_metrics(&self, registry: &prometheus::Registry) -> prometheus::Result<()> {
+        super::super::builder::register(registry, &self.families)
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.families]
+    }
+
+    fn reset_metrics(&self, _registry: &prometheus::Registry) {
+        self.families.reset();
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        let programs = self.programs.as_ref()?;
+        let mut errors: Vec<(String, String, u64)> = programs
+            .errors
+            .entries()
+            .map_err(|e| format!("reading syscall errors: {}", e))?
+            .into_iter()
+            .map(|(key, value)| {
+                let call = i32::from_ne_bytes(key[..4].try_into().unwrap());
+                let errno = i32::from_ne_bytes(key[4..].try_into().unwrap());
+                (syscall_name(call), errno_name(errno), words(&value)[0])
+            })
+            .collect();
+        errors.sort();
+        let errors = errors
+            .iter()
+            .map(|(call, errno, count)| counter(&[("syscall", call), ("errno", errno)], *count))
+            .collect();
+        self.families.set(&[MetricType::COUNTER], vec![errors]);
+        Ok(())
+    }
+}
+
+/// The name of a native system call, or its number for ones not listed
+/// here. Calls of 32-bit programs on a 64-bit kernel have numbers of their
+/// own, so they may be named after a different call.
+fn syscall_name(call: i32) -> String {
+    SYSCALLS
+        .iter()
+        .find(|(number, _)| *number == call as libc::c_long)
+        .map_or_else(|| call.to_string(), |(_, name)| name.to_string())
+}
+
+fn errno_name(errno: i32) -> String {
+    ERRNOS
+        .iter()
+        .find(|(number, _)| *number == errno)
+        .map_or_else(|| errno.to_string(), |(_, name)| name.to_string())
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const SYSCALLS: &[(libc::c_long, &str)] = &[
+    (libc::SYS_read, "read"),
+    (libc::SYS_write, "write"),
+    (libc::SYS_openat, "openat"),
+    (libc::SYS_openat2, "openat2"),
+    (libc::SYS_close, "close"),
+    (libc::SYS_fstat, "fstat"),
+    (libc::SYS_newfstatat, "newfstatat"),
+    (libc::SYS_statx, "statx"),
+    (libc::SYS_statfs, "statfs"),
+    (libc::SYS_fstatfs, "fstatfs"),
+    (libc::SYS_lseek, "lseek"),
+    (libc::SYS_mmap, "mmap"),
+    (libc::SYS_mprotect, "mprotect"),
+    (libc::SYS_munmap, "munmap"),
+    (libc::SYS_mremap, "mremap"),
+    (libc::SYS_madvise, "madvise"),
+    (libc::SYS_mlock, "mlock"),
+    (libc::SYS_brk, "brk"),
+    (libc::SYS_ioctl, "ioctl"),
+    (libc::SYS_pread64, "pread64"),
+    (libc::SYS_pwrite64, "pwrite64"),
+    (libc::SYS_readv, "readv"),
+    (libc::SYS_writev, "writev"),
+    (libc::SYS_faccessat, "faccessat"),
+    (libc::SYS_faccessat2, "faccessat2"),
+    (libc::SYS_dup, "dup"),
+    (libc::SYS_dup3, "dup3"),
+    (libc::SYS_fcntl, "fcntl"),
+    (libc::SYS_flock, "flock"),
+    (libc::SYS_fsync, "fsync"),
+    (libc::SYS_fdatasync, "fdatasync"),
+    (libc::SYS_truncate, "truncate"),
+    (libc::SYS_ftruncate, "ftruncate"),
+    (libc::SYS_getdents64, "getdents64"),
+    (libc::SYS_chdir, "chdir"),
+    (libc::SYS_renameat2, "renameat2"),
+    (libc::SYS_mkdirat, "mkdirat"),
+    (libc::SYS_unlinkat, "unlinkat"),
+    (libc::SYS_symlinkat, "symlinkat"),
+    (libc::SYS_linkat, "linkat"),
+    (libc::SYS_readlinkat, "readlinkat"),
+    (libc::SYS_fchmodat, "fchmodat"),
+    (libc::SYS_fchownat, "fchownat"),
+    (libc::SYS_splice, "splice"),
+    (libc::SYS_copy_file_range, "copy_file_range"),
+    (libc::SYS_inotify_add_watch, "inotify_add_watch"),
+    (libc::SYS_memfd_create, "memfd_create"),
+    (libc::SYS_getrandom, "getrandom"),
+    (libc::SYS_socket, "socket"),
+    (libc::SYS_connect, "connect"),
+    (libc::SYS_accept, "accept"),
+    (libc::SYS_accept4, "accept4"),
+    (libc::SYS_sendto, "sendto"),
+    (libc::SYS_recvfrom, "recvfrom"),
+    (libc::SYS_sendmsg, "sendmsg"),
+    (libc::SYS_recvmsg, "recvmsg"),
+    (libc::SYS_shutdown, "shutdown"),
+    (libc::SYS_bind, "bind"),
+    (libc::SYS_listen, "listen"),
+    (libc::SYS_setsockopt, "setsockopt"),
+    (libc::SYS_getsockopt, "getsockopt"),
+    (libc::SYS_epoll_create1, "epoll_create1"),
+    (libc::SYS_epoll_ctl, "epoll_ctl"),
+    (libc::SYS_epoll_pwait, "epoll_pwait"),
+    (libc::SYS_ppoll, "ppoll"),
+    (libc::SYS_pselect6, "pselect6"),
+    (libc::SYS_pipe2, "pipe2"),
+    (libc::SYS_io_uring_enter, "io_uring_enter"),
+    (libc::SYS_futex, "futex"),
+    (libc::SYS_nanosleep, "nanosleep"),
+    (libc::SYS_clock_nanosleep, "clock_nanosleep"),
+    (libc::SYS_rt_sigtimedwait, "rt_sigtimedwait"),
+    (libc::SYS_clone, "clone"),
+    (libc::SYS_clone3, "clone3"),
+    (libc::SYS_execve, "execve"),
+    (libc::SYS_execveat, "execveat"),
+    (libc::SYS_wait4, "wait4"),
+    (libc::SYS_waitid, "waitid"),
+    (libc::SYS_kill, "kill"),
+    (libc::SYS_tgkill, "tgkill"),
+    (libc::SYS_prctl, "prctl"),
+    (libc::SYS_ptrace, "ptrace"),
+    (libc::SYS_mount, "mount"),
+    (libc::SYS_umount2, "umount2"),
+    (libc::SYS_setns, "setns"),
+    (libc::SYS_unshare, "unshare"),
+    (libc::SYS_bpf, "bpf"),
+    (libc::SYS_perf_event_open, "perf_event_open"),
+    (libc::SYS_keyctl, "keyctl"),
+    (libc::SYS_finit_module, "finit_module"),
+    // Only in the older x86_64 table
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_open, "open"),
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_stat, "stat"),
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_lstat, "lstat"),
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_access, "access"),
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_poll, "poll"),
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_select, "select"),
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_epoll_wait, "epoll_wait"),
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_pipe, "pipe"),
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_dup2, "dup2"),
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_rename, "rename"),
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_renameat, "renameat"),
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_mkdir, "mkdir"),
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_rmdir, "rmdir"),
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_unlink, "unlink"),
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_readlink, "readlink"),
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_chmod, "chmod"),
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_chown, "chown"),
+    #[cfg(target_arch = "x86_64")]
+    (libc::SYS_sendfile, "sendfile"),
+];
+
+/// Numbers differ between architectures, so other ones get numbers only
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const SYSCALLS: &[(libc::c_long, &str)] = &[];
+
+const ERRNOS: &[(i32, &str)] = &[
+    (libc::EPERM, "EPERM"),
+    (libc::ENOENT, "ENOENT"),
+    (libc::ESRCH, "ESRCH"),
+    (libc::EINTR, "EINTR"),
+    (libc::EIO, "EIO"),
+    (libc::ENXIO, "ENXIO"),
+    (libc::E2BIG, "E2BIG"),
+    (libc::ENOEXEC, "ENOEXEC"),
+    (libc::EBADF, "EBADF"),
+    (libc::ECHILD, "ECHILD"),
+    (libc::EAGAIN, "EAGAIN"),
+    (libc::ENOMEM, "ENOMEM"),
+    (libc::EACCES, "EACCES"),
+    (libc::EFAULT, "EFAULT"),
+    (libc::EBUSY, "EBUSY"),
+    (libc::EEXIST, "EEXIST"),
+    (libc::EXDEV, "EXDEV"),
+    (libc::ENODEV, "ENODEV"),
+    (libc::ENOTDIR, "ENOTDIR"),
+    (libc::EISDIR, "EISDIR"),
+    (libc::EINVAL, "EINVAL"),
+    (libc::ENFILE, "ENFILE"),
+    (libc::EMFILE, "EMFILE"),
+    (libc::ENOTTY, "ENOTTY"),
+    (libc::EFBIG, "EFBIG"),
+    (libc::ENOSPC, "ENOSPC"),
+    (libc::ESPIPE, "ESPIPE"),
+    (libc::EROFS, "EROFS"),
+    (libc::EPIPE, "EPIPE"),
+    (libc::ERANGE, "ERANGE"),
+    (libc::EDEADLK, "EDEADLK"),
+    (libc::ENAMETOOLONG, "ENAMETOOLONG"),
+    (libc::ENOSYS, "ENOSYS"),
+    (libc::ENOTEMPTY, "ENOTEMPTY"),
+    (libc::ELOOP, "ELOOP"),
+    (libc::ENODATA, "ENODATA"),
+    (libc::ETIME, "ETIME"),
+    (libc::EOVERFLOW, "EOVERFLOW"),
+    (libc::ENOTSOCK, "ENOTSOCK"),
+    (libc::EMSGSIZE, "EMSGSIZE"),
+    (libc::EPROTONOSUPPORT, "EPROTONOSUPPORT"),
+    (libc::EOPNOTSUPP, "EOPNOTSUPP"),
+    (libc::EAFNOSUPPORT, "EAFNOSUPPORT"),
+    (libc::EADDRINUSE, "EADDRINUSE"),
+    (libc::EADDRNOTAVAIL, "EADDRNOTAVAIL"),
+    (libc::ENETUNREACH, "ENETUNREACH"),
+    (libc::ECONNABORTED, "ECONNABORTED"),
+    (libc::ECONNRESET, "ECONNRESET"),
+    (libc::ENOBUFS, "ENOBUFS"),
+    (libc::EISCONN, "EISCONN"),
+    (libc::ENOTCONN, "ENOTCONN"),
+    (libc::ETIMEDOUT, "ETIMEDOUT"),
+    (libc::ECONNREFUSED, "ECONNREFUSED"),
+    (libc::EHOSTUNREACH, "EHOSTUNREACH"),
+    (libc::EALREADY, "EALREADY"),
+    (libc::EINPROGRESS, "EINPROGRESS"),
+    (libc::ECANCELED, "ECANCELED"),
+    // The kernel's own, seen before a call interrupted by a signal is
+    // restarted or fails with EINTR (include/linux/errno.h)
+    (512, "ERESTARTSYS"),
+    (513, "ERESTARTNOINTR"),
+    (514, "ERESTARTNOHAND"),
+    (516, "ERESTART_RESTARTBLOCK"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_calls_and_errnos() {
+        assert_eq!(syscall_name(libc::SYS_openat as i32), "openat");
+        assert_eq!(syscall_name(100_000), "100000");
+        assert_eq!(errno_name(libc::ENOENT), "ENOENT");
+        assert_eq!(errno_name(512), "ERESTARTSYS");
+        assert_eq!(errno_name(4000), "4000");
+    }
+
+    /// Loading needs root and a kernel with the tracepoint, so this only
+    /// checks the verifier accepts the program where it can
+    #[test]
+    fn counts_failing_calls() {
+        let config = Config::parse("").unwrap();
+        let collector = SyscallsCollector::new(&config);
+        if let Some(reason) = collector.unavailable() {
+            eprintln!("skipping, the syscalls collector can't load: {}", reason);
+            return;
+        }
+        let missing = std::env::temp_dir().join(format!("metrixd-missing-{}", std::process::id()));
+        assert!(std::fs::File::open(&missing).is_err());
+        collector.collect_metrics().unwrap();
+        let families = collector.families.collect();
+        assert!(families[0].get_metric().iter().any(|metric| {
+            let labels = metric.get_label();
+            labels[0].value() == "openat" && labels[1].value() == "ENOENT"
+        }));
+    }
+}
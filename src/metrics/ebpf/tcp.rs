@@ -0,0 +1,332 @@
+//! TCP connection rates, retransmits and round-trip times per cgroup.
+//!
+//! Tracepoints mostly fire in softirq context, where the current task is
+//! whatever was interrupted, so sockets are attributed where a task is
+//! known: the one calling connect() when a socket enters SYN_SENT, and the
+//! one calling listen() for sockets accepted on its port. Retransmits and
+//! RTTs are then counted for the owner of their socket, or `unknown` for
+//! sockets opened before the programs were loaded.
+
+use std::collections::BTreeMap;
+
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::proto::MetricType;
+
+use super::asm::*;
+use super::bpf::{self, Attachment, Format, Map};
+use super::{counter, log2_histogram, words, CgroupNames, Families};
+use crate::collector::Collector;
+use crate::config::Config;
+
+// include/net/tcp_states.h
+const TCP_ESTABLISHED: i32 = 1;
+const TCP_SYN_SENT: i32 = 2;
+const TCP_SYN_RECV: i32 = 3;
+const TCP_CLOSE: i32 = 7;
+const TCP_LISTEN: i32 = 10;
+const IPPROTO_TCP: i32 = 6;
+
+/// RTT slots: powers of two of microseconds, up to about 8s
+const RTT_SLOTS: usize = 24;
+
+// Per cgroup counts, as u64s in this order
+const CONNECTS_OUT: i16 = 0;
+const CONNECTS_IN: i16 = 8;
+const RETRANSMITS: i16 = 16;
+const RTT_SUM: i16 = 24;
+const RTT_SLOT: i16 = 32;
+const STATS_SIZE: usize = 32 + RTT_SLOTS * 8;
+
+// Stack slots of the programs, below r10
+const SOCKET_KEY: i16 = -8;
+const CGROUP_KEY: i16 = -16;
+const PORT_KEY: i16 = -24;
+const SCRATCH: i16 = PORT_KEY - STATS_SIZE as i16;
+
+pub struct TcpCollector {
+    programs: Result<Programs, String>,
+    families: Families,
+    cgroups: CgroupNames,
+}
+
+struct Programs {
+    stats: Map,
+    _attachments: Vec<Attachment>,
+}
+
+impl TcpCollector {
+    pub fn new(config: &Config) -> Self {
+        TcpCollector {
+            programs: super::load(config, "tcp", Programs::load),
+            families: Families::new(&[
+                (
+                    "tcp_connections_total",
+                    "TCP connections opened, by the cgroup of the process connecting or listening",
+                    &["cgroup", "direction"],
+                ),
+                (
+                    "tcp_retransmits_total",
+                    "TCP segments retransmitted, by the cgroup owning the socket",
+                    &["cgroup"],
+                ),
+                (
+                    "tcp_rtt_seconds",
+                    "Smoothed round-trip time on every segment received, by the cgroup owning the socket",
+                    &["cgroup"],
+                ),
+            ]),
+            cgroups: CgroupNames::new(),
+        }
+    }
+}
+
+impl Programs {
+    fn load() -> std::io::Result<Programs> {
+        // Socket address to the cgroup that opened it
+        let owners = Map::lru_hash(8, 8, 65536)?;
+        // Local port to the cgroup listening on it
+        let listeners = Map::lru_hash(4, 8, 4096)?;
+        let stats = Map::lru_hash(8, STATS_SIZE, 4096)?;
+
+        let set_state = set_state_program(
+            &Format::read("sock", "inet_sock_set_state")?,
+            &owners,
+            &listeners,
+            &stats,
+        )?;
+        let retransmit =
+            retransmit_program(&Format::read("tcp", "tcp_retransmit_skb")?, &owners, &stats)?;
+        let probe = probe_program(&Format::read("tcp", "tcp_probe")?, &owners, &stats)?;
+        let attachments = vec![
+            bpf::attach("tcp_set_state", &set_state, "sock", "inet_sock_set_state")?,
+            bpf::attach("tcp_retransmit", &retransmit, "tcp", "tcp_retransmit_skb")?,
+            bpf::attach("tcp_probe", &probe, "tcp", "tcp_probe")?,
+        ];
+        Ok(Programs {
+            stats,
+            _attachments: attachments,
+        })
+    }
+}
+
+/// Store the calling task's cgroup at CGROUP_KEY
+fn current_cgroup(asm: &mut Asm) {
+    asm.call(GET_CURRENT_CGROUP_ID)
+        .store(DW, R10, CGROUP_KEY, R0);
+}
+
+/// Add one to the u64 at `field` of the cgroup's stats
+fn count(asm: &mut Asm, stats: &Map, field: i16) {
+    asm.lookup_or_init(stats, CGROUP_KEY, SCRATCH, "out")
+        .mov_imm(R1, 1)
+        .atomic_add(R0, field, R1);
+}
+
+/// Store the owner of the socket at SOCKET_KEY at CGROUP_KEY, or 0
+fn owner(asm: &mut Asm, owners: &Map) {
+    let unknown = asm.fresh_label();
+    asm.store_imm(DW, R10, CGROUP_KEY, 0)
+        .lookup(owners, SOCKET_KEY)
+        .jump_imm(JEQ, R0, 0, &unknown)
+        .load(DW, R1, R0, 0)
+        .store(DW, R10, CGROUP_KEY, R1)
+        .label(&unknown);
+}
+
+fn set_state_program(
+    format: &Format,
+    owners: &Map,
+    listeners: &Map,
+    stats: &Map,
+) -> std::io::Result<Vec<Insn>> {
+    let protocol = format.offset("protocol", 2)?;
+    let socket = format.offset("skaddr", 8)?;
+    let old = format.offset("oldstate", 4)?;
+    let new = format.offset("newstate", 4)?;
+    let port = format.offset("sport", 2)?;
+
+    let mut asm = Asm::new();
+    asm.mov(R6, R1)
+        .load(H, R1, R6, protocol)
+        .jump_imm(JNE, R1, IPPROTO_TCP, "out")
+        .load(DW, R1, R6, socket)
+        .store(DW, R10, SOCKET_KEY, R1)
+        .load(H, R1, R6, port)
+        .store(W, R10, PORT_KEY, R1)
+        .load(W, R8, R6, new)
+        .load(W, R9, R6, old)
+        .jump_imm(JEQ, R8, TCP_SYN_SENT, "connect")
+        .jump_imm(JEQ, R8, TCP_LISTEN, "listen")
+        .jump_imm(JEQ, R8, TCP_CLOSE, "close")
+        .jump_imm(JNE, R8, TCP_ESTABLISHED, "out")
+        .jump_imm(JNE, R9, TCP_SYN_RECV, "out");
+
+    // Accepted: owned by whoever listens on the port
+    asm.lookup(listeners, PORT_KEY)
+        .jump_imm(JEQ, R0, 0, "out")
+        .load(DW, R1, R0, 0)
+        .store(DW, R10, CGROUP_KEY, R1)
+        .update(owners, SOCKET_KEY, CGROUP_KEY, ANY);
+    count(&mut asm, stats, CONNECTS_IN);
+    asm.goto("out");
+
+    // connect() runs in the task opening the connection
+    asm.label("connect");
+    current_cgroup(&mut asm);
+    asm.update(owners, SOCKET_KEY, CGROUP_KEY, ANY);
+    count(&mut asm, stats, CONNECTS_OUT);
+    asm.goto("out");
+
+    // And listen() in the task that will accept
+    asm.label("listen");
+    current_cgroup(&mut asm);
+    asm.update(listeners, PORT_KEY, CGROUP_KEY, ANY).goto("out");
+
+    asm.label("close")
+        .delete(owners, SOCKET_KEY)
+        .jump_imm(JNE, R9, TCP_LISTEN, "out")
+        .delete(listeners, PORT_KEY);
+
+    asm.label("out").mov_imm(R0, 0).exit();
+    Ok(asm.finish())
+}
+
+fn retransmit_program(format: &Format, owners: &Map, stats: &Map) -> std::io::Result<Vec<Insn>> {
+    let socket = format.offset("skaddr", 8)?;
+
+    let mut asm = Asm::new();
+    asm.load(DW, R1, R1, socket).store(DW, R10, SOCKET_KEY, R1);
+    owner(&mut asm, owners);
+    count(&mut asm, stats, RETRANSMITS);
+    asm.label("out").mov_imm(R0, 0).exit();
+    Ok(asm.finish())
+}
+
+fn probe_program(format: &Format, owners: &Map, stats: &Map) -> std::io::Result<Vec<Insn>> {
+    let socket = format.offset("skaddr", 8)?;
+    // Already in microseconds
+    let srtt = format.offset("srtt", 4)?;
+
+    let mut asm = Asm::new();
+    asm.load(W, R7, R1, srtt)
+        .load(DW, R1, R1, socket)
+        .store(DW, R10, SOCKET_KEY, R1);
+    owner(&mut asm, owners);
+    asm.lookup_or_init(stats, CGROUP_KEY, SCRATCH, "out")
+        .mov(R9, R0)
+        .atomic_add(R9, RTT_SUM, R7)
+        .count_log2(R9, RTT_SLOT, R7, RTT_SLOTS);
+    asm.label("out").mov_imm(R0, 0).exit();
+    Ok(asm.finish())
+}
+
+impl Collector for TcpCollector {
+    fn name(&self) -> &'static str {
+        "tcp"
+    }
+
+    fn platforms(&self) -> &'static [&'static str] {
+        &["linux"]
+    }
+
+    fn unavailable(&self) -> Option<String> {
+        self.programs.as_ref().err().cloned()
+    }
+
+    fn register_metrics(&self, registry: &prometheus::Registry) -> prometheus::Result<()> {
+        super::super::builder::register(registry, &self.families)
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.families]
+    }
+
+    fn reset_metrics(&self, _registry: &prometheus::Registry) {
+        self.families.reset();
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        let programs = self.programs.as_ref()?;
+        let stats: BTreeMap<u64, Vec<u64>> = programs
+            .stats
+            .entries()
+            .map_err(|e| format!("reading TCP stats: {}", e))?
+            .into_iter()
+            .map(|(key, value)| (words(&key)[0], words(&value)))
+            .collect();
+        let names = self.cgroups.resolve(stats.keys().copied());
+
+        let (mut connections, mut retransmits, mut rtts) = (Vec::new(), Vec::new(), Vec::new());
+        for (id, values) in &stats {
+            let cgroup = names[id].as_str();
+            let field = |offset: i16| values[offset as usize / 8];
+            connections.push(counter(
+                &[("cgroup", cgroup), ("direction", "outbound")],
+                field(CONNECTS_OUT),
+            ));
+            connections.push(counter(
+                &[("cgroup", cgroup), ("direction", "inbound")],
+                field(CONNECTS_IN),
+            ));
+            retransmits.push(counter(&[("cgroup", cgroup)], field(RETRANSMITS)));
+            let slots = &values[RTT_SLOT as usize / 8..];
+            if slots.iter().any(|count| *count > 0) {
+                rtts.push(log2_histogram(
+                    &[("cgroup", cgroup)],
+                    slots,
+                    field(RTT_SUM) as f64 / 1e6,
+                    1e-6,
+                ));
+            }
+        }
+        self.families.set(
+            &[
+                MetricType::COUNTER,
+                MetricType::COUNTER,
+                MetricType::HISTOGRAM,
+            ],
+            vec![connections, retransmits, rtts],
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    /// Loading needs root and a kernel with the tracepoints, so this only
+    /// checks the verifier accepts the programs where it can
+    #[test]
+    fn counts_connections_of_this_process() {
+        let config = Config::parse("").unwrap();
+        let collector = TcpCollector::new(&config);
+        if let Some(reason) = collector.unavailable() {
+            eprintln!("skipping, the tcp collector can't load: {}", reason);
+            return;
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buffer = [0; 4];
+        server.read_exact(&mut buffer).unwrap();
+
+        collector.collect_metrics().unwrap();
+        let families = collector.families.collect();
+        let total = |direction: &str| -> f64 {
+            families[0]
+                .get_metric()
+                .iter()
+                .filter(|m| m.get_label().iter().any(|l| l.value() == direction))
+                .map(|m| m.get_counter().value())
+                .sum()
+        };
+        assert!(total("outbound") >= 1.0);
+        assert!(total("inbound") >= 1.0);
+    }
+}
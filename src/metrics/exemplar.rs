@@ -0,0 +1,134 @@
+//! Exemplars link a histogram observation to the trace it was made in, by
+//! `trace_id` and `span_id` labels taken from a W3C `traceparent`. Like the
+//! native histogram fields, they are written as raw protobuf fields, so
+//! only protobuf scrapes carry them.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::native_histogram::varint;
+
+pub struct Exemplar {
+    labels: Vec<(&'static str, String)>,
+    value: f64,
+    timestamp: SystemTime,
+}
+
+impl Exemplar {
+    /// An exemplar for `value` observed now, if `traceparent` is a valid
+    /// `version-trace_id-span_id-flags` header value
+    pub fn from_traceparent(traceparent: &str, value: f64) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+        // Later versions may append fields; version 00 must not
+        if version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        let hex = |field: &str, len: usize| {
+            field.len() == len
+                && field
+                    .bytes()
+                    .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        };
+        let zero = |field: &str| field.bytes().all(|b| b == b'0');
+        if !hex(version, 2) || !hex(flags, 2) || !hex(trace_id, 32) || !hex(span_id, 16) {
+            return None;
+        }
+        if zero(trace_id) || zero(span_id) {
+            return None;
+        }
+        Some(Exemplar {
+            labels: vec![
+                ("trace_id", trace_id.to_string()),
+                ("span_id", span_id.to_string()),
+            ],
+            value,
+            timestamp: SystemTime::now(),
+        })
+    }
+
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    /// The `io.prometheus.client.Exemplar` message
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut message = Vec::new();
+        for (name, value) in &self.labels {
+            // LabelPair { string name = 1; string value = 2; }
+            let mut label = Vec::new();
+            length_delimited(&mut label, 1, name.as_bytes());
+            length_delimited(&mut label, 2, value.as_bytes());
+            length_delimited(&mut message, 1, &label);
+        }
+        message.push(2 << 3 | 1);
+        message.extend_from_slice(&self.value.to_le_bytes());
+        // google.protobuf.Timestamp { int64 seconds = 1; int32 nanos = 2; }
+        let since_epoch = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut timestamp = Vec::new();
+        timestamp.push(1 << 3);
+        varint(&mut timestamp, since_epoch.as_secs());
+        timestamp.push(2 << 3);
+        varint(&mut timestamp, since_epoch.subsec_nanos().into());
+        length_delimited(&mut message, 3, &timestamp);
+        message
+    }
+}
+
+fn length_delimited(out: &mut Vec<u8>, field: u8, bytes: &[u8]) {
+    out.push(field << 3 | 2);
+    varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_trace_and_span_ids_from_traceparent() {
+        let exemplar = Exemplar::from_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            0.25,
+        )
+        .unwrap();
+        assert_eq!(
+            exemplar.labels,
+            vec![
+                ("trace_id", "4bf92f3577b34da6a3ce929d0e0e4736".to_string()),
+                ("span_id", "00f067aa0ba902b7".to_string()),
+            ]
+        );
+        assert_eq!(exemplar.value(), 0.25);
+    }
+
+    #[test]
+    fn rejects_invalid_traceparents() {
+        for traceparent in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+        ] {
+            assert!(
+                Exemplar::from_traceparent(traceparent, 1.0).is_none(),
+                "{}",
+                traceparent
+            );
+        }
+        // Future versions may carry more fields
+        assert!(Exemplar::from_traceparent(
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            1.0
+        )
+        .is_some());
+    }
+}
@@ -0,0 +1,339 @@
+//! Federation: scrape the `[federation] peers`, other metrixd agents, every
+//! cycle and re-expose their series with an `instance` label naming the
+//! peer, so one routable host can serve a whole edge site. Peers are asked
+//! for the protobuf format, which keeps histograms, summaries and
+//! exemplars intact on the way through.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use prometheus::core::{Collector as PrometheusCollector, Desc};
+use prometheus::proto::{LabelPair, MetricFamily};
+use prometheus::{GaugeVec, Opts};
+use protobuf::CodedInputStream;
+
+use crate::collector::Collector;
+use crate::config::Config;
+use crate::fetch;
+
+const ACCEPT: &str =
+    "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited";
+
+pub struct FederationCollector {
+    peers: Vec<String>,
+    timeout: Duration,
+    federated: Federated,
+}
+
+/// The peers' last successful scrapes, plus whether each one answered
+#[derive(Clone)]
+struct Federated {
+    up: GaugeVec,
+    scrape_duration: GaugeVec,
+    /// `None` for peers whose last scrape failed
+    scrapes: Arc<Mutex<BTreeMap<String, Option<Scrape>>>>,
+    /// Scrapes older than this are no longer exposed, e.g. once the
+    /// collector has been disabled
+    max_age: Duration,
+}
+
+/// When a peer was scraped, and what it returned
+type Scrape = (Instant, Vec<MetricFamily>);
+
+impl FederationCollector {
+    pub fn new(config: &Config) -> Self {
+        let opts = |name: &str, help: &str| {
+            Opts::new(name, help)
+                .namespace("metrixd")
+                .subsystem("federation")
+        };
+        let federated = Federated {
+            up: GaugeVec::new(
+                opts("peer_up", "Whether the last scrape of the peer succeeded"),
+                &["instance"],
+            )
+            .expect("invalid federation metrics"),
+            scrape_duration: GaugeVec::new(
+                opts(
+                    "peer_scrape_duration_seconds",
+                    "How long the last scrape of the peer took",
+                ),
+                &["instance"],
+            )
+            .expect("invalid federation metrics"),
+            scrapes: Arc::default(),
+            max_age: config.collection.interval * 3 + config.federation.timeout,
+        };
+        FederationCollector {
+            peers: config.federation.peers.clone(),
+            timeout: config.federation.timeout,
+            federated,
+        }
+    }
+
+    fn record(&self, peer: &str, result: Result<Vec<MetricFamily>, String>, took: Duration) {
+        let mut scrapes = self.federated.scrapes.lock().unwrap();
+        self.federated
+            .scrape_duration
+            .with_label_values(&[peer])
+            .set(took.as_secs_f64());
+        // Log only when a peer comes up or goes down, not every cycle
+        let was_up = scrapes.get(peer).map(Option::is_some);
+        match result {
+            Ok(families) => {
+                if was_up != Some(true) {
+                    println!("Federating peer {}", peer);
+                }
+                self.federated.up.with_label_values(&[peer]).set(1.0);
+                scrapes.insert(peer.to_string(), Some((Instant::now(), families)));
+            }
+            Err(e) => {
+                // Its series go away until it answers again
+                if was_up != Some(false) {
+                    eprintln!("Federation peer {} is down: {}", peer, e);
+                }
+                self.federated.up.with_label_values(&[peer]).set(0.0);
+                scrapes.insert(peer.to_string(), None);
+            }
+        }
+    }
+}
+
+impl Collector for FederationCollector {
+    fn name(&self) -> &'static str {
+        "federation"
+    }
+
+    fn register_metrics(&self, registry: &prometheus::Registry) -> prometheus::Result<()> {
+        super::builder::register(registry, &self.federated)
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.federated]
+    }
+
+    fn reset_metrics(&self, _registry: &prometheus::Registry) {
+        self.federated.up.reset();
+        self.federated.scrape_duration.reset();
+        self.federated.scrapes.lock().unwrap().clear();
+    }
+
+    /// Fails only if no peer answered, so one unreachable site doesn't
+    /// back off the others
+    fn collect_metrics(&self) -> Result<(), String> {
+        if self.peers.is_empty() {
+            return Ok(());
+        }
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let scrapes: Vec<_> = self
+                .peers
+                .iter()
+                .map(|peer| {
+                    scope.spawn(move || {
+                        let started = Instant::now();
+                        (scrape(peer, self.timeout), started.elapsed())
+                    })
+                })
+                .collect();
+            scrapes.into_iter().map(|s| s.join().unwrap()).collect()
+        });
+
+        let mut first_error = None;
+        let mut answered = 0;
+        for (peer, (result, took)) in self.peers.iter().zip(results) {
+            match &result {
+                Ok(_) => answered += 1,
+                Err(e) => {
+                    first_error.get_or_insert_with(|| e.clone());
+                }
+            }
+            self.record(peer, result, took);
+        }
+        match first_error {
+            Some(e) if answered == 0 => Err(format!("no peer answered: {}", e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl PrometheusCollector for Federated {
+    fn desc(&self) -> Vec<&Desc> {
+        self.up
+            .desc()
+            .into_iter()
+            .chain(self.scrape_duration.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.up.collect();
+        families.extend(self.scrape_duration.collect());
+        let scrapes = self.scrapes.lock().unwrap();
+        for (scraped, peer_families) in scrapes.values().flatten() {
+            if scraped.elapsed() <= self.max_age {
+                families.extend(peer_families.iter().cloned());
+            }
+        }
+        families
+    }
+}
+
+/// The peer's metric families, labelled with `instance="<peer>"`
+fn scrape(peer: &str, timeout: Duration) -> Result<Vec<MetricFamily>, String> {
+    let response = fetch::request(peer, "GET", "/metrics", &[("Accept", ACCEPT)], timeout)?;
+    if response.status != 200 {
+        return Err(format!("{}: HTTP status {}", peer, response.status));
+    }
+    let protobuf = response
+        .header("Content-Type")
+        .is_some_and(|content_type| content_type.starts_with("application/vnd.google.protobuf"));
+    if !protobuf {
+        return Err(format!("{}: did not answer in the protobuf format", peer));
+    }
+    let mut families = decode(&response.body).map_err(|e| format!("{}: {}", peer, e))?;
+    add_instance_label(&mut families, peer);
+    Ok(families)
+}
+
+/// Length-delimited `MetricFamily` messages, as served for `encoding=delimited`
+fn decode(body: &[u8]) -> protobuf::Result<Vec<MetricFamily>> {
+    let mut input = CodedInputStream::from_bytes(body);
+    let mut families = Vec::new();
+    while !input.eof()? {
+        families.push(input.read_message()?);
+    }
+    Ok(families)
+}
+
+/// Series that already have an `instance` label keep it as
+/// `exported_instance`, like Prometheus does with `honor_labels: false`
+fn add_instance_label(families: &mut [MetricFamily], peer: &str) {
+    for metric in families.iter_mut().flat_map(|family| family.mut_metric()) {
+        let mut labels = metric.take_label();
+        for label in labels.iter_mut().filter(|label| label.name() == "instance") {
+            label.set_name("exported_instance".to_string());
+        }
+        let mut instance = LabelPair::default();
+        instance.set_name("instance".to_string());
+        instance.set_value(peer.to_string());
+        labels.push(instance);
+        labels.sort_by(|a, b| a.name().cmp(b.name()));
+        metric.set_label(labels);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{Encoder, IntCounterVec, ProtobufEncoder, Registry};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// A peer exposition with one `requests_total` series
+    fn peer_body() -> Vec<u8> {
+        let registry = Registry::new();
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "Requests served"),
+            &["instance", "path"],
+        )
+        .unwrap();
+        registry.register(Box::new(requests.clone())).unwrap();
+        requests.with_label_values(&["app-1", "/"]).inc_by(3);
+        let mut body = Vec::new();
+        ProtobufEncoder::new()
+            .encode(&registry.gather(), &mut body)
+            .unwrap();
+        body
+    }
+
+    /// Answers one scrape with `peer_body`
+    fn fake_peer() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 4096];
+            let _ = stream.read(&mut request).unwrap();
+            let body = peer_body();
+            let head = format!(
+                "HTTP/1.0 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+                ACCEPT,
+                body.len()
+            );
+            let _ = stream.write_all(head.as_bytes());
+            let _ = stream.write_all(&body);
+        });
+        address
+    }
+
+    #[test]
+    fn exposes_series_of_peers_that_answered() {
+        let peer = fake_peer();
+        // Nothing listens on port 1
+        let config = Config::parse(&format!(
+            "[federation]\npeers = [\"{}\", \"127.0.0.1:1\"]\ntimeout = \"1s\"\n",
+            peer
+        ))
+        .unwrap();
+        let collector = FederationCollector::new(&config);
+        collector.collect_metrics().unwrap();
+
+        let families = collector.federated.collect();
+        let value = |name: &str, instance: &str| {
+            families
+                .iter()
+                .filter(|family| family.name() == name)
+                .flat_map(|family| family.get_metric())
+                .find(|metric| {
+                    metric
+                        .get_label()
+                        .iter()
+                        .any(|label| label.name() == "instance" && label.value() == instance)
+                })
+                .map(|metric| metric.get_gauge().value() + metric.get_counter().value())
+        };
+        assert_eq!(value("metrixd_federation_peer_up", &peer), Some(1.0));
+        assert_eq!(
+            value("metrixd_federation_peer_up", "127.0.0.1:1"),
+            Some(0.0)
+        );
+        assert_eq!(value("requests_total", &peer), Some(3.0));
+        assert_eq!(value("requests_total", "127.0.0.1:1"), None);
+    }
+
+    #[test]
+    fn fails_when_no_peer_answers() {
+        let config = Config::parse("[federation]\npeers = [\"127.0.0.1:1\"]\n").unwrap();
+        let collector = FederationCollector::new(&config);
+        assert!(collector.collect_metrics().is_err());
+    }
+
+    #[test]
+    fn decodes_delimited_families_and_labels_them_with_the_peer() {
+        let mut families = decode(&peer_body()).unwrap();
+        add_instance_label(&mut families, "edge-1:9100");
+        assert_eq!(families.len(), 1);
+        let metric = &families[0].get_metric()[0];
+        let labels: Vec<_> = metric
+            .get_label()
+            .iter()
+            .map(|label| (label.name(), label.value()))
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                ("exported_instance", "app-1"),
+                ("instance", "edge-1:9100"),
+                ("path", "/"),
+            ]
+        );
+        assert_eq!(metric.get_counter().value(), 3.0);
+    }
+
+    #[test]
+    fn rejects_truncated_bodies() {
+        assert!(decode(&[0x0a, 0x05, 0x0a]).is_err());
+        assert!(decode(&[]).unwrap().is_empty());
+    }
+}
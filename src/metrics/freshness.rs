@@ -0,0 +1,117 @@
+//! Freshness checks: the age of each of `freshness.files`, such as a
+//! backup's success marker or a heartbeat file a pipeline touches, so
+//! `file_age_seconds > 26 * 3600` catches a cron job that stopped running
+//! without anyone having to notice. A missing file sets `file_exists` to 0
+//! and drops its age, so a deleted marker alerts too.
+
+use super::builder::metric;
+use super::demo::Simulation;
+use crate::collector::Collector;
+use crate::config::Config;
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::GaugeVec;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+pub struct FreshnessCollector {
+    age_seconds: GaugeVec,
+    exists: GaugeVec,
+    files: Vec<PathBuf>,
+    simulation: Option<Simulation>,
+}
+
+impl FreshnessCollector {
+    pub fn new(config: &Config) -> Self {
+        FreshnessCollector {
+            age_seconds: metric("file_age_seconds", "Time since the file was last modified")
+                .gauge_vec(&["path"]),
+            exists: metric("file_exists", "Whether the file exists").gauge_vec(&["path"]),
+            files: config.freshness.files.clone(),
+            simulation: config.demo.map(|seed| Simulation::new(seed, "freshness")),
+        }
+    }
+
+    fn set(&self, path: &Path, age: Option<f64>) {
+        let path = path.to_string_lossy();
+        let labels = [path.as_ref()];
+        self.exists
+            .with_label_values(&labels)
+            .set(age.is_some() as u8 as f64);
+        match age {
+            Some(age) => self.age_seconds.with_label_values(&labels).set(age),
+            None => {
+                let _ = self.age_seconds.remove_label_values(&labels);
+            }
+        }
+    }
+
+    fn simulate(&self, simulation: &Simulation) {
+        // Touched every minute, like a heartbeat file
+        for path in &self.files {
+            self.set(path, Some(simulation.between(0.0, 60.0).floor()));
+        }
+    }
+}
+
+impl Collector for FreshnessCollector {
+    fn name(&self) -> &'static str {
+        "freshness"
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
+        Ok(())
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.age_seconds, &self.exists]
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        if let Some(simulation) = &self.simulation {
+            self.simulate(simulation);
+            return Ok(());
+        }
+        let now = SystemTime::now();
+        for path in &self.files {
+            self.set(path, age(path, now));
+        }
+        Ok(())
+    }
+}
+
+/// Seconds since `path` was modified, 0 for a modification time in the
+/// future; none if it doesn't exist or can't be read
+fn age(path: &Path, now: SystemTime) -> Option<f64> {
+    let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+    Some(
+        now.duration_since(modified)
+            .unwrap_or_default()
+            .as_secs_f64(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn ages_of_files() {
+        let path = std::env::temp_dir().join(format!("metrixd-freshness-{}", std::process::id()));
+        std::fs::write(&path, "ok").unwrap();
+        let modified = SystemTime::now() - Duration::from_secs(3600);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let now = modified + Duration::from_secs(3600);
+        assert_eq!(age(&path, now), Some(3600.0));
+        assert_eq!(age(&path, modified - Duration::from_secs(1)), Some(0.0));
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(age(&path, now), None);
+    }
+}
@@ -0,0 +1,242 @@
+//! Active storage checks: every cycle, write, fsync and read back a 4KiB
+//! file in each of `fsprobe.paths`, timing each step. A failing disk or a
+//! saturated NFS server shows up in these latencies before throughput
+//! counters move. Each probe runs in a thread of its own, so one hung on
+//! a dead mount only marks its path as stalled.
+
+use super::builder::metric;
+use super::demo::Simulation;
+use crate::collector::Collector;
+use crate::config::Config;
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::{CounterVec, GaugeVec, HistogramVec};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Kept between probes, so only the first one creates a file
+const PROBE_FILE: &str = ".metrixd-probe";
+
+const PROBE_SIZE: usize = 4096;
+
+struct Probe {
+    path: PathBuf,
+    /// When the probe still running started
+    running_since: Arc<Mutex<Option<Instant>>>,
+}
+
+pub struct FsprobeCollector {
+    duration_seconds: HistogramVec,
+    errors_total: CounterVec,
+    stalled: GaugeVec,
+    probes: Vec<Probe>,
+    timeout: Duration,
+    simulation: Option<Simulation>,
+}
+
+impl FsprobeCollector {
+    pub fn new(config: &Config) -> Self {
+        FsprobeCollector {
+            duration_seconds: metric(
+                "fsprobe_duration_seconds",
+                "Time to write, fsync or read back the probe file",
+            )
+            .buckets(config.histogram_buckets(
+                "fsprobe_duration_seconds",
+                vec![
+                    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+                ],
+            ))
+            .histogram_vec(&["path", "operation"]),
+            errors_total: metric(
+                "fsprobe_errors_total",
+                "Probe steps that failed, such as a write to a read-only file system",
+            )
+            .counter_vec(&["path", "operation"]),
+            stalled: metric(
+                "fsprobe_stalled",
+                "Whether the probe has been running for longer than fsprobe.timeout",
+            )
+            .gauge_vec(&["path"]),
+            probes: config
+                .fsprobe
+                .paths
+                .iter()
+                .map(|path| Probe {
+                    path: path.clone(),
+                    running_since: Arc::new(Mutex::new(None)),
+                })
+                .collect(),
+            timeout: config.fsprobe.timeout,
+            simulation: config.demo.map(|seed| Simulation::new(seed, "fsprobe")),
+        }
+    }
+
+    fn simulate(&self, simulation: &Simulation) {
+        for probe in &self.probes {
+            let path = probe.path.to_string_lossy();
+            for (operation, low, high) in [
+                ("write", 0.00002, 0.0002),
+                ("fsync", 0.0005, 0.02),
+                ("read", 0.00001, 0.0001),
+            ] {
+                self.duration_seconds
+                    .with_label_values(&[&path, operation])
+                    .observe(simulation.between(low, high));
+            }
+            self.stalled.with_label_values(&[&path]).set(0.0);
+        }
+    }
+}
+
+impl Collector for FsprobeCollector {
+    fn name(&self) -> &'static str {
+        "fsprobe"
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
+        Ok(())
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.duration_seconds, &self.errors_total, &self.stalled]
+    }
+
+    /// Waits up to `fsprobe.timeout` for this cycle's probes and fails
+    /// with the first error among them
+    fn collect_metrics(&self) -> Result<(), String> {
+        if let Some(simulation) = &self.simulation {
+            self.simulate(simulation);
+            return Ok(());
+        }
+
+        let (done_tx, done_rx) = mpsc::channel();
+        let mut started = 0;
+        for probe in &self.probes {
+            let mut running_since = probe.running_since.lock().unwrap();
+            if running_since.is_some() {
+                continue;
+            }
+            *running_since = Some(Instant::now());
+            let path = probe.path.clone();
+            let running = Arc::clone(&probe.running_since);
+            let durations = self.duration_seconds.clone();
+            let errors = self.errors_total.clone();
+            let done = done_tx.clone();
+            let spawned = std::thread::Builder::new()
+                .name("fsprobe".to_string())
+                .spawn(move || {
+                    let label = path.to_string_lossy();
+                    let result = run(&path, |operation, elapsed| {
+                        durations
+                            .with_label_values(&[&label, operation])
+                            .observe(elapsed.as_secs_f64());
+                    })
+                    .map_err(|(operation, e)| {
+                        errors.with_label_values(&[&label, operation]).inc();
+                        format!("{}: {}: {}", label, operation, e)
+                    });
+                    *running.lock().unwrap() = None;
+                    let _ = done.send(result);
+                });
+            match spawned {
+                Ok(_) => started += 1,
+                Err(e) => {
+                    *running_since = None;
+                    return Err(format!("cannot start the probe thread: {}", e));
+                }
+            }
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        let mut first_error = None;
+        for _ in 0..started {
+            match done_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    first_error.get_or_insert(e);
+                }
+                // The rest are stalled
+                Err(_) => break,
+            }
+        }
+        for probe in &self.probes {
+            let stalled = probe
+                .running_since
+                .lock()
+                .unwrap()
+                .is_some_and(|since| since.elapsed() >= self.timeout);
+            self.stalled
+                .with_label_values(&[&probe.path.to_string_lossy()])
+                .set(stalled as u8 as f64);
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+}
+
+/// Write, fsync and read back the probe file in `dir`, passing each step's
+/// duration to `observe`; a failure names the step
+fn run(
+    dir: &Path,
+    mut observe: impl FnMut(&'static str, Duration),
+) -> Result<(), (&'static str, String)> {
+    let mut step = |operation, work: &mut dyn FnMut() -> std::io::Result<()>| {
+        let start = Instant::now();
+        work().map_err(|e| (operation, e.to_string()))?;
+        observe(operation, start.elapsed());
+        Ok(())
+    };
+
+    // Different each time, so a stale read can't pass for a fresh one
+    let stamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_le_bytes();
+    let data: Vec<u8> = stamp.iter().cycle().take(PROBE_SIZE).copied().collect();
+    let mut file: Option<File> = None;
+    step("write", &mut || {
+        let mut opened = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(PROBE_FILE))?;
+        opened.write_all(&data)?;
+        file = Some(opened);
+        Ok(())
+    })?;
+    let mut file = file.expect("opened by the write step");
+    step("fsync", &mut || file.sync_data())?;
+    step("read", &mut || {
+        let mut read = vec![0; PROBE_SIZE];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut read)?;
+        if read != data {
+            return Err(std::io::Error::other("read back different data"));
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_a_directory() {
+        let dir = std::env::temp_dir().join(format!("metrixd-fsprobe-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut steps = Vec::new();
+        run(&dir, |operation, _| steps.push(operation)).unwrap();
+        run(&dir, |_, _| {}).unwrap();
+        assert_eq!(steps, ["write", "fsync", "read"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let missing = run(&dir, |_, _| {}).unwrap_err();
+        assert_eq!(missing.0, "write");
+    }
+}
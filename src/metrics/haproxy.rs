@@ -0,0 +1,330 @@
+//! HAProxy: `[services.haproxy]` reads the statistics of every frontend,
+//! backend and server from the stats socket (`show stat`) or the stats
+//! page's CSV export, and re-exposes them as `haproxy_frontend_*`,
+//! `haproxy_backend_*` and `haproxy_server_*`.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use prometheus::core::Collector as PrometheusCollector;
+
+use super::service::{Reading, ServiceMetrics};
+use crate::collector::Collector;
+use crate::config::{Config, HaproxyConfig, HaproxyStats};
+use crate::fetch;
+
+/// CSV field, metric name after `haproxy_<type>_`, help, and whether it's
+/// a counter
+type Field = (&'static str, &'static str, &'static str, bool);
+
+const FRONTEND_FIELDS: &[Field] = &[
+    ("scur", "current_sessions", "Current sessions", false),
+    ("smax", "max_sessions", "Most sessions at once", false),
+    ("slim", "limit_sessions", "Configured session limit", false),
+    ("stot", "sessions_total", "Sessions", true),
+    (
+        "rate",
+        "current_session_rate",
+        "Sessions in the last second",
+        false,
+    ),
+    ("bin", "bytes_in_total", "Bytes received", true),
+    ("bout", "bytes_out_total", "Bytes sent", true),
+    (
+        "dreq",
+        "requests_denied_total",
+        "Requests denied by rules",
+        true,
+    ),
+    (
+        "dresp",
+        "responses_denied_total",
+        "Responses denied by rules",
+        true,
+    ),
+    ("ereq", "request_errors_total", "Request errors", true),
+    ("req_tot", "http_requests_total", "HTTP requests", true),
+];
+
+const BACKEND_FIELDS: &[Field] = &[
+    (
+        "qcur",
+        "current_queue",
+        "Requests waiting for a server",
+        false,
+    ),
+    ("scur", "current_sessions", "Current sessions", false),
+    ("smax", "max_sessions", "Most sessions at once", false),
+    ("stot", "sessions_total", "Sessions", true),
+    ("bin", "bytes_in_total", "Bytes received", true),
+    ("bout", "bytes_out_total", "Bytes sent", true),
+    (
+        "econ",
+        "connection_errors_total",
+        "Failed connections to servers",
+        true,
+    ),
+    ("eresp", "response_errors_total", "Response errors", true),
+    ("wretr", "retry_warnings_total", "Connection retries", true),
+    (
+        "wredis",
+        "redispatch_warnings_total",
+        "Requests sent to another server",
+        true,
+    ),
+    ("act", "active_servers", "Active servers", false),
+    ("bck", "backup_servers", "Backup servers", false),
+    ("weight", "weight", "Total weight of the servers", false),
+    (
+        "downtime",
+        "downtime_seconds_total",
+        "Time spent down",
+        true,
+    ),
+];
+
+const SERVER_FIELDS: &[Field] = &[
+    (
+        "qcur",
+        "current_queue",
+        "Requests waiting for the server",
+        false,
+    ),
+    ("scur", "current_sessions", "Current sessions", false),
+    ("smax", "max_sessions", "Most sessions at once", false),
+    ("stot", "sessions_total", "Sessions", true),
+    ("bin", "bytes_in_total", "Bytes received", true),
+    ("bout", "bytes_out_total", "Bytes sent", true),
+    (
+        "econ",
+        "connection_errors_total",
+        "Failed connections",
+        true,
+    ),
+    ("eresp", "response_errors_total", "Response errors", true),
+    ("wretr", "retry_warnings_total", "Connection retries", true),
+    (
+        "wredis",
+        "redispatch_warnings_total",
+        "Requests sent to another server",
+        true,
+    ),
+    ("weight", "weight", "Weight of the server", false),
+    (
+        "chkfail",
+        "check_failures_total",
+        "Failed health checks",
+        true,
+    ),
+    (
+        "downtime",
+        "downtime_seconds_total",
+        "Time spent down",
+        true,
+    ),
+];
+
+const RESPONSE_CODES: &[&str] = &["1xx", "2xx", "3xx", "4xx", "5xx", "other"];
+
+pub struct HaproxyCollector {
+    config: Option<HaproxyConfig>,
+    metrics: ServiceMetrics,
+}
+
+impl HaproxyCollector {
+    pub fn new(config: &Config) -> Self {
+        HaproxyCollector {
+            config: config.services.haproxy.clone(),
+            metrics: ServiceMetrics::new("haproxy", &[]),
+        }
+    }
+}
+
+impl Collector for HaproxyCollector {
+    fn name(&self) -> &'static str {
+        "haproxy"
+    }
+
+    fn register_metrics(&self, registry: &prometheus::Registry) -> prometheus::Result<()> {
+        super::builder::register(registry, &self.metrics)
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.metrics]
+    }
+
+    fn reset_metrics(&self, _registry: &prometheus::Registry) {
+        self.metrics.reset();
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        let Some(config) = &self.config else {
+            return Ok(());
+        };
+        let result = read_stats(config).and_then(|csv| parse(&csv));
+        self.metrics.set_up(&[], result.is_ok());
+        match result {
+            Ok(reading) => {
+                self.metrics.set(reading);
+                Ok(())
+            }
+            Err(e) => {
+                self.metrics.set(Reading::default());
+                Err(e)
+            }
+        }
+    }
+}
+
+fn read_stats(config: &HaproxyConfig) -> Result<String, String> {
+    match &config.stats {
+        #[cfg(unix)]
+        HaproxyStats::Socket(path) => {
+            let error = |e: std::io::Error| format!("{}: {}", path.display(), e);
+            let mut stream = std::os::unix::net::UnixStream::connect(path).map_err(error)?;
+            stream
+                .set_read_timeout(Some(config.timeout))
+                .map_err(error)?;
+            stream
+                .set_write_timeout(Some(config.timeout))
+                .map_err(error)?;
+            stream.write_all(b"show stat\n").map_err(error)?;
+            let mut csv = String::new();
+            stream.read_to_string(&mut csv).map_err(error)?;
+            Ok(csv)
+        }
+        #[cfg(not(unix))]
+        HaproxyStats::Socket(path) => Err(format!(
+            "{}: stats sockets aren't supported on this platform",
+            path.display()
+        )),
+        HaproxyStats::Url(url) => {
+            let (address, mut path) = fetch::parse_url(url)?;
+            if !path.ends_with(";csv") {
+                path.push_str(";csv");
+            }
+            let response = fetch::request(&address, "GET", &path, &[], config.timeout)?;
+            if response.status != 200 {
+                return Err(format!("{}: HTTP status {}", url, response.status));
+            }
+            Ok(String::from_utf8_lossy(&response.body).into_owned())
+        }
+    }
+}
+
+/// The `show stat` CSV: a `# pxname,svname,...` header, then one line per
+/// frontend, backend, server and listener
+fn parse(csv: &str) -> Result<Reading, String> {
+    let mut lines = csv.lines();
+    let header: Vec<&str> = match lines.next().and_then(|line| line.strip_prefix("# ")) {
+        Some(header) => header.split(',').collect(),
+        None => return Err(format!("not HAProxy statistics: {}", csv.trim())),
+    };
+    let mut reading = Reading::default();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let row: HashMap<&str, &str> = header.iter().copied().zip(line.split(',')).collect();
+        let field = |name: &str| row.get(name).copied().unwrap_or_default();
+        let (proxy, server) = (field("pxname"), field("svname"));
+        let (kind, labels, fields): (_, Vec<(&str, &str)>, _) = match field("type") {
+            "0" => ("frontend", vec![("frontend", proxy)], FRONTEND_FIELDS),
+            "1" => ("backend", vec![("backend", proxy)], BACKEND_FIELDS),
+            "2" => (
+                "server",
+                vec![("backend", proxy), ("server", server)],
+                SERVER_FIELDS,
+            ),
+            _ => continue,
+        };
+
+        for (column, name, help, counter) in fields {
+            let Ok(value) = field(column).parse::<f64>() else {
+                continue;
+            };
+            let name = format!("haproxy_{}_{}", kind, name);
+            if *counter {
+                reading.counter(&name, help, &labels, value);
+            } else {
+                reading.gauge(&name, help, &labels, value);
+            }
+        }
+        for code in RESPONSE_CODES {
+            if let Ok(value) = field(&format!("hrsp_{}", code)).parse::<f64>() {
+                let mut labels = labels.clone();
+                labels.push(("code", code));
+                reading.counter(
+                    &format!("haproxy_{}_http_responses_total", kind),
+                    "HTTP responses by status class",
+                    &labels,
+                    value,
+                );
+            }
+        }
+        if kind != "frontend" {
+            // "UP", "UP 1/3" while going down, "no check", "DRAIN", ...
+            let status = field("status");
+            let up = status.starts_with("UP") || status == "no check" || status == "DRAIN";
+            reading.gauge(
+                &format!("haproxy_{}_up", kind),
+                "Whether HAProxy considers it up",
+                &labels,
+                up as u8 as f64,
+            );
+        }
+    }
+    Ok(reading)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_show_stat() {
+        let csv = "# pxname,svname,qcur,scur,stot,bin,status,type,hrsp_2xx,hrsp_5xx,\n\
+                   http,FRONTEND,,3,120,4096,OPEN,0,100,2,\n\
+                   app,web1,0,1,60,2048,UP,2,50,1,\n\
+                   app,web2,,0,60,2048,DOWN,2,50,1,\n\
+                   app,BACKEND,0,1,120,4096,UP,1,100,2,\n\
+                   stats,sock-1,,0,0,0,OPEN,3,,,\n";
+        let reading = parse(csv).unwrap();
+        let value = |name, labels| reading.value(name, labels);
+        assert_eq!(
+            value("haproxy_frontend_current_sessions", &[("frontend", "http")]),
+            Some(3.0)
+        );
+        assert_eq!(
+            value(
+                "haproxy_frontend_http_responses_total",
+                &[("frontend", "http"), ("code", "5xx")]
+            ),
+            Some(2.0)
+        );
+        assert_eq!(
+            value(
+                "haproxy_server_up",
+                &[("backend", "app"), ("server", "web1")]
+            ),
+            Some(1.0)
+        );
+        assert_eq!(
+            value(
+                "haproxy_server_up",
+                &[("backend", "app"), ("server", "web2")]
+            ),
+            Some(0.0)
+        );
+        assert_eq!(
+            value("haproxy_backend_bytes_in_total", &[("backend", "app")]),
+            Some(4096.0)
+        );
+        // Empty fields are left out
+        assert_eq!(
+            value(
+                "haproxy_server_current_queue",
+                &[("backend", "app"), ("server", "web2")]
+            ),
+            None
+        );
+        assert!(parse("Unknown command.\n").is_err());
+    }
+}
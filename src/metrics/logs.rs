@@ -0,0 +1,258 @@
+//! Lines of the files in `logs.files` that match the regexes in
+//! `[logs.patterns]`, a small mtail built in. Files are followed like
+//! `tail -F`: reading starts at the end, and a file that is renamed away or
+//! truncated by log rotation is finished and then read again from the start.
+
+use super::builder::metric;
+use super::demo::Simulation;
+use crate::collector::Collector;
+use crate::config::Config;
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::CounterVec;
+use regex::Regex;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Most read from one file in a cycle; the rest waits for the next one
+const READ_LIMIT: u64 = 16 * 1024 * 1024;
+
+/// Longer lines are matched in pieces of this size
+const MAX_LINE: usize = 64 * 1024;
+
+/// One followed file
+struct Tail {
+    path: PathBuf,
+    file: Option<File>,
+    /// Device and inode of `file`, where the platform has them
+    id: Option<(u64, u64)>,
+    offset: u64,
+    /// The end of the file after its last newline
+    partial: Vec<u8>,
+    /// Whether the path was ever opened; only the first open skips to the
+    /// end, so lines written right after a rotation aren't lost
+    opened: bool,
+}
+
+impl Tail {
+    fn new(path: PathBuf) -> Self {
+        Tail {
+            path,
+            file: None,
+            id: None,
+            offset: 0,
+            partial: Vec::new(),
+            opened: false,
+        }
+    }
+
+    /// Pass every line completed since the last call to `line`
+    fn read(&mut self, line: &mut dyn FnMut(&str)) -> std::io::Result<()> {
+        if self.file.is_some() {
+            let replaced = match std::fs::metadata(&self.path) {
+                Ok(metadata) => file_id(&metadata) != self.id,
+                Err(_) => true,
+            };
+            if replaced {
+                // Whatever was written before the rename still counts
+                self.drain(line)?;
+                self.flush(line);
+                self.file = None;
+            }
+        }
+        if self.file.is_none() {
+            let file = match File::open(&self.path) {
+                Ok(file) => file,
+                // Between a rename and the new file being created
+                Err(e) if self.opened && e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let metadata = file.metadata()?;
+            self.offset = if self.opened { 0 } else { metadata.len() };
+            self.id = file_id(&metadata);
+            self.file = Some(file);
+            self.opened = true;
+        }
+        self.drain(line)
+    }
+
+    /// Read from `offset` to the end of the open file
+    fn drain(&mut self, line: &mut dyn FnMut(&str)) -> std::io::Result<()> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        if file.metadata()?.len() < self.offset {
+            // Truncated in place, as by logrotate's copytruncate
+            self.offset = 0;
+            self.partial.clear();
+        }
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut data = Vec::new();
+        self.offset += file.by_ref().take(READ_LIMIT).read_to_end(&mut data)? as u64;
+
+        let mut rest = &data[..];
+        while let Some(end) = rest.iter().position(|&b| b == b'\n') {
+            self.partial.extend_from_slice(&rest[..end]);
+            self.flush(line);
+            rest = &rest[end + 1..];
+        }
+        self.partial.extend_from_slice(rest);
+        if self.partial.len() >= MAX_LINE {
+            self.flush(line);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, line: &mut dyn FnMut(&str)) {
+        if !self.partial.is_empty() {
+            line(&String::from_utf8_lossy(&self.partial));
+            self.partial.clear();
+        }
+    }
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+pub struct LogsCollector {
+    matches_total: CounterVec,
+    tails: Mutex<Vec<Tail>>,
+    patterns: Vec<(String, Regex)>,
+    simulation: Option<Simulation>,
+}
+
+impl LogsCollector {
+    pub fn new(config: &Config) -> Self {
+        let matches_total = metric(
+            "log_matches_total",
+            "Lines matching a pattern of [logs.patterns]",
+        )
+        .counter_vec(&["file", "pattern"]);
+        // Present from the start, so the first match is an increase
+        for file in &config.logs.files {
+            for name in config.logs.patterns.keys() {
+                matches_total.with_label_values(&[&file.to_string_lossy(), name.as_str()]);
+            }
+        }
+        LogsCollector {
+            matches_total,
+            tails: Mutex::new(config.logs.files.iter().cloned().map(Tail::new).collect()),
+            patterns: config
+                .logs
+                .patterns
+                .iter()
+                .map(|(name, regex)| (name.clone(), regex.clone()))
+                .collect(),
+            simulation: config.demo.map(|seed| Simulation::new(seed, "logs")),
+        }
+    }
+}
+
+impl Collector for LogsCollector {
+    fn name(&self) -> &'static str {
+        "logs"
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
+        Ok(())
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.matches_total]
+    }
+
+    /// Fails with the first file that couldn't be read, after reading the
+    /// others
+    fn collect_metrics(&self) -> Result<(), String> {
+        let mut tails = self.tails.lock().unwrap();
+        if let Some(simulation) = &self.simulation {
+            for tail in tails.iter() {
+                for (name, _) in &self.patterns {
+                    self.matches_total
+                        .with_label_values(&[&tail.path.to_string_lossy(), name.as_str()])
+                        .inc_by(simulation.between(0.0, 3.0).floor());
+                }
+            }
+            return Ok(());
+        }
+
+        let mut first_error = None;
+        for tail in tails.iter_mut() {
+            let file = tail.path.to_string_lossy().into_owned();
+            let counters: Vec<_> = self
+                .patterns
+                .iter()
+                .map(|(name, regex)| (regex, self.matches_total.with_label_values(&[&file, name])))
+                .collect();
+            let result = tail.read(&mut |line| {
+                for (regex, counter) in &counters {
+                    if regex.is_match(line) {
+                        counter.inc();
+                    }
+                }
+            });
+            if let Err(e) = result {
+                first_error.get_or_insert(format!("{}: {}", file, e));
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn follows_a_rotated_file() {
+        let dir = std::env::temp_dir().join(format!("metrixd-logs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+        let append = |text: &str| {
+            File::options()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .unwrap()
+                .write_all(text.as_bytes())
+                .unwrap()
+        };
+        let mut tail = Tail::new(path.clone());
+        let mut read = || {
+            let mut lines = Vec::new();
+            tail.read(&mut |line| lines.push(line.to_string())).unwrap();
+            lines
+        };
+
+        append("from before\n");
+        assert!(read().is_empty());
+        append("one\ntw");
+        assert_eq!(read(), ["one"]);
+        append("o\n");
+        assert_eq!(read(), ["two"]);
+
+        append("last before the rotation");
+        std::fs::rename(&path, dir.join("app.log.1")).unwrap();
+        append("first after\n");
+        assert_eq!(read(), ["last before the rotation", "first after"]);
+
+        std::fs::write(&path, "").unwrap();
+        assert!(read().is_empty());
+        append("after truncation\n");
+        assert_eq!(read(), ["after truncation"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(read().is_empty());
+        assert!(Tail::new(path).read(&mut |_| {}).is_err());
+    }
+}
@@ -1,50 +1,110 @@
+//! Memory usage from sysinfo, plus on Linux the kernel's own accounting
+//! from /proc and /sys: free blocks per allocation order, for correlating
+//! allocation failures with fragmentation, compressed swap in zram
+//! devices and the zswap pool for memory-constrained edge devices,
+//! transparent huge pages, which databases are tuned around, the largest
+//! slab caches, for kernel memory leaks, and shared memory in SysV segments
+//! and tmpfs mounts, whose files live in RAM.
+
+use super::builder::metric;
+use super::demo::Simulation;
+#[cfg(target_os = "linux")]
+use super::platform;
+use super::snapshot::{Refresh, SystemSnapshot};
 use crate::collector::Collector;
-use prometheus::{register_gauge, Gauge};
-use std::sync::Mutex;
-use sysinfo::System;
+use crate::config::Config;
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::Gauge;
+#[cfg(target_os = "linux")]
+use prometheus::{CounterVec, GaugeVec};
+#[cfg(target_os = "linux")]
+use std::collections::BTreeMap;
+#[cfg(target_os = "linux")]
+use std::path::Path;
+use std::sync::Arc;
 
 pub struct MemoryCollector {
     memory_usage_percent: Gauge,
     memory_total_bytes: Gauge,
     memory_used_bytes: Gauge,
     memory_available_bytes: Gauge,
-    system: Mutex<System>,
+    #[cfg(target_os = "linux")]
+    kernel: KernelMemory,
+    snapshot: Arc<SystemSnapshot>,
+    simulation: Option<Simulation>,
 }
 
 impl MemoryCollector {
-    pub fn new() -> Self {
+    pub fn new(config: &Config, snapshot: Arc<SystemSnapshot>) -> Self {
         let memory_usage_percent =
-            register_gauge!("memory_usage_percent", "Memory usage in percentage").unwrap();
-        let memory_total_bytes =
-            register_gauge!("memory_total_bytes", "Total memory in bytes").unwrap();
-        let memory_used_bytes =
-            register_gauge!("memory_used_bytes", "Used memory in bytes").unwrap();
+            metric("memory_usage_percent", "Memory usage in percentage").gauge();
+        let memory_total_bytes = metric("memory_total_bytes", "Total memory in bytes").gauge();
+        let memory_used_bytes = metric("memory_used_bytes", "Used memory in bytes").gauge();
         let memory_available_bytes =
-            register_gauge!("memory_available_bytes", "Available memory in bytes").unwrap();
-        let system = Mutex::new(System::new_all());
+            metric("memory_available_bytes", "Available memory in bytes").gauge();
 
         MemoryCollector {
             memory_usage_percent,
             memory_total_bytes,
             memory_used_bytes,
             memory_available_bytes,
-            system,
+            #[cfg(target_os = "linux")]
+            kernel: KernelMemory::new(config),
+            snapshot,
+            simulation: config.demo.map(|seed| Simulation::new(seed, "memory")),
         }
     }
+
+    fn simulate(&self, simulation: &Simulation) {
+        let total_memory = 16.0 * 1024.0 * 1024.0 * 1024.0; // 16GiB
+        let usage_percent = simulation.between(30.0, 80.0);
+        let used_memory = (total_memory * usage_percent / 100.0).round();
+
+        self.memory_usage_percent.set(usage_percent);
+        self.memory_total_bytes.set(total_memory);
+        self.memory_used_bytes.set(used_memory);
+        self.memory_available_bytes.set(total_memory - used_memory);
+        #[cfg(target_os = "linux")]
+        self.kernel.simulate(simulation);
+    }
 }
 
 impl Collector for MemoryCollector {
-    fn register_metrics(&self) -> prometheus::Result<()> {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
         Ok(())
     }
 
-    fn collect_metrics(&self) {
-        let mut system = self.system.lock().unwrap();
-        system.refresh_memory();
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        #[allow(unused_mut)]
+        let mut metrics: Vec<&dyn PrometheusCollector> = vec![
+            &self.memory_usage_percent,
+            &self.memory_total_bytes,
+            &self.memory_used_bytes,
+            &self.memory_available_bytes,
+        ];
+        #[cfg(target_os = "linux")]
+        metrics.extend(self.kernel.metrics());
+        metrics
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        if let Some(simulation) = &self.simulation {
+            self.simulate(simulation);
+            return Ok(());
+        }
 
-        let total_memory = system.total_memory();
-        let used_memory = system.used_memory();
-        let available_memory = system.available_memory();
+        let (total_memory, used_memory, available_memory) =
+            self.snapshot.with(Refresh::Memory, |system| {
+                (
+                    system.total_memory(),
+                    system.used_memory(),
+                    system.available_memory(),
+                )
+            });
 
         // Calculate usage percentage
         let usage_percent = if total_memory > 0 {
@@ -57,5 +117,767 @@ impl Collector for MemoryCollector {
         self.memory_total_bytes.set(total_memory as f64);
         self.memory_used_bytes.set(used_memory as f64);
         self.memory_available_bytes.set(available_memory as f64);
+        #[cfg(target_os = "linux")]
+        self.kernel.collect(Path::new("/"));
+        Ok(())
+    }
+}
+
+/// The metrics read from the kernel's own files. Each file is optional:
+/// a kernel without zswap, or a container without access to debugfs,
+/// just leaves those metrics out.
+#[cfg(target_os = "linux")]
+struct KernelMemory {
+    free_blocks: GaugeVec,
+    zram_original_bytes: GaugeVec,
+    zram_compressed_bytes: GaugeVec,
+    zram_used_bytes: GaugeVec,
+    zram_disksize_bytes: GaugeVec,
+    zswap_pool_bytes: GaugeVec,
+    zswap_stored_bytes: GaugeVec,
+    zswap_pages_total: CounterVec,
+    zswap_rejections_total: CounterVec,
+    thp_mode: GaugeVec,
+    thp_events_total: CounterVec,
+    huge_pages_bytes: GaugeVec,
+    slab_bytes: GaugeVec,
+    slab_top: usize,
+    shmem_bytes: GaugeVec,
+    sysv_shm_segments: GaugeVec,
+    sysv_shm_bytes: GaugeVec,
+    tmpfs_size_bytes: GaugeVec,
+    tmpfs_used_bytes: GaugeVec,
+}
+
+#[cfg(target_os = "linux")]
+impl KernelMemory {
+    fn new(config: &Config) -> Self {
+        let zram = |name, help| metric(name, help).gauge_vec(&["device"]);
+        KernelMemory {
+            free_blocks: metric(
+                "memory_free_blocks",
+                "Free blocks of 2^order contiguous pages, from /proc/buddyinfo",
+            )
+            .gauge_vec(&["node", "zone", "order"]),
+            zram_original_bytes: zram(
+                "memory_zram_original_bytes",
+                "Data stored in the zram device, before compression",
+            ),
+            zram_compressed_bytes: zram(
+                "memory_zram_compressed_bytes",
+                "Data stored in the zram device, after compression",
+            ),
+            zram_used_bytes: zram(
+                "memory_zram_used_bytes",
+                "Memory the zram device takes, allocator overhead included",
+            ),
+            zram_disksize_bytes: zram(
+                "memory_zram_disksize_bytes",
+                "Uncompressed capacity of the zram device",
+            ),
+            // No labels, and no series without zswap
+            zswap_pool_bytes: metric("memory_zswap_pool_bytes", "Memory the zswap pool takes")
+                .gauge_vec(&[]),
+            zswap_stored_bytes: metric(
+                "memory_zswap_stored_bytes",
+                "Data in the zswap pool, before compression",
+            )
+            .gauge_vec(&[]),
+            zswap_pages_total: metric(
+                "memory_zswap_pages_total",
+                "Pages zswap stored, loaded back or wrote back to the swap device",
+            )
+            .counter_vec(&["operation"]),
+            zswap_rejections_total: metric(
+                "memory_zswap_rejections_total",
+                "Pages zswap refused to store, by reason; needs debugfs",
+            )
+            .counter_vec(&["reason"]),
+            thp_mode: metric(
+                "memory_thp_mode",
+                "1 for the mode of each transparent huge page setting: enabled, defrag or shmem_enabled",
+            )
+            .gauge_vec(&["setting", "mode"]),
+            thp_events_total: metric(
+                "memory_thp_events_total",
+                "Transparent huge page events from the thp_ counters of /proc/vmstat, such as fault_alloc",
+            )
+            .counter_vec(&["event"]),
+            huge_pages_bytes: metric(
+                "memory_huge_pages_bytes",
+                "Memory in transparent huge pages: anon, shmem or file",
+            )
+            .gauge_vec(&["type"]),
+            slab_bytes: metric(
+                "memory_slab_bytes",
+                "Memory held by one of the memory.slab_top largest kernel slab caches",
+            )
+            .gauge_vec(&["cache"]),
+            slab_top: config.memory.slab_top,
+            shmem_bytes: metric(
+                "memory_shmem_bytes",
+                "Memory in tmpfs files and shared memory segments",
+            )
+            .gauge_vec(&[]),
+            sysv_shm_segments: metric("memory_sysv_shm_segments", "SysV shared memory segments")
+                .gauge_vec(&[]),
+            sysv_shm_bytes: metric(
+                "memory_sysv_shm_bytes",
+                "Size of the SysV shared memory segments",
+            )
+            .gauge_vec(&[]),
+            tmpfs_size_bytes: metric("memory_tmpfs_size_bytes", "Size limit of the tmpfs mount")
+                .gauge_vec(&["mountpoint"]),
+            tmpfs_used_bytes: metric(
+                "memory_tmpfs_used_bytes",
+                "Memory and swap taken by the files of the tmpfs mount",
+            )
+            .gauge_vec(&["mountpoint"]),
+        }
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![
+            &self.free_blocks,
+            &self.zram_original_bytes,
+            &self.zram_compressed_bytes,
+            &self.zram_used_bytes,
+            &self.zram_disksize_bytes,
+            &self.zswap_pool_bytes,
+            &self.zswap_stored_bytes,
+            &self.zswap_pages_total,
+            &self.zswap_rejections_total,
+            &self.thp_mode,
+            &self.thp_events_total,
+            &self.huge_pages_bytes,
+            &self.slab_bytes,
+            &self.shmem_bytes,
+            &self.sysv_shm_segments,
+            &self.sysv_shm_bytes,
+            &self.tmpfs_size_bytes,
+            &self.tmpfs_used_bytes,
+        ]
+    }
+
+    fn simulate(&self, simulation: &Simulation) {
+        // Plenty of small blocks, few large ones, as on a long-running host
+        for order in 0..11 {
+            let blocks = simulation.between(0.5, 1.0) * 20000.0 / 2f64.powi(order);
+            self.free_blocks
+                .with_label_values(&["0", "Normal", &order.to_string()])
+                .set(blocks.floor());
+        }
+        // A 2GiB zram swap device about a third full, compressing 3:1
+        let original = (simulation.between(0.3, 0.4) * 2048.0).round() * 1024.0 * 1024.0;
+        self.set_zram(&[Zram {
+            device: "zram0".to_string(),
+            original_bytes: original,
+            compressed_bytes: (original / 3.0).round(),
+            used_bytes: (original / 2.8).round(),
+            disksize_bytes: 2048.0 * 1024.0 * 1024.0,
+        }]);
+        let anon = (simulation.between(300.0, 400.0)).round() * 2.0 * 1024.0 * 1024.0;
+        self.set_thp(&Thp {
+            modes: vec![
+                ("enabled", "madvise".to_string()),
+                ("defrag", "madvise".to_string()),
+            ],
+            events: Vec::new(),
+            bytes: vec![("anon", anon), ("shmem", 0.0), ("file", 0.0)],
+        });
+        let slabs: Vec<(String, f64)> = [
+            ("dentry", 180.0),
+            ("inode_cache", 120.0),
+            ("ext4_inode_cache", 95.0),
+            ("kmalloc-512", 40.0),
+            ("radix_tree_node", 35.0),
+        ]
+        .into_iter()
+        .map(|(cache, mib)| {
+            let bytes = (mib * simulation.between(0.95, 1.05)).round() * 1024.0 * 1024.0;
+            (cache.to_string(), bytes)
+        })
+        .collect();
+        self.set_slabs(&slabs);
+        let shm = simulation.between(60.0, 70.0).round() * 1024.0 * 1024.0;
+        self.set_shm(
+            shm,
+            Some(SysvShm {
+                segments: 3.0,
+                bytes: 48.0 * 1024.0 * 1024.0,
+            }),
+            &[
+                ("/dev/shm".to_string(), 8.0 * 1024.0 * 1024.0 * 1024.0, shm),
+                (
+                    "/run".to_string(),
+                    1638.0 * 1024.0 * 1024.0,
+                    2.0 * 1024.0 * 1024.0,
+                ),
+            ],
+        );
+    }
+
+    fn collect(&self, root: &Path) {
+        for zone in read_buddyinfo(root) {
+            for (order, blocks) in zone.free_blocks.iter().enumerate() {
+                self.free_blocks
+                    .with_label_values(&[&zone.node, &zone.zone, &order.to_string()])
+                    .set(*blocks);
+            }
+        }
+        self.set_zram(&read_zram(root));
+        let meminfo = read_fields(&root.join("proc/meminfo"));
+        let vmstat = read_fields(&root.join("proc/vmstat"));
+        if let Some(zswap) = read_zswap(root, &meminfo, &vmstat) {
+            self.set_zswap(&zswap);
+        }
+        self.set_thp(&read_thp(root, &meminfo, &vmstat));
+        if self.slab_top > 0 {
+            // SAFETY: sysconf has no preconditions
+            let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as f64;
+            self.set_slabs(&read_slabs(root, page_size));
+        }
+        let tmpfs: Vec<(String, f64, f64)> = read_tmpfs_mounts(root)
+            .into_iter()
+            .filter_map(|mountpoint| {
+                let (size, used) = platform::space_stats(Path::new(&mountpoint))?;
+                Some((mountpoint, size as f64, used as f64))
+            })
+            .collect();
+        self.set_shm(
+            meminfo.get("Shmem").map_or(0.0, |kb| kb * 1024.0),
+            read_sysv_shm(root),
+            &tmpfs,
+        );
+    }
+
+    fn set_shm(&self, shmem: f64, sysv: Option<SysvShm>, tmpfs: &[(String, f64, f64)]) {
+        self.shmem_bytes.with_label_values::<&str>(&[]).set(shmem);
+        self.sysv_shm_segments.reset();
+        self.sysv_shm_bytes.reset();
+        if let Some(sysv) = sysv {
+            self.sysv_shm_segments
+                .with_label_values::<&str>(&[])
+                .set(sysv.segments);
+            self.sysv_shm_bytes
+                .with_label_values::<&str>(&[])
+                .set(sysv.bytes);
+        }
+        self.tmpfs_size_bytes.reset();
+        self.tmpfs_used_bytes.reset();
+        for (mountpoint, size, used) in tmpfs {
+            let labels = [mountpoint.as_str()];
+            self.tmpfs_size_bytes.with_label_values(&labels).set(*size);
+            self.tmpfs_used_bytes.with_label_values(&labels).set(*used);
+        }
+    }
+
+    /// Only the largest, so a host with hundreds of caches stays cheap
+    fn set_slabs(&self, caches: &[(String, f64)]) {
+        self.slab_bytes.reset();
+        for (cache, bytes) in caches.iter().take(self.slab_top) {
+            self.slab_bytes.with_label_values(&[cache]).set(*bytes);
+        }
+    }
+
+    /// The event counters are the kernel's own totals too
+    fn set_thp(&self, thp: &Thp) {
+        self.thp_mode.reset();
+        for (setting, mode) in &thp.modes {
+            self.thp_mode
+                .with_label_values(&[*setting, mode.as_str()])
+                .set(1.0);
+        }
+        self.thp_events_total.reset();
+        for (event, count) in &thp.events {
+            self.thp_events_total
+                .with_label_values(&[event])
+                .inc_by(*count);
+        }
+        for (kind, bytes) in &thp.bytes {
+            self.huge_pages_bytes.with_label_values(&[kind]).set(*bytes);
+        }
+    }
+
+    fn set_zram(&self, devices: &[Zram]) {
+        self.zram_original_bytes.reset();
+        self.zram_compressed_bytes.reset();
+        self.zram_used_bytes.reset();
+        self.zram_disksize_bytes.reset();
+        for zram in devices {
+            let labels = [zram.device.as_str()];
+            self.zram_original_bytes
+                .with_label_values(&labels)
+                .set(zram.original_bytes);
+            self.zram_compressed_bytes
+                .with_label_values(&labels)
+                .set(zram.compressed_bytes);
+            self.zram_used_bytes
+                .with_label_values(&labels)
+                .set(zram.used_bytes);
+            self.zram_disksize_bytes
+                .with_label_values(&labels)
+                .set(zram.disksize_bytes);
+        }
+    }
+
+    /// The counters are the kernel's own totals, so setting them afresh
+    /// keeps them monotonic
+    fn set_zswap(&self, zswap: &Zswap) {
+        self.zswap_pool_bytes
+            .with_label_values::<&str>(&[])
+            .set(zswap.pool_bytes);
+        self.zswap_stored_bytes
+            .with_label_values::<&str>(&[])
+            .set(zswap.stored_bytes);
+        self.zswap_pages_total.reset();
+        for (operation, pages) in &zswap.pages {
+            self.zswap_pages_total
+                .with_label_values(&[operation])
+                .inc_by(*pages);
+        }
+        self.zswap_rejections_total.reset();
+        for (reason, pages) in &zswap.rejections {
+            self.zswap_rejections_total
+                .with_label_values(&[reason])
+                .inc_by(*pages);
+        }
+    }
+}
+
+/// One line of /proc/buddyinfo
+#[cfg(target_os = "linux")]
+#[derive(Debug, PartialEq)]
+struct BuddyZone {
+    node: String,
+    zone: String,
+    /// Indexed by order
+    free_blocks: Vec<f64>,
+}
+
+/// `Node 0, zone   Normal   8198   1943    401 ...` for each zone below
+/// `root`, which is `/` outside tests; none where the file is missing
+#[cfg(target_os = "linux")]
+fn read_buddyinfo(root: &Path) -> Vec<BuddyZone> {
+    let text = std::fs::read_to_string(root.join("proc/buddyinfo")).unwrap_or_default();
+    text.lines()
+        .filter_map(|line| {
+            let (node, rest) = line.strip_prefix("Node ")?.split_once(',')?;
+            let mut fields = rest.split_whitespace();
+            if fields.next()? != "zone" {
+                return None;
+            }
+            Some(BuddyZone {
+                node: node.to_string(),
+                zone: fields.next()?.to_string(),
+                free_blocks: fields
+                    .map(|count| count.parse().ok())
+                    .collect::<Option<_>>()?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, PartialEq)]
+struct Zram {
+    device: String,
+    original_bytes: f64,
+    compressed_bytes: f64,
+    used_bytes: f64,
+    disksize_bytes: f64,
+}
+
+/// Every initialized zram device, from `/sys/block/zram*/mm_stat`, whose
+/// first columns are orig_data_size, compr_data_size and mem_used_total
+#[cfg(target_os = "linux")]
+fn read_zram(root: &Path) -> Vec<Zram> {
+    let Ok(entries) = std::fs::read_dir(root.join("sys/block")) else {
+        return Vec::new();
+    };
+    let mut devices: Vec<Zram> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let device = entry.file_name().into_string().ok()?;
+            if !device.starts_with("zram") {
+                return None;
+            }
+            let read = |file| std::fs::read_to_string(entry.path().join(file)).ok();
+            let mm_stat: Vec<f64> = read("mm_stat")?
+                .split_whitespace()
+                .map(|field| field.parse().ok())
+                .collect::<Option<_>>()?;
+            let disksize: f64 = read("disksize")?.trim().parse().ok()?;
+            // An unused device reports a size of 0
+            if disksize == 0.0 {
+                return None;
+            }
+            Some(Zram {
+                device,
+                original_bytes: *mm_stat.first()?,
+                compressed_bytes: *mm_stat.get(1)?,
+                used_bytes: *mm_stat.get(2)?,
+                disksize_bytes: disksize,
+            })
+        })
+        .collect();
+    devices.sort_by(|a, b| a.device.cmp(&b.device));
+    devices
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, PartialEq)]
+struct Zswap {
+    pool_bytes: f64,
+    stored_bytes: f64,
+    /// Kernel totals by operation: store, load and writeback
+    pages: Vec<(&'static str, f64)>,
+    /// Kernel totals by reason, only with debugfs mounted and readable
+    rejections: Vec<(String, f64)>,
+}
+
+/// The zswap pool from `/proc/meminfo` and `/proc/vmstat`, or none on
+/// kernels without zswap
+#[cfg(target_os = "linux")]
+fn read_zswap(
+    root: &Path,
+    meminfo: &BTreeMap<String, f64>,
+    vmstat: &BTreeMap<String, f64>,
+) -> Option<Zswap> {
+    let pages = [
+        ("store", "zswpout"),
+        ("load", "zswpin"),
+        ("writeback", "zswpwb"),
+    ]
+    .into_iter()
+    .filter_map(|(operation, field)| Some((operation, *vmstat.get(field)?)))
+    .collect();
+
+    let mut rejections = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(root.join("sys/kernel/debug/zswap")) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Some(reason) = name.strip_prefix("reject_") else {
+                continue;
+            };
+            let count = std::fs::read_to_string(entry.path())
+                .ok()
+                .and_then(|text| text.trim().parse().ok());
+            if let Some(count) = count {
+                rejections.push((reason.to_string(), count));
+            }
+        }
+    }
+    rejections.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Some(Zswap {
+        // Reported in kB
+        pool_bytes: meminfo.get("Zswap")? * 1024.0,
+        stored_bytes: meminfo.get("Zswapped")? * 1024.0,
+        pages,
+        rejections,
+    })
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, PartialEq)]
+struct Thp {
+    /// The bracketed choice of each setting, which the kernel may lack
+    modes: Vec<(&'static str, String)>,
+    /// The `thp_` counters of /proc/vmstat, without the prefix
+    events: Vec<(String, f64)>,
+    bytes: Vec<(&'static str, f64)>,
+}
+
+/// Transparent huge page settings from `/sys/kernel/mm/transparent_hugepage`,
+/// where `always [madvise] never` means madvise, and usage from
+/// `/proc/meminfo` and `/proc/vmstat`
+#[cfg(target_os = "linux")]
+fn read_thp(root: &Path, meminfo: &BTreeMap<String, f64>, vmstat: &BTreeMap<String, f64>) -> Thp {
+    let settings = root.join("sys/kernel/mm/transparent_hugepage");
+    let modes = ["enabled", "defrag", "shmem_enabled"]
+        .into_iter()
+        .filter_map(|setting| {
+            let text = std::fs::read_to_string(settings.join(setting)).ok()?;
+            let mode = text.split_once('[')?.1.split_once(']')?.0;
+            Some((setting, mode.to_string()))
+        })
+        .collect();
+    let events = vmstat
+        .iter()
+        .filter_map(|(name, count)| Some((name.strip_prefix("thp_")?.to_string(), *count)))
+        .collect();
+    let bytes = [
+        ("anon", "AnonHugePages"),
+        ("shmem", "ShmemHugePages"),
+        ("file", "FileHugePages"),
+    ]
+    .into_iter()
+    .filter_map(|(kind, field)| Some((kind, meminfo.get(field)? * 1024.0)))
+    .collect();
+    Thp {
+        modes,
+        events,
+        bytes,
+    }
+}
+
+/// Slab caches by the memory their slabs take, largest first. Comes from
+/// /proc/slabinfo, which only root may read, and otherwise from
+/// /sys/kernel/slab, where merged caches are one directory that each of
+/// their names links to.
+#[cfg(target_os = "linux")]
+fn read_slabs(root: &Path, page_size: f64) -> Vec<(String, f64)> {
+    let mut caches = match std::fs::read_to_string(root.join("proc/slabinfo")) {
+        // `dentry  35973  35973  192  21  1 : tunables ... : slabdata  1713  1713  0`
+        Ok(text) => text
+            .lines()
+            .skip(2)
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let objects: f64 = fields.get(2)?.parse().ok()?;
+                let pages_per_slab: f64 = fields.get(5)?.parse().ok()?;
+                let slabs: f64 = fields.get(14)?.parse().ok()?;
+                let size: f64 = fields.get(3)?.parse().ok()?;
+                // Slabs hold more than the objects, so count the pages
+                let bytes = (slabs * pages_per_slab * page_size).max(objects * size);
+                Some((fields.first()?.to_string(), bytes))
+            })
+            .collect(),
+        Err(_) => read_sysfs_slabs(&root.join("sys/kernel/slab"), page_size),
+    };
+    caches.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    caches
+}
+
+#[cfg(target_os = "linux")]
+fn read_sysfs_slabs(dir: &Path, page_size: f64) -> Vec<(String, f64)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    // By the directory the name resolves to, preferring a name over the
+    // `:0000192` style identifier of merged caches
+    let mut caches: BTreeMap<std::path::PathBuf, (String, f64)> = BTreeMap::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Ok(cache) = std::fs::canonicalize(entry.path()) else {
+            continue;
+        };
+        let read = |file| -> Option<f64> {
+            let text = std::fs::read_to_string(cache.join(file)).ok()?;
+            text.split_whitespace().next()?.parse().ok()
+        };
+        let (Some(slabs), Some(order)) = (read("slabs"), read("order")) else {
+            continue;
+        };
+        let bytes = slabs * page_size * 2f64.powi(order as i32);
+        let known = caches.entry(cache).or_insert_with(|| (name.clone(), bytes));
+        let better = match (known.0.starts_with(':'), name.starts_with(':')) {
+            (true, false) => true,
+            (false, false) => name < known.0,
+            _ => false,
+        };
+        if better {
+            known.0 = name;
+        }
+    }
+    caches.into_values().collect()
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, PartialEq)]
+struct SysvShm {
+    segments: f64,
+    bytes: f64,
+}
+
+/// Totals of `/proc/sysvipc/shm`, none without SysV IPC
+#[cfg(target_os = "linux")]
+fn read_sysv_shm(root: &Path) -> Option<SysvShm> {
+    let text = std::fs::read_to_string(root.join("proc/sysvipc/shm")).ok()?;
+    // `key shmid perms size cpid ...` after the header
+    let sizes: Vec<f64> = text
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().nth(3)?.parse().ok())
+        .collect();
+    Some(SysvShm {
+        segments: sizes.len() as f64,
+        // Not sum(), which makes -0 of no segments
+        bytes: sizes.iter().fold(0.0, |total, size| total + size),
+    })
+}
+
+/// Mount points of tmpfs file systems, each once even where it is
+/// mounted over itself
+#[cfg(target_os = "linux")]
+fn read_tmpfs_mounts(root: &Path) -> Vec<String> {
+    let text = std::fs::read_to_string(root.join("proc/mounts")).unwrap_or_default();
+    let mut mountpoints: Vec<String> = Vec::new();
+    for line in text.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [_, mountpoint, "tmpfs", ..] = fields[..] else {
+            continue;
+        };
+        // Spaces and the like are octal escapes such as \040
+        let mountpoint = unescape_octal(mountpoint);
+        if !mountpoints.contains(&mountpoint) {
+            mountpoints.push(mountpoint);
+        }
+    }
+    mountpoints
+}
+
+#[cfg(target_os = "linux")]
+fn unescape_octal(field: &str) -> String {
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut rest = field.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..3)
+            .filter(|digits| byte == b'\\' && digits.iter().all(|d| (b'0'..=b'7').contains(d)))
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match escaped {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[3..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// `Name: value` and `name value` lines, as in /proc/meminfo and
+/// /proc/vmstat, with units dropped
+#[cfg(target_os = "linux")]
+fn read_fields(path: &Path) -> BTreeMap<String, f64> {
+    let text = std::fs::read_to_string(path).unwrap_or_default();
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next()?.trim_end_matches(':');
+            Some((name.to_string(), fields.next()?.parse().ok()?))
+        })
+        .collect()
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    fn fixture() -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/procfs/linux-5.15")
+    }
+
+    #[test]
+    fn reads_buddyinfo() {
+        let zones = read_buddyinfo(&fixture());
+        assert_eq!(zones.len(), 3);
+        assert_eq!(
+            zones[2],
+            BuddyZone {
+                node: "0".to_string(),
+                zone: "Normal".to_string(),
+                free_blocks: vec![
+                    8198.0, 1943.0, 401.0, 68.0, 42.0, 40.0, 19.0, 16.0, 15.0, 3.0, 0.0
+                ],
+            }
+        );
+        assert!(read_buddyinfo(&fixture().join("nonexistent")).is_empty());
+    }
+
+    #[test]
+    fn reads_compressed_swap() {
+        assert_eq!(
+            read_zram(&fixture()),
+            [Zram {
+                device: "zram0".to_string(),
+                original_bytes: 434176000.0,
+                compressed_bytes: 121020416.0,
+                used_bytes: 128655360.0,
+                disksize_bytes: 2147483648.0,
+            }]
+        );
+        let meminfo = read_fields(&fixture().join("proc/meminfo"));
+        let vmstat = read_fields(&fixture().join("proc/vmstat"));
+        assert_eq!(
+            read_zswap(&fixture(), &meminfo, &vmstat),
+            Some(Zswap {
+                pool_bytes: 52428800.0,
+                stored_bytes: 157286400.0,
+                pages: vec![("store", 98304.0), ("load", 40960.0), ("writeback", 12.0)],
+                rejections: vec![
+                    ("compress_poor".to_string(), 311.0),
+                    ("reclaim_fail".to_string(), 2.0)
+                ],
+            })
+        );
+        let missing = BTreeMap::new();
+        assert_eq!(read_zswap(&fixture(), &missing, &missing), None);
+    }
+
+    #[test]
+    fn reads_slab_caches() {
+        let slabs = read_slabs(&fixture(), 4096.0);
+        assert_eq!(
+            slabs,
+            [
+                ("dentry".to_string(), 1713.0 * 4096.0),
+                ("ext4_groupinfo_4k".to_string(), 79.0 * 4096.0),
+                ("AF_VSOCK".to_string(), 4.0 * 4096.0),
+                ("fscrypt_inode_info".to_string(), 0.0),
+            ]
+        );
+
+        let sysfs = fixture().join("sys/kernel/slab");
+        assert_eq!(
+            read_sysfs_slabs(&sysfs, 4096.0)
+                .into_iter()
+                .collect::<BTreeMap<_, _>>(),
+            BTreeMap::from([
+                ("dentry".to_string(), 1713.0 * 4096.0),
+                ("cred_jar".to_string(), 50.0 * 2.0 * 4096.0),
+            ])
+        );
+    }
+
+    #[test]
+    fn reads_shared_memory() {
+        assert_eq!(
+            read_sysv_shm(&fixture()),
+            Some(SysvShm {
+                segments: 2.0,
+                bytes: 33554432.0 + 524288.0,
+            })
+        );
+        assert_eq!(
+            read_tmpfs_mounts(&fixture()),
+            ["/dev/shm", "/run", "/mnt/scratch space"]
+        );
+        assert_eq!(read_sysv_shm(&fixture().join("nonexistent")), None);
+    }
+
+    #[test]
+    fn reads_transparent_huge_pages() {
+        let meminfo = read_fields(&fixture().join("proc/meminfo"));
+        let vmstat = read_fields(&fixture().join("proc/vmstat"));
+        let thp = read_thp(&fixture(), &meminfo, &vmstat);
+        assert_eq!(
+            thp.modes,
+            [
+                ("enabled", "always".to_string()),
+                ("defrag", "madvise".to_string())
+            ]
+        );
+        assert_eq!(thp.events.len(), 6);
+        assert_eq!(thp.events[0], ("collapse_alloc".to_string(), 862.0));
+        assert_eq!(
+            thp.bytes,
+            [("anon", 417333248.0), ("shmem", 0.0), ("file", 0.0)]
+        );
     }
 }
@@ -1,11 +1,338 @@
+pub mod builder;
+#[cfg(feature = "cpu")]
 mod cpu;
+#[cfg(any(
+    feature = "cpu",
+    feature = "dirsize",
+    feature = "disk",
+    feature = "freshness",
+    feature = "fsprobe",
+    feature = "logs",
+    feature = "memory",
+    feature = "network",
+    feature = "processes",
+    feature = "system",
+    feature = "time"
+))]
+mod demo;
+#[cfg(feature = "dirsize")]
+mod dirsize;
+#[cfg(feature = "disk")]
 mod disk;
+#[cfg(all(target_os = "linux", feature = "ebpf"))]
+mod ebpf;
+pub mod exemplar;
+#[cfg(feature = "federation")]
+mod federation;
+#[cfg(feature = "freshness")]
+mod freshness;
+#[cfg(feature = "fsprobe")]
+mod fsprobe;
+#[cfg(feature = "haproxy")]
+mod haproxy;
+#[cfg(feature = "logs")]
+mod logs;
+#[cfg(feature = "memory")]
 mod memory;
+#[cfg(feature = "mysql")]
+mod mysql;
+pub mod native_histogram;
+#[cfg(feature = "network")]
 mod network;
+#[cfg(feature = "nginx")]
+mod nginx;
+#[cfg(feature = "packages")]
+mod packages;
+#[cfg(any(
+    feature = "cpu",
+    feature = "disk",
+    feature = "memory",
+    feature = "network",
+    feature = "processes"
+))]
+#[cfg_attr(
+    not(all(
+        feature = "cpu",
+        feature = "disk",
+        feature = "memory",
+        feature = "network",
+        feature = "processes"
+    )),
+    allow(dead_code)
+)]
+mod platform;
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(feature = "processes")]
+mod processes;
+#[cfg(feature = "proxy")]
+mod proxy;
+#[cfg(feature = "redis")]
+mod redis;
+mod runtime;
+#[cfg(feature = "scripts")]
+mod scripts;
+#[cfg(feature = "security")]
+pub(crate) mod security;
+#[cfg(any(
+    feature = "haproxy",
+    feature = "nginx",
+    feature = "postgres",
+    feature = "mysql",
+    feature = "redis"
+))]
+mod service;
+#[cfg(any(feature = "cpu", feature = "memory", feature = "system"))]
+#[cfg_attr(
+    not(all(feature = "cpu", feature = "memory", feature = "system")),
+    allow(dead_code)
+)]
+mod snapshot;
+#[cfg(feature = "sockets")]
+mod sockets;
+pub mod summary;
+#[cfg(feature = "sysctl")]
+mod sysctl;
+#[cfg(feature = "system")]
 mod system;
+#[cfg(feature = "time")]
+mod time;
+#[cfg(all(windows, feature = "windows"))]
+mod windows;
 
-pub use cpu::CpuCollector;
-pub use disk::DiskCollector;
-pub use memory::MemoryCollector;
-pub use network::NetworkCollector;
-pub use system::SystemCollector;
+#[cfg(feature = "cpu")]
+pub(crate) use cpu::CpuCollector;
+#[cfg(feature = "dirsize")]
+pub(crate) use dirsize::DirsizeCollector;
+#[cfg(feature = "disk")]
+pub(crate) use disk::DiskCollector;
+#[cfg(all(target_os = "linux", feature = "ebpf"))]
+pub(crate) use ebpf::{BlockioCollector, ExecsCollector, SyscallsCollector, TcpCollector};
+#[cfg(feature = "federation")]
+pub(crate) use federation::FederationCollector;
+#[cfg(feature = "freshness")]
+pub(crate) use freshness::FreshnessCollector;
+#[cfg(feature = "fsprobe")]
+pub(crate) use fsprobe::FsprobeCollector;
+#[cfg(feature = "haproxy")]
+pub(crate) use haproxy::HaproxyCollector;
+#[cfg(feature = "logs")]
+pub(crate) use logs::LogsCollector;
+#[cfg(feature = "memory")]
+pub(crate) use memory::MemoryCollector;
+#[cfg(feature = "mysql")]
+pub(crate) use mysql::MysqlCollector;
+#[cfg(feature = "network")]
+pub(crate) use network::NetworkCollector;
+#[cfg(feature = "nginx")]
+pub(crate) use nginx::NginxCollector;
+#[cfg(feature = "packages")]
+pub(crate) use packages::PackagesCollector;
+#[cfg(feature = "postgres")]
+pub(crate) use postgres::PostgresCollector;
+#[cfg(feature = "processes")]
+pub(crate) use processes::ProcessesCollector;
+#[cfg(feature = "proxy")]
+pub(crate) use proxy::ProxyCollector;
+#[cfg(feature = "redis")]
+pub(crate) use redis::RedisCollector;
+pub(crate) use runtime::RuntimeCollector;
+#[cfg(feature = "scripts")]
+pub(crate) use scripts::ScriptCollector;
+#[cfg(feature = "security")]
+pub(crate) use security::SecurityCollector;
+#[cfg(feature = "sockets")]
+pub(crate) use sockets::SocketsCollector;
+#[cfg(feature = "sysctl")]
+pub(crate) use sysctl::SysctlCollector;
+#[cfg(feature = "system")]
+pub(crate) use system::SystemCollector;
+#[cfg(feature = "time")]
+pub(crate) use time::TimeCollector;
+#[cfg(all(windows, feature = "windows"))]
+pub(crate) use windows::WindowsCollector;
+
+use crate::collector::Collector;
+use crate::config::Config;
+
+/// Names of all collectors metrixd knows about, as returned by
+/// `Collector::name`, whether or not they are compiled into this build.
+/// Config files may reference any of them so one file works across builds.
+pub const COLLECTOR_NAMES: &[&str] = &[
+    "cpu",
+    "memory",
+    "disk",
+    "system",
+    "network",
+    "windows",
+    "scripts",
+    "federation",
+    "haproxy",
+    "nginx",
+    "postgres",
+    "mysql",
+    "redis",
+    "time",
+    "sysctl",
+    "security",
+    "packages",
+    "fsprobe",
+    "dirsize",
+    "freshness",
+    "logs",
+    "sockets",
+    "processes",
+    "proxy",
+    "tcp",
+    "blockio",
+    "execs",
+    "syscalls",
+];
+
+/// Create every collector compiled into this build, including ones that
+/// can't run on this host (see `unavailable_reason`)
+#[allow(unused_mut, unused_variables, clippy::vec_init_then_push)]
+pub fn all_collectors(config: &Config) -> Vec<Box<dyn Collector + Send + Sync>> {
+    let mut collectors: Vec<Box<dyn Collector + Send + Sync>> = Vec::new();
+    // One sysinfo snapshot for all of them, refreshed once per cycle
+    #[cfg(any(feature = "cpu", feature = "memory", feature = "system"))]
+    let snapshot = std::sync::Arc::new(snapshot::SystemSnapshot::new());
+
+    #[cfg(feature = "cpu")]
+    collectors.push(Box::new(CpuCollector::new(config, snapshot.clone())));
+    #[cfg(feature = "memory")]
+    collectors.push(Box::new(MemoryCollector::new(config, snapshot.clone())));
+    #[cfg(feature = "disk")]
+    collectors.push(Box::new(DiskCollector::new(config)));
+    #[cfg(feature = "system")]
+    collectors.push(Box::new(SystemCollector::new(config, snapshot.clone())));
+    #[cfg(feature = "network")]
+    collectors.push(Box::new(NetworkCollector::new(config)));
+    #[cfg(all(windows, feature = "windows"))]
+    collectors.push(Box::new(WindowsCollector::new()));
+    #[cfg(feature = "federation")]
+    collectors.push(Box::new(FederationCollector::new(config)));
+    #[cfg(feature = "haproxy")]
+    collectors.push(Box::new(HaproxyCollector::new(config)));
+    #[cfg(feature = "nginx")]
+    collectors.push(Box::new(NginxCollector::new(config)));
+    #[cfg(feature = "postgres")]
+    collectors.push(Box::new(PostgresCollector::new(config)));
+    #[cfg(feature = "mysql")]
+    collectors.push(Box::new(MysqlCollector::new(config)));
+    #[cfg(feature = "redis")]
+    collectors.push(Box::new(RedisCollector::new(config)));
+    #[cfg(feature = "time")]
+    collectors.push(Box::new(TimeCollector::new(config)));
+    #[cfg(feature = "sysctl")]
+    collectors.push(Box::new(SysctlCollector::new(config)));
+    #[cfg(feature = "security")]
+    collectors.push(Box::new(SecurityCollector::new(config)));
+    #[cfg(feature = "packages")]
+    collectors.push(Box::new(PackagesCollector::new(config)));
+    #[cfg(feature = "fsprobe")]
+    collectors.push(Box::new(FsprobeCollector::new(config)));
+    #[cfg(feature = "dirsize")]
+    collectors.push(Box::new(DirsizeCollector::new(config)));
+    #[cfg(feature = "freshness")]
+    collectors.push(Box::new(FreshnessCollector::new(config)));
+    #[cfg(feature = "logs")]
+    collectors.push(Box::new(LogsCollector::new(config)));
+    #[cfg(feature = "sockets")]
+    collectors.push(Box::new(SocketsCollector::new(config)));
+    #[cfg(feature = "processes")]
+    collectors.push(Box::new(ProcessesCollector::new(config)));
+    #[cfg(feature = "proxy")]
+    collectors.push(Box::new(ProxyCollector::new(config)));
+    #[cfg(all(target_os = "linux", feature = "ebpf"))]
+    collectors.push(Box::new(TcpCollector::new(config)));
+    #[cfg(all(target_os = "linux", feature = "ebpf"))]
+    collectors.push(Box::new(BlockioCollector::new(config)));
+    #[cfg(all(target_os = "linux", feature = "ebpf"))]
+    collectors.push(Box::new(ExecsCollector::new(config)));
+    #[cfg(all(target_os = "linux", feature = "ebpf"))]
+    collectors.push(Box::new(SyscallsCollector::new(config)));
+    // Last, so scripts see this cycle's values of the other collectors and
+    // find the names they export taken
+    #[cfg(feature = "scripts")]
+    collectors.push(Box::new(ScriptCollector::new(config)));
+
+    collectors
+}
+
+/// True if the collector works on the operating system we're running on
+pub fn supports_current_platform(collector: &dyn Collector) -> bool {
+    collector.platforms().contains(&std::env::consts::OS)
+}
+
+/// Why the collector can't run on this host: its data sources don't exist
+/// on this platform (e.g. load averages on Windows, where the windows
+/// collector takes over instead) or are missing here
+pub fn unavailable_reason(collector: &dyn Collector) -> Option<String> {
+    if !supports_current_platform(collector) {
+        return Some(format!("not supported on {}", std::env::consts::OS));
+    }
+    collector.unavailable()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::Registry;
+
+    #[test]
+    fn collectors_can_be_created_and_registered_twice() {
+        let config = Config::parse("").unwrap();
+        let registry = Registry::new();
+        for _ in 0..2 {
+            let collectors = builder::with_registry(&registry, || all_collectors(&config));
+            for collector in &collectors {
+                collector
+                    .register_metrics(&registry)
+                    .unwrap_or_else(|e| panic!("{}: {}", collector.name(), e));
+            }
+        }
+        assert!(prometheus::gather()
+            .iter()
+            .all(|family| !family.name().starts_with("haproxy_")));
+    }
+
+    #[test]
+    fn reset_metrics_drops_labelled_series() {
+        let config = Config::parse("").unwrap();
+        let registry = Registry::new();
+        let collectors = builder::with_registry(&registry, || all_collectors(&config));
+        // Series of a label vec, which a shed collector should give up
+        let labelled = |collector: &dyn Collector| -> Vec<String> {
+            collector
+                .metrics()
+                .iter()
+                .flat_map(|metric| metric.collect())
+                .filter(|family| {
+                    family
+                        .get_metric()
+                        .iter()
+                        .any(|metric| !metric.get_label().is_empty())
+                })
+                .map(|family| family.name().to_string())
+                .collect()
+        };
+        let mut before = 0;
+        for collector in &collectors {
+            collector.register_metrics(&registry).unwrap();
+            let _ = collector.collect_metrics();
+            before += labelled(collector.as_ref()).len();
+        }
+        assert!(before > 0);
+
+        for collector in &collectors {
+            collector.reset_metrics(&registry);
+            assert_eq!(
+                labelled(collector.as_ref()),
+                Vec::<String>::new(),
+                "{}",
+                collector.name()
+            );
+        }
+    }
+}
@@ -0,0 +1,754 @@
+//! MySQL and MariaDB: `[services.mysql]` connects every cycle, over the
+//! unix socket by default, and exports connection and query counts and the
+//! InnoDB buffer pool from `SHOW GLOBAL STATUS`, plus the state of
+//! replication on replicas. Speaks just enough of the client/server
+//! protocol for that: the handshake, `mysql_native_password` and
+//! `caching_sha2_password` authentication, and text queries. There is no
+//! TLS.
+
+use std::collections::HashMap;
+
+use prometheus::core::Collector as PrometheusCollector;
+
+use super::service::{self, Reading, ServiceMetrics, Stream};
+use crate::collector::Collector;
+use crate::config::{Config, MysqlConfig};
+use crate::sigv4::sha256;
+
+/// Status variable, metric name after `mysql_`, help, and whether it's a
+/// counter
+type Field = (&'static str, &'static str, &'static str, bool);
+
+const STATUS_FIELDS: &[Field] = &[
+    (
+        "Threads_connected",
+        "threads_connected",
+        "Open client connections",
+        false,
+    ),
+    (
+        "Threads_running",
+        "threads_running",
+        "Threads executing a statement",
+        false,
+    ),
+    (
+        "Max_used_connections",
+        "max_used_connections",
+        "Most connections open at once since the server started",
+        false,
+    ),
+    (
+        "Connections",
+        "connections_total",
+        "Connection attempts",
+        true,
+    ),
+    (
+        "Aborted_connects",
+        "aborted_connects_total",
+        "Failed connection attempts",
+        true,
+    ),
+    (
+        "Questions",
+        "questions_total",
+        "Statements sent by clients",
+        true,
+    ),
+    (
+        "Slow_queries",
+        "slow_queries_total",
+        "Queries that took longer than long_query_time",
+        true,
+    ),
+    (
+        "Uptime",
+        "uptime_seconds",
+        "Time since the server started",
+        false,
+    ),
+    (
+        "Innodb_buffer_pool_pages_total",
+        "innodb_buffer_pool_pages",
+        "Pages in the InnoDB buffer pool",
+        false,
+    ),
+    (
+        "Innodb_buffer_pool_pages_free",
+        "innodb_buffer_pool_free_pages",
+        "Free pages in the InnoDB buffer pool",
+        false,
+    ),
+    (
+        "Innodb_buffer_pool_pages_dirty",
+        "innodb_buffer_pool_dirty_pages",
+        "Modified pages in the InnoDB buffer pool yet to be flushed",
+        false,
+    ),
+    (
+        "Innodb_buffer_pool_bytes_data",
+        "innodb_buffer_pool_data_bytes",
+        "Data held in the InnoDB buffer pool",
+        false,
+    ),
+    (
+        "Innodb_buffer_pool_read_requests",
+        "innodb_buffer_pool_read_requests_total",
+        "Reads of the InnoDB buffer pool",
+        true,
+    ),
+    (
+        "Innodb_buffer_pool_reads",
+        "innodb_buffer_pool_disk_reads_total",
+        "Reads the InnoDB buffer pool couldn't serve, so went to disk",
+        true,
+    ),
+    (
+        "Innodb_buffer_pool_wait_free",
+        "innodb_buffer_pool_wait_free_total",
+        "Waits for a free page in the InnoDB buffer pool",
+        true,
+    ),
+];
+
+// Capability flags
+const CLIENT_LONG_PASSWORD: u32 = 0x1;
+const CLIENT_PROTOCOL_41: u32 = 0x200;
+const CLIENT_TRANSACTIONS: u32 = 0x2000;
+const CLIENT_SECURE_CONNECTION: u32 = 0x8000;
+const CLIENT_PLUGIN_AUTH: u32 = 0x80000;
+
+pub struct MysqlCollector {
+    config: Option<MysqlConfig>,
+    metrics: ServiceMetrics,
+}
+
+impl MysqlCollector {
+    pub fn new(config: &Config) -> Self {
+        MysqlCollector {
+            config: config.services.mysql.clone(),
+            metrics: ServiceMetrics::new("mysql", &[]),
+        }
+    }
+}
+
+impl Collector for MysqlCollector {
+    fn name(&self) -> &'static str {
+        "mysql"
+    }
+
+    fn register_metrics(&self, registry: &prometheus::Registry) -> prometheus::Result<()> {
+        super::builder::register(registry, &self.metrics)
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.metrics]
+    }
+
+    fn reset_metrics(&self, _registry: &prometheus::Registry) {
+        self.metrics.reset();
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        let Some(config) = &self.config else {
+            return Ok(());
+        };
+        let result = Connection::open(config).and_then(|mut connection| {
+            read(&mut connection).map_err(|e| format!("{}: {}", config.address, e))
+        });
+        self.metrics.set_up(&[], result.is_ok());
+        match result {
+            Ok(reading) => {
+                self.metrics.set(reading);
+                Ok(())
+            }
+            Err(e) => {
+                self.metrics.set(Reading::default());
+                Err(e)
+            }
+        }
+    }
+}
+
+fn read(connection: &mut Connection) -> Result<Reading, String> {
+    let mut reading = Reading::default();
+    let (_, rows) = connection.query("SHOW GLOBAL STATUS")?;
+    let status: HashMap<&str, &str> = rows
+        .iter()
+        .filter_map(|row| match &row[..] {
+            [Some(name), Some(value)] => Some((name.as_str(), value.as_str())),
+            _ => None,
+        })
+        .collect();
+    for (variable, name, help, counter) in STATUS_FIELDS {
+        let Some(Ok(value)) = status.get(variable).map(|value| value.parse::<f64>()) else {
+            continue;
+        };
+        let name = format!("mysql_{}", name);
+        if *counter {
+            reading.counter(&name, help, &[], value);
+        } else {
+            reading.gauge(&name, help, &[], value);
+        }
+    }
+
+    let (_, rows) = connection.query("SELECT @@max_connections")?;
+    if let Some(Some(max)) = rows.first().and_then(|row| row.first()) {
+        reading.gauge(
+            "mysql_max_connections",
+            "Most connections the server accepts",
+            &[],
+            number(max),
+        );
+    }
+
+    // MySQL before 8.0.22 only knows the older name; MariaDB has both
+    let (columns, rows) = match connection.query("SHOW REPLICA STATUS") {
+        Ok(result) => result,
+        Err(_) => connection.query("SHOW SLAVE STATUS")?,
+    };
+    for row in rows {
+        let field = |names: &[&str]| {
+            let i = names
+                .iter()
+                .find_map(|name| columns.iter().position(|column| column == name))?;
+            row.get(i)?.as_deref()
+        };
+        let channel = field(&["Channel_Name", "Connection_name"]).unwrap_or_default();
+        let labels = [("channel", channel)];
+        let running = |names| (field(names) == Some("Yes")) as u8 as f64;
+        reading.gauge(
+            "mysql_replica_io_running",
+            "Whether the replica is receiving the source's binary log",
+            &labels,
+            running(&["Replica_IO_Running", "Slave_IO_Running"]),
+        );
+        reading.gauge(
+            "mysql_replica_sql_running",
+            "Whether the replica is applying what it received",
+            &labels,
+            running(&["Replica_SQL_Running", "Slave_SQL_Running"]),
+        );
+        // NULL while replication is stopped
+        if let Some(lag) = field(&["Seconds_Behind_Source", "Seconds_Behind_Master"]) {
+            reading.gauge(
+                "mysql_replica_lag_seconds",
+                "How far the replica's applied changes are behind the source",
+                &labels,
+                number(lag),
+            );
+        }
+    }
+    Ok(reading)
+}
+
+fn number(text: &str) -> f64 {
+    text.parse().unwrap_or(f64::NAN)
+}
+
+/// A result row, `None` for NULL
+type Row = Vec<Option<String>>;
+
+struct Connection {
+    stream: Box<dyn Stream>,
+    /// The sequence ID of the next packet sent
+    sequence: u8,
+}
+
+impl Connection {
+    fn open(config: &MysqlConfig) -> Result<Self, String> {
+        let mut connection = Connection {
+            stream: service::connect(&config.address, config.timeout)?,
+            sequence: 0,
+        };
+        connection
+            .handshake(config)
+            .map_err(|e| format!("{}: {}", config.address, e))?;
+        Ok(connection)
+    }
+
+    fn handshake(&mut self, config: &MysqlConfig) -> Result<(), String> {
+        let greeting = self.receive()?;
+        if greeting.first() == Some(&0xff) {
+            return Err(error_message(&greeting));
+        }
+        let (capabilities, mut scramble, mut plugin) =
+            parse_greeting(&greeting).ok_or("not a MySQL server")?;
+        if capabilities & (CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION)
+            != CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION
+        {
+            return Err("the server is too old".to_string());
+        }
+        let password = config.password.as_deref().unwrap_or_default();
+
+        let flags = CLIENT_LONG_PASSWORD
+            | CLIENT_PROTOCOL_41
+            | CLIENT_TRANSACTIONS
+            | CLIENT_SECURE_CONNECTION
+            | (capabilities & CLIENT_PLUGIN_AUTH);
+        let mut response = flags.to_le_bytes().to_vec();
+        response.extend_from_slice(&(1u32 << 24).to_le_bytes()); // max packet size
+        response.push(33); // utf8_general_ci
+        response.extend_from_slice(&[0; 23]);
+        response.extend_from_slice(config.user.as_bytes());
+        response.push(0);
+        let auth = scramble_password(&plugin, password, &scramble)?;
+        response.push(auth.len() as u8);
+        response.extend_from_slice(&auth);
+        if flags & CLIENT_PLUGIN_AUTH != 0 {
+            response.extend_from_slice(plugin.as_bytes());
+            response.push(0);
+        }
+        self.send(&response)?;
+
+        loop {
+            let packet = self.receive()?;
+            match packet.first() {
+                Some(0x00) => return Ok(()),
+                Some(0xff) => return Err(error_message(&packet)),
+                // The server wants another method
+                Some(0xfe) => {
+                    let mut fields = Fields(&packet[1..]);
+                    plugin = String::from_utf8_lossy(fields.nul_terminated().unwrap_or_default())
+                        .into_owned();
+                    scramble = fields.0.strip_suffix(&[0]).unwrap_or(fields.0).to_vec();
+                    let auth = scramble_password(&plugin, password, &scramble)?;
+                    self.send(&auth)?;
+                }
+                // caching_sha2_password: the cached hash matched
+                Some(0x01) if packet.get(1) == Some(&3) => {}
+                // caching_sha2_password: the server needs the password
+                // itself, which only goes over a unix socket in the clear
+                Some(0x01) if packet.get(1) == Some(&4) => {
+                    if !config.address.starts_with('/') {
+                        return Err("caching_sha2_password needs the password once over \
+                                    a unix socket or TLS before it works over TCP"
+                            .to_string());
+                    }
+                    let mut clear = password.as_bytes().to_vec();
+                    clear.push(0);
+                    self.send(&clear)?;
+                }
+                _ => return Err("unexpected packet during authentication".to_string()),
+            }
+        }
+    }
+
+    /// The column names and rows of a text query
+    fn query(&mut self, sql: &str) -> Result<(Vec<String>, Vec<Row>), String> {
+        self.sequence = 0;
+        let mut command = vec![0x03]; // COM_QUERY
+        command.extend_from_slice(sql.as_bytes());
+        self.send(&command)?;
+
+        let first = self.receive()?;
+        match first.first() {
+            Some(0xff) => return Err(error_message(&first)),
+            // No result set
+            Some(0x00) => return Ok((Vec::new(), Vec::new())),
+            _ => {}
+        }
+        let count = Fields(&first).length_encoded().ok_or("malformed result")?;
+        let mut columns = Vec::new();
+        for _ in 0..count {
+            let packet = self.receive()?;
+            let mut fields = Fields(&packet);
+            // catalog, schema, table, original table, then the name
+            for _ in 0..4 {
+                fields.text().ok_or("malformed column")?;
+            }
+            columns.push(fields.text().flatten().ok_or("malformed column")?);
+        }
+        if !is_eof(&self.receive()?) {
+            return Err("malformed result".to_string());
+        }
+        let mut rows = Vec::new();
+        loop {
+            let packet = self.receive()?;
+            if is_eof(&packet) {
+                break;
+            }
+            if packet.first() == Some(&0xff) {
+                return Err(error_message(&packet));
+            }
+            let mut fields = Fields(&packet);
+            let row = (0..count)
+                .map(|_| fields.text())
+                .collect::<Option<Row>>()
+                .ok_or("malformed row")?;
+            rows.push(row);
+        }
+        Ok((columns, rows))
+    }
+
+    fn send(&mut self, payload: &[u8]) -> Result<(), String> {
+        let mut packet = (payload.len() as u32).to_le_bytes()[..3].to_vec();
+        packet.push(self.sequence);
+        packet.extend_from_slice(payload);
+        self.sequence = self.sequence.wrapping_add(1);
+        self.stream.write_all(&packet).map_err(|e| e.to_string())
+    }
+
+    fn receive(&mut self) -> Result<Vec<u8>, String> {
+        let mut header = [0u8; 4];
+        self.stream
+            .read_exact(&mut header)
+            .map_err(|e| e.to_string())?;
+        let len = u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize;
+        self.sequence = header[3].wrapping_add(1);
+        let mut payload = vec![0u8; len];
+        self.stream
+            .read_exact(&mut payload)
+            .map_err(|e| e.to_string())?;
+        Ok(payload)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.sequence = 0;
+        let _ = self.send(&[0x01]); // COM_QUIT
+    }
+}
+
+/// The capabilities, scramble and authentication plugin of a protocol 10
+/// greeting
+fn parse_greeting(greeting: &[u8]) -> Option<(u32, Vec<u8>, String)> {
+    let mut fields = Fields(greeting);
+    if fields.int(1)? != 10 {
+        return None;
+    }
+    fields.nul_terminated()?; // server version
+    fields.int(4)?; // connection ID
+    let mut scramble = fields.bytes(8)?.to_vec();
+    fields.int(1)?;
+    let mut capabilities = fields.int(2)? as u32;
+    fields.int(3)?; // character set, status
+    capabilities |= (fields.int(2)? as u32) << 16;
+    let scramble_len = fields.int(1)? as usize;
+    fields.bytes(10)?;
+    let rest = fields.bytes(scramble_len.saturating_sub(8).max(13))?;
+    scramble.extend_from_slice(rest.strip_suffix(&[0]).unwrap_or(rest));
+    let plugin = match fields.nul_terminated() {
+        Some(name) if capabilities & CLIENT_PLUGIN_AUTH != 0 => {
+            String::from_utf8_lossy(name).into_owned()
+        }
+        _ => "mysql_native_password".to_string(),
+    };
+    Some((capabilities, scramble, plugin))
+}
+
+/// What `plugin` sends for `password`; nothing for an empty one
+fn scramble_password(plugin: &str, password: &str, scramble: &[u8]) -> Result<Vec<u8>, String> {
+    if password.is_empty() {
+        return Ok(Vec::new());
+    }
+    let password = password.as_bytes();
+    // SHA(password) XOR SHA(scramble + SHA(SHA(password))), and the other
+    // way around for SHA-256
+    let (hash, mixed) = match plugin {
+        "mysql_native_password" => {
+            let hash = sha1(password);
+            (
+                hash.to_vec(),
+                sha1(&[scramble, &sha1(&hash)].concat()).to_vec(),
+            )
+        }
+        "caching_sha2_password" => {
+            let hash = sha256(password);
+            (
+                hash.to_vec(),
+                sha256(&[&sha256(&hash)[..], scramble].concat()).to_vec(),
+            )
+        }
+        _ => return Err(format!("unsupported authentication plugin {}", plugin)),
+    };
+    Ok(hash.iter().zip(mixed).map(|(a, b)| a ^ b).collect())
+}
+
+/// An EOF packet, as opposed to a row starting with a long string
+fn is_eof(packet: &[u8]) -> bool {
+    packet.first() == Some(&0xfe) && packet.len() < 9
+}
+
+/// "error <code>: message" from an ERR packet
+fn error_message(packet: &[u8]) -> String {
+    let mut fields = Fields(packet.get(1..).unwrap_or_default());
+    let code = fields.int(2).unwrap_or_default();
+    let mut message = fields.0;
+    if let Some(rest) = message.strip_prefix(b"#") {
+        message = rest.get(5..).unwrap_or_default(); // SQL state
+    }
+    format!("error {}: {}", code, String::from_utf8_lossy(message))
+}
+
+/// The fields of a packet, read in order
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let (head, tail) = self.0.split_at_checked(n)?;
+        self.0 = tail;
+        Some(head)
+    }
+
+    /// A little-endian integer of `n` bytes
+    fn int(&mut self, n: usize) -> Option<u64> {
+        let bytes = self.bytes(n)?;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |int, &byte| int << 8 | byte as u64),
+        )
+    }
+
+    fn nul_terminated(&mut self) -> Option<&'a [u8]> {
+        let end = self.0.iter().position(|&byte| byte == 0)?;
+        let text = self.bytes(end)?;
+        self.0 = &self.0[1..];
+        Some(text)
+    }
+
+    fn length_encoded(&mut self) -> Option<u64> {
+        match self.int(1)? {
+            0xfc => self.int(2),
+            0xfd => self.int(3),
+            0xfe => self.int(8),
+            int => Some(int),
+        }
+    }
+
+    /// A length-encoded string, `Some(None)` for NULL
+    fn text(&mut self) -> Option<Option<String>> {
+        if self.0.first() == Some(&0xfb) {
+            self.0 = &self.0[1..];
+            return Some(None);
+        }
+        let len = self.length_encoded()? as usize;
+        Some(Some(String::from_utf8_lossy(self.bytes(len)?).into_owned()))
+    }
+}
+
+/// SHA-1 (RFC 3174), for `mysql_native_password` only
+fn sha1(message: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut padded = message.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((message.len() as u64) * 8).to_be_bytes());
+
+    for chunk in padded.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i / 20 {
+                0 => ((b & c) | (!b & d), 0x5a827999),
+                1 => (b ^ c ^ d, 0x6ed9eba1),
+                2 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (word, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn scrambles_passwords() {
+        assert_eq!(
+            hex(&sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        let scramble: Vec<u8> = (1..=20).collect();
+        assert_eq!(
+            hex(&scramble_password("mysql_native_password", "secret", &scramble).unwrap()),
+            "b32bb3a583e1340c0a1108d58b1be49781ad8c2f"
+        );
+        assert_eq!(
+            hex(&scramble_password("caching_sha2_password", "secret", &scramble).unwrap()),
+            "746ebe205d56a0707acb3e796e834e0dd7b1d61743b26bd5202c7a623230c7c9"
+        );
+        assert!(scramble_password("dialog", "secret", &scramble).is_err());
+        assert!(scramble_password("dialog", "", &scramble)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn queries_a_server() {
+        use std::io::{Read, Write};
+
+        fn packet(sequence: u8, payload: &[u8]) -> Vec<u8> {
+            let mut packet = (payload.len() as u32).to_le_bytes()[..3].to_vec();
+            packet.push(sequence);
+            packet.extend_from_slice(payload);
+            packet
+        }
+        fn text(values: &[Option<&str>]) -> Vec<u8> {
+            let mut payload = Vec::new();
+            for value in values {
+                match value {
+                    Some(value) => {
+                        payload.push(value.len() as u8);
+                        payload.extend_from_slice(value.as_bytes());
+                    }
+                    None => payload.push(0xfb),
+                }
+            }
+            payload
+        }
+        /// A result set as packets numbered from 1
+        fn result(columns: &[&str], rows: &[&[Option<&str>]]) -> Vec<u8> {
+            let mut packets = vec![vec![columns.len() as u8]];
+            for column in columns {
+                packets.push(text(&[
+                    Some("def"),
+                    Some(""),
+                    Some(""),
+                    Some(""),
+                    Some(column),
+                ]));
+            }
+            packets.push(vec![0xfe, 0, 0, 2, 0]);
+            packets.extend(rows.iter().map(|row| text(row)));
+            packets.push(vec![0xfe, 0, 0, 2, 0]);
+            packets
+                .iter()
+                .enumerate()
+                .flat_map(|(i, payload)| packet(i as u8 + 1, payload))
+                .collect()
+        }
+
+        let scramble: Vec<u8> = (1..=20).collect();
+        let (client, mut server) = std::os::unix::net::UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            let read = |server: &mut std::os::unix::net::UnixStream| {
+                let mut header = [0u8; 4];
+                server.read_exact(&mut header).unwrap();
+                let len = u32::from_le_bytes([header[0], header[1], header[2], 0]);
+                let mut payload = vec![0u8; len as usize];
+                server.read_exact(&mut payload).unwrap();
+                payload
+            };
+            let mut greeting = vec![10];
+            greeting.extend_from_slice(b"10.11.6-MariaDB\0");
+            greeting.extend_from_slice(&7u32.to_le_bytes());
+            greeting.extend_from_slice(&scramble[..8]);
+            greeting.push(0);
+            greeting.extend_from_slice(&0xf7feu16.to_le_bytes());
+            greeting.extend_from_slice(&[33, 2, 0]);
+            greeting.extend_from_slice(&0x81bfu16.to_le_bytes());
+            greeting.push(21);
+            greeting.extend_from_slice(&[0; 10]);
+            greeting.extend_from_slice(&scramble[8..]);
+            greeting.extend_from_slice(b"\0mysql_native_password\0");
+            server.write_all(&packet(0, &greeting)).unwrap();
+
+            let response = read(&mut server);
+            let auth = scramble_password("mysql_native_password", "secret", &scramble).unwrap();
+            assert!(response.windows(auth.len()).any(|window| window == auth));
+            server
+                .write_all(&packet(2, &[0, 0, 0, 2, 0, 0, 0]))
+                .unwrap();
+
+            loop {
+                let command = read(&mut server);
+                let reply = match &command[..] {
+                    [0x01] => break,
+                    [0x03, sql @ ..] if sql == b"SHOW GLOBAL STATUS" => result(
+                        &["Variable_name", "Value"],
+                        &[
+                            &[Some("Slow_queries"), Some("12")],
+                            &[Some("Threads_connected"), Some("4")],
+                            &[Some("Innodb_buffer_pool_pages_free"), Some("1024")],
+                        ],
+                    ),
+                    [0x03, sql @ ..] if sql == b"SELECT @@max_connections" => {
+                        result(&["@@max_connections"], &[&[Some("151")]])
+                    }
+                    [0x03, sql @ ..] if sql == b"SHOW REPLICA STATUS" => {
+                        packet(1, b"\xff\x28\x04#42000You have an error in your SQL syntax")
+                    }
+                    _ => result(
+                        &[
+                            "Connection_name",
+                            "Slave_IO_Running",
+                            "Slave_SQL_Running",
+                            "Seconds_Behind_Master",
+                        ],
+                        &[&[Some(""), Some("Yes"), Some("No"), None]],
+                    ),
+                };
+                server.write_all(&reply).unwrap();
+            }
+        });
+
+        let config = MysqlConfig {
+            address: "/run/mysqld/mysqld.sock".to_string(),
+            user: "root".to_string(),
+            password: Some("secret".to_string()),
+            timeout: std::time::Duration::from_secs(3),
+        };
+        let mut connection = Connection {
+            stream: Box::new(client),
+            sequence: 0,
+        };
+        connection.handshake(&config).unwrap();
+        let reading = read(&mut connection).unwrap();
+        drop(connection);
+        server.join().unwrap();
+
+        assert_eq!(reading.value("mysql_slow_queries_total", &[]), Some(12.0));
+        assert_eq!(reading.value("mysql_threads_connected", &[]), Some(4.0));
+        assert_eq!(
+            reading.value("mysql_innodb_buffer_pool_free_pages", &[]),
+            Some(1024.0)
+        );
+        assert_eq!(reading.value("mysql_max_connections", &[]), Some(151.0));
+        assert_eq!(
+            reading.value("mysql_replica_io_running", &[("channel", "")]),
+            Some(1.0)
+        );
+        assert_eq!(
+            reading.value("mysql_replica_sql_running", &[("channel", "")]),
+            Some(0.0)
+        );
+        assert_eq!(reading.value("mysql_replica_lag_seconds", &[]), None);
+    }
+}
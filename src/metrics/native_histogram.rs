@@ -0,0 +1,374 @@
+//! Histograms that can also carry a native (sparse, exponential bucket)
+//! histogram, which the prometheus crate's proto doesn't model yet. The
+//! native fields are appended to the `Histogram` message as raw protobuf
+//! fields with the numbers of Prometheus' `metrics.proto`, so protobuf
+//! scrapes get both, while the text format keeps the classic buckets.
+//!
+//! Observations can carry an `Exemplar`; the latest one per classic bucket
+//! is kept and exported on that bucket, and with the native histogram.
+//!
+//! Buckets start at schema 3 (8 per power of two, each ~9% wide) and are
+//! halved in resolution whenever there would be more than `MAX_BUCKETS`.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use super::exemplar::Exemplar;
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{self, MetricFamily};
+use prometheus::{Histogram, HistogramOpts, DEFAULT_BUCKETS};
+use protobuf::UnknownValue;
+
+const INITIAL_SCHEMA: i32 = 3;
+const MIN_SCHEMA: i32 = -4;
+const MAX_BUCKETS: usize = 160;
+/// Observations this close to zero go to the zero bucket; 2^-128 like
+/// client_golang's default
+const ZERO_THRESHOLD: f64 = 2.938735877055719e-39;
+
+// Field numbers of io.prometheus.client.Histogram
+const SCHEMA: u32 = 5;
+const ZERO_THRESHOLD_FIELD: u32 = 6;
+const ZERO_COUNT: u32 = 7;
+const NEGATIVE_SPAN: u32 = 9;
+const NEGATIVE_DELTA: u32 = 10;
+const POSITIVE_SPAN: u32 = 12;
+const POSITIVE_DELTA: u32 = 13;
+const EXEMPLARS: u32 = 16;
+// Field number of io.prometheus.client.Bucket.exemplar
+const BUCKET_EXEMPLAR: u32 = 3;
+
+struct Sparse {
+    schema: i32,
+    zero_count: u64,
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+}
+
+impl Sparse {
+    fn new() -> Self {
+        Sparse {
+            schema: INITIAL_SCHEMA,
+            zero_count: 0,
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        if value.abs() <= ZERO_THRESHOLD {
+            self.zero_count += 1;
+            return;
+        }
+        let buckets = if value > 0.0 {
+            &mut self.positive
+        } else {
+            &mut self.negative
+        };
+        *buckets
+            .entry(bucket_index(value.abs(), self.schema))
+            .or_default() += 1;
+
+        while self.positive.len() + self.negative.len() > MAX_BUCKETS && self.schema > MIN_SCHEMA {
+            self.schema -= 1;
+            self.positive = downscale(&self.positive);
+            self.negative = downscale(&self.negative);
+        }
+    }
+
+    fn write_to(&self, histogram: &mut proto::Histogram) {
+        let fields = histogram.special_fields.mut_unknown_fields();
+        fields.add_value(SCHEMA, UnknownValue::sint32(self.schema));
+        fields.add_value(ZERO_THRESHOLD_FIELD, UnknownValue::double(ZERO_THRESHOLD));
+        fields.add_varint(ZERO_COUNT, self.zero_count);
+        for (span_field, delta_field, buckets) in [
+            (NEGATIVE_SPAN, NEGATIVE_DELTA, &self.negative),
+            (POSITIVE_SPAN, POSITIVE_DELTA, &self.positive),
+        ] {
+            let (spans, deltas) = spans_and_deltas(buckets);
+            for (offset, length) in spans {
+                let mut span = Vec::new();
+                // BucketSpan { sint32 offset = 1; uint32 length = 2; }
+                span.push(1 << 3);
+                varint(&mut span, zigzag(offset.into()));
+                span.push(2 << 3);
+                varint(&mut span, length.into());
+                fields.add_length_delimited(span_field, span);
+            }
+            if !deltas.is_empty() {
+                let mut packed = Vec::new();
+                for delta in deltas {
+                    varint(&mut packed, zigzag(delta));
+                }
+                fields.add_length_delimited(delta_field, packed);
+            }
+        }
+        // Without observations nothing above tells a native histogram from
+        // a classic one, so add an empty span like client_golang does
+        if self.zero_count == 0 && self.positive.is_empty() && self.negative.is_empty() {
+            fields.add_length_delimited(POSITIVE_SPAN, vec![1 << 3, 0, 2 << 3, 0]);
+        }
+    }
+}
+
+/// Bucket `i` holds values in (base^(i-1), base^i], base = 2^(2^-schema)
+fn bucket_index(value: f64, schema: i32) -> i32 {
+    (value.log2() * 2f64.powi(schema)).ceil() as i32
+}
+
+/// The same buckets at half the resolution: pairs of buckets merge
+fn downscale(buckets: &BTreeMap<i32, u64>) -> BTreeMap<i32, u64> {
+    let mut merged = BTreeMap::new();
+    for (index, count) in buckets {
+        *merged.entry((index + 1) >> 1).or_default() += count;
+    }
+    merged
+}
+
+/// Runs of consecutive buckets as (offset from the previous run, length),
+/// and the bucket counts as differences to the previous bucket
+fn spans_and_deltas(buckets: &BTreeMap<i32, u64>) -> (Vec<(i32, u32)>, Vec<i64>) {
+    let mut spans: Vec<(i32, u32)> = Vec::new();
+    let mut deltas = Vec::with_capacity(buckets.len());
+    let mut previous: Option<(i32, u64)> = None;
+    for (&index, &count) in buckets {
+        match previous {
+            Some((previous_index, _)) if index == previous_index + 1 => {
+                spans.last_mut().unwrap().1 += 1
+            }
+            Some((previous_index, _)) => spans.push((index - previous_index - 1, 1)),
+            None => spans.push((index, 1)),
+        }
+        let previous_count = previous.map_or(0, |(_, count)| count);
+        deltas.push(count as i64 - previous_count as i64);
+        previous = Some((index, count));
+    }
+    (spans, deltas)
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+pub(super) fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+struct Core {
+    classic: Histogram,
+    /// None when native histograms are turned off
+    native: Option<Mutex<Sparse>>,
+    /// Upper bounds of the classic buckets, without +Inf
+    bounds: Vec<f64>,
+    /// The latest exemplar per classic bucket, and one for +Inf
+    exemplars: Mutex<Vec<Option<Exemplar>>>,
+}
+
+/// A classic histogram, plus a native one when enabled
+#[derive(Clone)]
+pub struct NativeHistogram {
+    core: Arc<Core>,
+}
+
+impl NativeHistogram {
+    pub fn new(opts: HistogramOpts, native: bool) -> prometheus::Result<Self> {
+        // The same bounds the classic histogram ends up with
+        let mut bounds = if opts.buckets.is_empty() {
+            DEFAULT_BUCKETS.to_vec()
+        } else {
+            opts.buckets.clone()
+        };
+        if bounds.last() == Some(&f64::INFINITY) {
+            bounds.pop();
+        }
+        let exemplars = Mutex::new((0..=bounds.len()).map(|_| None).collect());
+        Ok(NativeHistogram {
+            core: Arc::new(Core {
+                classic: Histogram::with_opts(opts)?,
+                native: native.then(|| Mutex::new(Sparse::new())),
+                bounds,
+                exemplars,
+            }),
+        })
+    }
+
+    /// Observe the exemplar's value and keep the exemplar for its bucket
+    pub fn observe_with_exemplar(&self, exemplar: Exemplar) {
+        let value = exemplar.value();
+        let bucket = self.core.bounds.partition_point(|bound| *bound < value);
+        self.core.exemplars.lock().unwrap()[bucket] = Some(exemplar);
+        self.observe(value);
+    }
+
+    /// Observe `value`, with an exemplar if there is a valid `traceparent`
+    pub fn observe_with_traceparent(&self, value: f64, traceparent: Option<&str>) {
+        match traceparent.and_then(|traceparent| Exemplar::from_traceparent(traceparent, value)) {
+            Some(exemplar) => self.observe_with_exemplar(exemplar),
+            None => self.observe(value),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        match &self.core.native {
+            // Under the lock so a scrape sees both in the same state
+            Some(native) => {
+                let mut sparse = native.lock().unwrap();
+                sparse.observe(value);
+                self.core.classic.observe(value);
+            }
+            None => self.core.classic.observe(value),
+        }
+    }
+}
+
+impl Collector for NativeHistogram {
+    fn desc(&self) -> Vec<&Desc> {
+        self.core.classic.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let sparse = self
+            .core
+            .native
+            .as_ref()
+            .map(|native| native.lock().unwrap());
+        let mut families = self.core.classic.collect();
+        let exemplars = self.core.exemplars.lock().unwrap();
+        for metric in families.iter_mut().flat_map(|family| family.mut_metric()) {
+            let histogram = metric.histogram.mut_or_insert_default();
+            for (bucket, exemplar) in histogram.bucket.iter_mut().zip(exemplars.iter()) {
+                if let Some(exemplar) = exemplar {
+                    bucket
+                        .special_fields
+                        .mut_unknown_fields()
+                        .add_length_delimited(BUCKET_EXEMPLAR, exemplar.encode());
+                }
+            }
+            if let Some(sparse) = &sparse {
+                sparse.write_to(histogram);
+                for exemplar in exemplars.iter().flatten() {
+                    histogram
+                        .special_fields
+                        .mut_unknown_fields()
+                        .add_length_delimited(EXEMPLARS, exemplar.encode());
+                }
+            }
+        }
+        families
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::UnknownValueRef;
+
+    #[test]
+    fn buckets_cover_powers_of_the_base() {
+        // Schema 0: (0.5, 1], (1, 2], (2, 4], ...
+        assert_eq!(bucket_index(1.0, 0), 0);
+        assert_eq!(bucket_index(1.5, 0), 1);
+        assert_eq!(bucket_index(2.0, 0), 1);
+        assert_eq!(bucket_index(0.3, 0), -1);
+        // Schema 3 splits each of those in 8
+        assert_eq!(bucket_index(2.0, 3), 8);
+        assert_eq!(bucket_index(1.05, 3), 1);
+    }
+
+    #[test]
+    fn spans_skip_empty_buckets() {
+        let buckets = BTreeMap::from([(-2, 3), (-1, 1), (2, 4), (3, 4)]);
+        assert_eq!(
+            spans_and_deltas(&buckets),
+            (vec![(-2, 2), (2, 2)], vec![3, -2, 3, 0])
+        );
+    }
+
+    #[test]
+    fn downscales_past_the_bucket_limit() {
+        let mut sparse = Sparse::new();
+        for i in 0..=MAX_BUCKETS {
+            sparse.observe(2f64.powf((i as f64 + 0.5) / 8.0));
+        }
+        assert_eq!(sparse.schema, INITIAL_SCHEMA - 1);
+        assert_eq!(
+            sparse.positive.values().sum::<u64>(),
+            MAX_BUCKETS as u64 + 1
+        );
+        assert!(sparse.positive.len() <= MAX_BUCKETS);
+    }
+
+    #[test]
+    fn appends_native_fields_to_the_classic_histogram() {
+        let histogram =
+            NativeHistogram::new(HistogramOpts::new("op_seconds", "Operations"), true).unwrap();
+        histogram.observe(0.0);
+        histogram.observe(1.0);
+        histogram.observe(1.0);
+
+        let families = histogram.collect();
+        let proto = families[0].get_metric()[0].get_histogram();
+        assert_eq!(proto.get_sample_count(), 3);
+        assert!(!proto.get_bucket().is_empty());
+        let fields = proto.special_fields.unknown_fields();
+        assert_eq!(fields.get(SCHEMA), Some(UnknownValueRef::Varint(zigzag(3))));
+        assert_eq!(fields.get(ZERO_COUNT), Some(UnknownValueRef::Varint(1)));
+        // One span of one bucket at index 0 holding both observations
+        assert_eq!(
+            fields.get(POSITIVE_SPAN),
+            Some(UnknownValueRef::LengthDelimited(&[8, 0, 16, 1]))
+        );
+        assert_eq!(
+            fields.get(POSITIVE_DELTA),
+            Some(UnknownValueRef::LengthDelimited(&[4]))
+        );
+    }
+
+    #[test]
+    fn exports_exemplars_on_their_buckets() {
+        let opts = HistogramOpts::new("op_seconds", "Operations").buckets(vec![0.1, 1.0]);
+        let histogram = NativeHistogram::new(opts, true).unwrap();
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        histogram.observe_with_traceparent(0.5, Some(traceparent));
+        histogram.observe_with_traceparent(0.05, None);
+
+        let families = histogram.collect();
+        let proto = families[0].get_metric()[0].get_histogram();
+        let exemplar = |bucket: usize| {
+            proto.get_bucket()[bucket]
+                .special_fields
+                .unknown_fields()
+                .get(BUCKET_EXEMPLAR)
+        };
+        assert_eq!(exemplar(0), None);
+        let Some(UnknownValueRef::LengthDelimited(encoded)) = exemplar(1) else {
+            panic!("no exemplar on the 1.0 bucket");
+        };
+        let text = String::from_utf8_lossy(encoded);
+        assert!(text.contains("trace_id"));
+        assert!(text.contains("4bf92f3577b34da6a3ce929d0e0e4736"));
+        assert!(proto
+            .special_fields
+            .unknown_fields()
+            .get(EXEMPLARS)
+            .is_some());
+    }
+
+    #[test]
+    fn classic_only_when_disabled() {
+        let histogram =
+            NativeHistogram::new(HistogramOpts::new("op_seconds", "Operations"), false).unwrap();
+        histogram.observe(1.0);
+        let families = histogram.collect();
+        let proto = families[0].get_metric()[0].get_histogram();
+        assert_eq!(proto.special_fields.unknown_fields().iter().count(), 0);
+    }
+}
@@ -1,7 +1,14 @@
+use super::builder::metric;
+use super::demo::Simulation;
+use super::platform::{self, InterfaceStats, QdiscStats};
 use crate::collector::Collector;
-use prometheus::{register_counter, register_gauge, register_histogram, Counter, Gauge, Histogram};
+use crate::config::Config;
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::{Counter, CounterVec, Gauge, GaugeVec, Histogram};
+use std::collections::HashMap;
 use std::sync::Mutex;
-use sysinfo::{Networks, System};
+use std::time::Instant;
+use sysinfo::Networks;
 
 pub struct NetworkCollector {
     // Gauges for current values
@@ -18,84 +25,152 @@ pub struct NetworkCollector {
     network_packets_received_total: Counter,
     network_packets_transmitted_total: Counter,
 
-    // Histogram for network latency simulation (for learning)
+    // Histogram for network latency; there is no real source for it yet,
+    // so only demo mode observes it
     network_latency_histogram: Histogram,
 
-    #[allow(dead_code)]
-    system: Mutex<System>,
+    // Per queueing discipline, where shaped links drop packets
+    qdisc_bytes_total: CounterVec,
+    qdisc_packets_total: CounterVec,
+    qdisc_drops_total: CounterVec,
+    qdisc_overlimits_total: CounterVec,
+    qdisc_requeues_total: CounterVec,
+    qdisc_backlog_bytes: GaugeVec,
+    qdisc_backlog_packets: GaugeVec,
+
+    // Per-interface rates from the deltas between cycles
+    receive_bytes_per_second: GaugeVec,
+    transmit_bytes_per_second: GaugeVec,
+    last_cycle: Mutex<Option<Instant>>,
+
     networks: Mutex<Networks>,
+    // Previous native counters per interface, to turn them into deltas
+    last_interface_stats: Mutex<HashMap<String, InterfaceStats>>,
+    simulation: Option<Simulation>,
 }
 
 impl NetworkCollector {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
         // Gauge metrics (current snapshot values)
-        let network_bytes_received = register_gauge!(
+        let network_bytes_received = metric(
             "network_bytes_received",
-            "Current network bytes received per second"
+            "Current network bytes received per second",
         )
-        .unwrap();
+        .gauge();
 
-        let network_bytes_transmitted = register_gauge!(
+        let network_bytes_transmitted = metric(
             "network_bytes_transmitted",
-            "Current network bytes transmitted per second"
+            "Current network bytes transmitted per second",
         )
-        .unwrap();
+        .gauge();
 
-        let network_packets_received = register_gauge!(
+        let network_packets_received = metric(
             "network_packets_received",
-            "Current network packets received per second"
+            "Current network packets received per second",
         )
-        .unwrap();
+        .gauge();
 
-        let network_packets_transmitted = register_gauge!(
+        let network_packets_transmitted = metric(
             "network_packets_transmitted",
-            "Current network packets transmitted per second"
+            "Current network packets transmitted per second",
         )
-        .unwrap();
+        .gauge();
 
         let network_errors_received =
-            register_gauge!("network_errors_received", "Current network errors received").unwrap();
+            metric("network_errors_received", "Current network errors received").gauge();
 
-        let network_errors_transmitted = register_gauge!(
+        let network_errors_transmitted = metric(
             "network_errors_transmitted",
-            "Current network errors transmitted"
+            "Current network errors transmitted",
         )
-        .unwrap();
+        .gauge();
 
         // Counter metrics (cumulative, always increasing)
-        let network_bytes_received_total = register_counter!(
+        let network_bytes_received_total = metric(
             "network_bytes_received_total",
-            "Total network bytes received since start"
+            "Total network bytes received since start",
         )
-        .unwrap();
+        .counter();
 
-        let network_bytes_transmitted_total = register_counter!(
+        let network_bytes_transmitted_total = metric(
             "network_bytes_transmitted_total",
-            "Total network bytes transmitted since start"
+            "Total network bytes transmitted since start",
         )
-        .unwrap();
+        .counter();
 
-        let network_packets_received_total = register_counter!(
+        let network_packets_received_total = metric(
             "network_packets_received_total",
-            "Total network packets received since start"
+            "Total network packets received since start",
         )
-        .unwrap();
+        .counter();
 
-        let network_packets_transmitted_total = register_counter!(
+        let network_packets_transmitted_total = metric(
             "network_packets_transmitted_total",
-            "Total network packets transmitted since start"
+            "Total network packets transmitted since start",
         )
-        .unwrap();
+        .counter();
 
         // Histogram metric (for distribution of values)
-        let network_latency_histogram = register_histogram!(
+        let network_latency_histogram = metric(
             "network_latency_seconds",
             "Network latency distribution in seconds",
-            vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
         )
-        .unwrap();
+        .buckets(config.histogram_buckets(
+            "network_latency_seconds",
+            vec![
+                0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+            ],
+        ))
+        .histogram();
+
+        let qdisc_labels = ["interface", "qdisc", "handle"];
+        let qdisc_bytes_total = metric(
+            "network_qdisc_bytes_total",
+            "Bytes the queueing discipline sent",
+        )
+        .counter_vec(&qdisc_labels);
+        let qdisc_packets_total = metric(
+            "network_qdisc_packets_total",
+            "Packets the queueing discipline sent",
+        )
+        .counter_vec(&qdisc_labels);
+        let qdisc_drops_total = metric(
+            "network_qdisc_drops_total",
+            "Packets the queueing discipline dropped",
+        )
+        .counter_vec(&qdisc_labels);
+        let qdisc_overlimits_total = metric(
+            "network_qdisc_overlimits_total",
+            "Times the queueing discipline held back a packet for exceeding its rate",
+        )
+        .counter_vec(&qdisc_labels);
+        let qdisc_requeues_total = metric(
+            "network_qdisc_requeues_total",
+            "Packets the driver handed back to the queueing discipline",
+        )
+        .counter_vec(&qdisc_labels);
+        let qdisc_backlog_bytes = metric(
+            "network_qdisc_backlog_bytes",
+            "Bytes waiting in the queueing discipline",
+        )
+        .gauge_vec(&qdisc_labels);
+        let qdisc_backlog_packets = metric(
+            "network_qdisc_backlog_packets",
+            "Packets waiting in the queueing discipline",
+        )
+        .gauge_vec(&qdisc_labels);
+
+        let receive_bytes_per_second = metric(
+            "network_receive_bytes_per_second",
+            "Bytes the interface received per second since the previous cycle",
+        )
+        .gauge_vec(&["interface"]);
+        let transmit_bytes_per_second = metric(
+            "network_transmit_bytes_per_second",
+            "Bytes the interface transmitted per second since the previous cycle",
+        )
+        .gauge_vec(&["interface"]);
 
-        let system = Mutex::new(System::new_all());
         let networks = Mutex::new(Networks::new_with_refreshed_list());
 
         NetworkCollector {
@@ -110,37 +185,166 @@ impl NetworkCollector {
             network_packets_received_total,
             network_packets_transmitted_total,
             network_latency_histogram,
-            system,
+            qdisc_bytes_total,
+            qdisc_packets_total,
+            qdisc_drops_total,
+            qdisc_overlimits_total,
+            qdisc_requeues_total,
+            qdisc_backlog_bytes,
+            qdisc_backlog_packets,
+            receive_bytes_per_second,
+            transmit_bytes_per_second,
+            last_cycle: Mutex::new(None),
             networks,
+            last_interface_stats: Mutex::new(HashMap::new()),
+            simulation: config.demo.map(|seed| Simulation::new(seed, "network")),
+        }
+    }
+
+    /// Traffic of each interface since the previous cycle, from the
+    /// kernel's counters where there is a native backend and sysinfo
+    /// elsewhere. Interfaces seen for the first time only set the baseline.
+    fn traffic_since_last_cycle(&self) -> Vec<InterfaceStats> {
+        if let Some(interfaces) = platform::network_stats() {
+            let mut last = self.last_interface_stats.lock().unwrap();
+            let growth = interfaces
+                .iter()
+                .filter_map(|current| {
+                    let previous = last.get(&current.name)?;
+                    let growth = |prev: u64, cur: u64| cur.saturating_sub(prev);
+                    Some(InterfaceStats {
+                        name: current.name.clone(),
+                        received_bytes: growth(previous.received_bytes, current.received_bytes),
+                        transmitted_bytes: growth(
+                            previous.transmitted_bytes,
+                            current.transmitted_bytes,
+                        ),
+                        received_packets: growth(
+                            previous.received_packets,
+                            current.received_packets,
+                        ),
+                        transmitted_packets: growth(
+                            previous.transmitted_packets,
+                            current.transmitted_packets,
+                        ),
+                        received_errors: growth(previous.received_errors, current.received_errors),
+                        transmitted_errors: growth(
+                            previous.transmitted_errors,
+                            current.transmitted_errors,
+                        ),
+                    })
+                })
+                .collect();
+            *last = interfaces
+                .into_iter()
+                .map(|stats| (stats.name.clone(), stats))
+                .collect();
+            return growth;
+        }
+
+        let mut networks = self.networks.lock().unwrap();
+        networks.refresh();
+
+        // sysinfo already reports the change since the last refresh
+        networks
+            .iter()
+            .map(|(name, data)| InterfaceStats {
+                name: name.clone(),
+                received_bytes: data.received(),
+                transmitted_bytes: data.transmitted(),
+                received_packets: data.packets_received(),
+                transmitted_packets: data.packets_transmitted(),
+                received_errors: data.errors_on_received(),
+                transmitted_errors: data.errors_on_transmitted(),
+            })
+            .collect()
+    }
+
+    /// Per-interface rates over the time since the previous cycle, for
+    /// consumers that can't apply rate() themselves; nothing on the first
+    /// cycle
+    fn set_rates(&self, interfaces: &[InterfaceStats]) {
+        let now = Instant::now();
+        let Some(previous) = self.last_cycle.lock().unwrap().replace(now) else {
+            return;
+        };
+        let seconds = now.duration_since(previous).as_secs_f64();
+        if seconds <= 0.0 {
+            return;
+        }
+        self.receive_bytes_per_second.reset();
+        self.transmit_bytes_per_second.reset();
+        for interface in interfaces {
+            let labels = [interface.name.as_str()];
+            self.receive_bytes_per_second
+                .with_label_values(&labels)
+                .set(interface.received_bytes as f64 / seconds);
+            self.transmit_bytes_per_second
+                .with_label_values(&labels)
+                .set(interface.transmitted_bytes as f64 / seconds);
         }
     }
 }
 
 impl Collector for NetworkCollector {
-    fn register_metrics(&self) -> prometheus::Result<()> {
+    fn name(&self) -> &'static str {
+        "network"
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
         Ok(())
     }
 
-    fn collect_metrics(&self) {
-        let mut networks = self.networks.lock().unwrap();
-        networks.refresh();
+    fn platforms(&self) -> &'static [&'static str] {
+        platform::PLATFORMS
+    }
 
-        let mut total_received = 0u64;
-        let mut total_transmitted = 0u64;
-        let mut total_packets_received = 0u64;
-        let mut total_packets_transmitted = 0u64;
-        let mut total_errors_received = 0u64;
-        let mut total_errors_transmitted = 0u64;
-
-        // Aggregate data from all network interfaces
-        for (_interface_name, data) in networks.iter() {
-            total_received += data.received();
-            total_transmitted += data.transmitted();
-            total_packets_received += data.packets_received();
-            total_packets_transmitted += data.packets_transmitted();
-            total_errors_received += data.errors_on_received();
-            total_errors_transmitted += data.errors_on_transmitted();
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![
+            &self.network_bytes_received,
+            &self.network_bytes_transmitted,
+            &self.network_packets_received,
+            &self.network_packets_transmitted,
+            &self.network_errors_received,
+            &self.network_errors_transmitted,
+            &self.network_bytes_received_total,
+            &self.network_bytes_transmitted_total,
+            &self.network_packets_received_total,
+            &self.network_packets_transmitted_total,
+            &self.network_latency_histogram,
+            &self.qdisc_bytes_total,
+            &self.qdisc_packets_total,
+            &self.qdisc_drops_total,
+            &self.qdisc_overlimits_total,
+            &self.qdisc_requeues_total,
+            &self.qdisc_backlog_bytes,
+            &self.qdisc_backlog_packets,
+            &self.receive_bytes_per_second,
+            &self.transmit_bytes_per_second,
+        ]
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        let interfaces = match &self.simulation {
+            Some(simulation) => vec![simulate_traffic(simulation)],
+            None => self.traffic_since_last_cycle(),
+        };
+        self.set_rates(&interfaces);
+        let mut traffic = InterfaceStats::default();
+        for interface in &interfaces {
+            traffic.received_bytes += interface.received_bytes;
+            traffic.transmitted_bytes += interface.transmitted_bytes;
+            traffic.received_packets += interface.received_packets;
+            traffic.transmitted_packets += interface.transmitted_packets;
+            traffic.received_errors += interface.received_errors;
+            traffic.transmitted_errors += interface.transmitted_errors;
         }
+        let total_received = traffic.received_bytes;
+        let total_transmitted = traffic.transmitted_bytes;
+        let total_packets_received = traffic.received_packets;
+        let total_packets_transmitted = traffic.transmitted_packets;
+        let total_errors_received = traffic.received_errors;
+        let total_errors_transmitted = traffic.transmitted_errors;
 
         // Update gauge metrics (current values)
         self.network_bytes_received.set(total_received as f64);
@@ -154,8 +358,7 @@ impl Collector for NetworkCollector {
         self.network_errors_transmitted
             .set(total_errors_transmitted as f64);
 
-        // Update counter metrics (increment by current values)
-        // Note: In a real implementation, you'd track the delta since last measurement
+        // Update counter metrics (increment by the traffic since the last cycle)
         self.network_bytes_received_total
             .inc_by(total_received as f64);
         self.network_bytes_transmitted_total
@@ -165,9 +368,100 @@ impl Collector for NetworkCollector {
         self.network_packets_transmitted_total
             .inc_by(total_packets_transmitted as f64);
 
-        // Simulate network latency for histogram (for learning purposes)
-        // In real implementation, you'd measure actual network latency
-        let simulated_latency = (total_received as f64 / 1000000.0).clamp(0.001, 10.0);
-        self.network_latency_histogram.observe(simulated_latency);
+        let qdiscs = match &self.simulation {
+            Some(simulation) => {
+                self.network_latency_histogram
+                    .observe(simulation.between(0.001, 0.5));
+                simulate_qdiscs(simulation)
+            }
+            None => platform::qdisc_stats()?,
+        };
+        self.set_qdiscs(&qdiscs);
+        Ok(())
     }
 }
+
+impl NetworkCollector {
+    /// Replace the qdisc series, so removed qdiscs go away. The counters
+    /// are the kernel's own totals, so setting them afresh keeps them
+    /// monotonic.
+    fn set_qdiscs(&self, qdiscs: &[QdiscStats]) {
+        self.qdisc_bytes_total.reset();
+        self.qdisc_packets_total.reset();
+        self.qdisc_drops_total.reset();
+        self.qdisc_overlimits_total.reset();
+        self.qdisc_requeues_total.reset();
+        self.qdisc_backlog_bytes.reset();
+        self.qdisc_backlog_packets.reset();
+        for qdisc in qdiscs {
+            let labels = [
+                qdisc.interface.as_str(),
+                qdisc.kind.as_str(),
+                qdisc.handle.as_str(),
+            ];
+            self.qdisc_bytes_total
+                .with_label_values(&labels)
+                .inc_by(qdisc.bytes as f64);
+            self.qdisc_packets_total
+                .with_label_values(&labels)
+                .inc_by(qdisc.packets as f64);
+            self.qdisc_drops_total
+                .with_label_values(&labels)
+                .inc_by(qdisc.drops as f64);
+            self.qdisc_overlimits_total
+                .with_label_values(&labels)
+                .inc_by(qdisc.overlimits as f64);
+            self.qdisc_requeues_total
+                .with_label_values(&labels)
+                .inc_by(qdisc.requeues as f64);
+            self.qdisc_backlog_bytes
+                .with_label_values(&labels)
+                .set(qdisc.backlog_bytes as f64);
+            self.qdisc_backlog_packets
+                .with_label_values(&labels)
+                .set(qdisc.backlog_packets as f64);
+        }
+    }
+}
+
+/// Traffic of a moderately busy host, errors included now and then
+fn simulate_traffic(simulation: &Simulation) -> InterfaceStats {
+    let received_bytes = simulation.between(0.0, 1_000_000.0) as u64;
+    let transmitted_bytes = simulation.between(0.0, 500_000.0) as u64;
+    InterfaceStats {
+        name: "demo0".to_string(),
+        received_bytes,
+        transmitted_bytes,
+        // Roughly 1KB packets
+        received_packets: received_bytes / 1000,
+        transmitted_packets: transmitted_bytes / 1000,
+        received_errors: (simulation.next() < 0.05) as u64,
+        transmitted_errors: (simulation.next() < 0.02) as u64,
+    }
+}
+
+/// A shaped uplink: an htb root dropping a few packets a second when full
+fn simulate_qdiscs(simulation: &Simulation) -> Vec<QdiscStats> {
+    let elapsed = simulation.elapsed();
+    let backlog_packets = simulation.between(0.0, 40.0).round() as u64;
+    vec![
+        QdiscStats {
+            interface: "demo0".to_string(),
+            kind: "htb".to_string(),
+            handle: "1:".to_string(),
+            bytes: (elapsed * 500_000.0) as u64,
+            packets: (elapsed * 500.0) as u64,
+            drops: (elapsed * 2.0) as u64,
+            overlimits: (elapsed * 150.0) as u64,
+            requeues: (elapsed / 60.0) as u64,
+            backlog_bytes: backlog_packets * 1500,
+            backlog_packets,
+        },
+        QdiscStats {
+            interface: "lo".to_string(),
+            kind: "noqueue".to_string(),
+            handle: "0:".to_string(),
+            ..QdiscStats::default()
+        },
+    ]
+}
@@ -0,0 +1,221 @@
+//! nginx: `[services.nginx]` reads the `stub_status` page of each of
+//! `urls` and exports its connection and request counts, labeled with the
+//! URL as `instance`.
+
+use prometheus::core::Collector as PrometheusCollector;
+
+use super::service::{Reading, ServiceMetrics};
+use crate::collector::Collector;
+use crate::config::{Config, NginxConfig};
+use crate::fetch;
+
+pub struct NginxCollector {
+    config: Option<NginxConfig>,
+    metrics: ServiceMetrics,
+}
+
+/// What `stub_status` shows
+#[derive(Debug, PartialEq)]
+struct Status {
+    active: f64,
+    accepts: f64,
+    handled: f64,
+    requests: f64,
+    reading: f64,
+    writing: f64,
+    waiting: f64,
+}
+
+impl NginxCollector {
+    pub fn new(config: &Config) -> Self {
+        NginxCollector {
+            config: config.services.nginx.clone(),
+            metrics: ServiceMetrics::new("nginx", &["instance"]),
+        }
+    }
+}
+
+impl Collector for NginxCollector {
+    fn name(&self) -> &'static str {
+        "nginx"
+    }
+
+    fn register_metrics(&self, registry: &prometheus::Registry) -> prometheus::Result<()> {
+        super::builder::register(registry, &self.metrics)
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.metrics]
+    }
+
+    fn reset_metrics(&self, _registry: &prometheus::Registry) {
+        self.metrics.reset();
+    }
+
+    /// Fails only if no instance answered
+    fn collect_metrics(&self) -> Result<(), String> {
+        let Some(config) = &self.config else {
+            return Ok(());
+        };
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let reads: Vec<_> = config
+                .urls
+                .iter()
+                .map(|url| scope.spawn(move || read_status(url, config)))
+                .collect();
+            reads.into_iter().map(|read| read.join().unwrap()).collect()
+        });
+
+        let mut reading = Reading::default();
+        let mut first_error = None;
+        let mut answered = 0;
+        for (url, result) in config.urls.iter().zip(results) {
+            self.metrics.set_up(&[url], result.is_ok());
+            match result {
+                Ok(status) => {
+                    answered += 1;
+                    add(&mut reading, url, &status);
+                }
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        self.metrics.set(reading);
+        match first_error {
+            Some(e) if answered == 0 => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn read_status(url: &str, config: &NginxConfig) -> Result<Status, String> {
+    let (address, path) = fetch::parse_url(url)?;
+    let response = fetch::request(&address, "GET", &path, &[], config.timeout)?;
+    if response.status != 200 {
+        return Err(format!("{}: HTTP status {}", url, response.status));
+    }
+    parse(&String::from_utf8_lossy(&response.body))
+        .ok_or_else(|| format!("{}: not a stub_status page", url))
+}
+
+fn add(reading: &mut Reading, instance: &str, status: &Status) {
+    let labels = [("instance", instance)];
+    reading.gauge(
+        "nginx_connections_active",
+        "Open client connections, waiting ones included",
+        &labels,
+        status.active,
+    );
+    reading.counter(
+        "nginx_connections_accepted_total",
+        "Accepted client connections",
+        &labels,
+        status.accepts,
+    );
+    reading.counter(
+        "nginx_connections_handled_total",
+        "Handled client connections, fewer than accepted when a limit was hit",
+        &labels,
+        status.handled,
+    );
+    reading.counter(
+        "nginx_http_requests_total",
+        "Client requests",
+        &labels,
+        status.requests,
+    );
+    reading.gauge(
+        "nginx_connections_reading",
+        "Connections where nginx is reading the request header",
+        &labels,
+        status.reading,
+    );
+    reading.gauge(
+        "nginx_connections_writing",
+        "Connections where nginx is writing the response",
+        &labels,
+        status.writing,
+    );
+    reading.gauge(
+        "nginx_connections_waiting",
+        "Idle keep-alive connections",
+        &labels,
+        status.waiting,
+    );
+}
+
+/// ```text
+/// Active connections: 291
+/// server accepts handled requests
+///  16630948 16630948 31070465
+/// Reading: 6 Writing: 179 Waiting: 106
+/// ```
+fn parse(page: &str) -> Option<Status> {
+    let mut lines = page.lines();
+    let active = lines
+        .next()?
+        .strip_prefix("Active connections:")?
+        .trim()
+        .parse()
+        .ok()?;
+    lines.next()?;
+    let totals: Vec<f64> = lines
+        .next()?
+        .split_whitespace()
+        .map(|total| total.parse().ok())
+        .collect::<Option<_>>()?;
+    let [accepts, handled, requests] = totals[..] else {
+        return None;
+    };
+    let states: Vec<&str> = lines.next()?.split_whitespace().collect();
+    let state = |name: &str| -> Option<f64> {
+        let i = states.iter().position(|word| *word == name)?;
+        states.get(i + 1)?.parse().ok()
+    };
+    Some(Status {
+        active,
+        accepts,
+        handled,
+        requests,
+        reading: state("Reading:")?,
+        writing: state("Writing:")?,
+        waiting: state("Waiting:")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_stub_status() {
+        let page = "Active connections: 291 \n\
+                    server accepts handled requests\n \
+                    16630948 16630947 31070465 \n\
+                    Reading: 6 Writing: 179 Waiting: 106 \n";
+        let status = parse(page).unwrap();
+        assert_eq!(
+            status,
+            Status {
+                active: 291.0,
+                accepts: 16630948.0,
+                handled: 16630947.0,
+                requests: 31070465.0,
+                reading: 6.0,
+                writing: 179.0,
+                waiting: 106.0,
+            }
+        );
+        let mut reading = Reading::default();
+        add(&mut reading, "http://127.0.0.1/nginx_status", &status);
+        assert_eq!(
+            reading.value(
+                "nginx_http_requests_total",
+                &[("instance", "http://127.0.0.1/nginx_status")]
+            ),
+            Some(31070465.0)
+        );
+        assert_eq!(parse("<html>Welcome to nginx!</html>"), None);
+    }
+}
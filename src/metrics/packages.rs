@@ -0,0 +1,364 @@
+//! Available package updates by severity, from whichever of apt-get, dnf
+//! and zypper is installed. `[packages]` turns it on. The package manager
+//! runs in a thread of its own every `packages.interval`, from its caches
+//! only and under `packages.timeout`, so a slow or hung one never holds up
+//! a cycle. Running programs needs `security.landlock` and
+//! `security.seccomp` off.
+
+use super::builder::metric;
+use crate::collector::Collector;
+use crate::config::{Config, PackagesConfig};
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::{Gauge, GaugeVec};
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Updates per severity: critical, important, moderate or low for rated
+/// security updates, security for unrated ones and other for the rest
+type Counts = Vec<(&'static str, f64)>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Manager {
+    Apt,
+    Dnf,
+    Zypper,
+}
+
+impl Manager {
+    /// The first package manager installed
+    fn detect() -> Option<Manager> {
+        [
+            ("/usr/bin/apt-get", Manager::Apt),
+            ("/usr/bin/dnf", Manager::Dnf),
+            ("/usr/bin/zypper", Manager::Zypper),
+        ]
+        .into_iter()
+        .find(|(program, _)| Path::new(program).exists())
+        .map(|(_, manager)| manager)
+    }
+
+    fn command(self) -> Command {
+        let (program, args): (&str, &[&str]) = match self {
+            Manager::Apt => (
+                "/usr/bin/apt-get",
+                &["--simulate", "--quiet", "dist-upgrade"],
+            ),
+            Manager::Dnf => (
+                "/usr/bin/dnf",
+                &["--cacheonly", "--quiet", "updateinfo", "list", "--updates"],
+            ),
+            Manager::Zypper => (
+                "/usr/bin/zypper",
+                &["--non-interactive", "--no-refresh", "list-patches"],
+            ),
+        };
+        let mut command = Command::new(program);
+        command.args(args).env("LC_ALL", "C");
+        command
+    }
+
+    fn parse(self, output: &str) -> Counts {
+        match self {
+            Manager::Apt => parse_apt(output),
+            Manager::Dnf => parse_dnf(output),
+            Manager::Zypper => parse_zypper(output),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    running: bool,
+    last_started: Option<Instant>,
+    /// The latest check's outcome, until a cycle picks it up
+    finished: Option<Result<Counts, String>>,
+}
+
+pub struct PackagesCollector {
+    config: Option<PackagesConfig>,
+    updates_pending: GaugeVec,
+    last_check: Gauge,
+    state: Arc<Mutex<State>>,
+    /// Landlock and seccomp both keep programs from running
+    sandboxed: bool,
+    demo: bool,
+}
+
+impl PackagesCollector {
+    pub fn new(config: &Config) -> Self {
+        PackagesCollector {
+            config: config.packages.clone(),
+            updates_pending: metric(
+                "package_updates_pending",
+                "Updates the package manager can install, by severity",
+            )
+            .gauge_vec(&["severity"]),
+            last_check: metric(
+                "package_updates_last_check_timestamp_seconds",
+                "When the package manager last answered",
+            )
+            .gauge(),
+            state: Arc::new(Mutex::new(State::default())),
+            sandboxed: config.security.landlock || config.security.seccomp,
+            demo: config.demo.is_some(),
+        }
+    }
+
+    fn set(&self, counts: &[(&'static str, f64)]) {
+        self.updates_pending.reset();
+        for (severity, count) in counts {
+            self.updates_pending
+                .with_label_values(&[severity])
+                .set(*count);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_check.set(now.as_secs_f64());
+    }
+}
+
+impl Collector for PackagesCollector {
+    fn name(&self) -> &'static str {
+        "packages"
+    }
+
+    fn platforms(&self) -> &'static [&'static str] {
+        &["linux"]
+    }
+
+    /// Only once `[packages]` asks for it
+    fn unavailable(&self) -> Option<String> {
+        if self.config.is_none() || self.demo {
+            return None;
+        }
+        if self.sandboxed {
+            return Some(
+                "running a package manager needs security.landlock and security.seccomp off"
+                    .to_string(),
+            );
+        }
+        match Manager::detect() {
+            Some(_) => None,
+            None => Some("no apt-get, dnf or zypper found".to_string()),
+        }
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
+        Ok(())
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.updates_pending, &self.last_check]
+    }
+
+    /// Starts a check when one is due and fails once for each check that
+    /// failed
+    fn collect_metrics(&self) -> Result<(), String> {
+        let Some(config) = &self.config else {
+            return Ok(());
+        };
+        if self.demo {
+            self.set(&[("important", 1.0), ("moderate", 2.0), ("other", 7.0)]);
+            return Ok(());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let finished = state.finished.take();
+        let due = state
+            .last_started
+            .is_none_or(|started| started.elapsed() >= config.interval);
+        if !state.running && due {
+            let manager = Manager::detect().ok_or("no apt-get, dnf or zypper found")?;
+            state.running = true;
+            state.last_started = Some(Instant::now());
+            let shared = Arc::clone(&self.state);
+            let timeout = config.timeout;
+            std::thread::Builder::new()
+                .name("package-updates".to_string())
+                .spawn(move || {
+                    let result = check(manager, timeout);
+                    let mut state = shared.lock().unwrap();
+                    state.running = false;
+                    state.finished = Some(result);
+                })
+                .map_err(|e| format!("cannot start the update check: {}", e))?;
+        }
+        drop(state);
+
+        match finished {
+            Some(Ok(counts)) => {
+                self.set(&counts);
+                Ok(())
+            }
+            Some(Err(e)) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Run the package manager, killing it after `timeout`
+fn check(manager: Manager, timeout: Duration) -> Result<Counts, String> {
+    let mut command = manager.command();
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("{}: {}", program, e))?;
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    // Drained on the side so a full pipe can't stall the package manager
+    let reader = std::thread::spawn(move || {
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).map(|_| output)
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() < deadline => {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("{}: timed out after {:?}", program, timeout));
+            }
+            Err(e) => return Err(format!("{}: {}", program, e)),
+        }
+    };
+    let output = reader
+        .join()
+        .expect("reader panicked")
+        .map_err(|e| format!("{}: {}", program, e))?;
+    if !status.success() {
+        return Err(format!("{}: {}", program, status));
+    }
+    Ok(manager.parse(&output))
+}
+
+fn add(counts: &mut Counts, severity: &'static str) {
+    match counts.iter_mut().find(|(known, _)| *known == severity) {
+        Some((_, count)) => *count += 1.0,
+        None => counts.push((severity, 1.0)),
+    }
+}
+
+/// A rating as dnf and zypper spell it, or `security` for others
+fn security_severity(rating: &str) -> &'static str {
+    match rating.to_ascii_lowercase().as_str() {
+        "critical" => "critical",
+        "important" => "important",
+        "moderate" => "moderate",
+        "low" => "low",
+        _ => "security",
+    }
+}
+
+/// `Inst libssl3 [3.0.11-1] (3.0.13-1 Debian-Security:12/stable-security [amd64])`,
+/// security updates being the ones from a security archive
+fn parse_apt(output: &str) -> Counts {
+    let mut counts = Counts::new();
+    for line in output.lines().filter(|line| line.starts_with("Inst ")) {
+        let origins = line.rsplit_once(" (").map_or("", |(_, origins)| origins);
+        let severity = if origins.to_ascii_lowercase().contains("security") {
+            "security"
+        } else {
+            "other"
+        };
+        add(&mut counts, severity);
+    }
+    counts
+}
+
+/// `RHSA-2024:1234 Important/Sec. openssl-1:3.0.7-25.el9.x86_64`, one line
+/// per package an advisory updates
+fn parse_dnf(output: &str) -> Counts {
+    let mut counts = Counts::new();
+    for line in output.lines() {
+        let [_, kind, _] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+            continue;
+        };
+        let severity = match kind.strip_suffix("/Sec.") {
+            Some(rating) => security_severity(rating),
+            None if kind == "security" => "security",
+            None => "other",
+        };
+        add(&mut counts, severity);
+    }
+    counts
+}
+
+/// A table with `Category`, `Severity` and `Status` columns, in an order
+/// that depends on the zypper version; one row per needed patch
+fn parse_zypper(output: &str) -> Counts {
+    let mut counts = Counts::new();
+    let mut columns: Option<(usize, usize, usize)> = None;
+    for line in output.lines() {
+        let cells: Vec<&str> = line.split('|').map(str::trim).collect();
+        let Some((category, severity, status)) = columns else {
+            let find = |name: &str| cells.iter().position(|cell| *cell == name);
+            if let (Some(category), Some(severity), Some(status)) =
+                (find("Category"), find("Severity"), find("Status"))
+            {
+                columns = Some((category, severity, status));
+            }
+            continue;
+        };
+        let (Some(kind), Some(rating), Some(status)) =
+            (cells.get(category), cells.get(severity), cells.get(status))
+        else {
+            continue;
+        };
+        if *status != "needed" {
+            continue;
+        }
+        let severity = if *kind == "security" {
+            security_severity(rating)
+        } else {
+            "other"
+        };
+        add(&mut counts, severity);
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_package_managers() {
+        let apt = "NOTE: This is only a simulation!\n\
+                   Inst libssl3 [3.0.11-1~deb12u1] (3.0.11-1~deb12u2 Debian-Security:12/stable-security [amd64])\n\
+                   Inst tzdata [2024a-0+deb12u1] (2025a-0+deb12u1 Debian:12.9/stable [all])\n\
+                   Inst curl [7.81.0-1] (7.81.0-1ubuntu1.20 Ubuntu:22.04/jammy-updates, Ubuntu:22.04/jammy-security [amd64])\n\
+                   Conf libssl3 (3.0.11-1~deb12u2 Debian-Security:12/stable-security [amd64])\n";
+        assert_eq!(parse_apt(apt), vec![("security", 2.0), ("other", 1.0)]);
+
+        let dnf = "RHSA-2024:1234 Important/Sec. openssl-1:3.0.7-25.el9_3.x86_64\n\
+                   RHSA-2024:1234 Important/Sec. openssl-libs-1:3.0.7-25.el9_3.x86_64\n\
+                   RHBA-2024:0042 bugfix        tzdata-2024a-1.el9.noarch\n\
+                   FEDORA-2024-7 security       curl-8.6.0-7.fc40.x86_64\n";
+        assert_eq!(
+            parse_dnf(dnf),
+            vec![("important", 2.0), ("other", 1.0), ("security", 1.0)]
+        );
+
+        let zypper = "Loading repository data...\n\
+                      Repository  | Name                 | Category    | Severity  | Interactive | Status | Summary\n\
+                      ------------+----------------------+-------------+-----------+-------------+--------+--------\n\
+                      repo-update | SUSE-2024-1 | security    | critical  | ---         | needed | Security update for glibc\n\
+                      repo-update | SUSE-2024-2 | recommended | moderate  | ---         | needed | Recommended update for tzdata\n\
+                      repo-update | SUSE-2024-3 | security    | important | ---         | applied | Security update for curl\n";
+        assert_eq!(
+            parse_zypper(zypper),
+            vec![("critical", 1.0), ("other", 1.0)]
+        );
+    }
+}
@@ -0,0 +1,309 @@
+use std::ffi::CStr;
+use std::mem;
+use std::ptr;
+
+use libc::c_int;
+
+#[cfg(target_os = "freebsd")]
+use super::Virtualization;
+use super::{CpuTimes, DiskStats, InterfaceStats};
+
+/// kern.cp_time: ticks per CPU state summed over all CPUs, scaled by the
+/// statistics clock rate
+pub fn cpu_times() -> Option<CpuTimes> {
+    let ticks = sys::cp_time()?;
+    let hz = sys::stat_hz()? as f64;
+    let tick = |state: usize| ticks[state] as f64 / hz;
+
+    Some(CpuTimes {
+        user: tick(sys::CP_USER) + tick(sys::CP_NICE),
+        system: sys::SYSTEM_STATES.iter().map(|s| tick(*s)).sum(),
+        idle: tick(sys::CP_IDLE),
+        // Not accounted by the BSD kernels
+        steal: 0.0,
+    })
+}
+
+/// kern.vm_guest, which already uses names close to systemd's, and whether
+/// we're in a jail
+#[cfg(target_os = "freebsd")]
+pub fn virtualization() -> Option<Virtualization> {
+    let guest = sysctl_bytes_by_name(c"kern.vm_guest")?;
+    let hypervisor = match CStr::from_bytes_until_nul(&guest).ok()?.to_str().ok()? {
+        "hv" => "microsoft",
+        "vbox" => "oracle",
+        "generic" => "other",
+        guest => guest,
+    };
+    let jailed = sysctl_bytes_by_name(c"security.jail.jailed")
+        .and_then(|value| Some(c_int::from_ne_bytes(value.get(..4)?.try_into().ok()?)));
+    Some(Virtualization {
+        hypervisor: hypervisor.to_string(),
+        container: if jailed == Some(1) { "jail" } else { "none" }.to_string(),
+    })
+}
+
+pub fn disk_stats() -> Option<DiskStats> {
+    sys::disk_stats()
+}
+
+/// Link-level (AF_LINK) entries from getifaddrs carry the interface's
+/// `struct if_data` counters
+pub fn network_stats() -> Option<Vec<InterfaceStats>> {
+    let mut interfaces = Vec::new();
+    let mut addrs: *mut libc::ifaddrs = ptr::null_mut();
+
+    unsafe {
+        if libc::getifaddrs(&mut addrs) != 0 {
+            return None;
+        }
+
+        let mut cursor = addrs;
+        while !cursor.is_null() {
+            let ifa = &*cursor;
+            cursor = ifa.ifa_next;
+
+            if ifa.ifa_addr.is_null()
+                || ifa.ifa_data.is_null()
+                || c_int::from((*ifa.ifa_addr).sa_family) != libc::AF_LINK
+            {
+                continue;
+            }
+
+            let data = &*(ifa.ifa_data as *const libc::if_data);
+            interfaces.push(InterfaceStats {
+                name: CStr::from_ptr(ifa.ifa_name).to_string_lossy().into_owned(),
+                received_bytes: data.ifi_ibytes,
+                transmitted_bytes: data.ifi_obytes,
+                received_packets: data.ifi_ipackets,
+                transmitted_packets: data.ifi_opackets,
+                received_errors: data.ifi_ierrors,
+                transmitted_errors: data.ifi_oerrors,
+            });
+        }
+
+        libc::freeifaddrs(addrs);
+    }
+
+    Some(interfaces)
+}
+
+/// Read a fixed-size value by MIB
+fn sysctl_value<T: Copy>(mib: &[c_int]) -> Option<T> {
+    let mut value = mem::MaybeUninit::<T>::uninit();
+    let mut len = mem::size_of::<T>();
+    let rc = unsafe {
+        libc::sysctl(
+            mib.as_ptr(),
+            mib.len() as libc::c_uint,
+            value.as_mut_ptr().cast(),
+            &mut len,
+            ptr::null_mut(),
+            0,
+        )
+    };
+    if rc != 0 || len != mem::size_of::<T>() {
+        return None;
+    }
+    Some(unsafe { value.assume_init() })
+}
+
+/// Read a variable-length value by MIB. The size is queried first; tables
+/// such as the disk list can grow in between, so leave some headroom.
+#[cfg(target_os = "openbsd")]
+fn sysctl_bytes(mib: &[c_int]) -> Option<Vec<u8>> {
+    let mut len = 0;
+    unsafe {
+        if libc::sysctl(
+            mib.as_ptr(),
+            mib.len() as libc::c_uint,
+            ptr::null_mut(),
+            &mut len,
+            ptr::null_mut(),
+            0,
+        ) != 0
+        {
+            return None;
+        }
+        len += len / 8;
+        let mut buf = vec![0u8; len];
+        if libc::sysctl(
+            mib.as_ptr(),
+            mib.len() as libc::c_uint,
+            buf.as_mut_ptr().cast(),
+            &mut len,
+            ptr::null_mut(),
+            0,
+        ) != 0
+        {
+            return None;
+        }
+        buf.truncate(len);
+        Some(buf)
+    }
+}
+
+/// Same as `sysctl_bytes` but by name, for nodes without a fixed MIB
+#[cfg(target_os = "freebsd")]
+fn sysctl_bytes_by_name(name: &CStr) -> Option<Vec<u8>> {
+    let mut len = 0;
+    unsafe {
+        if libc::sysctlbyname(name.as_ptr(), ptr::null_mut(), &mut len, ptr::null(), 0) != 0 {
+            return None;
+        }
+        len += len / 8;
+        let mut buf = vec![0u8; len];
+        if libc::sysctlbyname(
+            name.as_ptr(),
+            buf.as_mut_ptr().cast(),
+            &mut len,
+            ptr::null(),
+            0,
+        ) != 0
+        {
+            return None;
+        }
+        buf.truncate(len);
+        Some(buf)
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+mod sys {
+    use std::mem;
+
+    use libc::{c_long, c_void};
+
+    use super::{sysctl_bytes_by_name, sysctl_value, DiskStats};
+
+    pub const CP_USER: usize = libc::CP_USER as usize;
+    pub const CP_NICE: usize = libc::CP_NICE as usize;
+    pub const CP_IDLE: usize = libc::CP_IDLE as usize;
+    pub const SYSTEM_STATES: &[usize] = &[libc::CP_SYS as usize, libc::CP_INTR as usize];
+
+    pub fn cp_time() -> Option<[c_long; libc::CPUSTATES as usize]> {
+        let mut ticks = [0 as c_long; libc::CPUSTATES as usize];
+        let mut len = mem::size_of_val(&ticks);
+        let rc = unsafe {
+            libc::sysctlbyname(
+                c"kern.cp_time".as_ptr(),
+                ticks.as_mut_ptr() as *mut c_void,
+                &mut len,
+                std::ptr::null(),
+                0,
+            )
+        };
+        (rc == 0 && len == mem::size_of_val(&ticks)).then_some(ticks)
+    }
+
+    pub fn stat_hz() -> Option<i32> {
+        let clock: libc::clockinfo = sysctl_value(&[libc::CTL_KERN, libc::KERN_CLOCKRATE])?;
+        // stathz is 0 on systems without a separate statistics clock
+        let hz = if clock.stathz > 0 {
+            clock.stathz
+        } else {
+            clock.hz
+        };
+        (hz > 0).then_some(hz)
+    }
+
+    /// kern.devstat.all is a generation number followed by one `struct
+    /// devstat` per device. Only the byte and operation counters are read,
+    /// by offset, since the struct's enum fields may hold combined flags.
+    pub fn disk_stats() -> Option<DiskStats> {
+        let buf = sysctl_bytes_by_name(c"kern.devstat.all")?;
+        let entries = buf.get(mem::size_of::<c_long>()..)?;
+        let size = mem::size_of::<libc::devstat>();
+
+        let read = libc::DEVSTAT_READ as usize;
+        let write = libc::DEVSTAT_WRITE as usize;
+        let mut stats = DiskStats::default();
+        for entry in entries.chunks_exact(size) {
+            let counters = |offset: usize| -> [u64; libc::DEVSTAT_N_TRANS_FLAGS as usize] {
+                unsafe { std::ptr::read_unaligned(entry.as_ptr().add(offset).cast()) }
+            };
+            let bytes = counters(mem::offset_of!(libc::devstat, bytes));
+            let operations = counters(mem::offset_of!(libc::devstat, operations));
+            stats.reads += operations[read];
+            stats.writes += operations[write];
+            stats.read_bytes += bytes[read];
+            stats.write_bytes += bytes[write];
+        }
+        Some(stats)
+    }
+}
+
+#[cfg(target_os = "openbsd")]
+mod sys {
+    use std::mem;
+
+    use libc::{c_char, c_int, c_long};
+
+    use super::{sysctl_bytes, sysctl_value, DiskStats};
+
+    // sys/sched.h; libc only exposes KERN_CPTIME
+    pub const CP_USER: usize = 0;
+    pub const CP_NICE: usize = 1;
+    pub const CP_IDLE: usize = 5;
+    const CPUSTATES: usize = 6;
+    // CP_SYS, CP_SPIN and CP_INTR
+    pub const SYSTEM_STATES: &[usize] = &[2, 3, 4];
+
+    // sys/sysctl.h
+    const HW_DISKSTATS: c_int = 9;
+
+    /// sys/time.h; mirrors the kernel layout, so not every field is read
+    #[allow(dead_code)]
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct ClockInfo {
+        hz: c_int,
+        tick: c_int,
+        stathz: c_int,
+        profhz: c_int,
+    }
+
+    /// sys/disk.h
+    #[allow(dead_code)]
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct DiskStatsEntry {
+        name: [c_char; 16],
+        busy: c_int,
+        rxfer: u64,
+        wxfer: u64,
+        seek: u64,
+        rbytes: u64,
+        wbytes: u64,
+        attachtime: libc::timeval,
+        timestamp: libc::timeval,
+        time: libc::timeval,
+    }
+
+    pub fn cp_time() -> Option<[c_long; CPUSTATES]> {
+        sysctl_value(&[libc::CTL_KERN, libc::KERN_CPTIME])
+    }
+
+    pub fn stat_hz() -> Option<i32> {
+        let clock: ClockInfo = sysctl_value(&[libc::CTL_KERN, libc::KERN_CLOCKRATE])?;
+        let hz = if clock.stathz > 0 {
+            clock.stathz
+        } else {
+            clock.hz
+        };
+        (hz > 0).then_some(hz)
+    }
+
+    pub fn disk_stats() -> Option<DiskStats> {
+        let buf = sysctl_bytes(&[libc::CTL_HW, HW_DISKSTATS])?;
+        let mut stats = DiskStats::default();
+        for entry in buf.chunks_exact(mem::size_of::<DiskStatsEntry>()) {
+            let entry: DiskStatsEntry = unsafe { std::ptr::read_unaligned(entry.as_ptr().cast()) };
+            stats.reads += entry.rxfer;
+            stats.writes += entry.wxfer;
+            stats.read_bytes += entry.rbytes;
+            stats.write_bytes += entry.wbytes;
+        }
+        Some(stats)
+    }
+}
@@ -0,0 +1,358 @@
+use super::procfs::{HostFs, ProcFs};
+use super::{CpuTimes, DiskStats, InterfaceStats, Virtualization};
+
+// /proc/diskstats counts sectors of 512 bytes regardless of the device
+const SECTOR_SIZE: u64 = 512;
+
+/// Prefixes of DMI vendor and product strings and the hypervisor they
+/// mean, after systemd-detect-virt
+const DMI_VENDORS: &[(&str, &str)] = &[
+    ("KVM", "kvm"),
+    ("OpenStack", "kvm"),
+    ("KubeVirt", "kvm"),
+    ("Amazon EC2", "amazon"),
+    ("QEMU", "qemu"),
+    ("VMware", "vmware"),
+    ("VMW", "vmware"),
+    ("innotek GmbH", "oracle"),
+    ("VirtualBox", "oracle"),
+    ("Xen", "xen"),
+    ("Bochs", "bochs"),
+    ("Parallels", "parallels"),
+    ("BHYVE", "bhyve"),
+    ("Hyper-V", "microsoft"),
+    ("Apple Virtualization", "apple"),
+    ("Google Compute Engine", "google"),
+];
+
+pub fn cpu_times() -> Option<CpuTimes> {
+    let hz = match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        hz if hz > 0 => hz as f64,
+        _ => 100.0,
+    };
+    read_cpu_times(&HostFs, hz)
+}
+
+pub fn disk_stats() -> Option<DiskStats> {
+    read_disk_stats(&HostFs)
+}
+
+pub fn virtualization() -> Option<Virtualization> {
+    let mut virtualization = detect_virtualization(&HostFs);
+    if let Some(hypervisor) = cpuid_hypervisor() {
+        virtualization.hypervisor = hypervisor.to_string();
+    }
+    Some(virtualization)
+}
+
+/// The vendor signature at CPUID leaf 0x40000000, which names the
+/// hypervisor itself: DMI only shows what firmware it emulates, "QEMU" for
+/// KVM too
+#[cfg(target_arch = "x86_64")]
+fn cpuid_hypervisor() -> Option<&'static str> {
+    use std::arch::x86_64::__cpuid;
+
+    // The "hypervisor present" bit
+    if __cpuid(1).ecx & (1 << 31) == 0 {
+        return None;
+    }
+    let leaf = __cpuid(0x4000_0000);
+    let signature = [leaf.ebx, leaf.ecx, leaf.edx]
+        .map(u32::to_le_bytes)
+        .concat();
+    match &signature[..] {
+        b"KVMKVMKVM\0\0\0" => Some("kvm"),
+        b"XenVMMXenVMM" => Some("xen"),
+        b"VMwareVMware" => Some("vmware"),
+        b"Microsoft Hv" => Some("microsoft"),
+        b"bhyve bhyve " => Some("bhyve"),
+        b"TCGTCGTCGTCG" => Some("qemu"),
+        b"VBoxVBoxVBox" => Some("oracle"),
+        b" lrpepyh  vr" => Some("parallels"),
+        _ => None,
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn cpuid_hypervisor() -> Option<&'static str> {
+    None
+}
+
+pub fn network_stats() -> Option<Vec<InterfaceStats>> {
+    read_network_stats(&HostFs)
+}
+
+/// Aggregate "cpu" line of /proc/stat, in USER_HZ ticks
+fn read_cpu_times(fs: &dyn ProcFs, hz: f64) -> Option<CpuTimes> {
+    let stat = fs.read_to_string("/proc/stat")?;
+    let line = stat.lines().find(|line| line.starts_with("cpu "))?;
+    let ticks: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .filter_map(|field| field.parse().ok())
+        .collect();
+    if ticks.len() < 4 {
+        return None;
+    }
+    let field = |i: usize| ticks.get(i).copied().unwrap_or(0) as f64;
+
+    // user nice system idle iowait irq softirq steal ...
+    Some(CpuTimes {
+        user: (field(0) + field(1)) / hz,
+        system: (field(2) + field(5) + field(6)) / hz,
+        idle: (field(3) + field(4)) / hz,
+        steal: field(7) / hz,
+    })
+}
+
+fn detect_virtualization(fs: &dyn ProcFs) -> Virtualization {
+    Virtualization {
+        hypervisor: detect_hypervisor(fs).to_string(),
+        container: detect_container(fs),
+    }
+}
+
+/// From DMI, which paravirtualized Xen guests lack, then the CPU's
+/// hypervisor flag for anything else
+fn detect_hypervisor(fs: &dyn ProcFs) -> &'static str {
+    let dmi = |name: &str| {
+        fs.read_to_string(&format!("/sys/class/dmi/id/{}", name))
+            .map(|value| value.trim().to_string())
+            .unwrap_or_default()
+    };
+    for field in ["sys_vendor", "product_name", "board_vendor", "bios_vendor"] {
+        let value = dmi(field);
+        if let Some((_, hypervisor)) = DMI_VENDORS
+            .iter()
+            .find(|(prefix, _)| value.starts_with(prefix))
+        {
+            return hypervisor;
+        }
+    }
+    if dmi("sys_vendor").starts_with("Microsoft") && dmi("product_name") == "Virtual Machine" {
+        return "microsoft";
+    }
+    let xen = fs
+        .read_to_string("/sys/hypervisor/type")
+        .is_some_and(|kind| kind.trim() == "xen");
+    if xen || fs.exists("/proc/xen/capabilities") {
+        return "xen";
+    }
+    let flagged = fs.read_to_string("/proc/cpuinfo").is_some_and(|cpuinfo| {
+        cpuinfo
+            .lines()
+            .filter(|line| line.starts_with("flags"))
+            .any(|line| line.split_whitespace().any(|flag| flag == "hypervisor"))
+    });
+    if flagged {
+        "other"
+    } else {
+        "none"
+    }
+}
+
+/// From what runtimes leave for the container's init, then from the
+/// cgroup of PID 1
+fn detect_container(fs: &dyn ProcFs) -> String {
+    if let Some(container) = fs.read_to_string("/run/systemd/container") {
+        return container.trim().to_string();
+    }
+    let environ = fs.read_to_string("/proc/1/environ").unwrap_or_default();
+    if let Some(container) = environ
+        .split('\0')
+        .find_map(|variable| variable.strip_prefix("container="))
+    {
+        return container.to_string();
+    }
+    if fs.exists("/run/.containerenv") {
+        return "podman".to_string();
+    }
+    if fs.exists("/.dockerenv") {
+        return "docker".to_string();
+    }
+    let cgroup = fs.read_to_string("/proc/1/cgroup").unwrap_or_default();
+    for (marker, container) in [
+        ("kubepods", "kubernetes"),
+        ("/docker", "docker"),
+        ("/lxc", "lxc"),
+    ] {
+        if cgroup.contains(marker) {
+            return container.to_string();
+        }
+    }
+    let release = fs
+        .read_to_string("/proc/sys/kernel/osrelease")
+        .unwrap_or_default()
+        .to_lowercase();
+    if release.contains("microsoft") {
+        return "wsl".to_string();
+    }
+    "none".to_string()
+}
+
+/// Totals from /proc/diskstats over whole physical disks. Partitions, loop,
+/// device-mapper and md devices are skipped so I/O isn't counted twice.
+fn read_disk_stats(fs: &dyn ProcFs) -> Option<DiskStats> {
+    let diskstats = fs.read_to_string("/proc/diskstats")?;
+    let mut stats = DiskStats::default();
+
+    for line in diskstats.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 10 || !is_physical_disk(fs, fields[2]) {
+            continue;
+        }
+        let field = |i: usize| fields[i].parse::<u64>().unwrap_or(0);
+        stats.reads += field(3);
+        stats.read_bytes += field(5) * SECTOR_SIZE;
+        stats.writes += field(7);
+        stats.write_bytes += field(9) * SECTOR_SIZE;
+    }
+
+    Some(stats)
+}
+
+/// Only whole disks backed by real hardware have a device link in /sys/block
+fn is_physical_disk(fs: &dyn ProcFs, name: &str) -> bool {
+    fs.exists(&format!("/sys/block/{}/device", name))
+}
+
+/// Per-interface counters from /proc/net/dev
+fn read_network_stats(fs: &dyn ProcFs) -> Option<Vec<InterfaceStats>> {
+    let dev = fs.read_to_string("/proc/net/dev")?;
+    let mut interfaces = Vec::new();
+
+    // The first two lines are column headers
+    for line in dev.lines().skip(2) {
+        let Some((name, counters)) = line.split_once(':') else {
+            continue;
+        };
+        let counters: Vec<u64> = counters
+            .split_whitespace()
+            .map(|field| field.parse().unwrap_or(0))
+            .collect();
+        if counters.len() < 11 {
+            continue;
+        }
+
+        // Receive: bytes packets errs drop fifo frame compressed multicast,
+        // then transmit: bytes packets errs ...
+        interfaces.push(InterfaceStats {
+            name: name.trim().to_string(),
+            received_bytes: counters[0],
+            received_packets: counters[1],
+            received_errors: counters[2],
+            transmitted_bytes: counters[8],
+            transmitted_packets: counters[9],
+            transmitted_errors: counters[10],
+        });
+    }
+
+    Some(interfaces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::procfs::FixtureFs;
+    use super::*;
+
+    fn fixture(kernel: &str) -> FixtureFs {
+        FixtureFs::new(format!(
+            "{}/tests/fixtures/procfs/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            kernel
+        ))
+    }
+
+    #[test]
+    fn cpu_times_without_guest_columns() {
+        let times = read_cpu_times(&fixture("linux-2.6.18"), 100.0).unwrap();
+        assert_eq!(times.user, 12.0);
+        assert_eq!(times.system, 3.3);
+        assert_eq!(times.idle, 51.0);
+    }
+
+    #[test]
+    fn cpu_times_with_guest_columns() {
+        let times = read_cpu_times(&fixture("linux-5.15"), 100.0).unwrap();
+        assert_eq!(times.user, 205.0);
+        assert_eq!(times.system, 31.5);
+        assert_eq!(times.idle, 1012.0);
+        assert_eq!(times.steal, 2.5);
+    }
+
+    #[test]
+    fn virtualization_from_dmi_and_init() {
+        // The 5.15 tree is a QEMU guest running podman; the 2.6.18 one has
+        // nothing to go by
+        assert_eq!(
+            detect_virtualization(&fixture("linux-5.15")),
+            Virtualization {
+                hypervisor: "qemu".to_string(),
+                container: "podman".to_string(),
+            }
+        );
+        assert_eq!(
+            detect_virtualization(&fixture("linux-2.6.18")),
+            Virtualization {
+                hypervisor: "none".to_string(),
+                container: "none".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn disk_stats_skip_short_partition_lines() {
+        let stats = read_disk_stats(&fixture("linux-2.6.18")).unwrap();
+        assert_eq!(stats.reads, 110);
+        assert_eq!(stats.read_bytes, 2160 * SECTOR_SIZE);
+        assert_eq!(stats.writes, 44);
+        assert_eq!(stats.write_bytes, 832 * SECTOR_SIZE);
+    }
+
+    #[test]
+    fn disk_stats_only_count_physical_disks() {
+        // loop0, dm-0 and the partitions have no /sys/block/<name>/device
+        let stats = read_disk_stats(&fixture("linux-5.15")).unwrap();
+        assert_eq!(stats.reads, 1200);
+        assert_eq!(stats.read_bytes, 54000 * SECTOR_SIZE);
+        assert_eq!(stats.writes, 2100);
+        assert_eq!(stats.write_bytes, 81600 * SECTOR_SIZE);
+    }
+
+    #[test]
+    fn network_stats_without_space_after_name() {
+        let interfaces = read_network_stats(&fixture("linux-2.6.18")).unwrap();
+        let names: Vec<&str> = interfaces.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, ["lo", "eth0"]);
+
+        let eth0 = &interfaces[1];
+        assert_eq!(eth0.received_bytes, 12345678);
+        assert_eq!(eth0.received_packets, 40000);
+        assert_eq!(eth0.received_errors, 2);
+        assert_eq!(eth0.transmitted_bytes, 2500000);
+        assert_eq!(eth0.transmitted_packets, 30000);
+        assert_eq!(eth0.transmitted_errors, 1);
+    }
+
+    #[test]
+    fn network_stats_per_interface() {
+        let interfaces = read_network_stats(&fixture("linux-5.15")).unwrap();
+        assert_eq!(interfaces.len(), 3);
+
+        let enp3s0 = interfaces.iter().find(|i| i.name == "enp3s0").unwrap();
+        assert_eq!(enp3s0.received_bytes, 987654321);
+        assert_eq!(enp3s0.received_packets, 654321);
+        assert_eq!(enp3s0.received_errors, 3);
+        assert_eq!(enp3s0.transmitted_bytes, 123456789);
+        assert_eq!(enp3s0.transmitted_packets, 321000);
+        assert_eq!(enp3s0.transmitted_errors, 0);
+    }
+
+    #[test]
+    fn missing_files() {
+        let empty = fixture("missing");
+        assert!(read_cpu_times(&empty, 100.0).is_none());
+        assert!(read_disk_stats(&empty).is_none());
+        assert!(read_network_stats(&empty).is_none());
+    }
+}
@@ -0,0 +1,201 @@
+//! Native counters for the cpu, disk and network collectors, read straight
+//! from the kernel instead of through sysinfo. The backend is picked at
+//! compile time: /proc on Linux, sysctl on FreeBSD and OpenBSD. On other
+//! platforms every function returns `None` and collectors fall back to
+//! sysinfo, or leave the metric alone where sysinfo has nothing.
+
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+mod bsd;
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+mod netlink;
+#[cfg(target_os = "linux")]
+mod procfs;
+
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+use bsd as imp;
+#[cfg(target_os = "linux")]
+use linux as imp;
+
+/// Platforms the collectors built on this module support: everything
+/// sysinfo covers plus OpenBSD, where only the native backend works
+pub const PLATFORMS: &[&str] = &["linux", "macos", "windows", "freebsd", "openbsd"];
+
+/// Cumulative CPU time across all cores, in seconds
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuTimes {
+    /// User and nice time
+    pub user: f64,
+    /// Kernel time, including interrupt handling
+    pub system: f64,
+    /// Idle time, including time waiting on I/O where the kernel reports it
+    pub idle: f64,
+    /// Time a hypervisor ran something else while this guest wanted to run
+    pub steal: f64,
+}
+
+/// What the host runs under, each `"none"` when not detected
+#[derive(Debug, Clone, PartialEq)]
+pub struct Virtualization {
+    /// The hypervisor, named as systemd-detect-virt does: `kvm`, `xen`,
+    /// `vmware`, `microsoft`, ..., or `other` for an unrecognized one
+    pub hypervisor: String,
+    /// The container runtime: `docker`, `podman`, `lxc`, ...
+    pub container: String,
+}
+
+/// Cumulative I/O totals summed over all physical disks
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiskStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+}
+
+/// Cumulative traffic counters for one network interface
+#[derive(Debug, Clone, Default)]
+pub struct InterfaceStats {
+    pub name: String,
+    pub received_bytes: u64,
+    pub transmitted_bytes: u64,
+    pub received_packets: u64,
+    pub transmitted_packets: u64,
+    pub received_errors: u64,
+    pub transmitted_errors: u64,
+}
+
+/// Cumulative counters and current backlog of one queueing discipline
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QdiscStats {
+    pub interface: String,
+    /// Such as `fq_codel` or `htb`
+    pub kind: String,
+    /// The major number, such as `8001:`
+    pub handle: String,
+    pub bytes: u64,
+    pub packets: u64,
+    pub drops: u64,
+    pub overlimits: u64,
+    pub requeues: u64,
+    pub backlog_bytes: u64,
+    pub backlog_packets: u64,
+}
+
+/// Bytes one TCP connection carried so far
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TcpTraffic {
+    pub inode: u64,
+    /// Acknowledged by the peer, so retransmissions count once
+    pub sent: u64,
+    pub received: u64,
+}
+
+#[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "openbsd"))]
+pub fn cpu_times() -> Option<CpuTimes> {
+    imp::cpu_times()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd", target_os = "openbsd")))]
+pub fn cpu_times() -> Option<CpuTimes> {
+    None
+}
+
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+pub fn virtualization() -> Option<Virtualization> {
+    imp::virtualization()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+pub fn virtualization() -> Option<Virtualization> {
+    None
+}
+
+#[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "openbsd"))]
+pub fn disk_stats() -> Option<DiskStats> {
+    imp::disk_stats()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd", target_os = "openbsd")))]
+pub fn disk_stats() -> Option<DiskStats> {
+    None
+}
+
+#[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "openbsd"))]
+pub fn network_stats() -> Option<Vec<InterfaceStats>> {
+    imp::network_stats()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd", target_os = "openbsd")))]
+pub fn network_stats() -> Option<Vec<InterfaceStats>> {
+    None
+}
+
+/// Every qdisc of every interface; none where there is no backend
+#[cfg(target_os = "linux")]
+pub fn qdisc_stats() -> Result<Vec<QdiscStats>, String> {
+    netlink::qdisc_stats().map_err(|e| format!("qdisc statistics: {}", e))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn qdisc_stats() -> Result<Vec<QdiscStats>, String> {
+    Ok(Vec::new())
+}
+
+/// Every TCP connection with a peer on another host
+#[cfg(target_os = "linux")]
+pub fn tcp_traffic() -> Result<Vec<TcpTraffic>, String> {
+    netlink::tcp_traffic().map_err(|e| format!("TCP socket statistics: {}", e))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn tcp_traffic() -> Result<Vec<TcpTraffic>, String> {
+    Ok(Vec::new())
+}
+
+/// Total and free inodes of the filesystem mounted at `path`
+#[cfg(unix)]
+pub fn inode_stats(path: &std::path::Path) -> Option<(u64, u64)> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    #[allow(clippy::useless_conversion)] // fsfilcnt_t is 32 bits on some BSDs
+    Some((u64::from(stats.f_files), u64::from(stats.f_ffree)))
+}
+
+#[cfg(not(unix))]
+pub fn inode_stats(_path: &std::path::Path) -> Option<(u64, u64)> {
+    None
+}
+
+/// Size and used bytes of the filesystem mounted at `path`
+#[cfg(unix)]
+pub fn space_stats(path: &std::path::Path) -> Option<(u64, u64)> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    #[allow(clippy::useless_conversion)] // fsblkcnt_t is 32 bits on some BSDs
+    let (blocks, free) = (u64::from(stats.f_blocks), u64::from(stats.f_bfree));
+    let block = stats.f_frsize as u64;
+    Some((blocks * block, blocks.saturating_sub(free) * block))
+}
+
+#[cfg(not(unix))]
+pub fn space_stats(_path: &std::path::Path) -> Option<(u64, u64)> {
+    None
+}
+
+/// Growth of a cumulative counter since the previous reading. A counter that
+/// went backwards (device removed, wrap-around) counts as no growth.
+pub fn delta(previous: u64, current: u64) -> f64 {
+    current.saturating_sub(previous) as f64
+}
@@ -0,0 +1,343 @@
+//! Queueing discipline statistics over rtnetlink, what `tc -s qdisc show`
+//! prints, and the traffic of TCP sockets over sock_diag, what `ss -ti`
+//! prints. The kernel binds the socket on the first send, so this works
+//! under the seccomp filter, which forbids `bind`.
+
+use super::{QdiscStats, TcpTraffic};
+use std::io;
+
+const RTM_NEWQDISC: u16 = 36;
+const RTM_GETQDISC: u16 = 38;
+
+const TCA_KIND: u16 = 1;
+/// Legacy `struct tc_stats`, for kernels without TCA_STATS2
+const TCA_STATS: u16 = 3;
+const TCA_STATS2: u16 = 7;
+/// `struct gnet_stats_basic` inside TCA_STATS2
+const TCA_STATS_BASIC: u16 = 1;
+/// `struct gnet_stats_queue` inside TCA_STATS2
+const TCA_STATS_QUEUE: u16 = 3;
+
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+/// The `struct tcp_info` of a socket
+const INET_DIAG_INFO: u16 = 2;
+/// `tcpi_bytes_acked` and `tcpi_bytes_received`, in Linux 4.1 and later
+const TCPI_BYTES_ACKED: usize = 120;
+const TCPI_BYTES_RECEIVED: usize = 128;
+/// Listening sockets carry no traffic
+const TCP_LISTEN: u32 = 10;
+
+/// `struct nlmsghdr`
+const HEADER_LEN: usize = 16;
+/// `struct tcmsg`
+const TCMSG_LEN: usize = 20;
+/// `struct inet_diag_msg`
+const INET_DIAG_MSG_LEN: usize = 72;
+
+pub fn qdisc_stats() -> io::Result<Vec<QdiscStats>> {
+    // Every interface's qdiscs: an all-zero tcmsg
+    let mut qdiscs = Vec::new();
+    dump(
+        libc::NETLINK_ROUTE,
+        RTM_GETQDISC,
+        &[0; TCMSG_LEN],
+        |kind, payload| {
+            if kind == RTM_NEWQDISC {
+                qdiscs.extend(parse_qdisc(payload));
+            }
+        },
+    )?;
+    Ok(qdiscs)
+}
+
+/// Bytes sent and received on every TCP connection whose peer isn't on
+/// this host
+pub fn tcp_traffic() -> io::Result<Vec<TcpTraffic>> {
+    let mut sockets = Vec::new();
+    for family in [libc::AF_INET, libc::AF_INET6] {
+        // struct inet_diag_req_v2 with a zero socket id, matching all
+        let mut request = vec![family as u8, libc::IPPROTO_TCP as u8];
+        request.push(1 << (INET_DIAG_INFO - 1));
+        request.push(0);
+        // Every state but LISTEN
+        request.extend_from_slice(&(!(1u32 << TCP_LISTEN)).to_ne_bytes());
+        request.resize(56, 0);
+        dump(
+            libc::NETLINK_SOCK_DIAG,
+            SOCK_DIAG_BY_FAMILY,
+            &request,
+            |kind, payload| {
+                if kind == SOCK_DIAG_BY_FAMILY {
+                    sockets.extend(parse_tcp_socket(payload));
+                }
+            },
+        )?;
+    }
+    Ok(sockets)
+}
+
+/// Send a dump request and pass every message of the reply to `message`
+fn dump(
+    protocol: libc::c_int,
+    request: u16,
+    body: &[u8],
+    mut message: impl FnMut(u16, &[u8]),
+) -> io::Result<()> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            protocol,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let result = exchange(fd, request, body, &mut message);
+    unsafe { libc::close(fd) };
+    result
+}
+
+fn exchange(
+    fd: libc::c_int,
+    request: u16,
+    body: &[u8],
+    message: &mut dyn FnMut(u16, &[u8]),
+) -> io::Result<()> {
+    let mut data = Vec::with_capacity(HEADER_LEN + body.len());
+    data.extend_from_slice(&((HEADER_LEN + body.len()) as u32).to_ne_bytes());
+    data.extend_from_slice(&request.to_ne_bytes());
+    data.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+    data.extend_from_slice(&1u32.to_ne_bytes());
+    data.extend_from_slice(&0u32.to_ne_bytes());
+    data.extend_from_slice(body);
+    let mut kernel: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    kernel.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    let sent = unsafe {
+        libc::sendto(
+            fd,
+            data.as_ptr().cast(),
+            data.len(),
+            0,
+            (&kernel as *const libc::sockaddr_nl).cast(),
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut buffer = vec![0u8; 32 * 1024];
+    loop {
+        let received = unsafe { libc::recv(fd, buffer.as_mut_ptr().cast(), buffer.len(), 0) };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if parse_messages(&buffer[..received as usize], message)? {
+            return Ok(());
+        }
+    }
+}
+
+/// Pass the messages of one datagram of a dump on; true once it's complete
+fn parse_messages(mut data: &[u8], message: &mut dyn FnMut(u16, &[u8])) -> io::Result<bool> {
+    while data.len() >= HEADER_LEN {
+        let len = u32_at(data, 0) as usize;
+        let kind = u16::from_ne_bytes([data[4], data[5]]);
+        if len < HEADER_LEN || len > data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated message",
+            ));
+        }
+        let payload = &data[HEADER_LEN..len];
+        match kind {
+            k if k == libc::NLMSG_DONE as u16 => return Ok(true),
+            k if k == libc::NLMSG_ERROR as u16 => {
+                let errno = payload.get(..4).map_or(0, |code| u32_at(code, 0) as i32);
+                return Err(io::Error::from_raw_os_error(-errno));
+            }
+            _ => message(kind, payload),
+        }
+        data = data.get(align(len)..).unwrap_or_default();
+    }
+    Ok(false)
+}
+
+/// A `struct inet_diag_msg` followed by its attributes; none for
+/// connections to this host and kernels without the byte counts
+fn parse_tcp_socket(payload: &[u8]) -> Option<TcpTraffic> {
+    let msg = payload.get(..INET_DIAG_MSG_LEN)?;
+    let peer = &msg[24..40];
+    let loopback = match msg[0] as i32 {
+        libc::AF_INET => peer[0] == 127,
+        // ::1 and IPv4-mapped 127.0.0.0/8
+        _ => {
+            peer[..15].iter().all(|&b| b == 0) && peer[15] == 1
+                || peer[..10].iter().all(|&b| b == 0)
+                    && peer[10..12] == [0xff, 0xff]
+                    && peer[12] == 127
+        }
+    };
+    if loopback {
+        return None;
+    }
+    let (_, info) =
+        attributes(&payload[INET_DIAG_MSG_LEN..]).find(|(kind, _)| *kind == INET_DIAG_INFO)?;
+    if info.len() < TCPI_BYTES_RECEIVED + 8 {
+        return None;
+    }
+    Some(TcpTraffic {
+        inode: u32_at(msg, 68) as u64,
+        sent: u64_at(info, TCPI_BYTES_ACKED),
+        received: u64_at(info, TCPI_BYTES_RECEIVED),
+    })
+}
+
+/// A `struct tcmsg` followed by its attributes
+fn parse_qdisc(payload: &[u8]) -> Option<QdiscStats> {
+    let tcmsg = payload.get(..TCMSG_LEN)?;
+    let index = u32_at(tcmsg, 4);
+    let handle = u32_at(tcmsg, 8);
+    let mut qdisc = QdiscStats {
+        interface: interface_name(index).unwrap_or_else(|| index.to_string()),
+        handle: format!("{:x}:", handle >> 16),
+        ..QdiscStats::default()
+    };
+    let mut stats2 = false;
+    for (kind, value) in attributes(&payload[TCMSG_LEN..]) {
+        match kind {
+            TCA_KIND => {
+                let name = value.split(|&b| b == 0).next().unwrap_or_default();
+                qdisc.kind = String::from_utf8_lossy(name).into_owned();
+            }
+            TCA_STATS2 => {
+                stats2 = true;
+                for (kind, value) in attributes(value) {
+                    match kind {
+                        TCA_STATS_BASIC if value.len() >= 12 => {
+                            qdisc.bytes = u64_at(value, 0);
+                            qdisc.packets = u32_at(value, 8) as u64;
+                        }
+                        TCA_STATS_QUEUE if value.len() >= 20 => {
+                            qdisc.backlog_packets = u32_at(value, 0) as u64;
+                            qdisc.backlog_bytes = u32_at(value, 4) as u64;
+                            qdisc.drops = u32_at(value, 8) as u64;
+                            qdisc.requeues = u32_at(value, 12) as u64;
+                            qdisc.overlimits = u32_at(value, 16) as u64;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            TCA_STATS if !stats2 && value.len() >= 36 => {
+                qdisc.bytes = u64_at(value, 0);
+                qdisc.packets = u32_at(value, 8) as u64;
+                qdisc.drops = u32_at(value, 12) as u64;
+                qdisc.overlimits = u32_at(value, 16) as u64;
+                qdisc.backlog_packets = u32_at(value, 28) as u64;
+                qdisc.backlog_bytes = u32_at(value, 32) as u64;
+            }
+            _ => {}
+        }
+    }
+    Some(qdisc)
+}
+
+/// `struct rtattr`s: a 4-byte length and type, then the value, padded to
+/// 4 bytes
+fn attributes(mut data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let len = u16::from_ne_bytes([*data.first()?, *data.get(1)?]) as usize;
+        let kind = u16::from_ne_bytes([*data.get(2)?, *data.get(3)?]);
+        let value = data.get(4..len)?;
+        data = data.get(align(len)..).unwrap_or_default();
+        // The high bits flag nested and byte-order-swapped attributes
+        Some((kind & 0x3fff, value))
+    })
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_ne_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], at: usize) -> u64 {
+    u64::from_ne_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+fn interface_name(index: u32) -> Option<String> {
+    let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+    if unsafe { libc::if_indextoname(index, name.as_mut_ptr()) }.is_null() {
+        return None;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attribute(kind: u16, value: &[u8]) -> Vec<u8> {
+        let mut attribute = ((4 + value.len()) as u16).to_ne_bytes().to_vec();
+        attribute.extend_from_slice(&kind.to_ne_bytes());
+        attribute.extend_from_slice(value);
+        attribute.resize(align(attribute.len()), 0);
+        attribute
+    }
+
+    fn message(kind: u16, payload: &[u8]) -> Vec<u8> {
+        let mut message = ((HEADER_LEN + payload.len()) as u32).to_ne_bytes().to_vec();
+        message.extend_from_slice(&kind.to_ne_bytes());
+        message.resize(HEADER_LEN, 0);
+        message.extend_from_slice(payload);
+        message
+    }
+
+    #[test]
+    fn parses_a_qdisc_dump() {
+        let words =
+            |words: &[u32]| -> Vec<u8> { words.iter().flat_map(|w| w.to_ne_bytes()).collect() };
+        // Interface index 0 has no name, so it stays a number
+        let mut payload = words(&[0, 0, 0x8001_0000, 0xffff_ffff, 0]);
+        payload.extend(attribute(TCA_KIND, b"fq_codel\0"));
+        let mut basic = 123_456_789u64.to_ne_bytes().to_vec();
+        basic.extend(words(&[4567, 0]));
+        let mut stats2 = attribute(TCA_STATS_BASIC, &basic);
+        stats2.extend(attribute(TCA_STATS_QUEUE, &words(&[3, 4500, 17, 2, 5])));
+        payload.extend(attribute(TCA_STATS2 | 0x8000, &stats2));
+
+        let mut data = message(RTM_NEWQDISC, &payload);
+        data.extend(message(libc::NLMSG_DONE as u16, &[0; 4]));
+        let mut qdiscs = Vec::new();
+        let mut collect = |kind, payload: &[u8]| {
+            if kind == RTM_NEWQDISC {
+                qdiscs.extend(parse_qdisc(payload));
+            }
+        };
+        assert!(parse_messages(&data, &mut collect).unwrap());
+        assert_eq!(
+            qdiscs,
+            [QdiscStats {
+                interface: "0".to_string(),
+                kind: "fq_codel".to_string(),
+                handle: "8001:".to_string(),
+                bytes: 123_456_789,
+                packets: 4567,
+                drops: 17,
+                overlimits: 5,
+                requeues: 2,
+                backlog_bytes: 4500,
+                backlog_packets: 3,
+            }]
+        );
+
+        let error = message(libc::NLMSG_ERROR as u16, &(-libc::EPERM).to_ne_bytes());
+        let e = parse_messages(&error, &mut |_, _| {}).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EPERM));
+    }
+}
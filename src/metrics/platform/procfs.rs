@@ -0,0 +1,51 @@
+//! Read access to /proc and /sys behind a trait, so the Linux parsers can
+//! run against fixture trees captured from different kernel versions.
+
+use std::path::{Path, PathBuf};
+
+pub trait ProcFs {
+    /// Contents of an absolute path such as `/proc/stat`
+    fn read_to_string(&self, path: &str) -> Option<String>;
+    fn exists(&self, path: &str) -> bool;
+}
+
+/// The running system
+pub struct HostFs;
+
+impl ProcFs for HostFs {
+    fn read_to_string(&self, path: &str) -> Option<String> {
+        std::fs::read_to_string(path).ok()
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        Path::new(path).exists()
+    }
+}
+
+/// A directory standing in for `/`, e.g. `tests/fixtures/procfs/linux-5.15`
+/// containing `proc/stat`, `sys/block/sda/device`, ...
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct FixtureFs {
+    root: PathBuf,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl FixtureFs {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FixtureFs { root: root.into() }
+    }
+
+    fn path(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+}
+
+impl ProcFs for FixtureFs {
+    fn read_to_string(&self, path: &str) -> Option<String> {
+        std::fs::read_to_string(self.path(path)).ok()
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.path(path).exists()
+    }
+}
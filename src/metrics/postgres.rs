@@ -0,0 +1,692 @@
+//! PostgreSQL: `[services.postgres]` connects every cycle, over the unix
+//! socket by default, and exports connection counts, database sizes,
+//! transaction ID age and replication lag. Speaks just enough of the
+//! frontend/backend protocol for that: startup, trust, password, MD5 and
+//! SCRAM-SHA-256 authentication, and simple queries. There is no TLS.
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use prometheus::core::Collector as PrometheusCollector;
+
+use super::service::{self, Reading, ServiceMetrics, Stream};
+use crate::collector::Collector;
+use crate::config::{Config, PostgresConfig};
+use crate::sigv4::{hmac_sha256, sha256};
+
+const CONNECTIONS: &str = "SELECT datname, COALESCE(state, 'unknown'), count(*) \
+     FROM pg_stat_activity WHERE backend_type = 'client backend' GROUP BY 1, 2";
+const MAX_CONNECTIONS: &str = "SHOW max_connections";
+const DATABASES: &str = "SELECT datname, pg_database_size(datname), age(datfrozenxid) \
+     FROM pg_database WHERE datallowconn";
+const RECOVERY: &str = "SELECT pg_is_in_recovery(), \
+     EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp())";
+const REPLICAS: &str = "SELECT application_name, COALESCE(host(client_addr), 'local'), \
+     CASE WHEN pg_is_in_recovery() THEN NULL \
+     ELSE pg_wal_lsn_diff(pg_current_wal_lsn(), replay_lsn) END \
+     FROM pg_stat_replication";
+
+pub struct PostgresCollector {
+    config: Option<PostgresConfig>,
+    metrics: ServiceMetrics,
+}
+
+impl PostgresCollector {
+    pub fn new(config: &Config) -> Self {
+        PostgresCollector {
+            config: config.services.postgres.clone(),
+            metrics: ServiceMetrics::new("postgres", &[]),
+        }
+    }
+}
+
+impl Collector for PostgresCollector {
+    fn name(&self) -> &'static str {
+        "postgres"
+    }
+
+    fn register_metrics(&self, registry: &prometheus::Registry) -> prometheus::Result<()> {
+        super::builder::register(registry, &self.metrics)
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.metrics]
+    }
+
+    fn reset_metrics(&self, _registry: &prometheus::Registry) {
+        self.metrics.reset();
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        let Some(config) = &self.config else {
+            return Ok(());
+        };
+        let result = Connection::open(config).and_then(|mut connection| {
+            read(&mut connection).map_err(|e| format!("{}: {}", config.address, e))
+        });
+        self.metrics.set_up(&[], result.is_ok());
+        match result {
+            Ok(reading) => {
+                self.metrics.set(reading);
+                Ok(())
+            }
+            Err(e) => {
+                self.metrics.set(Reading::default());
+                Err(e)
+            }
+        }
+    }
+}
+
+fn read(connection: &mut Connection) -> Result<Reading, String> {
+    let mut reading = Reading::default();
+    for row in connection.query(CONNECTIONS)? {
+        if let [Some(database), Some(state), Some(count)] = &row[..] {
+            reading.gauge(
+                "postgres_connections",
+                "Client connections by database and state",
+                &[("database", database), ("state", state)],
+                number(count),
+            );
+        }
+    }
+    if let Some(Some(max)) = connection
+        .query(MAX_CONNECTIONS)?
+        .first()
+        .map(|row| &row[0])
+    {
+        reading.gauge(
+            "postgres_max_connections",
+            "Most connections the server accepts",
+            &[],
+            number(max),
+        );
+    }
+    for row in connection.query(DATABASES)? {
+        if let [Some(database), Some(size), Some(age)] = &row[..] {
+            let labels = [("database", database.as_str())];
+            reading.gauge(
+                "postgres_database_size_bytes",
+                "Disk space used by the database",
+                &labels,
+                number(size),
+            );
+            reading.gauge(
+                "postgres_database_xid_age",
+                "Transactions since the database's oldest unfrozen transaction ID; \
+                 wraparound is at 2^31",
+                &labels,
+                number(age),
+            );
+        }
+    }
+    if let Some([Some(in_recovery), lag]) = connection.query(RECOVERY)?.first().map(|row| &row[..])
+    {
+        let standby = in_recovery == "t";
+        reading.gauge(
+            "postgres_in_recovery",
+            "Whether the server is a standby",
+            &[],
+            standby as u8 as f64,
+        );
+        if let (true, Some(lag)) = (standby, lag) {
+            reading.gauge(
+                "postgres_replication_lag_seconds",
+                "Time since the last transaction replayed on this standby",
+                &[],
+                number(lag),
+            );
+        }
+    }
+    for row in connection.query(REPLICAS)? {
+        if let [Some(name), Some(address), Some(lag)] = &row[..] {
+            reading.gauge(
+                "postgres_replica_lag_bytes",
+                "WAL the standby has yet to replay",
+                &[("application_name", name), ("client_addr", address)],
+                number(lag),
+            );
+        }
+    }
+    Ok(reading)
+}
+
+fn number(text: &str) -> f64 {
+    text.parse().unwrap_or(f64::NAN)
+}
+
+struct Connection {
+    stream: Box<dyn Stream>,
+}
+
+/// A result row, `None` for NULL
+type Row = Vec<Option<String>>;
+
+impl Connection {
+    fn open(config: &PostgresConfig) -> Result<Self, String> {
+        let mut address = config.address.clone();
+        if Path::new(&address).is_dir() {
+            address = format!("{}/.s.PGSQL.5432", address.trim_end_matches('/'));
+        }
+        let mut connection = Connection {
+            stream: service::connect(&address, config.timeout)?,
+        };
+        connection
+            .startup(config)
+            .map_err(|e| format!("{}: {}", config.address, e))?;
+        Ok(connection)
+    }
+
+    fn startup(&mut self, config: &PostgresConfig) -> Result<(), String> {
+        let mut body = 196608u32.to_be_bytes().to_vec(); // protocol 3.0
+        for (key, value) in [
+            ("user", config.user.as_str()),
+            ("database", &config.database),
+            ("application_name", "metrixd"),
+        ] {
+            body.extend_from_slice(key.as_bytes());
+            body.push(0);
+            body.extend_from_slice(value.as_bytes());
+            body.push(0);
+        }
+        body.push(0);
+        let mut message = ((body.len() + 4) as u32).to_be_bytes().to_vec();
+        message.extend_from_slice(&body);
+        self.stream.write_all(&message).map_err(|e| e.to_string())?;
+
+        let password = || {
+            config
+                .password
+                .as_deref()
+                .ok_or("the server asks for a password, and none is set")
+        };
+        let mut scram = None;
+        loop {
+            let (kind, body) = self.receive()?;
+            match (
+                kind,
+                u32::from_be_bytes(body[..4.min(body.len())].try_into().unwrap_or_default()),
+            ) {
+                (b'R', 0) => {}
+                (b'R', 3) => self.send(b'p', &nul_terminated(password()?))?,
+                (b'R', 5) => {
+                    let salt = body.get(4..8).ok_or("short MD5 salt")?;
+                    self.send(
+                        b'p',
+                        &nul_terminated(&md5_password(&config.user, password()?, salt)),
+                    )?;
+                }
+                (b'R', 10) => {
+                    let mechanisms = String::from_utf8_lossy(&body[4..]);
+                    if !mechanisms.split('\0').any(|m| m == "SCRAM-SHA-256") {
+                        return Err(format!("unsupported SASL mechanisms: {}", mechanisms));
+                    }
+                    let exchange = Scram::new(password()?);
+                    let first = exchange.client_first();
+                    let mut response = nul_terminated("SCRAM-SHA-256");
+                    response.extend_from_slice(&(first.len() as u32).to_be_bytes());
+                    response.extend_from_slice(first.as_bytes());
+                    self.send(b'p', &response)?;
+                    scram = Some(exchange);
+                }
+                (b'R', 11) => {
+                    let exchange = scram.as_mut().ok_or("unexpected SASL message")?;
+                    let server_first = String::from_utf8_lossy(&body[4..]).into_owned();
+                    let client_final = exchange.client_final(&server_first)?;
+                    self.send(b'p', client_final.as_bytes())?;
+                }
+                (b'R', 12) => {
+                    let exchange = scram.as_ref().ok_or("unexpected SASL message")?;
+                    exchange.verify(&String::from_utf8_lossy(&body[4..]))?;
+                }
+                (b'R', code) => return Err(format!("unsupported authentication method {}", code)),
+                (b'E', _) => return Err(error_message(&body)),
+                (b'Z', _) => return Ok(()),
+                // Parameter statuses, the cancellation key, notices
+                _ => {}
+            }
+        }
+    }
+
+    /// The rows of a simple query
+    fn query(&mut self, sql: &str) -> Result<Vec<Row>, String> {
+        self.send(b'Q', &nul_terminated(sql))?;
+        let mut rows = Vec::new();
+        let mut error = None;
+        loop {
+            let (kind, body) = self.receive()?;
+            match kind {
+                b'D' => rows.push(data_row(&body).ok_or("malformed data row")?),
+                b'E' => error = Some(error_message(&body)),
+                b'Z' => break,
+                _ => {}
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(rows),
+        }
+    }
+
+    fn send(&mut self, kind: u8, body: &[u8]) -> Result<(), String> {
+        let mut message = vec![kind];
+        message.extend_from_slice(&((body.len() + 4) as u32).to_be_bytes());
+        message.extend_from_slice(body);
+        self.stream.write_all(&message).map_err(|e| e.to_string())
+    }
+
+    fn receive(&mut self) -> Result<(u8, Vec<u8>), String> {
+        let mut header = [0u8; 5];
+        self.stream
+            .read_exact(&mut header)
+            .map_err(|e| e.to_string())?;
+        let len = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+        if !(4..=1 << 24).contains(&len) {
+            return Err(format!("not a PostgreSQL server (message length {})", len));
+        }
+        let mut body = vec![0u8; len - 4];
+        self.stream
+            .read_exact(&mut body)
+            .map_err(|e| e.to_string())?;
+        Ok((header[0], body))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = self.send(b'X', &[]);
+    }
+}
+
+fn nul_terminated(text: &str) -> Vec<u8> {
+    let mut bytes = text.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+fn data_row(body: &[u8]) -> Option<Row> {
+    let count = u16::from_be_bytes(body.get(..2)?.try_into().ok()?);
+    let mut rest = &body[2..];
+    let mut row = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let len = i32::from_be_bytes(rest.get(..4)?.try_into().ok()?);
+        rest = &rest[4..];
+        if len < 0 {
+            row.push(None);
+        } else {
+            let value = rest.get(..len as usize)?;
+            row.push(Some(String::from_utf8_lossy(value).into_owned()));
+            rest = &rest[len as usize..];
+        }
+    }
+    Some(row)
+}
+
+/// "SEVERITY: message" from an ErrorResponse's fields
+fn error_message(body: &[u8]) -> String {
+    let fields: BTreeMap<u8, String> = body
+        .split(|&byte| byte == 0)
+        .filter_map(|field| {
+            let (&code, value) = field.split_first()?;
+            Some((code, String::from_utf8_lossy(value).into_owned()))
+        })
+        .collect();
+    match (fields.get(&b'S'), fields.get(&b'M')) {
+        (Some(severity), Some(message)) => format!("{}: {}", severity, message),
+        (None, Some(message)) => message.clone(),
+        _ => "unknown error".to_string(),
+    }
+}
+
+/// `md5` followed by md5(md5(password + user) + salt), in hex
+fn md5_password(user: &str, password: &str, salt: &[u8]) -> String {
+    let inner = hex(&md5(format!("{}{}", password, user).as_bytes()));
+    let mut outer = inner.into_bytes();
+    outer.extend_from_slice(salt);
+    format!("md5{}", hex(&md5(&outer)))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The client side of SCRAM-SHA-256 (RFC 7677) without channel binding.
+/// PostgreSQL takes the user name from the startup message, so it's empty
+/// here.
+struct Scram {
+    password: String,
+    nonce: String,
+    /// The salted password and auth message, once the server has answered
+    proof: Option<([u8; 32], String)>,
+}
+
+impl Scram {
+    fn new(password: &str) -> Self {
+        Scram {
+            password: password.to_string(),
+            nonce: nonce(),
+            proof: None,
+        }
+    }
+
+    fn client_first_bare(&self) -> String {
+        format!("n=,r={}", self.nonce)
+    }
+
+    fn client_first(&self) -> String {
+        format!("n,,{}", self.client_first_bare())
+    }
+
+    fn client_final(&mut self, server_first: &str) -> Result<String, String> {
+        let attribute = |name: &str| {
+            server_first
+                .split(',')
+                .find_map(|part| part.strip_prefix(name))
+                .ok_or_else(|| format!("bad SCRAM server message: {}", server_first))
+        };
+        let nonce = attribute("r=")?;
+        if !nonce.starts_with(&self.nonce) {
+            return Err("SCRAM server nonce doesn't extend ours".to_string());
+        }
+        let salt = base64_decode(attribute("s=")?).ok_or("bad SCRAM salt")?;
+        let iterations: u32 = attribute("i=")?
+            .parse()
+            .map_err(|_| "bad SCRAM iteration count")?;
+
+        let salted = pbkdf2_sha256(self.password.as_bytes(), &salt, iterations);
+        let without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare(),
+            server_first,
+            without_proof
+        );
+        let client_key = hmac_sha256(&salted, b"Client Key");
+        let signature = hmac_sha256(&sha256(&client_key), auth_message.as_bytes());
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(signature)
+            .map(|(key, signature)| key ^ signature)
+            .collect();
+        self.proof = Some((salted, auth_message));
+        Ok(format!("{},p={}", without_proof, base64_encode(&proof)))
+    }
+
+    /// Check the server's signature, which proves it knows the password too
+    fn verify(&self, server_final: &str) -> Result<(), String> {
+        let (salted, auth_message) = self.proof.as_ref().ok_or("SCRAM exchange out of order")?;
+        let server_key = hmac_sha256(salted, b"Server Key");
+        let expected = base64_encode(&hmac_sha256(&server_key, auth_message.as_bytes()));
+        match server_final.strip_prefix("v=") {
+            Some(signature) if signature == expected => Ok(()),
+            _ => Err(format!("SCRAM server signature mismatch: {}", server_final)),
+        }
+    }
+}
+
+/// 18 random bytes in base64. Seeded from the standard library's hash
+/// keys, which come from the OS's random source.
+fn nonce() -> String {
+    use std::hash::BuildHasher;
+    let state = std::collections::hash_map::RandomState::new();
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let bytes: Vec<u8> = (0..3u8)
+        .flat_map(|i| state.hash_one((time, i)).to_le_bytes())
+        .take(18)
+        .collect();
+    base64_encode(&bytes)
+}
+
+/// PBKDF2-HMAC-SHA-256 with a single 32-byte block
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut block = salt.to_vec();
+    block.extend_from_slice(&1u32.to_be_bytes());
+    let mut u = hmac_sha256(password, &block);
+    let mut result = u;
+    for _ in 1..iterations {
+        u = hmac_sha256(password, &u);
+        for (r, byte) in result.iter_mut().zip(u) {
+            *r ^= byte;
+        }
+    }
+    result
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &byte)| n | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+    let mut n = 0u32;
+    let mut bits = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        n = n << 6 | BASE64.iter().position(|&b| b == c)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((n >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+/// MD5 (RFC 1321), for the `md5` authentication method only
+fn md5(message: &[u8]) -> [u8; 16] {
+    const S: [u32; 64] = [
+        7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5,
+        9, 14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10,
+        15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+    ];
+    let k: Vec<u32> = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32)
+        .collect();
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    let mut padded = message.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((message.len() as u64) * 8).to_le_bytes());
+    for block in padded.chunks(64) {
+        let m: Vec<u32> = block
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(k[i])
+                .wrapping_add(m[g])
+                .rotate_left(S[i]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+        for (word, value) in state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+    let mut digest = [0u8; 16];
+    for (i, word) in state.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_passwords() {
+        assert_eq!(hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(
+            hex(&md5(b"The quick brown fox jumps over the lazy dog")),
+            "9e107d9d372bb6826bd81d3542a419d6"
+        );
+        assert_eq!(
+            md5_password("postgres", "secret", &[1, 2, 3, 4]),
+            format!(
+                "md5{}",
+                hex(&md5(&[
+                    hex(&md5(b"secretpostgres")).as_bytes(),
+                    &[1, 2, 3, 4]
+                ]
+                .concat()))
+            )
+        );
+        assert_eq!(base64_encode(b"metrixd"), "bWV0cml4ZA==");
+        assert_eq!(base64_decode("bWV0cml4ZA==").unwrap(), b"metrixd");
+    }
+
+    /// The example exchange of RFC 7677, with its user name left out as
+    /// PostgreSQL does
+    #[test]
+    fn authenticates_with_scram() {
+        let mut scram = Scram {
+            password: "pencil".to_string(),
+            nonce: "rOprNGfwEbeRWgbNEkqO".to_string(),
+            proof: None,
+        };
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                            s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        assert_eq!(
+            scram.client_final(server_first).unwrap(),
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             p=qvT2SWdEH5Q06albL+hjSYuUhCG7VndFyzIb7CK4n9k="
+        );
+        assert!(scram
+            .verify("v=3HO6Qt1M4MKJrmlKaoOqLAI0/0TV0HZe7J9H3MBtSOg=")
+            .is_ok());
+        assert!(scram.verify("v=wrong").is_err());
+    }
+
+    #[test]
+    fn decodes_messages() {
+        let mut body = 2u16.to_be_bytes().to_vec();
+        body.extend_from_slice(&2i32.to_be_bytes());
+        body.extend_from_slice(b"42");
+        body.extend_from_slice(&(-1i32).to_be_bytes());
+        assert_eq!(data_row(&body), Some(vec![Some("42".to_string()), None]));
+        assert_eq!(data_row(&body[..5]), None);
+        assert_eq!(
+            error_message(b"SFATAL\0C28P01\0Mpassword authentication failed\0\0"),
+            "FATAL: password authentication failed"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn queries_a_server() {
+        fn message(kind: u8, body: &[u8]) -> Vec<u8> {
+            let mut message = vec![kind];
+            message.extend_from_slice(&((body.len() + 4) as u32).to_be_bytes());
+            message.extend_from_slice(body);
+            message
+        }
+        fn row(values: &[Option<&str>]) -> Vec<u8> {
+            let mut body = (values.len() as u16).to_be_bytes().to_vec();
+            for value in values {
+                match value {
+                    Some(value) => {
+                        body.extend_from_slice(&(value.len() as i32).to_be_bytes());
+                        body.extend_from_slice(value.as_bytes());
+                    }
+                    None => body.extend_from_slice(&(-1i32).to_be_bytes()),
+                }
+            }
+            message(b'D', &body)
+        }
+
+        let (client, mut server) = std::os::unix::net::UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut len = [0u8; 4];
+            server.read_exact(&mut len).unwrap();
+            let mut startup = vec![0u8; u32::from_be_bytes(len) as usize - 4];
+            server.read_exact(&mut startup).unwrap();
+            assert!(startup.windows(8).any(|window| window == b"postgres"));
+            server.write_all(&message(b'R', &[0; 4])).unwrap();
+            server.write_all(&message(b'Z', b"I")).unwrap();
+            loop {
+                let mut header = [0u8; 5];
+                server.read_exact(&mut header).unwrap();
+                let mut body =
+                    vec![0u8; u32::from_be_bytes(header[1..].try_into().unwrap()) as usize - 4];
+                server.read_exact(&mut body).unwrap();
+                if header[0] == b'X' {
+                    break;
+                }
+                let sql = std::str::from_utf8(&body[..body.len() - 1]).unwrap();
+                let rows = match sql {
+                    CONNECTIONS => row(&[Some("app"), Some("active"), Some("3")]),
+                    MAX_CONNECTIONS => row(&[Some("100")]),
+                    DATABASES => row(&[Some("app"), Some("8192"), Some("42")]),
+                    RECOVERY => row(&[Some("f"), None]),
+                    _ => Vec::new(),
+                };
+                server.write_all(&rows).unwrap();
+                server.write_all(&message(b'C', b"SELECT 1\0")).unwrap();
+                server.write_all(&message(b'Z', b"I")).unwrap();
+            }
+        });
+
+        let config = PostgresConfig {
+            address: "/var/run/postgresql".to_string(),
+            user: "postgres".to_string(),
+            password: None,
+            database: "postgres".to_string(),
+            timeout: std::time::Duration::from_secs(3),
+        };
+        let mut connection = Connection {
+            stream: Box::new(client),
+        };
+        connection.startup(&config).unwrap();
+        let reading = read(&mut connection).unwrap();
+        drop(connection);
+        server.join().unwrap();
+
+        assert_eq!(
+            reading.value(
+                "postgres_connections",
+                &[("database", "app"), ("state", "active")]
+            ),
+            Some(3.0)
+        );
+        assert_eq!(reading.value("postgres_max_connections", &[]), Some(100.0));
+        assert_eq!(
+            reading.value("postgres_database_xid_age", &[("database", "app")]),
+            Some(42.0)
+        );
+        assert_eq!(reading.value("postgres_in_recovery", &[]), Some(0.0));
+        assert_eq!(reading.value("postgres_replication_lag_seconds", &[]), None);
+    }
+}
@@ -0,0 +1,402 @@
+//! The `processes.top` processes with the most open file descriptors, with
+//! their soft limit, so a descriptor leak shows up while there is still
+//! headroom: `process_open_fds / process_max_fds` approaching 1 is the
+//! alert. Likewise the processes moving the most TCP traffic to and from
+//! other hosts, from the byte counts sock_diag keeps for each connection,
+//! so what is saturating the NIC can be answered from metrics alone. Other
+//! users' processes are only seen when running as root.
+
+use super::builder::metric;
+use super::demo::Simulation;
+use super::platform::{self, TcpTraffic};
+use crate::collector::Collector;
+use crate::config::Config;
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::GaugeVec;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// One process of `/proc`
+#[derive(Debug, PartialEq)]
+struct Process {
+    pid: u32,
+    comm: String,
+    open_fds: f64,
+    /// Soft limit of open files, none when unlimited
+    max_fds: Option<f64>,
+}
+
+/// A process's TCP traffic since the previous cycle, per second
+#[derive(Debug, PartialEq)]
+struct Talker {
+    pid: u32,
+    comm: String,
+    received: f64,
+    transmitted: f64,
+}
+
+/// The byte counts of every connection at the previous cycle, by socket
+/// inode
+#[derive(Default)]
+struct LastTraffic {
+    at: Option<Instant>,
+    sockets: HashMap<u64, TcpTraffic>,
+}
+
+pub struct ProcessesCollector {
+    open_fds: GaugeVec,
+    max_fds: GaugeVec,
+    receive_bytes_per_second: GaugeVec,
+    transmit_bytes_per_second: GaugeVec,
+    last_traffic: Mutex<LastTraffic>,
+    top: usize,
+    simulation: Option<Simulation>,
+}
+
+impl ProcessesCollector {
+    pub fn new(config: &Config) -> Self {
+        ProcessesCollector {
+            open_fds: metric(
+                "process_open_fds",
+                "Open file descriptors of the processes with the most of them",
+            )
+            .gauge_vec(&["pid", "process"]),
+            max_fds: metric(
+                "process_max_fds",
+                "Soft limit of open file descriptors of the process",
+            )
+            .gauge_vec(&["pid", "process"]),
+            receive_bytes_per_second: metric(
+                "process_network_receive_bytes_per_second",
+                "TCP bytes the process received from other hosts per second since the previous cycle",
+            )
+            .gauge_vec(&["pid", "process"]),
+            transmit_bytes_per_second: metric(
+                "process_network_transmit_bytes_per_second",
+                "TCP bytes other hosts acknowledged from the process per second since the previous cycle",
+            )
+            .gauge_vec(&["pid", "process"]),
+            last_traffic: Mutex::default(),
+            top: config.processes.top,
+            simulation: config.demo.map(|seed| Simulation::new(seed, "processes")),
+        }
+    }
+
+    /// Replace the series, so processes that exited or dropped out of the
+    /// top go away
+    fn set(&self, processes: &[Process]) {
+        self.open_fds.reset();
+        self.max_fds.reset();
+        for process in processes {
+            let pid = process.pid.to_string();
+            let labels = [pid.as_str(), process.comm.as_str()];
+            self.open_fds
+                .with_label_values(&labels)
+                .set(process.open_fds);
+            if let Some(max_fds) = process.max_fds {
+                self.max_fds.with_label_values(&labels).set(max_fds);
+            }
+        }
+    }
+
+    fn set_talkers(&self, talkers: &[Talker]) {
+        self.receive_bytes_per_second.reset();
+        self.transmit_bytes_per_second.reset();
+        for talker in talkers {
+            let pid = talker.pid.to_string();
+            let labels = [pid.as_str(), talker.comm.as_str()];
+            self.receive_bytes_per_second
+                .with_label_values(&labels)
+                .set(talker.received);
+            self.transmit_bytes_per_second
+                .with_label_values(&labels)
+                .set(talker.transmitted);
+        }
+    }
+
+    /// Tell this cycle's connections apart from the previous cycle's and
+    /// attribute the difference to their processes; nothing on the first
+    /// cycle
+    fn measure_talkers(&self, root: &Path) -> Result<Vec<Talker>, String> {
+        let sockets = platform::tcp_traffic()?;
+        let now = Instant::now();
+        let mut last = self.last_traffic.lock().unwrap();
+        let previous = std::mem::replace(
+            &mut *last,
+            LastTraffic {
+                at: Some(now),
+                sockets: sockets
+                    .iter()
+                    .map(|socket| (socket.inode, *socket))
+                    .collect(),
+            },
+        );
+        let Some(at) = previous.at else {
+            return Ok(Vec::new());
+        };
+        let owners = socket_owners(root);
+        let seconds = now.duration_since(at).as_secs_f64().max(0.001);
+        Ok(talkers(
+            &previous.sockets,
+            &sockets,
+            &owners,
+            seconds,
+            self.top,
+        ))
+    }
+}
+
+impl Collector for ProcessesCollector {
+    fn name(&self) -> &'static str {
+        "processes"
+    }
+
+    fn platforms(&self) -> &'static [&'static str] {
+        &["linux"]
+    }
+
+    fn unavailable(&self) -> Option<String> {
+        let missing = self.simulation.is_none() && !Path::new("/proc/self/fd").is_dir();
+        missing.then(|| "/proc is missing".to_string())
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
+        Ok(())
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![
+            &self.open_fds,
+            &self.max_fds,
+            &self.receive_bytes_per_second,
+            &self.transmit_bytes_per_second,
+        ]
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        let (processes, talkers) = if let Some(simulation) = &self.simulation {
+            // A leaking java process next to steady ones, and a backup
+            // filling the uplink
+            let leaking = (800.0 + simulation.elapsed() / 10.0).round().min(4096.0);
+            let processes = [
+                (1204, "java", leaking, Some(4096.0)),
+                (812, "sshd", 12.0, Some(1024.0)),
+                (1, "systemd", simulation.between(100.0, 120.0).round(), None),
+            ]
+            .into_iter()
+            .take(self.top)
+            .map(|(pid, comm, open_fds, max_fds)| Process {
+                pid,
+                comm: comm.to_string(),
+                open_fds,
+                max_fds,
+            })
+            .collect();
+            let talkers = [
+                (1204, "java", 2_500_000.0, 400_000.0),
+                (2210, "rsync", 0.0, simulation.between(8e6, 12e6)),
+            ]
+            .into_iter()
+            .take(self.top)
+            .map(|(pid, comm, received, transmitted)| Talker {
+                pid,
+                comm: comm.to_string(),
+                received: received * simulation.between(0.8, 1.2),
+                transmitted,
+            })
+            .collect();
+            (processes, talkers)
+        } else {
+            let root = Path::new("/");
+            (top_processes(root, self.top)?, self.measure_talkers(root)?)
+        };
+        self.set(&processes);
+        self.set_talkers(&talkers);
+        Ok(())
+    }
+}
+
+/// The `top` processes below `root`, which is `/` outside tests, with the
+/// most open descriptors, lower pids first among equals. Processes whose
+/// descriptors can't be read are left out.
+fn top_processes(root: &Path, top: usize) -> Result<Vec<Process>, String> {
+    let proc = root.join("proc");
+    let entries = std::fs::read_dir(&proc).map_err(|e| format!("{}: {}", proc.display(), e))?;
+    let mut processes: Vec<Process> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let pid: u32 = entry.file_name().to_str()?.parse().ok()?;
+            let dir = entry.path();
+            let open_fds = std::fs::read_dir(dir.join("fd")).ok()?.count() as f64;
+            let comm = std::fs::read_to_string(dir.join("comm")).unwrap_or_default();
+            let limits = std::fs::read_to_string(dir.join("limits")).unwrap_or_default();
+            Some(Process {
+                pid,
+                comm: comm.trim_end().to_string(),
+                open_fds,
+                max_fds: max_open_files(&limits),
+            })
+        })
+        .collect();
+    processes.sort_by(|a, b| {
+        b.open_fds
+            .total_cmp(&a.open_fds)
+            .then_with(|| a.pid.cmp(&b.pid))
+    });
+    processes.truncate(top);
+    Ok(processes)
+}
+
+/// The lowest-numbered process holding each socket inode below `root`,
+/// with its command name
+fn socket_owners(root: &Path) -> HashMap<u64, (u32, String)> {
+    let mut owners = HashMap::new();
+    let Ok(entries) = std::fs::read_dir(root.join("proc")) else {
+        return owners;
+    };
+    let mut pids: Vec<u32> = entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .collect();
+    pids.sort_unstable();
+    for pid in pids {
+        let dir = root.join("proc").join(pid.to_string());
+        let Ok(fds) = std::fs::read_dir(dir.join("fd")) else {
+            continue;
+        };
+        let mut comm = None;
+        for fd in fds.flatten() {
+            let Some(inode) = std::fs::read_link(fd.path()).ok().and_then(|target| {
+                let target = target.to_str()?.strip_prefix("socket:[")?;
+                target.strip_suffix(']')?.parse().ok()
+            }) else {
+                continue;
+            };
+            let comm = comm.get_or_insert_with(|| {
+                let comm = std::fs::read_to_string(dir.join("comm")).unwrap_or_default();
+                comm.trim_end().to_string()
+            });
+            owners.entry(inode).or_insert_with(|| (pid, comm.clone()));
+        }
+    }
+    owners
+}
+
+/// The `top` processes with the most traffic in `seconds`, the growth of
+/// each connection's counts since `previous`. A connection that wasn't
+/// there is new, so all its traffic counts; one that closed in between
+/// loses its last stretch.
+fn talkers(
+    previous: &HashMap<u64, TcpTraffic>,
+    sockets: &[TcpTraffic],
+    owners: &HashMap<u64, (u32, String)>,
+    seconds: f64,
+    top: usize,
+) -> Vec<Talker> {
+    let mut traffic: HashMap<(u32, &str), (u64, u64)> = HashMap::new();
+    for socket in sockets {
+        let Some((pid, comm)) = owners.get(&socket.inode) else {
+            continue;
+        };
+        let (sent, received) = match previous.get(&socket.inode) {
+            Some(before) => (
+                socket.sent.saturating_sub(before.sent),
+                socket.received.saturating_sub(before.received),
+            ),
+            None => (socket.sent, socket.received),
+        };
+        let total = traffic.entry((*pid, comm.as_str())).or_default();
+        total.0 += sent;
+        total.1 += received;
+    }
+    let mut talkers: Vec<_> = traffic
+        .into_iter()
+        .filter(|(_, (sent, received))| sent + received > 0)
+        .collect();
+    talkers.sort_by(|((a_pid, _), a), ((b_pid, _), b)| {
+        (b.0 + b.1).cmp(&(a.0 + a.1)).then_with(|| a_pid.cmp(b_pid))
+    });
+    talkers
+        .into_iter()
+        .take(top)
+        .map(|((pid, comm), (sent, received))| Talker {
+            pid,
+            comm: comm.to_string(),
+            received: received as f64 / seconds,
+            transmitted: sent as f64 / seconds,
+        })
+        .collect()
+}
+
+/// The soft limit of `Max open files            1024                 524288               files`
+fn max_open_files(limits: &str) -> Option<f64> {
+    let line = limits
+        .lines()
+        .find(|line| line.starts_with("Max open files"))?;
+    line.split_whitespace().nth(3)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_processes_with_the_most_descriptors() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/procfs/linux-5.15");
+        assert_eq!(
+            top_processes(&root, 10).unwrap(),
+            [
+                Process {
+                    pid: 812,
+                    comm: "sshd".to_string(),
+                    open_fds: 3.0,
+                    max_fds: Some(1024.0),
+                },
+                Process {
+                    pid: 1204,
+                    comm: "java".to_string(),
+                    open_fds: 2.0,
+                    max_fds: None,
+                },
+            ]
+        );
+        assert_eq!(top_processes(&root, 1).unwrap().len(), 1);
+        assert!(top_processes(&root.join("nonexistent"), 10).is_err());
+    }
+
+    #[test]
+    fn attributes_traffic_to_processes() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/procfs/linux-5.15");
+        let owners = socket_owners(&root);
+        assert_eq!(owners.get(&23456), Some(&(812, "sshd".to_string())));
+        assert_eq!(owners.len(), 2);
+
+        let socket = |inode, sent, received| TcpTraffic {
+            inode,
+            sent,
+            received,
+        };
+        let previous = [socket(23456, 1000, 5000)]
+            .into_iter()
+            .map(|socket| (socket.inode, socket))
+            .collect();
+        // A connection that grew, a new one, and one nobody owns
+        let sockets = [
+            socket(23456, 3000, 6000),
+            socket(23457, 400, 0),
+            socket(99999, 1_000_000, 1_000_000),
+        ];
+        assert_eq!(
+            talkers(&previous, &sockets, &owners, 2.0, 10),
+            [Talker {
+                pid: 812,
+                comm: "sshd".to_string(),
+                received: 500.0,
+                transmitted: 1200.0,
+            }]
+        );
+        assert!(talkers(&previous, &sockets, &owners, 2.0, 0).is_empty());
+    }
+}
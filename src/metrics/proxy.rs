@@ -0,0 +1,385 @@
+//! The scrape proxy: fetch each `[proxy.<name>]` target, a local exporter
+//! that only listens on 127.0.0.1, every cycle and serve its series on
+//! metrixd's own /metrics, so a host needs one open port instead of one
+//! per exporter. Names get the target's `prefix` and series its `labels`.
+//! A family that would clash with one already served, by metrixd or by an
+//! earlier target, is dropped, since merging it would break the exposition.
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use prometheus::core::{Collector as PrometheusCollector, Desc};
+use prometheus::proto::{LabelPair, MetricFamily};
+use prometheus::{GaugeVec, Opts};
+
+use crate::collector::Collector;
+use crate::config::{Config, ProxyTarget};
+use crate::{exposition, fetch};
+
+thread_local! {
+    /// Set while the proxy gathers what else is served, which leaves its
+    /// own series out
+    static GATHERING: Cell<bool> = const { Cell::new(false) };
+}
+
+pub struct ProxyCollector {
+    targets: Vec<(String, ProxyTarget)>,
+    proxied: Proxied,
+    /// Conflicts logged already, so each is logged once rather than every
+    /// cycle
+    logged: Mutex<HashSet<String>>,
+    /// The registry served alongside the targets, the default one until
+    /// registered
+    registry: Mutex<Option<prometheus::Registry>>,
+}
+
+/// The targets' last successful scrapes, plus whether each one answered
+#[derive(Clone)]
+struct Proxied {
+    up: GaugeVec,
+    scrape_duration: GaugeVec,
+    /// `None` for targets whose last scrape failed
+    scrapes: Arc<Mutex<BTreeMap<String, Option<Scrape>>>>,
+    /// Scrapes older than this are no longer served, e.g. once the
+    /// collector has been disabled
+    max_age: Duration,
+}
+
+/// When a target was scraped, and its families after relabelling
+type Scrape = (Instant, Vec<MetricFamily>);
+
+impl ProxyCollector {
+    pub fn new(config: &Config) -> Self {
+        let opts = |name: &str, help: &str| {
+            Opts::new(name, help)
+                .namespace("metrixd")
+                .subsystem("proxy")
+        };
+        let timeout = config
+            .proxy
+            .values()
+            .map(|target| target.timeout)
+            .max()
+            .unwrap_or_default();
+        let proxied = Proxied {
+            up: GaugeVec::new(
+                opts(
+                    "target_up",
+                    "Whether the last scrape of the target succeeded",
+                ),
+                &["target"],
+            )
+            .expect("invalid proxy metrics"),
+            scrape_duration: GaugeVec::new(
+                opts(
+                    "scrape_duration_seconds",
+                    "How long the last scrape of the target took",
+                ),
+                &["target"],
+            )
+            .expect("invalid proxy metrics"),
+            scrapes: Arc::default(),
+            max_age: config.collection.interval * 3 + timeout,
+        };
+        ProxyCollector {
+            targets: config
+                .proxy
+                .iter()
+                .map(|(name, target)| (name.clone(), target.clone()))
+                .collect(),
+            proxied,
+            logged: Mutex::default(),
+            registry: Mutex::default(),
+        }
+    }
+
+    fn record(&self, name: &str, result: Result<Vec<MetricFamily>, String>, took: Duration) {
+        let mut scrapes = self.proxied.scrapes.lock().unwrap();
+        self.proxied
+            .scrape_duration
+            .with_label_values(&[name])
+            .set(took.as_secs_f64());
+        // Log only when a target comes up or goes down, not every cycle
+        let was_up = scrapes.get(name).map(Option::is_some);
+        match result {
+            Ok(families) => {
+                if was_up != Some(true) {
+                    println!("Proxying target {}", name);
+                }
+                self.proxied.up.with_label_values(&[name]).set(1.0);
+                scrapes.insert(name.to_string(), Some((Instant::now(), families)));
+            }
+            Err(e) => {
+                if was_up != Some(false) {
+                    eprintln!("Proxy target {} is down: {}", name, e);
+                }
+                self.proxied.up.with_label_values(&[name]).set(0.0);
+                scrapes.insert(name.to_string(), None);
+            }
+        }
+    }
+
+    /// Leave out the families that clash with `served`
+    fn drop_conflicts(
+        &self,
+        name: &str,
+        families: &mut Vec<MetricFamily>,
+        served: &[MetricFamily],
+    ) {
+        let mut dropped = HashSet::new();
+        let mut logged = self.logged.lock().unwrap();
+        for (family, reason) in exposition::conflicts(families, served) {
+            let message = format!("{}: dropping {}: {}", name, family, reason);
+            if logged.insert(message.clone()) {
+                eprintln!("Proxy target {}", message);
+            }
+            dropped.insert(family.to_string());
+        }
+        families.retain(|family| !dropped.contains(family.name()));
+    }
+}
+
+impl Collector for ProxyCollector {
+    fn name(&self) -> &'static str {
+        "proxy"
+    }
+
+    fn register_metrics(&self, registry: &prometheus::Registry) -> prometheus::Result<()> {
+        *self.registry.lock().unwrap() = Some(registry.clone());
+        super::builder::register(registry, &self.proxied)
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.proxied]
+    }
+
+    fn reset_metrics(&self, _registry: &prometheus::Registry) {
+        self.proxied.up.reset();
+        self.proxied.scrape_duration.reset();
+        self.proxied.scrapes.lock().unwrap().clear();
+    }
+
+    /// Fails only if no target answered, so one exporter that is down
+    /// doesn't back off the others
+    fn collect_metrics(&self) -> Result<(), String> {
+        if self.targets.is_empty() {
+            return Ok(());
+        }
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let scrapes: Vec<_> = self
+                .targets
+                .iter()
+                .map(|(_, target)| {
+                    scope.spawn(move || {
+                        let started = Instant::now();
+                        (scrape(target), started.elapsed())
+                    })
+                })
+                .collect();
+            scrapes.into_iter().map(|s| s.join().unwrap()).collect()
+        });
+
+        GATHERING.with(|gathering| gathering.set(true));
+        let mut served = match &*self.registry.lock().unwrap() {
+            Some(registry) => registry.gather(),
+            None => prometheus::gather(),
+        };
+        GATHERING.with(|gathering| gathering.set(false));
+
+        let mut first_error = None;
+        let mut answered = 0;
+        for ((name, _), (mut result, took)) in self.targets.iter().zip(results) {
+            match &mut result {
+                Ok(families) => {
+                    answered += 1;
+                    self.drop_conflicts(name, families, &served);
+                    served.extend(families.iter().cloned());
+                }
+                Err(e) => {
+                    first_error.get_or_insert_with(|| e.clone());
+                }
+            }
+            self.record(name, result, took);
+        }
+        match first_error {
+            Some(e) if answered == 0 => Err(format!("no target answered: {}", e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl PrometheusCollector for Proxied {
+    fn desc(&self) -> Vec<&Desc> {
+        self.up
+            .desc()
+            .into_iter()
+            .chain(self.scrape_duration.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.up.collect();
+        families.extend(self.scrape_duration.collect());
+        if GATHERING.with(Cell::get) {
+            return families;
+        }
+        let scrapes = self.scrapes.lock().unwrap();
+        for (scraped, target_families) in scrapes.values().flatten() {
+            if scraped.elapsed() <= self.max_age {
+                families.extend(target_families.iter().cloned());
+            }
+        }
+        families
+    }
+}
+
+/// The target's metric families, relabelled
+fn scrape(target: &ProxyTarget) -> Result<Vec<MetricFamily>, String> {
+    let (address, path) = fetch::parse_url(&target.url)?;
+    let response = fetch::request(
+        &address,
+        "GET",
+        &path,
+        &[("Accept", "text/plain")],
+        target.timeout,
+    )?;
+    if response.status != 200 {
+        return Err(format!("{}: HTTP status {}", target.url, response.status));
+    }
+    let text = String::from_utf8(response.body)
+        .map_err(|_| format!("{}: the response is not UTF-8", target.url))?;
+    let mut families = exposition::parse(&text).map_err(|e| format!("{}: {}", target.url, e))?;
+    relabel(&mut families, target);
+    Ok(families)
+}
+
+/// Apply the target's `prefix` and `labels`. A series' own label of the
+/// same name becomes `exported_<name>`, like Prometheus does with
+/// `honor_labels: false`.
+fn relabel(families: &mut [MetricFamily], target: &ProxyTarget) {
+    for family in families {
+        family.set_name(format!("{}{}", target.prefix, family.name()));
+        for metric in family.mut_metric() {
+            let mut labels = metric.take_label();
+            for label in labels
+                .iter_mut()
+                .filter(|label| target.labels.contains_key(label.name()))
+            {
+                label.set_name(format!("exported_{}", label.name()));
+            }
+            for (name, value) in &target.labels {
+                let mut label = LabelPair::default();
+                label.set_name(name.clone());
+                label.set_value(value.clone());
+                labels.push(label);
+            }
+            labels.sort_by(|a, b| a.name().cmp(b.name()));
+            metric.set_label(labels);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample::flatten;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// Answers one scrape with `body`
+    fn fake_exporter(body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 4096];
+            let _ = stream.read(&mut request).unwrap();
+            let head = format!(
+                "HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            );
+            let _ = stream.write_all(head.as_bytes());
+            let _ = stream.write_all(body.as_bytes());
+        });
+        address
+    }
+
+    #[test]
+    fn serves_relabelled_series_of_targets() {
+        let app = fake_exporter("# TYPE requests_total counter\nrequests_total{job=\"web\"} 3\n");
+        let other = fake_exporter("ok 1\n");
+        // Nothing listens on port 1
+        let config = Config::parse(&format!(
+            "[proxy.app]\nurl = \"http://{}/metrics\"\nprefix = \"app_\"\n\
+             [proxy.app.labels]\njob = \"app\"\n\
+             [proxy.down]\nurl = \"http://127.0.0.1:1/metrics\"\ntimeout = \"1s\"\n\
+             [proxy.other]\nurl = \"http://{}/metrics\"\n",
+            app, other
+        ))
+        .unwrap();
+        let collector = ProxyCollector::new(&config);
+        collector.collect_metrics().unwrap();
+
+        let samples: Vec<_> = flatten(&collector.proxied.collect())
+            .into_iter()
+            .map(|sample| (sample.name, sample.labels, sample.value))
+            .collect();
+        let labels = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+        for sample in [
+            ("metrixd_proxy_target_up", labels(&[("target", "app")]), 1.0),
+            (
+                "metrixd_proxy_target_up",
+                labels(&[("target", "down")]),
+                0.0,
+            ),
+            (
+                "app_requests_total",
+                labels(&[("exported_job", "web"), ("job", "app")]),
+                3.0,
+            ),
+            ("ok", vec![], 1.0),
+        ] {
+            let (name, labels, value) = sample.clone();
+            assert!(
+                samples.contains(&(name.to_string(), labels, value)),
+                "{:?}",
+                sample
+            );
+        }
+        // A counter of the same name as other's gauge can't be served next to it
+        let app = fake_exporter("# TYPE proxy_test_clash gauge\nproxy_test_clash 1\n");
+        let other = fake_exporter("# TYPE proxy_test_clash counter\nproxy_test_clash 2\n");
+        let config = Config::parse(&format!(
+            "[proxy.app]\nurl = \"http://{}/\"\n[proxy.other]\nurl = \"http://{}/\"\n",
+            app, other
+        ))
+        .unwrap();
+        let collector = ProxyCollector::new(&config);
+        collector.collect_metrics().unwrap();
+        let clashing: Vec<_> = collector
+            .proxied
+            .collect()
+            .into_iter()
+            .filter(|family| family.name() == "proxy_test_clash")
+            .map(|family| family.type_())
+            .collect();
+        assert_eq!(clashing, [prometheus::proto::MetricType::GAUGE]);
+    }
+
+    #[test]
+    fn fails_when_no_target_answers() {
+        let config =
+            Config::parse("[proxy.down]\nurl = \"http://127.0.0.1:1/metrics\"\ntimeout = \"1s\"\n")
+                .unwrap();
+        let collector = ProxyCollector::new(&config);
+        let e = collector.collect_metrics().unwrap_err();
+        assert!(e.starts_with("no target answered: 127.0.0.1:1"), "{}", e);
+    }
+}
@@ -0,0 +1,325 @@
+//! Redis: `[services.redis]` sends `INFO` every cycle and exports memory
+//! use, clients, keyspace hits and misses, per-database key counts and the
+//! replication role. Works with servers speaking the same protocol, such as
+//! Valkey and KeyDB. There is no TLS.
+
+use std::io::{BufRead, BufReader, Write};
+
+use prometheus::core::Collector as PrometheusCollector;
+
+use super::service::{self, Reading, ServiceMetrics};
+use crate::collector::Collector;
+use crate::config::{Config, RedisConfig};
+
+/// `INFO` field, metric name after `redis_`, help, and whether it's a
+/// counter
+type Field = (&'static str, &'static str, &'static str, bool);
+
+const INFO_FIELDS: &[Field] = &[
+    (
+        "used_memory",
+        "memory_used_bytes",
+        "Memory allocated for data",
+        false,
+    ),
+    (
+        "used_memory_rss",
+        "memory_rss_bytes",
+        "Memory of the process as the OS sees it",
+        false,
+    ),
+    (
+        "maxmemory",
+        "memory_max_bytes",
+        "Configured memory limit; 0 for none",
+        false,
+    ),
+    (
+        "connected_clients",
+        "connected_clients",
+        "Client connections, replicas excluded",
+        false,
+    ),
+    (
+        "blocked_clients",
+        "blocked_clients",
+        "Clients waiting in a blocking command",
+        false,
+    ),
+    (
+        "total_connections_received",
+        "connections_received_total",
+        "Accepted connections",
+        true,
+    ),
+    (
+        "rejected_connections",
+        "rejected_connections_total",
+        "Connections rejected for maxclients",
+        true,
+    ),
+    (
+        "total_commands_processed",
+        "commands_processed_total",
+        "Commands processed",
+        true,
+    ),
+    (
+        "keyspace_hits",
+        "keyspace_hits_total",
+        "Successful key lookups",
+        true,
+    ),
+    (
+        "keyspace_misses",
+        "keyspace_misses_total",
+        "Lookups of missing keys",
+        true,
+    ),
+    (
+        "expired_keys",
+        "expired_keys_total",
+        "Keys removed when they expired",
+        true,
+    ),
+    (
+        "evicted_keys",
+        "evicted_keys_total",
+        "Keys evicted for maxmemory",
+        true,
+    ),
+    (
+        "uptime_in_seconds",
+        "uptime_seconds",
+        "Time since the server started",
+        false,
+    ),
+    (
+        "connected_slaves",
+        "connected_replicas",
+        "Replicas connected to this primary",
+        false,
+    ),
+    (
+        "master_last_io_seconds_ago",
+        "master_last_io_seconds",
+        "Time since this replica last heard from its primary",
+        false,
+    ),
+];
+
+pub struct RedisCollector {
+    config: Option<RedisConfig>,
+    metrics: ServiceMetrics,
+}
+
+impl RedisCollector {
+    pub fn new(config: &Config) -> Self {
+        RedisCollector {
+            config: config.services.redis.clone(),
+            metrics: ServiceMetrics::new("redis", &[]),
+        }
+    }
+}
+
+impl Collector for RedisCollector {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    fn register_metrics(&self, registry: &prometheus::Registry) -> prometheus::Result<()> {
+        super::builder::register(registry, &self.metrics)
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.metrics]
+    }
+
+    fn reset_metrics(&self, _registry: &prometheus::Registry) {
+        self.metrics.reset();
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        let Some(config) = &self.config else {
+            return Ok(());
+        };
+        let result = read_info(config).map(|info| parse(&info));
+        self.metrics.set_up(&[], result.is_ok());
+        match result {
+            Ok(reading) => {
+                self.metrics.set(reading);
+                Ok(())
+            }
+            Err(e) => {
+                self.metrics.set(Reading::default());
+                Err(e)
+            }
+        }
+    }
+}
+
+fn read_info(config: &RedisConfig) -> Result<String, String> {
+    let mut stream = BufReader::new(service::connect(&config.address, config.timeout)?);
+    let mut command = |args: &[&str]| {
+        stream
+            .get_mut()
+            .write_all(&encode(args))
+            .map_err(|e| e.to_string())?;
+        reply(&mut stream)
+    };
+    let result = match (&config.user, &config.password) {
+        (Some(user), Some(password)) => command(&["AUTH", user, password]).map(drop),
+        (None, Some(password)) => command(&["AUTH", password]).map(drop),
+        _ => Ok(()),
+    }
+    .and_then(|()| command(&["INFO"]));
+    let _ = command(&["QUIT"]);
+    result.map_err(|e| format!("{}: {}", config.address, e))
+}
+
+/// A command as an array of bulk strings
+fn encode(args: &[&str]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg.as_bytes());
+        command.extend_from_slice(b"\r\n");
+    }
+    command
+}
+
+/// A simple string or bulk string reply; an error reply as `Err`
+fn reply(stream: &mut impl BufRead) -> Result<String, String> {
+    let mut line = String::new();
+    stream.read_line(&mut line).map_err(|e| e.to_string())?;
+    let line = line.trim_end_matches(['\r', '\n']);
+    match line.split_at_checked(1) {
+        Some(("+", text)) => Ok(text.to_string()),
+        Some(("-", error)) => Err(error.to_string()),
+        Some(("$", len)) => {
+            let len: usize = len.parse().map_err(|_| "null reply".to_string())?;
+            let mut text = vec![0; len + 2];
+            stream.read_exact(&mut text).map_err(|e| e.to_string())?;
+            text.truncate(len);
+            Ok(String::from_utf8_lossy(&text).into_owned())
+        }
+        _ => Err(format!("not a Redis server: {}", line)),
+    }
+}
+
+/// `INFO`'s `# Section` headers and `field:value` lines
+fn parse(info: &str) -> Reading {
+    let fields: Vec<(&str, &str)> = info
+        .lines()
+        .filter_map(|line| line.trim_end().split_once(':'))
+        .collect();
+    let mut reading = Reading::default();
+    for (field, name, help, counter) in INFO_FIELDS {
+        let Some(Ok(value)) = fields
+            .iter()
+            .find(|(key, _)| key == field)
+            .map(|(_, value)| value.parse::<f64>())
+        else {
+            continue;
+        };
+        let name = format!("redis_{}", name);
+        if *counter {
+            reading.counter(&name, help, &[], value);
+        } else {
+            reading.gauge(&name, help, &[], value);
+        }
+    }
+
+    for (key, value) in &fields {
+        match *key {
+            "role" => reading.gauge(
+                "redis_role",
+                "1 for the server's replication role, master or slave",
+                &[("role", value)],
+                1.0,
+            ),
+            "master_link_status" => reading.gauge(
+                "redis_master_link_up",
+                "Whether this replica is connected to its primary",
+                &[],
+                (*value == "up") as u8 as f64,
+            ),
+            // db0:keys=1,expires=0,avg_ttl=0
+            db if db.starts_with("db") && db[2..].parse::<u32>().is_ok() => {
+                let stats: Vec<(&str, &str)> = value
+                    .split(',')
+                    .filter_map(|stat| stat.split_once('='))
+                    .collect();
+                let stat = |name| {
+                    stats
+                        .iter()
+                        .find(|(key, _)| *key == name)
+                        .and_then(|(_, value)| value.parse::<f64>().ok())
+                };
+                let labels = [("db", db)];
+                if let Some(keys) = stat("keys") {
+                    reading.gauge("redis_db_keys", "Keys in the database", &labels, keys);
+                }
+                if let Some(expires) = stat("expires") {
+                    reading.gauge(
+                        "redis_db_keys_expiring",
+                        "Keys in the database with an expiry",
+                        &labels,
+                        expires,
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+    reading
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_info() {
+        let info = "# Server\r\nredis_version:7.2.4\r\nuptime_in_seconds:3600\r\n\r\n\
+                    # Clients\r\nconnected_clients:12\r\n\r\n\
+                    # Memory\r\nused_memory:1048576\r\nmaxmemory:0\r\n\r\n\
+                    # Stats\r\nkeyspace_hits:90\r\nkeyspace_misses:10\r\n\r\n\
+                    # Replication\r\nrole:slave\r\nmaster_link_status:down\r\n\r\n\
+                    # Keyspace\r\ndb0:keys=42,expires=3,avg_ttl=1000\r\n";
+        let reading = parse(info);
+        assert_eq!(reading.value("redis_connected_clients", &[]), Some(12.0));
+        assert_eq!(
+            reading.value("redis_memory_used_bytes", &[]),
+            Some(1048576.0)
+        );
+        assert_eq!(
+            reading.value("redis_keyspace_misses_total", &[]),
+            Some(10.0)
+        );
+        assert_eq!(reading.value("redis_role", &[("role", "slave")]), Some(1.0));
+        assert_eq!(reading.value("redis_master_link_up", &[]), Some(0.0));
+        assert_eq!(reading.value("redis_db_keys", &[("db", "db0")]), Some(42.0));
+        assert_eq!(
+            reading.value("redis_db_keys_expiring", &[("db", "db0")]),
+            Some(3.0)
+        );
+    }
+
+    #[test]
+    fn speaks_resp() {
+        assert_eq!(
+            encode(&["AUTH", "secret"]),
+            b"*2\r\n$4\r\nAUTH\r\n$6\r\nsecret\r\n"
+        );
+        let mut replies: &[u8] =
+            b"+OK\r\n$11\r\nrole:master\r\n-NOAUTH Authentication required.\r\n";
+        assert_eq!(reply(&mut replies).unwrap(), "OK");
+        assert_eq!(reply(&mut replies).unwrap(), "role:master");
+        assert_eq!(
+            reply(&mut replies).unwrap_err(),
+            "NOAUTH Authentication required."
+        );
+        assert!(reply(&mut &b"HTTP/1.1 400 Bad Request\r\n"[..]).is_err());
+    }
+}
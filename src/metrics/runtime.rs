@@ -0,0 +1,168 @@
+//! The daemon's own tokio runtime: workers, tasks, queues and, when built
+//! with `RUSTFLAGS="--cfg tokio_unstable"`, the blocking thread pool.
+//! Read from `RuntimeMetrics` on every scrape rather than per cycle, so a
+//! stalled collection loop still shows up.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{CounterVec, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts};
+use tokio::runtime::Handle;
+
+pub struct RuntimeCollector {
+    handle: Handle,
+    workers: IntGauge,
+    alive_tasks: IntGauge,
+    global_queue_depth: IntGauge,
+    worker_busy_seconds: CounterVec,
+    worker_parks: IntCounterVec,
+    wakeup_delay_seconds: Histogram,
+    #[cfg(tokio_unstable)]
+    blocking_threads: IntGauge,
+    #[cfg(tokio_unstable)]
+    idle_blocking_threads: IntGauge,
+    #[cfg(tokio_unstable)]
+    blocking_queue_depth: IntGauge,
+    /// Counters are reset to tokio's totals on each scrape; this keeps
+    /// concurrent scrapes from seeing a half-updated set
+    scrape: Mutex<()>,
+}
+
+fn opts(name: &str, help: &str) -> Opts {
+    Opts::new(name, help)
+        .namespace("metrixd")
+        .subsystem("tokio")
+}
+
+/// How long the wakeup probe task sleeps each round
+const PROBE_INTERVAL: Duration = Duration::from_millis(10);
+
+impl RuntimeCollector {
+    /// Also spawns a task on `handle` that feeds the wakeup delay histogram
+    pub fn new(handle: Handle) -> prometheus::Result<Self> {
+        let wakeup_delay_seconds = Histogram::with_opts(
+            HistogramOpts::from(opts(
+                "wakeup_delay_seconds",
+                "How much later than its timer a sleeping task got to run again",
+            ))
+            .buckets(vec![
+                0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+            ]),
+        )?;
+        // Tokio's timer ticks every millisecond, so up to 1ms of each delay is
+        // rounding; a busy or blocked worker shows up well above that
+        let delay = wakeup_delay_seconds.clone();
+        handle.spawn(async move {
+            loop {
+                let start = tokio::time::Instant::now();
+                tokio::time::sleep(PROBE_INTERVAL).await;
+                delay.observe(start.elapsed().saturating_sub(PROBE_INTERVAL).as_secs_f64());
+            }
+        });
+
+        Ok(RuntimeCollector {
+            handle,
+            workers: IntGauge::with_opts(opts("workers", "Worker threads of the runtime"))?,
+            alive_tasks: IntGauge::with_opts(opts(
+                "alive_tasks",
+                "Tasks spawned and not yet finished",
+            ))?,
+            global_queue_depth: IntGauge::with_opts(opts(
+                "global_queue_depth",
+                "Tasks waiting in the runtime's global queue",
+            ))?,
+            worker_busy_seconds: CounterVec::new(
+                opts(
+                    "worker_busy_seconds_total",
+                    "Time each worker spent running tasks",
+                ),
+                &["worker"],
+            )?,
+            worker_parks: IntCounterVec::new(
+                opts(
+                    "worker_parks_total",
+                    "Times each worker went idle waiting for work",
+                ),
+                &["worker"],
+            )?,
+            wakeup_delay_seconds,
+            #[cfg(tokio_unstable)]
+            blocking_threads: IntGauge::with_opts(opts(
+                "blocking_threads",
+                "Threads in the blocking pool",
+            ))?,
+            #[cfg(tokio_unstable)]
+            idle_blocking_threads: IntGauge::with_opts(opts(
+                "idle_blocking_threads",
+                "Idle threads in the blocking pool",
+            ))?,
+            #[cfg(tokio_unstable)]
+            blocking_queue_depth: IntGauge::with_opts(opts(
+                "blocking_queue_depth",
+                "Tasks waiting for a blocking pool thread",
+            ))?,
+            scrape: Mutex::new(()),
+        })
+    }
+
+    fn metrics(&self) -> Vec<&dyn Collector> {
+        vec![
+            &self.workers,
+            &self.alive_tasks,
+            &self.global_queue_depth,
+            &self.worker_busy_seconds,
+            &self.worker_parks,
+            &self.wakeup_delay_seconds,
+            #[cfg(tokio_unstable)]
+            &self.blocking_threads,
+            #[cfg(tokio_unstable)]
+            &self.idle_blocking_threads,
+            #[cfg(tokio_unstable)]
+            &self.blocking_queue_depth,
+        ]
+    }
+}
+
+impl Collector for RuntimeCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.metrics()
+            .into_iter()
+            .flat_map(|metric| metric.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let _scrape = self.scrape.lock().unwrap();
+        let metrics = self.handle.metrics();
+
+        self.workers.set(metrics.num_workers() as i64);
+        self.alive_tasks.set(metrics.num_alive_tasks() as i64);
+        self.global_queue_depth
+            .set(metrics.global_queue_depth() as i64);
+        for worker in 0..metrics.num_workers() {
+            let label = worker.to_string();
+            let busy = self.worker_busy_seconds.with_label_values(&[&label]);
+            busy.reset();
+            busy.inc_by(metrics.worker_total_busy_duration(worker).as_secs_f64());
+            let parks = self.worker_parks.with_label_values(&[&label]);
+            parks.reset();
+            parks.inc_by(metrics.worker_park_count(worker));
+        }
+        #[cfg(tokio_unstable)]
+        {
+            self.blocking_threads
+                .set(metrics.num_blocking_threads() as i64);
+            self.idle_blocking_threads
+                .set(metrics.num_idle_blocking_threads() as i64);
+            self.blocking_queue_depth
+                .set(metrics.blocking_queue_depth() as i64);
+        }
+
+        self.metrics()
+            .into_iter()
+            .flat_map(|metric| metric.collect())
+            .collect()
+    }
+}
@@ -0,0 +1,81 @@
+use super::builder::metric;
+use crate::collector::Collector;
+use crate::config::Config;
+use crate::sample::flatten;
+use crate::script::Expression;
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::{Gauge, Registry};
+use std::sync::{Mutex, PoisonError};
+
+/// One gauge per `[scripts.<name>]` entry, set to its expression's value
+/// every cycle
+pub struct ScriptCollector {
+    scripts: Vec<(String, Expression, Gauge)>,
+    /// Why scripts whose name another metric has taken didn't get a gauge
+    clashes: Vec<String>,
+    /// The registry the scripts read, the default one until registered
+    registry: Mutex<Option<Registry>>,
+}
+
+impl ScriptCollector {
+    pub fn new(config: &Config) -> Self {
+        let mut scripts = Vec::new();
+        let mut clashes = Vec::new();
+        for (name, script) in &config.scripts {
+            match metric(name, &script.help).try_gauge() {
+                Ok(gauge) => scripts.push((name.clone(), script.expression.clone(), gauge)),
+                Err(_) => clashes.push(format!(
+                    "scripts.{}: the name is taken by another metric",
+                    name
+                )),
+            }
+        }
+
+        ScriptCollector {
+            scripts,
+            clashes,
+            registry: Mutex::default(),
+        }
+    }
+}
+
+impl Collector for ScriptCollector {
+    fn name(&self) -> &'static str {
+        "scripts"
+    }
+
+    fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        *self.registry.lock().unwrap_or_else(PoisonError::into_inner) = Some(registry.clone());
+        match self.clashes.first() {
+            Some(clash) => Err(prometheus::Error::Msg(clash.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        self.scripts
+            .iter()
+            .map(|(_, _, gauge)| gauge as &dyn PrometheusCollector)
+            .collect()
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        if self.scripts.is_empty() {
+            return Ok(());
+        }
+
+        let families = match &*self.registry.lock().unwrap_or_else(PoisonError::into_inner) {
+            Some(registry) => registry.gather(),
+            None => prometheus::gather(),
+        };
+        let samples = flatten(&families);
+        for (name, expression, gauge) in &self.scripts {
+            // A failing script keeps its last value
+            match expression.eval(&samples) {
+                Ok(value) => gauge.set(value),
+                Err(e) => eprintln!("Script {} failed: {}", name, e),
+            }
+        }
+        Ok(())
+    }
+}
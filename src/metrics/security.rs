@@ -0,0 +1,239 @@
+//! Compliance basics: SELinux and AppArmor modes, security updates waiting
+//! to be installed and how long ago the host booted. Pending updates come
+//! from the file update-notifier keeps on Debian and Ubuntu, so no package
+//! manager runs on the collection path.
+
+use super::builder::metric;
+use crate::collector::Collector;
+use crate::config::Config;
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::{Gauge, GaugeVec};
+use regex::Regex;
+use std::path::Path;
+
+/// Refreshed by apt's update-notifier hook after every `apt update`
+pub(crate) const UPDATE_NOTIFIER_DIR: &str = "/var/lib/update-notifier";
+
+pub struct SecurityCollector {
+    selinux_mode: GaugeVec,
+    apparmor_enabled: Gauge,
+    apparmor_profiles: GaugeVec,
+    /// No series without update-notifier, rather than a misleading 0
+    updates_pending: GaugeVec,
+    reboot_required: Gauge,
+    boot_age_days: Gauge,
+    demo: bool,
+}
+
+#[derive(Debug, PartialEq)]
+struct Posture {
+    /// disabled, permissive or enforcing
+    selinux: &'static str,
+    apparmor: bool,
+    /// Loaded profiles by mode, such as enforce or complain
+    apparmor_profiles: Vec<(String, f64)>,
+    /// None without update-notifier
+    updates_pending: Option<f64>,
+    reboot_required: bool,
+    boot_age_days: f64,
+}
+
+impl SecurityCollector {
+    pub fn new(config: &Config) -> Self {
+        SecurityCollector {
+            selinux_mode: metric(
+                "security_selinux_mode",
+                "1 for the SELinux mode: disabled, permissive or enforcing",
+            )
+            .gauge_vec(&["mode"]),
+            apparmor_enabled: metric(
+                "security_apparmor_enabled",
+                "Whether the AppArmor module is enabled",
+            )
+            .gauge(),
+            apparmor_profiles: metric(
+                "security_apparmor_profiles",
+                "Loaded AppArmor profiles by mode",
+            )
+            .gauge_vec(&["mode"]),
+            updates_pending: metric(
+                "security_updates_pending",
+                "Security updates apt can install, as of the last apt update",
+            )
+            .gauge_vec(&[]),
+            reboot_required: metric(
+                "security_reboot_required",
+                "Whether an installed update asked for a reboot",
+            )
+            .gauge(),
+            boot_age_days: metric("security_boot_age_days", "Days since the host booted").gauge(),
+            demo: config.demo.is_some(),
+        }
+    }
+
+    fn set(&self, posture: &Posture) {
+        self.selinux_mode.reset();
+        self.selinux_mode
+            .with_label_values(&[posture.selinux])
+            .set(1.0);
+        self.apparmor_enabled.set(posture.apparmor as u8 as f64);
+        self.apparmor_profiles.reset();
+        for (mode, count) in &posture.apparmor_profiles {
+            self.apparmor_profiles
+                .with_label_values(&[mode])
+                .set(*count);
+        }
+        self.updates_pending.reset();
+        if let Some(pending) = posture.updates_pending {
+            self.updates_pending
+                .with_label_values::<&str>(&[])
+                .set(pending);
+        }
+        self.reboot_required
+            .set(posture.reboot_required as u8 as f64);
+        self.boot_age_days.set(posture.boot_age_days);
+    }
+}
+
+impl Collector for SecurityCollector {
+    fn name(&self) -> &'static str {
+        "security"
+    }
+
+    fn platforms(&self) -> &'static [&'static str] {
+        &["linux"]
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
+        Ok(())
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![
+            &self.selinux_mode,
+            &self.apparmor_enabled,
+            &self.apparmor_profiles,
+            &self.updates_pending,
+            &self.reboot_required,
+            &self.boot_age_days,
+        ]
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        let posture = if self.demo {
+            Posture {
+                selinux: "disabled",
+                apparmor: true,
+                apparmor_profiles: vec![
+                    ("complain".to_string(), 2.0),
+                    ("enforce".to_string(), 38.0),
+                ],
+                updates_pending: Some(3.0),
+                reboot_required: false,
+                boot_age_days: 3.0,
+            }
+        } else {
+            read_posture(Path::new("/"))?
+        };
+        self.set(&posture);
+        Ok(())
+    }
+}
+
+/// Everything below `root`, which is `/` outside tests
+fn read_posture(root: &Path) -> Result<Posture, String> {
+    let read = |path: &str| std::fs::read_to_string(root.join(path)).ok();
+
+    let selinux = match read("sys/fs/selinux/enforce").as_deref().map(str::trim) {
+        Some("1") => "enforcing",
+        Some(_) => "permissive",
+        None => "disabled",
+    };
+
+    let apparmor =
+        read("sys/module/apparmor/parameters/enabled").is_some_and(|enabled| enabled.trim() == "Y");
+    // "/usr/bin/man (enforce)", readable by root only on some kernels
+    let mut apparmor_profiles: Vec<(String, f64)> = Vec::new();
+    for profile in read("sys/kernel/security/apparmor/profiles")
+        .unwrap_or_default()
+        .lines()
+    {
+        let Some(mode) = profile
+            .rsplit_once(" (")
+            .and_then(|(_, mode)| mode.strip_suffix(')'))
+        else {
+            continue;
+        };
+        match apparmor_profiles
+            .iter_mut()
+            .find(|(known, _)| known == mode)
+        {
+            Some((_, count)) => *count += 1.0,
+            None => apparmor_profiles.push((mode.to_string(), 1.0)),
+        }
+    }
+    apparmor_profiles.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let uptime = read("proc/uptime").ok_or("cannot read /proc/uptime")?;
+    let uptime: f64 = uptime
+        .split_whitespace()
+        .next()
+        .and_then(|seconds| seconds.parse().ok())
+        .ok_or_else(|| format!("/proc/uptime: unexpected contents {:?}", uptime))?;
+
+    Ok(Posture {
+        selinux,
+        apparmor,
+        apparmor_profiles,
+        updates_pending: read(&format!(
+            "{}/updates-available",
+            UPDATE_NOTIFIER_DIR.trim_start_matches('/')
+        ))
+        .map(|text| security_updates(&text)),
+        reboot_required: root.join("run/reboot-required").exists(),
+        boot_age_days: uptime / 86400.0,
+    })
+}
+
+/// The security updates line of update-notifier's message, which reads
+/// "5 of these updates are standard security updates." on recent releases
+/// and "5 updates are security updates." on older ones; none without it
+fn security_updates(text: &str) -> f64 {
+    let line = Regex::new(r"(?m)^(\d+) (?:of these )?updates? (?:is a|are) (?:standard )?security")
+        .unwrap();
+    line.captures(text)
+        .and_then(|captures| captures[1].parse().ok())
+        .unwrap_or(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_posture() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/procfs/linux-5.15");
+        assert_eq!(
+            read_posture(&root),
+            Ok(Posture {
+                selinux: "permissive",
+                apparmor: true,
+                apparmor_profiles: vec![
+                    ("complain".to_string(), 1.0),
+                    ("enforce".to_string(), 2.0)
+                ],
+                updates_pending: Some(5.0),
+                reboot_required: true,
+                boot_age_days: 1.5,
+            })
+        );
+        assert_eq!(
+            security_updates("12 packages can be updated.\n1 update is a security update.\n"),
+            1.0
+        );
+        assert_eq!(
+            security_updates("\n0 updates can be applied immediately.\n"),
+            0.0
+        );
+    }
+}
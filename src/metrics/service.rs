@@ -0,0 +1,177 @@
+//! What the collectors of local services (HAProxy, ...) share: they read
+//! values the service keeps itself, its counters included, and expose them
+//! as of the last successful read, next to a `<service>_up` gauge.
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use prometheus::core::{Collector as PrometheusCollector, Desc};
+use prometheus::proto::{Counter, Gauge, LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::{GaugeVec, Opts};
+
+use crate::fetch;
+
+/// The values of one read, grouped into families by metric name
+#[derive(Default)]
+pub struct Reading {
+    families: BTreeMap<String, MetricFamily>,
+}
+
+impl Reading {
+    pub fn gauge(&mut self, name: &str, help: &str, labels: &[(&str, &str)], value: f64) {
+        let mut gauge = Gauge::default();
+        gauge.set_value(value);
+        let mut metric = new_metric(labels);
+        metric.set_gauge(gauge);
+        self.push(name, help, MetricType::GAUGE, metric);
+    }
+
+    #[cfg_attr(not(any(feature = "haproxy", feature = "nginx")), allow(dead_code))]
+    pub fn counter(&mut self, name: &str, help: &str, labels: &[(&str, &str)], value: f64) {
+        let mut counter = Counter::default();
+        counter.set_value(value);
+        let mut metric = new_metric(labels);
+        metric.set_counter(counter);
+        self.push(name, help, MetricType::COUNTER, metric);
+    }
+
+    /// Add `metric` to the family `name`, created on first use
+    fn push(&mut self, name: &str, help: &str, kind: MetricType, metric: Metric) {
+        self.families
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                let mut family = MetricFamily::default();
+                family.set_name(name.to_string());
+                family.set_help(help.to_string());
+                family.set_field_type(kind);
+                family
+            })
+            .mut_metric()
+            .push(metric);
+    }
+
+    #[cfg(test)]
+    pub fn value(&self, name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+        self.families
+            .get(name)?
+            .get_metric()
+            .iter()
+            .find(|metric| {
+                labels.iter().all(|(name, value)| {
+                    metric
+                        .get_label()
+                        .iter()
+                        .any(|label| label.name() == *name && label.value() == *value)
+                })
+            })
+            .map(|metric| metric.get_gauge().value() + metric.get_counter().value())
+    }
+}
+
+fn new_metric(labels: &[(&str, &str)]) -> Metric {
+    let mut metric = Metric::default();
+    metric.set_label(
+        labels
+            .iter()
+            .map(|(name, value)| {
+                let mut label = LabelPair::default();
+                label.set_name(name.to_string());
+                label.set_value(value.to_string());
+                label
+            })
+            .collect(),
+    );
+    metric
+}
+
+/// A connection to a service, over TCP or a unix socket
+#[cfg_attr(
+    not(any(feature = "postgres", feature = "mysql", feature = "redis")),
+    allow(dead_code)
+)]
+pub trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Connect to the unix socket at `address` if it's an absolute path, or
+/// else to `host:port`; `timeout` applies to every read and write too
+#[cfg_attr(
+    not(any(feature = "postgres", feature = "mysql", feature = "redis")),
+    allow(dead_code)
+)]
+pub fn connect(address: &str, timeout: Duration) -> Result<Box<dyn Stream>, String> {
+    let error = |e: std::io::Error| format!("{}: {}", address, e);
+    if address.starts_with('/') {
+        #[cfg(unix)]
+        {
+            let stream = std::os::unix::net::UnixStream::connect(address).map_err(error)?;
+            stream.set_read_timeout(Some(timeout)).map_err(error)?;
+            stream.set_write_timeout(Some(timeout)).map_err(error)?;
+            return Ok(Box::new(stream));
+        }
+        #[cfg(not(unix))]
+        return Err(format!(
+            "{}: unix sockets aren't supported on this platform",
+            address
+        ));
+    }
+    let stream = fetch::connect(address, timeout)?;
+    stream.set_read_timeout(Some(timeout)).map_err(error)?;
+    stream.set_write_timeout(Some(timeout)).map_err(error)?;
+    Ok(Box::new(stream))
+}
+
+/// `<service>_up` and the families of the last successful read
+#[derive(Clone)]
+pub struct ServiceMetrics {
+    up: GaugeVec,
+    last: Arc<Mutex<Vec<MetricFamily>>>,
+}
+
+impl ServiceMetrics {
+    pub fn new(service: &str, labels: &[&str]) -> Self {
+        let up = GaugeVec::new(
+            Opts::new(
+                format!("{}_up", service),
+                format!("Whether the last read of {} succeeded", service),
+            ),
+            labels,
+        )
+        .expect("invalid service metrics");
+        ServiceMetrics {
+            up,
+            last: Arc::default(),
+        }
+    }
+
+    pub fn set_up(&self, labels: &[&str], up: bool) {
+        self.up
+            .with_label_values(labels)
+            .set(if up { 1.0 } else { 0.0 });
+    }
+
+    /// Expose `reading` until the next read; an empty one after a failed
+    /// read, so stale values go away
+    pub fn set(&self, reading: Reading) {
+        *self.last.lock().unwrap() = reading.families.into_values().collect();
+    }
+
+    /// Drop `_up` and the last reading until the next read
+    pub fn reset(&self) {
+        self.up.reset();
+        self.last.lock().unwrap().clear();
+    }
+}
+
+impl PrometheusCollector for ServiceMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        self.up.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.up.collect();
+        families.extend(self.last.lock().unwrap().iter().cloned());
+        families
+    }
+}
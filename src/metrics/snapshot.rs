@@ -0,0 +1,59 @@
+//! One sysinfo `System` shared by the cpu, memory and system collectors,
+//! instead of one each. Every part is refreshed at most once per cycle:
+//! a refresh newer than `MINIMUM_CPU_UPDATE_INTERVAL` is reused, which
+//! covers collectors running back to back in the same cycle while still
+//! refreshing every cycle at any sensible collection interval.
+
+use std::sync::Mutex;
+use std::time::Instant;
+
+use sysinfo::{System, MINIMUM_CPU_UPDATE_INTERVAL};
+
+/// The parts of the snapshot a collector can ask for
+#[derive(Clone, Copy)]
+pub enum Refresh {
+    Cpu,
+    Memory,
+    /// The process table, by far the most expensive to scan
+    Processes,
+}
+
+pub struct SystemSnapshot {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    system: System,
+    /// When each `Refresh` part was last refreshed, by discriminant
+    refreshed: [Option<Instant>; 3],
+}
+
+impl SystemSnapshot {
+    pub fn new() -> Self {
+        let mut system = System::new();
+        // CPU usage is measured between two refreshes, so take the first
+        // sample now as System::new_all did
+        system.refresh_cpu();
+        SystemSnapshot {
+            inner: Mutex::new(Inner {
+                system,
+                refreshed: [None; 3],
+            }),
+        }
+    }
+
+    /// Run `f` on the snapshot with `part` refreshed this cycle
+    pub fn with<T>(&self, part: Refresh, f: impl FnOnce(&System) -> T) -> T {
+        let mut inner = self.inner.lock().unwrap();
+        let refreshed = &mut inner.refreshed[part as usize];
+        if refreshed.is_none_or(|at| at.elapsed() >= MINIMUM_CPU_UPDATE_INTERVAL) {
+            *refreshed = Some(Instant::now());
+            match part {
+                Refresh::Cpu => inner.system.refresh_cpu(),
+                Refresh::Memory => inner.system.refresh_memory(),
+                Refresh::Processes => inner.system.refresh_processes(),
+            }
+        }
+        f(&inner.system)
+    }
+}
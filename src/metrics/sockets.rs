@@ -0,0 +1,444 @@
+//! Inventory of listening sockets from `/proc/net/{tcp,tcp6,udp,udp6}`, as
+//! `node_listening_port{port,proto,process}`, so an alert fires when an
+//! expected service stops listening or an unexpected port opens. The
+//! process comes from matching socket inodes against `/proc/<pid>/fd`,
+//! which only covers other users' processes when running as root.
+//! Established TCP connections are counted by the remote network they come
+//! from, as named in `[sockets.remote_networks]`, so unusual traffic
+//! sources show up without flow monitoring. TCP and UDP buffer memory is
+//! exported next to the `tcp_mem`/`udp_mem` limits, past which the kernel
+//! drops packets without counting them against any interface.
+
+use super::builder::metric;
+use crate::collector::Collector;
+use crate::config::{Cidr, Config};
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::GaugeVec;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+/// `st` of a listening TCP socket
+const TCP_LISTEN: u8 = 0x0a;
+
+/// `st` of an established TCP connection
+const TCP_ESTABLISHED: u8 = 0x01;
+
+/// `st` of a UDP socket that isn't connected to a peer
+const UDP_UNCONNECTED: u8 = 0x07;
+
+/// Levels of `/proc/sys/net/ipv4/{tcp,udp}_mem`, in pages
+const MEMORY_LEVELS: [&str; 3] = ["min", "pressure", "max"];
+
+/// One line of a `/proc/net` socket table
+#[derive(Debug, PartialEq)]
+struct Socket {
+    local_port: u16,
+    remote: IpAddr,
+    remote_port: u16,
+    state: u8,
+    inode: u64,
+}
+
+pub struct SocketsCollector {
+    listening_port: GaugeVec,
+    connections: GaugeVec,
+    memory_bytes: GaugeVec,
+    memory_limit_bytes: GaugeVec,
+    remote_networks: Vec<(String, Vec<Cidr>)>,
+    demo: bool,
+}
+
+impl SocketsCollector {
+    pub fn new(config: &Config) -> Self {
+        SocketsCollector {
+            listening_port: metric(
+                "node_listening_port",
+                "1 for every port a socket listens on; process is empty when unknown",
+            )
+            .gauge_vec(&["port", "proto", "process"]),
+            connections: metric(
+                "node_connections_established",
+                "Established TCP connections by the remote network they come from",
+            )
+            .gauge_vec(&["network"]),
+            memory_bytes: metric(
+                "node_socket_memory_bytes",
+                "Memory in the buffers of the protocol's sockets",
+            )
+            .gauge_vec(&["proto"]),
+            memory_limit_bytes: metric(
+                "node_socket_memory_limit_bytes",
+                "Socket buffer memory at which the kernel starts to moderate (pressure) or refuses (max) allocations",
+            )
+            .gauge_vec(&["proto", "level"]),
+            remote_networks: config.sockets.remote_networks.clone(),
+            demo: config.demo.is_some(),
+        }
+    }
+}
+
+impl Collector for SocketsCollector {
+    fn name(&self) -> &'static str {
+        "sockets"
+    }
+
+    fn platforms(&self) -> &'static [&'static str] {
+        &["linux"]
+    }
+
+    fn unavailable(&self) -> Option<String> {
+        let missing = !self.demo && !Path::new("/proc/net/tcp").exists();
+        missing.then(|| "/proc/net/tcp is missing".to_string())
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
+        Ok(())
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![
+            &self.listening_port,
+            &self.connections,
+            &self.memory_bytes,
+            &self.memory_limit_bytes,
+        ]
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        let (listening, connections, memory) = if self.demo {
+            let listening = [
+                (22, "tcp", "sshd"),
+                (53, "udp", "systemd-resolve"),
+                (443, "tcp", "nginx"),
+                (9100, "tcp", "metrixd"),
+            ]
+            .into_iter()
+            .map(|(port, proto, process)| (port, proto, process.to_string()))
+            .collect();
+            let connections = [("loopback", 4.0), ("internal", 37.0), ("external", 212.0)]
+                .into_iter()
+                .map(|(network, count)| (network.to_string(), count))
+                .collect();
+            let memory = vec![
+                SocketMemory {
+                    proto: "tcp",
+                    bytes: 3.5 * 1024.0 * 1024.0,
+                    limits: Some([283_000_000.0, 377_000_000.0, 566_000_000.0]),
+                },
+                SocketMemory {
+                    proto: "udp",
+                    bytes: 64.0 * 1024.0,
+                    limits: Some([566_000_000.0, 755_000_000.0, 1_133_000_000.0]),
+                },
+            ];
+            (listening, connections, memory)
+        } else {
+            let root = Path::new("/");
+            let sockets = read_sockets(root)?;
+            let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as f64;
+            (
+                listening(root, &sockets),
+                connections(&sockets, &self.remote_networks),
+                socket_memory(root, page_size)?,
+            )
+        };
+        self.listening_port.reset();
+        for (port, proto, process) in &listening {
+            self.listening_port
+                .with_label_values(&[port.to_string().as_str(), proto, process])
+                .set(1.0);
+        }
+        self.connections.reset();
+        for (network, count) in &connections {
+            self.connections.with_label_values(&[network]).set(*count);
+        }
+        self.memory_limit_bytes.reset();
+        for memory in &memory {
+            self.memory_bytes
+                .with_label_values(&[memory.proto])
+                .set(memory.bytes);
+            for (level, limit) in MEMORY_LEVELS.iter().zip(memory.limits.iter().flatten()) {
+                self.memory_limit_bytes
+                    .with_label_values(&[memory.proto, level])
+                    .set(*limit);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Every socket of `/proc/net/{tcp,tcp6,udp,udp6}` below `root`, which is
+/// `/` outside tests, with its protocol
+fn read_sockets(root: &Path) -> Result<Vec<(&'static str, Socket)>, String> {
+    let mut sockets = Vec::new();
+    for (table, proto) in [
+        ("tcp", "tcp"),
+        ("tcp6", "tcp"),
+        ("udp", "udp"),
+        ("udp6", "udp"),
+    ] {
+        let path = root.join("proc/net").join(table);
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            // No IPv6 in this kernel
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && table.ends_with('6') => continue,
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        let parsed = text.lines().skip(1).filter_map(parse_socket);
+        sockets.extend(parsed.map(|socket| (proto, socket)));
+    }
+    Ok(sockets)
+}
+
+/// Listening ports with the protocol and the process holding the socket,
+/// IPv4 and IPv6 merged
+fn listening(
+    root: &Path,
+    sockets: &[(&'static str, Socket)],
+) -> BTreeSet<(u16, &'static str, String)> {
+    let ports: Vec<(u16, &'static str, u64)> = sockets
+        .iter()
+        .filter(|(proto, socket)| {
+            let listening = if *proto == "tcp" {
+                TCP_LISTEN
+            } else {
+                UDP_UNCONNECTED
+            };
+            socket.state == listening && socket.remote_port == 0
+        })
+        .map(|(proto, socket)| (socket.local_port, *proto, socket.inode))
+        .collect();
+    let processes = socket_processes(root, ports.iter().map(|(_, _, inode)| *inode).collect());
+    ports
+        .into_iter()
+        .map(|(port, proto, inode)| {
+            let process = processes.get(&inode).cloned().unwrap_or_default();
+            (port, proto, process)
+        })
+        .collect()
+}
+
+/// Established TCP connections per remote network, the one with the
+/// longest matching prefix; `external` for those matching none. Every
+/// network is present, so a count dropping to 0 stays visible.
+fn connections(
+    sockets: &[(&'static str, Socket)],
+    networks: &[(String, Vec<Cidr>)],
+) -> BTreeMap<String, f64> {
+    let mut counts: BTreeMap<String, f64> = networks
+        .iter()
+        .map(|(name, _)| (name.clone(), 0.0))
+        .chain([("external".to_string(), 0.0)])
+        .collect();
+    for (_, socket) in sockets
+        .iter()
+        .filter(|(proto, socket)| *proto == "tcp" && socket.state == TCP_ESTABLISHED)
+    {
+        let network = networks
+            .iter()
+            .flat_map(|(name, cidrs)| cidrs.iter().map(move |cidr| (name, cidr)))
+            .filter(|(_, cidr)| cidr.contains(socket.remote))
+            .max_by_key(|(_, cidr)| cidr.prefix())
+            .map_or("external", |(name, _)| name.as_str());
+        *counts.get_mut(network).expect("every network has a count") += 1.0;
+    }
+    counts
+}
+
+#[derive(Debug, PartialEq)]
+struct SocketMemory {
+    proto: &'static str,
+    bytes: f64,
+    /// `min`, `pressure` and `max` in bytes; none without the sysctl
+    limits: Option<[f64; 3]>,
+}
+
+/// Buffer memory of TCP and UDP sockets from `/proc/net/sockstat`, which
+/// counts it in pages, lines such as `TCP: inuse 4 orphan 0 tw 85 alloc 4 mem 12`
+fn socket_memory(root: &Path, page_size: f64) -> Result<Vec<SocketMemory>, String> {
+    let path = root.join("proc/net/sockstat");
+    let sockstat =
+        std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut memory = Vec::new();
+    for (prefix, proto) in [("TCP:", "tcp"), ("UDP:", "udp")] {
+        let Some(pages) = sockstat
+            .lines()
+            .find_map(|line| line.strip_prefix(prefix))
+            .and_then(|fields| {
+                let fields: Vec<&str> = fields.split_whitespace().collect();
+                let at = fields.iter().position(|&field| field == "mem")?;
+                fields.get(at + 1)?.parse::<f64>().ok()
+            })
+        else {
+            continue;
+        };
+        let sysctl = root
+            .join("proc/sys/net/ipv4")
+            .join(format!("{}_mem", proto));
+        let limits = std::fs::read_to_string(sysctl).ok().and_then(|text| {
+            let pages: Vec<f64> = text
+                .split_whitespace()
+                .map(|pages| pages.parse().ok())
+                .collect::<Option<_>>()?;
+            let pages: [f64; 3] = pages.try_into().ok()?;
+            Some(pages.map(|pages| pages * page_size))
+        });
+        memory.push(SocketMemory {
+            proto,
+            bytes: pages * page_size,
+            limits,
+        });
+    }
+    Ok(memory)
+}
+
+/// `   0: 0500000A:0016 0900000A:C738 01 00000000:00000000 02:000A7B2B 00000000     0        0 40001 ...`,
+/// addresses being the kernel's 32-bit words printed in host byte order
+fn parse_socket(line: &str) -> Option<Socket> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let endpoint = |field: &str| -> Option<(IpAddr, u16)> {
+        let (address, port) = field.rsplit_once(':')?;
+        let words = address
+            .as_bytes()
+            .chunks(8)
+            .map(|word| {
+                let word = std::str::from_utf8(word).ok()?;
+                Some(u32::from_str_radix(word, 16).ok()?.to_ne_bytes())
+            })
+            .collect::<Option<Vec<[u8; 4]>>>()?;
+        let address = match words[..] {
+            [v4] => IpAddr::V4(Ipv4Addr::from(v4)),
+            [_, _, _, _] => {
+                let bytes: [u8; 16] = words.concat().try_into().ok()?;
+                IpAddr::V6(Ipv6Addr::from(bytes))
+            }
+            _ => return None,
+        };
+        Some((address, u16::from_str_radix(port, 16).ok()?))
+    };
+    let (_, local_port) = endpoint(fields.get(1)?)?;
+    let (remote, remote_port) = endpoint(fields.get(2)?)?;
+    Some(Socket {
+        local_port,
+        remote,
+        remote_port,
+        state: u8::from_str_radix(fields.get(3)?, 16).ok()?,
+        inode: fields.get(9)?.parse().ok()?,
+    })
+}
+
+/// The command name of the lowest-numbered process holding each of
+/// `inodes`, which for pre-forking servers is the parent
+fn socket_processes(root: &Path, mut inodes: BTreeSet<u64>) -> BTreeMap<u64, String> {
+    let mut processes = BTreeMap::new();
+    let Ok(entries) = std::fs::read_dir(root.join("proc")) else {
+        return processes;
+    };
+    let mut pids: Vec<u32> = entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .collect();
+    pids.sort_unstable();
+
+    for pid in pids {
+        if inodes.is_empty() {
+            break;
+        }
+        let process = root.join("proc").join(pid.to_string());
+        // Other users' descriptors are unreadable without root
+        let Ok(fds) = std::fs::read_dir(process.join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            let Ok(target) = std::fs::read_link(fd.path()) else {
+                continue;
+            };
+            let Some(inode) = target
+                .to_str()
+                .and_then(|target| target.strip_prefix("socket:["))
+                .and_then(|target| target.strip_suffix(']'))
+                .and_then(|inode| inode.parse().ok())
+            else {
+                continue;
+            };
+            if inodes.remove(&inode) {
+                let comm = std::fs::read_to_string(process.join("comm")).unwrap_or_default();
+                processes.insert(inode, comm.trim_end().to_string());
+            }
+        }
+    }
+    processes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_sockets() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/procfs/linux-5.15");
+        let sockets = read_sockets(&root).unwrap();
+        assert_eq!(
+            listening(&root, &sockets).into_iter().collect::<Vec<_>>(),
+            [
+                (22, "tcp", "sshd".to_string()),
+                (53, "udp", String::new()),
+                (9100, "tcp", String::new()),
+            ]
+        );
+
+        let networks = |networks: &[(&str, &[&str])]| -> Vec<(String, Vec<Cidr>)> {
+            networks
+                .iter()
+                .map(|(name, cidrs)| {
+                    let cidrs = cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect();
+                    (name.to_string(), cidrs)
+                })
+                .collect()
+        };
+        let counts = connections(
+            &sockets,
+            &networks(&[("internal", &["10.0.0.0/8"]), ("peer", &["10.0.0.9"])]),
+        );
+        assert_eq!(
+            counts.into_iter().collect::<Vec<_>>(),
+            [
+                ("external".to_string(), 2.0),
+                ("internal".to_string(), 0.0),
+                ("peer".to_string(), 1.0),
+            ]
+        );
+
+        let mapped: IpAddr = "::ffff:10.1.2.3".parse().unwrap();
+        assert!("10.0.0.0/8".parse::<Cidr>().unwrap().contains(mapped));
+        assert!(!"fc00::/7".parse::<Cidr>().unwrap().contains(mapped));
+        assert!("::/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains("2001:db8::1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!(read_sockets(&root.join("nonexistent")).is_err());
+    }
+
+    #[test]
+    fn reads_socket_memory() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/procfs/linux-5.15");
+        assert_eq!(
+            socket_memory(&root, 4096.0).unwrap(),
+            [
+                SocketMemory {
+                    proto: "tcp",
+                    bytes: 12.0 * 4096.0,
+                    limits: Some([70812.0 * 4096.0, 94418.0 * 4096.0, 141624.0 * 4096.0]),
+                },
+                SocketMemory {
+                    proto: "udp",
+                    bytes: 3.0 * 4096.0,
+                    limits: None,
+                },
+            ]
+        );
+        assert!(socket_memory(&root.join("nonexistent"), 4096.0).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+//! Summary metrics, which the prometheus crate only knows as a proto type.
+//! Quantiles are computed over a sliding time window of recent observations;
+//! sum and count are cumulative like in the other client libraries.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use prometheus::core::{Collector, Desc, Describer};
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType, Quantile};
+use prometheus::Opts;
+
+/// Quantiles exported when none are configured
+pub const DEFAULT_QUANTILES: &[f64] = &[0.5, 0.9, 0.99];
+
+/// How long observations count towards the quantiles
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(600);
+
+// Bounds memory for summaries that are observed very often
+const MAX_OBSERVATIONS: usize = 1024;
+
+#[derive(Default)]
+struct Series {
+    window: VecDeque<(Instant, f64)>,
+    sum: f64,
+    count: u64,
+}
+
+impl Series {
+    fn observe(&mut self, value: f64) {
+        if self.window.len() == MAX_OBSERVATIONS {
+            self.window.pop_front();
+        }
+        self.window.push_back((Instant::now(), value));
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+struct Core {
+    desc: Desc,
+    quantiles: Vec<f64>,
+    max_age: Duration,
+    /// Keyed by variable label values; a summary without labels has one
+    /// series under the empty key
+    series: Mutex<BTreeMap<Vec<String>, Series>>,
+}
+
+/// A summary with one series per combination of variable label values
+#[derive(Clone)]
+pub struct SummaryVec {
+    core: Arc<Core>,
+}
+
+/// A single summary series, obtained from `SummaryVec::with_label_values`
+/// or built directly without variable labels
+#[derive(Clone)]
+pub struct Summary {
+    core: Arc<Core>,
+    label_values: Vec<String>,
+}
+
+impl SummaryVec {
+    pub fn new(opts: Opts, label_names: &[&str], quantiles: Vec<f64>) -> prometheus::Result<Self> {
+        if let Some(q) = quantiles.iter().find(|q| !(0.0..=1.0).contains(*q)) {
+            return Err(prometheus::Error::Msg(format!(
+                "quantile {} is not between 0 and 1",
+                q
+            )));
+        }
+        let opts = opts.variable_labels(label_names.iter().map(|s| s.to_string()).collect());
+        Ok(SummaryVec {
+            core: Arc::new(Core {
+                desc: opts.describe()?,
+                quantiles,
+                max_age: DEFAULT_MAX_AGE,
+                series: Mutex::new(BTreeMap::new()),
+            }),
+        })
+    }
+
+    /// Panics if the number of values doesn't match the label names, like
+    /// the vectors in the prometheus crate
+    pub fn with_label_values(&self, values: &[&str]) -> Summary {
+        assert_eq!(
+            values.len(),
+            self.core.desc.variable_labels.len(),
+            "wrong number of label values for {}",
+            self.core.desc.fq_name
+        );
+        Summary {
+            core: Arc::clone(&self.core),
+            label_values: values.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+    /// Drop every series
+    pub fn reset(&self) {
+        self.core.series.lock().unwrap().clear();
+    }
+}
+
+impl Summary {
+    pub fn new(opts: Opts, quantiles: Vec<f64>) -> prometheus::Result<Self> {
+        Ok(SummaryVec::new(opts, &[], quantiles)?.with_label_values(&[]))
+    }
+
+    pub fn observe(&self, value: f64) {
+        self.core
+            .series
+            .lock()
+            .unwrap()
+            .entry(self.label_values.clone())
+            .or_default()
+            .observe(value);
+    }
+}
+
+impl Core {
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut series = self.series.lock().unwrap();
+        let now = Instant::now();
+
+        let mut metrics = Vec::with_capacity(series.len());
+        for (label_values, series) in series.iter_mut() {
+            while series
+                .window
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) > self.max_age)
+            {
+                series.window.pop_front();
+            }
+
+            let mut values: Vec<f64> = series.window.iter().map(|(_, v)| *v).collect();
+            values.sort_by(f64::total_cmp);
+
+            let mut summary = prometheus::proto::Summary::default();
+            summary.set_sample_count(series.count);
+            summary.set_sample_sum(series.sum);
+            summary.set_quantile(
+                self.quantiles
+                    .iter()
+                    .map(|q| {
+                        let mut quantile = Quantile::default();
+                        quantile.set_quantile(*q);
+                        quantile.set_value(rank(&values, *q));
+                        quantile
+                    })
+                    .collect(),
+            );
+
+            let mut labels = self.desc.const_label_pairs.clone();
+            for (name, value) in self.desc.variable_labels.iter().zip(label_values) {
+                let mut pair = LabelPair::default();
+                pair.set_name(name.clone());
+                pair.set_value(value.clone());
+                labels.push(pair);
+            }
+            labels.sort_by(|a, b| a.name().cmp(b.name()));
+
+            let mut metric = Metric::from_label(labels);
+            metric.set_summary(summary);
+            metrics.push(metric);
+        }
+
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::SUMMARY);
+        family.set_metric(metrics);
+        vec![family]
+    }
+}
+
+/// Nearest-rank quantile of sorted values; NaN when there are none, as
+/// Prometheus expects for an empty window
+fn rank(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let index = (q * sorted.len() as f64).ceil() as usize;
+    sorted[index.clamp(1, sorted.len()) - 1]
+}
+
+impl Collector for SummaryVec {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.core.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.core.collect()
+    }
+}
+
+impl Collector for Summary {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.core.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.core.collect()
+    }
+}
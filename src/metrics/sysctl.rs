@@ -0,0 +1,160 @@
+//! Kernel parameters from `/proc/sys`, named in `sysctl.names`, so
+//! configuration drift across a fleet shows up in a query. Numbers become
+//! `sysctl_value`, one series per number for lists like `net.ipv4.tcp_rmem`;
+//! anything else, such as `net.ipv4.tcp_congestion_control`, `sysctl_info`.
+
+use super::builder::metric;
+use crate::collector::Collector;
+use crate::config::Config;
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::GaugeVec;
+use std::path::Path;
+
+const ROOT: &str = "/proc/sys";
+
+/// Shown in demo mode, for the names that are configured
+const DEMO_VALUES: &[(&str, &str)] = &[
+    ("fs.file-max", "9223372036854775807"),
+    ("kernel.pid_max", "4194304"),
+    ("net.core.somaxconn", "4096"),
+    ("net.ipv4.ip_forward", "1"),
+    ("net.ipv4.tcp_congestion_control", "cubic"),
+    ("net.ipv4.tcp_rmem", "4096\t131072\t6291456"),
+    ("vm.overcommit_memory", "0"),
+    ("vm.swappiness", "60"),
+];
+
+pub struct SysctlCollector {
+    sysctl_value: GaugeVec,
+    sysctl_info: GaugeVec,
+    names: Vec<String>,
+    demo: bool,
+}
+
+/// What a parameter holds
+#[derive(Debug, PartialEq)]
+enum Value {
+    Numbers(Vec<f64>),
+    Text(String),
+}
+
+impl SysctlCollector {
+    pub fn new(config: &Config) -> Self {
+        SysctlCollector {
+            sysctl_value: metric(
+                "sysctl_value",
+                "Numeric kernel parameter; index counts the numbers of a list",
+            )
+            .gauge_vec(&["name", "index"]),
+            sysctl_info: metric("sysctl_info", "Non-numeric kernel parameter, always 1")
+                .gauge_vec(&["name", "value"]),
+            names: config.sysctl.names.clone(),
+            demo: config.demo.is_some(),
+        }
+    }
+
+    fn set(&self, name: &str, value: &Value) {
+        match value {
+            Value::Numbers(numbers) => {
+                for (index, number) in numbers.iter().enumerate() {
+                    self.sysctl_value
+                        .with_label_values(&[name, &index.to_string()])
+                        .set(*number);
+                }
+            }
+            Value::Text(text) => self.sysctl_info.with_label_values(&[name, text]).set(1.0),
+        }
+    }
+}
+
+impl Collector for SysctlCollector {
+    fn name(&self) -> &'static str {
+        "sysctl"
+    }
+
+    fn platforms(&self) -> &'static [&'static str] {
+        &["linux"]
+    }
+
+    fn unavailable(&self) -> Option<String> {
+        let missing = !self.demo && !Path::new("/proc/sys").is_dir();
+        missing.then(|| "/proc/sys is missing".to_string())
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
+        Ok(())
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.sysctl_value, &self.sysctl_info]
+    }
+
+    /// Fails with the first parameter that couldn't be read, after setting
+    /// the others
+    fn collect_metrics(&self) -> Result<(), String> {
+        self.sysctl_value.reset();
+        self.sysctl_info.reset();
+        if self.demo {
+            for (name, value) in DEMO_VALUES {
+                if self.names.iter().any(|configured| configured == name) {
+                    self.set(name, &parse(value));
+                }
+            }
+            return Ok(());
+        }
+
+        let mut first_error = None;
+        for name in &self.names {
+            match read(Path::new(ROOT), name) {
+                Ok(value) => self.set(name, &value),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+}
+
+fn read(root: &Path, name: &str) -> Result<Value, String> {
+    let path = name
+        .split('.')
+        .fold(root.to_path_buf(), |path, part| path.join(part));
+    std::fs::read_to_string(&path)
+        .map(|text| parse(&text))
+        .map_err(|e| format!("{}: {}", name, e))
+}
+
+/// Whitespace-separated numbers, or else the text with its whitespace
+/// collapsed
+fn parse(text: &str) -> Value {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    match words.iter().map(|word| word.parse::<f64>()).collect() {
+        Ok(numbers) if !words.is_empty() => Value::Numbers(numbers),
+        _ => Value::Text(words.join(" ")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_parameters() {
+        let root =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/procfs/linux-5.15/proc/sys");
+        assert_eq!(read(&root, "vm.swappiness"), Ok(Value::Numbers(vec![60.0])));
+        assert_eq!(
+            read(&root, "net.ipv4.tcp_rmem"),
+            Ok(Value::Numbers(vec![4096.0, 131072.0, 6291456.0]))
+        );
+        assert_eq!(
+            read(&root, "net.ipv4.tcp_congestion_control"),
+            Ok(Value::Text("cubic".to_string()))
+        );
+        assert!(read(&root, "vm.nonexistent")
+            .unwrap_err()
+            .starts_with("vm.nonexistent: "));
+        assert_eq!(parse(""), Value::Text(String::new()));
+    }
+}
@@ -1,60 +1,309 @@
+use super::builder::metric;
+use super::demo::Simulation;
+use super::native_histogram::NativeHistogram;
+use super::snapshot::{Refresh, SystemSnapshot};
 use crate::collector::Collector;
-use prometheus::{register_gauge, Gauge};
-use std::sync::Mutex;
+use crate::config::Config;
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::{CounterVec, Gauge, GaugeVec};
+use std::sync::{Arc, Once};
+use std::time::{Duration, Instant};
 use sysinfo::System;
 
+/// How long the wakeup probe sleeps each round
+const PROBE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Where cgroup v2 is mounted
+#[cfg(target_os = "linux")]
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
 pub struct SystemCollector {
     load_average_1min: Gauge,
     load_average_5min: Gauge,
     load_average_15min: Gauge,
+    // Cycles with the 1-minute load above each of thresholds.load_per_core
+    // times the core count
+    load_breaches_total: CounterVec,
+    load_thresholds: Vec<f64>,
     uptime_seconds: Gauge,
     process_count: Gauge,
-    system: Mutex<System>,
+    // Per systemd service and scope, far fewer series than per process
+    unit_cpu_seconds_total: CounterVec,
+    unit_memory_bytes: GaugeVec,
+    unit_processes: GaugeVec,
+    scheduler_wakeup_delay_seconds: NativeHistogram,
+    probe: Once,
+    snapshot: Arc<SystemSnapshot>,
+    simulation: Option<Simulation>,
 }
 
 impl SystemCollector {
-    pub fn new() -> Self {
+    pub fn new(config: &Config, snapshot: Arc<SystemSnapshot>) -> Self {
         let load_average_1min =
-            register_gauge!("load_average_1min", "System load average over 1 minute").unwrap();
+            metric("load_average_1min", "System load average over 1 minute").gauge();
         let load_average_5min =
-            register_gauge!("load_average_5min", "System load average over 5 minutes").unwrap();
+            metric("load_average_5min", "System load average over 5 minutes").gauge();
         let load_average_15min =
-            register_gauge!("load_average_15min", "System load average over 15 minutes").unwrap();
-        let uptime_seconds = register_gauge!("uptime_seconds", "System uptime in seconds").unwrap();
-        let process_count =
-            register_gauge!("process_count", "Number of running processes").unwrap();
-        let system = Mutex::new(System::new_all());
+            metric("load_average_15min", "System load average over 15 minutes").gauge();
+        let load_breaches_total = metric(
+            "load_average_1min_breaches_total",
+            "Collection cycles in which the 1-minute load average was above per_core times the core count",
+        )
+        .counter_vec(&["per_core"]);
+        for per_core in &config.thresholds.load_per_core {
+            load_breaches_total.with_label_values(&[&per_core.to_string()]);
+        }
+        let uptime_seconds = metric("uptime_seconds", "System uptime in seconds").gauge();
+        let process_count = metric("process_count", "Number of running processes").gauge();
+        let unit_cpu_seconds_total = metric(
+            "unit_cpu_seconds_total",
+            "CPU time used by the processes of a systemd unit, exited ones included",
+        )
+        .counter_vec(&["unit"]);
+        let unit_memory_bytes = metric(
+            "unit_memory_bytes",
+            "Memory charged to a systemd unit, page cache included",
+        )
+        .gauge_vec(&["unit"]);
+        let unit_processes =
+            metric("unit_processes", "Processes in a systemd unit").gauge_vec(&["unit"]);
+        let scheduler_wakeup_delay_seconds = metric(
+            "scheduler_wakeup_delay_seconds",
+            "How much later than its timer a sleeping thread got to run again",
+        )
+        .buckets(config.histogram_buckets(
+            "scheduler_wakeup_delay_seconds",
+            vec![
+                0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1,
+            ],
+        ))
+        .native_histogram(config.metrics.native_histograms);
 
         SystemCollector {
             load_average_1min,
             load_average_5min,
             load_average_15min,
+            load_breaches_total,
+            load_thresholds: config.thresholds.load_per_core.clone(),
             uptime_seconds,
             process_count,
-            system,
+            unit_cpu_seconds_total,
+            unit_memory_bytes,
+            unit_processes,
+            scheduler_wakeup_delay_seconds,
+            probe: Once::new(),
+            snapshot,
+            simulation: config.demo.map(|seed| Simulation::new(seed, "system")),
         }
     }
+
+    fn simulate(&self, simulation: &Simulation) {
+        let load = simulation.between(0.2, 4.0);
+        self.load_average_1min.set(load);
+        self.load_average_5min
+            .set(load * simulation.between(0.8, 1.0));
+        self.load_average_15min
+            .set(load * simulation.between(0.6, 0.9));
+        self.count_breaches(load, 8);
+        // Up for three days when the demo starts
+        self.uptime_seconds
+            .set((3.0 * 86400.0 + simulation.elapsed()).floor());
+        self.process_count
+            .set(simulation.between(150.0, 250.0).round());
+        self.scheduler_wakeup_delay_seconds
+            .observe(simulation.between(0.00005, 0.0005));
+    }
 }
 
 impl Collector for SystemCollector {
-    fn register_metrics(&self) -> prometheus::Result<()> {
+    fn name(&self) -> &'static str {
+        "system"
+    }
+
+    // Load averages are not available on Windows
+    fn platforms(&self) -> &'static [&'static str] {
+        &["linux", "macos", "freebsd"]
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
         Ok(())
     }
 
-    fn collect_metrics(&self) {
-        let mut system = self.system.lock().unwrap();
-        system.refresh_all();
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![
+            &self.load_average_1min,
+            &self.load_average_5min,
+            &self.load_average_15min,
+            &self.load_breaches_total,
+            &self.uptime_seconds,
+            &self.process_count,
+            &self.unit_cpu_seconds_total,
+            &self.unit_memory_bytes,
+            &self.unit_processes,
+            &self.scheduler_wakeup_delay_seconds,
+        ]
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        if let Some(simulation) = &self.simulation {
+            self.simulate(simulation);
+            return Ok(());
+        }
+        self.probe
+            .call_once(|| spawn_wakeup_probe(self.scheduler_wakeup_delay_seconds.clone()));
 
         // Get load averages (static method)
         let load_avg = System::load_average();
         self.load_average_1min.set(load_avg.one);
         self.load_average_5min.set(load_avg.five);
         self.load_average_15min.set(load_avg.fifteen);
+        let cores = self
+            .snapshot
+            .with(Refresh::Cpu, |system| system.cpus().len());
+        self.count_breaches(load_avg.one, cores);
 
         // Get uptime (static method)
         self.uptime_seconds.set(System::uptime() as f64);
 
         // Get process count
-        self.process_count.set(system.processes().len() as f64);
+        let processes = self
+            .snapshot
+            .with(Refresh::Processes, |system| system.processes().len());
+        self.process_count.set(processes as f64);
+
+        #[cfg(target_os = "linux")]
+        self.set_units(&read_units(std::path::Path::new(CGROUP_ROOT)));
+        Ok(())
+    }
+}
+
+impl SystemCollector {
+    fn count_breaches(&self, load: f64, cores: usize) {
+        for per_core in &self.load_thresholds {
+            if load > per_core * cores as f64 {
+                self.load_breaches_total
+                    .with_label_values(&[&per_core.to_string()])
+                    .inc();
+            }
+        }
+    }
+
+    /// Replace the unit series, so stopped units go away. The counters are
+    /// the kernel's own totals, so setting them afresh keeps them monotonic.
+    #[cfg(target_os = "linux")]
+    fn set_units(&self, units: &[UnitStats]) {
+        self.unit_cpu_seconds_total.reset();
+        self.unit_memory_bytes.reset();
+        self.unit_processes.reset();
+        for unit in units {
+            let labels = [unit.name.as_str()];
+            self.unit_cpu_seconds_total
+                .with_label_values(&labels)
+                .inc_by(unit.cpu_seconds);
+            self.unit_memory_bytes
+                .with_label_values(&labels)
+                .set(unit.memory_bytes);
+            self.unit_processes
+                .with_label_values(&labels)
+                .set(unit.processes);
+        }
+    }
+}
+
+/// Sleep in a loop and record how late each wakeup is: the time a runnable
+/// thread waits for a CPU, which grows with saturation before the load
+/// average shows it. Timer slack adds a floor of about 50µs on Linux.
+fn spawn_wakeup_probe(delay: NativeHistogram) {
+    let spawned = std::thread::Builder::new()
+        .name("wakeup-probe".to_string())
+        .spawn(move || loop {
+            let start = Instant::now();
+            std::thread::sleep(PROBE_INTERVAL);
+            delay.observe(start.elapsed().saturating_sub(PROBE_INTERVAL).as_secs_f64());
+        });
+    if let Err(e) = spawned {
+        eprintln!("Failed to start the wakeup probe: {}", e);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, PartialEq)]
+struct UnitStats {
+    name: String,
+    cpu_seconds: f64,
+    memory_bytes: f64,
+    processes: f64,
+}
+
+/// Every `.service` and `.scope` cgroup under `root`, which is empty
+/// without cgroup v2 or systemd. Units nested in a unit, such as the ones
+/// of `user@1000.service`, count towards it.
+#[cfg(target_os = "linux")]
+fn read_units(root: &std::path::Path) -> Vec<UnitStats> {
+    fn walk(dir: &std::path::Path, depth: usize, units: &mut Vec<UnitStats>) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            if !entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let path = entry.path();
+            if name.ends_with(".service") || name.ends_with(".scope") {
+                let read =
+                    |file: &str| std::fs::read_to_string(path.join(file)).unwrap_or_default();
+                let usage_usec = read("cpu.stat")
+                    .lines()
+                    .find_map(|line| line.strip_prefix("usage_usec "))
+                    .and_then(|usec| usec.trim().parse::<f64>().ok())
+                    .unwrap_or(0.0);
+                units.push(UnitStats {
+                    cpu_seconds: usage_usec / 1e6,
+                    memory_bytes: read("memory.current").trim().parse().unwrap_or(0.0),
+                    processes: read("cgroup.procs").lines().count() as f64,
+                    name,
+                });
+            } else if depth < 8 {
+                walk(&path, depth + 1, units);
+            }
+        }
+    }
+    // cgroup v1 repeats the hierarchy once per controller
+    let mut units = Vec::new();
+    if !root.join("cgroup.controllers").exists() {
+        return units;
+    }
+    walk(root, 0, &mut units);
+    units.sort_by(|a, b| a.name.cmp(&b.name));
+    units
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units_from_cgroups() {
+        let root = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/procfs/linux-5.15/sys/fs/cgroup");
+        let units = read_units(&root);
+        assert_eq!(
+            units,
+            [
+                UnitStats {
+                    name: "sshd.service".to_string(),
+                    cpu_seconds: 12.5,
+                    memory_bytes: 4194304.0,
+                    processes: 2.0,
+                },
+                UnitStats {
+                    name: "user@1000.service".to_string(),
+                    cpu_seconds: 3.0,
+                    memory_bytes: 1048576.0,
+                    processes: 0.0,
+                },
+            ]
+        );
     }
 }
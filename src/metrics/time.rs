@@ -0,0 +1,256 @@
+//! Clock health: the kernel's NTP discipline state from `adjtimex` on
+//! Linux, and how far the wall clock moved against the monotonic clock
+//! since the last cycle, which catches steps such as a manual `date -s` or
+//! an NTP client correcting a large offset at once.
+
+use super::builder::metric;
+use super::demo::Simulation;
+use crate::collector::Collector;
+use crate::config::Config;
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::{Counter, Gauge};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// The fastest the kernel slews the clock, 500ppm; anything beyond that
+/// between two cycles was a step
+const MAX_SLEW: f64 = 500e-6;
+
+pub struct TimeCollector {
+    time_seconds: Gauge,
+    time_skew_seconds: Gauge,
+    time_jumps_backward_total: Counter,
+    #[cfg(target_os = "linux")]
+    time_offset_seconds: Gauge,
+    #[cfg(target_os = "linux")]
+    time_max_error_seconds: Gauge,
+    #[cfg(target_os = "linux")]
+    time_estimated_error_seconds: Gauge,
+    #[cfg(target_os = "linux")]
+    time_synchronized: Gauge,
+    /// Both clocks as of the last cycle
+    last: Mutex<Option<(SystemTime, Instant)>>,
+    simulation: Option<Simulation>,
+}
+
+impl TimeCollector {
+    pub fn new(config: &Config) -> Self {
+        TimeCollector {
+            time_seconds: metric("time_seconds", "System time in seconds since the epoch").gauge(),
+            time_skew_seconds: metric(
+                "time_skew_seconds",
+                "How much further the wall clock moved than the monotonic clock since the last cycle",
+            )
+            .gauge(),
+            time_jumps_backward_total: metric(
+                "time_jumps_backward_total",
+                "Cycles in which the wall clock was set back",
+            )
+            .counter(),
+            #[cfg(target_os = "linux")]
+            time_offset_seconds: metric(
+                "time_offset_seconds",
+                "Offset from the NTP reference the kernel is still correcting",
+            )
+            .gauge(),
+            #[cfg(target_os = "linux")]
+            time_max_error_seconds: metric(
+                "time_max_error_seconds",
+                "Upper bound of the clock's error, as set by the NTP client",
+            )
+            .gauge(),
+            #[cfg(target_os = "linux")]
+            time_estimated_error_seconds: metric(
+                "time_estimated_error_seconds",
+                "Estimated error of the clock, as set by the NTP client",
+            )
+            .gauge(),
+            #[cfg(target_os = "linux")]
+            time_synchronized: metric(
+                "time_synchronized",
+                "Whether an NTP client keeps the kernel clock synchronized",
+            )
+            .gauge(),
+            last: Mutex::new(None),
+            simulation: config.demo.map(|seed| Simulation::new(seed, "time")),
+        }
+    }
+
+    fn simulate(&self, simulation: &Simulation) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.time_seconds.set(now.as_secs_f64());
+        self.time_skew_seconds
+            .set(simulation.between(-0.0005, 0.0005));
+        #[cfg(target_os = "linux")]
+        {
+            self.time_offset_seconds
+                .set(simulation.between(-0.002, 0.002));
+            self.time_max_error_seconds
+                .set(simulation.between(0.01, 0.1));
+            self.time_estimated_error_seconds
+                .set(simulation.between(0.0001, 0.001));
+            self.time_synchronized.set(1.0);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_timex(&self, timex: &Timex) {
+        self.time_offset_seconds.set(timex.offset_seconds);
+        self.time_max_error_seconds.set(timex.max_error_seconds);
+        self.time_estimated_error_seconds
+            .set(timex.estimated_error_seconds);
+        self.time_synchronized.set(timex.synchronized as u8 as f64);
+    }
+}
+
+impl Collector for TimeCollector {
+    fn name(&self) -> &'static str {
+        "time"
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
+        Ok(())
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![
+            &self.time_seconds,
+            &self.time_skew_seconds,
+            &self.time_jumps_backward_total,
+            #[cfg(target_os = "linux")]
+            &self.time_offset_seconds,
+            #[cfg(target_os = "linux")]
+            &self.time_max_error_seconds,
+            #[cfg(target_os = "linux")]
+            &self.time_estimated_error_seconds,
+            #[cfg(target_os = "linux")]
+            &self.time_synchronized,
+        ]
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        if let Some(simulation) = &self.simulation {
+            self.simulate(simulation);
+            return Ok(());
+        }
+
+        let now = (SystemTime::now(), Instant::now());
+        self.time_seconds.set(seconds_since(UNIX_EPOCH, now.0));
+        if let Some(last) = self.last.lock().unwrap().replace(now) {
+            let skew = skew(last, now);
+            self.time_skew_seconds.set(skew);
+            let elapsed = now.1.duration_since(last.1).as_secs_f64();
+            if skew < -elapsed * MAX_SLEW {
+                self.time_jumps_backward_total.inc();
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        self.set_timex(&read_timex()?);
+        Ok(())
+    }
+}
+
+/// Seconds from `earlier` to `later`, negative if the clock went back
+fn seconds_since(earlier: SystemTime, later: SystemTime) -> f64 {
+    match later.duration_since(earlier) {
+        Ok(elapsed) => elapsed.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    }
+}
+
+/// How much further the wall clock moved than the monotonic one between
+/// two readings of both
+fn skew(last: (SystemTime, Instant), now: (SystemTime, Instant)) -> f64 {
+    seconds_since(last.0, now.0) - now.1.duration_since(last.1).as_secs_f64()
+}
+
+/// The parts of `struct timex` worth exporting
+#[cfg(target_os = "linux")]
+#[derive(Debug, PartialEq)]
+struct Timex {
+    offset_seconds: f64,
+    max_error_seconds: f64,
+    estimated_error_seconds: f64,
+    synchronized: bool,
+}
+
+/// Read the kernel's clock state without changing it
+#[cfg(target_os = "linux")]
+fn read_timex() -> Result<Timex, String> {
+    // SAFETY: an all-zero timex is valid, and modes 0 only reads
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+    let state = unsafe { libc::adjtimex(&mut timex) };
+    if state < 0 {
+        return Err(format!("adjtimex: {}", std::io::Error::last_os_error()));
+    }
+    Ok(from_timex(state, &timex))
+}
+
+#[cfg(target_os = "linux")]
+fn from_timex(state: libc::c_int, timex: &libc::timex) -> Timex {
+    // The offset is in nanoseconds when the NTP client asked for it
+    let offset_unit = if timex.status & libc::STA_NANO != 0 {
+        1e9
+    } else {
+        1e6
+    };
+    Timex {
+        offset_seconds: timex.offset as f64 / offset_unit,
+        max_error_seconds: timex.maxerror as f64 / 1e6,
+        estimated_error_seconds: timex.esterror as f64 / 1e6,
+        synchronized: state != libc::TIME_ERROR && timex.status & libc::STA_UNSYNC == 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn measures_skew() {
+        let wall = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let monotonic = Instant::now();
+        let later = monotonic + Duration::from_secs(15);
+        let skew_of = |wall_elapsed: f64| {
+            let now = if wall_elapsed < 0.0 {
+                wall - Duration::from_secs_f64(-wall_elapsed)
+            } else {
+                wall + Duration::from_secs_f64(wall_elapsed)
+            };
+            skew((wall, monotonic), (now, later))
+        };
+        assert_eq!(skew_of(15.0), 0.0);
+        assert_eq!(skew_of(17.5), 2.5);
+        // Set back by 20s during the cycle
+        assert_eq!(skew_of(-5.0), -20.0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reads_timex() {
+        // SAFETY: an all-zero timex is valid
+        let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+        timex.offset = -250;
+        timex.maxerror = 16000;
+        timex.esterror = 500;
+        timex.status = libc::STA_PLL;
+        assert_eq!(
+            from_timex(libc::TIME_OK, &timex),
+            Timex {
+                offset_seconds: -0.00025,
+                max_error_seconds: 0.016,
+                estimated_error_seconds: 0.0005,
+                synchronized: true,
+            }
+        );
+        timex.status |= libc::STA_NANO | libc::STA_UNSYNC;
+        let timex = from_timex(libc::TIME_ERROR, &timex);
+        assert_eq!(timex.offset_seconds, -0.00000025);
+        assert!(!timex.synchronized);
+        assert!(read_timex().is_ok());
+    }
+}
@@ -0,0 +1,410 @@
+//! Windows-only metrics that sysinfo doesn't provide: CPU times from
+//! GetSystemTimes, disk I/O from PDH performance counters and service states
+//! from the service control manager.
+
+use super::builder::metric;
+use crate::collector::Collector;
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::{CounterVec, IntGaugeVec};
+use std::sync::Mutex;
+
+pub struct WindowsCollector {
+    cpu_time_seconds_total: CounterVec,
+    disk_operations_total: CounterVec,
+    disk_bytes_total: CounterVec,
+    service_state: IntGaugeVec,
+
+    // PDH counters report cumulative raw values; remember the previous ones
+    // so the prometheus counters can be advanced by the delta
+    state: Mutex<State>,
+}
+
+struct State {
+    disk_query: Option<ffi::DiskQuery>,
+    last_cpu: Option<[f64; 3]>,
+    last_disk: Option<[i64; 4]>,
+}
+
+const SERVICE_STATES: [&str; 7] = [
+    "stopped",
+    "start_pending",
+    "stop_pending",
+    "running",
+    "continue_pending",
+    "pause_pending",
+    "paused",
+];
+
+impl WindowsCollector {
+    pub fn new() -> Self {
+        let cpu_time_seconds_total = metric(
+            "windows_cpu_time_seconds_total",
+            "Total CPU time spent in each mode across all cores",
+        )
+        .counter_vec(&["mode"]);
+
+        let disk_operations_total = metric(
+            "windows_disk_operations_total",
+            "Total disk read/write operations across all physical disks",
+        )
+        .counter_vec(&["operation"]);
+
+        let disk_bytes_total = metric(
+            "windows_disk_bytes_total",
+            "Total bytes read/written across all physical disks",
+        )
+        .counter_vec(&["operation"]);
+
+        let service_state = metric(
+            "windows_service_state",
+            "Current state of each Win32 service (1 for the active state)",
+        )
+        .int_gauge_vec(&["name", "state"]);
+
+        let disk_query = match ffi::DiskQuery::open() {
+            Ok(query) => Some(query),
+            Err(status) => {
+                eprintln!("Failed to open PDH disk query: 0x{:08x}", status);
+                None
+            }
+        };
+
+        WindowsCollector {
+            cpu_time_seconds_total,
+            disk_operations_total,
+            disk_bytes_total,
+            service_state,
+            state: Mutex::new(State {
+                disk_query,
+                last_cpu: None,
+                last_disk: None,
+            }),
+        }
+    }
+}
+
+impl Collector for WindowsCollector {
+    fn name(&self) -> &'static str {
+        "windows"
+    }
+
+    fn platforms(&self) -> &'static [&'static str] {
+        &["windows"]
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
+        Ok(())
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![
+            &self.cpu_time_seconds_total,
+            &self.disk_operations_total,
+            &self.disk_bytes_total,
+            &self.service_state,
+        ]
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+
+        // CPU times: kernel time includes idle time, report it separately
+        if let Some((idle, kernel, user)) = ffi::system_times() {
+            let current = [idle, kernel - idle, user];
+            if let Some(last) = state.last_cpu {
+                for (i, mode) in ["idle", "system", "user"].iter().enumerate() {
+                    let delta = current[i] - last[i];
+                    if delta > 0.0 {
+                        self.cpu_time_seconds_total
+                            .with_label_values(&[mode])
+                            .inc_by(delta);
+                    }
+                }
+            }
+            state.last_cpu = Some(current);
+        }
+
+        // Disk I/O from \PhysicalDisk(_Total)
+        if let Some(values) = state.disk_query.as_ref().and_then(|q| q.collect()) {
+            if let Some(last) = state.last_disk {
+                let delta = |i: usize| (values[i] - last[i]).max(0) as f64;
+                self.disk_operations_total
+                    .with_label_values(&["read"])
+                    .inc_by(delta(0));
+                self.disk_operations_total
+                    .with_label_values(&["write"])
+                    .inc_by(delta(1));
+                self.disk_bytes_total
+                    .with_label_values(&["read"])
+                    .inc_by(delta(2));
+                self.disk_bytes_total
+                    .with_label_values(&["write"])
+                    .inc_by(delta(3));
+            }
+            state.last_disk = Some(values);
+        }
+
+        // Service states; reset so removed services disappear
+        if let Some(services) = ffi::services() {
+            self.service_state.reset();
+            for (name, current) in services {
+                for (i, label) in SERVICE_STATES.iter().enumerate() {
+                    let active = current as usize == i + 1;
+                    self.service_state
+                        .with_label_values(&[name.as_str(), *label])
+                        .set(active as i64);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Raw bindings for the handful of Win32 calls used above
+#[allow(non_snake_case, clippy::upper_case_acronyms)]
+mod ffi {
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    struct FILETIME {
+        dwLowDateTime: u32,
+        dwHighDateTime: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct PDH_RAW_COUNTER {
+        CStatus: u32,
+        TimeStamp: FILETIME,
+        FirstValue: i64,
+        SecondValue: i64,
+        MultiCount: u32,
+    }
+
+    #[repr(C)]
+    struct SERVICE_STATUS_PROCESS {
+        dwServiceType: u32,
+        dwCurrentState: u32,
+        dwControlsAccepted: u32,
+        dwWin32ExitCode: u32,
+        dwServiceSpecificExitCode: u32,
+        dwCheckPoint: u32,
+        dwWaitHint: u32,
+        dwProcessId: u32,
+        dwServiceFlags: u32,
+    }
+
+    #[repr(C)]
+    struct ENUM_SERVICE_STATUS_PROCESSW {
+        lpServiceName: *mut u16,
+        lpDisplayName: *mut u16,
+        ServiceStatusProcess: SERVICE_STATUS_PROCESS,
+    }
+
+    const ERROR_SUCCESS: u32 = 0;
+    const ERROR_MORE_DATA: u32 = 234;
+    const SC_MANAGER_ENUMERATE_SERVICE: u32 = 0x0004;
+    const SC_ENUM_PROCESS_INFO: u32 = 0;
+    const SERVICE_WIN32: u32 = 0x30;
+    const SERVICE_STATE_ALL: u32 = 0x3;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetSystemTimes(idle: *mut FILETIME, kernel: *mut FILETIME, user: *mut FILETIME) -> i32;
+        fn GetLastError() -> u32;
+    }
+
+    #[link(name = "pdh")]
+    extern "system" {
+        fn PdhOpenQueryW(data_source: *const u16, user_data: usize, query: *mut isize) -> u32;
+        fn PdhAddEnglishCounterW(
+            query: isize,
+            path: *const u16,
+            user_data: usize,
+            counter: *mut isize,
+        ) -> u32;
+        fn PdhCollectQueryData(query: isize) -> u32;
+        fn PdhGetRawCounterValue(
+            counter: isize,
+            counter_type: *mut u32,
+            value: *mut PDH_RAW_COUNTER,
+        ) -> u32;
+        fn PdhCloseQuery(query: isize) -> u32;
+    }
+
+    #[link(name = "advapi32")]
+    extern "system" {
+        fn OpenSCManagerW(machine: *const u16, database: *const u16, access: u32) -> isize;
+        fn EnumServicesStatusExW(
+            manager: isize,
+            info_level: u32,
+            service_type: u32,
+            service_state: u32,
+            services: *mut u8,
+            buf_size: u32,
+            bytes_needed: *mut u32,
+            services_returned: *mut u32,
+            resume_handle: *mut u32,
+            group_name: *const u16,
+        ) -> i32;
+        fn CloseServiceHandle(handle: isize) -> i32;
+    }
+
+    fn wide(s: &str) -> Vec<u16> {
+        OsStr::new(s).encode_wide().chain(Some(0)).collect()
+    }
+
+    unsafe fn from_wide(p: *const u16) -> String {
+        if p.is_null() {
+            return String::new();
+        }
+        let mut len = 0;
+        while *p.add(len) != 0 {
+            len += 1;
+        }
+        String::from_utf16_lossy(std::slice::from_raw_parts(p, len))
+    }
+
+    /// FILETIME is in 100ns units
+    fn seconds(t: FILETIME) -> f64 {
+        (((t.dwHighDateTime as u64) << 32) | t.dwLowDateTime as u64) as f64 / 1e7
+    }
+
+    /// (idle, kernel, user) seconds summed over all processors
+    pub fn system_times() -> Option<(f64, f64, f64)> {
+        let mut idle = FILETIME::default();
+        let mut kernel = FILETIME::default();
+        let mut user = FILETIME::default();
+        // SAFETY: all three pointers refer to live, writable FILETIMEs
+        let ok = unsafe { GetSystemTimes(&mut idle, &mut kernel, &mut user) };
+        (ok != 0).then(|| (seconds(idle), seconds(kernel), seconds(user)))
+    }
+
+    const DISK_COUNTERS: [&str; 4] = [
+        r"\PhysicalDisk(_Total)\Disk Reads/sec",
+        r"\PhysicalDisk(_Total)\Disk Writes/sec",
+        r"\PhysicalDisk(_Total)\Disk Read Bytes/sec",
+        r"\PhysicalDisk(_Total)\Disk Write Bytes/sec",
+    ];
+
+    pub struct DiskQuery {
+        query: isize,
+        counters: [isize; 4],
+    }
+
+    // PDH handles may be used from any thread as long as access is serialized,
+    // which the collector's Mutex guarantees
+    unsafe impl Send for DiskQuery {}
+
+    impl DiskQuery {
+        pub fn open() -> Result<Self, u32> {
+            let mut query = 0;
+            // SAFETY: a null data source selects real-time data
+            let status = unsafe { PdhOpenQueryW(ptr::null(), 0, &mut query) };
+            if status != ERROR_SUCCESS {
+                return Err(status);
+            }
+
+            let mut counters = [0; 4];
+            for (path, counter) in DISK_COUNTERS.iter().zip(counters.iter_mut()) {
+                let path = wide(path);
+                // SAFETY: `path` is NUL-terminated and outlives the call
+                let status = unsafe { PdhAddEnglishCounterW(query, path.as_ptr(), 0, counter) };
+                if status != ERROR_SUCCESS {
+                    // SAFETY: query was opened above
+                    unsafe { PdhCloseQuery(query) };
+                    return Err(status);
+                }
+            }
+
+            Ok(DiskQuery { query, counters })
+        }
+
+        /// Cumulative reads, writes, read bytes and write bytes
+        pub fn collect(&self) -> Option<[i64; 4]> {
+            // SAFETY: the query handle is valid until drop
+            if unsafe { PdhCollectQueryData(self.query) } != ERROR_SUCCESS {
+                return None;
+            }
+            let mut values = [0; 4];
+            for (counter, value) in self.counters.iter().zip(values.iter_mut()) {
+                let mut raw = PDH_RAW_COUNTER::default();
+                // SAFETY: counter belongs to this query; raw is writable
+                let status = unsafe { PdhGetRawCounterValue(*counter, ptr::null_mut(), &mut raw) };
+                if status != ERROR_SUCCESS {
+                    return None;
+                }
+                *value = raw.FirstValue;
+            }
+            Some(values)
+        }
+    }
+
+    impl Drop for DiskQuery {
+        fn drop(&mut self) {
+            // SAFETY: the handle came from PdhOpenQueryW and is closed once
+            unsafe { PdhCloseQuery(self.query) };
+        }
+    }
+
+    /// (service name, SERVICE_STATUS_PROCESS.dwCurrentState) for every Win32 service
+    pub fn services() -> Option<Vec<(String, u32)>> {
+        // SAFETY: null machine/database open the local active database
+        let manager =
+            unsafe { OpenSCManagerW(ptr::null(), ptr::null(), SC_MANAGER_ENUMERATE_SERVICE) };
+        if manager == 0 {
+            return None;
+        }
+
+        let mut result = Vec::new();
+        // u64 elements keep the buffer aligned for the pointer-bearing structs
+        let mut buffer: Vec<u64> = vec![0; 8 * 1024];
+        let mut resume = 0u32;
+        loop {
+            let mut needed = 0u32;
+            let mut returned = 0u32;
+            // SAFETY: buffer is writable for the size passed
+            let ok = unsafe {
+                EnumServicesStatusExW(
+                    manager,
+                    SC_ENUM_PROCESS_INFO,
+                    SERVICE_WIN32,
+                    SERVICE_STATE_ALL,
+                    buffer.as_mut_ptr() as *mut u8,
+                    (buffer.len() * 8) as u32,
+                    &mut needed,
+                    &mut returned,
+                    &mut resume,
+                    ptr::null(),
+                )
+            };
+            // SAFETY: trivially safe
+            let more = ok == 0 && unsafe { GetLastError() } == ERROR_MORE_DATA;
+            if ok == 0 && !more {
+                break;
+            }
+
+            let entries = buffer.as_ptr() as *const ENUM_SERVICE_STATUS_PROCESSW;
+            for i in 0..returned as usize {
+                // SAFETY: the API wrote `returned` entries at the start of buffer
+                let entry = unsafe { &*entries.add(i) };
+                let name = unsafe { from_wide(entry.lpServiceName) };
+                result.push((name, entry.ServiceStatusProcess.dwCurrentState));
+            }
+
+            if !more {
+                break;
+            }
+            if returned == 0 {
+                buffer.resize((needed as usize).div_ceil(8), 0);
+            }
+        }
+
+        // SAFETY: manager was opened above
+        unsafe { CloseServiceHandle(manager) };
+        Some(result)
+    }
+}
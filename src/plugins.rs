@@ -0,0 +1,256 @@
+//! Collector plugins: shared libraries in `plugins.directory` exporting a
+//! `create_collector` symbol, so third parties can ship collectors without
+//! rebuilding metrixd. A plugin is a `cdylib` crate depending on metrixd:
+//!
+//! ```ignore
+//! metrixd::declare_plugin!(NginxCollector::new);
+//! ```
+//!
+//! Rust has no stable ABI, so a plugin must be built against the same
+//! metrixd version, with the same compiler and prometheus version, as the
+//! daemon loading it. The version is checked on load; the rest is up to
+//! whoever builds the plugin.
+//!
+//! A plugin links its own copy of prometheus, whose default registry
+//! metrixd never gathers. Plugin metrics are therefore served through
+//! `Collector::metrics()`, and should be created with `Gauge::with_opts`
+//! and friends rather than registered by the plugin; its `register_metrics`
+//! isn't called. Such metrics aren't known to metrixd's builder, so a plugin
+//! whose vecs should be cleared when it is shed overrides `reset_metrics`.
+//!
+//! `.wasm` files in the directory are WASM plugins instead, which need
+//! metrixd built with the `wasm` feature; see `crate::wasm`.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use prometheus::core::{Collector as PrometheusCollector, Desc};
+use prometheus::proto::MetricFamily;
+
+use crate::collector::Collector;
+use crate::config::PluginsConfig;
+
+pub type PluginCollector = Box<dyn Collector + Send + Sync>;
+
+/// Signature of the `create_collector` symbol
+pub type CreateCollector = unsafe extern "C" fn() -> *mut PluginCollector;
+
+/// The metrixd version plugins are built against, NUL-terminated for the
+/// `metrixd_plugin_version` symbol
+#[doc(hidden)]
+pub const PLUGIN_VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "\0");
+
+/// Export `create_collector` and `metrixd_plugin_version` from a plugin.
+/// Takes a function (or closure) returning the collector.
+#[macro_export]
+macro_rules! declare_plugin {
+    ($constructor:expr) => {
+        #[unsafe(no_mangle)]
+        pub extern "C" fn metrixd_plugin_version() -> *const ::std::ffi::c_char {
+            $crate::plugins::PLUGIN_VERSION.as_ptr().cast()
+        }
+
+        #[unsafe(no_mangle)]
+        pub extern "C" fn create_collector() -> *mut $crate::plugins::PluginCollector {
+            let collector: $crate::plugins::PluginCollector = Box::new(($constructor)());
+            Box::into_raw(Box::new(collector))
+        }
+    };
+}
+
+/// A loaded plugin. Libraries are never unloaded, since collectors
+/// created from them live as long as the daemon.
+pub struct Plugin {
+    path: PathBuf,
+    kind: Kind,
+}
+
+enum Kind {
+    Library(CreateCollector),
+    #[cfg(feature = "wasm")]
+    Wasm(crate::wasm::WasmPlugin),
+}
+
+impl Plugin {
+    pub fn create_collector(&self) -> PluginCollector {
+        let collector: PluginCollector = match &self.kind {
+            Kind::Library(create) => {
+                let collector = unsafe { Box::from_raw(create()) };
+                Box::new(LoadedCollector(Arc::from(*collector)))
+            }
+            #[cfg(feature = "wasm")]
+            Kind::Wasm(plugin) => Box::new(plugin.create_collector()),
+        };
+        println!(
+            "Loaded collector {} from {}",
+            collector.name(),
+            self.path.display()
+        );
+        collector
+    }
+}
+
+/// Load every shared library and WASM module in `plugins.directory`, in
+/// file name order. Done before the filesystem sandbox is applied; the
+/// plugin's collector is only created later, alongside the built-in ones.
+pub fn open(config: &PluginsConfig) -> Result<Vec<Plugin>, String> {
+    let Some(directory) = &config.directory else {
+        return Ok(Vec::new());
+    };
+    let entries =
+        std::fs::read_dir(directory).map_err(|e| format!("{}: {}", directory.display(), e))?;
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            let extension = path.extension();
+            extension == Some(std::env::consts::DLL_EXTENSION.as_ref())
+                || extension == Some("wasm".as_ref())
+        })
+        .collect();
+    paths.sort();
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let kind = match path.extension() == Some("wasm".as_ref()) {
+                true => open_wasm(&path, config),
+                false => imp::open(&path).map(Kind::Library),
+            };
+            let kind = kind.map_err(|e| format!("{}: {}", path.display(), e))?;
+            Ok(Plugin { path, kind })
+        })
+        .collect()
+}
+
+#[cfg(feature = "wasm")]
+fn open_wasm(path: &Path, config: &PluginsConfig) -> Result<Kind, String> {
+    crate::wasm::open(path, &config.wasm_read_paths).map(Kind::Wasm)
+}
+
+#[cfg(not(feature = "wasm"))]
+fn open_wasm(_path: &Path, _config: &PluginsConfig) -> Result<Kind, String> {
+    Err("WASM plugins need metrixd built with the wasm feature".to_string())
+}
+
+/// Adapts a plugin's collector to the daemon: its metrics are registered
+/// with the daemon's registry through `Collector::metrics()`
+struct LoadedCollector(Arc<dyn Collector + Send + Sync>);
+
+/// Serves a collector's `metrics()` through a registry it didn't register
+/// them with
+#[derive(Clone)]
+pub(crate) struct CollectorMetrics(pub(crate) Arc<dyn Collector + Send + Sync>);
+
+impl Collector for LoadedCollector {
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    /// The plugin's own `register_metrics` isn't called, since its metrics
+    /// are served through the wrapper alone
+    fn register_metrics(&self, registry: &prometheus::Registry) -> prometheus::Result<()> {
+        crate::metrics::builder::register(registry, &CollectorMetrics(Arc::clone(&self.0)))
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        self.0.collect_metrics()
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        self.0.metrics()
+    }
+
+    fn reset_metrics(&self, registry: &prometheus::Registry) {
+        self.0.reset_metrics(registry);
+    }
+
+    fn platforms(&self) -> &'static [&'static str] {
+        self.0.platforms()
+    }
+}
+
+impl PrometheusCollector for CollectorMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        self.0
+            .metrics()
+            .into_iter()
+            .flat_map(|metric| metric.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.0
+            .metrics()
+            .into_iter()
+            .flat_map(|metric| metric.collect())
+            .collect()
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    use super::{CreateCollector, PLUGIN_VERSION};
+
+    pub fn open(path: &Path) -> Result<CreateCollector, String> {
+        let c_path =
+            CString::new(path.as_os_str().as_bytes()).map_err(|_| "invalid path".to_string())?;
+        let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            // dlerror already names the file, which the caller adds too
+            let error = dl_error();
+            let prefix = format!("{}: ", path.display());
+            return Err(error.strip_prefix(&prefix).unwrap_or(&error).to_string());
+        }
+
+        let version = symbol(handle, c"metrixd_plugin_version")?;
+        let version: unsafe extern "C" fn() -> *const libc::c_char =
+            unsafe { std::mem::transmute(version) };
+        let version = unsafe { CStr::from_ptr(version()) }.to_string_lossy();
+        let expected = PLUGIN_VERSION.trim_end_matches('\0');
+        if version != expected {
+            return Err(format!(
+                "built for metrixd {}, this is metrixd {}",
+                version, expected
+            ));
+        }
+
+        let create = symbol(handle, c"create_collector")?;
+        Ok(unsafe { std::mem::transmute::<*mut libc::c_void, CreateCollector>(create) })
+    }
+
+    fn symbol(handle: *mut libc::c_void, name: &CStr) -> Result<*mut libc::c_void, String> {
+        let symbol = unsafe { libc::dlsym(handle, name.as_ptr()) };
+        if symbol.is_null() {
+            return Err(format!(
+                "missing symbol {} (not a metrixd plugin?)",
+                name.to_string_lossy()
+            ));
+        }
+        Ok(symbol)
+    }
+
+    fn dl_error() -> String {
+        let error = unsafe { libc::dlerror() };
+        if error.is_null() {
+            return "dlopen failed".to_string();
+        }
+        unsafe { CStr::from_ptr(error) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::path::Path;
+
+    use super::CreateCollector;
+
+    pub fn open(_path: &Path) -> Result<CreateCollector, String> {
+        Err("plugins are only supported on Unix".to_string())
+    }
+}
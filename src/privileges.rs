@@ -0,0 +1,162 @@
+//! Dropping root privileges once listening sockets are bound and
+//! collectors have opened whatever privileged files they need.
+
+use crate::config::SecurityConfig;
+
+/// The identity to switch to, resolved from `security.user`/`security.group`.
+/// Without a group, the user's primary group is used.
+#[cfg(unix)]
+pub struct Identity {
+    uid: Option<libc::uid_t>,
+    gid: libc::gid_t,
+}
+
+#[cfg(unix)]
+impl Identity {
+    /// Look the configured names up. Done early, while the user and group
+    /// databases are still readable under the sandbox.
+    pub fn resolve(security: &SecurityConfig) -> Result<Option<Identity>, String> {
+        let (uid, user_gid) = match &security.user {
+            Some(user) => {
+                let (uid, gid) = lookup_user(user)?;
+                (Some(uid), Some(gid))
+            }
+            None => (None, None),
+        };
+        let gid = match (&security.group, user_gid) {
+            (Some(group), _) => lookup_group(group)?,
+            (None, Some(gid)) => gid,
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(Identity { uid, gid }))
+    }
+
+    /// Drop supplementary groups, then switch group and user, so nothing
+    /// inherited from root survives
+    pub fn switch(&self) -> Result<(), String> {
+        let (uid, gid) = (self.uid, self.gid);
+
+        // Nothing to do when already running as the target identity, e.g.
+        // under a service manager that set User= itself
+        let already =
+            unsafe { libc::getegid() == gid && uid.is_none_or(|uid| libc::geteuid() == uid) };
+        if already {
+            return Ok(());
+        }
+
+        unsafe {
+            if libc::setgroups(1, &gid) != 0 {
+                return Err(format!("setgroups: {}", std::io::Error::last_os_error()));
+            }
+            if libc::setgid(gid) != 0 {
+                return Err(format!(
+                    "setgid({}): {}",
+                    gid,
+                    std::io::Error::last_os_error()
+                ));
+            }
+            if let Some(uid) = uid {
+                if libc::setuid(uid) != 0 {
+                    return Err(format!(
+                        "setuid({}): {}",
+                        uid,
+                        std::io::Error::last_os_error()
+                    ));
+                }
+                // Make sure root can't be regained
+                if uid != 0 && libc::setuid(0) == 0 {
+                    return Err("privileges could be regained after setuid".to_string());
+                }
+            }
+        }
+
+        match uid {
+            Some(uid) => println!("Dropped privileges to uid {}, gid {}", uid, gid),
+            None => println!("Dropped privileges to gid {}", gid),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+pub struct Identity;
+
+#[cfg(not(unix))]
+impl Identity {
+    pub fn resolve(security: &SecurityConfig) -> Result<Option<Identity>, String> {
+        if security.user.is_some() || security.group.is_some() {
+            return Err("security.user and security.group are only supported on Unix".to_string());
+        }
+        Ok(None)
+    }
+
+    pub fn switch(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Resolve a user name (or numeric uid) to its uid and primary gid
+#[cfg(unix)]
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t), String> {
+    let name = std::ffi::CString::new(user).map_err(|_| format!("invalid user '{}'", user))?;
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+
+    let rc = unsafe {
+        match user.parse::<libc::uid_t>() {
+            Ok(uid) => libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result),
+            Err(_) => libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            ),
+        }
+    };
+    if rc != 0 {
+        return Err(format!(
+            "looking up user '{}': {}",
+            user,
+            std::io::Error::from_raw_os_error(rc)
+        ));
+    }
+    if result.is_null() {
+        return Err(format!("unknown user '{}'", user));
+    }
+    Ok((pwd.pw_uid, pwd.pw_gid))
+}
+
+/// Resolve a group name (or numeric gid) to its gid
+#[cfg(unix)]
+fn lookup_group(group: &str) -> Result<libc::gid_t, String> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+    let name = std::ffi::CString::new(group).map_err(|_| format!("invalid group '{}'", group))?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::group = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+
+    let rc = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if rc != 0 {
+        return Err(format!(
+            "looking up group '{}': {}",
+            group,
+            std::io::Error::from_raw_os_error(rc)
+        ));
+    }
+    if result.is_null() {
+        return Err(format!("unknown group '{}'", group));
+    }
+    Ok(grp.gr_gid)
+}
@@ -0,0 +1,53 @@
+//! The multi-target exporter pattern: `/probe?target=<target>&module=<name>`
+//! checks a remote endpoint while the scrape waits and answers with
+//! `probe_*` metrics about it, like blackbox_exporter. Prometheus keeps the
+//! list of targets and passes each one as a parameter through relabeling,
+//! so one metrixd can watch many endpoints.
+
+use std::time::{Duration, Instant};
+
+use prometheus::proto::MetricFamily;
+use prometheus::Registry;
+
+use crate::config::{ProbeModule, Prober};
+use crate::fetch;
+use crate::metrics::builder::metric;
+
+/// Probe `target` with `module`, giving up after `timeout`
+pub fn probe(module: &ProbeModule, target: &str, timeout: Duration) -> Vec<MetricFamily> {
+    let registry = Registry::new();
+    let success = metric("probe_success", "Whether the probe succeeded")
+        .registry(&registry)
+        .gauge();
+    let duration = metric("probe_duration_seconds", "How long the probe took")
+        .registry(&registry)
+        .gauge();
+
+    let started = Instant::now();
+    let succeeded = match module.prober {
+        Prober::Tcp => fetch::connect(target, timeout).is_ok(),
+        Prober::Http => {
+            let status_code = metric(
+                "probe_http_status_code",
+                "Status code of the response, 0 if there was none",
+            )
+            .registry(&registry)
+            .gauge();
+            match http_get(target, timeout) {
+                Ok(status) => {
+                    status_code.set(status.into());
+                    (200..300).contains(&status)
+                }
+                Err(_) => false,
+            }
+        }
+    };
+    duration.set(started.elapsed().as_secs_f64());
+    success.set(if succeeded { 1.0 } else { 0.0 });
+    registry.gather()
+}
+
+fn http_get(target: &str, timeout: Duration) -> Result<u16, String> {
+    let (address, path) = fetch::parse_url(target)?;
+    fetch::request(&address, "GET", &path, &[], timeout).map(|response| response.status)
+}
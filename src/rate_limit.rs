@@ -0,0 +1,88 @@
+//! Token buckets per client address for `web.rate_limit`, so a scraper
+//! misconfigured to poll every 100ms can't keep the agent busy. Each client
+//! may make `burst` requests at once and `rate` per second after that.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Buckets are only pruned once there are this many, so a short list of
+/// scrapers never pays for it
+const PRUNE_ABOVE: usize = 1024;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Take a token from the client's bucket, or say how long until the
+    /// next one is there
+    pub fn acquire(
+        &self,
+        client: IpAddr,
+        rate: u32,
+        burst: u32,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let (rate, burst) = (f64::from(rate), f64::from(burst));
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > PRUNE_ABOVE {
+            // Full buckets are what a new client starts with anyway
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refilled).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_each_client_separately() {
+        let limiter = RateLimiter::default();
+        let (scraper, other) = (IpAddr::from([10, 0, 0, 5]), IpAddr::from([10, 0, 0, 6]));
+        let start = Instant::now();
+
+        // A burst of 3, then one request every half second
+        for _ in 0..3 {
+            assert_eq!(limiter.acquire(scraper, 2, 3, start), Ok(()));
+        }
+        assert_eq!(
+            limiter.acquire(scraper, 2, 3, start),
+            Err(Duration::from_millis(500))
+        );
+        assert_eq!(limiter.acquire(other, 2, 3, start), Ok(()));
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.acquire(scraper, 2, 3, later), Ok(()));
+        assert!(limiter.acquire(scraper, 2, 3, later).is_err());
+        // Idle time refills up to the burst only
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limiter.acquire(scraper, 2, 3, much_later), Ok(()));
+        }
+        assert!(limiter.acquire(scraper, 2, 3, much_later).is_err());
+    }
+}
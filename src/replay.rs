@@ -0,0 +1,137 @@
+//! `metrixd record` and `metrixd replay`: dump every collection cycle to a
+//! file, then serve the recorded cycles on /metrics with their original
+//! timing, for reproducible dashboards, demos and regression tests.
+//!
+//! A recording is a header line followed by one entry per cycle: the
+//! milliseconds since recording started and the number of metric families
+//! as varints, then the families as length-delimited protobuf messages.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use prometheus::core::{Collector as PrometheusCollector, Desc};
+use prometheus::proto::MetricFamily;
+use protobuf::{CodedInputStream, CodedOutputStream};
+
+use crate::collector::Collector;
+use crate::server::gather_filtered;
+use crate::state::AppState;
+
+const HEADER: &[u8] = b"metrixd recording v1\n";
+
+pub struct Cycle {
+    /// Time since the first cycle was recorded
+    pub offset: Duration,
+    pub families: Vec<MetricFamily>,
+}
+
+/// Collect every interval and append each cycle to the file, until killed.
+/// Every cycle is flushed, so an interrupted recording stays readable.
+pub fn record(
+    collectors: &[Box<dyn Collector + Send + Sync>],
+    state: &AppState,
+    path: &Path,
+) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut writer = BufWriter::new(file);
+    let write_error = |e: std::io::Error| format!("{}: {}", path.display(), e);
+    writer.write_all(HEADER).map_err(write_error)?;
+
+    println!("Recording to {}", path.display());
+    let started = Instant::now();
+    let mut cycle = 0u64;
+    loop {
+        cycle += 1;
+        for collector in collectors {
+            state.run_collector(collector.as_ref());
+        }
+
+        let families = gather_filtered(state);
+        let mut out = CodedOutputStream::new(&mut writer);
+        let offset_ms = started.elapsed().as_millis() as u64;
+        out.write_uint64_no_tag(offset_ms)
+            .and_then(|()| out.write_uint64_no_tag(families.len() as u64))
+            .and_then(|()| {
+                families
+                    .iter()
+                    .try_for_each(|family| out.write_message_no_tag(family))
+            })
+            .and_then(|()| out.flush())
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        drop(out);
+        writer.flush().map_err(write_error)?;
+        println!("Recorded cycle {}", cycle);
+
+        std::thread::sleep(state.config().collection.interval);
+    }
+}
+
+/// Read a whole recording into memory
+pub fn load(path: &Path) -> Result<Vec<Cycle>, String> {
+    let data = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let body = data
+        .strip_prefix(HEADER)
+        .ok_or_else(|| format!("{}: not a metrixd recording", path.display()))?;
+
+    let mut input = CodedInputStream::from_bytes(body);
+    let mut cycles = Vec::new();
+    let corrupt = |e: protobuf::Error| format!("{}: corrupt recording: {}", path.display(), e);
+    while !input.eof().map_err(corrupt)? {
+        let offset = Duration::from_millis(input.read_uint64().map_err(corrupt)?);
+        let count = input.read_uint64().map_err(corrupt)?;
+        let families = (0..count)
+            .map(|_| input.read_message::<MetricFamily>())
+            .collect::<Result<_, _>>()
+            .map_err(corrupt)?;
+        cycles.push(Cycle { offset, families });
+    }
+    if cycles.is_empty() {
+        return Err(format!("{}: recording has no cycles", path.display()));
+    }
+    Ok(cycles)
+}
+
+/// Exposes the cycle being replayed through the default registry, so
+/// /metrics, its filters and the gRPC API work as usual. It has no fixed
+/// descriptors since the recorded families are only known at runtime.
+#[derive(Clone, Default)]
+pub struct Player {
+    current: Arc<Mutex<Vec<MetricFamily>>>,
+}
+
+impl Player {
+    /// Step through the cycles with their original spacing. Afterwards the
+    /// last cycle stays up, or with `repeat` the recording starts over.
+    pub async fn play(&self, cycles: Vec<Cycle>, repeat: bool, state: &AppState) {
+        loop {
+            let started = tokio::time::Instant::now();
+            for cycle in &cycles {
+                tokio::time::sleep_until(started + cycle.offset).await;
+                *self.current.lock().unwrap() = cycle.families.clone();
+                state.mark_changed();
+            }
+            if !repeat {
+                println!("Replay finished, serving the last cycle");
+                return;
+            }
+            // Leave the last cycle up for as long as the average interval
+            let last = cycles.last().map_or(Duration::ZERO, |c| c.offset);
+            let gap =
+                (last / cycles.len().saturating_sub(1).max(1) as u32).max(Duration::from_secs(1));
+            tokio::time::sleep_until(started + last + gap).await;
+        }
+    }
+}
+
+impl PrometheusCollector for Player {
+    fn desc(&self) -> Vec<&Desc> {
+        Vec::new()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.current.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,96 @@
+use prometheus::proto::{MetricFamily, MetricType};
+
+/// A single flattened series value, as it would appear on one line of the
+/// text exposition format (histogram buckets become `_bucket` samples etc.).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    pub kind: MetricType,
+    pub help: String,
+}
+
+/// Flatten gathered metric families into individual samples
+pub fn flatten(families: &[MetricFamily]) -> Vec<Sample> {
+    let mut samples = Vec::new();
+
+    for family in families {
+        let name = family.name();
+        let kind = family.type_();
+        let help = family.help();
+
+        for metric in &family.metric {
+            let labels: Vec<(String, String)> = metric
+                .label
+                .iter()
+                .map(|l| (l.name().to_string(), l.value().to_string()))
+                .collect();
+
+            let mut push = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+                let mut labels = labels.clone();
+                if let Some((k, v)) = extra {
+                    labels.push((k.to_string(), v));
+                }
+                samples.push(Sample {
+                    name: format!("{}{}", name, suffix),
+                    labels,
+                    value,
+                    kind,
+                    help: help.to_string(),
+                });
+            };
+
+            match kind {
+                MetricType::COUNTER => push("", None, metric.counter.value()),
+                MetricType::GAUGE => push("", None, metric.gauge.value()),
+                MetricType::UNTYPED => push("", None, metric.untyped.value()),
+                MetricType::HISTOGRAM => {
+                    let histogram = &metric.histogram;
+                    for bucket in &histogram.bucket {
+                        push(
+                            "_bucket",
+                            Some(("le", format_bound(bucket.upper_bound()))),
+                            bucket.cumulative_count() as f64,
+                        );
+                    }
+                    let has_inf = histogram
+                        .bucket
+                        .last()
+                        .is_some_and(|b| b.upper_bound() == f64::INFINITY);
+                    if !has_inf {
+                        push(
+                            "_bucket",
+                            Some(("le", "+Inf".to_string())),
+                            histogram.sample_count() as f64,
+                        );
+                    }
+                    push("_sum", None, histogram.sample_sum());
+                    push("_count", None, histogram.sample_count() as f64);
+                }
+                MetricType::SUMMARY => {
+                    let summary = &metric.summary;
+                    for quantile in &summary.quantile {
+                        push(
+                            "",
+                            Some(("quantile", format_bound(quantile.quantile()))),
+                            quantile.value(),
+                        );
+                    }
+                    push("_sum", None, summary.sample_sum());
+                    push("_count", None, summary.sample_count() as f64);
+                }
+            }
+        }
+    }
+
+    samples
+}
+
+fn format_bound(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
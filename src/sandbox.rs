@@ -0,0 +1,432 @@
+//! Optional Landlock and seccomp confinement for security-sensitive fleets.
+//!
+//! Landlock limits the filesystem to read-only access below /proc, /sys,
+//! the config file, secret files, update-notifier's directory for the
+//! security collector, `dirsize.paths`, `security.landlock_read_paths`,
+//! `plugins.wasm_read_paths` and the directories of `logs.files` and
+//! `freshness.files`, plus
+//! creating and writing files in `debug.dump_directory`, `fsprobe.paths`
+//! and the directory of `export.leader_election.lock_file`. With
+//! federation peers, proxy targets, /probe, push exporters or service
+//! collectors the resolver's files stay readable too, so host names
+//! resolve. It only covers the calling thread and threads started
+//! afterwards, so it has to be applied before the tokio runtime or any
+//! collector spawns threads.
+//!
+//! The seccomp filter is applied once the listening sockets are bound and
+//! privileges are dropped. It rejects, with EPERM, syscalls a metrics
+//! daemon never needs: running programs, tracing other processes, changing
+//! identity, binding or listening on new sockets, loading kernel code and
+//! reconfiguring the system.
+
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, ExportConfig, SecurityConfig, ServicesConfig};
+
+/// Directories that are always readable under Landlock
+const DEFAULT_READ_PATHS: &[&str] = &["/proc", "/sys"];
+
+/// What getaddrinfo reads to resolve a host name
+const RESOLVER_PATHS: &[&str] = &[
+    "/etc/hosts",
+    "/etc/resolv.conf",
+    "/etc/nsswitch.conf",
+    "/etc/gai.conf",
+];
+
+/// Apply `security.landlock`, if enabled
+pub fn restrict_filesystem(config: &Config, config_file: Option<&Path>) -> Result<(), String> {
+    let security = &config.security;
+    if !security.landlock {
+        return Ok(());
+    }
+
+    let resolver: &[&str] = if config.federation.peers.is_empty()
+        && config.proxy.is_empty()
+        && !config.probe.enabled
+        && config.export == ExportConfig::default()
+        && config.services == ServicesConfig::default()
+    {
+        &[]
+    } else {
+        RESOLVER_PATHS
+    };
+    let mut paths: Vec<PathBuf> = DEFAULT_READ_PATHS
+        .iter()
+        .chain(resolver)
+        .map(PathBuf::from)
+        .filter(|path| path.exists())
+        .collect();
+    // Needed for reloads
+    paths.extend(config_file.map(Path::to_path_buf));
+    paths.extend(config.secret_files.iter().cloned());
+    #[cfg(feature = "security")]
+    if config.collector("security").enabled {
+        let notifier = Path::new(crate::metrics::security::UPDATE_NOTIFIER_DIR);
+        paths.extend(notifier.exists().then(|| notifier.to_path_buf()));
+    }
+    paths.extend(security.landlock_read_paths.iter().map(PathBuf::from));
+    paths.extend(
+        config
+            .dirsize
+            .paths
+            .iter()
+            .chain(&config.plugins.wasm_read_paths)
+            .filter(|path| path.exists())
+            .cloned(),
+    );
+    // Directories rather than the files, so rotated files can be reopened
+    // and markers replaced by a rename still be seen
+    paths.extend(
+        config
+            .logs
+            .files
+            .iter()
+            .chain(&config.freshness.files)
+            .filter_map(|file| file.parent())
+            .filter(|dir| dir.exists())
+            .map(Path::to_path_buf),
+    );
+    let writable: Vec<PathBuf> = config
+        .debug
+        .dump_directory
+        .iter()
+        .chain(&config.fsprobe.paths)
+        .cloned()
+        .chain(
+            config
+                .export
+                .leader_election
+                .iter()
+                .filter_map(|election| election.lock_file.parent())
+                .filter(|dir| dir.exists())
+                .map(Path::to_path_buf),
+        )
+        .collect();
+
+    imp::landlock(&paths, &writable)?;
+    println!("Landlock filesystem rules applied");
+    Ok(())
+}
+
+/// Apply `security.seccomp`, if enabled
+pub fn restrict_syscalls(security: &SecurityConfig) -> Result<(), String> {
+    if !security.seccomp {
+        return Ok(());
+    }
+    imp::seccomp()?;
+    println!("Seccomp filter applied");
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+
+    // include/uapi/linux/landlock.h
+    const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+    const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// Every filesystem right the running kernel's Landlock ABI knows
+    /// about, so none of them stay implicitly allowed
+    fn handled_access_fs(abi: i64) -> u64 {
+        let v1 = (1 << 13) - 1; // EXECUTE through MAKE_SYM
+        match abi {
+            1 => v1,
+            2 => v1 | 1 << 13, // REFER
+            3 | 4 => v1 | 1 << 13 | ACCESS_FS_TRUNCATE,
+            _ => v1 | 1 << 13 | ACCESS_FS_TRUNCATE | 1 << 15, // IOCTL_DEV
+        }
+    }
+
+    pub fn landlock(paths: &[PathBuf], writable: &[PathBuf]) -> Result<(), String> {
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0usize,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            return Err(format!(
+                "Landlock is not available: {}",
+                io::Error::last_os_error()
+            ));
+        }
+
+        let handled = handled_access_fs(abi);
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        let ruleset = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                std::mem::size_of::<RulesetAttr>(),
+                0u32,
+            )
+        };
+        if ruleset < 0 {
+            return Err(format!(
+                "landlock_create_ruleset: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        let ruleset = ruleset as libc::c_int;
+
+        let result = add_rules(ruleset, paths, 0)
+            .and_then(|()| add_rules(ruleset, writable, ACCESS_FS_WRITE_FILE | ACCESS_FS_MAKE_REG))
+            .and_then(|()| restrict_self(ruleset));
+        unsafe { libc::close(ruleset) };
+        result
+    }
+
+    /// Read access to each path, plus `extra` rights on directories
+    fn add_rules(ruleset: libc::c_int, paths: &[PathBuf], extra: u64) -> Result<(), String> {
+        for path in paths {
+            let metadata = path
+                .metadata()
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            // Directory rights are rejected on rules for plain files
+            let allowed = if metadata.is_dir() {
+                ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR | extra
+            } else {
+                ACCESS_FS_READ_FILE
+            };
+
+            let c_path = CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| format!("{}: invalid path", path.display()))?;
+            let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+            if fd < 0 {
+                return Err(format!(
+                    "{}: {}",
+                    path.display(),
+                    io::Error::last_os_error()
+                ));
+            }
+
+            let rule = PathBeneathAttr {
+                allowed_access: allowed,
+                parent_fd: fd,
+            };
+            let rc = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset,
+                    LANDLOCK_RULE_PATH_BENEATH,
+                    &rule,
+                    0u32,
+                )
+            };
+            let error = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            if rc != 0 {
+                return Err(format!("landlock_add_rule({}): {}", path.display(), error));
+            }
+        }
+        Ok(())
+    }
+
+    fn restrict_self(ruleset: libc::c_int) -> Result<(), String> {
+        no_new_privs()?;
+        let rc = unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0u32) };
+        if rc != 0 {
+            return Err(format!(
+                "landlock_restrict_self: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Required for unprivileged Landlock and seccomp, and keeps setuid
+    /// binaries from regaining privileges
+    fn no_new_privs() -> Result<(), String> {
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(format!(
+                "PR_SET_NO_NEW_PRIVS: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        Ok(())
+    }
+
+    // include/uapi/linux/audit.h
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xC000_003E;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+    // x32 syscalls on x86_64 have this bit set and would bypass the list
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    const DENIED_SYSCALLS: &[libc::c_long] = &[
+        // Running other programs
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        // Inspecting or modifying other processes
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        // Changing identity (privileges are already dropped by now)
+        libc::SYS_setuid,
+        libc::SYS_setgid,
+        libc::SYS_setreuid,
+        libc::SYS_setregid,
+        libc::SYS_setresuid,
+        libc::SYS_setresgid,
+        libc::SYS_setfsuid,
+        libc::SYS_setfsgid,
+        libc::SYS_setgroups,
+        // New listening sockets; the ones we serve on are already bound
+        libc::SYS_bind,
+        libc::SYS_listen,
+        // Namespaces and mounts
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        // Kernel code and keys
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_kexec_load,
+        libc::SYS_kexec_file_load,
+        libc::SYS_bpf,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        // System configuration
+        libc::SYS_reboot,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+        libc::SYS_acct,
+        libc::SYS_personality,
+    ];
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn statement(code: u32, k: u32) -> libc::sock_filter {
+        jump(code, k, 0, 0)
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn seccomp() -> Result<(), String> {
+        use libc::{
+            BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W, SECCOMP_RET_ALLOW,
+            SECCOMP_RET_ERRNO, SECCOMP_RET_KILL_PROCESS,
+        };
+
+        // Offsets into struct seccomp_data
+        const NR: u32 = 0;
+        const ARCH: u32 = 4;
+        // The low half of the first argument, on little-endian targets
+        #[cfg(feature = "ebpf")]
+        const ARG0: u32 = 16;
+        // bpf() commands, from include/uapi/linux/bpf.h
+        #[cfg(feature = "ebpf")]
+        const BPF_MAP_LOOKUP_ELEM: u32 = 1;
+        #[cfg(feature = "ebpf")]
+        const BPF_MAP_GET_NEXT_KEY: u32 = 4;
+        let deny = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+        let mut filter = vec![
+            // Kill on a foreign architecture, whose numbers mean other things
+            statement(BPF_LD | BPF_W | BPF_ABS, ARCH),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+            statement(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+            statement(BPF_LD | BPF_W | BPF_ABS, NR),
+            jump(BPF_JMP | BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1),
+            statement(BPF_RET | BPF_K, deny),
+        ];
+        // The eBPF collectors' programs are loaded by now, and reading
+        // their maps is all they still need bpf() for
+        #[cfg(feature = "ebpf")]
+        filter.extend([
+            jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_bpf as u32, 0, 5),
+            statement(BPF_LD | BPF_W | BPF_ABS, ARG0),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, BPF_MAP_LOOKUP_ELEM, 2, 0),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, BPF_MAP_GET_NEXT_KEY, 1, 0),
+            statement(BPF_RET | BPF_K, deny),
+            statement(BPF_RET | BPF_K, SECCOMP_RET_ALLOW),
+        ]);
+        for nr in DENIED_SYSCALLS {
+            filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, *nr as u32, 0, 1));
+            filter.push(statement(BPF_RET | BPF_K, deny));
+        }
+        filter.push(statement(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+
+        let program = libc::sock_fprog {
+            len: filter.len() as libc::c_ushort,
+            filter: filter.as_mut_ptr(),
+        };
+
+        no_new_privs()?;
+        // TSYNC applies the filter to every thread, not just this one
+        let rc = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &program,
+            )
+        };
+        if rc != 0 {
+            return Err(format!("seccomp: {}", io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub fn seccomp() -> Result<(), String> {
+        Err("security.seccomp is only supported on x86_64 and aarch64".to_string())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::path::PathBuf;
+
+    pub fn landlock(_paths: &[PathBuf], _writable: &[PathBuf]) -> Result<(), String> {
+        Err("security.landlock is only supported on Linux".to_string())
+    }
+
+    pub fn seccomp() -> Result<(), String> {
+        Err("security.seccomp is only supported on Linux".to_string())
+    }
+}
@@ -0,0 +1,307 @@
+//! When collection cycles run. By default the first cycle runs right away
+//! and each following one an interval after the previous finished.
+//!
+//! `collection.jitter` delays the first cycle by a random part of the given
+//! duration, chosen once per process, so agents restarted together by a
+//! fleet-wide deploy don't refresh in lockstep. `collection.align` starts
+//! cycles on wall-clock multiples of the interval instead (e.g. :00, :15,
+//! :30 and :45 with `15s`), shifted by the same random part of the jitter.
+//!
+//! `collection.adaptive` saves power on laptops and edge devices: while the
+//! host is idle or runs on battery the interval doubles after every cycle,
+//! up to `collection.max_interval`; once the load crosses the lowest of
+//! `thresholds.load_per_core` it drops to `collection.min_interval` right
+//! away, so a busy host is watched closely; otherwise it is the interval.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::CollectionConfig;
+
+/// Below this 1-minute load per core a host counts as idle
+const IDLE_LOAD_PER_CORE: f64 = 0.1;
+
+pub struct Schedule {
+    /// Random fraction of the jitter applied to this process, in [0, 1)
+    fraction: f64,
+    /// The interval `collection.adaptive` settled on last
+    adapted: Option<Duration>,
+}
+
+/// What `collection.adaptive` goes by, read after each cycle
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Conditions {
+    /// The 1-minute load average divided by the core count; none on
+    /// Windows, which has no load average
+    pub load_per_core: Option<f64>,
+    pub on_battery: bool,
+}
+
+impl Conditions {
+    /// The host's conditions; `root` is `/` outside of tests
+    pub fn read(root: &Path) -> Self {
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        Conditions {
+            load_per_core: load_average().map(|load| load / cores as f64),
+            on_battery: on_battery(root),
+        }
+    }
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        let random = RandomState::new().hash_one(std::process::id());
+        Schedule {
+            fraction: (random >> 11) as f64 / (1u64 << 53) as f64,
+            adapted: None,
+        }
+    }
+
+    /// The interval of the next wait
+    fn interval(&self, config: &CollectionConfig) -> Duration {
+        match self.adapted {
+            Some(adapted) if config.adaptive => adapted,
+            _ => config.interval,
+        }
+    }
+
+    /// With `collection.adaptive`, pick the interval of the wait after this
+    /// cycle; `busy_load_per_core` is where shortening starts
+    pub fn adapt(
+        &mut self,
+        config: &CollectionConfig,
+        busy_load_per_core: f64,
+        conditions: Conditions,
+    ) -> Duration {
+        if !config.adaptive {
+            self.adapted = None;
+            return config.interval;
+        }
+        let min = config.min_interval.unwrap_or(config.interval / 2);
+        let max = config.max_interval.unwrap_or(config.interval * 4);
+        let load = conditions.load_per_core;
+        let adapted = if load.is_some_and(|load| load >= busy_load_per_core) {
+            min
+        } else if conditions.on_battery || load.is_some_and(|load| load < IDLE_LOAD_PER_CORE) {
+            let current = self.interval(config).max(config.interval);
+            (current * 2).min(max)
+        } else {
+            config.interval
+        };
+        // Bounds win over the interval, e.g. a max_interval below it
+        let adapted = adapted.clamp(min.min(max), max);
+        self.adapted = Some(adapted);
+        adapted
+    }
+
+    fn offset(&self, config: &CollectionConfig) -> Duration {
+        config.jitter.mul_f64(self.fraction)
+    }
+
+    /// How long to wait before the first cycle
+    pub fn first_delay(&self, config: &CollectionConfig, now: SystemTime) -> Duration {
+        if config.align {
+            self.until_boundary(config, now)
+        } else {
+            self.offset(config)
+        }
+    }
+
+    /// How long to wait after a cycle finished. Re-evaluated every cycle so
+    /// a reload can change the settings.
+    pub fn next_delay(&self, config: &CollectionConfig, now: SystemTime) -> Duration {
+        if config.align {
+            self.until_boundary(config, now)
+        } else {
+            self.interval(config)
+        }
+    }
+
+    fn until_boundary(&self, config: &CollectionConfig, now: SystemTime) -> Duration {
+        let interval = self.interval(config).as_nanos();
+        let offset = self.offset(config).as_nanos() % interval;
+        let since_epoch = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let phase = (since_epoch + interval - offset) % interval;
+        Duration::from_nanos((interval - phase) as u64)
+    }
+}
+
+#[cfg(unix)]
+fn load_average() -> Option<f64> {
+    let mut load = [0.0f64; 1];
+    (unsafe { libc::getloadavg(load.as_mut_ptr(), 1) } == 1).then_some(load[0])
+}
+
+#[cfg(not(unix))]
+fn load_average() -> Option<f64> {
+    None
+}
+
+/// Whether mains power is known to be off, or else a battery discharges.
+/// Hosts without power supplies in sysfs, like most servers, aren't.
+fn on_battery(root: &Path) -> bool {
+    let Ok(supplies) = std::fs::read_dir(root.join("sys/class/power_supply")) else {
+        return false;
+    };
+    let (mut mains, mut discharging) = (None, false);
+    for supply in supplies.flatten() {
+        let read = |file: &str| {
+            std::fs::read_to_string(supply.path().join(file))
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        };
+        match read("type").as_str() {
+            "Mains" => mains = Some(mains.unwrap_or(false) || read("online") == "1"),
+            "Battery" => discharging |= read("status") == "Discharging",
+            _ => {}
+        }
+    }
+    mains.map_or(discharging, |online| !online)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(interval: u64, jitter: u64, align: bool) -> CollectionConfig {
+        CollectionConfig {
+            interval: Duration::from_secs(interval),
+            jitter: Duration::from_secs(jitter),
+            align,
+            max_backoff: Duration::from_secs(300),
+            adaptive: false,
+            min_interval: None,
+            max_interval: None,
+        }
+    }
+
+    fn at(secs: u64, millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn unaligned_waits_the_interval() {
+        let schedule = Schedule {
+            fraction: 0.5,
+            adapted: None,
+        };
+        let config = config(15, 0, false);
+        assert_eq!(schedule.first_delay(&config, at(100, 0)), Duration::ZERO);
+        assert_eq!(
+            schedule.next_delay(&config, at(100, 0)),
+            Duration::from_secs(15)
+        );
+    }
+
+    #[test]
+    fn jitter_delays_the_first_cycle() {
+        let schedule = Schedule {
+            fraction: 0.25,
+            adapted: None,
+        };
+        let config = config(15, 8, false);
+        assert_eq!(
+            schedule.first_delay(&config, at(100, 0)),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            schedule.next_delay(&config, at(100, 0)),
+            Duration::from_secs(15)
+        );
+    }
+
+    #[test]
+    fn aligned_waits_for_the_next_boundary() {
+        let schedule = Schedule {
+            fraction: 0.5,
+            adapted: None,
+        };
+        let config = config(15, 0, true);
+        assert_eq!(
+            schedule.first_delay(&config, at(1000, 500)),
+            Duration::from_millis(4500)
+        );
+        // Exactly on a boundary waits for the next one
+        assert_eq!(
+            schedule.next_delay(&config, at(1005, 0)),
+            Duration::from_secs(15)
+        );
+    }
+
+    #[test]
+    fn aligned_boundaries_are_shifted_by_the_jitter() {
+        let schedule = Schedule {
+            fraction: 0.5,
+            adapted: None,
+        };
+        let config = config(15, 6, true);
+        // Boundaries at :03, :18, :33 and :48 past each minute
+        assert_eq!(
+            schedule.next_delay(&config, at(1020, 0)),
+            Duration::from_secs(3)
+        );
+        assert_eq!(
+            schedule.next_delay(&config, at(1023, 0)),
+            Duration::from_secs(15)
+        );
+    }
+
+    #[test]
+    fn adapts_to_load_and_power() {
+        let mut schedule = Schedule::new();
+        let mut config = config(15, 0, false);
+        config.adaptive = true;
+        let conditions = |load_per_core, on_battery| Conditions {
+            load_per_core: Some(load_per_core),
+            on_battery,
+        };
+
+        // Idle: doubling up to four times the interval
+        for expected in [30, 60, 60] {
+            assert_eq!(
+                schedule.adapt(&config, 1.0, conditions(0.05, false)),
+                Duration::from_secs(expected)
+            );
+        }
+        assert_eq!(
+            schedule.next_delay(&config, at(100, 0)),
+            Duration::from_secs(60)
+        );
+        // Busy: half the interval at once, even on battery
+        assert_eq!(
+            schedule.adapt(&config, 1.0, conditions(1.5, true)),
+            Duration::from_millis(7500)
+        );
+        assert_eq!(
+            schedule.adapt(&config, 1.0, conditions(0.5, true)),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            schedule.adapt(&config, 1.0, conditions(0.5, false)),
+            Duration::from_secs(15)
+        );
+
+        config.adaptive = false;
+        assert_eq!(
+            schedule.adapt(&config, 1.0, conditions(0.05, true)),
+            Duration::from_secs(15)
+        );
+        assert_eq!(
+            schedule.next_delay(&config, at(100, 0)),
+            Duration::from_secs(15)
+        );
+    }
+
+    #[test]
+    fn reads_power_supplies() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/procfs/linux-5.15");
+        assert!(on_battery(&root));
+        assert!(!on_battery(Path::new("/nonexistent")));
+    }
+}
@@ -0,0 +1,535 @@
+//! A small expression language for `[scripts.<name>]` metrics, covering
+//! one-off needs without writing a collector or plugin:
+//!
+//! ```text
+//! value("memory_used_bytes") / value("memory_total_bytes") * 100
+//! value("network_received_bytes_total", "interface", "eth0")
+//! count("disk_usage_percent")
+//! file("/proc/sys/fs/file-nr", 0) - file("/proc/sys/fs/file-nr", 1)
+//! ```
+//!
+//! `value` sums the samples of a metric, optionally only those with the
+//! given label/value pairs; `count` counts them. `file` reads a
+//! whitespace-separated field (the first by default) of a file as a number.
+//! Numbers, `+ - * /`, unary minus and parentheses work as usual; dividing
+//! by zero fails the script, so its gauge keeps the last value.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::sample::Sample;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Number(f64),
+    Negate(Box<Expression>),
+    Binary(Box<Expression>, Operator, Box<Expression>),
+    Value(Selector),
+    Count(Selector),
+    File { path: PathBuf, field: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+/// Samples with this (flattened) name and all of these labels
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+}
+
+/// Syntax error with the 1-based column it was found at
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+impl Expression {
+    pub fn parse(input: &str) -> Result<Expression, ParseError> {
+        let mut parser = Parser {
+            chars: input.chars().collect(),
+            pos: 0,
+        };
+        let expression = parser.expression()?;
+        parser.skip_whitespace();
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("unexpected input after expression"));
+        }
+        Ok(expression)
+    }
+
+    /// Evaluate against the samples gathered this cycle
+    #[cfg_attr(not(feature = "scripts"), allow(dead_code))]
+    pub fn eval(&self, samples: &[Sample]) -> Result<f64, String> {
+        match self {
+            Expression::Number(n) => Ok(*n),
+            Expression::Negate(e) => Ok(-e.eval(samples)?),
+            Expression::Binary(left, op, right) => {
+                let (left, right) = (left.eval(samples)?, right.eval(samples)?);
+                Ok(match op {
+                    Operator::Add => left + right,
+                    Operator::Subtract => left - right,
+                    Operator::Multiply => left * right,
+                    Operator::Divide if right == 0.0 => return Err("division by zero".to_string()),
+                    Operator::Divide => left / right,
+                })
+            }
+            Expression::Value(selector) => {
+                let matching: Vec<f64> = selector.matching(samples).map(|s| s.value).collect();
+                if matching.is_empty() {
+                    return Err(format!("no samples for {}", selector));
+                }
+                Ok(matching.iter().sum())
+            }
+            Expression::Count(selector) => Ok(selector.matching(samples).count() as f64),
+            Expression::File { path, field } => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                let value = text
+                    .split_whitespace()
+                    .nth(*field)
+                    .ok_or_else(|| format!("{}: no field {}", path.display(), field))?;
+                value
+                    .parse()
+                    .map_err(|_| format!("{}: '{}' is not a number", path.display(), value))
+            }
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "scripts"), allow(dead_code))]
+impl Selector {
+    fn matching<'a>(&'a self, samples: &'a [Sample]) -> impl Iterator<Item = &'a Sample> {
+        samples.iter().filter(move |sample| {
+            sample.name == self.name
+                && self
+                    .labels
+                    .iter()
+                    .all(|label| sample.labels.contains(label))
+        })
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if !self.labels.is_empty() {
+            let labels: Vec<String> = self
+                .labels
+                .iter()
+                .map(|(name, value)| format!("{}=\"{}\"", name, value))
+                .collect();
+            write!(f, "{{{}}}", labels.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+enum Argument {
+    String(String),
+    Number(f64),
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> ParseError {
+        ParseError {
+            column: self.pos + 1,
+            message: message.to_string(),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        if self.peek() != Some(expected) {
+            return Err(self.error(&format!("expected '{}'", expected)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// term (('+' | '-') term)*
+    fn expression(&mut self) -> Result<Expression, ParseError> {
+        let mut left = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Some('+') => Operator::Add,
+                Some('-') => Operator::Subtract,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            left = Expression::Binary(Box::new(left), op, Box::new(self.term()?));
+        }
+    }
+
+    /// factor (('*' | '/') factor)*
+    fn term(&mut self) -> Result<Expression, ParseError> {
+        let mut left = self.factor()?;
+        loop {
+            let op = match self.peek() {
+                Some('*') => Operator::Multiply,
+                Some('/') => Operator::Divide,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            left = Expression::Binary(Box::new(left), op, Box::new(self.factor()?));
+        }
+    }
+
+    fn factor(&mut self) -> Result<Expression, ParseError> {
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                Ok(Expression::Negate(Box::new(self.factor()?)))
+            }
+            Some('(') => {
+                self.pos += 1;
+                let inner = self.expression()?;
+                self.expect(')')?;
+                Ok(inner)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => Ok(Expression::Number(self.number()?)),
+            Some(c) if c.is_ascii_alphabetic() => self.call(),
+            Some(_) => Err(self.error("expected a number, function call or '('")),
+            None => Err(self.error("unexpected end of expression")),
+        }
+    }
+
+    fn number(&mut self) -> Result<f64, ParseError> {
+        let start = self.pos;
+        while self
+            .chars
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_digit() || *c == '.')
+        {
+            self.pos += 1;
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        text.parse().map_err(|_| ParseError {
+            column: start + 1,
+            message: format!("invalid number '{}'", text),
+        })
+    }
+
+    fn call(&mut self) -> Result<Expression, ParseError> {
+        let start = self.pos;
+        while self
+            .chars
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_')
+        {
+            self.pos += 1;
+        }
+        let function: String = self.chars[start..self.pos].iter().collect();
+        let arguments = self.arguments()?;
+        let error = |message: String| ParseError {
+            column: start + 1,
+            message,
+        };
+
+        match function.as_str() {
+            "value" | "count" => {
+                let mut strings = Vec::new();
+                for argument in arguments {
+                    match argument {
+                        Argument::String(s) => strings.push(s),
+                        Argument::Number(_) => {
+                            return Err(error(format!("{}() takes string arguments", function)))
+                        }
+                    }
+                }
+                if strings.len() % 2 != 1 {
+                    return Err(error(format!(
+                        "{}() takes a metric name followed by label/value pairs",
+                        function
+                    )));
+                }
+                let name = strings.remove(0);
+                let labels = strings
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+                let selector = Selector { name, labels };
+                Ok(if function == "value" {
+                    Expression::Value(selector)
+                } else {
+                    Expression::Count(selector)
+                })
+            }
+            "file" => match arguments.as_slice() {
+                [Argument::String(path)] => Ok(Expression::File {
+                    path: PathBuf::from(path),
+                    field: 0,
+                }),
+                [Argument::String(path), Argument::Number(field)]
+                    if field.fract() == 0.0 && *field >= 0.0 =>
+                {
+                    Ok(Expression::File {
+                        path: PathBuf::from(path),
+                        field: *field as usize,
+                    })
+                }
+                _ => Err(error(
+                    "file() takes a path and an optional field index".to_string(),
+                )),
+            },
+            _ => Err(error(format!("unknown function '{}'", function))),
+        }
+    }
+
+    /// '(' (argument (',' argument)*)? ')', where arguments are string or
+    /// number literals
+    fn arguments(&mut self) -> Result<Vec<Argument>, ParseError> {
+        self.expect('(')?;
+        let mut arguments = Vec::new();
+        if self.peek() == Some(')') {
+            self.pos += 1;
+            return Ok(arguments);
+        }
+        loop {
+            arguments.push(match self.peek() {
+                Some('"') => Argument::String(self.string()?),
+                Some(c) if c.is_ascii_digit() => Argument::Number(self.number()?),
+                _ => return Err(self.error("expected a string or number argument")),
+            });
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(')') => {
+                    self.pos += 1;
+                    return Ok(arguments);
+                }
+                _ => return Err(self.error("expected ',' or ')'")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+        let mut value = String::new();
+        loop {
+            match self.chars.get(self.pos) {
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(value);
+                }
+                Some('\\') if self.chars.get(self.pos + 1).is_some() => {
+                    value.push(self.chars[self.pos + 1]);
+                    self.pos += 2;
+                }
+                Some(c) => {
+                    value.push(*c);
+                    self.pos += 1;
+                }
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::proto::MetricType;
+
+    fn eval(input: &str) -> Result<f64, String> {
+        Expression::parse(input).unwrap().eval(&[])
+    }
+
+    fn error(input: &str) -> String {
+        Expression::parse(input).unwrap_err().to_string()
+    }
+
+    fn sample(name: &str, labels: &[(&str, &str)], value: f64) -> Sample {
+        Sample {
+            name: name.to_string(),
+            labels: labels
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            value,
+            kind: MetricType::GAUGE,
+            help: String::new(),
+        }
+    }
+
+    #[test]
+    fn operators_follow_precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7.0));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9.0));
+        assert_eq!(eval("10 - 4 - 3"), Ok(3.0));
+        assert_eq!(eval("12 / 3 / 2"), Ok(2.0));
+        assert_eq!(eval("2 * 3 - 8 / 4"), Ok(4.0));
+        assert_eq!(eval(" 0.5*( 1.5+.5 ) "), Ok(1.0));
+    }
+
+    #[test]
+    fn unary_minus_binds_tighter_than_operators() {
+        assert_eq!(eval("-2 * 3"), Ok(-6.0));
+        assert_eq!(eval("4 - -2"), Ok(6.0));
+        assert_eq!(eval("--3"), Ok(3.0));
+        assert_eq!(eval("-(1 + 2) * 2"), Ok(-6.0));
+    }
+
+    #[test]
+    fn division_by_zero_fails() {
+        assert_eq!(eval("1 / 0"), Err("division by zero".to_string()));
+        assert_eq!(eval("0 / (2 - 2)"), Err("division by zero".to_string()));
+        assert_eq!(eval("0 / 2"), Ok(0.0));
+    }
+
+    #[test]
+    fn strings_unescape_quotes_and_backslashes() {
+        let expression = Expression::parse(r#"value("a\"b", "path", "C:\\x", "n", "\q")"#).unwrap();
+        assert_eq!(
+            expression,
+            Expression::Value(Selector {
+                name: "a\"b".to_string(),
+                labels: vec![
+                    ("path".to_string(), "C:\\x".to_string()),
+                    ("n".to_string(), "q".to_string()),
+                ],
+            })
+        );
+        assert_eq!(error(r#"value("open)"#), "column 13: unterminated string");
+    }
+
+    #[test]
+    fn reports_errors_at_their_column() {
+        assert_eq!(
+            error(r#"1 + value("a", "b")"#),
+            "column 5: value() takes a metric name followed by label/value pairs"
+        );
+        assert_eq!(
+            error("count()"),
+            "column 1: count() takes a metric name followed by label/value pairs"
+        );
+        assert_eq!(
+            error(r#"value("a", 1)"#),
+            "column 1: value() takes string arguments"
+        );
+        assert_eq!(
+            error(r#"2 * file("/a", 1, 2)"#),
+            "column 5: file() takes a path and an optional field index"
+        );
+        assert_eq!(
+            error(r#"file("/a", 1.5)"#),
+            "column 1: file() takes a path and an optional field index"
+        );
+        assert_eq!(error("max(1)"), "column 1: unknown function 'max'");
+        assert_eq!(error("1 +"), "column 4: unexpected end of expression");
+        assert_eq!(error("(1 + 2"), "column 7: expected ')'");
+        assert_eq!(error("1 2"), "column 3: unexpected input after expression");
+        assert_eq!(error("1.2.3"), "column 1: invalid number '1.2.3'");
+        assert_eq!(
+            error("* 2"),
+            "column 1: expected a number, function call or '('"
+        );
+        assert_eq!(
+            error("value(x)"),
+            "column 7: expected a string or number argument"
+        );
+        assert_eq!(error(r#"value("a" "b")"#), "column 11: expected ',' or ')'");
+    }
+
+    #[test]
+    fn value_and_count_match_labels() {
+        let samples = [
+            sample(
+                "net_bytes_total",
+                &[("interface", "eth0"), ("dir", "rx")],
+                10.0,
+            ),
+            sample(
+                "net_bytes_total",
+                &[("interface", "eth0"), ("dir", "tx")],
+                5.0,
+            ),
+            sample(
+                "net_bytes_total",
+                &[("interface", "lo"), ("dir", "rx")],
+                1.0,
+            ),
+            sample("net_errors_total", &[("interface", "eth0")], 2.0),
+        ];
+        let eval = |input: &str| Expression::parse(input).unwrap().eval(&samples);
+
+        assert_eq!(eval(r#"value("net_bytes_total")"#), Ok(16.0));
+        assert_eq!(
+            eval(r#"value("net_bytes_total", "interface", "eth0")"#),
+            Ok(15.0)
+        );
+        assert_eq!(
+            eval(r#"value("net_bytes_total", "interface", "eth0", "dir", "tx")"#),
+            Ok(5.0)
+        );
+        assert_eq!(eval(r#"count("net_bytes_total", "dir", "rx")"#), Ok(2.0));
+        assert_eq!(
+            eval(r#"count("net_bytes_total", "interface", "wlan0")"#),
+            Ok(0.0)
+        );
+        assert_eq!(
+            eval(r#"value("net_bytes_total", "interface", "wlan0")"#),
+            Err("no samples for net_bytes_total{interface=\"wlan0\"}".to_string())
+        );
+        assert_eq!(
+            eval(r#"value("net_errors_total") / count("net_bytes_total", "interface", "eth0")"#),
+            Ok(1.0)
+        );
+    }
+
+    #[test]
+    fn file_reads_the_selected_field() {
+        let path = std::env::temp_dir().join(format!("metrixd-script-{}", std::process::id()));
+        std::fs::write(&path, "1024\t0  8192\nnan-ish x\n").unwrap();
+        let eval = |input: String| Expression::parse(&input).unwrap().eval(&[]);
+        let display = path.display();
+
+        assert_eq!(eval(format!(r#"file("{}")"#, display)), Ok(1024.0));
+        assert_eq!(eval(format!(r#"file("{}", 2)"#, display)), Ok(8192.0));
+        assert_eq!(
+            eval(format!(
+                r#"file("{}", 2) - file("{}", 0)"#,
+                display, display
+            )),
+            Ok(7168.0)
+        );
+        assert_eq!(
+            eval(format!(r#"file("{}", 4)"#, display)),
+            Err(format!("{}: 'x' is not a number", display))
+        );
+        assert_eq!(
+            eval(format!(r#"file("{}", 5)"#, display)),
+            Err(format!("{}: no field 5", display))
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(eval(format!(r#"file("{}")"#, display)).is_err());
+    }
+}
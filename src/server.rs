@@ -0,0 +1,1202 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use hyper::body::HttpBody;
+use hyper::header::{
+    HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+    LAST_MODIFIED, RETRY_AFTER, USER_AGENT, VARY,
+};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use prometheus::{Encoder, ProtobufEncoder, TextEncoder};
+
+use crate::config::{Config, ViewConfig};
+use crate::export::json_string;
+use crate::probe;
+use crate::rate_limit::RateLimiter;
+use crate::state::AppState;
+use crate::utc::Utc;
+
+/// Serve /metrics and the admin endpoints on an already bound listener until
+/// shutdown is requested
+pub async fn serve(listener: TcpListener, state: Arc<AppState>) -> hyper::Result<()> {
+    let make_svc = {
+        let state = Arc::clone(&state);
+        let limiter = Arc::new(RateLimiter::default());
+        make_service_fn(move |conn: &AddrStream| {
+            let state = Arc::clone(&state);
+            let buffer = Arc::new(EncodeBuffer::new());
+            let limiter = Arc::clone(&limiter);
+            let client = conn.remote_addr().ip();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req| {
+                    handle(
+                        req,
+                        client,
+                        Arc::clone(&state),
+                        Arc::clone(&buffer),
+                        Arc::clone(&limiter),
+                    )
+                }))
+            }
+        })
+    };
+
+    if let Ok(addr) = listener.local_addr() {
+        println!("Serving metrics on http://{}", addr);
+    }
+
+    // Many replicas scraping every few seconds can share one connection each
+    let web = state.config().web;
+    Server::from_tcp(listener)?
+        .http1_only(!web.http2)
+        .http1_keepalive(web.keep_alive)
+        .http2_keep_alive_interval(web.http2_keep_alive_interval)
+        .http2_max_concurrent_streams(web.http2_max_concurrent_streams)
+        .serve(make_svc)
+        .with_graceful_shutdown(state.shutdown_signal())
+        .await
+}
+
+/// Handle the request unless the client is over `web.rate_limit`, then
+/// print an access log line with `web.access_log`
+async fn handle(
+    req: Request<Body>,
+    client: IpAddr,
+    state: Arc<AppState>,
+    buffer: Arc<EncodeBuffer>,
+    limiter: Arc<RateLimiter>,
+) -> Result<Response<Body>, hyper::Error> {
+    let config = state.config();
+    let entry = config
+        .web
+        .access_log
+        .then(|| AccessEntry::new(&req, client, &config));
+
+    let limited = config.web.rate_limit.and_then(|rate| {
+        let burst = config.web.rate_limit_burst.unwrap_or(rate);
+        limiter.acquire(client, rate, burst, Instant::now()).err()
+    });
+    let response = match limited {
+        Some(wait) => {
+            state.count_rate_limited();
+            let mut response = text_response(StatusCode::TOO_MANY_REQUESTS, "Too Many Requests");
+            // Whole seconds, rounded up so a retry is never early
+            let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+            response
+        }
+        None => route(req, state, buffer).await?,
+    };
+
+    if let Some(entry) = entry {
+        println!("{}", entry.line(&response, entry.started.elapsed()));
+    }
+    Ok(response)
+}
+
+/// Who asked for what, taken from a request before it is handled
+struct AccessEntry {
+    client: IpAddr,
+    /// `admin` when the request carried the admin token, the view's name
+    /// when it carried the token of the view it asked for, `metrics` when
+    /// it carried the metrics token, `-` otherwise
+    principal: String,
+    request: String,
+    user_agent: String,
+    started: Instant,
+}
+
+impl AccessEntry {
+    fn new(req: &Request<Body>, client: IpAddr, config: &Config) -> Self {
+        let authenticated = |token: Option<&str>| token.is_some() && is_authorized(req, token);
+        let view = req.uri().path().strip_prefix("/metrics/");
+        let principal = match view.map(|name| (name, config.views.get(name))) {
+            _ if authenticated(config.web.admin_token.as_deref()) => "admin",
+            Some((name, Some(view))) if authenticated(view.token.as_deref()) => name,
+            _ if authenticated(config.web.metrics_token.as_deref()) => "metrics",
+            _ => "-",
+        };
+        AccessEntry {
+            client,
+            principal: principal.to_string(),
+            request: format!("{} {} {:?}", req.method(), req.uri(), req.version()),
+            user_agent: req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("-")
+                .to_string(),
+            started: Instant::now(),
+        }
+    }
+
+    /// `10.0.0.5 - "GET /metrics HTTP/1.1" 200 38208 0.004s "Prometheus/2.48.0"`,
+    /// with `-` for the size of streamed bodies
+    fn line(&self, response: &Response<Body>, elapsed: Duration) -> String {
+        let size = response.body().size_hint().exact();
+        format!(
+            "{} {} \"{}\" {} {} {:.3}s \"{}\"",
+            self.client,
+            self.principal,
+            self.request,
+            response.status().as_u16(),
+            size.map_or("-".to_string(), |size| size.to_string()),
+            elapsed.as_secs_f64(),
+            self.user_agent
+        )
+    }
+}
+
+async fn route(
+    req: Request<Body>,
+    state: Arc<AppState>,
+    buffer: Arc<EncodeBuffer>,
+) -> Result<Response<Body>, hyper::Error> {
+    match req.uri().path() {
+        "/-/reload" | "/-/quit" => Ok(lifecycle_handler(req, &state)),
+        path if path == "/admin/collectors" || path.starts_with("/admin/collectors/") => {
+            collectors_handler(req, &state).await
+        }
+        // Unlike a view, these serve every series
+        "/probe" | "/status" | "/metrics-docs" if !metrics_authorized(&req, &state) => {
+            Ok(unauthorized_response())
+        }
+        "/probe" => probe_handler(req, &state, &buffer).await,
+        "/status" => Ok(status_handler(req, &state)),
+        "/metrics-docs" => Ok(metrics_docs_handler(req, &state)),
+        _ => metrics_handler(req, &state, &buffer).await,
+    }
+}
+
+/// Prometheus-style lifecycle endpoints. Disabled unless
+/// `web.enable_lifecycle` is set, and guarded by `web.admin_token` if present.
+fn lifecycle_handler(req: Request<Body>, state: &AppState) -> Response<Body> {
+    let config = state.config();
+
+    if !config.web.enable_lifecycle {
+        return text_response(StatusCode::FORBIDDEN, "Lifecycle API is not enabled.");
+    }
+    if !is_authorized(&req, config.web.admin_token.as_deref()) {
+        return unauthorized_response();
+    }
+    if req.method() != Method::POST && req.method() != Method::PUT {
+        return text_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Only POST or PUT requests allowed",
+        );
+    }
+
+    match req.uri().path() {
+        "/-/reload" => match state.reload() {
+            Ok(()) => text_response(StatusCode::OK, ""),
+            Err(e) => {
+                eprintln!("Failed to reload configuration: {}", e);
+                text_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("failed to reload config: {}", e),
+                )
+            }
+        },
+        _ => {
+            println!("Received termination request via /-/quit");
+            state.shutdown();
+            text_response(StatusCode::OK, "Requesting termination... Goodbye!")
+        }
+    }
+}
+
+/// `GET /admin/collectors` lists collectors and their state,
+/// `PUT /admin/collectors/{name}` with a body of `true` or `false` toggles one.
+/// Disabled collectors stop being refreshed and their series are hidden.
+async fn collectors_handler(
+    req: Request<Body>,
+    state: &AppState,
+) -> Result<Response<Body>, hyper::Error> {
+    let config = state.config();
+
+    if !config.web.enable_admin_api {
+        return Ok(text_response(
+            StatusCode::FORBIDDEN,
+            "Admin API is not enabled.",
+        ));
+    }
+    if !is_authorized(&req, config.web.admin_token.as_deref()) {
+        return Ok(unauthorized_response());
+    }
+
+    let name = req
+        .uri()
+        .path()
+        .trim_start_matches("/admin/collectors")
+        .trim_start_matches('/')
+        .to_string();
+
+    if name.is_empty() {
+        if req.method() != Method::GET {
+            return Ok(text_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Only GET requests allowed",
+            ));
+        }
+        let entries: Vec<String> = state
+            .collector_states()
+            .iter()
+            .map(|(name, enabled)| collector_json(name, *enabled))
+            .collect();
+        return Ok(json_response(
+            StatusCode::OK,
+            format!("[{}]", entries.join(",")),
+        ));
+    }
+
+    if req.method() != Method::PUT {
+        return Ok(text_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Only PUT requests allowed",
+        ));
+    }
+
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let enabled = match std::str::from_utf8(&body).map(str::trim) {
+        Ok("true") => true,
+        Ok("false") => false,
+        _ => {
+            return Ok(text_response(
+                StatusCode::BAD_REQUEST,
+                "Request body must be true or false",
+            ))
+        }
+    };
+
+    if !state.set_collector_enabled(&name, enabled) {
+        return Ok(text_response(
+            StatusCode::NOT_FOUND,
+            &format!("Unknown collector: {}", name),
+        ));
+    }
+    Ok(json_response(
+        StatusCode::OK,
+        collector_json(&name, enabled),
+    ))
+}
+
+/// `GET /status`: what a fleet tool needs to tell agents apart and spot a
+/// broken one, as JSON. `last_error` is kept after the collector recovers;
+/// `failures` counts the consecutive ones so far.
+fn status_handler(req: Request<Body>, state: &AppState) -> Response<Body> {
+    if req.method() != Method::GET {
+        return text_response(StatusCode::METHOD_NOT_ALLOWED, "Only GET requests allowed");
+    }
+
+    let collectors: Vec<String> = state
+        .collector_statuses()
+        .iter()
+        .map(|c| {
+            format!(
+                "{{\"collector\":\"{}\",\"enabled\":{},\"shed\":{},\"failures\":{},\
+                 \"last_duration_seconds\":{},\"last_error\":{}}}",
+                c.name,
+                c.enabled,
+                c.shed,
+                c.failures,
+                c.last_duration
+                    .map_or("null".to_string(), |d| d.as_secs_f64().to_string()),
+                c.last_error
+                    .as_deref()
+                    .map_or("null".to_string(), json_string)
+            )
+        })
+        .collect();
+    json_response(
+        StatusCode::OK,
+        format!(
+            "{{\"version\":\"{}\",\"uptime_seconds\":{},\"config_hash\":{},\"collectors\":[{}]}}",
+            env!("CARGO_PKG_VERSION"),
+            state.uptime().as_secs(),
+            state
+                .config_hash()
+                .map_or("null".to_string(), |hash| format!("\"{}\"", hash)),
+            collectors.join(",")
+        ),
+    )
+}
+
+/// `GET /metrics-docs`: every metric family the enabled collectors
+/// export, with its type, help and label keys, as JSON
+fn metrics_docs_handler(req: Request<Body>, state: &AppState) -> Response<Body> {
+    if req.method() != Method::GET {
+        return text_response(StatusCode::METHOD_NOT_ALLOWED, "Only GET requests allowed");
+    }
+
+    let collectors: Vec<String> = state
+        .metric_docs()
+        .iter()
+        .map(|(name, docs)| {
+            let metrics: Vec<String> = docs
+                .iter()
+                .map(|doc| {
+                    let labels: Vec<String> = doc.labels.iter().map(|l| json_string(l)).collect();
+                    format!(
+                        "{{\"name\":{},\"type\":\"{}\",\"help\":{},\"labels\":[{}]}}",
+                        json_string(&doc.name),
+                        type_name(doc.metric_type.unwrap_or(MetricType::UNTYPED)),
+                        json_string(&doc.help),
+                        labels.join(",")
+                    )
+                })
+                .collect();
+            format!(
+                "{{\"collector\":\"{}\",\"metrics\":[{}]}}",
+                name,
+                metrics.join(",")
+            )
+        })
+        .collect();
+    json_response(StatusCode::OK, format!("[{}]", collectors.join(",")))
+}
+
+/// As in the text format's `# TYPE` lines
+fn type_name(metric_type: MetricType) -> &'static str {
+    match metric_type {
+        MetricType::COUNTER => "counter",
+        MetricType::GAUGE => "gauge",
+        MetricType::HISTOGRAM => "histogram",
+        MetricType::SUMMARY => "summary",
+        MetricType::UNTYPED => "untyped",
+    }
+}
+
+fn collector_json(name: &str, enabled: bool) -> String {
+    format!("{{\"collector\":\"{}\",\"enabled\":{}}}", name, enabled)
+}
+
+fn json_response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// Check `web.metrics_token` when one is configured
+pub(crate) fn metrics_authorized(req: &Request<Body>, state: &AppState) -> bool {
+    is_authorized(req, state.config().web.metrics_token.as_deref())
+}
+
+/// Check the bearer token when one is configured
+fn is_authorized(req: &Request<Body>, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+
+    let expected = format!("Bearer {}", token);
+    req.headers()
+        .get(AUTHORIZATION)
+        .is_some_and(|value| value.as_bytes() == expected.as_bytes())
+}
+
+fn unauthorized_response() -> Response<Body> {
+    let mut response = text_response(StatusCode::UNAUTHORIZED, "Unauthorized");
+    response
+        .headers_mut()
+        .insert("WWW-Authenticate", HeaderValue::from_static("Bearer"));
+    response
+}
+
+fn text_response(status: StatusCode, body: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Gather the registry, dropping families excluded by `metrics.exclude` and
+/// those of disabled collectors, and add the configured `labels` and any
+/// cloud metadata labels
+pub fn gather_filtered(state: &AppState) -> Vec<MetricFamily> {
+    let config = state.config();
+    let hidden = state.hidden_metric_names();
+    let mut families = state.registry().gather();
+    families.retain(|family| {
+        !hidden.contains(family.name())
+            && !config
+                .metrics
+                .exclude
+                .iter()
+                .any(|re| re.is_match(family.name()))
+    });
+    let extra_labels = state.labels();
+    if !extra_labels.is_empty() {
+        for metric in families.iter_mut().flat_map(|family| family.mut_metric()) {
+            let mut labels = metric.take_label();
+            for (name, value) in &extra_labels {
+                if !labels.iter().any(|label| label.name() == name) {
+                    let mut label = LabelPair::default();
+                    label.set_name(name.clone());
+                    label.set_value(value.clone());
+                    labels.push(label);
+                }
+            }
+            labels.sort_by(|a, b| a.name().cmp(b.name()));
+            metric.set_label(labels);
+        }
+    }
+    families
+}
+
+/// Series a family adds to the exposition: one per sample, and for
+/// histograms and summaries one per bucket or quantile plus `_sum` and
+/// `_count`
+pub fn series_count(family: &MetricFamily) -> usize {
+    family
+        .get_metric()
+        .iter()
+        .map(|metric| match family.get_field_type() {
+            MetricType::HISTOGRAM => {
+                let buckets = metric.get_histogram().get_bucket();
+                // The text format adds the +Inf bucket when it's missing
+                let infinite = buckets
+                    .last()
+                    .is_some_and(|bucket| bucket.upper_bound() == f64::INFINITY);
+                buckets.len() + if infinite { 2 } else { 3 }
+            }
+            MetricType::SUMMARY => metric.get_summary().get_quantile().len() + 2,
+            _ => 1,
+        })
+        .sum()
+}
+
+/// Keep what `/metrics/<name>` selects: the families of the view's
+/// collectors and those matching `include`, minus those matching
+/// `exclude`. A collector that isn't running adds nothing. Then set the
+/// view's labels, keeping a series' own value of one as
+/// `exported_<name>`, so a tenant can't be mistaken for another.
+fn apply_view(families: &mut Vec<MetricFamily>, view: &ViewConfig, state: &AppState) {
+    let selected: HashSet<String> = view
+        .collectors
+        .iter()
+        .filter_map(|name| state.collector_metric_names(name))
+        .flatten()
+        .collect();
+    let everything = view.collectors.is_empty() && view.include.is_empty();
+    families.retain(|family| {
+        let name = family.name();
+        (everything || selected.contains(name) || view.include.iter().any(|re| re.is_match(name)))
+            && !view.exclude.iter().any(|re| re.is_match(name))
+    });
+    if view.labels.is_empty() {
+        return;
+    }
+    for metric in families.iter_mut().flat_map(|family| family.mut_metric()) {
+        let mut labels = metric.take_label();
+        for label in labels
+            .iter_mut()
+            .filter(|label| view.labels.contains_key(label.name()))
+        {
+            label.set_name(format!("exported_{}", label.name()));
+        }
+        for (name, value) in &view.labels {
+            let mut label = LabelPair::default();
+            label.set_name(name.clone());
+            label.set_value(value.clone());
+            labels.push(label);
+        }
+        labels.sort_by(|a, b| a.name().cmp(b.name()));
+        metric.set_label(labels);
+    }
+}
+
+/// Apply the `collect[]` and `exclude[]` query parameters, which like in
+/// node_exporter keep only, or drop, the families of the named collectors.
+/// Returns whether either was given.
+fn select_collectors(
+    families: &mut Vec<MetricFamily>,
+    state: &AppState,
+    query: &str,
+) -> Result<bool, String> {
+    let params = query_params(query);
+    let names = |key: &str| -> Vec<&str> {
+        params
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    };
+    let (collect, exclude) = (names("collect[]"), names("exclude[]"));
+    if !collect.is_empty() && !exclude.is_empty() {
+        return Err("collect[] and exclude[] cannot be combined".to_string());
+    }
+
+    let mut selected = HashSet::new();
+    for name in collect.iter().chain(&exclude) {
+        let metric_names = state
+            .collector_metric_names(name)
+            .ok_or_else(|| format!("Unknown collector: {}", name))?;
+        selected.extend(metric_names);
+    }
+    if !collect.is_empty() {
+        families.retain(|family| selected.contains(family.name()));
+    } else if !exclude.is_empty() {
+        families.retain(|family| !selected.contains(family.name()));
+    }
+    Ok(!collect.is_empty() || !exclude.is_empty())
+}
+
+/// Decoded `key=value` pairs of a query string, in order
+fn query_params(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// The encode buffer of one connection. Each response takes the encoded
+/// bytes without copying; once hyper has written and dropped them,
+/// `reserve` reclaims the same allocation for the next scrape.
+struct EncodeBuffer {
+    buffer: Mutex<BytesMut>,
+    /// Responses estimated larger than this are streamed instead
+    stream_threshold: usize,
+}
+
+impl EncodeBuffer {
+    fn new() -> Self {
+        EncodeBuffer {
+            buffer: Mutex::new(BytesMut::new()),
+            stream_threshold: STREAM_THRESHOLD,
+        }
+    }
+
+    /// Encode into the buffer, allocating `size_hint` bytes up front so it
+    /// doesn't grow step by step
+    fn encode(
+        &self,
+        encoder: &impl Encoder,
+        families: &[MetricFamily],
+        size_hint: usize,
+    ) -> prometheus::Result<Bytes> {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.reserve(size_hint);
+        let result = encoder.encode(families, &mut (&mut *buffer).writer());
+        let encoded = buffer.split().freeze();
+        result?;
+        Ok(encoded)
+    }
+}
+
+/// Roughly the size of the text exposition of `families`, without encoding
+/// them: one line per series, bucket and quantile. The protobuf format
+/// comes out somewhat smaller.
+fn estimated_size(families: &[MetricFamily]) -> usize {
+    families
+        .iter()
+        .map(|family| {
+            let name = family.name().len();
+            // The HELP and TYPE lines
+            let header = 2 * name + family.help().len() + 24;
+            let series: usize = family
+                .metric
+                .iter()
+                .map(|metric| {
+                    let labels: usize = metric
+                        .label
+                        .iter()
+                        .map(|label| label.name().len() + label.value().len() + 4)
+                        .sum();
+                    let lines = match family.type_() {
+                        MetricType::HISTOGRAM => metric.histogram.bucket.len() + 3,
+                        MetricType::SUMMARY => metric.summary.quantile.len() + 2,
+                        _ => 1,
+                    };
+                    // Name, labels, braces, suffix or le/quantile, value
+                    lines * (name + labels + 16)
+                })
+                .sum();
+            header + series
+        })
+        .sum()
+}
+
+/// Exposition formats /metrics can answer with
+#[derive(Debug, PartialEq)]
+enum Format {
+    Text,
+    /// Length-delimited `io.prometheus.client.MetricFamily` messages
+    Protobuf,
+}
+
+/// One entry of an `Accept` header, e.g. `text/plain;version=0.0.4;q=0.3`
+struct MediaRange<'a> {
+    media_type: &'a str,
+    params: Vec<(&'a str, &'a str)>,
+    q: f64,
+}
+
+impl<'a> MediaRange<'a> {
+    fn parse(range: &'a str) -> Option<Self> {
+        let mut parts = range.split(';').map(str::trim);
+        let media_type = parts.next().filter(|media_type| !media_type.is_empty())?;
+        let mut media_range = MediaRange {
+            media_type,
+            params: Vec::new(),
+            q: 1.0,
+        };
+        for param in parts {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            let (name, value) = (name.trim(), value.trim().trim_matches('"'));
+            if name.eq_ignore_ascii_case("q") {
+                media_range.q = value.parse().unwrap_or(0.0);
+            } else {
+                media_range.params.push((name, value));
+            }
+        }
+        Some(media_range)
+    }
+
+    fn is(&self, media_type: &str) -> bool {
+        self.media_type.eq_ignore_ascii_case(media_type)
+    }
+
+    fn param(&self, name: &str) -> Option<&'a str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    }
+}
+
+/// Pick the format for an `Accept` header the way Prometheus client
+/// libraries do: the most preferred (highest q) range the server can
+/// produce wins, and anything unrecognised gets the text format
+fn negotiate(accept: Option<&str>) -> Format {
+    let mut ranges: Vec<MediaRange> = accept
+        .unwrap_or("")
+        .split(',')
+        .filter_map(MediaRange::parse)
+        .filter(|range| range.q > 0.0)
+        .collect();
+    // Stable, so equally preferred ranges keep the client's order
+    ranges.sort_by(|a, b| b.q.total_cmp(&a.q));
+
+    for range in ranges {
+        if range.is("application/vnd.google.protobuf")
+            && range.param("proto") == Some("io.prometheus.client.MetricFamily")
+            && range.param("encoding") == Some("delimited")
+        {
+            return Format::Protobuf;
+        }
+        if range.is("text/plain") || range.is("text/*") || range.is("*/*") {
+            return Format::Text;
+        }
+    }
+    Format::Text
+}
+
+async fn metrics_handler(
+    req: Request<Body>,
+    state: &Arc<AppState>,
+    buffer: &EncodeBuffer,
+) -> Result<Response<Body>, hyper::Error> {
+    let view = match req.uri().path().strip_prefix("/metrics/") {
+        Some(name) => match state.config().views.remove(name) {
+            Some(view) => Some(view),
+            None => {
+                return Ok(text_response(
+                    StatusCode::NOT_FOUND,
+                    &format!("Unknown view: {}", name),
+                ))
+            }
+        },
+        None => None,
+    };
+    // Each tenant's view only opens with its own token, everything else,
+    // views without one included, with the metrics token
+    let authorized = match view.as_ref().and_then(|view| view.token.as_deref()) {
+        Some(token) => is_authorized(&req, Some(token)),
+        None => metrics_authorized(&req, state),
+    };
+    if !authorized {
+        return Ok(unauthorized_response());
+    }
+
+    // Read before gathering, so a cycle finishing meanwhile is served again
+    let (changes, changed_at) = state.last_change();
+    let format = match negotiate(accept(&req)) {
+        Format::Text => "text",
+        Format::Protobuf => "protobuf",
+    };
+    let etag = format!("\"{}-{}\"", changes, format);
+    let last_modified = Utc::from_system_time(changed_at).http_date();
+    if not_modified(&req, &etag, &last_modified) {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(ETAG, etag)
+            .header(LAST_MODIFIED, last_modified)
+            .header(VARY, "Accept")
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    let mut metric_families = gather_filtered(state);
+    if let Some(view) = &view {
+        apply_view(&mut metric_families, view, state);
+    }
+    let selected =
+        match select_collectors(&mut metric_families, state, req.uri().query().unwrap_or("")) {
+            Ok(selected) => selected,
+            Err(e) => return Ok(text_response(StatusCode::BAD_REQUEST, &e)),
+        };
+
+    // Only the full /metrics is recorded, not whichever subset came last
+    let full = view.is_none() && !selected;
+    let recorder = Arc::clone(state);
+    let mut response = encode_response(&req, metric_families, buffer, move |size| {
+        if full {
+            recorder.set_exposition_size(size)
+        }
+    });
+    if response.status() == StatusCode::OK {
+        let headers = response.headers_mut();
+        headers.insert(ETAG, HeaderValue::from_str(&etag).unwrap());
+        headers.insert(
+            LAST_MODIFIED,
+            HeaderValue::from_str(&last_modified).unwrap(),
+        );
+    }
+    Ok(response)
+}
+
+/// Whether the scraper's copy is still current. `If-None-Match` takes
+/// precedence; `If-Modified-Since` only matches the exact `Last-Modified`
+/// sent before, which is what clients send back.
+fn not_modified(req: &Request<Body>, etag: &str, last_modified: &str) -> bool {
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+    };
+    if let Some(tags) = header(IF_NONE_MATCH) {
+        return tags
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+    header(IF_MODIFIED_SINCE) == Some(last_modified)
+}
+
+/// `GET /probe?target=<target>&module=<name>` probes the target while the
+/// scraper waits, with the `http_2xx` module unless another one is named.
+/// Disabled unless `probe.enabled` is set.
+async fn probe_handler(
+    req: Request<Body>,
+    state: &AppState,
+    buffer: &EncodeBuffer,
+) -> Result<Response<Body>, hyper::Error> {
+    let config = state.config();
+    if !config.probe.enabled {
+        return Ok(text_response(
+            StatusCode::FORBIDDEN,
+            "Probe endpoint is not enabled.",
+        ));
+    }
+
+    let params = query_params(req.uri().query().unwrap_or(""));
+    let param = |key: &str| {
+        params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+    };
+    let Some(target) = param("target").filter(|target| !target.is_empty()) else {
+        return Ok(text_response(
+            StatusCode::BAD_REQUEST,
+            "Missing target parameter",
+        ));
+    };
+    let name = param("module").unwrap_or_else(|| "http_2xx".to_string());
+    let Some(module) = config.probe.modules.get(&name).cloned() else {
+        return Ok(text_response(
+            StatusCode::BAD_REQUEST,
+            &format!("Unknown module: {}", name),
+        ));
+    };
+
+    // Answer before Prometheus gives up on the scrape
+    let timeout =
+        scrape_timeout(&req).map_or(module.timeout, |timeout| timeout.min(module.timeout));
+    let probed = tokio::task::spawn_blocking(move || probe::probe(&module, &target, timeout)).await;
+    match probed {
+        Ok(families) => Ok(encode_response(&req, families, buffer, |_| {})),
+        Err(e) => {
+            eprintln!("Probe failed: {}", e);
+            Ok(text_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// What is left of Prometheus' scrape timeout after sending the response
+fn scrape_timeout(req: &Request<Body>) -> Option<Duration> {
+    let seconds: f64 = req
+        .headers()
+        .get("X-Prometheus-Scrape-Timeout-Seconds")?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+    Duration::try_from_secs_f64(seconds - 0.5)
+        .ok()
+        .filter(|timeout| !timeout.is_zero())
+}
+
+fn accept(req: &Request<Body>) -> Option<&str> {
+    req.headers()
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Expositions estimated larger than this are sent in chunks as they are
+/// encoded, so a scrape holds one chunk and one family at a time instead of
+/// the whole body
+const STREAM_THRESHOLD: usize = 4 * 1024 * 1024;
+
+/// Encoded families are sent once this much has built up
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Encode families in the format the request's `Accept` header asks for,
+/// passing the size of the body to `record` once it is complete
+fn encode_response(
+    req: &Request<Body>,
+    families: Vec<MetricFamily>,
+    buffer: &EncodeBuffer,
+    record: impl FnOnce(usize) + Send + 'static,
+) -> Response<Body> {
+    let encoded = match negotiate(accept(req)) {
+        Format::Text => {
+            let encoder = TextEncoder::new();
+            let content_type = encoder.format_type().to_string();
+            encode_body(encoder, families, buffer, record).map(|body| (body, content_type))
+        }
+        Format::Protobuf => {
+            let encoder = ProtobufEncoder::new();
+            let content_type = encoder.format_type().to_string();
+            encode_body(encoder, families, buffer, record).map(|body| (body, content_type))
+        }
+    };
+    let (body, content_type) = match encoded {
+        Ok(encoded) => encoded,
+        Err(e) => {
+            eprintln!("Failed to encode metrics: {}", e);
+            return Response::builder()
+                .status(500)
+                .body(Body::from("Internal Server Error"))
+                .unwrap();
+        }
+    };
+
+    Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .header(VARY, "Accept")
+        .body(body)
+        .unwrap()
+}
+
+/// The body from the connection's buffer, or streamed in chunks when the
+/// exposition is estimated above the buffer's `stream_threshold`. Deciding
+/// per response keeps small ones, e.g. probes and views, from making the
+/// next large one buffered.
+fn encode_body<E: Encoder + Send + 'static>(
+    encoder: E,
+    families: Vec<MetricFamily>,
+    buffer: &EncodeBuffer,
+    record: impl FnOnce(usize) + Send + 'static,
+) -> prometheus::Result<Body> {
+    let estimate = estimated_size(&families);
+    if estimate <= buffer.stream_threshold {
+        let encoded = buffer.encode(&encoder, &families, estimate)?;
+        record(encoded.len());
+        return Ok(Body::from(encoded));
+    }
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut chunk = BytesMut::new();
+        let mut size = 0;
+        for (i, family) in families.iter().enumerate() {
+            let encoded = encoder.encode(std::slice::from_ref(family), &mut (&mut chunk).writer());
+            if let Err(e) = encoded {
+                eprintln!("Failed to encode metrics: {}", e);
+                // Cut the response off rather than end it as if complete
+                sender.abort();
+                return;
+            }
+            if chunk.len() >= CHUNK_SIZE || i + 1 == families.len() {
+                size += chunk.len();
+                // Waits until hyper has sent the previous chunk, and fails
+                // once the scraper has gone
+                if sender.send_data(chunk.split().freeze()).await.is_err() {
+                    return;
+                }
+            }
+        }
+        record(size);
+    });
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{HistogramOpts, HistogramVec, IntGauge, IntGaugeVec, Opts, Registry};
+
+    #[test]
+    fn encode_buffer_reuses_its_allocation() {
+        let registry = Registry::new();
+        let gauge = IntGauge::new("queue_depth", "Items waiting").unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        let buffer = EncodeBuffer::new();
+
+        let first = buffer
+            .encode(&TextEncoder::new(), &registry.gather(), 0)
+            .unwrap();
+        let address = first.as_ptr();
+        drop(first);
+        gauge.set(7);
+        let second = buffer
+            .encode(&TextEncoder::new(), &registry.gather(), 0)
+            .unwrap();
+        assert_eq!(second.as_ptr(), address);
+        assert!(String::from_utf8_lossy(&second).contains("queue_depth 7"));
+    }
+
+    /// 20,000 series of a labelled gauge, about 900 KB as text
+    fn large_registry() -> Registry {
+        let registry = Registry::new();
+        let gauge = IntGaugeVec::new(Opts::new("file_size_bytes", "Size"), &["path"]).unwrap();
+        let other = IntGauge::new("files", "Count").unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        registry.register(Box::new(other.clone())).unwrap();
+        for i in 0..20_000 {
+            gauge
+                .with_label_values(&[&format!("/srv/data/{}", i)])
+                .set(i);
+        }
+        other.set(20_000);
+        registry
+    }
+
+    /// The body and how many chunks it came in
+    async fn read_body(mut body: Body) -> (String, usize) {
+        let mut read = Vec::new();
+        let mut chunks = 0;
+        while let Some(chunk) = body.data().await {
+            read.extend_from_slice(&chunk.unwrap());
+            chunks += 1;
+        }
+        (String::from_utf8(read).unwrap(), chunks)
+    }
+
+    #[test]
+    fn estimates_the_exposition_size() {
+        let registry = large_registry();
+        let histogram = HistogramVec::new(
+            HistogramOpts::new("request_duration_seconds", "Request latency"),
+            &["handler"],
+        )
+        .unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        for handler in ["/metrics", "/probe", "/status"] {
+            histogram.with_label_values(&[handler]).observe(0.1);
+        }
+        let families = registry.gather();
+        let mut encoded = Vec::new();
+        TextEncoder::new().encode(&families, &mut encoded).unwrap();
+
+        let estimate = estimated_size(&families) as f64;
+        let actual = encoded.len() as f64;
+        assert!(
+            (0.8..1.25).contains(&(estimate / actual)),
+            "{} vs {}",
+            estimate,
+            actual
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_large_expositions_in_chunks() {
+        let families = large_registry().gather();
+        let mut expected = Vec::new();
+        TextEncoder::new().encode(&families, &mut expected).unwrap();
+
+        let mut buffer = EncodeBuffer::new();
+        buffer.stream_threshold = 64 * 1024;
+        let (recorded, record) = std::sync::mpsc::channel();
+        let body = encode_body(TextEncoder::new(), families, &buffer, move |size| {
+            recorded.send(size).unwrap()
+        })
+        .unwrap();
+        assert_eq!(body.size_hint().exact(), None);
+
+        let (streamed, chunks) = read_body(body).await;
+        assert!(chunks > 1);
+        assert_eq!(streamed, String::from_utf8(expected).unwrap());
+        assert_eq!(record.recv().unwrap(), streamed.len());
+    }
+
+    #[tokio::test]
+    async fn small_responses_leave_the_next_large_one_streamed() {
+        let large = large_registry();
+        let small = Registry::new();
+        let gauge = IntGauge::new("probe_success", "Whether the probe succeeded").unwrap();
+        small.register(Box::new(gauge)).unwrap();
+
+        let mut buffer = EncodeBuffer::new();
+        buffer.stream_threshold = 64 * 1024;
+        for (registry, streamed) in [(&large, true), (&small, false), (&large, true)] {
+            let body = encode_body(TextEncoder::new(), registry.gather(), &buffer, |_| {}).unwrap();
+            assert_eq!(body.size_hint().exact().is_none(), streamed);
+            let (_, chunks) = read_body(body).await;
+            assert_eq!(chunks > 1, streamed);
+        }
+    }
+
+    #[test]
+    fn counts_the_series_of_each_family() {
+        let registry = Registry::new();
+        let gauge = IntGaugeVec::new(Opts::new("temperature", "Degrees"), &["sensor"]).unwrap();
+        let histogram = HistogramVec::new(
+            HistogramOpts::new("latency_seconds", "Latency").buckets(vec![0.1, 1.0]),
+            &["path"],
+        )
+        .unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        gauge.with_label_values(&["cpu"]).set(40);
+        gauge.with_label_values(&["gpu"]).set(60);
+        histogram.with_label_values(&["/metrics"]).observe(0.2);
+
+        let counts: Vec<_> = registry
+            .gather()
+            .iter()
+            .map(|family| (family.name().to_string(), series_count(family)))
+            .collect();
+        // Two buckets, +Inf, _sum and _count
+        assert_eq!(
+            counts,
+            [
+                ("latency_seconds".to_string(), 5),
+                ("temperature".to_string(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn access_log_lines_name_the_client() {
+        let client = IpAddr::from([10, 0, 0, 5]);
+        let req = Request::builder()
+            .uri("/admin/collectors")
+            .header(AUTHORIZATION, "Bearer changeme")
+            .header(USER_AGENT, "curl/8.5.0")
+            .body(Body::empty())
+            .unwrap();
+        let response = text_response(StatusCode::OK, "[]");
+        let elapsed = Duration::from_millis(4);
+
+        let config = Config::parse(
+            "[web]\nadmin_token = \"changeme\"\nmetrics_token = \"m-secret\"\n\
+             [views.team-a]\ntoken = \"a-secret\"\n",
+        )
+        .unwrap();
+        let entry = AccessEntry::new(&req, client, &config);
+        assert_eq!(
+            entry.line(&response, elapsed),
+            "10.0.0.5 admin \"GET /admin/collectors HTTP/1.1\" 200 2 0.004s \"curl/8.5.0\""
+        );
+        let entry = AccessEntry::new(&req, client, &Config::default());
+        assert!(entry.line(&response, elapsed).starts_with("10.0.0.5 - "));
+
+        // A tenant is named after the view its token opens
+        let req = Request::builder()
+            .uri("/metrics/team-a")
+            .header(AUTHORIZATION, "Bearer a-secret")
+            .body(Body::empty())
+            .unwrap();
+        let entry = AccessEntry::new(&req, client, &config);
+        assert!(entry
+            .line(&response, elapsed)
+            .starts_with("10.0.0.5 team-a "));
+
+        let req = Request::builder()
+            .uri("/metrics")
+            .header(AUTHORIZATION, "Bearer m-secret")
+            .body(Body::empty())
+            .unwrap();
+        let entry = AccessEntry::new(&req, client, &config);
+        assert!(entry
+            .line(&response, elapsed)
+            .starts_with("10.0.0.5 metrics "));
+    }
+
+    #[test]
+    fn negotiates_protobuf_only_when_preferred() {
+        let protobuf = "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited";
+        assert_eq!(negotiate(Some(protobuf)), Format::Protobuf);
+        // What Prometheus sends with native histograms enabled
+        assert_eq!(
+            negotiate(Some(&format!(
+                "{};q=0.7,text/plain;version=0.0.4;q=0.3,*/*;q=0.1",
+                protobuf
+            ))),
+            Format::Protobuf
+        );
+        assert_eq!(
+            negotiate(Some(&format!("text/plain;q=0.9,{};q=0.5", protobuf))),
+            Format::Text
+        );
+        // Other protobuf messages or encodings aren't ours
+        assert_eq!(
+            negotiate(Some("application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=text")),
+            Format::Text
+        );
+        assert_eq!(negotiate(Some(&format!("{};q=0", protobuf))), Format::Text);
+        assert_eq!(negotiate(None), Format::Text);
+    }
+}
@@ -0,0 +1,625 @@
+use std::collections::{BTreeMap, HashSet};
+use std::future::Future;
+use std::path::Path;
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use prometheus::{Gauge, GaugeVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Registry};
+use tokio::sync::watch;
+
+use crate::cli::Args;
+use crate::collector::{Collector, MetricDoc};
+use crate::config::{Config, ConfigError};
+use crate::identity;
+use crate::memory_limit::{self, MemoryLimit};
+use crate::metrics::builder::metric;
+use crate::metrics::summary::SummaryVec;
+use crate::sigv4;
+
+struct CollectorState {
+    enabled: bool,
+    metrics: Vec<MetricDoc>,
+    /// Collections that failed in a row
+    failures: u32,
+    /// Cycles still to sit out before the next attempt
+    backoff_cycles: u32,
+    /// Disabled by `limits.max_rss_mib`
+    shed: bool,
+    last_duration: Option<Duration>,
+    /// Kept after the collector recovers
+    last_error: Option<String>,
+}
+
+/// A collector as /status shows it
+pub struct CollectorStatus {
+    pub name: &'static str,
+    pub enabled: bool,
+    pub shed: bool,
+    pub failures: u32,
+    pub last_duration: Option<Duration>,
+    pub last_error: Option<String>,
+}
+
+/// State shared between the collection loop and the HTTP/gRPC servers
+pub struct AppState {
+    args: Args,
+    config: RwLock<Config>,
+    shutdown: watch::Sender<bool>,
+    /// Runtime on/off switch per collector, keyed by collector name
+    collectors: RwLock<BTreeMap<&'static str, CollectorState>>,
+    /// Gathered by /metrics and the gRPC API
+    registry: Registry,
+    /// From the instance metadata service, with `cloud.metadata_labels`
+    cloud_labels: OnceLock<BTreeMap<String, String>>,
+    /// The identity when `instance.id` isn't set
+    host_identity: String,
+    /// Empty where neither systemd nor D-Bus left one
+    machine_id: String,
+    identity_info: IntGaugeVec,
+    collector_enabled: IntGaugeVec,
+    collector_available: IntGaugeVec,
+    collector_duration: SummaryVec,
+    collector_errors: IntCounterVec,
+    collector_backoff: GaugeVec,
+    series_count: IntGaugeVec,
+    exposition_size: IntGauge,
+    rate_limited: IntCounter,
+    collection_interval: Gauge,
+    memory_limit: MemoryLimit,
+    resident_memory: IntGauge,
+    collector_shed: IntGaugeVec,
+    degraded: IntGauge,
+    started: Instant,
+    /// Of the config file last loaded
+    config_hash: RwLock<Option<String>>,
+    /// Bumped, with the time, whenever what /metrics serves may have
+    /// changed: after a collection cycle, a toggle or a reload
+    changes: RwLock<(u64, SystemTime)>,
+}
+
+impl AppState {
+    pub fn new(args: Args) -> Result<Self, ConfigError> {
+        Self::with_registry(args, prometheus::default_registry().clone())
+    }
+
+    /// Serve and register self-metrics with another registry than the
+    /// default one, e.g. to run several instances in one process in tests
+    pub fn with_registry(args: Args, registry: Registry) -> Result<Self, ConfigError> {
+        let config = Self::load_config(&args)?;
+        let (shutdown, _) = watch::channel(false);
+        let collector_enabled = metric(
+            "collector_enabled",
+            "Whether a collector is currently enabled (1) or disabled (0)",
+        )
+        .namespace("metrixd")
+        .registry(&registry)
+        .int_gauge_vec(&["collector"]);
+        let collector_available = metric(
+            "collector_available",
+            "Whether a collector can run on this host (1) or was left out at startup (0)",
+        )
+        .namespace("metrixd")
+        .registry(&registry)
+        .int_gauge_vec(&["collector"]);
+        let collector_duration = metric(
+            "collector_duration_seconds",
+            "Time taken by each collection, over the last 10 minutes",
+        )
+        .namespace("metrixd")
+        .registry(&registry)
+        .summary_vec(&["collector"]);
+        let collector_errors = metric(
+            "collector_errors_total",
+            "Collections that returned an error",
+        )
+        .namespace("metrixd")
+        .registry(&registry)
+        .int_counter_vec(&["collector"]);
+        let collector_backoff = metric(
+            "collector_backoff_seconds",
+            "Time until a failing collector is retried, 0 while it succeeds",
+        )
+        .namespace("metrixd")
+        .registry(&registry)
+        .gauge_vec(&["collector"]);
+        let series_count = metric(
+            "series_count",
+            "Series a collector exported in its last collection, before metrics.exclude",
+        )
+        .namespace("metrixd")
+        .registry(&registry)
+        .int_gauge_vec(&["collector"]);
+        let exposition_size = metric(
+            "exposition_size_bytes",
+            "Size of the last unfiltered /metrics response body",
+        )
+        .namespace("metrixd")
+        .registry(&registry)
+        .int_gauge();
+        let rate_limited = metric(
+            "http_requests_limited_total",
+            "Requests answered with 429 because of web.rate_limit",
+        )
+        .namespace("metrixd")
+        .registry(&registry)
+        .int_counter();
+        let collection_interval = metric(
+            "collection_interval_seconds",
+            "Wait after the last collection cycle, as adapted by collection.adaptive",
+        )
+        .namespace("metrixd")
+        .registry(&registry)
+        .gauge();
+        collection_interval.set(config.collection.interval.as_secs_f64());
+        let resident_memory = metric(
+            "resident_memory_bytes",
+            "The agent's resident set size after the last collection cycle",
+        )
+        .namespace("metrixd")
+        .registry(&registry)
+        .int_gauge();
+        let collector_shed = metric(
+            "collector_shed",
+            "Whether a collector was disabled for nearing limits.max_rss_mib",
+        )
+        .namespace("metrixd")
+        .registry(&registry)
+        .int_gauge_vec(&["collector"]);
+        let degraded = metric(
+            "degraded",
+            "Whether collectors were shed to stay below limits.max_rss_mib",
+        )
+        .namespace("metrixd")
+        .registry(&registry)
+        .int_gauge();
+        // Joined with asset inventories, hence the node namespace
+        let identity_info = metric(
+            "identity_info",
+            "Machine ID and cloud instance of the host, always 1",
+        )
+        .namespace("node")
+        .registry(&registry)
+        .int_gauge_vec(&["machine_id", "cloud_provider", "instance_id"]);
+        let machine_id = identity::machine_id(Path::new("/")).unwrap_or_default();
+        identity_info
+            .with_label_values(&[&machine_id, "", ""])
+            .set(1);
+
+        let config_hash = config_hash(&args);
+        Ok(AppState {
+            args,
+            config: RwLock::new(config),
+            shutdown,
+            collectors: RwLock::new(BTreeMap::new()),
+            registry,
+            cloud_labels: OnceLock::new(),
+            host_identity: identity::host_identity(Path::new("/")),
+            machine_id,
+            identity_info,
+            collector_enabled,
+            collector_available,
+            collector_duration,
+            collector_errors,
+            collector_backoff,
+            series_count,
+            exposition_size,
+            rate_limited,
+            collection_interval,
+            memory_limit: MemoryLimit::default(),
+            resident_memory,
+            collector_shed,
+            degraded,
+            started: Instant::now(),
+            config_hash: RwLock::new(config_hash),
+            changes: RwLock::new((0, SystemTime::now())),
+        })
+    }
+
+    fn load_config(args: &Args) -> Result<Config, ConfigError> {
+        let mut config = match &args.config_file {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        config.apply_env(std::env::vars())?;
+        args.apply(&mut config);
+        Ok(config)
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Set the cloud metadata labels, once at startup
+    pub fn set_cloud_labels(&self, labels: BTreeMap<String, String>) {
+        let _ = self.cloud_labels.set(labels);
+    }
+
+    /// Fill in the cloud side of `node_identity_info`, once at startup
+    pub fn set_cloud_identity(&self, provider: &str, instance_id: &str) {
+        self.identity_info.reset();
+        self.identity_info
+            .with_label_values(&[&self.machine_id, &provider.to_lowercase(), instance_id])
+            .set(1);
+    }
+
+    /// Labels added to every series: the cloud metadata labels and the
+    /// `instance.label`, overridden by the configured `labels`
+    pub fn labels(&self) -> BTreeMap<String, String> {
+        let mut labels = self.cloud_labels.get().cloned().unwrap_or_default();
+        let config = self.config.read().unwrap();
+        if let Some(label) = &config.instance.label {
+            let id = config.instance.id.as_ref().unwrap_or(&self.host_identity);
+            labels.insert(label.clone(), id.clone());
+        }
+        labels.extend(config.labels.clone());
+        labels
+    }
+
+    /// `instance.id`, or else the host name or machine ID
+    pub fn instance_id(&self) -> String {
+        let configured = self.config.read().unwrap().instance.id.clone();
+        configured.unwrap_or_else(|| self.host_identity.clone())
+    }
+
+    /// Snapshot of the current configuration
+    pub fn config(&self) -> Config {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Re-read the config file. On error the running configuration is kept.
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        let new_config = Self::load_config(&self.args)?;
+        let mut config = self.config.write().unwrap();
+
+        if new_config.web.listen_address != config.web.listen_address
+            || new_config.grpc != config.grpc
+        {
+            eprintln!("Listen address changes take effect after a restart");
+        }
+        if (
+            new_config.web.http2,
+            new_config.web.keep_alive,
+            new_config.web.http2_keep_alive_interval,
+            new_config.web.http2_max_concurrent_streams,
+        ) != (
+            config.web.http2,
+            config.web.keep_alive,
+            config.web.http2_keep_alive_interval,
+            config.web.http2_max_concurrent_streams,
+        ) {
+            eprintln!("HTTP/2 and keep-alive changes take effect after a restart");
+        }
+        if new_config.security != config.security {
+            eprintln!("security.user and security.group changes take effect after a restart");
+        }
+        if new_config.cloud != config.cloud {
+            eprintln!(
+                "cloud.metadata_labels and cloud.identity_info changes take effect after a restart"
+            );
+        }
+        if new_config.federation != config.federation {
+            eprintln!("Federation changes take effect after a restart");
+        }
+        if new_config.export != config.export {
+            eprintln!("Exporter changes take effect after a restart");
+        }
+        if new_config.services != config.services {
+            eprintln!("Service collector changes take effect after a restart");
+        }
+        if new_config.plugins != config.plugins {
+            eprintln!("Plugin changes take effect after a restart");
+        }
+        if new_config.scripts != config.scripts {
+            eprintln!("Script changes take effect after a restart");
+        }
+
+        // Only apply `enabled` settings that changed in the file, so runtime
+        // toggles made through the admin API survive unrelated reloads
+        let names: Vec<&'static str> = self.collectors.read().unwrap().keys().copied().collect();
+        for name in names {
+            let enabled = new_config.collector(name).enabled;
+            if enabled != config.collector(name).enabled {
+                self.set_collector_enabled(name, enabled);
+            }
+        }
+
+        *config = new_config;
+        *self.config_hash.write().unwrap() = config_hash(&self.args);
+        self.mark_changed();
+        println!("Configuration reloaded");
+        Ok(())
+    }
+
+    /// Record whether a collector passed the startup check of its data
+    /// sources
+    pub fn set_collector_available(&self, name: &str, available: bool) {
+        self.collector_available
+            .with_label_values(&[name])
+            .set(available as i64);
+    }
+
+    /// Make a collector known to the admin API, enabled unless the config
+    /// says otherwise
+    pub fn register_collector(&self, collector: &dyn Collector) {
+        let name = collector.name();
+        let enabled = self.config().collector(name).enabled;
+        self.collectors.write().unwrap().insert(
+            name,
+            CollectorState {
+                enabled,
+                metrics: collector.metric_docs(),
+                failures: 0,
+                backoff_cycles: 0,
+                shed: false,
+                last_duration: None,
+                last_error: None,
+            },
+        );
+        self.collector_enabled
+            .with_label_values(&[name])
+            .set(enabled as i64);
+        self.collector_errors.with_label_values(&[name]);
+        self.collector_backoff.with_label_values(&[name]).set(0.0);
+        self.series_count.with_label_values(&[name]);
+    }
+
+    /// Run a collector for this cycle unless it is disabled or backing off,
+    /// recording how long it took and whether it failed. Returns whether it
+    /// ran.
+    pub fn run_collector(&self, collector: &dyn Collector) -> bool {
+        let name = collector.name();
+        if !self.take_turn(name) {
+            return false;
+        }
+        let started = Instant::now();
+        let result = collector.collect_metrics();
+        self.observe_collection(name, started.elapsed());
+        self.record_outcome(name, result);
+        let series: usize = collector
+            .metrics()
+            .iter()
+            .flat_map(|metric| metric.collect())
+            .map(|family| crate::server::series_count(&family))
+            .sum();
+        self.series_count
+            .with_label_values(&[name])
+            .set(series as i64);
+        true
+    }
+
+    /// Note that the exposition may differ from what was served so far
+    pub fn mark_changed(&self) {
+        let mut changes = self.changes.write().unwrap();
+        *changes = (changes.0 + 1, SystemTime::now());
+    }
+
+    /// How often and when the exposition last changed, for the `ETag` and
+    /// `Last-Modified` headers of /metrics
+    pub fn last_change(&self) -> (u64, SystemTime) {
+        *self.changes.read().unwrap()
+    }
+
+    pub fn count_rate_limited(&self) {
+        self.rate_limited.inc();
+    }
+
+    /// Check the agent's memory against `limits.max_rss_mib` after a
+    /// cycle, shedding the enabled collector with the most series if it's
+    /// close (see `memory_limit`). Returns the shed collector, whose series
+    /// the caller should reset.
+    pub fn limit_memory(&self) -> Option<&'static str> {
+        let resident = memory_limit::resident_bytes()?;
+        self.resident_memory.set(resident as i64);
+        let ceiling = self.config.read().unwrap().limits.max_rss?;
+        let candidates: Vec<(&'static str, i64)> = self
+            .collector_states()
+            .into_iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| (name, self.series_count.with_label_values(&[name]).get()))
+            .collect();
+        let name = self.memory_limit.check(resident, ceiling, &candidates)?;
+        eprintln!(
+            "Resident memory of {} MiB is close to limits.max_rss_mib = {}, shedding collector {}",
+            resident >> 20,
+            ceiling >> 20,
+            name
+        );
+        self.set_collector_enabled(name, false);
+        if let Some(state) = self.collectors.write().unwrap().get_mut(name) {
+            state.shed = true;
+        }
+        self.collector_shed.with_label_values(&[name]).set(1);
+        self.degraded.set(1);
+        Some(name)
+    }
+
+    pub fn set_collection_interval(&self, interval: Duration) {
+        self.collection_interval.set(interval.as_secs_f64());
+    }
+
+    /// Record the size of a /metrics response
+    pub fn set_exposition_size(&self, bytes: usize) {
+        self.exposition_size.set(bytes as i64);
+    }
+
+    /// Whether a collector runs this cycle; counts down its backoff if not
+    fn take_turn(&self, name: &str) -> bool {
+        let mut collectors = self.collectors.write().unwrap();
+        let Some(state) = collectors.get_mut(name) else {
+            return false;
+        };
+        if !state.enabled {
+            return false;
+        }
+        if state.backoff_cycles > 0 {
+            state.backoff_cycles -= 1;
+            return false;
+        }
+        true
+    }
+
+    /// After repeated errors a collector sits out 1, 3, 7, ... cycles, so
+    /// its retry delay doubles up to `collection.max_backoff`. The first
+    /// success resets it.
+    fn record_outcome(&self, name: &str, result: Result<(), String>) {
+        let collection = self.config().collection;
+        let mut collectors = self.collectors.write().unwrap();
+        let Some(state) = collectors.get_mut(name) else {
+            return;
+        };
+
+        let Err(e) = result else {
+            if state.failures > 0 {
+                println!(
+                    "Collector {} recovered after {} failures",
+                    name, state.failures
+                );
+            }
+            state.failures = 0;
+            state.backoff_cycles = 0;
+            self.collector_backoff.with_label_values(&[name]).set(0.0);
+            return;
+        };
+
+        state.failures += 1;
+        state.last_error = Some(e.clone());
+        let max_cycles = (collection.max_backoff.as_secs_f64() / collection.interval.as_secs_f64())
+            .max(1.0) as u32;
+        let cycles = 2u32.saturating_pow(state.failures - 1).clamp(1, max_cycles);
+        state.backoff_cycles = cycles - 1;
+        let delay = collection.interval * cycles;
+        self.collector_errors.with_label_values(&[name]).inc();
+        self.collector_backoff
+            .with_label_values(&[name])
+            .set(delay.as_secs_f64());
+        eprintln!("Collector {} failed: {} (retrying in {:?})", name, e, delay);
+    }
+
+    pub fn is_collector_enabled(&self, name: &str) -> bool {
+        self.collectors
+            .read()
+            .unwrap()
+            .get(name)
+            .is_some_and(|c| c.enabled)
+    }
+
+    /// Returns false if no collector with that name exists
+    pub fn set_collector_enabled(&self, name: &str, enabled: bool) -> bool {
+        let mut collectors = self.collectors.write().unwrap();
+        let Some(state) = collectors.get_mut(name) else {
+            return false;
+        };
+        state.enabled = enabled;
+        // Enabling a shed collector again takes it off the list
+        if enabled && state.shed {
+            state.shed = false;
+            self.collector_shed.with_label_values(&[name]).set(0);
+            self.degraded
+                .set(collectors.values().any(|c| c.shed) as i64);
+        }
+        self.collector_enabled
+            .with_label_values(&[name])
+            .set(enabled as i64);
+        self.mark_changed();
+        println!(
+            "Collector {} {}",
+            name,
+            if enabled { "enabled" } else { "disabled" }
+        );
+        true
+    }
+
+    fn observe_collection(&self, name: &str, duration: Duration) {
+        self.collector_duration
+            .with_label_values(&[name])
+            .observe(duration.as_secs_f64());
+        if let Some(state) = self.collectors.write().unwrap().get_mut(name) {
+            state.last_duration = Some(duration);
+        }
+    }
+
+    pub fn collector_states(&self) -> Vec<(&'static str, bool)> {
+        self.collectors
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, c)| (*name, c.enabled))
+            .collect()
+    }
+
+    pub fn collector_statuses(&self) -> Vec<CollectorStatus> {
+        self.collectors
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, c)| CollectorStatus {
+                name,
+                enabled: c.enabled,
+                shed: c.shed,
+                failures: c.failures,
+                last_duration: c.last_duration,
+                last_error: c.last_error.clone(),
+            })
+            .collect()
+    }
+
+    /// What the enabled collectors export, for /metrics-docs
+    pub fn metric_docs(&self) -> Vec<(&'static str, Vec<MetricDoc>)> {
+        self.collectors
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, c)| c.enabled)
+            .map(|(name, c)| (*name, c.metrics.clone()))
+            .collect()
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// SHA-256 of the config file as last loaded, none without one
+    pub fn config_hash(&self) -> Option<String> {
+        self.config_hash.read().unwrap().clone()
+    }
+
+    /// Metric families owned by disabled collectors, which are left out of
+    /// the exposition rather than exported with stale values
+    pub fn hidden_metric_names(&self) -> HashSet<String> {
+        self.collectors
+            .read()
+            .unwrap()
+            .values()
+            .filter(|c| !c.enabled)
+            .flat_map(|c| c.metrics.iter().map(|metric| metric.name.clone()))
+            .collect()
+    }
+
+    /// Metric families of one collector, or `None` if there's no such collector
+    pub fn collector_metric_names(&self, name: &str) -> Option<Vec<String>> {
+        self.collectors
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|c| c.metrics.iter().map(|metric| metric.name.clone()).collect())
+    }
+
+    /// Ask all servers to finish in-flight requests and stop
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Resolves once `shutdown` has been called
+    pub fn shutdown_signal(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut rx = self.shutdown.subscribe();
+        async move {
+            let _ = rx.wait_for(|stop| *stop).await;
+        }
+    }
+}
+
+/// Tells which version of the config file an agent runs
+fn config_hash(args: &Args) -> Option<String> {
+    let contents = std::fs::read(args.config_file.as_ref()?).ok()?;
+    Some(sigv4::hex(&sigv4::sha256(&contents)))
+}
@@ -0,0 +1,165 @@
+//! Utilities for end-to-end tests of the HTTP endpoint: `TestServer` runs
+//! the server on an ephemeral port with its own registry, so tests can run
+//! in parallel in one process and only see the collectors they pass in.
+//!
+//! ```ignore
+//! let server = TestServer::start("[web]\nenable_admin_api = true\n", vec![Box::new(fake)]).await;
+//! server.collect();
+//! let response = server.get("/metrics").await;
+//! assert!(response.body.contains("fake_value 1"));
+//! ```
+
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Body, Client, Method, Request, StatusCode};
+use prometheus::Registry;
+
+use crate::cli::Args;
+use crate::collector::Collector;
+use crate::plugins::CollectorMetrics;
+use crate::state::AppState;
+
+// Keeps config files of servers started by the same process apart
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub struct TestServer {
+    addr: SocketAddr,
+    state: Arc<AppState>,
+    registry: Registry,
+    collectors: Vec<Arc<dyn Collector + Send + Sync>>,
+    config_file: PathBuf,
+}
+
+/// A response with its body read to the end
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+    /// The body as received, for the protobuf format
+    pub bytes: Vec<u8>,
+}
+
+impl TestResponse {
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+    }
+}
+
+impl TestServer {
+    /// Serve on 127.0.0.1 with the given config file contents. The
+    /// collectors' `metrics()` are registered with a fresh registry, which
+    /// also holds the self-metrics; their `register_metrics` isn't called.
+    /// Panics on invalid config, since that's a bug in the test.
+    pub async fn start(config: &str, collectors: Vec<Box<dyn Collector + Send + Sync>>) -> Self {
+        let config_file = std::env::temp_dir().join(format!(
+            "metrixd-test-{}-{}.toml",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&config_file, config).expect("failed to write test config");
+        let args = Args {
+            config_file: Some(config_file.clone()),
+            ..Args::default()
+        };
+
+        let registry = Registry::new();
+        let state =
+            Arc::new(AppState::with_registry(args, registry.clone()).expect("invalid test config"));
+        let collectors: Vec<Arc<dyn Collector + Send + Sync>> =
+            collectors.into_iter().map(Arc::from).collect();
+        for collector in &collectors {
+            registry
+                .register(Box::new(CollectorMetrics(Arc::clone(collector))))
+                .unwrap_or_else(|e| panic!("failed to register {}: {}", collector.name(), e));
+            state.register_collector(collector.as_ref());
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind test server");
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(crate::server::serve(listener, Arc::clone(&state)));
+
+        TestServer {
+            addr,
+            state,
+            registry,
+            collectors,
+            config_file,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// The registry /metrics is gathered from, for registering extra metrics
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Run one collection cycle, as the daemon does every interval: disabled
+    /// and backed off collectors are skipped
+    pub fn collect(&self) {
+        for collector in &self.collectors {
+            self.state.run_collector(collector.as_ref());
+        }
+        self.state.mark_changed();
+    }
+
+    pub async fn get(&self, path: &str) -> TestResponse {
+        self.request(Method::GET, path, &[], "").await
+    }
+
+    pub async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> TestResponse {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(self.url(path))
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        for (name, value) in headers {
+            request.headers_mut().append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+
+        let response = Client::new()
+            .request(request)
+            .await
+            .unwrap_or_else(|e| panic!("request to {} failed: {}", path, e));
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .expect("failed to read response body");
+        TestResponse {
+            status,
+            headers,
+            body: String::from_utf8_lossy(&body).into_owned(),
+            bytes: body.to_vec(),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.state.shutdown();
+        let _ = std::fs::remove_file(&self.config_file);
+    }
+}
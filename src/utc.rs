@@ -0,0 +1,110 @@
+//! Calendar time in UTC, for the few places that format timestamps
+//! themselves (dump file names, signed requests, exporter payloads)
+//! without a date library
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct Utc {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    pub millis: u32,
+    /// 0 for Sunday
+    pub weekday: u32,
+}
+
+impl Utc {
+    pub fn from_system_time(time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let (days, secs_of_day) = (secs / 86400, (secs % 86400) as u32);
+
+        // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+        let z = days as i64 + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        Utc {
+            year,
+            month: month as u32,
+            day: day as u32,
+            hour: secs_of_day / 3600,
+            minute: secs_of_day / 60 % 60,
+            second: secs_of_day % 60,
+            millis: since_epoch.subsec_millis(),
+            // 1970-01-01 was a Thursday
+            weekday: ((days + 4) % 7) as u32,
+        }
+    }
+
+    /// `YYYYMMDD`
+    pub fn basic_date(&self) -> String {
+        format!("{:04}{:02}{:02}", self.year, self.month, self.day)
+    }
+
+    /// `YYYYMMDDTHHMMSSZ`, the ISO 8601 basic format
+    pub fn basic(&self) -> String {
+        format!(
+            "{}T{:02}{:02}{:02}Z",
+            self.basic_date(),
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+
+    /// `YYYY-MM-DDTHH:MM:SS.mmmZ`
+    pub fn rfc3339(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.millis
+        )
+    }
+
+    /// `Thu, 29 Feb 2024 23:59:58 GMT`, the format of HTTP date headers
+    pub fn http_date(&self) -> String {
+        const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+        format!(
+            "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+            WEEKDAYS[self.weekday as usize],
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn converts_unix_time_to_calendar_time() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_251_198_250);
+        let utc = Utc::from_system_time(time);
+        assert_eq!((utc.year, utc.month, utc.day), (2024, 2, 29), "a leap day");
+        assert_eq!(
+            (utc.hour, utc.minute, utc.second, utc.millis),
+            (23, 59, 58, 250)
+        );
+        assert_eq!(utc.basic(), "20240229T235958Z");
+        assert_eq!(utc.rfc3339(), "2024-02-29T23:59:58.250Z");
+        assert_eq!(utc.http_date(), "Thu, 29 Feb 2024 23:59:58 GMT");
+    }
+}
@@ -0,0 +1,1030 @@
+//! Runs decoded modules. Values are kept as raw bits in `u64`s: i32s
+//! zero-extended, floats by their bit patterns and refs as function
+//! indices. Calls don't recurse on the Rust stack, and every instruction
+//! costs one unit of fuel, host calls as much as their work is worth, so a
+//! plugin can neither overflow the daemon's stack nor keep a collection
+//! cycle waiting forever.
+
+use std::sync::Arc;
+
+use super::module::{ConstExpr, Export, Module, Op, NULL};
+
+pub const PAGE: usize = 65536;
+
+/// Calls that may be nested, against runaway recursion
+const MAX_FRAMES: usize = 1000;
+
+/// Values on the operand stack or in locals, across all frames
+const MAX_VALUES: usize = 1 << 20;
+
+/// Elements a table may have
+const MAX_TABLE: u32 = 1 << 20;
+
+/// The functions a module imports, called with the instance's memory and
+/// the fuel left, which they `burn` for their work
+pub trait Host {
+    fn call(
+        &mut self,
+        import: u32,
+        args: &[u64],
+        memory: &mut [u8],
+        fuel: &mut u64,
+    ) -> Result<Vec<u64>, String>;
+}
+
+/// Take `amount` of the fuel left, trapping once there isn't enough
+pub fn burn(fuel: &mut u64, amount: u64) -> Result<(), String> {
+    match fuel.checked_sub(amount) {
+        Some(left) => {
+            *fuel = left;
+            Ok(())
+        }
+        None => {
+            *fuel = 0;
+            trap("ran out of fuel")
+        }
+    }
+}
+
+pub struct Instance {
+    module: Arc<Module>,
+    memory: Vec<u8>,
+    max_pages: usize,
+    globals: Vec<u64>,
+    tables: Vec<Vec<u64>>,
+    /// Data segments `data.drop` emptied
+    dropped: Vec<bool>,
+}
+
+struct Frame {
+    /// Index into `Module::codes`
+    code: usize,
+    pc: usize,
+    /// Where the arguments were on the operand stack
+    stack: usize,
+    /// Where the frame's labels and locals start
+    labels: usize,
+    locals: usize,
+}
+
+/// Where a branch out of a block goes, and the values it keeps
+struct Label {
+    target: usize,
+    arity: usize,
+    height: usize,
+}
+
+fn trap<T>(message: &str) -> Result<T, String> {
+    Err(message.to_string())
+}
+
+macro_rules! pop {
+    ($stack:expr) => {
+        $stack.pop().ok_or("stack underflow")?
+    };
+}
+
+impl Instance {
+    /// Set up memory, tables and globals, then run the start function.
+    /// Memory is capped at `max_memory` bytes whatever the module asks for.
+    pub fn new(
+        module: Arc<Module>,
+        max_memory: usize,
+        host: &mut dyn Host,
+        fuel: &mut u64,
+    ) -> Result<Instance, String> {
+        let cap = max_memory / PAGE;
+        let (pages, max_pages) = match module.memory {
+            Some(limits) => (
+                limits.min as usize,
+                limits.max.map_or(cap, |max| cap.min(max as usize)),
+            ),
+            None => (0, 0),
+        };
+        if pages > max_pages {
+            return Err(format!(
+                "needs {} pages of memory, more than the {} allowed",
+                pages, max_pages
+            ));
+        }
+        let mut tables = Vec::new();
+        for limits in &module.tables {
+            if limits.min > MAX_TABLE {
+                return Err("table too large".to_string());
+            }
+            tables.push(vec![NULL; limits.min as usize]);
+        }
+        let mut instance = Instance {
+            memory: vec![0; pages * PAGE],
+            max_pages,
+            globals: Vec::new(),
+            tables,
+            dropped: vec![false; module.datas.len()],
+            module: Arc::clone(&module),
+        };
+        for global in &module.globals {
+            let value = instance.eval(global.init)?;
+            instance.globals.push(value);
+        }
+        for element in &module.elements {
+            let offset = instance.eval(element.offset)? as u32 as usize;
+            instance
+                .tables
+                .get_mut(element.table as usize)
+                .ok_or("element segment for an unknown table")?
+                .get_mut(offset..offset + element.funcs.len())
+                .ok_or("element segment out of bounds")?
+                .copy_from_slice(&element.funcs);
+        }
+        for data in &module.datas {
+            if let Some(offset) = data.offset {
+                let offset = instance.eval(offset)? as u32 as usize;
+                instance
+                    .memory
+                    .get_mut(offset..offset + data.bytes.len())
+                    .ok_or("data segment out of bounds")?
+                    .copy_from_slice(&data.bytes);
+            }
+        }
+        if let Some(start) = module.start {
+            instance.invoke(start, &[], host, fuel)?;
+        }
+        Ok(instance)
+    }
+
+    fn eval(&self, expr: ConstExpr) -> Result<u64, String> {
+        match expr {
+            ConstExpr::Value(value) => Ok(value),
+            ConstExpr::GlobalGet(global) => self
+                .globals
+                .get(global as usize)
+                .copied()
+                .ok_or_else(|| "unknown global in a constant expression".to_string()),
+        }
+    }
+
+    /// Call an exported function, running at most `fuel` instructions
+    pub fn call(
+        &mut self,
+        name: &str,
+        args: &[u64],
+        host: &mut dyn Host,
+        fuel: &mut u64,
+    ) -> Result<Vec<u64>, String> {
+        match self.module.exports.get(name) {
+            Some(Export::Func(func)) => self.invoke(*func, args, host, fuel),
+            _ => Err(format!("no exported function {}", name)),
+        }
+    }
+
+    fn invoke(
+        &mut self,
+        func: u32,
+        args: &[u64],
+        host: &mut dyn Host,
+        fuel: &mut u64,
+    ) -> Result<Vec<u64>, String> {
+        let module = Arc::clone(&self.module);
+        let ty = module.func_type(func).ok_or("unknown function")?;
+        if args.len() != ty.params.len() {
+            return Err(format!(
+                "called with {} arguments instead of {}",
+                args.len(),
+                ty.params.len()
+            ));
+        }
+        let mut thread = Thread {
+            stack: args.to_vec(),
+            frames: Vec::new(),
+            labels: Vec::new(),
+            locals: Vec::new(),
+        };
+        thread.enter(self, &module, func, host, fuel)?;
+        thread.run(self, &module, host, fuel)?;
+        Ok(thread.stack)
+    }
+}
+
+/// The state of one call into an instance
+struct Thread {
+    stack: Vec<u64>,
+    frames: Vec<Frame>,
+    labels: Vec<Label>,
+    locals: Vec<u64>,
+}
+
+impl Thread {
+    /// Call `func` with its arguments on top of the stack: the host's
+    /// functions right away, the module's by pushing a frame
+    fn enter(
+        &mut self,
+        instance: &mut Instance,
+        module: &Module,
+        func: u32,
+        host: &mut dyn Host,
+        fuel: &mut u64,
+    ) -> Result<(), String> {
+        let ty = module.func_type(func).ok_or("unknown function")?;
+        let base = self
+            .stack
+            .len()
+            .checked_sub(ty.params.len())
+            .ok_or("stack underflow")?;
+        let Some(code) = (func as usize).checked_sub(module.imports.len()) else {
+            let results = host.call(func, &self.stack[base..], &mut instance.memory, fuel)?;
+            self.stack.truncate(base);
+            self.stack.extend(results);
+            return Ok(());
+        };
+        if self.frames.len() == MAX_FRAMES || self.locals.len() > MAX_VALUES {
+            return trap("call stack exhausted");
+        }
+        let locals = self.locals.len();
+        self.locals.extend(self.stack.drain(base..));
+        self.locals.extend_from_slice(&module.codes[code].locals);
+        // The body is a block, whose End pops this
+        self.labels.push(Label {
+            target: module.codes[code].body.len(),
+            arity: ty.results.len(),
+            height: base,
+        });
+        self.frames.push(Frame {
+            code,
+            pc: 0,
+            stack: base,
+            labels: self.labels.len() - 1,
+            locals,
+        });
+        Ok(())
+    }
+
+    /// Leave the top `arity` values at `height`, dropping those between
+    fn keep(&mut self, height: usize, arity: usize) -> Result<(), String> {
+        match self.stack.len().checked_sub(arity) {
+            Some(top) if top >= height => {
+                self.stack.drain(height..top);
+                Ok(())
+            }
+            _ => trap("stack underflow"),
+        }
+    }
+
+    /// Branch out of the `depth`th enclosing block, returning where to
+    fn branch(&mut self, depth: u32) -> Result<usize, String> {
+        let first = self.frames.last().map_or(0, |frame| frame.labels);
+        let index = self
+            .labels
+            .len()
+            .checked_sub(depth as usize + 1)
+            .filter(|index| *index >= first)
+            .ok_or("branch out of the function")?;
+        let Label {
+            target,
+            arity,
+            height,
+        } = self.labels[index];
+        self.keep(height, arity)?;
+        // Branching to a loop runs its Loop again, which pushes it back
+        self.labels.truncate(index);
+        Ok(target)
+    }
+
+    fn local(&self, index: u32) -> Result<usize, String> {
+        let first = self.frames.last().map_or(0, |frame| frame.locals);
+        let index = first + index as usize;
+        match index < self.locals.len() {
+            true => Ok(index),
+            false => trap("unknown local"),
+        }
+    }
+
+    /// Where a block's label keeps its values, below its parameters
+    fn height(&self, params: u32) -> Result<usize, String> {
+        self.stack
+            .len()
+            .checked_sub(params as usize)
+            .ok_or_else(|| "stack underflow".to_string())
+    }
+
+    fn run(
+        &mut self,
+        instance: &mut Instance,
+        module: &Module,
+        host: &mut dyn Host,
+        fuel: &mut u64,
+    ) -> Result<(), String> {
+        while let Some(frame) = self.frames.last_mut() {
+            let code = &module.codes[frame.code];
+            let Some(op) = code.body.get(frame.pc) else {
+                // Returned, or ran off the end of the body
+                let frame = self.frames.pop().unwrap();
+                let results = module.types[code.ty as usize].results.len();
+                self.keep(frame.stack, results)?;
+                self.labels.truncate(frame.labels);
+                self.locals.truncate(frame.locals);
+                continue;
+            };
+            if *fuel == 0 {
+                return trap("ran out of fuel");
+            }
+            *fuel -= 1;
+            if self.stack.len() > MAX_VALUES {
+                return trap("operand stack exhausted");
+            }
+            frame.pc += 1;
+            let pc = frame.pc;
+            let mut jump = None;
+
+            match op {
+                Op::Unreachable => return trap("unreachable executed"),
+                Op::Nop => {}
+                Op::Block {
+                    params,
+                    results,
+                    end,
+                } => {
+                    let height = self.height(*params)?;
+                    self.labels.push(Label {
+                        target: *end as usize + 1,
+                        arity: *results as usize,
+                        height,
+                    });
+                }
+                Op::Loop { params } => {
+                    let height = self.height(*params)?;
+                    self.labels.push(Label {
+                        target: pc - 1,
+                        arity: *params as usize,
+                        height,
+                    });
+                }
+                Op::If {
+                    params,
+                    results,
+                    otherwise,
+                    end,
+                } => {
+                    if pop!(self.stack) as u32 == 0 {
+                        // Past the Else, or to the End popping the label
+                        jump = Some(match otherwise == end {
+                            true => *end as usize,
+                            false => *otherwise as usize + 1,
+                        });
+                    }
+                    let height = self.height(*params)?;
+                    self.labels.push(Label {
+                        target: *end as usize + 1,
+                        arity: *results as usize,
+                        height,
+                    });
+                }
+                // Only reached at the end of the then branch
+                Op::Else { end } => jump = Some(*end as usize),
+                Op::End => {
+                    self.labels.pop();
+                }
+                Op::Br(depth) => jump = Some(self.branch(*depth)?),
+                Op::BrIf(depth) => {
+                    if pop!(self.stack) as u32 != 0 {
+                        jump = Some(self.branch(*depth)?);
+                    }
+                }
+                Op::BrTable(depths) => {
+                    let index = pop!(self.stack) as u32 as usize;
+                    let default = depths[depths.len() - 1];
+                    jump = Some(self.branch(*depths.get(index).unwrap_or(&default))?);
+                }
+                Op::Return => jump = Some(code.body.len()),
+                Op::Call(func) => self.enter(instance, module, *func, host, fuel)?,
+                Op::CallIndirect { ty, table } => {
+                    let index = pop!(self.stack) as u32 as usize;
+                    let func = *instance
+                        .tables
+                        .get(*table as usize)
+                        .ok_or("unknown table")?
+                        .get(index)
+                        .ok_or("undefined table element")?;
+                    if func == NULL {
+                        return trap("uninitialized table element");
+                    }
+                    let func = u32::try_from(func).map_err(|_| "unknown function")?;
+                    if module.func_type(func) != module.types.get(*ty as usize) {
+                        return trap("indirect call type mismatch");
+                    }
+                    self.enter(instance, module, func, host, fuel)?;
+                }
+                Op::Drop => {
+                    pop!(self.stack);
+                }
+                Op::Select => {
+                    let condition = pop!(self.stack) as u32;
+                    let second = pop!(self.stack);
+                    let first = self.stack.last_mut().ok_or("stack underflow")?;
+                    if condition == 0 {
+                        *first = second;
+                    }
+                }
+                Op::LocalGet(index) => {
+                    let index = self.local(*index)?;
+                    self.stack.push(self.locals[index]);
+                }
+                Op::LocalSet(index) => {
+                    let index = self.local(*index)?;
+                    self.locals[index] = pop!(self.stack);
+                }
+                Op::LocalTee(index) => {
+                    let index = self.local(*index)?;
+                    self.locals[index] = *self.stack.last().ok_or("stack underflow")?;
+                }
+                Op::GlobalGet(index) => {
+                    let value = *instance
+                        .globals
+                        .get(*index as usize)
+                        .ok_or("unknown global")?;
+                    self.stack.push(value);
+                }
+                Op::GlobalSet(index) => {
+                    let value = pop!(self.stack);
+                    match module.globals.get(*index as usize) {
+                        Some(global) if global.mutable => instance.globals[*index as usize] = value,
+                        Some(_) => return trap("write to an immutable global"),
+                        None => return trap("unknown global"),
+                    }
+                }
+                Op::TableGet(table) => {
+                    let index = pop!(self.stack) as u32 as usize;
+                    let table = instance
+                        .tables
+                        .get(*table as usize)
+                        .ok_or("unknown table")?;
+                    let value = *table.get(index).ok_or("table access out of bounds")?;
+                    self.stack.push(value);
+                }
+                Op::TableSet(table) => {
+                    let value = pop!(self.stack);
+                    let index = pop!(self.stack) as u32 as usize;
+                    let table = instance
+                        .tables
+                        .get_mut(*table as usize)
+                        .ok_or("unknown table")?;
+                    *table.get_mut(index).ok_or("table access out of bounds")? = value;
+                }
+                Op::Load(opcode, offset) => {
+                    let address = pop!(self.stack) as u32 as usize + *offset as usize;
+                    let value = load(&instance.memory, *opcode, address)?;
+                    self.stack.push(value);
+                }
+                Op::Store(opcode, offset) => {
+                    let value = pop!(self.stack);
+                    let address = pop!(self.stack) as u32 as usize + *offset as usize;
+                    store(&mut instance.memory, *opcode, address, value)?;
+                }
+                Op::MemorySize => self.stack.push((instance.memory.len() / PAGE) as u64),
+                Op::MemoryGrow => {
+                    let delta = pop!(self.stack) as u32 as usize;
+                    let pages = instance.memory.len() / PAGE;
+                    if pages + delta > instance.max_pages {
+                        self.stack.push(u32::MAX as u64);
+                    } else {
+                        instance.memory.resize((pages + delta) * PAGE, 0);
+                        self.stack.push(pages as u64);
+                    }
+                }
+                Op::Const(value) => self.stack.push(*value),
+                Op::Numeric(opcode) => numeric(&mut self.stack, *opcode)?,
+                Op::TruncSat(sub) => {
+                    let value = self.stack.last_mut().ok_or("stack underflow")?;
+                    *value = trunc_sat(*sub, *value);
+                }
+                Op::MemoryInit(data) => {
+                    let len = pop!(self.stack) as u32 as usize;
+                    let source = pop!(self.stack) as u32 as usize;
+                    let dest = pop!(self.stack) as u32 as usize;
+                    let bytes: &[u8] = match instance.dropped.get(*data as usize) {
+                        Some(false) => &module.datas[*data as usize].bytes,
+                        Some(true) => &[],
+                        None => return trap("unknown data segment"),
+                    };
+                    let source = bytes
+                        .get(source..source + len)
+                        .ok_or("memory access out of bounds")?;
+                    instance
+                        .memory
+                        .get_mut(dest..dest + len)
+                        .ok_or("memory access out of bounds")?
+                        .copy_from_slice(source);
+                }
+                Op::DataDrop(data) => {
+                    *instance
+                        .dropped
+                        .get_mut(*data as usize)
+                        .ok_or("unknown data segment")? = true;
+                }
+                Op::MemoryCopy => {
+                    let len = pop!(self.stack) as u32 as usize;
+                    let source = pop!(self.stack) as u32 as usize;
+                    let dest = pop!(self.stack) as u32 as usize;
+                    let size = instance.memory.len();
+                    if source + len > size || dest + len > size {
+                        return trap("memory access out of bounds");
+                    }
+                    instance.memory.copy_within(source..source + len, dest);
+                }
+                Op::MemoryFill => {
+                    let len = pop!(self.stack) as u32 as usize;
+                    let value = pop!(self.stack) as u8;
+                    let dest = pop!(self.stack) as u32 as usize;
+                    instance
+                        .memory
+                        .get_mut(dest..dest + len)
+                        .ok_or("memory access out of bounds")?
+                        .fill(value);
+                }
+                Op::RefNull => self.stack.push(NULL),
+                Op::RefIsNull => {
+                    let value = self.stack.last_mut().ok_or("stack underflow")?;
+                    *value = (*value == NULL) as u64;
+                }
+                Op::RefFunc(func) => self.stack.push(*func as u64),
+            }
+
+            if let Some(target) = jump {
+                // Still this op's frame: calls never jump
+                self.frames.last_mut().unwrap().pc = target;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn load(memory: &[u8], opcode: u8, address: usize) -> Result<u64, String> {
+    let size = match opcode {
+        0x29 | 0x2b => 8,
+        0x28 | 0x2a | 0x34 | 0x35 => 4,
+        0x2e | 0x2f | 0x32 | 0x33 => 2,
+        _ => 1,
+    };
+    let bytes = memory
+        .get(address..address + size)
+        .ok_or("memory access out of bounds")?;
+    let mut raw = [0; 8];
+    raw[..size].copy_from_slice(bytes);
+    let raw = u64::from_le_bytes(raw);
+    Ok(match opcode {
+        0x2c => raw as i8 as u32 as u64,
+        0x2e => raw as i16 as u32 as u64,
+        0x30 => raw as i8 as u64,
+        0x32 => raw as i16 as u64,
+        0x34 => raw as i32 as u64,
+        _ => raw,
+    })
+}
+
+fn store(memory: &mut [u8], opcode: u8, address: usize, value: u64) -> Result<(), String> {
+    let size = match opcode {
+        0x37 | 0x39 => 8,
+        0x36 | 0x38 | 0x3e => 4,
+        0x3b | 0x3d => 2,
+        _ => 1,
+    };
+    memory
+        .get_mut(address..address + size)
+        .ok_or("memory access out of bounds")?
+        .copy_from_slice(&value.to_le_bytes()[..size]);
+    Ok(())
+}
+
+/// Float min and max as WebAssembly has them: NaN if either is, and -0
+/// less than 0. f32s go through f64, which holds them exactly.
+fn min(a: f64, b: f64) -> f64 {
+    match (a.is_nan() || b.is_nan(), a == b) {
+        (true, _) => f64::NAN,
+        (false, true) => f64::from_bits(a.to_bits() | b.to_bits()),
+        (false, false) => a.min(b),
+    }
+}
+
+fn max(a: f64, b: f64) -> f64 {
+    match (a.is_nan() || b.is_nan(), a == b) {
+        (true, _) => f64::NAN,
+        (false, true) => f64::from_bits(a.to_bits() & b.to_bits()),
+        (false, false) => a.max(b),
+    }
+}
+
+/// Truncate a float for converting to an integer type holding `low` up
+/// to but not including `high`, trapping outside that
+fn truncate(value: f64, (low, high): (f64, f64)) -> Result<f64, String> {
+    if value.is_nan() {
+        return trap("invalid conversion to integer");
+    }
+    let value = value.trunc();
+    match value >= low && value < high {
+        true => Ok(value),
+        false => trap("integer overflow"),
+    }
+}
+
+const I32_RANGE: (f64, f64) = (-2147483648.0, 2147483648.0);
+const U32_RANGE: (f64, f64) = (0.0, 4294967296.0);
+const I64_RANGE: (f64, f64) = (-9223372036854775808.0, 9223372036854775808.0);
+const U64_RANGE: (f64, f64) = (0.0, 18446744073709551616.0);
+
+/// Integer division, trapping where WebAssembly does
+fn divide<T>(by_zero: bool, quotient: Option<T>) -> Result<T, String> {
+    match (by_zero, quotient) {
+        (true, _) => trap("integer divide by zero"),
+        (false, None) => trap("integer overflow"),
+        (false, Some(quotient)) => Ok(quotient),
+    }
+}
+
+fn f32_bits(value: f32) -> u64 {
+    value.to_bits() as u64
+}
+
+/// The comparison, arithmetic and conversion instructions, replacing
+/// their operands on the stack with their result
+fn numeric(stack: &mut Vec<u64>, opcode: u8) -> Result<(), String> {
+    let binary = matches!(
+        opcode,
+        0x46..=0x4f | 0x51..=0x66 | 0x6a..=0x78 | 0x7c..=0x8a | 0x92..=0x98 | 0xa0..=0xa6
+    );
+    let b = if binary { pop!(stack) } else { 0 };
+    let top = stack.last_mut().ok_or("stack underflow")?;
+    let a = *top;
+    let (i, j) = (a as u32, b as u32);
+    let (f, g) = (f32::from_bits(i), f32::from_bits(j));
+    let (x, y) = (f64::from_bits(a), f64::from_bits(b));
+
+    *top = match opcode {
+        0x45 => (i == 0) as u64,
+        0x46 => (i == j) as u64,
+        0x47 => (i != j) as u64,
+        0x48 => ((i as i32) < (j as i32)) as u64,
+        0x49 => (i < j) as u64,
+        0x4a => (i as i32 > j as i32) as u64,
+        0x4b => (i > j) as u64,
+        0x4c => (i as i32 <= j as i32) as u64,
+        0x4d => (i <= j) as u64,
+        0x4e => (i as i32 >= j as i32) as u64,
+        0x4f => (i >= j) as u64,
+
+        0x50 => (a == 0) as u64,
+        0x51 => (a == b) as u64,
+        0x52 => (a != b) as u64,
+        0x53 => ((a as i64) < (b as i64)) as u64,
+        0x54 => (a < b) as u64,
+        0x55 => (a as i64 > b as i64) as u64,
+        0x56 => (a > b) as u64,
+        0x57 => (a as i64 <= b as i64) as u64,
+        0x58 => (a <= b) as u64,
+        0x59 => (a as i64 >= b as i64) as u64,
+        0x5a => (a >= b) as u64,
+
+        0x5b => (f == g) as u64,
+        0x5c => (f != g) as u64,
+        0x5d => (f < g) as u64,
+        0x5e => (f > g) as u64,
+        0x5f => (f <= g) as u64,
+        0x60 => (f >= g) as u64,
+
+        0x61 => (x == y) as u64,
+        0x62 => (x != y) as u64,
+        0x63 => (x < y) as u64,
+        0x64 => (x > y) as u64,
+        0x65 => (x <= y) as u64,
+        0x66 => (x >= y) as u64,
+
+        0x67 => i.leading_zeros() as u64,
+        0x68 => i.trailing_zeros() as u64,
+        0x69 => i.count_ones() as u64,
+        0x6a => i.wrapping_add(j) as u64,
+        0x6b => i.wrapping_sub(j) as u64,
+        0x6c => i.wrapping_mul(j) as u64,
+        0x6d => divide(j == 0, (i as i32).checked_div(j as i32))? as u32 as u64,
+        0x6e => divide(j == 0, i.checked_div(j))? as u64,
+        0x6f => divide(j == 0, Some((i as i32).wrapping_rem(j as i32)))? as u32 as u64,
+        0x70 => divide(j == 0, i.checked_rem(j))? as u64,
+        0x71 => (i & j) as u64,
+        0x72 => (i | j) as u64,
+        0x73 => (i ^ j) as u64,
+        0x74 => i.wrapping_shl(j) as u64,
+        0x75 => (i as i32).wrapping_shr(j) as u32 as u64,
+        0x76 => i.wrapping_shr(j) as u64,
+        0x77 => i.rotate_left(j) as u64,
+        0x78 => i.rotate_right(j) as u64,
+
+        0x79 => a.leading_zeros() as u64,
+        0x7a => a.trailing_zeros() as u64,
+        0x7b => a.count_ones() as u64,
+        0x7c => a.wrapping_add(b),
+        0x7d => a.wrapping_sub(b),
+        0x7e => a.wrapping_mul(b),
+        0x7f => divide(b == 0, (a as i64).checked_div(b as i64))? as u64,
+        0x80 => divide(b == 0, a.checked_div(b))?,
+        0x81 => divide(b == 0, Some((a as i64).wrapping_rem(b as i64)))? as u64,
+        0x82 => divide(b == 0, a.checked_rem(b))?,
+        0x83 => a & b,
+        0x84 => a | b,
+        0x85 => a ^ b,
+        0x86 => a.wrapping_shl(j),
+        0x87 => (a as i64).wrapping_shr(j) as u64,
+        0x88 => a.wrapping_shr(j),
+        0x89 => a.rotate_left(j),
+        0x8a => a.rotate_right(j),
+
+        // Sign changes go by the bit so NaNs keep their payloads
+        0x8b => (i & 0x7fff_ffff) as u64,
+        0x8c => (i ^ 0x8000_0000) as u64,
+        0x8d => f32_bits(f.ceil()),
+        0x8e => f32_bits(f.floor()),
+        0x8f => f32_bits(f.trunc()),
+        0x90 => f32_bits(f.round_ties_even()),
+        0x91 => f32_bits(f.sqrt()),
+        0x92 => f32_bits(f + g),
+        0x93 => f32_bits(f - g),
+        0x94 => f32_bits(f * g),
+        0x95 => f32_bits(f / g),
+        0x96 => f32_bits(min(f as f64, g as f64) as f32),
+        0x97 => f32_bits(max(f as f64, g as f64) as f32),
+        0x98 => ((i & 0x7fff_ffff) | (j & 0x8000_0000)) as u64,
+
+        0x99 => a & 0x7fff_ffff_ffff_ffff,
+        0x9a => a ^ 0x8000_0000_0000_0000,
+        0x9b => x.ceil().to_bits(),
+        0x9c => x.floor().to_bits(),
+        0x9d => x.trunc().to_bits(),
+        0x9e => x.round_ties_even().to_bits(),
+        0x9f => x.sqrt().to_bits(),
+        0xa0 => (x + y).to_bits(),
+        0xa1 => (x - y).to_bits(),
+        0xa2 => (x * y).to_bits(),
+        0xa3 => (x / y).to_bits(),
+        0xa4 => min(x, y).to_bits(),
+        0xa5 => max(x, y).to_bits(),
+        0xa6 => (a & 0x7fff_ffff_ffff_ffff) | (b & 0x8000_0000_0000_0000),
+
+        0xa7 => i as u64,
+        0xa8 => truncate(f as f64, I32_RANGE)? as i32 as u32 as u64,
+        0xa9 => truncate(f as f64, U32_RANGE)? as u32 as u64,
+        0xaa => truncate(x, I32_RANGE)? as i32 as u32 as u64,
+        0xab => truncate(x, U32_RANGE)? as u32 as u64,
+        0xac => i as i32 as u64,
+        0xad => i as u64,
+        0xae => truncate(f as f64, I64_RANGE)? as i64 as u64,
+        0xaf => truncate(f as f64, U64_RANGE)? as u64,
+        0xb0 => truncate(x, I64_RANGE)? as i64 as u64,
+        0xb1 => truncate(x, U64_RANGE)? as u64,
+        0xb2 => f32_bits(i as i32 as f32),
+        0xb3 => f32_bits(i as f32),
+        0xb4 => f32_bits(a as i64 as f32),
+        0xb5 => f32_bits(a as f32),
+        0xb6 => f32_bits(x as f32),
+        0xb7 => (i as i32 as f64).to_bits(),
+        0xb8 => (i as f64).to_bits(),
+        0xb9 => (a as i64 as f64).to_bits(),
+        0xba => (a as f64).to_bits(),
+        0xbb => (f as f64).to_bits(),
+        // Reinterpretations: the bits are already there
+        0xbc => i as u64,
+        0xbd..=0xbf => a,
+        0xc0 => i as i8 as u32 as u64,
+        0xc1 => i as i16 as u32 as u64,
+        0xc2 => a as i8 as u64,
+        0xc3 => a as i16 as u64,
+        0xc4 => a as i32 as u64,
+        _ => return trap("unsupported instruction"),
+    };
+    Ok(())
+}
+
+/// The saturating float to int conversions, which Rust's casts match
+fn trunc_sat(sub: u8, value: u64) -> u64 {
+    let f = f32::from_bits(value as u32) as f64;
+    let x = f64::from_bits(value);
+    match sub {
+        0 => f as i32 as u32 as u64,
+        1 => f as u32 as u64,
+        2 => x as i32 as u32 as u64,
+        3 => x as u32 as u64,
+        4 => f as i64 as u64,
+        5 => f as u64,
+        6 => x as i64 as u64,
+        _ => x as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::module::{sleb, Assembler};
+    use super::*;
+
+    /// Imports nothing, so is never called
+    struct NoHost;
+
+    impl Host for NoHost {
+        fn call(
+            &mut self,
+            _: u32,
+            _: &[u64],
+            _: &mut [u8],
+            _: &mut u64,
+        ) -> Result<Vec<u64>, String> {
+            unreachable!()
+        }
+    }
+
+    fn instantiate(assembler: &Assembler) -> Instance {
+        let module = Module::parse(&assembler.finish()).unwrap();
+        Instance::new(Arc::new(module), 1 << 20, &mut NoHost, &mut 1000).unwrap()
+    }
+
+    fn call(instance: &mut Instance, name: &str, args: &[u64]) -> Result<Vec<u64>, String> {
+        instance.call(name, args, &mut NoHost, &mut 10_000_000)
+    }
+
+    const I32: u8 = 0x7f;
+    const I64: u8 = 0x7e;
+
+    #[test]
+    fn runs_loops_and_calls() {
+        let mut wasm = Assembler::default();
+        let unary = wasm.ty(&[I32], &[I32]);
+        let sum = wasm.ty(&[I32], &[I64]);
+        // if n < 2 { n } else { fib(n - 1) + fib(n - 2) }
+        let fib = wasm.func(
+            unary,
+            &[],
+            &[
+                0x20, 0, 0x41, 2, 0x48, 0x04, I32, 0x20, 0, 0x05, 0x20, 0, 0x41, 1, 0x6b, 0x10, 0,
+                0x20, 0, 0x41, 2, 0x6b, 0x10, 0, 0x6a, 0x0b,
+            ],
+        );
+        // while n != 0 { total += n; n -= 1 }
+        let sum = wasm.func(
+            sum,
+            &[I64],
+            &[
+                0x02, 0x40, 0x03, 0x40, 0x20, 0, 0x45, 0x0d, 1, 0x20, 1, 0x20, 0, 0xad, 0x7c, 0x21,
+                1, 0x20, 0, 0x41, 1, 0x6b, 0x21, 0, 0x0c, 0, 0x0b, 0x0b, 0x20, 1,
+            ],
+        );
+        wasm.export("fib", fib);
+        wasm.export("sum", sum);
+        let mut instance = instantiate(&wasm);
+        assert_eq!(call(&mut instance, "fib", &[20]).unwrap(), [6765]);
+        assert_eq!(
+            call(&mut instance, "sum", &[100_000]).unwrap(),
+            [5_000_050_000]
+        );
+        assert_eq!(
+            call(&mut instance, "sum", &[]).unwrap_err(),
+            "called with 0 arguments instead of 1"
+        );
+    }
+
+    #[test]
+    fn branches_out_of_blocks() {
+        let mut wasm = Assembler::default();
+        let unary = wasm.ty(&[I32], &[I32]);
+        let constant = wasm.ty(&[], &[I32]);
+        let select = wasm.func(
+            unary,
+            &[],
+            &[
+                0x02, 0x40, 0x02, 0x40, 0x02, 0x40, 0x20, 0, 0x0e, 2, 0, 1, 2, 0x0b, 0x41, 10,
+                0x0f, 0x0b, 0x41, 20, 0x0f, 0x0b, 0x41, 30,
+            ],
+        );
+        // 50 + block { 7; br_if 0 with 5 } keeps only the 5
+        let keep = wasm.func(
+            constant,
+            &[],
+            &[
+                0x41, 50, 0x02, I32, 0x41, 7, 0x41, 5, 0x41, 1, 0x0d, 0, 0x1a, 0x41, 6, 0x0b, 0x6a,
+            ],
+        );
+        wasm.export("select", select);
+        wasm.export("keep", keep);
+        let mut instance = instantiate(&wasm);
+        for (index, result) in [(0, 10), (1, 20), (2, 30), (7, 30)] {
+            assert_eq!(call(&mut instance, "select", &[index]).unwrap(), [result]);
+        }
+        assert_eq!(call(&mut instance, "keep", &[]).unwrap(), [55]);
+    }
+
+    #[test]
+    fn calls_through_tables() {
+        let mut wasm = Assembler::default();
+        let unary = wasm.ty(&[I32], &[I32]);
+        let constant = wasm.ty(&[], &[I32]);
+        let dispatch = wasm.ty(&[I32, I32], &[I32]);
+        let increment = wasm.func(unary, &[], &[0x20, 0, 0x41, 1, 0x6a]);
+        let one = wasm.func(constant, &[], &[0x41, 1]);
+        let dispatch = wasm.func(dispatch, &[], &[0x20, 1, 0x20, 0, 0x11, unary as u8, 0]);
+        wasm.table(&[increment, one]);
+        wasm.export("dispatch", dispatch);
+        let mut instance = instantiate(&wasm);
+        assert_eq!(call(&mut instance, "dispatch", &[0, 41]).unwrap(), [42]);
+        assert_eq!(
+            call(&mut instance, "dispatch", &[1, 41]).unwrap_err(),
+            "indirect call type mismatch"
+        );
+        assert_eq!(
+            call(&mut instance, "dispatch", &[2, 41]).unwrap_err(),
+            "undefined table element"
+        );
+    }
+
+    #[test]
+    fn keeps_to_its_memory() {
+        let mut wasm = Assembler::default();
+        let load = wasm.ty(&[I32], &[I32]);
+        let grow = wasm.ty(&[I32], &[I32]);
+        let load = wasm.func(load, &[], &[0x20, 0, 0x2e, 1, 0]);
+        let grow = wasm.func(grow, &[], &[0x20, 0, 0x40, 0]);
+        wasm.memory(1);
+        wasm.data(16, &[0xfe, 0xff]);
+        wasm.export("load16_s", load);
+        wasm.export("grow", grow);
+        let mut instance = instantiate(&wasm);
+        assert_eq!(
+            call(&mut instance, "load16_s", &[16]).unwrap(),
+            [-2i32 as u32 as u64]
+        );
+        assert_eq!(
+            call(&mut instance, "load16_s", &[65535]).unwrap_err(),
+            "memory access out of bounds"
+        );
+        // 1 MiB is 16 pages
+        assert_eq!(call(&mut instance, "grow", &[15]).unwrap(), [1]);
+        assert_eq!(
+            call(&mut instance, "grow", &[1]).unwrap(),
+            [u32::MAX as u64]
+        );
+        assert_eq!(call(&mut instance, "load16_s", &[65535]).unwrap(), [0]);
+    }
+
+    #[test]
+    fn computes_like_webassembly() {
+        let run = |opcode: u8, operands: &[u64]| {
+            let mut stack = operands.to_vec();
+            numeric(&mut stack, opcode).map(|()| stack[0])
+        };
+        let f32 = |value: f32| value.to_bits() as u64;
+        let f64 = |value: f64| value.to_bits();
+        assert_eq!(run(0xa4, &[f64(-0.0), f64(0.0)]), Ok(f64(-0.0)));
+        assert_eq!(run(0xa5, &[f64(-0.0), f64(0.0)]), Ok(f64(0.0)));
+        assert!(f64::from_bits(run(0xa4, &[f64(f64::NAN), f64(1.0)]).unwrap()).is_nan());
+        assert_eq!(run(0x90, &[f32(2.5)]), Ok(f32(2.0)));
+        assert_eq!(run(0x8c, &[f32(1.0)]), Ok(f32(-1.0)));
+        assert_eq!(run(0xaa, &[f64(-2147483648.9)]), Ok(i32::MIN as u32 as u64));
+        assert_eq!(run(0xaa, &[f64(3e9)]), Err("integer overflow".to_string()));
+        assert_eq!(
+            run(0xab, &[f64(f64::NAN)]),
+            Err("invalid conversion to integer".to_string())
+        );
+        assert_eq!(trunc_sat(2, f64(3e9)), i32::MAX as u32 as u64);
+        assert_eq!(trunc_sat(3, f64(-1.0)), 0);
+        let min = i32::MIN as u32 as u64;
+        let minus_one = u32::MAX as u64;
+        assert_eq!(
+            run(0x6d, &[min, minus_one]),
+            Err("integer overflow".to_string())
+        );
+        assert_eq!(run(0x6f, &[min, minus_one]), Ok(0));
+        assert_eq!(
+            run(0x6e, &[1, 0]),
+            Err("integer divide by zero".to_string())
+        );
+        assert_eq!(run(0x75, &[min, 33]), Ok(0xc000_0000));
+        assert_eq!(run(0xc0, &[0x80]), Ok(0xffff_ff80));
+        assert_eq!(run(0xac, &[minus_one]), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn stops_runaway_plugins() {
+        let mut wasm = Assembler::default();
+        let nothing = wasm.ty(&[], &[]);
+        let spin = wasm.func(nothing, &[], &[0x03, 0x40, 0x0c, 0, 0x0b]);
+        let recurse = wasm.func(nothing, &[], &[0x10, 1]);
+        let big = [&[0x41][..], &sleb(100_000), &[0x40, 0, 0x1a]].concat();
+        let grow = wasm.func(nothing, &[], &big);
+        wasm.memory(0);
+        wasm.export("spin", spin);
+        wasm.export("recurse", recurse);
+        wasm.export("grow", grow);
+        let mut instance = instantiate(&wasm);
+        assert_eq!(
+            call(&mut instance, "spin", &[]).unwrap_err(),
+            "ran out of fuel"
+        );
+        assert_eq!(
+            call(&mut instance, "recurse", &[]).unwrap_err(),
+            "call stack exhausted"
+        );
+        call(&mut instance, "grow", &[]).unwrap();
+        assert!(instance.memory.is_empty());
+    }
+}
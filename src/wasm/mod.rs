@@ -0,0 +1,667 @@
+//! WASM collector plugins: `.wasm` modules in `plugins.directory`, run by
+//! an interpreter built into metrixd rather than loaded into the process,
+//! so a plugin from an untrusted source can touch nothing but its own
+//! memory. Each cycle the module's exported `collect` function runs, with
+//! `FUEL` to burn, one per instruction and `HOST_FUEL` plus one per byte
+//! handled per call into metrixd, and at most `MAX_MEMORY` bytes of memory.
+//! It reports samples through functions it imports from `metrixd`:
+//!
+//! - `log(ptr: i32, len: i32)` writes a UTF-8 message to stderr
+//! - `read_file(path_ptr: i32, path_len: i32, buf_ptr: i32, buf_len: i32) -> i32`
+//!   copies up to `buf_len` bytes of a file below `plugins.wasm_read_paths`
+//!   into the buffer and returns the file's size, or a negative errno
+//! - `gauge(name_ptr: i32, name_len: i32, labels_ptr: i32, labels_len: i32, value: f64)`
+//!   and `counter(...)` set a sample; labels are `name=value` lines,
+//!   each name at most once
+//!
+//! Metric names must start with the plugin's name, its file name without
+//! `.wasm`, and an underscore. The samples reported in a cycle replace
+//! those of the last one. `collect` may return an i32, non-zero for a
+//! failed cycle. A plugin that traps is instantiated afresh next cycle.
+
+mod interp;
+mod module;
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use prometheus::core::{Collector as PrometheusCollector, Desc};
+use prometheus::proto::{Counter, Gauge, LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::{IntGauge, Opts};
+
+use crate::collector::Collector;
+use crate::config::valid_label_name;
+use interp::{burn, Host, Instance};
+use module::{Export, Module, ValType};
+
+/// Fuel a cycle may burn, including instantiation
+const FUEL: u64 = 100_000_000;
+
+/// Fuel a call of the host's functions costs, on top of one per byte it
+/// reads, copies or writes, so calling them in a loop can't keep a cycle
+/// busy any longer than running instructions can
+const HOST_FUEL: u64 = 1000;
+
+/// Memory an instance may grow to
+const MAX_MEMORY: usize = 64 << 20;
+
+/// Samples a cycle may report
+const MAX_SAMPLES: usize = 10_000;
+
+/// Bytes of a file `read_file` reads, however large the buffer
+const MAX_READ: u64 = 16 << 20;
+
+/// Characters of a message `log` writes
+const MAX_LOG: usize = 1024;
+
+const EACCES: i32 = 13;
+const EIO: i32 = 5;
+
+/// The functions plugins can import from `metrixd`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Log,
+    ReadFile,
+    Gauge,
+    Counter,
+}
+
+const FUNCTIONS: &[(&str, Function, &[ValType], &[ValType])] = {
+    use ValType::{F64, I32};
+    &[
+        ("log", Function::Log, &[I32, I32], &[]),
+        (
+            "read_file",
+            Function::ReadFile,
+            &[I32, I32, I32, I32],
+            &[I32],
+        ),
+        ("gauge", Function::Gauge, &[I32, I32, I32, I32, F64], &[]),
+        (
+            "counter",
+            Function::Counter,
+            &[I32, I32, I32, I32, F64],
+            &[],
+        ),
+    ]
+};
+
+/// A decoded plugin, whose imports are all provided
+#[derive(Debug)]
+pub struct WasmPlugin {
+    name: &'static str,
+    module: Arc<Module>,
+    /// The host function behind each import
+    imports: Arc<[Function]>,
+    read_paths: Arc<[PathBuf]>,
+}
+
+/// Decode the plugin at `path`, which may read files below `read_paths`
+pub fn open(path: &Path, read_paths: &[PathBuf]) -> Result<WasmPlugin, String> {
+    let name = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .filter(|stem| valid_label_name(stem))
+        .ok_or("the file name must be a valid metric name prefix")?;
+    let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+    let module = Module::parse(&bytes)?;
+
+    let mut imports = Vec::new();
+    for import in &module.imports {
+        let function = FUNCTIONS
+            .iter()
+            .find(|(name, ..)| import.module == "metrixd" && *name == import.name)
+            .ok_or_else(|| {
+                format!(
+                    "imports {}.{}, which metrixd doesn't provide",
+                    import.module, import.name
+                )
+            })?;
+        // Parsing checks import types against the one type section
+        let ty = &module.types[import.ty as usize];
+        if ty.params != function.2 || ty.results != function.3 {
+            return Err(format!(
+                "imports metrixd.{} with the wrong type",
+                import.name
+            ));
+        }
+        imports.push(function.1);
+    }
+    let collect = match module.exports.get("collect") {
+        Some(Export::Func(func)) => module.func_type(*func),
+        _ => None,
+    };
+    match collect {
+        Some(ty) if ty.params.is_empty() && matches!(ty.results[..], [] | [ValType::I32]) => {}
+        Some(_) => {
+            return Err("collect must take no arguments and return nothing or an i32".to_string())
+        }
+        None => return Err("doesn't export a collect function".to_string()),
+    }
+
+    Ok(WasmPlugin {
+        // Collector names are static; plugins are only loaded at startup
+        name: Box::leak(name.to_string().into_boxed_str()),
+        module: Arc::new(module),
+        imports: imports.into(),
+        // Resolved now, as the sandbox may hide where links lead later
+        read_paths: read_paths
+            .iter()
+            .filter_map(|path| path.canonicalize().ok())
+            .collect(),
+    })
+}
+
+impl WasmPlugin {
+    pub fn create_collector(&self) -> WasmCollector {
+        let instructions = IntGauge::with_opts(
+            Opts::new(
+                "metrixd_wasm_plugin_instructions",
+                "Fuel the WASM plugin burned in its last collection cycle",
+            )
+            .const_label("plugin", self.name),
+        )
+        .unwrap();
+        WasmCollector {
+            name: self.name,
+            module: Arc::clone(&self.module),
+            imports: Arc::clone(&self.imports),
+            read_paths: Arc::clone(&self.read_paths),
+            instance: Mutex::new(None),
+            reported: Reported {
+                instructions,
+                families: Arc::default(),
+            },
+        }
+    }
+}
+
+pub struct WasmCollector {
+    name: &'static str,
+    module: Arc<Module>,
+    imports: Arc<[Function]>,
+    read_paths: Arc<[PathBuf]>,
+    /// Created on the first cycle, and again after a trap
+    instance: Mutex<Option<Instance>>,
+    reported: Reported,
+}
+
+/// The families of the last successful cycle, plus the plugin's cost
+#[derive(Clone)]
+struct Reported {
+    instructions: IntGauge,
+    families: Arc<Mutex<Vec<MetricFamily>>>,
+}
+
+impl PrometheusCollector for Reported {
+    fn desc(&self) -> Vec<&Desc> {
+        self.instructions.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.instructions.collect();
+        families.extend(self.families.lock().unwrap().iter().cloned());
+        families
+    }
+}
+
+impl Collector for WasmCollector {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn register_metrics(&self, registry: &prometheus::Registry) -> prometheus::Result<()> {
+        crate::metrics::builder::register(registry, &self.reported)
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.reported]
+    }
+
+    fn reset_metrics(&self, _registry: &prometheus::Registry) {
+        self.reported.families.lock().unwrap().clear();
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        let mut host = Calls {
+            plugin: self,
+            samples: BTreeMap::new(),
+        };
+        let mut fuel = FUEL;
+        let mut instance = self.instance.lock().unwrap();
+        let result = match &mut *instance {
+            Some(instance) => instance.call("collect", &[], &mut host, &mut fuel),
+            None => Instance::new(Arc::clone(&self.module), MAX_MEMORY, &mut host, &mut fuel)
+                .and_then(|created| {
+                    instance
+                        .insert(created)
+                        .call("collect", &[], &mut host, &mut fuel)
+                }),
+        };
+        self.reported.instructions.set((FUEL - fuel) as i64);
+        match result {
+            Err(e) => {
+                *instance = None;
+                Err(format!("trapped: {}", e))
+            }
+            Ok(status) => match status.first().map(|status| *status as i32) {
+                Some(status) if status != 0 => Err(format!("collect returned {}", status)),
+                _ => {
+                    *self.reported.families.lock().unwrap() = families(self.name, host.samples);
+                    Ok(())
+                }
+            },
+        }
+    }
+}
+
+/// A sample's name and sorted labels
+type Series = (String, Vec<(String, String)>);
+
+/// The host side of a cycle, collecting what the plugin reports
+struct Calls<'a> {
+    plugin: &'a WasmCollector,
+    /// Values by series, and whether they are counters
+    samples: BTreeMap<Series, (bool, f64)>,
+}
+
+fn bytes(memory: &[u8], ptr: u64, len: u64) -> Result<&[u8], String> {
+    let (ptr, len) = (ptr as u32 as usize, len as u32 as usize);
+    memory
+        .get(ptr..ptr + len)
+        .ok_or_else(|| "passed memory out of bounds".to_string())
+}
+
+fn string(memory: &[u8], ptr: u64, len: u64) -> Result<&str, String> {
+    std::str::from_utf8(bytes(memory, ptr, len)?).map_err(|_| "passed invalid UTF-8".to_string())
+}
+
+impl Host for Calls<'_> {
+    fn call(
+        &mut self,
+        import: u32,
+        args: &[u64],
+        memory: &mut [u8],
+        fuel: &mut u64,
+    ) -> Result<Vec<u64>, String> {
+        burn(fuel, HOST_FUEL)?;
+        match self.plugin.imports[import as usize] {
+            Function::Log => {
+                let message = bytes(memory, args[0], args[1])?;
+                burn(fuel, message.len() as u64)?;
+                let message = String::from_utf8_lossy(message);
+                let message: String = message.chars().take(MAX_LOG).collect();
+                eprintln!("Plugin {}: {}", self.plugin.name, message);
+                Ok(vec![])
+            }
+            Function::ReadFile => {
+                let path = string(memory, args[0], args[1])?;
+                burn(fuel, path.len() as u64)?;
+                let result = self.read_file(Path::new(path));
+                let buffer = memory
+                    .get_mut(args[2] as u32 as usize..)
+                    .and_then(|tail| tail.get_mut(..args[3] as u32 as usize))
+                    .ok_or("passed memory out of bounds")?;
+                let result = match result {
+                    Ok(contents) => {
+                        burn(fuel, contents.len() as u64)?;
+                        let len = contents.len().min(buffer.len());
+                        buffer[..len].copy_from_slice(&contents[..len]);
+                        contents.len() as i32
+                    }
+                    Err(errno) => -errno,
+                };
+                Ok(vec![result as u32 as u64])
+            }
+            function @ (Function::Gauge | Function::Counter) => {
+                let name = string(memory, args[0], args[1])?;
+                let labels = string(memory, args[2], args[3])?;
+                burn(fuel, (name.len() + labels.len()) as u64)?;
+                let value = f64::from_bits(args[4]);
+                self.report(name, labels, function == Function::Counter, value)?;
+                Ok(vec![])
+            }
+        }
+    }
+}
+
+impl Calls<'_> {
+    /// The start of the file if it's below a readable path, or an errno
+    fn read_file(&self, path: &Path) -> Result<Vec<u8>, i32> {
+        let path = path
+            .canonicalize()
+            .map_err(|e| e.raw_os_error().unwrap_or(EIO))?;
+        if !self
+            .plugin
+            .read_paths
+            .iter()
+            .any(|allowed| path.starts_with(allowed))
+        {
+            return Err(EACCES);
+        }
+        let mut contents = Vec::new();
+        std::fs::File::open(&path)
+            .and_then(|file| file.take(MAX_READ).read_to_end(&mut contents))
+            .map_err(|e| e.raw_os_error().unwrap_or(EIO))?;
+        Ok(contents)
+    }
+
+    fn report(
+        &mut self,
+        name: &str,
+        labels: &str,
+        counter: bool,
+        value: f64,
+    ) -> Result<(), String> {
+        let prefix = name
+            .strip_prefix(self.plugin.name)
+            .and_then(|rest| rest.strip_prefix('_'));
+        if prefix.is_none() || !valid_label_name(name) {
+            return Err(format!(
+                "reported {}, which isn't a metric name starting with {}_",
+                name, self.plugin.name
+            ));
+        }
+        let mut pairs = Vec::new();
+        for line in labels.lines().filter(|line| !line.is_empty()) {
+            match line.split_once('=') {
+                Some((label, value)) if valid_label_name(label) => {
+                    pairs.push((label.to_string(), value.to_string()))
+                }
+                _ => return Err(format!("reported {} with invalid label {:?}", name, line)),
+            }
+        }
+        pairs.sort();
+        if let Some(pair) = pairs.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(format!("reported {} with label {} twice", name, pair[0].0));
+        }
+        if self.samples.len() == MAX_SAMPLES {
+            return Err(format!("reported more than {} samples", MAX_SAMPLES));
+        }
+        if let Some(((_, _), (was_counter, _))) = self
+            .samples
+            .range((name.to_string(), Vec::new())..)
+            .next()
+            .filter(|((other, _), _)| other == name)
+        {
+            if *was_counter != counter {
+                return Err(format!("reported {} as both a gauge and a counter", name));
+            }
+        }
+        self.samples
+            .insert((name.to_string(), pairs), (counter, value));
+        Ok(())
+    }
+}
+
+/// Families of the samples, by name
+fn families(plugin: &str, samples: BTreeMap<Series, (bool, f64)>) -> Vec<MetricFamily> {
+    let mut families: Vec<MetricFamily> = Vec::new();
+    for ((name, labels), (counter, value)) in samples {
+        if families.last().is_none_or(|family| family.name() != name) {
+            let mut family = MetricFamily::default();
+            family.set_name(name);
+            family.set_help(format!("Reported by the {} WASM plugin", plugin));
+            family.set_field_type(match counter {
+                true => MetricType::COUNTER,
+                false => MetricType::GAUGE,
+            });
+            families.push(family);
+        }
+        let mut metric = Metric::from_label(
+            labels
+                .into_iter()
+                .map(|(name, value)| {
+                    let mut pair = LabelPair::default();
+                    pair.set_name(name);
+                    pair.set_value(value);
+                    pair
+                })
+                .collect(),
+        );
+        if counter {
+            let mut sample = Counter::default();
+            sample.set_value(value);
+            metric.set_counter(sample);
+        } else {
+            let mut sample = Gauge::default();
+            sample.set_value(value);
+            metric.set_gauge(sample);
+        }
+        families.last_mut().unwrap().mut_metric().push(metric);
+    }
+    families
+}
+
+#[cfg(test)]
+mod tests {
+    use super::module::{sleb, Assembler};
+    use super::*;
+
+    const I32: u8 = 0x7f;
+    const F64: u8 = 0x7c;
+
+    fn i32_const(value: i32) -> Vec<u8> {
+        [&[0x41][..], &sleb(value as i64)].concat()
+    }
+
+    /// Push a string put in memory at `offset` by `data`
+    fn string(offset: i32, value: &str) -> Vec<u8> {
+        [i32_const(offset), i32_const(value.len() as i32)].concat()
+    }
+
+    /// A plugin importing all of the host API, with `strings` in memory
+    /// one after another from 0 and `collect` running `body` with an i32
+    /// local
+    fn plugin(dir: &Path, name: &str, strings: &[&str], body: &[u8]) -> PathBuf {
+        let mut wasm = Assembler::default();
+        let read_file = wasm.ty(&[I32, I32, I32, I32], &[I32]);
+        let report = wasm.ty(&[I32, I32, I32, I32, F64], &[]);
+        let collect = wasm.ty(&[], &[I32]);
+        wasm.import("metrixd", "read_file", read_file);
+        wasm.import("metrixd", "gauge", report);
+        wasm.import("metrixd", "counter", report);
+        let collect = wasm.func(collect, &[I32], body);
+        wasm.export("collect", collect);
+        wasm.memory(1);
+        wasm.data(0, strings.concat().as_bytes());
+        let path = dir.join(format!("{}.wasm", name));
+        std::fs::write(&path, wasm.finish()).unwrap();
+        path
+    }
+
+    /// Call `import` to report `value` for the `name` and `labels` strings
+    fn report(import: u8, name: (i32, &str), labels: (i32, &str), value: f64) -> Vec<u8> {
+        [
+            string(name.0, name.1),
+            string(labels.0, labels.1),
+            [0x44].into(),
+            value.to_le_bytes().into(),
+            vec![0x10, import],
+        ]
+        .concat()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("metrixd-wasm-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn samples(collector: &WasmCollector) -> Vec<(String, MetricType, String, f64)> {
+        let mut samples = Vec::new();
+        for family in collector.reported.families.lock().unwrap().iter() {
+            for metric in family.get_metric() {
+                let labels: Vec<String> = metric
+                    .get_label()
+                    .iter()
+                    .map(|pair| format!("{}={}", pair.name(), pair.value()))
+                    .collect();
+                let value = match family.get_field_type() {
+                    MetricType::COUNTER => metric.get_counter().value(),
+                    _ => metric.get_gauge().value(),
+                };
+                samples.push((
+                    family.name().to_string(),
+                    family.get_field_type(),
+                    labels.join(","),
+                    value,
+                ));
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn reports_samples() {
+        let dir = temp_dir("samples");
+        let strings = ["demo_up", "demo_reads_total", "file=b\nkind=log\n"];
+        let body = [
+            report(1, (0, strings[0]), (0, ""), 1.0),
+            report(2, (7, strings[1]), (23, strings[2]), 3.0),
+            i32_const(0),
+        ]
+        .concat();
+        let collector = open(&plugin(&dir, "demo", &strings, &body), &[])
+            .unwrap()
+            .create_collector();
+        collector.collect_metrics().unwrap();
+        assert_eq!(
+            samples(&collector),
+            [
+                (
+                    "demo_reads_total".to_string(),
+                    MetricType::COUNTER,
+                    "file=b,kind=log".to_string(),
+                    3.0
+                ),
+                ("demo_up".to_string(), MetricType::GAUGE, String::new(), 1.0),
+            ]
+        );
+        assert!(collector.reported.instructions.get() > 0);
+
+        // Labels that would break the scrape trap instead
+        for (labels, error) in [
+            ("file=a\nfile=b\n", "with label file twice"),
+            ("__name__=x\n", "with invalid label \"__name__=x\""),
+            ("kind\n", "with invalid label \"kind\""),
+        ] {
+            let strings = ["demo_up", labels];
+            let body = [report(1, (0, strings[0]), (7, labels), 1.0), i32_const(0)].concat();
+            let collector = open(&plugin(&dir, "demo", &strings, &body), &[])
+                .unwrap()
+                .create_collector();
+            assert_eq!(
+                collector.collect_metrics().unwrap_err(),
+                format!("trapped: reported demo_up {}", error)
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn host_calls_burn_fuel() {
+        let dir = temp_dir("fuel");
+        // Report demo_up 200000 times, each call costing fuel for a few
+        // instructions but HOST_FUEL more in metrixd
+        let body = [
+            i32_const(200_000),
+            vec![0x21, 0, 0x03, 0x40],
+            report(1, (0, "demo_up"), (0, ""), 1.0),
+            vec![0x20, 0, 0x41, 1, 0x6b, 0x22, 0, 0x0d, 0, 0x0b],
+            i32_const(0),
+        ]
+        .concat();
+        let plugin = open(&plugin(&dir, "demo", &["demo_up"], &body), &[]).unwrap();
+        let collector = plugin.create_collector();
+        assert_eq!(
+            collector.collect_metrics().unwrap_err(),
+            "trapped: ran out of fuel"
+        );
+        assert_eq!(collector.reported.instructions.get(), FUEL as i64);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_only_allowed_files() {
+        let dir = temp_dir("read");
+        std::fs::create_dir_all(dir.join("allowed")).unwrap();
+        std::fs::write(dir.join("allowed/value"), "42").unwrap();
+        std::fs::write(dir.join("secret"), "hunter2").unwrap();
+        let read = |path: &Path| {
+            let path = path.to_str().unwrap();
+            // Read into 64.., returning the size read_file returns
+            let body = [string(0, path), i32_const(64), i32_const(16), vec![0x10, 0]].concat();
+            let plugin = plugin(&dir, "read", &[path], &body);
+            let collector = open(&plugin, &[dir.join("allowed")])
+                .unwrap()
+                .create_collector();
+            collector.collect_metrics()
+        };
+        assert_eq!(
+            read(&dir.join("allowed/value")).unwrap_err(),
+            "collect returned 2"
+        );
+        assert_eq!(
+            read(&dir.join("secret")).unwrap_err(),
+            "collect returned -13"
+        );
+        assert_eq!(
+            read(&dir.join("allowed/../secret")).unwrap_err(),
+            "collect returned -13"
+        );
+        assert_eq!(
+            read(&dir.join("missing")).unwrap_err(),
+            "collect returned -2"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn starts_over_after_a_trap() {
+        let dir = temp_dir("trap");
+        let body = [report(1, (0, "other_up"), (0, ""), 1.0), i32_const(0)].concat();
+        let plugin = open(&plugin(&dir, "demo", &["other_up"], &body), &[]).unwrap();
+        let collector = plugin.create_collector();
+        assert_eq!(
+            collector.collect_metrics().unwrap_err(),
+            "trapped: reported other_up, which isn't a metric name starting with demo_"
+        );
+        assert!(collector.instance.lock().unwrap().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_plugins_it_cant_run() {
+        let dir = temp_dir("open");
+        let mut wasm = Assembler::default();
+        let nothing = wasm.ty(&[], &[]);
+        wasm.import("wasi_snapshot_preview1", "fd_write", nothing);
+        let path = dir.join("demo.wasm");
+        std::fs::write(&path, wasm.finish()).unwrap();
+        assert_eq!(
+            open(&path, &[]).unwrap_err(),
+            "imports wasi_snapshot_preview1.fd_write, which metrixd doesn't provide"
+        );
+
+        let mut wasm = Assembler::default();
+        let nothing = wasm.ty(&[], &[]);
+        wasm.func(nothing, &[], &[]);
+        std::fs::write(&path, wasm.finish()).unwrap();
+        assert_eq!(
+            open(&path, &[]).unwrap_err(),
+            "doesn't export a collect function"
+        );
+
+        let path = dir.join("my-plugin.wasm");
+        std::fs::write(&path, wasm.finish()).unwrap();
+        assert_eq!(
+            open(&path, &[]).unwrap_err(),
+            "the file name must be a valid metric name prefix"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
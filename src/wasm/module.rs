@@ -0,0 +1,978 @@
+//! Decoding of WebAssembly binaries into what the interpreter runs: the
+//! MVP format plus the sign-extension, saturating conversion and bulk
+//! memory instructions current compilers emit by default. Function bodies
+//! become `Op`s with the targets of their blocks resolved, so branches
+//! don't scan for the matching `end` at run time.
+//!
+//! Bodies aren't type-checked. The interpreter checks every stack,
+//! local and memory access instead, so a malformed module traps rather
+//! than misbehaving outside its sandbox.
+
+use std::collections::HashMap;
+
+/// Refs are function indices; this one is null
+pub const NULL: u64 = u64::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValType {
+    I32,
+    I64,
+    F32,
+    F64,
+    FuncRef,
+    ExternRef,
+}
+
+impl ValType {
+    /// The value locals of this type start with
+    pub fn zero(self) -> u64 {
+        match self {
+            ValType::FuncRef | ValType::ExternRef => NULL,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncType {
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+}
+
+/// A function the host provides
+#[derive(Debug)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+    pub ty: u32,
+}
+
+#[derive(Debug)]
+pub struct Code {
+    pub ty: u32,
+    /// Initial values of the locals after the parameters
+    pub locals: Vec<u64>,
+    pub body: Vec<Op>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub min: u32,
+    pub max: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ConstExpr {
+    Value(u64),
+    GlobalGet(u32),
+}
+
+#[derive(Debug)]
+pub struct Global {
+    pub mutable: bool,
+    pub init: ConstExpr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Export {
+    Func(u32),
+    Table(u32),
+    Memory(u32),
+    Global(u32),
+}
+
+/// An active element segment, filling a table at instantiation. Passive
+/// and declarative ones are only read past.
+#[derive(Debug)]
+pub struct Element {
+    pub table: u32,
+    pub offset: ConstExpr,
+    pub funcs: Vec<u64>,
+}
+
+#[derive(Debug)]
+pub struct Data {
+    /// Where an active segment is copied at instantiation; passive ones
+    /// are for `memory.init`
+    pub offset: Option<ConstExpr>,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Unreachable,
+    Nop,
+    /// `end` is the index of the matching `End`
+    Block {
+        params: u32,
+        results: u32,
+        end: u32,
+    },
+    Loop {
+        params: u32,
+    },
+    /// `otherwise` is the index of the `Else`, or the `End` without one
+    If {
+        params: u32,
+        results: u32,
+        otherwise: u32,
+        end: u32,
+    },
+    Else {
+        end: u32,
+    },
+    End,
+    Br(u32),
+    BrIf(u32),
+    /// The depths for each index, then the default one
+    BrTable(Box<[u32]>),
+    Return,
+    Call(u32),
+    CallIndirect {
+        ty: u32,
+        table: u32,
+    },
+    Drop,
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    TableGet(u32),
+    TableSet(u32),
+    /// Loads and stores by opcode, with the offset of their memarg
+    Load(u8, u32),
+    Store(u8, u32),
+    MemorySize,
+    MemoryGrow,
+    Const(u64),
+    /// Comparisons, arithmetic and conversions, by opcode
+    Numeric(u8),
+    /// Saturating float to int conversions, by their 0xfc sub-opcode
+    TruncSat(u8),
+    MemoryInit(u32),
+    DataDrop(u32),
+    MemoryCopy,
+    MemoryFill,
+    RefNull,
+    RefIsNull,
+    RefFunc(u32),
+}
+
+#[derive(Debug, Default)]
+pub struct Module {
+    pub types: Vec<FuncType>,
+    pub imports: Vec<Import>,
+    /// Defined functions, numbered after the imports
+    pub codes: Vec<Code>,
+    pub tables: Vec<Limits>,
+    pub memory: Option<Limits>,
+    pub globals: Vec<Global>,
+    pub exports: HashMap<String, Export>,
+    pub start: Option<u32>,
+    pub elements: Vec<Element>,
+    pub datas: Vec<Data>,
+}
+
+impl Module {
+    /// The type of a function, imported or defined
+    pub fn func_type(&self, func: u32) -> Option<&FuncType> {
+        let func = func as usize;
+        let ty = match func.checked_sub(self.imports.len()) {
+            None => self.imports[func].ty,
+            Some(defined) => self.codes.get(defined)?.ty,
+        };
+        self.types.get(ty as usize)
+    }
+
+    pub fn func_count(&self) -> usize {
+        self.imports.len() + self.codes.len()
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Module, String> {
+        let mut reader = Reader::new(bytes);
+        if reader.bytes(4)? != b"\0asm" {
+            return Err("not a WebAssembly module".to_string());
+        }
+        if reader.bytes(4)? != [1, 0, 0, 0] {
+            return Err("unsupported WebAssembly version".to_string());
+        }
+
+        let mut module = Module::default();
+        let mut funcs: Vec<u32> = Vec::new();
+        let mut last = 0;
+        while !reader.done() {
+            let id = reader.byte()?;
+            let size = reader.u32()? as usize;
+            let mut section = Reader::new(reader.bytes(size)?);
+            // Each section at most once and in order, so what is checked
+            // against an earlier one can't be replaced afterwards. The data
+            // count section comes before the code.
+            let rank = match id {
+                10 | 11 => id + 1,
+                12 => 10,
+                id => id,
+            };
+            if id != 0 {
+                if rank <= last {
+                    return Err(format!("section {} is repeated or out of order", id));
+                }
+                last = rank;
+            }
+            match id {
+                0 => continue,
+                1 => module.types = section.vec(parse_func_type)?,
+                2 => {
+                    let types = module.types.len();
+                    module.imports = section.vec(|r| parse_import(r, types))?;
+                }
+                3 => funcs = section.vec(Reader::u32)?,
+                4 => module.tables = section.vec(parse_table)?,
+                5 => {
+                    let memories = section.vec(Reader::limits)?;
+                    if memories.len() > 1 {
+                        return Err("more than one memory".to_string());
+                    }
+                    module.memory = memories.first().copied();
+                }
+                6 => module.globals = section.vec(parse_global)?,
+                7 => {
+                    for (name, export) in section.vec(parse_export)? {
+                        module.exports.insert(name, export);
+                    }
+                }
+                8 => module.start = Some(section.u32()?),
+                9 => module.elements = section.vec(parse_element)?.into_iter().flatten().collect(),
+                10 => {
+                    let bodies = section.vec(|r| {
+                        let size = r.u32()? as usize;
+                        Ok(r.bytes(size)?.to_vec())
+                    })?;
+                    if bodies.len() != funcs.len() {
+                        return Err("function and code sections differ in length".to_string());
+                    }
+                    let count = (module.imports.len() + funcs.len()) as u32;
+                    for (index, (ty, body)) in funcs.iter().zip(bodies).enumerate() {
+                        let code = parse_code(&module, count, *ty, &body).map_err(|e| {
+                            format!("function {}: {}", module.imports.len() + index, e)
+                        })?;
+                        module.codes.push(code);
+                    }
+                }
+                11 => module.datas = section.vec(parse_data)?,
+                12 => {
+                    section.u32()?;
+                }
+                id => return Err(format!("unknown section {}", id)),
+            }
+            if !section.done() {
+                return Err(format!("section {} is longer than its contents", id));
+            }
+        }
+        if module.codes.len() != funcs.len() {
+            return Err("functions without code".to_string());
+        }
+        if let Some(start) = module.start {
+            if start as usize >= module.func_count() {
+                return Err("unknown start function".to_string());
+            }
+        }
+        Ok(module)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, pos: 0 }
+    }
+
+    fn done(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self.bytes.get(self.pos).ok_or("unexpected end of module")?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or("unexpected end of module")?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// An unsigned LEB128 number of at most `bits` bits
+    fn unsigned(&mut self, bits: u32) -> Result<u64, String> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            if shift >= bits || (shift + 7 > bits && u64::from(byte & 0x7f) >> (bits - shift) != 0)
+            {
+                return Err("integer too large".to_string());
+            }
+            value |= u64::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// A signed LEB128 number of at most `bits` bits, sign-extended
+    fn signed(&mut self, bits: u32) -> Result<i64, String> {
+        let mut value = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            if shift >= bits {
+                return Err("integer too large".to_string());
+            }
+            value |= i64::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        self.unsigned(32).map(|value| value as u32)
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec())
+            .map_err(|_| "invalid UTF-8 in a name".to_string())
+    }
+
+    fn vec<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<T, String>,
+    ) -> Result<Vec<T>, String> {
+        let len = self.u32()?;
+        // Not trusting the length for the allocation
+        let mut items = Vec::with_capacity(len.min(1024) as usize);
+        for _ in 0..len {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn val_type(&mut self) -> Result<ValType, String> {
+        match self.byte()? {
+            0x7f => Ok(ValType::I32),
+            0x7e => Ok(ValType::I64),
+            0x7d => Ok(ValType::F32),
+            0x7c => Ok(ValType::F64),
+            0x70 => Ok(ValType::FuncRef),
+            0x6f => Ok(ValType::ExternRef),
+            0x7b => Err("SIMD isn't supported".to_string()),
+            byte => Err(format!("unknown value type 0x{:02x}", byte)),
+        }
+    }
+
+    fn limits(&mut self) -> Result<Limits, String> {
+        match self.byte()? {
+            0 => Ok(Limits {
+                min: self.u32()?,
+                max: None,
+            }),
+            1 => Ok(Limits {
+                min: self.u32()?,
+                max: Some(self.u32()?),
+            }),
+            _ => Err("shared and 64-bit memories aren't supported".to_string()),
+        }
+    }
+
+    fn const_expr(&mut self) -> Result<ConstExpr, String> {
+        let expr = match self.byte()? {
+            0x41 => ConstExpr::Value(self.signed(32)? as i32 as u32 as u64),
+            0x42 => ConstExpr::Value(self.signed(64)? as u64),
+            0x43 => ConstExpr::Value(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()) as u64),
+            0x44 => ConstExpr::Value(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap())),
+            0x23 => ConstExpr::GlobalGet(self.u32()?),
+            0xd0 => {
+                self.byte()?;
+                ConstExpr::Value(NULL)
+            }
+            0xd2 => ConstExpr::Value(self.u32()? as u64),
+            byte => return Err(format!("unsupported constant expression 0x{:02x}", byte)),
+        };
+        match self.byte()? {
+            0x0b => Ok(expr),
+            _ => Err(
+                "constant expressions of more than one instruction aren't supported".to_string(),
+            ),
+        }
+    }
+}
+
+fn parse_func_type(reader: &mut Reader) -> Result<FuncType, String> {
+    if reader.byte()? != 0x60 {
+        return Err("malformed function type".to_string());
+    }
+    Ok(FuncType {
+        params: reader.vec(Reader::val_type)?,
+        results: reader.vec(Reader::val_type)?,
+    })
+}
+
+/// An import, whose type must be one of the `types` read before it
+fn parse_import(reader: &mut Reader, types: usize) -> Result<Import, String> {
+    let module = reader.name()?;
+    let name = reader.name()?;
+    match reader.byte()? {
+        0 => {
+            let ty = reader.u32()?;
+            if ty as usize >= types {
+                return Err(format!("imports {}.{} with an unknown type", module, name));
+            }
+            Ok(Import { module, name, ty })
+        }
+        kind => {
+            let kind = match kind {
+                1 => "table",
+                2 => "memory",
+                3 => "global",
+                _ => "tag",
+            };
+            Err(format!(
+                "imports the {} {}.{}; only functions can be imported",
+                kind, module, name
+            ))
+        }
+    }
+}
+
+fn parse_table(reader: &mut Reader) -> Result<Limits, String> {
+    reader.val_type()?;
+    reader.limits()
+}
+
+fn parse_global(reader: &mut Reader) -> Result<Global, String> {
+    reader.val_type()?;
+    let mutable = reader.byte()? == 1;
+    Ok(Global {
+        mutable,
+        init: reader.const_expr()?,
+    })
+}
+
+fn parse_export(reader: &mut Reader) -> Result<(String, Export), String> {
+    let name = reader.name()?;
+    let kind = reader.byte()?;
+    let index = reader.u32()?;
+    let export = match kind {
+        0 => Export::Func(index),
+        1 => Export::Table(index),
+        2 => Export::Memory(index),
+        3 => Export::Global(index),
+        _ => return Err(format!("unknown export kind {}", kind)),
+    };
+    Ok((name, export))
+}
+
+/// The function a `ref.func` or `ref.null` element expression refers to
+fn element_expr(reader: &mut Reader) -> Result<u64, String> {
+    match reader.const_expr()? {
+        ConstExpr::Value(func) => Ok(func),
+        ConstExpr::GlobalGet(_) => Err("unsupported element expression".to_string()),
+    }
+}
+
+fn parse_element(reader: &mut Reader) -> Result<Option<Element>, String> {
+    let flags = reader.u32()?;
+    let (table, offset) = match flags {
+        0 | 4 => (0, Some(reader.const_expr()?)),
+        2 | 6 => (reader.u32()?, Some(reader.const_expr()?)),
+        1 | 3 | 5 | 7 => (0, None),
+        _ => return Err(format!("unknown element segment kind {}", flags)),
+    };
+    // The element kind or reference type, funcref in practice
+    if flags & 3 != 0 {
+        reader.byte()?;
+    }
+    let funcs = if flags & 4 == 0 {
+        reader.vec(|r| r.u32().map(u64::from))?
+    } else {
+        reader.vec(element_expr)?
+    };
+    Ok(offset.map(|offset| Element {
+        table,
+        offset,
+        funcs,
+    }))
+}
+
+fn parse_data(reader: &mut Reader) -> Result<Data, String> {
+    let offset = match reader.u32()? {
+        0 => Some(reader.const_expr()?),
+        1 => None,
+        2 => {
+            reader.u32()?;
+            Some(reader.const_expr()?)
+        }
+        flags => return Err(format!("unknown data segment kind {}", flags)),
+    };
+    let len = reader.u32()? as usize;
+    Ok(Data {
+        offset,
+        bytes: reader.bytes(len)?.to_vec(),
+    })
+}
+
+/// `(params, results)` of a block type
+fn block_type(module: &Module, reader: &mut Reader) -> Result<(u32, u32), String> {
+    match reader.bytes.get(reader.pos) {
+        Some(0x40) => {
+            reader.pos += 1;
+            Ok((0, 0))
+        }
+        Some(0x7f | 0x7e | 0x7d | 0x7c | 0x70 | 0x6f) => {
+            reader.pos += 1;
+            Ok((0, 1))
+        }
+        _ => {
+            let ty = reader.signed(33)?;
+            let ty = usize::try_from(ty)
+                .ok()
+                .and_then(|ty| module.types.get(ty))
+                .ok_or("unknown block type")?;
+            Ok((ty.params.len() as u32, ty.results.len() as u32))
+        }
+    }
+}
+
+fn memarg(reader: &mut Reader) -> Result<u32, String> {
+    let align = reader.u32()?;
+    if align & 0x40 != 0 {
+        return Err("multiple memories aren't supported".to_string());
+    }
+    reader.u32()
+}
+
+/// A function body, in a module of `funcs` functions
+fn parse_code(module: &Module, funcs: u32, ty: u32, body: &[u8]) -> Result<Code, String> {
+    if module.types.get(ty as usize).is_none() {
+        return Err("unknown type".to_string());
+    }
+    let mut reader = Reader::new(body);
+    let mut locals = Vec::new();
+    for _ in 0..reader.u32()? {
+        let count = reader.u32()?;
+        let zero = reader.val_type()?.zero();
+        if locals.len() + count as usize > 50_000 {
+            return Err("too many locals".to_string());
+        }
+        locals.extend(std::iter::repeat_n(zero, count as usize));
+    }
+
+    let func = |index: u32| -> Result<u32, String> {
+        match index < funcs {
+            true => Ok(index),
+            false => Err(format!("unknown function {}", index)),
+        }
+    };
+    let mut ops = Vec::new();
+    // Indices of the blocks, loops and ifs still open
+    let mut open: Vec<usize> = Vec::new();
+    loop {
+        let opcode = reader.byte()?;
+        let op = match opcode {
+            0x00 => Op::Unreachable,
+            0x01 => Op::Nop,
+            0x02 => {
+                let (params, results) = block_type(module, &mut reader)?;
+                open.push(ops.len());
+                Op::Block {
+                    params,
+                    results,
+                    end: 0,
+                }
+            }
+            0x03 => {
+                let (params, _) = block_type(module, &mut reader)?;
+                open.push(ops.len());
+                Op::Loop { params }
+            }
+            0x04 => {
+                let (params, results) = block_type(module, &mut reader)?;
+                open.push(ops.len());
+                Op::If {
+                    params,
+                    results,
+                    otherwise: 0,
+                    end: 0,
+                }
+            }
+            0x05 => {
+                let index = ops.len() as u32;
+                match open.last().and_then(|open| ops.get_mut(*open)) {
+                    Some(Op::If { otherwise, .. }) => *otherwise = index,
+                    _ => return Err("else outside an if".to_string()),
+                }
+                Op::Else { end: 0 }
+            }
+            0x0b => {
+                let index = ops.len() as u32;
+                let Some(start) = open.pop() else {
+                    // The end of the body
+                    ops.push(Op::End);
+                    break;
+                };
+                let mut branch = None;
+                match &mut ops[start] {
+                    Op::Block { end, .. } => *end = index,
+                    Op::If { otherwise, end, .. } => {
+                        *end = index;
+                        match *otherwise {
+                            0 => *otherwise = index,
+                            other => branch = Some(other as usize),
+                        }
+                    }
+                    _ => {}
+                }
+                if let Some(Op::Else { end }) = branch.map(|other| &mut ops[other]) {
+                    *end = index;
+                }
+                Op::End
+            }
+            0x0c => Op::Br(reader.u32()?),
+            0x0d => Op::BrIf(reader.u32()?),
+            0x0e => {
+                let mut depths = reader.vec(Reader::u32)?;
+                depths.push(reader.u32()?);
+                Op::BrTable(depths.into_boxed_slice())
+            }
+            0x0f => Op::Return,
+            0x10 => Op::Call(func(reader.u32()?)?),
+            0x11 => {
+                let ty = reader.u32()?;
+                if module.types.get(ty as usize).is_none() {
+                    return Err("unknown type".to_string());
+                }
+                Op::CallIndirect {
+                    ty,
+                    table: reader.u32()?,
+                }
+            }
+            0x1a => Op::Drop,
+            0x1b => Op::Select,
+            0x1c => {
+                reader.vec(Reader::val_type)?;
+                Op::Select
+            }
+            0x20 => Op::LocalGet(reader.u32()?),
+            0x21 => Op::LocalSet(reader.u32()?),
+            0x22 => Op::LocalTee(reader.u32()?),
+            0x23 => Op::GlobalGet(reader.u32()?),
+            0x24 => Op::GlobalSet(reader.u32()?),
+            0x25 => Op::TableGet(reader.u32()?),
+            0x26 => Op::TableSet(reader.u32()?),
+            0x28..=0x35 => Op::Load(opcode, memarg(&mut reader)?),
+            0x36..=0x3e => Op::Store(opcode, memarg(&mut reader)?),
+            0x3f | 0x40 => {
+                if reader.byte()? != 0 {
+                    return Err("multiple memories aren't supported".to_string());
+                }
+                match opcode {
+                    0x3f => Op::MemorySize,
+                    _ => Op::MemoryGrow,
+                }
+            }
+            0x41 => Op::Const(reader.signed(32)? as i32 as u32 as u64),
+            0x42 => Op::Const(reader.signed(64)? as u64),
+            0x43 => Op::Const(u32::from_le_bytes(reader.bytes(4)?.try_into().unwrap()) as u64),
+            0x44 => Op::Const(u64::from_le_bytes(reader.bytes(8)?.try_into().unwrap())),
+            0x45..=0xc4 => Op::Numeric(opcode),
+            0xd0 => {
+                reader.byte()?;
+                Op::RefNull
+            }
+            0xd1 => Op::RefIsNull,
+            0xd2 => Op::RefFunc(func(reader.u32()?)?),
+            0xfc => match reader.u32()? {
+                sub @ 0..=7 => Op::TruncSat(sub as u8),
+                8 => {
+                    let data = reader.u32()?;
+                    reader.byte()?;
+                    Op::MemoryInit(data)
+                }
+                9 => Op::DataDrop(reader.u32()?),
+                10 => {
+                    reader.bytes(2)?;
+                    Op::MemoryCopy
+                }
+                11 => {
+                    reader.byte()?;
+                    Op::MemoryFill
+                }
+                sub => return Err(format!("unsupported instruction 0xfc {}", sub)),
+            },
+            opcode => return Err(format!("unsupported instruction 0x{:02x}", opcode)),
+        };
+        ops.push(op);
+    }
+    if !reader.done() {
+        return Err("instructions after the end of the body".to_string());
+    }
+    Ok(Code {
+        ty,
+        locals,
+        body: ops,
+    })
+}
+
+/// Builds modules for the tests, which have no WebAssembly toolchain to
+/// compile them with. Function bodies are given without their final `end`.
+#[cfg(test)]
+#[derive(Default)]
+pub(super) struct Assembler {
+    types: Vec<Vec<u8>>,
+    imports: Vec<Vec<u8>>,
+    funcs: Vec<u32>,
+    codes: Vec<Vec<u8>>,
+    table: Vec<u32>,
+    memory: Option<u32>,
+    exports: Vec<Vec<u8>>,
+    datas: Vec<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl Assembler {
+    pub fn ty(&mut self, params: &[u8], results: &[u8]) -> u32 {
+        let ty = [&[0x60][..], &vec(params), &vec(results)].concat();
+        self.types.push(ty);
+        self.types.len() as u32 - 1
+    }
+
+    /// Import a function; all of them come before `func`
+    pub fn import(&mut self, module: &str, name: &str, ty: u32) -> u32 {
+        let import = [
+            &vec(module.as_bytes())[..],
+            &vec(name.as_bytes()),
+            &[0],
+            &leb(ty as u64),
+        ]
+        .concat();
+        self.imports.push(import);
+        self.imports.len() as u32 - 1
+    }
+
+    /// Define a function with a local of each of `locals`' types
+    pub fn func(&mut self, ty: u32, locals: &[u8], body: &[u8]) -> u32 {
+        let mut code = leb(locals.len() as u64);
+        for local in locals {
+            code.extend([1, *local]);
+        }
+        code.extend(body);
+        code.push(0x0b);
+        self.funcs.push(ty);
+        self.codes.push(vec(&code));
+        (self.imports.len() + self.funcs.len()) as u32 - 1
+    }
+
+    pub fn export(&mut self, name: &str, func: u32) {
+        let export = [&vec(name.as_bytes())[..], &[0], &leb(func as u64)].concat();
+        self.exports.push(export);
+    }
+
+    pub fn memory(&mut self, pages: u32) {
+        self.memory = Some(pages);
+    }
+
+    /// A table holding `funcs`
+    pub fn table(&mut self, funcs: &[u32]) {
+        self.table = funcs.to_vec();
+    }
+
+    pub fn data(&mut self, offset: u32, bytes: &[u8]) {
+        let data = [&[0, 0x41][..], &sleb(offset as i64), &[0x0b], &vec(bytes)].concat();
+        self.datas.push(data);
+    }
+
+    pub fn finish(&self) -> Vec<u8> {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        let mut section = |id: u8, items: &[Vec<u8>]| {
+            if !items.is_empty() {
+                let contents = [leb(items.len() as u64), items.concat()].concat();
+                module.push(id);
+                module.extend(vec(&contents));
+            }
+        };
+        let table = [&[0x70, 0][..], &leb(self.table.len() as u64)].concat();
+        let funcs: Vec<Vec<u8>> = self.table.iter().map(|func| leb(*func as u64)).collect();
+        let element = [
+            &[0, 0x41, 0, 0x0b][..],
+            &leb(funcs.len() as u64),
+            &funcs.concat(),
+        ]
+        .concat();
+        let tables = match self.table.is_empty() {
+            true => vec![],
+            false => vec![table],
+        };
+        let elements = match self.table.is_empty() {
+            true => vec![],
+            false => vec![element],
+        };
+        section(1, &self.types);
+        section(2, &self.imports);
+        section(
+            3,
+            &self
+                .funcs
+                .iter()
+                .map(|ty| leb(*ty as u64))
+                .collect::<Vec<_>>(),
+        );
+        section(4, &tables);
+        section(
+            5,
+            &self
+                .memory
+                .map(|pages| [vec![0], leb(pages as u64)].concat())
+                .into_iter()
+                .collect::<Vec<_>>(),
+        );
+        section(7, &self.exports);
+        section(9, &elements);
+        section(10, &self.codes);
+        section(11, &self.datas);
+        module
+    }
+}
+
+/// Bytes prefixed with their length
+#[cfg(test)]
+fn vec(bytes: &[u8]) -> Vec<u8> {
+    [leb(bytes.len() as u64), bytes.to_vec()].concat()
+}
+
+#[cfg(test)]
+pub(super) fn leb(mut value: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+pub(super) fn sleb(mut value: i64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_leb128() {
+        let mut reader = Reader::new(&[
+            0xe5, 0x8e, 0x26, 0x7f, 0x80, 0x7f, 0xff, 0xff, 0xff, 0xff, 0x1f,
+        ]);
+        assert_eq!(reader.u32().unwrap(), 624485);
+        assert_eq!(reader.signed(32).unwrap(), -1);
+        assert_eq!(reader.signed(32).unwrap(), -128);
+        assert_eq!(reader.u32().unwrap_err(), "integer too large");
+    }
+
+    #[test]
+    fn resolves_block_ends() {
+        // block; i32.const 1; if; else; end; end; end
+        let body = [0, 0x02, 0x40, 0x41, 1, 0x04, 0x40, 0x05, 0x0b, 0x0b, 0x0b];
+        let module = Module {
+            types: vec![FuncType {
+                params: vec![],
+                results: vec![],
+            }],
+            ..Module::default()
+        };
+        let code = parse_code(&module, 0, 0, &body).unwrap();
+        assert_eq!(
+            code.body,
+            [
+                Op::Block {
+                    params: 0,
+                    results: 0,
+                    end: 5
+                },
+                Op::Const(1),
+                Op::If {
+                    params: 0,
+                    results: 0,
+                    otherwise: 3,
+                    end: 4
+                },
+                Op::Else { end: 4 },
+                Op::End,
+                Op::End,
+                Op::End,
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_what_it_cant_run() {
+        assert_eq!(
+            Module::parse(b"\x7fELF").unwrap_err(),
+            "not a WebAssembly module"
+        );
+        // An imported memory
+        let module = b"\0asm\x01\0\0\0\x02\x0b\x01\x03env\x03mem\x02\0\x01";
+        assert_eq!(
+            Module::parse(module).unwrap_err(),
+            "imports the memory env.mem; only functions can be imported"
+        );
+        // A function import of a type the module doesn't have
+        let mut asm = Assembler::default();
+        asm.ty(&[], &[]);
+        asm.import("metrixd", "log", 99);
+        assert_eq!(
+            Module::parse(&asm.finish()).unwrap_err(),
+            "imports metrixd.log with an unknown type"
+        );
+        // An empty type section after the imports were checked
+        let mut asm = Assembler::default();
+        let ty = asm.ty(&[], &[]);
+        asm.import("metrixd", "log", ty);
+        let mut module = asm.finish();
+        module.extend([1, 1, 0]);
+        assert_eq!(
+            Module::parse(&module).unwrap_err(),
+            "section 1 is repeated or out of order"
+        );
+    }
+}
@@ -0,0 +1,683 @@
+//! End-to-end tests of the HTTP endpoint against fake collectors
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use hyper::{Method, StatusCode};
+use metrixd::testing::TestServer;
+use metrixd::Collector;
+use prometheus::core::Collector as PrometheusCollector;
+use prometheus::proto::{MetricFamily, MetricType};
+use prometheus::{IntCounter, IntGaugeVec, Opts};
+use protobuf::CodedInputStream;
+
+/// Counts its own collections and exports a labelled gauge
+struct FakeCollector {
+    name: &'static str,
+    collections: IntCounter,
+    temperature: IntGaugeVec,
+}
+
+fn fake(name: &'static str) -> Box<dyn Collector + Send + Sync> {
+    let collections = IntCounter::with_opts(Opts::new(
+        format!("{}_collections_total", name),
+        "Collections so far",
+    ))
+    .unwrap();
+    let temperature = IntGaugeVec::new(
+        Opts::new(format!("{}_temperature_celsius", name), "Fake temperature"),
+        &["sensor"],
+    )
+    .unwrap();
+    Box::new(FakeCollector {
+        name,
+        collections,
+        temperature,
+    })
+}
+
+impl Collector for FakeCollector {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
+        Ok(())
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        self.collections.inc();
+        self.temperature.with_label_values(&["core0"]).set(42);
+        Ok(())
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.collections, &self.temperature]
+    }
+}
+
+/// Fails while `failing` is set, counting its attempts
+struct FlakyCollector {
+    failing: Arc<AtomicBool>,
+    attempts: IntCounter,
+}
+
+impl Collector for FlakyCollector {
+    fn name(&self) -> &'static str {
+        "flaky"
+    }
+
+    fn register_metrics(&self, _registry: &prometheus::Registry) -> prometheus::Result<()> {
+        Ok(())
+    }
+
+    fn collect_metrics(&self) -> Result<(), String> {
+        self.attempts.inc();
+        if self.failing.load(Ordering::Relaxed) {
+            return Err("socket missing".to_string());
+        }
+        Ok(())
+    }
+
+    fn metrics(&self) -> Vec<&dyn PrometheusCollector> {
+        vec![&self.attempts]
+    }
+}
+
+async fn start(config: &str) -> TestServer {
+    let server = TestServer::start(config, vec![fake("alpha"), fake("beta")]).await;
+    server.collect();
+    server
+}
+
+#[tokio::test]
+async fn serves_text_exposition_format() {
+    let server = start("").await;
+    let response = server.get("/metrics").await;
+
+    assert_eq!(response.status, StatusCode::OK);
+    let body = &response.body;
+    assert!(body.contains("# HELP alpha_collections_total Collections so far\n"));
+    assert!(body.contains("# TYPE alpha_collections_total counter\n"));
+    assert!(body.contains("alpha_collections_total 1\n"));
+    assert!(body.contains("# TYPE beta_temperature_celsius gauge\n"));
+    assert!(body.contains("beta_temperature_celsius{sensor=\"core0\"} 42\n"));
+    assert!(body.contains("metrixd_collector_enabled{collector=\"alpha\"} 1\n"));
+    assert!(body.contains("metrixd_collector_duration_seconds_count{collector=\"beta\"} 1\n"));
+}
+
+#[tokio::test]
+async fn exports_series_counts_and_exposition_size() {
+    let server = start("").await;
+    let first = server.get("/metrics").await;
+    assert!(first
+        .body
+        .contains("metrixd_series_count{collector=\"alpha\"} 2\n"));
+
+    let second = server.get("/metrics").await;
+    assert!(second.body.contains(&format!(
+        "metrixd_exposition_size_bytes {}\n",
+        first.bytes.len()
+    )));
+
+    // Subsets of /metrics leave the size of the full response alone
+    let subset = server.get("/metrics?collect[]=beta").await;
+    assert!(subset.bytes.len() < second.bytes.len());
+    let third = server.get("/metrics").await;
+    assert!(third.body.contains(&format!(
+        "metrixd_exposition_size_bytes {}\n",
+        second.bytes.len()
+    )));
+}
+
+#[tokio::test]
+async fn exports_the_host_identity() {
+    let server = start("").await;
+    let response = server.get("/metrics").await;
+    // No cloud lookup without cloud.identity_info
+    assert!(response
+        .body
+        .contains("node_identity_info{cloud_provider=\"\",instance_id=\"\",machine_id=\""));
+}
+
+#[tokio::test]
+async fn answers_unchanged_expositions_with_not_modified() {
+    let server = start("").await;
+    let response = server.get("/metrics").await;
+    let header = |name: &str| response.headers[name].to_str().unwrap().to_string();
+    let (etag, last_modified) = (header("etag"), header("last-modified"));
+
+    let cached = server
+        .request(Method::GET, "/metrics", &[("If-None-Match", &etag)], "")
+        .await;
+    assert_eq!(cached.status, StatusCode::NOT_MODIFIED);
+    assert!(cached.body.is_empty());
+    let cached = server
+        .request(
+            Method::GET,
+            "/metrics",
+            &[("If-Modified-Since", &last_modified)],
+            "",
+        )
+        .await;
+    assert_eq!(cached.status, StatusCode::NOT_MODIFIED);
+    // Another format is another representation
+    let protobuf = server
+        .request(
+            Method::GET,
+            "/metrics",
+            &[
+                ("If-None-Match", &etag),
+                ("Accept", "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited"),
+            ],
+            "",
+        )
+        .await;
+    assert_eq!(protobuf.status, StatusCode::OK);
+
+    server.collect();
+    let response = server
+        .request(Method::GET, "/metrics", &[("If-None-Match", &etag)], "")
+        .await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_ne!(response.headers["etag"].to_str().unwrap(), etag);
+}
+
+#[tokio::test]
+async fn serves_http2_with_prior_knowledge() {
+    let client = hyper::Client::builder()
+        .http2_only(true)
+        .build_http::<hyper::Body>();
+
+    let server = start("").await;
+    let uri = server.url("/metrics").parse().unwrap();
+    let response = client.get(uri).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.version(), hyper::Version::HTTP_2);
+
+    let server = start("[web]\nhttp2 = false\n").await;
+    let uri = server.url("/metrics").parse().unwrap();
+    assert!(client.get(uri).await.is_err());
+}
+
+#[tokio::test]
+async fn rate_limits_each_client() {
+    let server = start("[web]\nrate_limit = 1\nrate_limit_burst = 2\n").await;
+    for _ in 0..2 {
+        assert_eq!(server.get("/metrics").await.status, StatusCode::OK);
+    }
+    let response = server.get("/metrics").await;
+    assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers["retry-after"], "1");
+
+    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    let response = server.get("/metrics").await;
+    assert!(response
+        .body
+        .contains("metrixd_http_requests_limited_total 1\n"));
+}
+
+#[tokio::test]
+async fn reflects_each_collection() {
+    let server = start("").await;
+    server.collect();
+
+    let response = server.get("/metrics").await;
+    assert!(response.body.contains("alpha_collections_total 2\n"));
+}
+
+#[tokio::test]
+async fn answers_other_accept_headers_with_text_format() {
+    let server = start("").await;
+
+    for accept in [
+        "text/plain",
+        "*/*",
+        "application/openmetrics-text; version=1.0.0",
+        "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=text",
+    ] {
+        let response = server
+            .request(Method::GET, "/metrics", &[("Accept", accept)], "")
+            .await;
+        assert_eq!(response.status, StatusCode::OK, "Accept: {}", accept);
+        assert_eq!(
+            response.content_type(),
+            Some("text/plain; version=0.0.4"),
+            "Accept: {}",
+            accept
+        );
+        assert!(response
+            .body
+            .contains("# TYPE alpha_collections_total counter\n"));
+    }
+}
+
+#[tokio::test]
+async fn answers_protobuf_accept_header_with_delimited_families() {
+    let server = start("").await;
+    let response = server
+        .request(
+            Method::GET,
+            "/metrics",
+            &[(
+                "Accept",
+                "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3",
+            )],
+            "",
+        )
+        .await;
+
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(
+        response.content_type(),
+        Some("application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited")
+    );
+    let mut input = CodedInputStream::from_bytes(&response.bytes);
+    let mut families = Vec::new();
+    while !input.eof().unwrap() {
+        families.push(input.read_message::<MetricFamily>().unwrap());
+    }
+    let alpha = families
+        .iter()
+        .find(|family| family.name() == "alpha_collections_total")
+        .expect("alpha_collections_total missing");
+    assert_eq!(alpha.get_field_type(), MetricType::COUNTER);
+    assert_eq!(alpha.get_metric()[0].get_counter().value(), 1.0);
+}
+
+#[tokio::test]
+async fn collect_parameter_keeps_only_named_collectors() {
+    let server = start("").await;
+    let response = server.get("/metrics?collect[]=beta").await;
+
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.body.contains("beta_collections_total 1\n"));
+    assert!(!response.body.contains("alpha_"));
+    assert!(!response.body.contains("metrixd_"));
+
+    let response = server
+        .get("/metrics?collect%5B%5D=alpha&collect%5B%5D=beta")
+        .await;
+    assert!(response.body.contains("alpha_collections_total 1\n"));
+    assert!(response.body.contains("beta_collections_total 1\n"));
+}
+
+#[tokio::test]
+async fn exclude_parameter_drops_named_collectors() {
+    let server = start("").await;
+    let response = server.get("/metrics?exclude[]=alpha").await;
+
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(!response.body.contains("alpha_"));
+    assert!(response.body.contains("beta_collections_total 1\n"));
+    assert!(response.body.contains("metrixd_collector_enabled"));
+}
+
+#[tokio::test]
+async fn rejects_invalid_collector_parameters() {
+    let server = start("").await;
+
+    let response = server.get("/metrics?collect[]=gamma").await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    assert_eq!(response.body, "Unknown collector: gamma");
+
+    let response = server.get("/metrics?collect[]=alpha&exclude[]=beta").await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn config_exclude_drops_matching_families() {
+    let server = start("[metrics]\nexclude = [\"alpha_temp.*\", \"metrixd_.*\"]\n").await;
+    let response = server.get("/metrics").await;
+
+    assert!(response.body.contains("alpha_collections_total 1\n"));
+    assert!(!response.body.contains("alpha_temperature_celsius"));
+    assert!(!response.body.contains("metrixd_"));
+}
+
+#[tokio::test]
+async fn views_serve_their_own_subsets() {
+    let server = start(
+        "[views.slim]\ncollectors = [\"beta\"]\ninclude = [\"alpha_temp.*\"]\n\
+         [views.full]\nexclude = [\"metrixd_.*\"]\n",
+    )
+    .await;
+
+    let response = server.get("/metrics/slim").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.body.contains("beta_collections_total 1\n"));
+    assert!(response.body.contains("alpha_temperature_celsius"));
+    assert!(!response.body.contains("alpha_collections_total"));
+    assert!(!response.body.contains("metrixd_"));
+
+    let response = server.get("/metrics/full?exclude[]=beta").await;
+    assert!(response.body.contains("alpha_collections_total 1\n"));
+    assert!(!response.body.contains("beta_"));
+    assert!(!response.body.contains("metrixd_"));
+
+    let response = server.get("/metrics/wide").await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+    assert_eq!(response.body, "Unknown view: wide");
+}
+
+#[tokio::test]
+async fn tenant_views_label_their_series_and_require_their_own_token() {
+    let server = start(
+        "[web]\nmetrics_token = \"m-secret\"\n\
+         [views.team-a]\ntoken = \"a-secret\"\ninclude = [\"alpha_.*\"]\n\
+         [views.team-a.labels]\ntenant = \"a\"\nsensor = \"shared\"\n\
+         [views.team-b]\ntoken = \"b-secret\"\n",
+    )
+    .await;
+    let get = |path: &'static str, token: &'static str| {
+        let header = format!("Bearer {}", token);
+        let server = &server;
+        async move {
+            server
+                .request(Method::GET, path, &[("Authorization", &header)], "")
+                .await
+        }
+    };
+    let scrape = |token| get("/metrics/team-a", token);
+
+    assert_eq!(
+        server.get("/metrics/team-a").await.status,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(scrape("b-secret").await.status, StatusCode::UNAUTHORIZED);
+    let response = scrape("a-secret").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response
+        .body
+        .contains("alpha_collections_total{sensor=\"shared\",tenant=\"a\"} 1\n"));
+    assert!(response.body.contains(
+        "alpha_temperature_celsius{exported_sensor=\"core0\",sensor=\"shared\",tenant=\"a\"} 42\n"
+    ));
+    assert!(!response.body.contains("beta_"));
+
+    // Everything else only opens with the metrics token
+    for path in ["/metrics", "/probe", "/status", "/metrics-docs"] {
+        assert_eq!(server.get(path).await.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(get(path, "a-secret").await.status, StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(scrape("m-secret").await.status, StatusCode::UNAUTHORIZED);
+    let response = get("/metrics", "m-secret").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.body.contains("alpha_collections_total 1\n"));
+}
+
+#[tokio::test]
+async fn views_without_a_token_require_the_metrics_token() {
+    let server = start(
+        "[web]\nmetrics_token = \"m-secret\"\n\
+         [views.slim]\ncollectors = [\"beta\"]\n",
+    )
+    .await;
+
+    assert_eq!(
+        server.get("/metrics/slim").await.status,
+        StatusCode::UNAUTHORIZED
+    );
+    let response = server
+        .request(
+            Method::GET,
+            "/metrics/slim",
+            &[("Authorization", "Bearer m-secret")],
+            "",
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.body.contains("beta_collections_total 1\n"));
+}
+
+#[tokio::test]
+async fn config_labels_are_added_to_every_series() {
+    let server = start("[labels]\nenv = \"test\"\nsensor = \"none\"\n").await;
+    let response = server.get("/metrics").await;
+
+    assert!(response
+        .body
+        .contains("alpha_collections_total{env=\"test\",sensor=\"none\"} 1\n"));
+    // Labels the series already has are kept
+    assert!(response
+        .body
+        .contains("alpha_temperature_celsius{env=\"test\",sensor=\"core0\"} 42\n"));
+}
+
+#[tokio::test]
+async fn instance_label_is_added_to_every_series() {
+    let server = start("[instance]\nid = \"web-1\"\nlabel = \"host\"\n").await;
+    let response = server.get("/metrics").await;
+    assert!(response
+        .body
+        .contains("alpha_temperature_celsius{host=\"web-1\",sensor=\"core0\"} 42\n"));
+}
+
+#[tokio::test]
+async fn disabled_collectors_are_hidden_and_not_collected() {
+    let server = start("[web]\nenable_admin_api = true\n").await;
+
+    let response = server
+        .request(Method::PUT, "/admin/collectors/alpha", &[], "false")
+        .await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.body, "{\"collector\":\"alpha\",\"enabled\":false}");
+    server.collect();
+
+    let response = server.get("/metrics").await;
+    assert!(!response.body.contains("alpha_collections_total"));
+    assert!(response.body.contains("beta_collections_total 2\n"));
+    assert!(response
+        .body
+        .contains("metrixd_collector_enabled{collector=\"alpha\"} 0\n"));
+
+    server
+        .request(Method::PUT, "/admin/collectors/alpha", &[], "true")
+        .await;
+    let response = server.get("/metrics").await;
+    assert!(response.body.contains("alpha_collections_total 1\n"));
+}
+
+#[tokio::test]
+async fn admin_api_requires_configuration_and_token() {
+    let server = start("").await;
+    let response = server.get("/admin/collectors").await;
+    assert_eq!(response.status, StatusCode::FORBIDDEN);
+
+    let server = start("[web]\nenable_admin_api = true\nadmin_token = \"secret\"\n").await;
+    let response = server.get("/admin/collectors").await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+
+    let response = server
+        .request(
+            Method::GET,
+            "/admin/collectors",
+            &[("Authorization", "Bearer secret")],
+            "",
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.content_type(), Some("application/json"));
+    assert_eq!(
+        response.body,
+        "[{\"collector\":\"alpha\",\"enabled\":true},{\"collector\":\"beta\",\"enabled\":true}]"
+    );
+}
+
+#[tokio::test]
+async fn admin_token_file_is_reread_on_reload() {
+    let token_file =
+        std::env::temp_dir().join(format!("metrixd-test-token-{}", std::process::id()));
+    std::fs::write(&token_file, "first\n").unwrap();
+    let config = format!(
+        "[web]\nenable_lifecycle = true\nenable_admin_api = true\nadmin_token_file = \"{}\"\n",
+        token_file.display()
+    );
+    let server = start(&config).await;
+
+    let get = |token: &'static str| {
+        let header = format!("Bearer {}", token);
+        let server = &server;
+        async move {
+            server
+                .request(
+                    Method::GET,
+                    "/admin/collectors",
+                    &[("Authorization", &header)],
+                    "",
+                )
+                .await
+                .status
+        }
+    };
+    assert_eq!(get("first").await, StatusCode::OK);
+
+    std::fs::write(&token_file, "second\n").unwrap();
+    let response = server
+        .request(
+            Method::POST,
+            "/-/reload",
+            &[("Authorization", "Bearer first")],
+            "",
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(get("first").await, StatusCode::UNAUTHORIZED);
+    assert_eq!(get("second").await, StatusCode::OK);
+
+    std::fs::remove_file(&token_file).unwrap();
+}
+
+#[tokio::test]
+async fn documents_the_metrics_of_enabled_collectors() {
+    let server = start("[web]\nenable_admin_api = true\n").await;
+    server
+        .request(Method::PUT, "/admin/collectors/alpha", &[], "false")
+        .await;
+
+    let response = server.get("/metrics-docs").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.headers["content-type"], "application/json");
+    assert_eq!(
+        response.body,
+        "[{\"collector\":\"beta\",\"metrics\":[\
+         {\"name\":\"beta_collections_total\",\"type\":\"counter\",\
+         \"help\":\"Collections so far\",\"labels\":[]},\
+         {\"name\":\"beta_temperature_celsius\",\"type\":\"gauge\",\
+         \"help\":\"Fake temperature\",\"labels\":[\"sensor\"]}]}]"
+    );
+}
+
+#[tokio::test]
+async fn failing_collectors_back_off() {
+    let failing = Arc::new(AtomicBool::new(true));
+    let flaky = FlakyCollector {
+        failing: Arc::clone(&failing),
+        attempts: IntCounter::new("flaky_attempts_total", "Collection attempts").unwrap(),
+    };
+    let server = TestServer::start(
+        "[collection]\ninterval = \"10s\"\nmax_backoff = \"40s\"\n",
+        vec![Box::new(flaky)],
+    )
+    .await;
+
+    // Attempts in cycles 1, 2, 4, 8 and then every 4th, at most 40s apart
+    for _ in 0..12 {
+        server.collect();
+    }
+    let body = server.get("/metrics").await.body;
+    assert!(body.contains("flaky_attempts_total 5\n"));
+    assert!(body.contains("metrixd_collector_errors_total{collector=\"flaky\"} 5\n"));
+    assert!(body.contains("metrixd_collector_backoff_seconds{collector=\"flaky\"} 40\n"));
+
+    failing.store(false, Ordering::Relaxed);
+    for _ in 0..4 {
+        server.collect();
+    }
+    let body = server.get("/metrics").await.body;
+    assert!(body.contains("flaky_attempts_total 6\n"));
+    assert!(body.contains("metrixd_collector_backoff_seconds{collector=\"flaky\"} 0\n"));
+
+    server.collect();
+    let body = server.get("/metrics").await.body;
+    assert!(body.contains("flaky_attempts_total 7\n"));
+}
+
+#[tokio::test]
+async fn status_reports_collections_and_errors() {
+    let failing = Arc::new(AtomicBool::new(true));
+    let flaky = FlakyCollector {
+        failing: Arc::clone(&failing),
+        attempts: IntCounter::new("flaky_attempts_total", "Collection attempts").unwrap(),
+    };
+    let server = TestServer::start("", vec![Box::new(flaky), fake("alpha")]).await;
+
+    let response = server.get("/status").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.headers["content-type"], "application/json");
+    assert!(response.body.starts_with(&format!(
+        "{{\"version\":\"{}\",\"uptime_seconds\":",
+        env!("CARGO_PKG_VERSION")
+    )));
+    // SHA-256 of the empty config file
+    assert!(response.body.contains(
+        "\"config_hash\":\"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\""
+    ));
+    assert!(response.body.contains(
+        "{\"collector\":\"flaky\",\"enabled\":true,\"shed\":false,\"failures\":0,\
+         \"last_duration_seconds\":null,\"last_error\":null}"
+    ));
+
+    server.collect();
+    let body = server.get("/status").await.body;
+    assert!(body.contains("\"failures\":1,\"last_duration_seconds\":"));
+    assert!(body.contains("\"last_error\":\"socket missing\"}"));
+    assert!(!body.contains("\"last_duration_seconds\":null"));
+
+    // The last error outlives the recovery
+    failing.store(false, Ordering::Relaxed);
+    server.collect();
+    let body = server.get("/status").await.body;
+    assert!(
+        body.contains("\"collector\":\"flaky\",\"enabled\":true,\"shed\":false,\"failures\":0,")
+    );
+    assert!(body.contains("\"last_error\":\"socket missing\"}"));
+
+    let response = server.request(Method::POST, "/status", &[], "").await;
+    assert_eq!(response.status, StatusCode::METHOD_NOT_ALLOWED);
+}
+
+#[tokio::test]
+async fn probe_endpoint_checks_the_target() {
+    let server = start("").await;
+    let response = server.get("/probe?target=127.0.0.1:1").await;
+    assert_eq!(response.status, StatusCode::FORBIDDEN);
+
+    let server = start("[probe]\nenabled = true\n").await;
+    let target = format!("http://{}/metrics", server.addr());
+    let response = server.get(&format!("/probe?target={}", target)).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.body.contains("probe_success 1\n"));
+    assert!(response.body.contains("probe_http_status_code 200\n"));
+    assert!(response.body.contains("probe_duration_seconds "));
+    assert!(!response.body.contains("alpha_"));
+
+    // Nothing listens on port 1
+    let response = server
+        .get("/probe?target=127.0.0.1:1&module=tcp_connect")
+        .await;
+    assert!(response.body.contains("probe_success 0\n"));
+    assert!(!response.body.contains("probe_http_status_code"));
+}
+
+#[tokio::test]
+async fn probe_endpoint_rejects_invalid_parameters() {
+    let server = start("[probe]\nenabled = true\n").await;
+    let response = server.get("/probe").await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    assert_eq!(response.body, "Missing target parameter");
+
+    let response = server.get("/probe?target=example.com&module=icmp").await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    assert_eq!(response.body, "Unknown module: icmp");
+}